@@ -0,0 +1,95 @@
+//! Reference guest agent.
+//!
+//! Runs inside the guest and serves the host's guest agent requests.
+//!
+//! Usage:
+//! * `asgard-agent [PORT_PATH]` serves a virtio-serial port, `/dev/virtio-ports/org.asgard.agent.0` by default
+//! * `asgard-agent --vsock PORT` accepts vsock connections on `PORT`, serving one at a time
+
+use std::fs::OpenOptions;
+use std::process::ExitCode;
+use AsgardManager::error::VmError;
+use AsgardManager::guest_agent::agent::{serve, DEFAULT_AGENT_PORT};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [flag, port] if flag == "--vsock" => match port.parse::<u32>() {
+            Ok(port) => serve_vsock(port),
+            Err(e) => Err(VmError::config(format!("invalid vsock port {}: {}", port, e))),
+        },
+        [path] => serve_port(path),
+        [] => serve_port(DEFAULT_AGENT_PORT),
+        _ => Err(VmError::config("usage: asgard-agent [PORT_PATH | --vsock PORT]")),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("asgard-agent: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Serves the virtio-serial port at `path`.
+fn serve_port(path: &str) -> Result<(), VmError> {
+    let mut port = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(port) => port,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path, e), e)),
+    };
+    serve(&mut port)
+}
+
+/// Serves the vsock connections made to `port`, one after the other.
+#[cfg(target_os = "linux")]
+fn serve_vsock(port: u32) -> Result<(), VmError> {
+    use std::fs::File;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    // SAFETY: plain socket calls; the descriptors are owned right after creation
+    let listener = unsafe {
+        let fd = libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(VmError::io(format!("failed to create vsock socket: {}", e), e));
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+    // SAFETY: sockaddr_vm is plain data for which all zeroes is valid
+    let mut address: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    address.svm_port = port;
+    address.svm_cid = libc::VMADDR_CID_ANY;
+    // SAFETY: `address` is a valid vsock address of the given size
+    let bound = unsafe {
+        let fd = listener.as_raw_fd();
+        libc::bind(fd, &address as *const libc::sockaddr_vm as *const libc::sockaddr, size_of::<libc::sockaddr_vm>() as libc::socklen_t) == 0
+            && libc::listen(fd, 1) == 0
+    };
+    if !bound {
+        let e = std::io::Error::last_os_error();
+        return Err(VmError::io(format!("failed to listen on vsock port {}: {}", port, e), e));
+    }
+
+    loop {
+        // SAFETY: the peer address isn't requested, so no buffer is passed
+        let connection = unsafe {
+            libc::accept4(listener.as_raw_fd(), std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC)
+        };
+        if connection < 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(VmError::io(format!("failed to accept vsock connection: {}", e), e));
+        }
+        // SAFETY: accept4 returned a new descriptor nobody else owns
+        let mut stream = File::from(unsafe { OwnedFd::from_raw_fd(connection) });
+        // A host that hangs up or misbehaves only ends its own connection
+        if let Err(e) = serve(&mut stream) {
+            eprintln!("asgard-agent: {}", e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn serve_vsock(_port: u32) -> Result<(), VmError> {
+    Err(VmError::config("vsock is only supported on Linux guests"))
+}
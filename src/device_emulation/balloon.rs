@@ -0,0 +1,252 @@
+//! virtio-balloon device.
+//!
+//! The host sets the size it wants the balloon to have, see
+//! `VmHandle::set_balloon_target`. The guest driver reads it from the configuration
+//! space and inflates the balloon by handing the device pages it stops using, or
+//! deflates it by taking pages back. Inflated pages are released to the host, so an
+//! idle VM doesn't keep memory resident that its guest gave up. The guest may take
+//! pages back without asking when it runs out of memory itself.
+
+use virtio_queue::{QueueSync, QueueT};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use crate::device_emulation::virtio_mmio::{VirtioDevice, VirtqueueState};
+use crate::error::VmError;
+
+/// Virtio device ID of a memory balloon.
+const VIRTIO_ID_BALLOON: u32 = 5;
+/// Feature bit letting the guest deflate the balloon when it runs out of memory.
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+/// Size of the inflate and deflate queues.
+pub const BALLOON_QUEUE_SIZE: u16 = 128;
+/// Size of the pages the balloon is counted in, whatever the guest page size.
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+/// Queue the driver hands pages to the device through.
+pub const BALLOON_INFLATE_QUEUE: usize = 0;
+/// Queue the driver takes pages back through.
+pub const BALLOON_DEFLATE_QUEUE: usize = 1;
+/// Offset of the `actual` field in the configuration space, after `num_pages`.
+const CONFIG_ACTUAL: u64 = 4;
+
+/// Advice releasing anonymous memory right away; macOS only does so lazily with
+/// `MADV_FREE`, as its `MADV_DONTNEED` keeps the pages.
+#[cfg(target_os = "linux")]
+const MADV_RELEASE: libc::c_int = libc::MADV_DONTNEED;
+#[cfg(target_os = "macos")]
+const MADV_RELEASE: libc::c_int = libc::MADV_FREE;
+
+/// virtio-balloon device with its inflate and deflate queues.
+pub struct VirtioBalloon {
+    mem: GuestMemoryMmap,
+    /// Inflate queue followed by the deflate queue
+    queues: Vec<QueueSync>,
+    /// Pages the host wants in the balloon
+    target_pages: u32,
+    /// Pages the driver reports in the balloon
+    actual_pages: u32,
+    /// Bytes of guest memory released to the host so far
+    released_bytes: u64,
+}
+
+impl VirtioBalloon {
+    /// Creates an empty balloon.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory holding the virtqueues and the ballooned pages
+    ///
+    /// # Returns
+    /// * `Ok(VirtioBalloon)` on success
+    /// * `Err(VmError)` if the virtqueues couldn't be created
+    pub fn new(mem: GuestMemoryMmap) -> Result<Self, VmError> {
+        let mut queues = Vec::with_capacity(2);
+        for _ in 0..2 {
+            match QueueSync::new(BALLOON_QUEUE_SIZE) {
+                Ok(queue) => queues.push(queue),
+                Err(e) => return Err(VmError::device_source(format!("failed to create virtqueue: {}", e), e))
+            }
+        }
+        Ok(VirtioBalloon { mem, queues, target_pages: 0, actual_pages: 0, released_bytes: 0 })
+    }
+
+    /// Returns virtqueue `index`, e.g. to set it up without a guest driver.
+    pub fn get_queue_mut(&mut self, index: usize) -> Option<&mut QueueSync> {
+        self.queues.get_mut(index)
+    }
+
+    /// Sets the size the guest is asked to inflate the balloon to, rounded down to
+    /// whole balloon pages. The transport must signal a configuration change for the
+    /// driver to notice.
+    pub fn set_target(&mut self, bytes: u64) {
+        self.target_pages = (bytes / BALLOON_PAGE_SIZE).min(u32::MAX as u64) as u32;
+    }
+
+    /// Returns the size the guest is asked to inflate the balloon to, in bytes.
+    pub fn get_target(&self) -> u64 {
+        self.target_pages as u64 * BALLOON_PAGE_SIZE
+    }
+
+    /// Returns the size of the balloon reported by the driver, in bytes.
+    pub fn get_actual(&self) -> u64 {
+        self.actual_pages as u64 * BALLOON_PAGE_SIZE
+    }
+
+    /// Returns the bytes of guest memory released to the host so far.
+    pub fn get_released_bytes(&self) -> u64 {
+        self.released_bytes
+    }
+
+    /// Returns the configuration space: `num_pages`, then `actual`.
+    fn get_config_space(&self) -> [u8; 8] {
+        let mut config = [0u8; 8];
+        config[..4].copy_from_slice(&self.target_pages.to_le_bytes());
+        config[4..].copy_from_slice(&self.actual_pages.to_le_bytes());
+        config
+    }
+
+    /// Consumes the page lists the driver made available in queue `index`, releasing
+    /// the pages of the inflate queue to the host.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the consumed buffers
+    fn process_page_queue(&mut self, index: usize) -> bool {
+        let queue = &mut self.queues[index];
+        if !queue.ready() {
+            return false;
+        }
+        let mut used_any = false;
+        while let Some(chain) = queue.pop_descriptor_chain(&self.mem) {
+            let head_index = chain.head_index();
+            // Deflated pages need nothing: the guest faults them back in when it uses them
+            if index == BALLOON_INFLATE_QUEUE {
+                let mut pfns = Vec::new();
+                for descriptor in chain.readable() {
+                    let mut buffer = vec![0u8; descriptor.len() as usize];
+                    if self.mem.read_slice(&mut buffer, descriptor.addr()).is_ok() {
+                        pfns.extend(buffer.chunks_exact(4).map(|pfn| u32::from_le_bytes([pfn[0], pfn[1], pfn[2], pfn[3]])));
+                    }
+                }
+                self.released_bytes += release_guest_pages(&self.mem, &pfns);
+            }
+            if queue.add_used(&self.mem, head_index, 0).is_err() {
+                break;
+            }
+            used_any = true;
+        }
+        used_any && queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+}
+
+/// Releases the guest pages numbered `pfns` (in `BALLOON_PAGE_SIZE` units) to the
+/// host, one `madvise` per run of pages contiguous in host memory. Pages outside
+/// guest RAM are skipped.
+///
+/// # Returns
+/// * The number of bytes released
+fn release_guest_pages(mem: &GuestMemoryMmap, pfns: &[u32]) -> u64 {
+    let mut pages: Vec<usize> = pfns.iter()
+        .filter_map(|pfn| mem.get_host_address(GuestAddress(*pfn as u64 * BALLOON_PAGE_SIZE)).ok())
+        .map(|host_addr| host_addr as usize)
+        .collect();
+    pages.sort_unstable();
+    pages.dedup();
+
+    let mut released = 0;
+    let mut index = 0;
+    while index < pages.len() {
+        let start = pages[index];
+        let mut len = BALLOON_PAGE_SIZE as usize;
+        index += 1;
+        while index < pages.len() && pages[index] == start + len {
+            len += BALLOON_PAGE_SIZE as usize;
+            index += 1;
+        }
+        // SAFETY: the range is guest RAM mapped by `mem`, whose content the guest gave up
+        if unsafe { libc::madvise(start as *mut libc::c_void, len, MADV_RELEASE) } == 0 {
+            released += len as u64;
+        }
+    }
+    released
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn get_device_type(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn get_device_features(&self) -> u64 {
+        VIRTIO_BALLOON_F_DEFLATE_ON_OOM
+    }
+
+    fn get_num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn get_queue_max_size(&self, _index: usize) -> u16 {
+        BALLOON_QUEUE_SIZE
+    }
+
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+        match self.queues.get_mut(index) {
+            Some(queue) => state.apply(queue, &self.mem),
+            None => Err(VmError::device(format!("no virtqueue {}", index)))
+        }
+    }
+
+    fn deactivate_queue(&mut self, index: usize) {
+        if let Some(queue) = self.queues.get_mut(index) {
+            queue.reset();
+        }
+    }
+
+    fn process_queue(&mut self, index: usize) -> bool {
+        match index {
+            BALLOON_INFLATE_QUEUE | BALLOON_DEFLATE_QUEUE => self.process_page_queue(index),
+            _ => false,
+        }
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.get_config_space();
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = offset.checked_add(index as u64)
+                .and_then(|position| config.get(position as usize))
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    /// Lets the driver report the size of the balloon through `actual`; the target is
+    /// read-only.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset == CONFIG_ACTUAL && data.len() == 4 {
+            self.actual_pages = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        }
+    }
+
+    /// Drops the queue state and the size reported by the driver; the target is kept
+    /// for the next driver.
+    fn reset(&mut self) {
+        for queue in &mut self.queues {
+            queue.reset();
+        }
+        self.actual_pages = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_guest_pages() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 16 * BALLOON_PAGE_SIZE as usize)]).unwrap();
+        mem.write_slice(&[0xaa; 8], GuestAddress(2 * BALLOON_PAGE_SIZE)).unwrap();
+
+        // Pages 2 to 4 form one run, page 9 another; page 100 isn't guest RAM
+        assert_eq!(release_guest_pages(&mem, &[4, 2, 3, 9, 3, 100]), 4 * BALLOON_PAGE_SIZE);
+        let mut released = [0xffu8; 8];
+        mem.read_slice(&mut released, GuestAddress(2 * BALLOON_PAGE_SIZE)).unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(released, [0u8; 8]);
+        assert_eq!(release_guest_pages(&mem, &[]), 0);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use memmap2::MmapMut;
+use crate::error::VmError;
+use crate::device_emulation::block_device::storage_backend::StorageBackend;
+
+/// Sector size of the virtio-blk protocol, which capacities and request offsets are counted in.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// When writes to the disk image reach the backing file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// Writes stay in the page cache until the guest sends a flush request; fast,
+    /// but a host crash loses what the guest didn't flush
+    #[default]
+    Writeback,
+    /// Every write is synced to the backing file before it completes
+    Writethrough,
+}
+
+/// Disk as seen by a block device: a run of 512-byte sectors, whatever the image
+/// format or location.
+///
+/// Raw images are served by `MmapBackend` and `FileBackend`, and every
+/// `StorageBackend` (e.g. `IoUringBackend`) is a `DiskBackend` too. Formats that
+/// translate sectors, such as qcow2, or disks on the network, such as NBD exports,
+/// implement the trait themselves. Buffers always cover whole sectors. Any disk can
+/// take snapshots when wrapped in a `SnapshotDisk`.
+pub trait DiskBackend {
+    /// Reads the sectors starting at `sector` into `buf`.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the whole buffer was filled
+    /// * `Err(VmError)` if `buf` isn't made of whole sectors, reaches past the end of
+    ///   the disk, or the read failed
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), VmError>;
+
+    /// Writes `buf` to the sectors starting at `sector`.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the whole buffer was written
+    /// * `Err(VmError)` if `buf` isn't made of whole sectors, reaches past the end of
+    ///   the disk, or the write failed
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), VmError>;
+
+    /// Flushes any buffered writes down to stable storage.
+    fn flush(&mut self) -> Result<(), VmError>;
+
+    /// Returns the capacity of the disk in sectors.
+    fn capacity(&self) -> u64;
+
+    /// Flushes the writes to `count` sectors starting at `sector`, for writethrough
+    /// caching. Flushes the whole disk unless the backend can do better.
+    fn flush_sectors(&mut self, sector: u64, count: u64) -> Result<(), VmError> {
+        let _ = (sector, count);
+        self.flush()
+    }
+
+    /// Returns the disk contents if they are mapped in memory, letting requests copy
+    /// straight between them and guest memory.
+    fn as_mapped(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Captures the current contents of the disk as the snapshot `name`, see
+    /// `SnapshotDisk`. Disks don't support snapshots unless they say otherwise.
+    fn snapshot(&mut self, name: &str) -> Result<(), VmError> {
+        Err(VmError::device(format!("can't take snapshot {}: the disk doesn't support snapshots", name)))
+    }
+
+    /// Rolls the disk back to the snapshot `name`.
+    fn revert(&mut self, name: &str) -> Result<(), VmError> {
+        Err(VmError::device(format!("can't revert to snapshot {}: the disk doesn't support snapshots", name)))
+    }
+}
+
+impl<T: StorageBackend + ?Sized> DiskBackend for T {
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        let range = get_byte_range(DiskBackend::capacity(self), sector, buf.len())?;
+        self.read_at(range.start, buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), VmError> {
+        let range = get_byte_range(DiskBackend::capacity(self), sector, buf.len())?;
+        self.write_at(range.start, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), VmError> {
+        StorageBackend::flush(self)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.size() / SECTOR_SIZE
+    }
+}
+
+/// Raw image mapped in memory; a partial last sector is left out.
+pub struct MmapBackend {
+    disk_image: MmapMut,
+}
+
+impl MmapBackend {
+    /// Creates a backend on a mapped raw image, e.g. from `map_disk_image`.
+    pub fn new(disk_image: MmapMut) -> MmapBackend {
+        MmapBackend { disk_image }
+    }
+}
+
+impl DiskBackend for MmapBackend {
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        let range = get_byte_range(self.capacity(), sector, buf.len())?;
+        buf.copy_from_slice(&self.disk_image[range.start as usize..range.end as usize]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), VmError> {
+        let range = get_byte_range(self.capacity(), sector, buf.len())?;
+        self.disk_image[range.start as usize..range.end as usize].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VmError> {
+        match self.disk_image.flush() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to flush disk image: {}", e), e))
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.disk_image.len() as u64 / SECTOR_SIZE
+    }
+
+    fn flush_sectors(&mut self, sector: u64, count: u64) -> Result<(), VmError> {
+        let range = get_byte_range(self.capacity(), sector, (count * SECTOR_SIZE) as usize)?;
+        match self.disk_image.flush_range(range.start as usize, (range.end - range.start) as usize) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to flush disk image: {}", e), e))
+        }
+    }
+
+    fn as_mapped(&mut self) -> Option<&mut [u8]> {
+        let len = (self.capacity() * SECTOR_SIZE) as usize;
+        Some(&mut self.disk_image[..len])
+    }
+}
+
+/// Raw image accessed with plain reads and writes, for images too large to map or
+/// on filesystems that can't map them; a partial last sector is left out.
+pub struct FileBackend {
+    file: File,
+    /// Capacity in sectors, fixed when the backend is created
+    capacity: u64,
+}
+
+impl FileBackend {
+    /// Opens the raw image at `path` for reading and writing.
+    ///
+    /// # Returns
+    /// * `Ok(FileBackend)` on success
+    /// * `Err(VmError)` if the image couldn't be opened
+    pub fn open(path: &Path) -> Result<FileBackend, VmError> {
+        match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => FileBackend::from_file(file),
+            Err(e) => Err(VmError::io(format!("failed to open disk image {}: {}", path.display(), e), e))
+        }
+    }
+
+    /// Creates a backend on an open raw image.
+    ///
+    /// # Returns
+    /// * `Ok(FileBackend)` on success
+    /// * `Err(VmError)` if the size of the image couldn't be read
+    pub fn from_file(file: File) -> Result<FileBackend, VmError> {
+        match file.metadata() {
+            Ok(metadata) => Ok(FileBackend { file, capacity: metadata.len() / SECTOR_SIZE }),
+            Err(e) => Err(VmError::io(format!("failed to read disk image size: {}", e), e))
+        }
+    }
+}
+
+impl DiskBackend for FileBackend {
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        let range = get_byte_range(self.capacity, sector, buf.len())?;
+        if let Err(e) = self.file.seek(SeekFrom::Start(range.start)).and_then(|_| self.file.read_exact(buf)) {
+            return Err(VmError::io(format!("failed to read {} bytes at offset {}: {}", buf.len(), range.start, e), e));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), VmError> {
+        let range = get_byte_range(self.capacity, sector, buf.len())?;
+        if let Err(e) = self.file.seek(SeekFrom::Start(range.start)).and_then(|_| self.file.write_all(buf)) {
+            return Err(VmError::io(format!("failed to write {} bytes at offset {}: {}", buf.len(), range.start, e), e));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VmError> {
+        match self.file.sync_data() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to flush disk image: {}", e), e))
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+/// Returns the byte range covered by `len` bytes at `sector` of a disk of
+/// `capacity` sectors.
+///
+/// # Returns
+/// * `Ok(range)` if `len` is made of whole sectors and lies within the disk
+/// * `Err(VmError)` otherwise
+pub(crate) fn get_byte_range(capacity: u64, sector: u64, len: usize) -> Result<Range<u64>, VmError> {
+    let len = len as u64;
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(VmError::device(format!("access of {} bytes isn't made of whole sectors", len)));
+    }
+    match sector.checked_add(len / SECTOR_SIZE) {
+        Some(end) if end <= capacity => Ok(sector * SECTOR_SIZE..end * SECTOR_SIZE),
+        _ => Err(VmError::device(format!("access of {} bytes at sector {} is out of disk bounds", len, sector)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper: byte-addressed storage in memory
+    struct MemoryStorage(Vec<u8>);
+
+    impl StorageBackend for MemoryStorage {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VmError> {
+            buf.copy_from_slice(&self.0[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        }
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), VmError> {
+            self.0[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+        fn flush(&mut self) -> Result<(), VmError> {
+            Ok(())
+        }
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    // Helper: writes two sectors, reads them back and checks the bounds of the disk
+    fn check_backend(backend: &mut dyn DiskBackend) {
+        assert_eq!(backend.capacity(), 8);
+        let data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+        backend.write_sectors(6, &data).expect("Write should succeed");
+        backend.flush_sectors(6, 2).expect("Flush should succeed");
+        let mut read = vec![0u8; 1024];
+        backend.read_sectors(6, &mut read).expect("Read should succeed");
+        assert_eq!(read, data);
+
+        assert!(backend.write_sectors(7, &data).is_err());
+        assert!(backend.read_sectors(u64::MAX, &mut read[..512]).is_err());
+        assert!(backend.read_sectors(0, &mut read[..100]).is_err());
+        backend.flush().expect("Flush should succeed");
+    }
+
+    #[test]
+    fn test_raw_backends() {
+        // A partial last sector is left out
+        let file = tempfile::NamedTempFile::new().unwrap();
+        file.as_file().set_len(8 * SECTOR_SIZE + 100).unwrap();
+        let mut mapped = MmapBackend::new(unsafe { MmapMut::map_mut(file.as_file()).unwrap() });
+        check_backend(&mut mapped);
+        assert_eq!(mapped.as_mapped().map(|data| data.len()), Some(8 * SECTOR_SIZE as usize));
+        drop(mapped);
+
+        let mut plain = FileBackend::open(file.path()).expect("Open should succeed");
+        let mut read = vec![0u8; 512];
+        plain.read_sectors(7, &mut read).unwrap();
+        assert_eq!(read[..4], [10, 11, 12, 13]);
+        check_backend(&mut plain);
+        assert!(plain.as_mapped().is_none());
+        assert!(FileBackend::open(&file.path().with_extension("missing")).is_err());
+    }
+
+    #[test]
+    fn test_storage_backends_are_disk_backends() {
+        let mut storage = MemoryStorage(vec![0; 8 * SECTOR_SIZE as usize]);
+        check_backend(&mut storage);
+        assert_eq!(storage.0[6 * SECTOR_SIZE as usize + 1], 1);
+    }
+}
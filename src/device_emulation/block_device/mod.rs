@@ -1,2 +1,26 @@
-#[cfg(target_os = "linux")]
-pub mod linux;
\ No newline at end of file
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+pub mod virtio_block;
+
+pub mod disk_backend;
+pub mod qcow2;
+pub mod snapshot;
+pub mod storage_backend;
+
+#[cfg(all(target_os = "linux", feature = "linux_io_uring"))]
+pub mod uring;
+
+/// Snapshot of the request queue statistics of a block device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockDeviceMetrics {
+    /// Configured maximum number of requests in flight
+    pub max_queue_depth: usize,
+    /// Requests the guest made available that the device hasn't completed yet
+    pub inflight: usize,
+    /// Highest number of requests that were in flight at the same time
+    pub peak_inflight: usize,
+    /// Total number of requests completed
+    pub completed_requests: u64,
+    /// Number of times guest notifications were suppressed because more requests than
+    /// the queue depth were outstanding
+    pub backpressure_events: u64,
+}
@@ -0,0 +1,699 @@
+//! Native reader and writer of qcow2 images.
+//!
+//! Guest offsets are translated through the two-level L1/L2 cluster tables; clusters
+//! are allocated at the end of the image file and accounted for in the refcount
+//! table, so images stay consistent for `qemu-img check`. Reads understand
+//! deflate-compressed and zero clusters. Writes never modify shared data in place:
+//! compressed, zero and unallocated clusters are copied into a fresh cluster first.
+//! Backing files, encryption, external data files and zstd compression are not
+//! supported, and images with internal snapshots are opened read-only.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use flate2::read::DeflateDecoder;
+use crate::error::VmError;
+use crate::device_emulation::block_device::disk_backend::{get_byte_range, DiskBackend, SECTOR_SIZE};
+
+/// Magic number opening every qcow2 image ("QFI\xfb").
+pub const QCOW2_MAGIC: u32 = 0x5146_49fb;
+/// Cluster size of the images created by `Qcow2Backend::create`, as a power of two.
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+/// Refcount width of created images, as a power of two of bits (16-bit refcounts).
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+/// Length of the version 3 header written by `create`.
+const V3_HEADER_LENGTH: u32 = 104;
+/// Offset bits of L1, L2 and refcount table entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Table entry flag: the cluster has a refcount of exactly one and may be written in place.
+const FLAG_COPIED: u64 = 1 << 63;
+/// L2 entry flag: the cluster is compressed.
+const FLAG_COMPRESSED: u64 = 1 << 62;
+/// L2 entry flag (version 3): the cluster reads as zeroes.
+const FLAG_ZERO: u64 = 1;
+/// Incompatible feature bit: refcounts may be stale after a crash.
+const INCOMPATIBLE_DIRTY: u64 = 1;
+/// Largest L1 table accepted in bytes, the limit of QEMU.
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+/// Largest refcount table accepted in bytes, the limit of QEMU.
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+
+/// Where the data of a guest cluster lives, decoded from its L2 entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClusterData {
+    /// Never written, or explicitly zeroed
+    Zero,
+    /// Stored uncompressed at a host offset
+    Normal(u64),
+    /// Deflate-compressed: host offset and length of the compressed data
+    Compressed(u64, u64),
+}
+
+/// Disk backend reading and writing a qcow2 image (version 2 or 3).
+pub struct Qcow2Backend {
+    file: File,
+    /// Refuse writes, for inspection and for images that can't be written safely
+    read_only: bool,
+    cluster_bits: u32,
+    cluster_size: u64,
+    /// Guest visible size in bytes
+    size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// Refcount width as a power of two of bits
+    refcount_order: u32,
+    /// Host offset of the next cluster allocated, always the end of the image
+    next_cluster: u64,
+    /// Most recently used L2 table and its host offset
+    l2_cache: Option<(u64, Vec<u64>)>,
+}
+
+impl Qcow2Backend {
+    /// Opens the qcow2 image at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - Image to open
+    /// * `read_only` - Refuse writes; images with internal snapshots or stale refcounts
+    ///   can only be opened this way
+    ///
+    /// # Returns
+    /// * `Ok(Qcow2Backend)` on success
+    /// * `Err(VmError)` if the image couldn't be read, isn't a qcow2 image or uses an
+    ///   unsupported feature
+    pub fn open(path: &Path, read_only: bool) -> Result<Qcow2Backend, VmError> {
+        let file = match OpenOptions::new().read(true).write(!read_only).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path.display(), e), e))
+        };
+        Qcow2Backend::from_file(file, read_only)
+    }
+
+    /// Creates an empty qcow2 image of `size` bytes at `path`, replacing any file there,
+    /// and opens it for writing.
+    ///
+    /// # Returns
+    /// * `Ok(Qcow2Backend)` on success
+    /// * `Err(VmError)` if `size` isn't made of whole sectors or the image couldn't be written
+    pub fn create(path: &Path, size: u64) -> Result<Qcow2Backend, VmError> {
+        Qcow2Backend::create_with_cluster_bits(path, size, DEFAULT_CLUSTER_BITS)
+    }
+
+    fn create_with_cluster_bits(path: &Path, size: u64, cluster_bits: u32) -> Result<Qcow2Backend, VmError> {
+        if size == 0 || !size.is_multiple_of(SECTOR_SIZE) {
+            return Err(VmError::config(format!("qcow2 image size {} isn't made of whole sectors", size)));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_size = size.div_ceil(cluster_size * (cluster_size / 8));
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+        // Header, refcount table, one refcount block and the L1 table
+        let metadata_clusters = 3 + l1_clusters;
+
+        let mut header = vec![0u8; cluster_size as usize];
+        header[0..4].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        header[20..24].copy_from_slice(&cluster_bits.to_be_bytes());
+        header[24..32].copy_from_slice(&size.to_be_bytes());
+        header[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+        header[40..48].copy_from_slice(&(3 * cluster_size).to_be_bytes());
+        header[48..56].copy_from_slice(&cluster_size.to_be_bytes());
+        header[56..60].copy_from_slice(&1u32.to_be_bytes());
+        header[96..100].copy_from_slice(&DEFAULT_REFCOUNT_ORDER.to_be_bytes());
+        header[100..104].copy_from_slice(&V3_HEADER_LENGTH.to_be_bytes());
+        // The end of header extensions marker is all zeroes and already in place
+
+        let mut metadata = header;
+        metadata.resize((metadata_clusters * cluster_size) as usize, 0);
+        let refcount_table = cluster_size as usize;
+        metadata[refcount_table..refcount_table + 8].copy_from_slice(&(2 * cluster_size).to_be_bytes());
+        let refcount_block = 2 * cluster_size as usize;
+        for cluster in 0..metadata_clusters as usize {
+            metadata[refcount_block + 2 * cluster..refcount_block + 2 * cluster + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(VmError::io(format!("failed to create {}: {}", path.display(), e), e))
+        };
+        if let Err(e) = file.write_all(&metadata) {
+            return Err(VmError::io(format!("failed to write {}: {}", path.display(), e), e));
+        }
+        Qcow2Backend::from_file(file, false)
+    }
+
+    /// Reads the header and tables of an open image.
+    fn from_file(mut file: File, read_only: bool) -> Result<Qcow2Backend, VmError> {
+        let mut header = [0u8; V3_HEADER_LENGTH as usize];
+        if let Err(e) = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_exact(&mut header[..72])) {
+            return Err(VmError::io(format!("failed to read qcow2 header: {}", e), e));
+        }
+        let be32 = |header: &[u8], at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+        let be64 = |header: &[u8], at: usize| u64::from_be_bytes(header[at..at + 8].try_into().unwrap());
+        if be32(&header, 0) != QCOW2_MAGIC {
+            return Err(VmError::image("not a qcow2 image"));
+        }
+        let version = be32(&header, 4);
+        let (incompatible_features, refcount_order) = match version {
+            2 => (0, 4),
+            3 => {
+                if let Err(e) = file.read_exact(&mut header[72..]) {
+                    return Err(VmError::io(format!("failed to read qcow2 header: {}", e), e));
+                }
+                (be64(&header, 72), be32(&header, 96))
+            },
+            _ => return Err(VmError::image(format!("unsupported qcow2 version {}", version)))
+        };
+
+        let cluster_bits = be32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(VmError::image(format!("invalid qcow2 cluster size 2^{}", cluster_bits)));
+        }
+        if be64(&header, 8) != 0 {
+            return Err(VmError::image("qcow2 images with a backing file are not supported"));
+        }
+        if be32(&header, 32) != 0 {
+            return Err(VmError::image("encrypted qcow2 images are not supported"));
+        }
+        if incompatible_features & !INCOMPATIBLE_DIRTY != 0 {
+            return Err(VmError::image(format!("unsupported qcow2 features {:#x}", incompatible_features)));
+        }
+        if refcount_order > 6 {
+            return Err(VmError::image(format!("invalid qcow2 refcount width 2^{}", refcount_order)));
+        }
+        if !read_only && (refcount_order < 3 || be32(&header, 60) != 0 || incompatible_features & INCOMPATIBLE_DIRTY != 0) {
+            return Err(VmError::image("qcow2 image has snapshots, stale refcounts or sub-byte refcounts and can only be opened read-only"));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = be64(&header, 24);
+        let l1_size = be32(&header, 36) as u64;
+        if l1_size < size.div_ceil(cluster_size * (cluster_size / 8)) || l1_size * 8 > MAX_L1_TABLE_SIZE {
+            return Err(VmError::image(format!("qcow2 L1 table of {} entries doesn't fit the image size", l1_size)));
+        }
+        let refcount_table_clusters = be32(&header, 56) as u64;
+        if refcount_table_clusters * cluster_size > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(VmError::image("qcow2 refcount table is too large"));
+        }
+        let file_len = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(VmError::io(format!("failed to read qcow2 image size: {}", e), e))
+        };
+        // Both tables are read whole, so they must be in the file before anything is allocated for them
+        check_table_bounds("L1", be64(&header, 40), l1_size, file_len)?;
+        check_table_bounds("refcount", be64(&header, 48), refcount_table_clusters * cluster_size / 8, file_len)?;
+
+        let mut backend = Qcow2Backend {
+            file,
+            read_only,
+            cluster_bits,
+            cluster_size,
+            size,
+            l1_table_offset: be64(&header, 40),
+            l1_table: Vec::new(),
+            refcount_table_offset: be64(&header, 48),
+            refcount_table: Vec::new(),
+            refcount_order,
+            next_cluster: file_len.div_ceil(cluster_size) * cluster_size,
+            l2_cache: None,
+        };
+        backend.l1_table = backend.read_table(backend.l1_table_offset, l1_size)?;
+        backend.refcount_table = backend.read_table(backend.refcount_table_offset, refcount_table_clusters * cluster_size / 8)?;
+        Ok(backend)
+    }
+
+    /// Reads `count` big-endian entries of a table at `offset`.
+    fn read_table(&mut self, offset: u64, count: u64) -> Result<Vec<u64>, VmError> {
+        let mut bytes = vec![0u8; count as usize * 8];
+        self.read_host(offset, &mut bytes)?;
+        Ok(bytes.chunks_exact(8).map(|entry| u64::from_be_bytes(entry.try_into().unwrap())).collect())
+    }
+
+    /// Reads from the image file at a host offset.
+    fn read_host(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        if let Err(e) = self.file.seek(SeekFrom::Start(offset)).and_then(|_| self.file.read_exact(buf)) {
+            return Err(VmError::io(format!("failed to read {} bytes at qcow2 offset {}: {}", buf.len(), offset, e), e));
+        }
+        Ok(())
+    }
+
+    /// Writes to the image file at a host offset.
+    fn write_host(&mut self, offset: u64, buf: &[u8]) -> Result<(), VmError> {
+        if let Err(e) = self.file.seek(SeekFrom::Start(offset)).and_then(|_| self.file.write_all(buf)) {
+            return Err(VmError::io(format!("failed to write {} bytes at qcow2 offset {}: {}", buf.len(), offset, e), e));
+        }
+        Ok(())
+    }
+
+    /// Returns the L1 index and L2 index of a guest cluster.
+    fn get_table_indices(&self, guest_cluster: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+        ((guest_cluster >> l2_bits) as usize, (guest_cluster & ((1 << l2_bits) - 1)) as usize)
+    }
+
+    /// Loads the L2 table at `offset` into the cache.
+    fn load_l2_table(&mut self, offset: u64) -> Result<&mut Vec<u64>, VmError> {
+        if self.l2_cache.as_ref().map(|(cached, _)| *cached) != Some(offset) {
+            let table = self.read_table(offset, self.cluster_size / 8)?;
+            self.l2_cache = Some((offset, table));
+        }
+        Ok(&mut self.l2_cache.as_mut().unwrap().1)
+    }
+
+    /// Returns the L2 entry of a guest cluster, 0 if it has none.
+    fn get_l2_entry(&mut self, guest_cluster: u64) -> Result<u64, VmError> {
+        let (l1_index, l2_index) = self.get_table_indices(guest_cluster);
+        let l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.load_l2_table(l2_offset)?[l2_index])
+    }
+
+    /// Decodes an L2 entry.
+    fn get_cluster_data(&self, entry: u64) -> ClusterData {
+        if entry & FLAG_COMPRESSED != 0 {
+            // The offset takes the low bits, the count of additional sectors the rest
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & !(FLAG_COPIED | FLAG_COMPRESSED)) >> offset_bits) + 1;
+            return ClusterData::Compressed(offset, sectors * SECTOR_SIZE - offset % SECTOR_SIZE);
+        }
+        match entry & OFFSET_MASK {
+            _ if entry & FLAG_ZERO != 0 => ClusterData::Zero,
+            0 => ClusterData::Zero,
+            offset => ClusterData::Normal(offset),
+        }
+    }
+
+    /// Fills `buf` with the part of a guest cluster starting at `offset_in_cluster`.
+    fn read_cluster(&mut self, entry: u64, offset_in_cluster: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        match self.get_cluster_data(entry) {
+            ClusterData::Zero => buf.fill(0),
+            ClusterData::Normal(offset) => self.read_host(offset + offset_in_cluster, buf)?,
+            ClusterData::Compressed(offset, len) => {
+                let cluster = self.decompress_cluster(offset, len)?;
+                buf.copy_from_slice(&cluster[offset_in_cluster as usize..offset_in_cluster as usize + buf.len()]);
+            },
+        }
+        Ok(())
+    }
+
+    /// Inflates a compressed cluster; its data may end at the end of the image file.
+    fn decompress_cluster(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, VmError> {
+        let mut compressed = Vec::new();
+        if let Err(e) = self.file.seek(SeekFrom::Start(offset)).and_then(|_| (&self.file).take(len).read_to_end(&mut compressed)) {
+            return Err(VmError::io(format!("failed to read compressed cluster at qcow2 offset {}: {}", offset, e), e));
+        }
+        let mut cluster = vec![0u8; self.cluster_size as usize];
+        if let Err(e) = DeflateDecoder::new(&compressed[..]).read_exact(&mut cluster) {
+            return Err(VmError::io(format!("failed to inflate compressed cluster at qcow2 offset {}: {}", offset, e), e));
+        }
+        Ok(cluster)
+    }
+
+    /// Writes `data` into a guest cluster at `offset_in_cluster`, moving the cluster to
+    /// a fresh host cluster unless it can be written in place.
+    fn write_cluster(&mut self, guest_cluster: u64, offset_in_cluster: u64, data: &[u8]) -> Result<(), VmError> {
+        let entry = self.get_l2_entry(guest_cluster)?;
+        let in_place = entry & FLAG_COPIED != 0 && entry & FLAG_ZERO == 0;
+        if let (true, ClusterData::Normal(offset)) = (in_place, self.get_cluster_data(entry)) {
+            return self.write_host(offset + offset_in_cluster, data);
+        }
+
+        let mut cluster = vec![0u8; self.cluster_size as usize];
+        if data.len() as u64 != self.cluster_size {
+            self.read_cluster(entry, 0, &mut cluster)?;
+        }
+        cluster[offset_in_cluster as usize..offset_in_cluster as usize + data.len()].copy_from_slice(data);
+        // Data and refcount go first, so a crash before the L2 update merely leaks the cluster
+        let offset = self.allocate_cluster()?;
+        self.write_host(offset, &cluster)?;
+        self.set_l2_entry(guest_cluster, offset | FLAG_COPIED)?;
+        self.release_cluster(entry)
+    }
+
+    /// Points the L2 entry of a guest cluster at new data, allocating the L2 table if needed.
+    fn set_l2_entry(&mut self, guest_cluster: u64, entry: u64) -> Result<(), VmError> {
+        let (l1_index, l2_index) = self.get_table_indices(guest_cluster);
+        let mut l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+            self.write_host(l2_offset, &vec![0u8; self.cluster_size as usize])?;
+            self.l1_table[l1_index] = l2_offset | FLAG_COPIED;
+            self.write_host(self.l1_table_offset + 8 * l1_index as u64, &(l2_offset | FLAG_COPIED).to_be_bytes())?;
+        }
+        self.write_host(l2_offset + 8 * l2_index as u64, &entry.to_be_bytes())?;
+        self.load_l2_table(l2_offset)?[l2_index] = entry;
+        Ok(())
+    }
+
+    /// Drops the references of a replaced L2 entry to its host clusters.
+    fn release_cluster(&mut self, entry: u64) -> Result<(), VmError> {
+        let (first, last) = match self.get_cluster_data(entry) {
+            ClusterData::Normal(offset) => (offset, offset),
+            // Preallocated zero clusters keep their host cluster
+            ClusterData::Zero if entry & OFFSET_MASK != 0 => (entry & OFFSET_MASK, entry & OFFSET_MASK),
+            ClusterData::Compressed(offset, len) => (offset, offset + len - 1),
+            ClusterData::Zero => return Ok(()),
+        };
+        let mut cluster = first & !(self.cluster_size - 1);
+        while cluster <= last {
+            let refcount = self.get_refcount(cluster)?;
+            if refcount > 0 {
+                self.set_refcount(cluster, refcount - 1)?;
+            }
+            cluster += self.cluster_size;
+        }
+        Ok(())
+    }
+
+    /// Allocates a cluster at the end of the image with a refcount of one.
+    fn allocate_cluster(&mut self) -> Result<u64, VmError> {
+        let offset = self.next_cluster;
+        self.next_cluster += self.cluster_size;
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Returns the refcount table index and the byte offset within the refcount block
+    /// of the host cluster at `offset`.
+    fn get_refcount_position(&self, offset: u64) -> (usize, u64) {
+        let cluster = offset >> self.cluster_bits;
+        let entries_per_block = (self.cluster_size * 8) >> self.refcount_order;
+        let width = (1u64 << self.refcount_order) / 8;
+        ((cluster / entries_per_block) as usize, cluster % entries_per_block * width)
+    }
+
+    /// Returns the refcount of the host cluster at `offset`.
+    fn get_refcount(&mut self, offset: u64) -> Result<u64, VmError> {
+        let (table_index, position) = self.get_refcount_position(offset);
+        let block = self.refcount_table.get(table_index).map_or(0, |entry| entry & OFFSET_MASK);
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut bytes = vec![0u8; (1usize << self.refcount_order) / 8];
+        self.read_host(block + position, &mut bytes)?;
+        Ok(bytes.iter().fold(0, |refcount, &byte| (refcount << 8) | byte as u64))
+    }
+
+    /// Sets the refcount of the host cluster at `offset`, allocating refcount blocks
+    /// and growing the refcount table as needed.
+    fn set_refcount(&mut self, offset: u64, refcount: u64) -> Result<(), VmError> {
+        let (table_index, position) = self.get_refcount_position(offset);
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(table_index + 1)?;
+        }
+        let mut block = self.refcount_table[table_index] & OFFSET_MASK;
+        if block == 0 {
+            block = self.next_cluster;
+            self.next_cluster += self.cluster_size;
+            self.write_host(block, &vec![0u8; self.cluster_size as usize])?;
+            self.refcount_table[table_index] = block;
+            self.write_host(self.refcount_table_offset + 8 * table_index as u64, &block.to_be_bytes())?;
+            // The new block is usually the first user of itself
+            self.set_refcount(block, 1)?;
+        }
+        let width = (1usize << self.refcount_order) / 8;
+        let bytes = refcount.to_be_bytes();
+        self.write_host(block + position, &bytes[8 - width..])
+    }
+
+    /// Moves the refcount table to a larger one at the end of the image covering at
+    /// least `entries` refcount blocks.
+    fn grow_refcount_table(&mut self, entries: usize) -> Result<(), VmError> {
+        let entries = entries.max(self.refcount_table.len() * 2);
+        let clusters = (entries as u64 * 8).div_ceil(self.cluster_size);
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = (self.refcount_table.len() as u64 * 8).div_ceil(self.cluster_size);
+
+        let offset = self.next_cluster;
+        self.next_cluster += clusters * self.cluster_size;
+        self.refcount_table.resize((clusters * self.cluster_size / 8) as usize, 0);
+        let table: Vec<u8> = self.refcount_table.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+        self.write_host(offset, &table)?;
+        let mut header = [0u8; 12];
+        header[..8].copy_from_slice(&offset.to_be_bytes());
+        header[8..].copy_from_slice(&(clusters as u32).to_be_bytes());
+        self.write_host(48, &header)?;
+        self.refcount_table_offset = offset;
+
+        for cluster in 0..clusters {
+            self.set_refcount(offset + cluster * self.cluster_size, 1)?;
+        }
+        for cluster in 0..old_clusters {
+            self.set_refcount(old_offset + cluster * self.cluster_size, 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl DiskBackend for Qcow2Backend {
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        let range = get_byte_range(self.capacity(), sector, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = range.start + done as u64;
+            let offset_in_cluster = offset & (self.cluster_size - 1);
+            let len = ((self.cluster_size - offset_in_cluster) as usize).min(buf.len() - done);
+            let entry = self.get_l2_entry(offset >> self.cluster_bits)?;
+            self.read_cluster(entry, offset_in_cluster, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), VmError> {
+        if self.read_only {
+            return Err(VmError::device("qcow2 image is opened read-only"));
+        }
+        let range = get_byte_range(self.capacity(), sector, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = range.start + done as u64;
+            let offset_in_cluster = offset & (self.cluster_size - 1);
+            let len = ((self.cluster_size - offset_in_cluster) as usize).min(buf.len() - done);
+            self.write_cluster(offset >> self.cluster_bits, offset_in_cluster, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), VmError> {
+        if self.read_only {
+            return Ok(());
+        }
+        match self.file.sync_data() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to flush qcow2 image: {}", e), e))
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.size / SECTOR_SIZE
+    }
+}
+
+/// Checks that a table of `count` entries at `offset` lies within an image file of `file_len` bytes.
+fn check_table_bounds(name: &str, offset: u64, count: u64, file_len: u64) -> Result<(), VmError> {
+    match count.checked_mul(8).and_then(|len| offset.checked_add(len)) {
+        Some(end) if end <= file_len => Ok(()),
+        _ => Err(VmError::image(format!("qcow2 {} table of {} entries at offset {} is past the end of the image", name, count, offset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+
+    // Helper: checks that every host cluster has exactly the refcount the metadata
+    // accounts for
+    fn check_refcounts(backend: &mut Qcow2Backend) {
+        let cluster_size = backend.cluster_size;
+        let mut expected: HashMap<u64, u64> = HashMap::new();
+        let add_range = |expected: &mut HashMap<u64, u64>, offset: u64, len: u64| {
+            let mut cluster = offset / cluster_size;
+            while cluster * cluster_size < offset + len {
+                *expected.entry(cluster).or_default() += 1;
+                cluster += 1;
+            }
+        };
+        add_range(&mut expected, 0, cluster_size);
+        add_range(&mut expected, backend.l1_table_offset, backend.l1_table.len() as u64 * 8);
+        add_range(&mut expected, backend.refcount_table_offset, backend.refcount_table.len() as u64 * 8);
+        for block in backend.refcount_table.clone() {
+            if block != 0 {
+                add_range(&mut expected, block & OFFSET_MASK, cluster_size);
+            }
+        }
+        for l1_entry in backend.l1_table.clone() {
+            let l2_offset = l1_entry & OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            add_range(&mut expected, l2_offset, cluster_size);
+            for entry in backend.read_table(l2_offset, cluster_size / 8).unwrap() {
+                match backend.get_cluster_data(entry) {
+                    ClusterData::Normal(offset) => add_range(&mut expected, offset, cluster_size),
+                    ClusterData::Compressed(offset, len) => add_range(&mut expected, offset, len),
+                    ClusterData::Zero if entry & OFFSET_MASK != 0 => add_range(&mut expected, entry & OFFSET_MASK, cluster_size),
+                    ClusterData::Zero => {},
+                }
+            }
+        }
+        for cluster in 0..backend.next_cluster / cluster_size {
+            let refcount = backend.get_refcount(cluster * cluster_size).unwrap();
+            assert_eq!(refcount, expected.get(&cluster).copied().unwrap_or(0), "refcount of cluster {}", cluster);
+        }
+    }
+
+    #[test]
+    fn test_create_write_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let mut backend = Qcow2Backend::create(&path, 1 << 30).expect("Create should succeed");
+        assert_eq!(backend.capacity(), (1 << 30) / SECTOR_SIZE);
+        check_refcounts(&mut backend);
+
+        // A write straddling two clusters, and one near the end of the disk
+        let data: Vec<u8> = (0..4096).map(|i| (i % 253) as u8).collect();
+        backend.write_sectors(126, &data).expect("Write should succeed");
+        backend.write_sectors(backend.capacity() - 1, &[0x77; 512]).expect("Write should succeed");
+        backend.write_sectors(127, &[0x11; 512]).expect("Write should succeed");
+        assert!(backend.write_sectors(backend.capacity(), &[0; 512]).is_err());
+        backend.flush().unwrap();
+        check_refcounts(&mut backend);
+        drop(backend);
+        // The metadata created up front, two L2 tables and three data clusters
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 9 * (1 << 16));
+
+        let mut backend = Qcow2Backend::open(&path, true).expect("Open should succeed");
+        let mut read = vec![0u8; 4096];
+        backend.read_sectors(126, &mut read).unwrap();
+        assert_eq!(read[..512], data[..512]);
+        assert_eq!(read[512..1024], [0x11; 512]);
+        assert_eq!(read[1024..], data[1024..]);
+        backend.read_sectors(backend.capacity() - 1, &mut read[..512]).unwrap();
+        assert_eq!(read[..512], [0x77; 512]);
+        backend.read_sectors(1000, &mut read).unwrap();
+        assert!(read.iter().all(|&byte| byte == 0));
+        assert!(backend.write_sectors(0, &[0; 512]).is_err());
+    }
+
+    #[test]
+    fn test_refcount_table_growth() {
+        // 512-byte clusters: a refcount block covers 128 KiB and the table 8 MiB
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("small.qcow2");
+        let mut backend = Qcow2Backend::create_with_cluster_bits(&path, 16 << 20, 9).unwrap();
+        let old_table = backend.refcount_table_offset;
+        for chunk in 0..(12 << 20) / 8192u64 {
+            backend.write_sectors(chunk * 16, &vec![chunk as u8 | 1; 8192]).unwrap();
+        }
+        assert_ne!(backend.refcount_table_offset, old_table);
+        check_refcounts(&mut backend);
+        drop(backend);
+
+        let mut backend = Qcow2Backend::open(&path, false).unwrap();
+        let mut read = vec![0u8; 8192];
+        for chunk in [0u64, 700, 1535] {
+            backend.read_sectors(chunk * 16, &mut read).unwrap();
+            assert!(read.iter().all(|&byte| byte == chunk as u8 | 1));
+        }
+    }
+
+    #[test]
+    fn test_compressed_and_zero_clusters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compressed.qcow2");
+        let mut backend = Qcow2Backend::create(&path, 4 << 20).unwrap();
+        let cluster: Vec<u8> = (0..1 << 16).map(|i| (i / 512) as u8).collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&cluster).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        // Compressed data packed right after a 100 byte gap, as qemu packs them
+        let offset = backend.allocate_cluster().unwrap() + 100;
+        backend.write_host(offset, &compressed).unwrap();
+        let sectors = (offset % 512 + compressed.len() as u64).div_ceil(512);
+        backend.set_l2_entry(1, FLAG_COMPRESSED | (sectors - 1) << (62 - 8) | offset).unwrap();
+        // A version 3 zero cluster over data that must not show through
+        backend.write_sectors(256, &[0xee; 512]).unwrap();
+        let entry = backend.get_l2_entry(2).unwrap();
+        backend.set_l2_entry(2, entry | FLAG_ZERO).unwrap();
+        check_refcounts(&mut backend);
+
+        let mut read = vec![0u8; 1 << 16];
+        backend.read_sectors(128, &mut read).unwrap();
+        assert_eq!(read, cluster);
+        backend.read_sectors(256, &mut read[..512]).unwrap();
+        assert_eq!(read[..512], [0; 512]);
+
+        // Writes move both to fresh clusters, releasing the old ones
+        backend.write_sectors(129, &[0x55; 512]).unwrap();
+        backend.write_sectors(257, &[0x66; 512]).unwrap();
+        check_refcounts(&mut backend);
+        backend.read_sectors(128, &mut read).unwrap();
+        assert_eq!(read[..512], cluster[..512]);
+        assert_eq!(read[512..1024], [0x55; 512]);
+        assert_eq!(read[1024..], cluster[1024..]);
+        backend.read_sectors(256, &mut read[..1024]).unwrap();
+        assert_eq!(read[..512], [0; 512]);
+        assert_eq!(read[512..1024], [0x66; 512]);
+    }
+
+    #[test]
+    fn test_open_refuses_unsupported_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("raw.img");
+        std::fs::write(&raw, vec![0u8; 4096]).unwrap();
+        assert!(Qcow2Backend::open(&raw, true).is_err());
+        assert!(Qcow2Backend::open(&dir.path().join("missing.qcow2"), true).is_err());
+
+        let path = dir.path().join("backed.qcow2");
+        drop(Qcow2Backend::create(&path, 1 << 20).unwrap());
+        let mut image = std::fs::read(&path).unwrap();
+        image[8..16].copy_from_slice(&512u64.to_be_bytes());
+        std::fs::write(&path, &image).unwrap();
+        assert!(Qcow2Backend::open(&path, true).is_err());
+
+        // Snapshots make the image read-only
+        image[8..16].copy_from_slice(&0u64.to_be_bytes());
+        image[60..64].copy_from_slice(&1u32.to_be_bytes());
+        std::fs::write(&path, &image).unwrap();
+        assert!(Qcow2Backend::open(&path, false).is_err());
+        assert!(Qcow2Backend::open(&path, true).is_ok());
+        assert!(Qcow2Backend::create(&path, 1000).is_err());
+    }
+
+    #[test]
+    fn test_open_refuses_oversized_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tables.qcow2");
+        drop(Qcow2Backend::create(&path, 1 << 20).unwrap());
+        let image = std::fs::read(&path).unwrap();
+        let open_with = |at: usize, field: &[u8]| {
+            let mut crafted = image.clone();
+            crafted[at..at + field.len()].copy_from_slice(field);
+            std::fs::write(&path, &crafted).unwrap();
+            Qcow2Backend::open(&path, true)
+        };
+
+        // A refcount table of 2^16 clusters of 2 MiB, then one above the QEMU limit
+        let mut huge_clusters = image.clone();
+        huge_clusters[20..24].copy_from_slice(&21u32.to_be_bytes());
+        huge_clusters[56..60].copy_from_slice(&(1u32 << 16).to_be_bytes());
+        std::fs::write(&path, &huge_clusters).unwrap();
+        assert!(Qcow2Backend::open(&path, true).is_err());
+        assert!(open_with(56, &129u32.to_be_bytes()).is_err());
+        // An L1 table above the QEMU limit, then tables past the end of the file
+        assert!(open_with(36, &(1u32 << 23).to_be_bytes()).is_err());
+        assert!(open_with(40, &(1u64 << 40).to_be_bytes()).is_err());
+        assert!(open_with(48, &u64::MAX.to_be_bytes()).is_err());
+        assert!(open_with(56, &128u32.to_be_bytes()).is_err());
+        assert!(open_with(56, &1u32.to_be_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::device_emulation::block_device::disk_backend::{get_byte_range, DiskBackend, SECTOR_SIZE};
+use crate::error::VmError;
+use crate::vm_manager::bundle::is_valid_name;
+
+/// Sectors saved at once when a snapshotted block is first overwritten.
+pub const SNAPSHOT_BLOCK_SECTORS: u64 = 128;
+/// Extension of the undo logs of snapshots.
+pub const UNDO_LOG_EXTENSION: &str = "undo";
+
+/// Snapshot taken by a `SnapshotDisk`.
+struct Snapshot {
+    name: String,
+    /// Undo log holding the blocks as they were when the snapshot was taken
+    log: File,
+    path: PathBuf,
+    /// Offset of every saved block in the log, by block index
+    blocks: HashMap<u64, u64>,
+    /// End of the log
+    len: u64,
+}
+
+/// Disk that can capture its state as named snapshots and roll back to them.
+///
+/// Snapshots are incremental: taking one costs nothing, and the first write to a
+/// block after the latest snapshot saves the block's previous content to the
+/// snapshot's undo log, a file in the snapshot directory. Reverting writes the saved
+/// blocks back, newest snapshot first, and drops the snapshots taken after the one
+/// reverted to. The undo logs are deleted when the disk is dropped.
+///
+/// Requests always go through the wrapped disk, so a mapped image loses its
+/// zero-copy path while wrapped.
+pub struct SnapshotDisk {
+    disk: Box<dyn DiskBackend + Send>,
+    /// Directory holding the undo logs
+    directory: PathBuf,
+    /// Snapshots in the order they were taken
+    snapshots: Vec<Snapshot>,
+}
+
+impl SnapshotDisk {
+    /// Wraps `disk`, keeping undo logs in `directory`, e.g. `VmDirectory::get_snapshots_dir`.
+    pub fn new(disk: Box<dyn DiskBackend + Send>, directory: impl Into<PathBuf>) -> SnapshotDisk {
+        SnapshotDisk { disk, directory: directory.into(), snapshots: Vec::new() }
+    }
+
+    /// Returns the names of the snapshots, oldest first.
+    pub fn list_snapshots(&self) -> Vec<String> {
+        self.snapshots.iter().map(|snapshot| snapshot.name.clone()).collect()
+    }
+
+    /// Returns the number of bytes saved in the undo logs.
+    pub fn get_undo_size(&self) -> u64 {
+        self.snapshots.iter().map(|snapshot| snapshot.len).sum()
+    }
+
+    /// Saves the blocks covered by a write of `len` bytes at `sector` to the undo
+    /// log of the latest snapshot, unless they are saved already.
+    fn save_blocks(&mut self, sector: u64, len: usize) -> Result<(), VmError> {
+        let capacity = self.disk.capacity();
+        let range = get_byte_range(capacity, sector, len)?;
+        let snapshot = match self.snapshots.last_mut() {
+            Some(snapshot) => snapshot,
+            None => return Ok(())
+        };
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let first = range.start / SECTOR_SIZE / SNAPSHOT_BLOCK_SECTORS;
+        let last = (range.end / SECTOR_SIZE - 1) / SNAPSHOT_BLOCK_SECTORS;
+        let mut buffer = Vec::new();
+        for block in first..=last {
+            if snapshot.blocks.contains_key(&block) {
+                continue;
+            }
+            let block_sector = block * SNAPSHOT_BLOCK_SECTORS;
+            let sectors = SNAPSHOT_BLOCK_SECTORS.min(capacity - block_sector);
+            buffer.resize((sectors * SECTOR_SIZE) as usize, 0);
+            self.disk.read_sectors(block_sector, &mut buffer)?;
+            if let Err(e) = snapshot.log.seek(SeekFrom::Start(snapshot.len)).and_then(|_| snapshot.log.write_all(&buffer)) {
+                return Err(VmError::io(format!("failed to write undo log {}: {}", snapshot.path.display(), e), e));
+            }
+            snapshot.blocks.insert(block, snapshot.len);
+            snapshot.len += buffer.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the blocks saved in an undo log back to the disk.
+    fn apply_undo_log(&mut self, index: usize) -> Result<(), VmError> {
+        let capacity = self.disk.capacity();
+        let snapshot = &mut self.snapshots[index];
+        let mut buffer = Vec::new();
+        for (&block, &offset) in &snapshot.blocks {
+            let block_sector = block * SNAPSHOT_BLOCK_SECTORS;
+            let sectors = SNAPSHOT_BLOCK_SECTORS.min(capacity - block_sector);
+            buffer.resize((sectors * SECTOR_SIZE) as usize, 0);
+            if let Err(e) = snapshot.log.seek(SeekFrom::Start(offset)).and_then(|_| snapshot.log.read_exact(&mut buffer)) {
+                return Err(VmError::io(format!("failed to read undo log {}: {}", snapshot.path.display(), e), e));
+            }
+            self.disk.write_sectors(block_sector, &buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl DiskBackend for SnapshotDisk {
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        self.disk.read_sectors(sector, buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), VmError> {
+        self.save_blocks(sector, buf.len())?;
+        self.disk.write_sectors(sector, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), VmError> {
+        self.disk.flush()
+    }
+
+    fn capacity(&self) -> u64 {
+        self.disk.capacity()
+    }
+
+    fn flush_sectors(&mut self, sector: u64, count: u64) -> Result<(), VmError> {
+        self.disk.flush_sectors(sector, count)
+    }
+
+    fn snapshot(&mut self, name: &str) -> Result<(), VmError> {
+        if !is_valid_name(name) {
+            return Err(VmError::config(format!("invalid snapshot name {:?}", name)));
+        }
+        if self.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(VmError::config(format!("snapshot {} already exists", name)));
+        }
+
+        let path = get_undo_log_path(&self.directory, name);
+        let log = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path) {
+            Ok(log) => log,
+            Err(e) => return Err(VmError::io(format!("failed to create undo log {}: {}", path.display(), e), e))
+        };
+        self.snapshots.push(Snapshot { name: name.to_string(), log, path, blocks: HashMap::new(), len: 0 });
+        Ok(())
+    }
+
+    fn revert(&mut self, name: &str) -> Result<(), VmError> {
+        let index = match self.snapshots.iter().position(|snapshot| snapshot.name == name) {
+            Some(index) => index,
+            None => return Err(VmError::config(format!("snapshot {} doesn't exist", name)))
+        };
+
+        for newer in (index..self.snapshots.len()).rev() {
+            self.apply_undo_log(newer)?;
+        }
+        self.disk.flush()?;
+
+        for snapshot in self.snapshots.drain(index + 1..) {
+            let _ = remove_file(&snapshot.path);
+        }
+        let snapshot = &mut self.snapshots[index];
+        if let Err(e) = snapshot.log.set_len(0) {
+            return Err(VmError::io(format!("failed to truncate undo log {}: {}", snapshot.path.display(), e), e));
+        }
+        snapshot.blocks.clear();
+        snapshot.len = 0;
+        Ok(())
+    }
+}
+
+impl Drop for SnapshotDisk {
+    fn drop(&mut self) {
+        for snapshot in &self.snapshots {
+            let _ = remove_file(&snapshot.path);
+        }
+    }
+}
+
+/// Returns the path of the undo log of the snapshot `name` in `directory`.
+pub fn get_undo_log_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(format!("{}.{}", name, UNDO_LOG_EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::block_device::disk_backend::FileBackend;
+    use tempfile::TempDir;
+
+    // Helper: reads `len` bytes at `sector`
+    fn read(disk: &mut SnapshotDisk, sector: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.read_sectors(sector, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_snapshot_and_revert() {
+        let dir = TempDir::new().unwrap();
+        let image = dir.path().join("disk.img");
+        // 2.5 blocks, so the last block is partial
+        std::fs::write(&image, vec![1u8; (SNAPSHOT_BLOCK_SECTORS * SECTOR_SIZE * 5 / 2) as usize]).unwrap();
+        let mut disk = SnapshotDisk::new(Box::new(FileBackend::open(&image).unwrap()), dir.path());
+
+        // Writes before the first snapshot aren't logged
+        disk.write_sectors(0, &[2u8; 512]).unwrap();
+        assert_eq!(disk.get_undo_size(), 0);
+
+        disk.snapshot("clean").expect("Snapshot should succeed");
+        assert!(disk.snapshot("clean").is_err());
+        assert!(disk.snapshot("../clean").is_err());
+        disk.write_sectors(0, &[3u8; 1024]).unwrap();
+        disk.write_sectors(1, &[4u8; 512]).unwrap();
+        assert_eq!(disk.get_undo_size(), SNAPSHOT_BLOCK_SECTORS * SECTOR_SIZE, "A block is saved once");
+
+        disk.snapshot("configured").unwrap();
+        disk.write_sectors(2 * SNAPSHOT_BLOCK_SECTORS - 1, &[5u8; 1024]).unwrap();
+        assert_eq!(disk.list_snapshots(), vec!["clean".to_string(), "configured".to_string()]);
+        assert!(get_undo_log_path(dir.path(), "configured").exists());
+
+        disk.revert("configured").expect("Revert should succeed");
+        assert_eq!(read(&mut disk, 0, 1024), [[3u8; 512], [4u8; 512]].concat());
+        assert_eq!(read(&mut disk, 2 * SNAPSHOT_BLOCK_SECTORS - 1, 1024), vec![1u8; 1024]);
+
+        // Reverting to an older snapshot drops the newer ones
+        disk.write_sectors(0, &[6u8; 512]).unwrap();
+        disk.revert("clean").unwrap();
+        assert_eq!(read(&mut disk, 0, 1024), [[2u8; 512], [1u8; 512]].concat());
+        assert_eq!(disk.list_snapshots(), vec!["clean".to_string()]);
+        assert!(!get_undo_log_path(dir.path(), "configured").exists());
+        assert!(disk.revert("configured").is_err());
+
+        // A snapshot can be reverted to again
+        disk.write_sectors(0, &[7u8; 512]).unwrap();
+        disk.revert("clean").unwrap();
+        assert_eq!(read(&mut disk, 0, 512), vec![2u8; 512]);
+
+        drop(disk);
+        assert!(!get_undo_log_path(dir.path(), "clean").exists());
+        assert_eq!(std::fs::read(&image).unwrap()[..512], [2u8; 512]);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::error::VmError;
+
+/// Backing storage abstraction used by block devices to access disk image contents.
+///
+/// Implementations translate byte-addressed reads and writes into operations on
+/// whatever actually holds the disk data (a memory-mapped file, an io_uring-driven
+/// file descriptor, ...). Offsets are always relative to the start of the image.
+/// Every `StorageBackend` is also a `DiskBackend`, so block devices can use it directly.
+pub trait StorageBackend {
+    /// Reads exactly `buf.len()` bytes starting at byte `offset` of the backing storage.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the whole buffer was filled
+    /// * `Err(VmError)` if the read failed or was short
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VmError>;
+
+    /// Writes all of `buf` starting at byte `offset` of the backing storage.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the whole buffer was written
+    /// * `Err(VmError)` if the write failed or was short
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), VmError>;
+
+    /// Flushes any buffered writes down to stable storage.
+    fn flush(&mut self) -> Result<(), VmError>;
+
+    /// Returns the size of the backing storage in bytes.
+    fn size(&self) -> u64;
+}
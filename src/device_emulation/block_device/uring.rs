@@ -0,0 +1,560 @@
+//! io_uring-based storage backend for virtio block devices on Linux.
+//!
+//! Disk I/O is submitted through an io_uring instance instead of touching a
+//! memory-mapped image, which avoids page-fault stalls and gets close to native
+//! throughput on NVMe-backed images. Optionally the ring uses a set of buffers
+//! registered with the kernel (READ_FIXED/WRITE_FIXED) and kernel-side submission
+//! queue polling (SQPOLL) for busy hosts, and can bypass the host page cache with
+//! `O_DIRECT`.
+
+use std::alloc::Layout;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr::NonNull;
+use io_uring::{IoUring, opcode, squeue, types};
+use super::storage_backend::StorageBackend;
+use crate::error::VmError;
+
+/// Alignment of buffers, offsets and lengths of `O_DIRECT` I/O; covers both 512 byte
+/// and 4 KiB logical block sizes.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Tuning options for an [`IoUringBackend`].
+#[derive(Debug, Clone)]
+pub struct IoUringOptions {
+    /// Number of submission queue entries allocated for the ring, i.e. how many
+    /// operations are in flight at most.
+    pub queue_entries: u32,
+    /// Enables kernel-side submission queue polling; the value is the idle time in
+    /// milliseconds after which the polling thread goes to sleep.
+    pub sq_poll_idle_ms: Option<u32>,
+    /// Pins the submission queue polling thread to the given host CPU.
+    /// Only used when `sq_poll_idle_ms` is set.
+    pub sq_poll_cpu: Option<u32>,
+    /// Number of fixed buffers registered with the kernel. `0` disables registered
+    /// buffers and I/O is performed directly on the caller's buffers.
+    pub registered_buffer_count: u16,
+    /// Size in bytes of each registered buffer.
+    pub registered_buffer_size: usize,
+    /// Opens the image with `O_DIRECT`, so its data doesn't pass through the host page
+    /// cache. The image size and the registered buffer size must then be multiples of
+    /// `DIRECT_IO_ALIGNMENT`; unaligned accesses are read-modify-written in whole blocks.
+    pub direct_io: bool,
+}
+
+impl Default for IoUringOptions {
+    fn default() -> Self {
+        IoUringOptions {
+            queue_entries: 128,
+            sq_poll_idle_ms: None,
+            sq_poll_cpu: None,
+            registered_buffer_count: 8,
+            registered_buffer_size: 128 * 1024,
+            direct_io: false,
+        }
+    }
+}
+
+/// Storage backend performing disk image I/O through io_uring.
+pub struct IoUringBackend {
+    /// The io_uring instance. Declared before `buffers` so the ring (and its buffer
+    /// registration) is torn down before the buffers are freed.
+    ring: IoUring,
+    /// Buffers registered with the ring, indexed by their registration index
+    buffers: Vec<AlignedBuffer>,
+    /// Open disk image file
+    file: File,
+    /// Size of the disk image in bytes
+    size: u64,
+    /// Whether the image was opened with `O_DIRECT`
+    direct_io: bool,
+    /// Set once submitting failed; the entries left in the submission queue refer to
+    /// buffers that are gone, so the ring must never submit again
+    poisoned: bool,
+}
+
+/// Zero-initialized heap buffer aligned to `DIRECT_IO_ALIGNMENT`.
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the buffer exclusively owns its allocation
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocates a zeroed buffer of `len` bytes.
+    fn new(len: usize) -> Result<AlignedBuffer, VmError> {
+        let layout = match Layout::from_size_align(len.max(1), DIRECT_IO_ALIGNMENT) {
+            Ok(layout) => layout,
+            Err(e) => return Err(VmError::device(format!("invalid I/O buffer size {}: {}", len, e)))
+        };
+        // SAFETY: the layout has a non-zero size
+        match NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) }) {
+            Some(ptr) => Ok(AlignedBuffer { ptr, len }),
+            None => Err(VmError::device(format!("failed to allocate a {} byte I/O buffer", len)))
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` initialized bytes owned by the buffer
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: as in `as_slice`, and `&mut self` makes the access exclusive
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with this very layout
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), Layout::from_size_align_unchecked(self.len.max(1), DIRECT_IO_ALIGNMENT)) };
+    }
+}
+
+impl IoUringBackend {
+    /// Opens the disk image at `path` and sets up an io_uring instance for it.
+    ///
+    /// # Arguments
+    /// * `path` - Path to a raw disk image file
+    /// * `options` - Ring and buffer configuration
+    ///
+    /// # Returns
+    /// * `Ok(Self)` on success
+    /// * `Err(VmError)` if the options are inconsistent, the file can't be opened, the
+    ///   ring can't be created or the buffers can't be registered
+    pub fn new(path: &str, options: IoUringOptions) -> Result<Self, VmError> {
+        if options.queue_entries == 0 {
+            return Err(VmError::device("io_uring queue depth must be greater than zero"));
+        }
+        if options.registered_buffer_count > 0 && options.registered_buffer_size == 0 {
+            return Err(VmError::device("registered buffer size must be greater than zero"));
+        }
+        if options.direct_io && !options.registered_buffer_size.is_multiple_of(DIRECT_IO_ALIGNMENT) {
+            return Err(VmError::device(format!("registered buffer size must be a multiple of {} for direct I/O", DIRECT_IO_ALIGNMENT)));
+        }
+
+        // Open the image for both reading and writing
+        let mut open_options = OpenOptions::new();
+        open_options.read(true).write(true);
+        if options.direct_io {
+            open_options.custom_flags(libc::O_DIRECT);
+        }
+        let file = match open_options.open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(VmError::io(format!("Failed to open disk image: {}", e), e))
+        };
+
+        let size = match file.metadata() {
+            Ok(m) => m.len(),
+            Err(e) => return Err(VmError::io(format!("Failed to read disk image metadata: {}", e), e))
+        };
+        if options.direct_io && !size.is_multiple_of(DIRECT_IO_ALIGNMENT as u64) {
+            return Err(VmError::device(format!("disk image size must be a multiple of {} for direct I/O", DIRECT_IO_ALIGNMENT)));
+        }
+
+        // Configure submission queue polling if requested
+        let mut builder = IoUring::builder();
+        if let Some(idle) = options.sq_poll_idle_ms {
+            builder.setup_sqpoll(idle);
+            if let Some(cpu) = options.sq_poll_cpu {
+                builder.setup_sqpoll_cpu(cpu);
+            }
+        }
+
+        let ring = match builder.build(options.queue_entries) {
+            Ok(r) => r,
+            Err(e) => return Err(VmError::io(format!("Failed to create io_uring instance: {}", e), e))
+        };
+
+        // Allocate the fixed buffers; their heap allocations never move after this point
+        let mut buffers: Vec<AlignedBuffer> = Vec::with_capacity(options.registered_buffer_count as usize);
+        for _ in 0..options.registered_buffer_count {
+            buffers.push(AlignedBuffer::new(options.registered_buffer_size)?);
+        }
+
+        if !buffers.is_empty() {
+            let iovecs: Vec<libc::iovec> = buffers
+                .iter_mut()
+                .map(|b| libc::iovec { iov_base: b.as_mut_slice().as_mut_ptr() as *mut _, iov_len: b.len })
+                .collect();
+
+            // SAFETY: the buffers are owned by the backend and outlive the ring registration
+            if let Err(e) = unsafe { ring.submitter().register_buffers(&iovecs) } {
+                return Err(VmError::io(format!("Failed to register io_uring buffers: {}", e), e));
+            }
+        }
+
+        Ok(IoUringBackend { ring, buffers, file, size, direct_io: options.direct_io, poisoned: false })
+    }
+
+    /// Validates that `len` bytes starting at `offset` lie within the disk image.
+    fn check_bounds(&self, offset: u64, len: usize) -> Result<(), VmError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(VmError::device(format!("access of {} bytes at offset {} is out of disk image bounds", len, offset)))
+        }
+    }
+
+    /// Returns the block-aligned range `[start, end)` covering `len` bytes at `offset`.
+    fn get_aligned_range(offset: u64, len: usize) -> (u64, u64) {
+        let alignment = DIRECT_IO_ALIGNMENT as u64;
+        let start = offset / alignment * alignment;
+        let end = (offset + len as u64).div_ceil(alignment) * alignment;
+        (start, end)
+    }
+
+    /// Whether `buf` at `offset` can be handed to `O_DIRECT` I/O as it is.
+    ///
+    /// Registered buffers are aligned, so only the caller's buffer address matters
+    /// when they aren't used.
+    fn is_direct_io_aligned(&self, offset: u64, buf: &[u8]) -> bool {
+        let aligned = |value: u64| value.is_multiple_of(DIRECT_IO_ALIGNMENT as u64);
+        aligned(offset) && aligned(buf.len() as u64) && (!self.buffers.is_empty() || aligned(buf.as_ptr() as u64))
+    }
+
+    /// Largest transfer of a single operation, a multiple of `DIRECT_IO_ALIGNMENT`.
+    fn chunk_size(&self) -> usize {
+        match self.buffers.first() {
+            Some(b) => b.len,
+            None => u32::MAX as usize / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT
+        }
+    }
+
+    /// Maximum number of operations submitted together in one batch.
+    fn batch_size(&self) -> usize {
+        let entries = self.ring.params().sq_entries() as usize;
+        if self.buffers.is_empty() {
+            entries
+        } else {
+            self.buffers.len().min(entries)
+        }
+    }
+
+    /// Pushes the given submission entries, waits for all of them to complete and
+    /// checks that each one transferred exactly the expected number of bytes.
+    ///
+    /// The user data of every entry must be its index in `batch`. Errors are only
+    /// returned once every entry that reached the kernel has completed, so none of
+    /// them still refers to a buffer when the caller reuses it.
+    fn submit_batch(&mut self, batch: &[(squeue::Entry, u32)]) -> Result<(), VmError> {
+        if self.poisoned {
+            return Err(VmError::device("io_uring ring is unusable after a failed submission"));
+        }
+        let mut failure: Option<VmError> = None;
+        let mut pushed = 0;
+        for (entry, _) in batch {
+            // SAFETY: every buffer referenced by the entries stays alive until completion below
+            if let Err(e) = unsafe { self.ring.submission().push(entry) } {
+                failure = Some(VmError::device(format!("Failed to push io_uring submission: {:?}", e)));
+                break;
+            }
+            pushed += 1;
+        }
+
+        // The entries pushed before a push failure are still submitted and reaped, with
+        // SQPOLL the kernel may have picked them up already
+        let mut completed = 0;
+        while completed < pushed {
+            if let Err(e) = self.ring.submit_and_wait(pushed - completed) {
+                match e.raw_os_error() {
+                    // Interrupted, or short of resources until completions are reaped below
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => {},
+                    _ => {
+                        // Any other error means the ring is unusable. The entries left in the
+                        // submission queue would be handed to the kernel by the next submit, after
+                        // their buffers are gone, so the ring is poisoned; those that reached the
+                        // kernel are waited for
+                        self.poisoned = true;
+                        let in_flight = pushed - completed - self.ring.submission().len();
+                        failure.get_or_insert(VmError::io(format!("Failed to submit io_uring operations: {}", e), e));
+                        if in_flight == 0 {
+                            break;
+                        }
+                        std::thread::yield_now();
+                    }
+                }
+            }
+
+            for cqe in self.ring.completion() {
+                completed += 1;
+                let expected = match batch.get(cqe.user_data() as usize) {
+                    Some((_, len)) => *len,
+                    None => continue
+                };
+                if cqe.result() < 0 {
+                    let e = std::io::Error::from_raw_os_error(-cqe.result());
+                    failure = Some(VmError::io(format!("io_uring operation failed: {}", e), e));
+                } else if cqe.result() as u32 != expected {
+                    failure = Some(VmError::device(format!("short io_uring transfer: {} of {} bytes", cqe.result(), expected)));
+                }
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+
+    /// Reads `buf.len()` bytes at `offset` as they are; with `O_DIRECT` both must be aligned.
+    fn read_raw(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let batch_size = self.batch_size();
+        let chunk_size = self.chunk_size();
+
+        let mut done = 0usize;
+        while done < buf.len() {
+            // (buffer index, position in `buf`, length) of every chunk in this batch
+            let mut chunks: Vec<(usize, usize, usize)> = Vec::with_capacity(batch_size);
+            let mut batch: Vec<(squeue::Entry, u32)> = Vec::with_capacity(batch_size);
+            while done < buf.len() && batch.len() < batch_size {
+                let len = chunk_size.min(buf.len() - done);
+                let index = batch.len();
+                let entry = if self.buffers.is_empty() {
+                    opcode::Read::new(fd, buf[done..].as_mut_ptr(), len as u32)
+                        .offset(offset + done as u64)
+                        .build()
+                } else {
+                    opcode::ReadFixed::new(fd, self.buffers[index].as_mut_slice().as_mut_ptr(), len as u32, index as u16)
+                        .offset(offset + done as u64)
+                        .build()
+                };
+                batch.push((entry.user_data(index as u64), len as u32));
+                chunks.push((index, done, len));
+                done += len;
+            }
+
+            self.submit_batch(&batch)?;
+
+            // Copy the data out of the registered buffers
+            if !self.buffers.is_empty() {
+                for (index, start, len) in chunks {
+                    buf[start..start + len].copy_from_slice(&self.buffers[index].as_slice()[..len]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes all of `buf` at `offset` as it is; with `O_DIRECT` both must be aligned.
+    fn write_raw(&mut self, offset: u64, buf: &[u8]) -> Result<(), VmError> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let batch_size = self.batch_size();
+        let chunk_size = self.chunk_size();
+
+        let mut done = 0usize;
+        while done < buf.len() {
+            let mut batch: Vec<(squeue::Entry, u32)> = Vec::with_capacity(batch_size);
+            while done < buf.len() && batch.len() < batch_size {
+                let len = chunk_size.min(buf.len() - done);
+                let index = batch.len();
+                let entry = if self.buffers.is_empty() {
+                    opcode::Write::new(fd, buf[done..].as_ptr(), len as u32)
+                        .offset(offset + done as u64)
+                        .build()
+                } else {
+                    // Stage the data in the registered buffer first
+                    self.buffers[index].as_mut_slice()[..len].copy_from_slice(&buf[done..done + len]);
+                    opcode::WriteFixed::new(fd, self.buffers[index].as_slice().as_ptr(), len as u32, index as u16)
+                        .offset(offset + done as u64)
+                        .build()
+                };
+                batch.push((entry.user_data(index as u64), len as u32));
+                done += len;
+            }
+
+            self.submit_batch(&batch)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StorageBackend for IoUringBackend {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        self.check_bounds(offset, buf.len())?;
+        if !self.direct_io || self.is_direct_io_aligned(offset, buf) {
+            return self.read_raw(offset, buf);
+        }
+
+        // Read the covering blocks into an aligned bounce buffer
+        let (start, end) = Self::get_aligned_range(offset, buf.len());
+        let mut bounce = AlignedBuffer::new((end - start) as usize)?;
+        self.read_raw(start, bounce.as_mut_slice())?;
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&bounce.as_slice()[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), VmError> {
+        self.check_bounds(offset, buf.len())?;
+        if !self.direct_io || self.is_direct_io_aligned(offset, buf) {
+            return self.write_raw(offset, buf);
+        }
+
+        // Merge the data into the covering blocks; only partially written blocks at
+        // either end need their current content
+        let (start, end) = Self::get_aligned_range(offset, buf.len());
+        let mut bounce = AlignedBuffer::new((end - start) as usize)?;
+        let block = DIRECT_IO_ALIGNMENT;
+        let skip = (offset - start) as usize;
+        if skip != 0 {
+            self.read_raw(start, &mut bounce.as_mut_slice()[..block])?;
+        }
+        let tail = bounce.len - block;
+        if offset + buf.len() as u64 != end && (tail != 0 || skip == 0) {
+            self.read_raw(end - block as u64, &mut bounce.as_mut_slice()[tail..])?;
+        }
+        bounce.as_mut_slice()[skip..skip + buf.len()].copy_from_slice(buf);
+        self.write_raw(start, bounce.as_slice())
+    }
+
+    fn flush(&mut self) -> Result<(), VmError> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let entry = opcode::Fsync::new(fd).build().user_data(0);
+        self.submit_batch(&[(entry, 0)])
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    // Helper: create a zero-filled temporary image of the given size
+    fn create_image(size: u64) -> NamedTempFile {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        file.as_file().set_len(size).expect("Failed to set temp file size");
+        file
+    }
+
+    #[test]
+    fn test_io_uring_backend_write_then_read_registered_buffers() {
+        let image = create_image(1024 * 1024);
+        let options = IoUringOptions { registered_buffer_count: 2, registered_buffer_size: 4096, ..Default::default() };
+        let mut backend = IoUringBackend::new(image.path().to_str().unwrap(), options).expect("Failed to create backend");
+
+        // Spans several registered buffers and more than one batch
+        let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        backend.write_at(512, &data).expect("Write should succeed");
+        backend.flush().expect("Flush should succeed");
+
+        let mut read_back = vec![0u8; data.len()];
+        backend.read_at(512, &mut read_back).expect("Read should succeed");
+        assert_eq!(read_back, data);
+    }
+
+    #[test]
+    fn test_io_uring_backend_without_registered_buffers() {
+        let image = create_image(64 * 1024);
+        let options = IoUringOptions { registered_buffer_count: 0, ..Default::default() };
+        let mut backend = IoUringBackend::new(image.path().to_str().unwrap(), options).expect("Failed to create backend");
+
+        backend.write_at(0, &[1, 2, 3, 4]).expect("Write should succeed");
+        let mut read_back = [0u8; 4];
+        backend.read_at(0, &mut read_back).expect("Read should succeed");
+        assert_eq!(read_back, [1, 2, 3, 4]);
+        assert_eq!(backend.size(), 64 * 1024);
+    }
+
+    #[test]
+    fn test_io_uring_backend_out_of_bounds() {
+        let image = create_image(4096);
+        let mut backend = IoUringBackend::new(image.path().to_str().unwrap(), IoUringOptions::default()).expect("Failed to create backend");
+
+        let mut buf = [0u8; 512];
+        assert!(backend.read_at(4000, &mut buf).is_err());
+        assert!(backend.write_at(u64::MAX, &buf).is_err());
+    }
+
+    #[test]
+    fn test_io_uring_backend_direct_io_unaligned_access() {
+        let image = create_image(64 * 1024);
+        for registered_buffer_count in [0, 2] {
+            let options = IoUringOptions { registered_buffer_count, registered_buffer_size: 8192, direct_io: true, ..Default::default() };
+            let mut backend = match IoUringBackend::new(image.path().to_str().unwrap(), options) {
+                Ok(backend) => backend,
+                // Not every filesystem supports O_DIRECT (e.g. tmpfs)
+                Err(_) => return,
+            };
+
+            // Starts and ends within blocks, spanning three of them
+            let data: Vec<u8> = (0..9000).map(|i| (i % 253) as u8 + registered_buffer_count as u8).collect();
+            backend.write_at(1000, &data).expect("Write should succeed");
+            // Within a single block
+            backend.write_at(20000, &[7; 100]).expect("Write should succeed");
+
+            let mut read_back = vec![0u8; data.len()];
+            backend.read_at(1000, &mut read_back).expect("Read should succeed");
+            assert_eq!(read_back, data);
+            let mut around = [0xffu8; 102];
+            backend.read_at(19999, &mut around).expect("Read should succeed");
+            assert_eq!(around[0], 0);
+            assert_eq!(&around[1..101], &[7; 100][..]);
+            assert_eq!(around[101], 0);
+        }
+        let written = std::fs::read(image.path()).unwrap();
+        assert!(written[..1000].iter().all(|&byte| byte == 0));
+        assert!(written[10000..20000].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_io_uring_backend_direct_io_requires_aligned_sizes() {
+        let image = create_image(4096 + 512);
+        let options = IoUringOptions { direct_io: true, ..Default::default() };
+        assert!(IoUringBackend::new(image.path().to_str().unwrap(), options).is_err());
+
+        let image = create_image(8192);
+        let options = IoUringOptions { direct_io: true, registered_buffer_size: 6000, ..Default::default() };
+        assert!(IoUringBackend::new(image.path().to_str().unwrap(), options).is_err());
+        let options = IoUringOptions { queue_entries: 0, ..Default::default() };
+        assert!(IoUringBackend::new(image.path().to_str().unwrap(), options).is_err());
+    }
+
+    #[test]
+    fn test_io_uring_backend_push_failure_reaps_pushed_entries() {
+        let image = create_image(64 * 1024);
+        let options = IoUringOptions { queue_entries: 2, registered_buffer_count: 0, ..Default::default() };
+        let mut backend = IoUringBackend::new(image.path().to_str().unwrap(), options).expect("Failed to create backend");
+
+        // More entries than the submission queue holds
+        let entries = backend.ring.params().sq_entries() as usize + 2;
+        let batch: Vec<(squeue::Entry, u32)> = (0..entries)
+            .map(|index| (opcode::Nop::new().build().user_data(index as u64), 0))
+            .collect();
+        assert!(backend.submit_batch(&batch).is_err());
+        assert!(backend.ring.submission().is_empty());
+        assert!(backend.ring.completion().is_empty());
+
+        // The ring holds no leftovers of the failed batch
+        backend.write_at(0, &[5; 100]).expect("Write should succeed");
+        let mut read_back = [0u8; 100];
+        backend.read_at(0, &mut read_back).expect("Read should succeed");
+        assert_eq!(read_back, [5; 100]);
+    }
+
+    #[test]
+    fn test_io_uring_backend_poisoned_ring_refuses_io() {
+        let image = create_image(64 * 1024);
+        let mut backend = IoUringBackend::new(image.path().to_str().unwrap(), IoUringOptions::default()).expect("Failed to create backend");
+        backend.poisoned = true;
+        let mut buf = [0u8; 512];
+        assert!(backend.read_at(0, &mut buf).is_err());
+        assert!(backend.write_at(0, &buf).is_err());
+    }
+
+    #[test]
+    fn test_io_uring_backend_missing_file() {
+        let result = IoUringBackend::new("/nonexistent/disk.img", IoUringOptions::default());
+        assert!(result.is_err());
+    }
+}
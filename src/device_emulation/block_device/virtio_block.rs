@@ -0,0 +1,765 @@
+use virtio_bindings::virtio_blk::*;
+use virtio_queue::{QueueT, QueueSync, DescriptorChain};
+use virtio_queue::desc::split::Descriptor;
+use vm_memory::{Bytes, GuestMemoryMmap, Address};
+use memmap2::MmapMut;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::error::VmError;
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, MmapBackend};
+use crate::device_emulation::io_worker::IoWorker;
+use crate::device_emulation::virtqueue_config::VirtqueueConfig;
+use crate::device_emulation::virtio_mmio::{VirtioDevice, VirtqueueState, VringNotifier};
+
+/// Virtio device ID of a block device.
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+pub use crate::device_emulation::block_device::disk_backend::{CacheMode, SECTOR_SIZE};
+pub use crate::device_emulation::block_device::BlockDeviceMetrics;
+/// Largest data segment accepted in a request, reported as SIZE_MAX.
+const MAX_SEGMENT_SIZE: u32 = 1 << 20;
+/// Data segments per request, reported as SEG_MAX; leaves room for the header and
+/// status descriptors of a request filling a 128 entry queue.
+const MAX_SEGMENTS: u32 = 126;
+/// Heads reported in the legacy geometry.
+const GEOMETRY_HEADS: u8 = 16;
+/// Sectors per track reported in the legacy geometry.
+const GEOMETRY_SECTORS: u8 = 63;
+/// Size of the configuration space fields the device fills in (capacity through num_queues).
+const CONFIG_SPACE_SIZE: usize = 36;
+/// Offset of the writeback field in the configuration space.
+const CONFIG_WRITEBACK: u64 = 32;
+/// Offset of the num_queues field in the configuration space.
+const CONFIG_NUM_QUEUES: usize = 34;
+
+/// Default maximum number of requests the device keeps in flight at once.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 128;
+
+/// Virtio block device implementation.
+/// Handles guest memory, disk image backing and virtio queues; the guest reaches it
+/// through an `MmioTransport`, which owns the registers and the interrupt line.
+///
+/// Requests are processed on the thread notifying the device unless an I/O worker
+/// was started with `start_io_worker`.
+pub struct VirtioBlockDevice {
+    /// Disk backing the block device
+    pub disk_image: Arc<Mutex<Box<dyn DiskBackend + Send>>>,
+    /// Virtio request queues, set up by the guest driver through the MMIO queue registers
+    pub queues: Vec<RefCell<QueueSync>>,
+    /// Number and size of the virtqueues and the MSI-X vectors they need
+    queue_config: VirtqueueConfig,
+    /// Executes the requests, on this thread or the I/O worker's
+    handler: RequestHandler,
+    /// Thread processing the queues when notified, if started
+    io_worker: Option<IoWorker>
+}
+
+/// Request processing state, shared between the device and its I/O worker.
+#[derive(Clone)]
+struct RequestHandler {
+    /// Guest physical memory mapping
+    mem: GuestMemoryMmap,
+    /// Disk backing the block device
+    disk_image: Arc<Mutex<Box<dyn DiskBackend + Send>>>,
+    /// Maximum number of requests taken in at a time before back-pressuring the guest
+    max_queue_depth: Arc<AtomicUsize>,
+    /// Request queue statistics
+    metrics: Arc<Mutex<BlockDeviceMetrics>>,
+    /// Write cache mode, also switchable by the guest through the writeback config field
+    cache_mode: Arc<Mutex<CacheMode>>,
+    /// Whether write requests are refused
+    read_only: Arc<AtomicBool>,
+    /// How long the I/O worker keeps polling the queues after draining them
+    poll_window: Arc<Mutex<Duration>>
+}
+
+impl VirtioBlockDevice {
+    /// Creates a new VirtioBlockDevice instance with a single 1024 descriptor queue.
+    ///
+    /// See `with_queue_config` for the details.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory
+    /// * `disk_image` - Memory mapped backing storage for the block device
+    ///
+    /// # Returns
+    /// * `Ok(Self)` on success
+    /// * `Err(VmError)` on failure (e.g., queue initialization failure or invalid queue)
+    pub fn new(mem: GuestMemoryMmap, disk_image: MmapMut) -> Result<Self, VmError> {
+        Self::with_queue_config(mem, disk_image, VirtqueueConfig::default())
+    }
+
+    /// Creates a new VirtioBlockDevice instance with the given virtqueue sizing.
+    ///
+    /// Every queue starts inactive; the guest driver sets it up through the
+    /// virtio-mmio queue registers.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory
+    /// * `disk_image` - Memory mapped backing storage for the block device
+    /// * `queue_config` - Number and size of the virtqueues, usually `VmSetup::get_virtqueue_config`
+    ///
+    /// # Returns
+    /// * `Ok(Self)` on success
+    /// * `Err(VmError)` on failure (e.g., queue initialization failure)
+    pub fn with_queue_config(mem: GuestMemoryMmap, disk_image: MmapMut, queue_config: VirtqueueConfig) -> Result<Self, VmError> {
+        Self::with_storage(mem, Box::new(MmapBackend::new(disk_image)), queue_config)
+    }
+
+    /// Creates a new VirtioBlockDevice instance on the given disk, e.g. a `FileBackend`
+    /// or an `IoUringBackend` for large images.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory
+    /// * `storage` - Disk backend holding the disk data
+    /// * `queue_config` - Number and size of the virtqueues, usually `VmSetup::get_virtqueue_config`
+    ///
+    /// # Returns
+    /// * `Ok(Self)` on success
+    /// * `Err(VmError)` on failure (e.g., queue initialization failure)
+    pub fn with_storage(mem: GuestMemoryMmap, storage: Box<dyn DiskBackend + Send>, queue_config: VirtqueueConfig) -> Result<Self, VmError> {
+        let mut queues = Vec::with_capacity(queue_config.get_num_queues() as usize);
+        for _ in 0..queue_config.get_num_queues() {
+            match QueueSync::new(queue_config.get_queue_size()) {
+                Ok(q) => queues.push(q),
+                Err(e) => return Err(VmError::device_source(format!("failed to create virtqueue: {}", e), e))
+            };
+        }
+
+        // Return the new block device instance with initialized fields
+        let disk_image = Arc::new(Mutex::new(storage));
+        Ok(Self {
+            disk_image: Arc::clone(&disk_image),
+            queues: queues.into_iter().map(RefCell::new).collect(),
+            queue_config,
+            handler: RequestHandler {
+                mem,
+                disk_image,
+                max_queue_depth: Arc::new(AtomicUsize::new(DEFAULT_MAX_QUEUE_DEPTH)),
+                metrics: Arc::new(Mutex::new(BlockDeviceMetrics { max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH, ..Default::default() })),
+                cache_mode: Arc::new(Mutex::new(CacheMode::default())),
+                read_only: Arc::new(AtomicBool::new(false)),
+                poll_window: Arc::new(Mutex::new(Duration::ZERO)),
+            },
+            io_worker: None,
+        })
+    }
+
+    /// Returns the disk capacity in 512-byte sectors; a partial last sector is left out.
+    pub fn get_capacity(&self) -> u64 {
+        self.disk_image.lock().unwrap_or_else(|e| e.into_inner()).capacity()
+    }
+
+    /// Replaces the backing storage with a mapped image, e.g. after the image file
+    /// was grown.
+    ///
+    /// When the capacity changes the guest must be told through
+    /// `MmioTransport::signal_config_change` so it rereads the configuration space.
+    ///
+    /// # Returns
+    /// * `true` if the capacity changed
+    pub fn set_disk_image(&mut self, disk_image: MmapMut) -> bool {
+        self.set_storage(Box::new(MmapBackend::new(disk_image)))
+    }
+
+    /// Replaces the backing disk, as `set_disk_image` does for mapped images.
+    ///
+    /// # Returns
+    /// * `true` if the capacity changed
+    pub fn set_storage(&mut self, storage: Box<dyn DiskBackend + Send>) -> bool {
+        let old_capacity = self.get_capacity();
+        *self.disk_image.lock().unwrap_or_else(|e| e.into_inner()) = storage;
+        self.get_capacity() != old_capacity
+    }
+
+    /// Captures the current disk contents as the snapshot `name`.
+    ///
+    /// The disk must support snapshots, e.g. by being a `SnapshotDisk`. Requests in
+    /// progress finish before the snapshot is taken.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the disk doesn't support snapshots, the name is invalid or
+    ///   already taken, or the snapshot couldn't be created
+    pub fn snapshot(&self, name: &str) -> Result<(), VmError> {
+        self.disk_image.lock().unwrap_or_else(|e| e.into_inner()).snapshot(name)
+    }
+
+    /// Rolls the disk back to the snapshot `name`, dropping the snapshots taken after it.
+    ///
+    /// The guest isn't told, so its caches must not hold disk contents: revert while
+    /// the guest is stopped or before it mounts the disk again.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the disk holds the contents it had when the snapshot was taken
+    /// * `Err(VmError)` if the disk doesn't support snapshots, there is no such
+    ///   snapshot, or the contents couldn't be restored
+    pub fn revert(&self, name: &str) -> Result<(), VmError> {
+        self.disk_image.lock().unwrap_or_else(|e| e.into_inner()).revert(name)
+    }
+
+    /// Sets when writes reach the backing file. Takes effect with the next request.
+    pub fn set_cache_mode(&self, mode: CacheMode) {
+        *self.handler.cache_mode.lock().unwrap_or_else(|e| e.into_inner()) = mode;
+    }
+
+    /// Returns the current write cache mode.
+    pub fn get_cache_mode(&self) -> CacheMode {
+        self.handler.get_cache_mode()
+    }
+
+    /// Makes the disk read-only: the guest is told through the RO feature and write
+    /// requests fail. Must be set before the guest driver negotiates the features.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.handler.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    /// Returns whether the disk is read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.handler.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Sets how long the I/O worker busy-polls the queues after draining them before it
+    /// sleeps until the next guest notification. Takes effect with the next notification.
+    ///
+    /// While polling, guest notifications are suppressed and every request found
+    /// restarts the window, so a guest submitting steadily is served without VM exits
+    /// at the cost of a host CPU spinning for the worker. Zero, the default, turns
+    /// polling off. Without an I/O worker the queues are never polled.
+    pub fn set_poll_window(&self, window: Duration) {
+        *self.handler.poll_window.lock().unwrap_or_else(|e| e.into_inner()) = window;
+    }
+
+    /// Returns how long the I/O worker polls the queues after draining them.
+    pub fn get_poll_window(&self) -> Duration {
+        *self.handler.poll_window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Moves request processing to a worker thread, so guest notifications return
+    /// right away and the disk I/O happens off the vCPU thread.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the worker thread
+    /// * `notifier` - Signals completed requests, from `MmioTransport::get_vring_notifier`
+    ///   of the transport wrapping the device
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the thread couldn't be started
+    pub fn start_io_worker(&mut self, name: &str, notifier: VringNotifier) -> Result<(), VmError> {
+        let handler = self.handler.clone();
+        // Clones of a QueueSync share the queue state
+        let queues: Vec<QueueSync> = self.queues.iter().map(|queue| queue.borrow().clone()).collect();
+        let worker = IoWorker::spawn(name, move |index| {
+            let Some(queue) = queues.get(index) else { return };
+            if handler.process_virtqueue(&mut queue.clone()) {
+                let _ = notifier.notify();
+            }
+            let window = *handler.poll_window.lock().unwrap_or_else(|e| e.into_inner());
+            if !window.is_zero() {
+                handler.poll_virtqueues(&mut queues.clone(), window, || {
+                    let _ = notifier.notify();
+                });
+            }
+        })?;
+        self.io_worker = Some(worker);
+        Ok(())
+    }
+
+    /// Waits for the I/O worker, if any, to finish the requests it was kicked for.
+    fn wait_for_io_worker(&self) {
+        if let Some(worker) = &self.io_worker {
+            worker.wait_idle();
+        }
+    }
+
+    /// Returns the virtio-blk configuration space: capacity, size_max, seg_max, geometry,
+    /// blk_size, writeback and num_queues.
+    fn get_config_space(&self) -> [u8; CONFIG_SPACE_SIZE] {
+        let capacity = self.get_capacity();
+        let cylinders = (capacity / (GEOMETRY_HEADS as u64 * GEOMETRY_SECTORS as u64)).min(u16::MAX as u64) as u16;
+        let mut config = [0u8; CONFIG_SPACE_SIZE];
+        config[0..8].copy_from_slice(&capacity.to_le_bytes());
+        config[8..12].copy_from_slice(&MAX_SEGMENT_SIZE.to_le_bytes());
+        config[12..16].copy_from_slice(&MAX_SEGMENTS.to_le_bytes());
+        config[16..18].copy_from_slice(&cylinders.to_le_bytes());
+        config[18] = GEOMETRY_HEADS;
+        config[19] = GEOMETRY_SECTORS;
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config[CONFIG_WRITEBACK as usize] = (self.get_cache_mode() == CacheMode::Writeback) as u8;
+        config[CONFIG_NUM_QUEUES..CONFIG_NUM_QUEUES + 2].copy_from_slice(&(self.queues.len() as u16).to_le_bytes());
+        config
+    }
+
+    /// Returns the virtqueue sizing of the device.
+    pub fn get_queue_config(&self) -> VirtqueueConfig {
+        self.queue_config
+    }
+
+    /// Sets the maximum number of requests the device keeps in flight.
+    ///
+    /// The device takes in at most this many requests of a virtqueue at a time; while
+    /// the guest has more outstanding, its notifications are suppressed until the
+    /// backlog is worked off.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if `depth` is zero
+    pub fn set_max_queue_depth(&self, depth: usize) -> Result<(), VmError> {
+        if depth == 0 {
+            return Err(VmError::device("max queue depth must be greater than zero"));
+        }
+        self.handler.max_queue_depth.store(depth, Ordering::Relaxed);
+        self.handler.metrics.lock().unwrap_or_else(|e| e.into_inner()).max_queue_depth = depth;
+        Ok(())
+    }
+
+    /// Returns the configured maximum queue depth.
+    pub fn get_max_queue_depth(&self) -> usize {
+        self.handler.max_queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the device's request queue metrics.
+    pub fn metrics(&self) -> BlockDeviceMetrics {
+        *self.handler.metrics.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Processes descriptor chains from every ready virtqueue on the calling thread.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for any of the completed requests
+    pub fn process_descriptor_chain(&self) -> bool {
+        self.wait_for_io_worker();
+        let mut needs_interrupt = false;
+        for queue in &self.queues {
+            needs_interrupt |= self.handler.process_virtqueue(&mut queue.borrow_mut());
+        }
+        needs_interrupt
+    }
+}
+
+impl RequestHandler {
+    /// Returns the current write cache mode.
+    fn get_cache_mode(&self) -> CacheMode {
+        *self.cache_mode.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Processes descriptor chains from a single virtqueue.
+    ///
+    /// Takes in up to the configured maximum queue depth of descriptor chains at a time,
+    /// interprets block requests (read/write), performs I/O on the backing disk image,
+    /// updates used ring and writes status. While the guest has more requests outstanding
+    /// than the queue depth, its notifications are suppressed until the backlog is
+    /// worked off.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the completed requests
+    fn process_virtqueue(&self, que: &mut QueueSync) -> bool {
+        let memory = &self.mem;
+        let mut needs_interrupt = false;
+        let mut suppressed = false;
+
+        // If queue not ready, no processing possible
+        if !que.ready() {
+            return false;
+        }
+
+        loop {
+            let max_queue_depth = self.max_queue_depth.load(Ordering::Relaxed);
+
+            // Requests the guest made available that the device hasn't taken in yet
+            let outstanding = match que.avail_idx(memory, Ordering::Acquire) {
+                Ok(avail) => avail.0.wrapping_sub(que.next_avail()) as usize,
+                Err(_) => return needs_interrupt
+            };
+
+            // Back-pressure the guest while the backend is saturated
+            let saturated = outstanding > max_queue_depth;
+            if saturated != suppressed {
+                let toggled = if saturated {
+                    que.disable_notification(memory)
+                } else {
+                    que.enable_notification(memory).map(|_| ())
+                };
+                if toggled.is_err() {
+                    return needs_interrupt;
+                }
+                suppressed = saturated;
+                if saturated {
+                    self.metrics.lock().unwrap_or_else(|e| e.into_inner()).backpressure_events += 1;
+                } else {
+                    // Pick up requests made available before notifications were back on
+                    continue;
+                }
+            }
+
+            {
+                let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+                metrics.inflight = outstanding;
+                metrics.peak_inflight = metrics.peak_inflight.max(outstanding);
+            }
+            if outstanding == 0 {
+                return needs_interrupt;
+            }
+
+            let mut taken = 0;
+            while taken < outstanding.min(max_queue_depth) {
+                let descriptor_chain = match que.pop_descriptor_chain(memory) {
+                    Some(chain) => chain,
+                    None => break
+                };
+                taken += 1;
+                // Head descriptor index, needed for used ring update
+                let head_index = descriptor_chain.head_index();
+
+                let used_len = self.execute_request(memory, descriptor_chain);
+
+                {
+                    let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+                    metrics.inflight -= 1;
+                    metrics.completed_requests += 1;
+                }
+
+                let used_len = match used_len {
+                    Some(l) => l,
+                    None => continue
+                };
+
+                // Add the processed descriptor to the used ring with the length of the data buffer
+                if let Err(_) = que.add_used(memory, head_index, used_len) {
+                    return needs_interrupt;
+                }
+            }
+
+            // A malformed avail ring entry can't be taken in, don't spin on it
+            if taken == 0 {
+                return needs_interrupt;
+            }
+
+            // Check if guest requested notification; the transport raises the interrupt
+            match que.needs_notification(memory) {
+                Ok(b) => needs_interrupt |= b,
+                Err(_) => return needs_interrupt
+            }
+        }
+    }
+
+    /// Keeps processing `queues` until none of them got a request for `window`, with
+    /// guest notifications suppressed meanwhile.
+    ///
+    /// Requests the guest makes available before notifications are back on are
+    /// processed before returning, so none is left waiting for a notification.
+    ///
+    /// # Arguments
+    /// * `queues` - Queues of the device, shared with the transport
+    /// * `window` - How long to wait for a request before returning
+    /// * `notify` - Raises the interrupt once the guest asked for one
+    fn poll_virtqueues(&self, queues: &mut [QueueSync], window: Duration, notify: impl Fn()) {
+        let memory = &self.mem;
+        let mut deadline = Instant::now() + window;
+        loop {
+            for que in queues.iter_mut().filter(|que| que.ready()) {
+                // Processing a saturated queue turns its notifications back on
+                let _ = que.disable_notification(memory);
+            }
+            let pending = queues.iter().position(|que| {
+                que.ready() && que.avail_idx(memory, Ordering::Acquire).is_ok_and(|avail| avail.0 != que.next_avail())
+            });
+            match pending {
+                Some(index) => {
+                    if self.process_virtqueue(&mut queues[index]) {
+                        notify();
+                    }
+                    deadline = Instant::now() + window;
+                },
+                None if Instant::now() < deadline => std::hint::spin_loop(),
+                None => {
+                    // Sleep until the next notification, unless requests came in before
+                    // notifications were back on
+                    let mut raced = false;
+                    for que in queues.iter_mut().filter(|que| que.ready()) {
+                        raced |= que.enable_notification(memory).unwrap_or(false);
+                    }
+                    if !raced {
+                        return;
+                    }
+                    deadline = Instant::now() + window;
+                }
+            }
+        }
+    }
+
+    /// Executes a single block request described by `descriptor_chain`.
+    ///
+    /// The chain starts with the request header and ends with the status byte; every
+    /// descriptor in between is a data segment, read from for writes and written to for
+    /// reads. Requests reaching past the end of the disk image, whose segments don't
+    /// match the request's direction or the advertised limits, or whose buffers aren't
+    /// in guest memory, complete with VIRTIO_BLK_S_IOERR.
+    ///
+    /// # Returns
+    /// * `Some(len)` - the number of bytes to report in the used ring
+    /// * `None` - if the chain is malformed and can't be completed
+    fn execute_request(&self, memory: &GuestMemoryMmap, descriptor_chain: DescriptorChain<&GuestMemoryMmap>) -> Option<u32> {
+        let descriptors: Vec<Descriptor> = descriptor_chain.into_iter().collect();
+        if descriptors.len() < 2 {
+            return None;
+        }
+
+        // The last descriptor is used to return the status byte to the guest
+        let status_descriptor = descriptors[descriptors.len() - 1];
+        if !status_descriptor.is_write_only() || status_descriptor.len() == 0 {
+            return None;
+        }
+
+        // The first descriptor contains the request header, the ones in between the data segments
+        let header_descriptor = descriptors[0];
+        let data_descriptors = &descriptors[1..descriptors.len() - 1];
+
+        let status = match self.read_request_header(memory, &header_descriptor) {
+            Some((VIRTIO_BLK_T_IN, sector)) => self.transfer(memory, sector, data_descriptors, true),
+            Some((VIRTIO_BLK_T_OUT, _)) if self.read_only.load(Ordering::Relaxed) => VIRTIO_BLK_S_IOERR,
+            Some((VIRTIO_BLK_T_OUT, sector)) => self.transfer(memory, sector, data_descriptors, false),
+            Some((VIRTIO_BLK_T_FLUSH, _)) => self.flush(),
+            Some(_) => VIRTIO_BLK_S_OK,
+            None => VIRTIO_BLK_S_IOERR
+        };
+
+        // Write the status byte to the status descriptor buffer
+        memory.write_obj(status as u8, status_descriptor.addr()).ok()?;
+
+        if status != VIRTIO_BLK_S_OK {
+            return Some(0);
+        }
+        // Checked by transfer for reads and writes; other requests carry no data
+        Some(data_descriptors.iter().fold(0u32, |total, descriptor| total.saturating_add(descriptor.len())))
+    }
+
+    /// Reads the request type and the starting sector from the request header.
+    ///
+    /// # Returns
+    /// * `Some((request_type, sector))` on success
+    /// * `None` if the header is device-writable, too short or not in guest memory
+    fn read_request_header(&self, memory: &GuestMemoryMmap, header_descriptor: &Descriptor) -> Option<(u32, u64)> {
+        // type (u32), reserved (u32), sector (u64)
+        if header_descriptor.is_write_only() || header_descriptor.len() < 16 {
+            return None;
+        }
+        let request_type = memory.read_obj::<u32>(header_descriptor.addr()).ok()?;
+        // The sector number is stored 8 bytes after the start of the header descriptor
+        let sector = memory.read_obj::<u64>(header_descriptor.addr().checked_add(8)?).ok()?;
+        Some((request_type, sector))
+    }
+
+    /// Copies the data segments of a request between guest memory and the disk image.
+    ///
+    /// The segments cover consecutive bytes of the disk starting at `sector`. The whole
+    /// range is checked before anything is copied, so an out-of-range request leaves
+    /// the disk untouched; a segment missing from guest memory may leave a write
+    /// partially done, as on real hardware.
+    ///
+    /// # Arguments
+    /// * `sector` - First sector of the request
+    /// * `data_descriptors` - Data segments, in order
+    /// * `is_read` - `true` to copy from the disk into the segments, `false` for the opposite
+    ///
+    /// # Returns
+    /// * The virtio-blk status of the request
+    fn transfer(&self, memory: &GuestMemoryMmap, sector: u64, data_descriptors: &[Descriptor], is_read: bool) -> u32 {
+        if data_descriptors.is_empty() || data_descriptors.len() > MAX_SEGMENTS as usize {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let mut total_len: u32 = 0;
+        for descriptor in data_descriptors {
+            // The device writes into the segments of reads and only reads those of writes
+            if descriptor.is_write_only() != is_read || descriptor.len() > MAX_SEGMENT_SIZE {
+                return VIRTIO_BLK_S_IOERR;
+            }
+            total_len = match total_len.checked_add(descriptor.len()) {
+                Some(len) => len,
+                None => return VIRTIO_BLK_S_IOERR
+            };
+        }
+
+        // Requests move whole sectors
+        if !(total_len as u64).is_multiple_of(SECTOR_SIZE) {
+            return VIRTIO_BLK_S_IOERR;
+        }
+
+        let mut storage = self.disk_image.lock().unwrap_or_else(|e| e.into_inner());
+        let range = match Self::get_disk_range(storage.capacity() * SECTOR_SIZE, sector, total_len) {
+            Some(range) => range,
+            None => return VIRTIO_BLK_S_IOERR
+        };
+
+        let copied = match storage.as_mapped() {
+            Some(disk_img) => Self::transfer_mapped(memory, disk_img, range.start, data_descriptors, is_read),
+            None => Self::transfer_backend(memory, storage.as_mut(), sector, total_len, data_descriptors, is_read),
+        };
+        if !copied {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        if !is_read && self.get_cache_mode() == CacheMode::Writethrough
+            && storage.flush_sectors(sector, total_len as u64 / SECTOR_SIZE).is_err() {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        VIRTIO_BLK_S_OK
+    }
+
+    /// Copies the segments of a request straight between guest memory and a mapped disk.
+    ///
+    /// # Returns
+    /// * `true` if every segment was copied
+    fn transfer_mapped(memory: &GuestMemoryMmap, disk_img: &mut [u8], start: usize, data_descriptors: &[Descriptor], is_read: bool) -> bool {
+        let mut offset = start;
+        for descriptor in data_descriptors {
+            let segment = offset..offset + descriptor.len() as usize;
+            let copied = if is_read {
+                // Handle read request: copy data from disk to guest buffer
+                memory.write_slice(&disk_img[segment], descriptor.addr())
+            } else {
+                // Handle write request: copy data from guest buffer to disk
+                memory.read_slice(&mut disk_img[segment], descriptor.addr())
+            };
+            if copied.is_err() {
+                return false;
+            }
+            offset += descriptor.len() as usize;
+        }
+        true
+    }
+
+    /// Transfers the segments of a request between guest memory and a disk backend
+    /// through a bounce buffer covering the whole request.
+    ///
+    /// # Returns
+    /// * `true` if every segment was transferred
+    fn transfer_backend(memory: &GuestMemoryMmap, backend: &mut dyn DiskBackend, sector: u64, total_len: u32, data_descriptors: &[Descriptor], is_read: bool) -> bool {
+        let mut buffer = vec![0u8; total_len as usize];
+        if is_read && backend.read_sectors(sector, &mut buffer).is_err() {
+            return false;
+        }
+        let mut offset = 0;
+        for descriptor in data_descriptors {
+            let segment = &mut buffer[offset..offset + descriptor.len() as usize];
+            let copied = if is_read {
+                memory.write_slice(segment, descriptor.addr())
+            } else {
+                memory.read_slice(segment, descriptor.addr())
+            };
+            if copied.is_err() {
+                return false;
+            }
+            offset += descriptor.len() as usize;
+        }
+        is_read || backend.write_sectors(sector, &buffer).is_ok()
+    }
+
+    /// Writes the modified data of the disk image back to the backing file.
+    ///
+    /// # Returns
+    /// * The virtio-blk status of the flush request
+    fn flush(&self) -> u32 {
+        match self.disk_image.lock().unwrap_or_else(|e| e.into_inner()).flush() {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(_) => VIRTIO_BLK_S_IOERR
+        }
+    }
+
+    /// Returns the byte range of the disk image covered by a request.
+    ///
+    /// # Returns
+    /// * `Some(range)` if the request lies entirely within the image
+    /// * `None` if it reaches past the end of the image or its offset overflows
+    fn get_disk_range(disk_len: u64, sector: u64, len: u32) -> Option<Range<usize>> {
+        let start = sector.checked_mul(SECTOR_SIZE)?;
+        let end = start.checked_add(len as u64)?;
+        if end > disk_len {
+            return None;
+        }
+        Some(start as usize..end as usize)
+    }
+}
+
+impl VirtioDevice for VirtioBlockDevice {
+    fn get_device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    /// MQ is always offered, a driver that negotiates it reads the number of request
+    /// queues from num_queues.
+    fn get_device_features(&self) -> u64 {
+        let features = (1 << VIRTIO_BLK_F_SIZE_MAX) | (1 << VIRTIO_BLK_F_SEG_MAX) | (1 << VIRTIO_BLK_F_GEOMETRY) | (1 << VIRTIO_BLK_F_BLK_SIZE)
+            | (1 << VIRTIO_BLK_F_FLUSH) | (1 << VIRTIO_BLK_F_CONFIG_WCE) | (1 << VIRTIO_BLK_F_MQ);
+        if self.is_read_only() {
+            features | (1 << VIRTIO_BLK_F_RO)
+        } else {
+            features
+        }
+    }
+
+    fn get_num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn get_queue_max_size(&self, _index: usize) -> u16 {
+        self.queue_config.get_queue_size()
+    }
+
+    /// Reads the configuration space; fields past num_queues read as zeroes.
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.get_config_space();
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = offset.checked_add(index as u64)
+                .and_then(|position| config.get(position as usize))
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    /// Lets the guest switch the cache mode through the writeback field; the rest of
+    /// the configuration space is read-only.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset == CONFIG_WRITEBACK && data.len() == 1 {
+            self.set_cache_mode(if data[0] == 0 { CacheMode::Writethrough } else { CacheMode::Writeback });
+        }
+    }
+
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+        match self.queues.get(index) {
+            Some(queue) => state.apply(&mut queue.borrow_mut(), &self.handler.mem),
+            None => Err(VmError::device(format!("no virtqueue {}", index)))
+        }
+    }
+
+    fn deactivate_queue(&mut self, index: usize) {
+        self.wait_for_io_worker();
+        if let Some(queue) = self.queues.get(index) {
+            queue.borrow_mut().reset();
+        }
+    }
+
+    /// Processes the queue, or kicks the I/O worker which signals completions itself.
+    /// A worker that died falls back to processing on the notifying thread.
+    fn process_queue(&mut self, index: usize) -> bool {
+        if self.io_worker.as_ref().is_some_and(|worker| worker.kick(index)) {
+            return false;
+        }
+        match self.queues.get(index) {
+            Some(queue) => self.handler.process_virtqueue(&mut queue.borrow_mut()),
+            None => false
+        }
+    }
+
+    /// Drops the queue state once in-flight requests completed; the driver sets the
+    /// queues up again.
+    fn reset(&mut self) {
+        self.wait_for_io_worker();
+        for queue in &self.queues {
+            queue.borrow_mut().reset();
+        }
+    }
+}
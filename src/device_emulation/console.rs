@@ -0,0 +1,301 @@
+//! virtio-console device with a single port.
+//!
+//! The guest sees a `hvc` console; the host gets a `ConsoleStream` implementing
+//! tokio's `AsyncRead` (guest output) and `AsyncWrite` (guest input). Guest output is
+//! collected when the driver notifies the transmit queue. Host input is buffered and
+//! copied into the receive queue by `forward_console_input`, a task running on the VM's
+//! tokio runtime, or when the driver hands the device new receive buffers.
+
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use virtio_queue::{QueueSync, QueueT};
+use vm_memory::{Bytes, GuestMemoryMmap};
+use crate::device_emulation::virtio_mmio::{MmioTransport, VirtioDevice, VirtqueueState, VIRTIO_MMIO_INT_VRING};
+use crate::error::VmError;
+
+/// Virtio device ID of a console.
+const VIRTIO_ID_CONSOLE: u32 = 3;
+/// Queue carrying host input to the guest.
+pub const CONSOLE_RECEIVE_QUEUE: usize = 0;
+/// Queue carrying guest output to the host.
+pub const CONSOLE_TRANSMIT_QUEUE: usize = 1;
+/// Size of both console queues.
+pub const CONSOLE_QUEUE_SIZE: u16 = 256;
+/// Most bytes buffered in each direction before the writer has to wait.
+pub const CONSOLE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Bytes in flight between the device and the host stream.
+#[derive(Default)]
+struct ConsoleBuffers {
+    /// Host input not yet copied into the receive queue
+    to_guest: VecDeque<u8>,
+    /// Guest output not yet read by the host
+    from_guest: VecDeque<u8>,
+    /// Host task waiting for guest output
+    read_waker: Option<Waker>,
+    /// Host task waiting for room in `to_guest`
+    write_waker: Option<Waker>,
+    /// Set once the device is gone
+    device_gone: bool,
+}
+
+/// State shared by a `VirtioConsole` and its `ConsoleStream`.
+#[derive(Default)]
+struct ConsolePipe {
+    buffers: Mutex<ConsoleBuffers>,
+    /// Signalled when host input is waiting for the receive queue
+    input_ready: Notify,
+}
+
+impl ConsolePipe {
+    fn lock(&self) -> MutexGuard<'_, ConsoleBuffers> {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Host end of a virtio-console port.
+///
+/// Reading returns what the guest wrote to the console and ends once the VM is
+/// gone; writing feeds the guest's console input and fails with `BrokenPipe` once
+/// the VM is gone.
+pub struct ConsoleStream {
+    pipe: Arc<ConsolePipe>,
+}
+
+impl AsyncRead for ConsoleStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut buffers = self.pipe.lock();
+        if buffers.from_guest.is_empty() {
+            if buffers.device_gone {
+                return Poll::Ready(Ok(()));
+            }
+            buffers.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.remaining().min(buffers.from_guest.len());
+        let chunk: Vec<u8> = buffers.from_guest.drain(..len).collect();
+        buf.put_slice(&chunk);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ConsoleStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<std::io::Result<usize>> {
+        let mut buffers = self.pipe.lock();
+        if buffers.device_gone {
+            return Poll::Ready(Err(std::io::Error::new(ErrorKind::BrokenPipe, "the virtio console is gone")));
+        }
+        let room = CONSOLE_BUFFER_SIZE - buffers.to_guest.len();
+        if room == 0 {
+            buffers.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = room.min(data.len());
+        buffers.to_guest.extend(&data[..len]);
+        drop(buffers);
+        self.pipe.input_ready.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// virtio-console device with one port and no optional features.
+pub struct VirtioConsole {
+    mem: GuestMemoryMmap,
+    /// Receive queue (host to guest) followed by the transmit queue (guest to host)
+    queues: Vec<QueueSync>,
+    pipe: Arc<ConsolePipe>,
+}
+
+impl VirtioConsole {
+    /// Creates a console and the host stream connected to it.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory holding the virtqueues
+    ///
+    /// # Returns
+    /// * `Ok((VirtioConsole, ConsoleStream))` on success
+    /// * `Err(VmError)` if the virtqueues couldn't be created
+    pub fn new(mem: GuestMemoryMmap) -> Result<(Self, ConsoleStream), VmError> {
+        let mut queues = Vec::with_capacity(2);
+        for _ in 0..2 {
+            match QueueSync::new(CONSOLE_QUEUE_SIZE) {
+                Ok(queue) => queues.push(queue),
+                Err(e) => return Err(VmError::device_source(format!("failed to create virtqueue: {}", e), e))
+            }
+        }
+        let pipe = Arc::new(ConsolePipe::default());
+        Ok((VirtioConsole { mem, queues, pipe: Arc::clone(&pipe) }, ConsoleStream { pipe }))
+    }
+
+    /// Returns virtqueue `index`, e.g. to set it up without a guest driver.
+    pub fn get_queue_mut(&mut self, index: usize) -> Option<&mut QueueSync> {
+        self.queues.get_mut(index)
+    }
+
+    /// Copies buffered host input into the buffers the driver made available.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the filled buffers
+    pub fn fill_receive_queue(&mut self) -> bool {
+        let queue = &mut self.queues[CONSOLE_RECEIVE_QUEUE];
+        if !queue.ready() {
+            return false;
+        }
+        let mut buffers = self.pipe.lock();
+        let mut used_any = false;
+        while !buffers.to_guest.is_empty() {
+            let Some(chain) = queue.pop_descriptor_chain(&self.mem) else { break };
+            let head_index = chain.head_index();
+            let mut written = 0u32;
+            for descriptor in chain.writable() {
+                let len = (descriptor.len() as usize).min(buffers.to_guest.len());
+                let chunk: Vec<u8> = buffers.to_guest.drain(..len).collect();
+                if self.mem.write_slice(&chunk, descriptor.addr()).is_err() {
+                    break;
+                }
+                written += len as u32;
+                if buffers.to_guest.is_empty() {
+                    break;
+                }
+            }
+            if queue.add_used(&self.mem, head_index, written).is_err() {
+                break;
+            }
+            used_any = true;
+        }
+        if let Some(waker) = buffers.write_waker.take() {
+            waker.wake();
+        }
+        used_any && queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+
+    /// Collects what the guest wrote to the transmit queue.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the consumed buffers
+    fn drain_transmit_queue(&mut self) -> bool {
+        let queue = &mut self.queues[CONSOLE_TRANSMIT_QUEUE];
+        if !queue.ready() {
+            return false;
+        }
+        let mut buffers = self.pipe.lock();
+        let mut used_any = false;
+        // Output the host doesn't read stays in the guest's buffers rather than piling up here
+        while buffers.from_guest.len() < CONSOLE_BUFFER_SIZE {
+            let Some(chain) = queue.pop_descriptor_chain(&self.mem) else { break };
+            let head_index = chain.head_index();
+            for descriptor in chain.readable() {
+                let mut chunk = vec![0u8; descriptor.len() as usize];
+                if self.mem.read_slice(&mut chunk, descriptor.addr()).is_ok() {
+                    buffers.from_guest.extend(chunk);
+                }
+            }
+            if queue.add_used(&self.mem, head_index, 0).is_err() {
+                break;
+            }
+            used_any = true;
+        }
+        if let Some(waker) = buffers.read_waker.take() {
+            waker.wake();
+        }
+        used_any && queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn get_device_type(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn get_device_features(&self) -> u64 {
+        0
+    }
+
+    fn get_num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn get_queue_max_size(&self, _index: usize) -> u16 {
+        CONSOLE_QUEUE_SIZE
+    }
+
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+        match self.queues.get_mut(index) {
+            Some(queue) => state.apply(queue, &self.mem),
+            None => Err(VmError::device(format!("no virtqueue {}", index)))
+        }
+    }
+
+    fn deactivate_queue(&mut self, index: usize) {
+        if let Some(queue) = self.queues.get_mut(index) {
+            queue.reset();
+        }
+    }
+
+    fn process_queue(&mut self, index: usize) -> bool {
+        match index {
+            CONSOLE_RECEIVE_QUEUE => self.fill_receive_queue(),
+            CONSOLE_TRANSMIT_QUEUE => self.drain_transmit_queue(),
+            _ => false,
+        }
+    }
+
+    /// Drops the queue state; buffered input and output are kept for the next driver.
+    fn reset(&mut self) {
+        for queue in &mut self.queues {
+            queue.reset();
+        }
+    }
+}
+
+impl Drop for VirtioConsole {
+    fn drop(&mut self) {
+        let mut buffers = self.pipe.lock();
+        buffers.device_gone = true;
+        for waker in [buffers.read_waker.take(), buffers.write_waker.take()].into_iter().flatten() {
+            waker.wake();
+        }
+        drop(buffers);
+        self.pipe.input_ready.notify_one();
+    }
+}
+
+/// Copies host input into the console's receive queue as it arrives.
+///
+/// Spawn it on the VM's tokio runtime next to registering the transport on the MMIO
+/// bus. The task ends when the device is dropped with the VM.
+pub async fn forward_console_input(transport: Weak<Mutex<MmioTransport<VirtioConsole>>>) {
+    let pipe = match transport.upgrade() {
+        Some(transport) => {
+            let transport = transport.lock().unwrap_or_else(|e| e.into_inner());
+            Arc::clone(&transport.get_device().pipe)
+        },
+        None => return,
+    };
+    loop {
+        pipe.input_ready.notified().await;
+        if pipe.lock().device_gone {
+            return;
+        }
+        let Some(transport) = transport.upgrade() else { return };
+        let mut transport = transport.lock().unwrap_or_else(|e| e.into_inner());
+        // Input arriving before the driver is up waits for its first receive buffers
+        if transport.get_core().is_driver_ok() && transport.get_device_mut().fill_receive_queue() {
+            // The guest polls the queue again on its next notification, so a lost interrupt isn't fatal
+            let _ = transport.signal(VIRTIO_MMIO_INT_VRING);
+        }
+    }
+}
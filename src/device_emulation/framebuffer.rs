@@ -0,0 +1,204 @@
+//! Linear framebuffer and screenshots of it.
+//!
+//! The guest draws to a block of host memory mapped past its RAM, in 32-bit XRGB
+//! pixels stored little-endian (blue in the lowest byte) with rows of `width * 4`
+//! bytes, the format the Linux efifb and simpledrm drivers handle. The boot loader
+//! tells the kernel where it is through the `screen_info` of the zero page, see
+//! `x86_64_boot::write_screen_info`.
+//!
+//! Nothing traps the guest's writes: a screenshot copies the pixels as they are when
+//! it is taken and encodes them as a PNG image.
+
+use std::io::Write;
+use std::sync::Arc;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use crate::device_emulation::shared_memory::SharedMemoryMapping;
+use crate::error::VmError;
+
+/// Bytes of a pixel in the framebuffer.
+pub const FRAMEBUFFER_BYTES_PER_PIXEL: u32 = 4;
+
+/// Signature starting every PNG file.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// IHDR color type of 8-bit RGB images.
+const PNG_COLOR_TYPE_RGB: u8 = 2;
+/// Scanline filter leaving the bytes as they are.
+const PNG_FILTER_NONE: u8 = 0;
+
+/// Framebuffer of a guest display.
+#[derive(Clone)]
+pub struct Framebuffer {
+    /// Pixels, mapped into the guest by the backend
+    mapping: Arc<SharedMemoryMapping>,
+    width: u32,
+    height: u32,
+}
+
+impl Framebuffer {
+    /// Creates a framebuffer of `width` by `height` pixels over `mapping`.
+    ///
+    /// # Returns
+    /// * `Ok(Framebuffer)` on success
+    /// * `Err(VmError)` if the mapping can't hold every pixel
+    pub fn new(mapping: Arc<SharedMemoryMapping>, width: u32, height: u32) -> Result<Self, VmError> {
+        let needed = get_framebuffer_size(width, height);
+        if mapping.get_size() < needed {
+            return Err(VmError::device(format!("a {}x{} framebuffer needs {} bytes, the mapping has {}", width, height, needed, mapping.get_size())));
+        }
+        Ok(Framebuffer { mapping, width, height })
+    }
+
+    /// Returns the memory holding the pixels.
+    pub fn get_mapping(&self) -> &Arc<SharedMemoryMapping> {
+        &self.mapping
+    }
+
+    /// Returns the width of the framebuffer in pixels.
+    pub fn get_width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the framebuffer in pixels.
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the number of bytes between the start of two rows.
+    pub fn get_stride(&self) -> u32 {
+        self.width * FRAMEBUFFER_BYTES_PER_PIXEL
+    }
+
+    /// Captures what the guest currently shows.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` with the PNG image on success
+    /// * `Err(VmError)` if the image couldn't be encoded
+    pub fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+        let pixels = &self.mapping.as_slice()[..get_framebuffer_size(self.width, self.height) as usize];
+        let mut rgb = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for pixel in pixels.chunks_exact(FRAMEBUFFER_BYTES_PER_PIXEL as usize) {
+            rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+        }
+        encode_png(self.width, self.height, &rgb)
+    }
+}
+
+/// Returns the number of bytes of a `width` by `height` framebuffer.
+pub fn get_framebuffer_size(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64 * FRAMEBUFFER_BYTES_PER_PIXEL as u64
+}
+
+/// Encodes `rgb`, `height` rows of `width` 8-bit RGB pixels, as a PNG image.
+///
+/// # Returns
+/// * `Ok(Vec<u8>)` with the PNG file on success
+/// * `Err(VmError)` if `rgb` doesn't hold the pixels or compressing them failed
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Result<Vec<u8>, VmError> {
+    let row_size = width as usize * 3;
+    if rgb.len() != row_size * height as usize {
+        return Err(VmError::device(format!("{} bytes of pixels don't make a {}x{} RGB image", rgb.len(), width, height)));
+    }
+
+    // Every scanline starts with the filter applied to it
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    for row in rgb.chunks_exact(row_size.max(1)).take(height as usize) {
+        if let Err(e) = encoder.write_all(&[PNG_FILTER_NONE]).and_then(|_| encoder.write_all(row)) {
+            return Err(VmError::io(format!("failed to compress the image: {}", e), e));
+        }
+    }
+    let data = match encoder.finish() {
+        Ok(data) => data,
+        Err(e) => return Err(VmError::io(format!("failed to compress the image: {}", e), e))
+    };
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per sample, RGB, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, PNG_COLOR_TYPE_RGB, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &data);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+/// Appends a chunk of type `kind` holding `data` to `png`.
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::ZlibDecoder;
+    use crate::vm_setup::setup_utils::{SharedMemoryBacking, SharedMemoryRegion};
+
+    // Helper: split a PNG file into its chunks, checking their CRC
+    fn read_chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = rest[4..8].try_into().unwrap();
+            let data = rest[8..8 + len].to_vec();
+            let mut crc = Crc::new();
+            crc.update(&kind);
+            crc.update(&data);
+            assert_eq!(rest[8 + len..12 + len], crc.sum().to_be_bytes());
+            chunks.push((kind, data));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_encode_png_layout() {
+        let png = encode_png(2, 1, &[255, 0, 0, 0, 0, 255]).unwrap();
+        let chunks = read_chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 1, 8, PNG_COLOR_TYPE_RGB, 0, 0, 0]);
+
+        let mut scanlines = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..]).read_to_end(&mut scanlines).unwrap();
+        assert_eq!(scanlines, [PNG_FILTER_NONE, 255, 0, 0, 0, 0, 255]);
+
+        assert!(encode_png(2, 2, &[0; 6]).is_err());
+    }
+
+    #[test]
+    fn test_screenshot_converts_guest_pixels() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("framebuffer");
+        let region = SharedMemoryRegion::new(SharedMemoryBacking::Path(path.clone()), 0x1000);
+        let mapping = Arc::new(SharedMemoryMapping::open(&region).unwrap());
+        assert!(Framebuffer::new(Arc::clone(&mapping), 64, 64).is_err());
+        let framebuffer = Framebuffer::new(mapping, 2, 2).unwrap();
+        assert_eq!(framebuffer.get_stride(), 8);
+
+        // What the guest draws reaches the mapping through the file: XRGB, blue first
+        let mut pixels = vec![0u8; 16];
+        pixels[..4].copy_from_slice(&0x00ff_8000u32.to_le_bytes());
+        pixels[12..].copy_from_slice(&0x0000_00ffu32.to_le_bytes());
+        std::fs::File::options().write(true).open(&path).unwrap().write_all(&pixels).unwrap();
+
+        let chunks = read_chunks(&framebuffer.screenshot().unwrap());
+        let mut scanlines = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..]).read_to_end(&mut scanlines).unwrap();
+        assert_eq!(scanlines, [
+            PNG_FILTER_NONE, 0xff, 0x80, 0x00, 0, 0, 0,
+            PNG_FILTER_NONE, 0, 0, 0, 0x00, 0x00, 0xff,
+        ]);
+    }
+}
@@ -0,0 +1,730 @@
+//! Emulated GICv2 and GICv3 interrupt controllers for ARM64 guests.
+//!
+//! Hypervisor.framework has no interrupt controller of its own: a vCPU only has an IRQ
+//! line, which the vCPU loop marks pending before entering the guest. The controller
+//! is emulated here and the vCPU loop keeps the IRQ line pending for as long as
+//! `Gic::has_pending_irq` says so. Devices raise their SPIs through `GicSpi`, an
+//! `Interrupt` line of the distributor.
+//!
+//! Both versions share one interrupt state. A GICv2 is a distributor and a CPU
+//! interface, both MMIO register windows. A GICv3 is a distributor, a redistributor
+//! holding the SGI and PPI registers, and a CPU interface made of system registers,
+//! which the vCPU loop serves through `Gic::read_icc_register` and
+//! `Gic::write_icc_register` when the guest's accesses trap.
+//!
+//! A single CPU interface is emulated, which is all the boot vCPU the backend runs needs.
+//! Priorities are honoured against the priority mask and the running priority; binary
+//! point, groups, SGIs and LPIs aren't.
+
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use crate::device_emulation::mmio::{read_le, read_register_bytes, write_le, MmioDevice};
+use crate::error::VmError;
+use crate::utils::signals::{Interrupt, TriggerMode};
+
+/// Number of the first private peripheral interrupt; lower ones are SGIs.
+pub const GIC_PPI_BASE: u32 = 16;
+/// Number of the first shared peripheral interrupt.
+pub const GIC_SPI_BASE: u32 = 32;
+/// Number of interrupts the distributor handles, SGIs and PPIs included.
+pub const GIC_IRQ_COUNT: u32 = 128;
+/// Size of the CPU interface register window of a GICv2.
+pub const GICC_SIZE: u64 = 0x2000;
+/// Size of the register frames of the redistributor of one CPU of a GICv3.
+pub const GICR_SIZE: u64 = 0x2_0000;
+/// Interrupt number the CPU interface acknowledges when nothing is pending.
+pub const GIC_SPURIOUS_IRQ: u32 = 1023;
+
+/// Number of 32-bit words of a one bit per interrupt register bank.
+const IRQ_WORDS: usize = (GIC_IRQ_COUNT / 32) as usize;
+/// Lowest priority; the reset value of the priority mask lets nothing through.
+const GIC_IDLE_PRIORITY: u8 = 0xff;
+
+// Distributor registers, see the GICv2 architecture specification, section 4.3, and
+// the GICv3 one, section 12.9
+const GICD_CTLR: u64 = 0x000;
+const GICD_TYPER: u64 = 0x004;
+const GICD_IIDR: u64 = 0x008;
+const GICD_ISENABLER: u64 = 0x100;
+const GICD_ICENABLER: u64 = 0x180;
+const GICD_ISPENDR: u64 = 0x200;
+const GICD_ICPENDR: u64 = 0x280;
+const GICD_ISACTIVER: u64 = 0x300;
+const GICD_ICACTIVER: u64 = 0x380;
+const GICD_IPRIORITYR: u64 = 0x400;
+const GICD_ITARGETSR: u64 = 0x800;
+const GICD_ICFGR: u64 = 0xc00;
+const GICD_V2_PIDR2: u64 = 0xfe8;
+const GICD_V3_PIDR2: u64 = 0xffe8;
+/// GICD_CTLR group 1 enables; a GICv3 with a single security state has ARE and DS set
+const GICD_CTLR_ENABLE_GRP1: u32 = 0b11;
+const GICD_CTLR_ARE_DS: u32 = (1 << 4) | (1 << 6);
+// CPU interface registers of a GICv2, section 4.4
+const GICC_CTLR: u64 = 0x00;
+const GICC_PMR: u64 = 0x04;
+const GICC_IAR: u64 = 0x0c;
+const GICC_EOIR: u64 = 0x10;
+const GICC_RPR: u64 = 0x14;
+const GICC_HPPIR: u64 = 0x18;
+const GICC_IIDR: u64 = 0xfc;
+// Redistributor registers of a GICv3, section 12.10; the SGI frame follows the RD frame
+const GICR_CTLR: u64 = 0x0000;
+const GICR_IIDR: u64 = 0x0004;
+const GICR_TYPER: u64 = 0x0008;
+const GICR_WAKER: u64 = 0x0014;
+const GICR_PIDR2: u64 = 0xffe8;
+const GICR_SGI_FRAME: u64 = 0x1_0000;
+/// GICR_TYPER.Last: no redistributor follows this one
+const GICR_TYPER_LAST: u32 = 1 << 4;
+/// Implementer code of ARM, reported by the IIDR registers.
+const GIC_IIDR_ARM: u32 = 0x43b;
+
+/// System register of the CPU interface of a GICv3, as `(op0, op1, CRn, CRm, op2)`.
+pub type IccRegister = (u8, u8, u8, u8, u8);
+
+// CPU interface registers of a GICv3, section 12.2
+const ICC_PMR_EL1: IccRegister = (3, 0, 4, 6, 0);
+const ICC_IAR0_EL1: IccRegister = (3, 0, 12, 8, 0);
+const ICC_HPPIR0_EL1: IccRegister = (3, 0, 12, 8, 2);
+const ICC_AP1R_EL1: (u8, u8, u8, u8) = (3, 0, 12, 9);
+const ICC_DIR_EL1: IccRegister = (3, 0, 12, 11, 1);
+const ICC_RPR_EL1: IccRegister = (3, 0, 12, 11, 3);
+const ICC_IAR1_EL1: IccRegister = (3, 0, 12, 12, 0);
+const ICC_EOIR1_EL1: IccRegister = (3, 0, 12, 12, 1);
+const ICC_HPPIR1_EL1: IccRegister = (3, 0, 12, 12, 2);
+const ICC_CTLR_EL1: IccRegister = (3, 0, 12, 12, 4);
+const ICC_SRE_EL1: IccRegister = (3, 0, 12, 12, 5);
+const ICC_IGRPEN1_EL1: IccRegister = (3, 0, 12, 12, 7);
+/// ICC_CTLR_EL1.PRIbits: 8 bits of priority
+const ICC_CTLR_PRIBITS: u64 = 7 << 8;
+/// ICC_SRE_EL1: system registers enabled, IRQ and FIQ bypass disabled
+const ICC_SRE_ENABLED: u64 = 0x7;
+
+/// Architecture version of an emulated GIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GicVersion {
+    /// Memory-mapped CPU interface; at most 8 CPUs
+    #[default]
+    V2,
+    /// Redistributors and a system register CPU interface
+    V3,
+}
+
+impl GicVersion {
+    /// Returns the size of the distributor register window.
+    pub fn get_distributor_size(&self) -> u64 {
+        match self {
+            GicVersion::V2 => 0x1000,
+            GicVersion::V3 => 0x1_0000,
+        }
+    }
+}
+
+/// Register state of the distributor and the CPU interface.
+struct GicState {
+    version: GicVersion,
+    /// GICD_CTLR as written by the guest
+    distributor_ctlr: u32,
+    /// GICC_CTLR or ICC_IGRPEN1_EL1 enable bit
+    cpu_interface_enabled: bool,
+    /// GICC_PMR: only interrupts of a higher priority (lower value) are signalled
+    priority_mask: u8,
+    enabled: [u32; IRQ_WORDS],
+    /// Pending state latched by edges or the guest
+    pending: [u32; IRQ_WORDS],
+    active: [u32; IRQ_WORDS],
+    /// Current level of level-triggered lines; such an interrupt is pending while its
+    /// line is high and it isn't active
+    level: [u32; IRQ_WORDS],
+    /// GICD_ICFGR, two bits per interrupt
+    config: [u32; IRQ_WORDS * 2],
+    priority: [u8; GIC_IRQ_COUNT as usize],
+    /// Acknowledged interrupts not yet ended, most recent last
+    running: Vec<u32>,
+}
+
+/// Returns the word index and bit mask of `irq` in a one bit per interrupt bank.
+fn irq_bit(irq: u32) -> (usize, u32) {
+    ((irq / 32) as usize, 1 << (irq % 32))
+}
+
+impl GicState {
+    fn is_set(bank: &[u32; IRQ_WORDS], irq: u32) -> bool {
+        let (word, mask) = irq_bit(irq);
+        bank[word] & mask != 0
+    }
+
+    fn is_pending(&self, irq: u32) -> bool {
+        Self::is_set(&self.pending, irq) || (Self::is_set(&self.level, irq) && !Self::is_set(&self.active, irq))
+    }
+
+    /// Returns the priority of the interrupt being handled, or idle if none is.
+    fn get_running_priority(&self) -> u8 {
+        self.running.last().map(|irq| self.priority[*irq as usize]).unwrap_or(GIC_IDLE_PRIORITY)
+    }
+
+    /// Returns the highest priority pending and enabled interrupt, regardless of masks.
+    fn get_highest_pending(&self) -> Option<u32> {
+        (0..GIC_IRQ_COUNT)
+            .filter(|irq| Self::is_set(&self.enabled, *irq) && self.is_pending(*irq) && !Self::is_set(&self.active, *irq))
+            .min_by_key(|irq| self.priority[*irq as usize])
+    }
+
+    /// Returns the interrupt the CPU interface signals to the vCPU, if any.
+    fn get_signalled(&self) -> Option<u32> {
+        if self.distributor_ctlr & GICD_CTLR_ENABLE_GRP1 == 0 || !self.cpu_interface_enabled {
+            return None;
+        }
+        let irq = self.get_highest_pending()?;
+        let priority = self.priority[irq as usize];
+        (priority < self.priority_mask && priority < self.get_running_priority()).then_some(irq)
+    }
+
+    /// Acknowledges the signalled interrupt, making it active.
+    fn acknowledge(&mut self) -> u32 {
+        let irq = match self.get_signalled() {
+            Some(irq) => irq,
+            None => return GIC_SPURIOUS_IRQ
+        };
+        let (word, mask) = irq_bit(irq);
+        self.pending[word] &= !mask;
+        self.active[word] |= mask;
+        self.running.push(irq);
+        irq
+    }
+
+    /// Ends the handling of `irq`.
+    fn end_of_interrupt(&mut self, irq: u32) {
+        if irq >= GIC_IRQ_COUNT {
+            return;
+        }
+        let (word, mask) = irq_bit(irq);
+        self.active[word] &= !mask;
+        self.running.retain(|running| *running != irq);
+    }
+
+    /// Returns the word of a one bit per interrupt bank at `offset` from its base, if it
+    /// holds interrupts of `irqs`.
+    fn bank_word(offset: u64, irqs: &Range<u32>) -> Option<usize> {
+        let word = (offset / 4) as u32;
+        (word >= irqs.start / 32 && word < irqs.end.div_ceil(32)).then_some(word as usize)
+    }
+
+    /// Reads the 32-bit interrupt register at `offset`, laid out the same way in the
+    /// distributor and the SGI frame of the redistributor. Registers of interrupts
+    /// outside `irqs` read as zero.
+    fn read_irq_register(&self, offset: u64, irqs: &Range<u32>) -> u32 {
+        let (bank, base) = match offset {
+            o if (GICD_ISENABLER..GICD_ISPENDR).contains(&o) => (&self.enabled, (o - GICD_ISENABLER) % 0x80),
+            o if (GICD_ISPENDR..GICD_ISACTIVER).contains(&o) => {
+                return match Self::bank_word((o - GICD_ISPENDR) % 0x80, irqs) {
+                    Some(word) => (0..32u32).filter(|bit| self.is_pending(word as u32 * 32 + bit)).fold(0, |value, bit| value | 1 << bit),
+                    None => 0
+                };
+            },
+            o if (GICD_ISACTIVER..GICD_IPRIORITYR).contains(&o) => (&self.active, (o - GICD_ISACTIVER) % 0x80),
+            o if (GICD_IPRIORITYR..GICD_IPRIORITYR + GIC_IRQ_COUNT as u64).contains(&o) => {
+                let first = (o - GICD_IPRIORITYR) as u32;
+                return (0..4u32)
+                    .filter(|index| irqs.contains(&(first + index)))
+                    .fold(0, |value, index| value | (self.priority[(first + index) as usize] as u32) << (8 * index));
+            },
+            o if (GICD_ICFGR..GICD_ICFGR + IRQ_WORDS as u64 * 8).contains(&o) => {
+                let word = ((o - GICD_ICFGR) / 4) as u32;
+                return if irqs.contains(&(word * 16)) { self.config[word as usize] } else { 0 };
+            },
+            _ => return 0
+        };
+        Self::bank_word(base, irqs).map(|word| bank[word]).unwrap_or(0)
+    }
+
+    /// Writes the interrupt registers at `offset`, see `read_irq_register`. Writes to
+    /// interrupts outside `irqs` are ignored.
+    fn write_irq_register(&mut self, offset: u64, data: &[u8], irqs: &Range<u32>) {
+        // Priority registers hold one byte per interrupt
+        if (GICD_IPRIORITYR..GICD_IPRIORITYR + GIC_IRQ_COUNT as u64).contains(&offset) {
+            let first = (offset - GICD_IPRIORITYR) as u32;
+            for (index, byte) in data.iter().enumerate() {
+                let irq = first + index as u32;
+                if irqs.contains(&irq) {
+                    self.priority[irq as usize] = *byte;
+                }
+            }
+            return;
+        }
+        let value = read_le(data) as u32;
+        let (bank, base, set) = match offset {
+            o if (GICD_ISENABLER..GICD_ICENABLER).contains(&o) => (&mut self.enabled, o - GICD_ISENABLER, true),
+            o if (GICD_ICENABLER..GICD_ISPENDR).contains(&o) => (&mut self.enabled, o - GICD_ICENABLER, false),
+            o if (GICD_ISPENDR..GICD_ICPENDR).contains(&o) => (&mut self.pending, o - GICD_ISPENDR, true),
+            o if (GICD_ICPENDR..GICD_ISACTIVER).contains(&o) => (&mut self.pending, o - GICD_ICPENDR, false),
+            o if (GICD_ISACTIVER..GICD_ICACTIVER).contains(&o) => (&mut self.active, o - GICD_ISACTIVER, true),
+            o if (GICD_ICACTIVER..GICD_IPRIORITYR).contains(&o) => (&mut self.active, o - GICD_ICACTIVER, false),
+            o if (GICD_ICFGR..GICD_ICFGR + IRQ_WORDS as u64 * 8).contains(&o) => {
+                let word = ((o - GICD_ICFGR) / 4) as u32;
+                if irqs.contains(&(word * 16)) {
+                    self.config[word as usize] = value;
+                }
+                return;
+            },
+            _ => return
+        };
+        if let Some(word) = Self::bank_word(base, irqs) {
+            if set {
+                bank[word] |= value;
+            } else {
+                bank[word] &= !value;
+            }
+        }
+    }
+}
+
+/// Interrupt notification hook, called whenever the signalled interrupt may have changed.
+type GicKick = Box<dyn Fn() + Send + Sync>;
+
+/// State shared by the register windows, the interrupt lines and the vCPU loop.
+pub struct Gic {
+    state: Mutex<GicState>,
+    /// Signalled when an interrupt is raised, for vCPUs waiting in WFI
+    raised: Condvar,
+    /// Makes a running vCPU exit so it picks up a newly raised interrupt
+    kick: Mutex<Option<GicKick>>,
+}
+
+impl Gic {
+    /// Creates a GIC with every interrupt disabled, inactive and at the highest priority.
+    pub fn new(version: GicVersion) -> Arc<Gic> {
+        Arc::new(Gic {
+            state: Mutex::new(GicState {
+                version,
+                distributor_ctlr: 0,
+                cpu_interface_enabled: false,
+                priority_mask: 0,
+                enabled: [0; IRQ_WORDS],
+                pending: [0; IRQ_WORDS],
+                active: [0; IRQ_WORDS],
+                level: [0; IRQ_WORDS],
+                config: [0; IRQ_WORDS * 2],
+                priority: [0; GIC_IRQ_COUNT as usize],
+                running: Vec::new(),
+            }),
+            raised: Condvar::new(),
+            kick: Mutex::new(None),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, GicState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the architecture version of the GIC.
+    pub fn get_version(&self) -> GicVersion {
+        self.lock().version
+    }
+
+    /// Sets the hook making a running vCPU exit when an interrupt is raised.
+    pub fn set_kick(&self, kick: GicKick) {
+        *self.kick.lock().unwrap_or_else(|e| e.into_inner()) = Some(kick);
+    }
+
+    /// Returns `true` if the vCPU must enter the guest with its IRQ line pending.
+    pub fn has_pending_irq(&self) -> bool {
+        self.lock().get_signalled().is_some()
+    }
+
+    /// Blocks until an interrupt is signalled or `timeout` elapsed, for a vCPU in WFI.
+    pub fn wait_for_irq(&self, timeout: Duration) {
+        let state = self.lock();
+        if state.get_signalled().is_none() {
+            let _ = self.raised.wait_timeout(state, timeout);
+        }
+    }
+
+    /// Sets the level of private peripheral interrupt `ppi`, interrupt number
+    /// `GIC_PPI_BASE + ppi`, such as the virtual timer the vCPU loop forwards.
+    pub fn set_ppi_level(&self, ppi: u32, high: bool) {
+        if ppi < GIC_SPI_BASE - GIC_PPI_BASE {
+            self.set_line(GIC_PPI_BASE + ppi, TriggerMode::Level, high);
+        }
+    }
+
+    /// Reads a system register of the CPU interface of a GICv3, acknowledging the
+    /// signalled interrupt for ICC_IAR1_EL1.
+    ///
+    /// # Returns
+    /// * `Some(value)` with the register value
+    /// * `None` if `register` isn't a CPU interface register or the GIC is a GICv2
+    pub fn read_icc_register(&self, register: IccRegister) -> Option<u64> {
+        let mut state = self.lock();
+        if state.version != GicVersion::V3 {
+            return None;
+        }
+        let value = match register {
+            ICC_PMR_EL1 => state.priority_mask as u64,
+            ICC_IAR1_EL1 => state.acknowledge() as u64,
+            ICC_HPPIR1_EL1 => state.get_highest_pending().unwrap_or(GIC_SPURIOUS_IRQ) as u64,
+            ICC_RPR_EL1 => state.get_running_priority() as u64,
+            ICC_CTLR_EL1 => ICC_CTLR_PRIBITS,
+            ICC_SRE_EL1 => ICC_SRE_ENABLED,
+            ICC_IGRPEN1_EL1 => state.cpu_interface_enabled as u64,
+            // Group 0 is never signalled
+            ICC_IAR0_EL1 | ICC_HPPIR0_EL1 => GIC_SPURIOUS_IRQ as u64,
+            (3, 0, 12, 8, _) => 0,
+            (op0, op1, crn, crm, _) if (op0, op1, crn, crm) == ICC_AP1R_EL1 => 0,
+            _ => return None
+        };
+        Some(value)
+    }
+
+    /// Writes a system register of the CPU interface of a GICv3.
+    ///
+    /// # Returns
+    /// * `true` if the write was served, possibly ignored as for read-only registers
+    /// * `false` if `register` isn't a CPU interface register or the GIC is a GICv2
+    pub fn write_icc_register(&self, register: IccRegister, value: u64) -> bool {
+        {
+            let mut state = self.lock();
+            if state.version != GicVersion::V3 {
+                return false;
+            }
+            match register {
+                ICC_PMR_EL1 => state.priority_mask = value as u8,
+                // The interrupt number is in the low 24 bits
+                ICC_EOIR1_EL1 => state.end_of_interrupt((value & 0xff_ffff) as u32),
+                ICC_IGRPEN1_EL1 => state.cpu_interface_enabled = value & 1 != 0,
+                // Interrupts are deactivated at EOI, SGIs to other CPUs have no target
+                ICC_DIR_EL1 | ICC_CTLR_EL1 | ICC_SRE_EL1 | (3, 0, 12, 11, 5) | (3, 0, 12, 8, _) | (3, 0, 12, 12, 6) => {},
+                (op0, op1, crn, crm, _) if (op0, op1, crn, crm) == ICC_AP1R_EL1 => {},
+                _ => return false
+            }
+        }
+        self.raised.notify_all();
+        true
+    }
+
+    /// Sets the level of the line of `irq`, or latches an edge on it.
+    fn set_line(&self, irq: u32, trigger_mode: TriggerMode, high: bool) {
+        {
+            let mut state = self.lock();
+            let (word, mask) = irq_bit(irq);
+            match (trigger_mode, high) {
+                (TriggerMode::Edge, true) => state.pending[word] |= mask,
+                (TriggerMode::Edge, false) => return,
+                (TriggerMode::Level, true) => state.level[word] |= mask,
+                (TriggerMode::Level, false) => {
+                    state.level[word] &= !mask;
+                    return;
+                }
+            }
+        }
+        self.raised.notify_all();
+        if let Some(kick) = self.kick.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            kick();
+        }
+    }
+}
+
+/// A shared peripheral interrupt line of the distributor, raised by a device.
+pub struct GicSpi {
+    gic: Arc<Gic>,
+    irq: u32,
+    trigger_mode: TriggerMode,
+}
+
+impl GicSpi {
+    /// Creates the line of SPI `spi`, interrupt number `GIC_SPI_BASE + spi`.
+    ///
+    /// # Returns
+    /// * `Ok(GicSpi)` on success
+    /// * `Err(VmError)` if the distributor has no such SPI
+    pub fn new(gic: Arc<Gic>, spi: u32, trigger_mode: TriggerMode) -> Result<GicSpi, VmError> {
+        if spi >= GIC_IRQ_COUNT - GIC_SPI_BASE {
+            return Err(VmError::config(format!("SPI {} is out of range, the GIC has {}", spi, GIC_IRQ_COUNT - GIC_SPI_BASE)));
+        }
+        Ok(GicSpi { gic, irq: GIC_SPI_BASE + spi, trigger_mode })
+    }
+
+    /// Returns the interrupt number of the line.
+    pub fn get_irq(&self) -> u32 {
+        self.irq
+    }
+}
+
+impl Interrupt for GicSpi {
+    fn get_trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    fn assert(&self) -> Result<(), VmError> {
+        self.gic.set_line(self.irq, self.trigger_mode, true);
+        Ok(())
+    }
+
+    fn deassert(&self) -> Result<(), VmError> {
+        self.gic.set_line(self.irq, self.trigger_mode, false);
+        Ok(())
+    }
+}
+
+/// Distributor register window of a `Gic`.
+pub struct GicDistributor {
+    gic: Arc<Gic>,
+}
+
+impl GicDistributor {
+    /// Creates the distributor of `gic`.
+    pub fn new(gic: Arc<Gic>) -> Self {
+        GicDistributor { gic }
+    }
+}
+
+impl MmioDevice for GicDistributor {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        let state = self.gic.lock();
+        // The redistributor holds the SGI and PPI registers of a GICv3
+        let irqs = match state.version {
+            GicVersion::V2 => 0..GIC_IRQ_COUNT,
+            GicVersion::V3 => GIC_SPI_BASE..GIC_IRQ_COUNT,
+        };
+        if state.version == GicVersion::V2 && (GICD_ITARGETSR..GICD_ITARGETSR + GIC_IRQ_COUNT as u64).contains(&offset) {
+            // Every interrupt targets the single CPU interface
+            data.fill(1);
+            return;
+        }
+        read_register_bytes(offset, data, |offset| match (state.version, offset) {
+            (GicVersion::V2, GICD_CTLR) => state.distributor_ctlr & 1,
+            (GicVersion::V3, GICD_CTLR) => state.distributor_ctlr & GICD_CTLR_ENABLE_GRP1 | GICD_CTLR_ARE_DS,
+            // ITLinesNumber, one CPU interface
+            (GicVersion::V2, GICD_TYPER) => GIC_IRQ_COUNT / 32 - 1,
+            // ITLinesNumber, 10 interrupt ID bits
+            (GicVersion::V3, GICD_TYPER) => (GIC_IRQ_COUNT / 32 - 1) | 9 << 19,
+            (_, GICD_IIDR) => GIC_IIDR_ARM,
+            // ArchRev
+            (GicVersion::V2, GICD_V2_PIDR2) => 0x2 << 4,
+            (GicVersion::V3, GICD_V3_PIDR2) => 0x3 << 4,
+            // GICD_IROUTER of a GICv3 reads as zero: every SPI targets CPU 0
+            (_, offset) => state.read_irq_register(offset, &irqs),
+        });
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        let raised = {
+            let mut state = self.gic.lock();
+            let irqs = match state.version {
+                GicVersion::V2 => 0..GIC_IRQ_COUNT,
+                GicVersion::V3 => GIC_SPI_BASE..GIC_IRQ_COUNT,
+            };
+            match offset {
+                GICD_CTLR => state.distributor_ctlr = read_le(data) as u32,
+                offset => state.write_irq_register(offset, data, &irqs),
+            }
+            state.get_signalled().is_some()
+        };
+        // Enabling an interrupt or the distributor may signal one that was already pending
+        if raised {
+            self.gic.raised.notify_all();
+        }
+    }
+}
+
+/// CPU interface register window of a GICv2 `Gic`.
+pub struct GicCpuInterface {
+    gic: Arc<Gic>,
+}
+
+impl GicCpuInterface {
+    /// Creates the CPU interface of `gic`.
+    pub fn new(gic: Arc<Gic>) -> Self {
+        GicCpuInterface { gic }
+    }
+}
+
+impl MmioDevice for GicCpuInterface {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        let mut state = self.gic.lock();
+        // Reading IAR acknowledges the interrupt, so it is served once and not per byte
+        if offset == GICC_IAR {
+            let irq = state.acknowledge();
+            write_le(data, irq as u64);
+            return;
+        }
+        read_register_bytes(offset, data, |offset| match offset {
+            GICC_CTLR => state.cpu_interface_enabled as u32,
+            GICC_PMR => state.priority_mask as u32,
+            GICC_RPR => state.get_running_priority() as u32,
+            GICC_HPPIR => state.get_highest_pending().unwrap_or(GIC_SPURIOUS_IRQ),
+            // GICv2, ARM
+            GICC_IIDR => 0x0002_0000 | GIC_IIDR_ARM,
+            _ => 0,
+        });
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        let value = read_le(data) as u32;
+        let raised = {
+            let mut state = self.gic.lock();
+            match offset {
+                GICC_CTLR => state.cpu_interface_enabled = value & 1 != 0,
+                GICC_PMR => state.priority_mask = value as u8,
+                // The interrupt number is in the low 10 bits
+                GICC_EOIR => state.end_of_interrupt(value & 0x3ff),
+                _ => {}
+            }
+            state.get_signalled().is_some()
+        };
+        if raised {
+            self.gic.raised.notify_all();
+        }
+    }
+}
+
+/// Redistributor register frames of the single CPU of a GICv3 `Gic`: the RD frame,
+/// then the SGI frame with the SGI and PPI registers.
+pub struct GicRedistributor {
+    gic: Arc<Gic>,
+}
+
+impl GicRedistributor {
+    /// Creates the redistributor of `gic`.
+    pub fn new(gic: Arc<Gic>) -> Self {
+        GicRedistributor { gic }
+    }
+}
+
+impl MmioDevice for GicRedistributor {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        let state = self.gic.lock();
+        read_register_bytes(offset, data, |offset| match offset {
+            // The CPU interface is always awake, so GICR_WAKER reads as zero
+            GICR_CTLR | GICR_WAKER => 0,
+            GICR_IIDR => GIC_IIDR_ARM,
+            // Affinity 0 in the upper half, and the only redistributor
+            GICR_TYPER => GICR_TYPER_LAST,
+            // ArchRev
+            GICR_PIDR2 => 0x3 << 4,
+            o if o >= GICR_SGI_FRAME => state.read_irq_register(o - GICR_SGI_FRAME, &(0..GIC_SPI_BASE)),
+            _ => 0,
+        });
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        let raised = {
+            let mut state = self.gic.lock();
+            if offset >= GICR_SGI_FRAME {
+                state.write_irq_register(offset - GICR_SGI_FRAME, data, &(0..GIC_SPI_BASE));
+            }
+            state.get_signalled().is_some()
+        };
+        if raised {
+            self.gic.raised.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write32(device: &mut dyn MmioDevice, offset: u64, value: u32) {
+        device.write_mmio(offset, &value.to_le_bytes());
+    }
+
+    fn read32(device: &mut dyn MmioDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read_mmio(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn enabled_gic() -> (Arc<Gic>, GicDistributor, GicCpuInterface) {
+        let gic = Gic::new(GicVersion::V2);
+        let mut distributor = GicDistributor::new(gic.clone());
+        let mut cpu_interface = GicCpuInterface::new(gic.clone());
+        write32(&mut distributor, GICD_CTLR, 1);
+        write32(&mut cpu_interface, GICC_CTLR, 1);
+        write32(&mut cpu_interface, GICC_PMR, 0xf0);
+        (gic, distributor, cpu_interface)
+    }
+
+    #[test]
+    fn test_level_spi_is_acknowledged_and_ended() {
+        let (gic, mut distributor, mut cpu_interface) = enabled_gic();
+        let spi = GicSpi::new(gic.clone(), 3, TriggerMode::Level).unwrap();
+        assert_eq!(spi.get_irq(), 35);
+
+        // Disabled interrupts aren't signalled
+        spi.assert().unwrap();
+        assert!(!gic.has_pending_irq());
+        write32(&mut distributor, GICD_ISENABLER + 4, 1 << 3);
+        assert!(gic.has_pending_irq());
+        assert_eq!(read32(&mut distributor, GICD_ISPENDR + 4), 1 << 3);
+
+        assert_eq!(read32(&mut cpu_interface, GICC_IAR), 35);
+        assert!(!gic.has_pending_irq());
+        assert_eq!(read32(&mut cpu_interface, GICC_IAR), GIC_SPURIOUS_IRQ);
+
+        // Still asserted at EOI, so pending again; deasserted, gone
+        write32(&mut cpu_interface, GICC_EOIR, 35);
+        assert!(gic.has_pending_irq());
+        spi.deassert().unwrap();
+        assert!(!gic.has_pending_irq());
+    }
+
+    #[test]
+    fn test_priority_mask_and_preemption() {
+        let (gic, mut distributor, mut cpu_interface) = enabled_gic();
+        let low = GicSpi::new(gic.clone(), 0, TriggerMode::Edge).unwrap();
+        let high = GicSpi::new(gic.clone(), 1, TriggerMode::Edge).unwrap();
+        write32(&mut distributor, GICD_ISENABLER + 4, 0b11);
+        distributor.write_mmio(GICD_IPRIORITYR + 32, &[0xa0, 0x20]);
+        assert_eq!(read32(&mut distributor, GICD_IPRIORITYR + 32), 0x20a0);
+
+        low.trigger().unwrap();
+        assert_eq!(read32(&mut cpu_interface, GICC_IAR), 32);
+        assert_eq!(read32(&mut cpu_interface, GICC_RPR), 0xa0);
+        // A higher priority interrupt preempts the running one, the same one doesn't
+        high.trigger().unwrap();
+        low.trigger().unwrap();
+        assert_eq!(read32(&mut cpu_interface, GICC_IAR), 33);
+        assert!(!gic.has_pending_irq());
+        write32(&mut cpu_interface, GICC_EOIR, 33);
+        write32(&mut cpu_interface, GICC_EOIR, 32);
+        assert!(gic.has_pending_irq());
+
+        // Masked by the priority mask
+        write32(&mut cpu_interface, GICC_PMR, 0xa0);
+        assert!(!gic.has_pending_irq());
+    }
+
+    #[test]
+    fn test_gicv3_redistributor_and_system_registers() {
+        let gic = Gic::new(GicVersion::V3);
+        let mut distributor = GicDistributor::new(gic.clone());
+        let mut redistributor = GicRedistributor::new(gic.clone());
+        assert_eq!(read32(&mut distributor, GICD_V3_PIDR2), 0x30);
+        assert_eq!(read32(&mut redistributor, GICR_PIDR2), 0x30);
+        assert_ne!(read32(&mut redistributor, GICR_TYPER) & GICR_TYPER_LAST, 0);
+        assert_eq!(gic.read_icc_register(ICC_SRE_EL1), Some(ICC_SRE_ENABLED));
+        assert_eq!(gic.read_icc_register((3, 0, 1, 0, 0)), None);
+
+        write32(&mut distributor, GICD_CTLR, GICD_CTLR_ENABLE_GRP1);
+        assert!(gic.write_icc_register(ICC_IGRPEN1_EL1, 1));
+        assert!(gic.write_icc_register(ICC_PMR_EL1, 0xf0));
+
+        // PPIs are enabled in the redistributor, not the distributor
+        gic.set_ppi_level(11, true);
+        write32(&mut distributor, GICD_ISENABLER, 1 << 27);
+        assert!(!gic.has_pending_irq());
+        write32(&mut redistributor, GICR_SGI_FRAME + GICD_ISENABLER, 1 << 27);
+        assert!(gic.has_pending_irq());
+
+        assert_eq!(gic.read_icc_register(ICC_IAR1_EL1), Some(27));
+        assert_eq!(gic.read_icc_register(ICC_IAR1_EL1), Some(GIC_SPURIOUS_IRQ as u64));
+        gic.set_ppi_level(11, false);
+        assert!(gic.write_icc_register(ICC_EOIR1_EL1, 27));
+        assert!(!gic.has_pending_irq());
+
+        // A GICv2 has no system register interface
+        assert_eq!(Gic::new(GicVersion::V2).read_icc_register(ICC_IAR1_EL1), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Worker thread processing device queues off the vCPU thread.
+//!
+//! A device handing its queue processing to an `IoWorker` returns from the guest's
+//! notification right away, so the vCPU goes back to running the guest while the disk
+//! I/O happens; the worker raises the device interrupt once requests completed,
+//! usually through a `VringNotifier`. Kicks are processed in order; a kick for a queue
+//! that was already drained by an earlier one finds nothing to do.
+
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::error::VmError;
+
+/// Kicks queued or being processed, and the condition signalled when none are left.
+type PendingKicks = Arc<(Mutex<usize>, Condvar)>;
+
+/// Thread processing the queues of a device when kicked.
+pub struct IoWorker {
+    /// Kicks for the thread, carrying the queue index; dropped to stop the thread
+    sender: Option<Sender<usize>>,
+    /// The worker thread
+    thread: Option<JoinHandle<()>>,
+    /// Kicks sent but not processed yet
+    pending: PendingKicks,
+}
+
+impl IoWorker {
+    /// Starts a worker thread calling `process` with the index of every kicked queue.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the thread, e.g. `virtio-blk0-io`
+    /// * `process` - Processes a queue and signals the guest about completed requests
+    ///
+    /// # Returns
+    /// * `Ok(IoWorker)` on success
+    /// * `Err(VmError)` if the thread couldn't be started
+    pub fn spawn<F>(name: &str, mut process: F) -> Result<IoWorker, VmError>
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        let (sender, receiver) = channel::<usize>();
+        let pending: PendingKicks = Arc::new((Mutex::new(0), Condvar::new()));
+        let worker_pending = Arc::clone(&pending);
+        let spawned = std::thread::Builder::new().name(name.to_string()).spawn(move || {
+            for index in receiver {
+                process(index);
+                let (count, idle) = &*worker_pending;
+                let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+                *count -= 1;
+                if *count == 0 {
+                    idle.notify_all();
+                }
+            }
+        });
+        match spawned {
+            Ok(thread) => Ok(IoWorker { sender: Some(sender), thread: Some(thread), pending }),
+            Err(e) => Err(VmError::io(format!("failed to start I/O worker {}: {}", name, e), e))
+        }
+    }
+
+    /// Asks the worker to process queue `index`.
+    ///
+    /// # Returns
+    /// * `true` if the kick was queued
+    /// * `false` if the worker thread is gone, e.g. after `process` panicked
+    pub fn kick(&self, index: usize) -> bool {
+        let Some(sender) = &self.sender else { return false };
+        let (count, _) = &*self.pending;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        if sender.send(index).is_ok() {
+            return true;
+        }
+        *count.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        false
+    }
+
+    /// Waits until every kick sent so far has been processed, e.g. before the queues
+    /// are reset under the worker's feet.
+    pub fn wait_idle(&self) {
+        let (count, idle) = &*self.pending;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count > 0 {
+            // A thread that died with kicks queued never brings the count down
+            if self.thread.as_ref().is_none_or(|thread| thread.is_finished()) {
+                return;
+            }
+            count = match idle.wait_timeout(count, Duration::from_millis(100)) {
+                Ok((count, _)) => count,
+                Err(e) => e.into_inner().0
+            };
+        }
+    }
+}
+
+impl Drop for IoWorker {
+    /// Stops the thread once it processed the kicks already sent.
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_kicks_run_on_the_worker_thread() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let worker_processed = Arc::clone(&processed);
+        let worker = IoWorker::spawn("test-io", move |index| {
+            std::thread::sleep(Duration::from_millis(5));
+            let name = std::thread::current().name().map(str::to_string);
+            worker_processed.lock().unwrap().push((index, name));
+        }).unwrap();
+
+        assert!(worker.kick(0));
+        assert!(worker.kick(1));
+        worker.wait_idle();
+        let name = Some("test-io".to_string());
+        assert_eq!(*processed.lock().unwrap(), vec![(0, name.clone()), (1, name)]);
+    }
+
+    #[test]
+    fn test_dead_worker_does_not_block() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let worker_runs = Arc::clone(&runs);
+        let worker = IoWorker::spawn("test-io-panic", move |_| {
+            worker_runs.fetch_add(1, Ordering::SeqCst);
+            panic!("device bug");
+        }).unwrap();
+
+        worker.kick(0);
+        worker.wait_idle();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // The thread is gone; kicks are refused instead of piling up
+        while worker.kick(0) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        worker.wait_idle();
+    }
+}
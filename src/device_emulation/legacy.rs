@@ -0,0 +1,230 @@
+//! Minimal legacy PC devices living in the x86 I/O port space.
+//!
+//! Guests probe these ports while booting; the models here answer just enough for
+//! a Linux kernel to get past the probes and reboot. The COM1 UART lives in
+//! `device_emulation::serial`, the PCI devices in `device_emulation::pci`.
+
+use crate::device_emulation::mmio::{read_le, write_le};
+use crate::device_emulation::pci::PciBus;
+use crate::device_emulation::pio::PioDevice;
+use crate::vm_setup::boot_progress::{BootProgress, BootStage};
+use std::sync::Arc;
+
+/// First I/O port of the i8042 keyboard controller (data port, status/command at +4).
+pub const I8042_PORT_BASE: u16 = 0x60;
+/// Number of I/O ports spanned by the i8042.
+pub const I8042_PORT_COUNT: u16 = 5;
+/// First I/O port of the PCI configuration mechanism #1 (address at +0, data at +4).
+pub const PCI_CONFIG_PORT_BASE: u16 = 0xcf8;
+/// Number of I/O ports of the PCI configuration mechanism.
+pub const PCI_CONFIG_PORT_COUNT: u16 = 8;
+/// I/O port of the ISA pvpanic device.
+pub const PVPANIC_PORT: u16 = 0x505;
+
+/// i8042 command pulsing the CPU reset line.
+const I8042_CMD_RESET_CPU: u8 = 0xfe;
+/// Offset of the i8042 status/command port.
+const I8042_COMMAND_OFFSET: u16 = 4;
+/// Offset of the PCI configuration data port.
+const PCI_CONFIG_DATA_OFFSET: u16 = 4;
+/// Address register bit enabling configuration accesses through the data port.
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+/// pvpanic event: the guest kernel panicked.
+const PVPANIC_PANICKED: u8 = 0x01;
+/// pvpanic event: a crash kernel was loaded after a panic.
+const PVPANIC_CRASH_LOADED: u8 = 0x02;
+
+/// i8042 keyboard controller without a keyboard.
+///
+/// Reads report no pending data. The only command handled is the CPU reset pulse
+/// (0xfe), which Linux uses to reboot with `reboot=k`.
+#[derive(Debug, Default)]
+pub struct I8042Device {
+    reset_requested: bool,
+}
+
+impl I8042Device {
+    /// Creates a keyboard controller with no reset requested.
+    pub fn new() -> Self {
+        I8042Device { reset_requested: false }
+    }
+
+    /// Returns `true` once the guest pulsed the CPU reset line.
+    pub fn is_reset_requested(&self) -> bool {
+        self.reset_requested
+    }
+}
+
+impl PioDevice for I8042Device {
+    fn read_pio(&mut self, _offset: u16, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn write_pio(&mut self, offset: u16, data: &[u8]) {
+        if offset == I8042_COMMAND_OFFSET && data.first() == Some(&I8042_CMD_RESET_CPU) {
+            self.reset_requested = true;
+        }
+    }
+}
+
+/// PCI configuration mechanism #1.
+///
+/// The address register latches what the guest writes. Configuration accesses go to
+/// the function it selects on the `PciBus`, if any; without a bus every configuration
+/// read returns all ones, which tells the guest there is no device at that address.
+#[derive(Default)]
+pub struct PciConfigPorts {
+    address: u32,
+    bus: Option<Arc<PciBus>>,
+}
+
+impl PciConfigPorts {
+    /// Creates the configuration ports of a bus with no device, with a cleared address register.
+    pub fn new() -> Self {
+        PciConfigPorts { address: 0, bus: None }
+    }
+
+    /// Creates the configuration ports of `bus`, with a cleared address register.
+    pub fn with_bus(bus: Arc<PciBus>) -> Self {
+        PciConfigPorts { address: 0, bus: Some(bus) }
+    }
+
+    /// Returns the bus, slot, function and register offset selected by the address
+    /// register for an access at `offset` in the data port, `None` if configuration
+    /// accesses aren't enabled.
+    fn get_selected_function(&self, offset: u16) -> Option<(u8, u8, u8, u64)> {
+        if self.address & PCI_CONFIG_ENABLE == 0 {
+            return None;
+        }
+        let register = (self.address & 0xfc) as u64 + (offset - PCI_CONFIG_DATA_OFFSET) as u64;
+        Some(((self.address >> 16) as u8, (self.address >> 11) as u8 & 0x1f, (self.address >> 8) as u8 & 0x7, register))
+    }
+}
+
+impl PioDevice for PciConfigPorts {
+    fn read_pio(&mut self, offset: u16, data: &mut [u8]) {
+        match offset {
+            0..=3 => write_le(data, (self.address >> (8 * offset as u32)) as u64),
+            _ => match (&self.bus, self.get_selected_function(offset)) {
+                (Some(bus), Some((bus_number, slot, function, register))) => bus.read_config(bus_number, slot, function, register, data),
+                _ => data.fill(0xff)
+            },
+        }
+    }
+
+    fn write_pio(&mut self, offset: u16, data: &[u8]) {
+        if offset >= PCI_CONFIG_DATA_OFFSET {
+            // Without a bus, configuration data writes go nowhere
+            if let (Some(bus), Some((bus_number, slot, function, register))) = (&self.bus, self.get_selected_function(offset)) {
+                bus.write_config(bus_number, slot, function, register, data);
+            }
+            return;
+        }
+        if offset as usize + data.len() <= 4 {
+            let shift = 8 * offset as u32;
+            let mask = match data.len() {
+                4 => u32::MAX,
+                len => ((1u32 << (8 * len)) - 1) << shift,
+            };
+            self.address = (self.address & !mask) | ((read_le(data) as u32) << shift & mask);
+        }
+    }
+}
+
+/// ISA pvpanic device reporting guest panics to the boot progress.
+///
+/// Reads return the events the device supports. The guest finds the port through
+/// ACPI or the device tree, or a guest agent writes to it directly.
+pub struct PvPanicDevice {
+    progress: Arc<BootProgress>,
+}
+
+impl PvPanicDevice {
+    /// Creates a pvpanic device reporting to `progress`.
+    pub fn new(progress: Arc<BootProgress>) -> Self {
+        PvPanicDevice { progress }
+    }
+}
+
+impl PioDevice for PvPanicDevice {
+    fn read_pio(&mut self, _offset: u16, data: &mut [u8]) {
+        data.fill(0);
+        data[0] = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+    }
+
+    fn write_pio(&mut self, _offset: u16, data: &[u8]) {
+        match data.first() {
+            Some(event) if event & PVPANIC_PANICKED != 0 => self.progress.advance(BootStage::Panicked),
+            // A crash kernel taking over is still a sign of life
+            Some(event) if event & PVPANIC_CRASH_LOADED != 0 => self.progress.record_activity(),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_i8042_reset_command() {
+        let mut i8042 = I8042Device::new();
+        i8042.write_pio(I8042_COMMAND_OFFSET, &[0xad]);
+        assert!(!i8042.is_reset_requested());
+        i8042.write_pio(I8042_COMMAND_OFFSET, &[I8042_CMD_RESET_CPU]);
+        assert!(i8042.is_reset_requested());
+    }
+
+    #[test]
+    fn test_pci_config_without_devices() {
+        let mut pci = PciConfigPorts::new();
+        pci.write_pio(0, &0x8000_0000u32.to_le_bytes());
+        pci.write_pio(2, &[0x12]);
+
+        let mut address = [0u8; 4];
+        pci.read_pio(0, &mut address);
+        assert_eq!(u32::from_le_bytes(address), 0x8012_0000);
+
+        let mut vendor = [0u8; 2];
+        pci.read_pio(4, &mut vendor);
+        assert_eq!(vendor, [0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_pci_config_reaches_the_bus() {
+        let mut pci = PciConfigPorts::with_bus(Arc::new(PciBus::new()));
+        let mut vendor = [0u8; 2];
+        pci.write_pio(0, &0x8000_0000u32.to_le_bytes());
+        pci.read_pio(4, &mut vendor);
+        assert_ne!(vendor, [0xff, 0xff]);
+
+        // Slot 1 is empty, and nothing is read while accesses are disabled
+        pci.write_pio(0, &0x8000_0800u32.to_le_bytes());
+        pci.read_pio(4, &mut vendor);
+        assert_eq!(vendor, [0xff, 0xff]);
+        pci.write_pio(0, &0u32.to_le_bytes());
+        pci.read_pio(4, &mut vendor);
+        assert_eq!(vendor, [0xff, 0xff]);
+
+        // The class code of the host bridge, read a byte at a time through the data port
+        pci.write_pio(0, &0x8000_0008u32.to_le_bytes());
+        let mut class = [0u8; 1];
+        pci.read_pio(7, &mut class);
+        assert_eq!(class, [0x06]);
+    }
+
+    #[test]
+    fn test_pvpanic_reports_panic() {
+        let progress = Arc::new(BootProgress::new());
+        let mut pvpanic = PvPanicDevice::new(progress.clone());
+
+        let mut events = [0u8; 1];
+        pvpanic.read_pio(0, &mut events);
+        assert_eq!(events, [PVPANIC_PANICKED | PVPANIC_CRASH_LOADED]);
+
+        pvpanic.write_pio(0, &[PVPANIC_CRASH_LOADED]);
+        assert_eq!(progress.get_stage(), BootStage::NotStarted);
+        pvpanic.write_pio(0, &[PVPANIC_PANICKED]);
+        assert_eq!(progress.get_stage(), BootStage::Panicked);
+    }
+}
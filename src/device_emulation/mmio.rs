@@ -0,0 +1,274 @@
+//! MMIO bus and helpers shared by MMIO device models.
+//!
+//! Guest MMIO accesses arrive as an address plus a 1, 2, 4 or 8 byte little-endian
+//! data buffer, the way KVM reports them in `VcpuExit::MmioRead`/`MmioWrite`. Devices
+//! model their registers as 32-bit values and use these helpers to serve accesses of
+//! any width. The `MmioBus` routes each access to the device owning the address.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use crate::error::VmError;
+
+/// A device exposing registers in guest physical address space.
+///
+/// Offsets are relative to the base address the device was registered at.
+pub trait MmioDevice: Send {
+    /// Handles a guest read of `data.len()` bytes at `offset`.
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]);
+
+    /// Handles a guest write of `data` at `offset`.
+    fn write_mmio(&mut self, offset: u64, data: &[u8]);
+}
+
+/// An address range claimed by a device on the bus.
+struct MmioRange {
+    len: u64,
+    /// Name the device is reported under, e.g. in the exit latencies
+    name: String,
+    device: Arc<Mutex<dyn MmioDevice>>,
+}
+
+/// Routes guest MMIO accesses to the devices registered for the accessed address.
+///
+/// The bus is filled before the vCPUs start and then shared by all of them; each
+/// device sits behind its own lock so accesses to different devices don't contend.
+#[derive(Default)]
+pub struct MmioBus {
+    ranges: BTreeMap<u64, MmioRange>,
+}
+
+impl MmioBus {
+    /// Creates a bus with no device registered.
+    pub fn new() -> Self {
+        MmioBus { ranges: BTreeMap::new() }
+    }
+
+    /// Registers `device` for the `len` bytes starting at `base`.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the range is empty, wraps around or overlaps a registered range
+    pub fn register(&mut self, base: u64, len: u64, device: Arc<Mutex<dyn MmioDevice>>) -> Result<(), VmError> {
+        let end = match base.checked_add(len) {
+            Some(end) if len > 0 => end,
+            _ => return Err(VmError::config(format!("invalid MMIO range {:#x} with length {:#x}", base, len)))
+        };
+        let overlaps_previous = self.ranges.range(..end).next_back()
+            .is_some_and(|(other_base, other)| other_base + other.len > base);
+        if overlaps_previous {
+            return Err(VmError::config(format!("MMIO range {:#x}-{:#x} overlaps a registered device", base, end - 1)));
+        }
+
+        self.ranges.insert(base, MmioRange { len, name: format!("mmio@{:#x}", base), device });
+        Ok(())
+    }
+
+    /// Removes the device registered at `base`.
+    ///
+    /// # Returns
+    /// * `true` if a device was registered at that address
+    pub fn unregister(&mut self, base: u64) -> bool {
+        self.ranges.remove(&base).is_some()
+    }
+
+    /// Names the device registered at `base`, which is otherwise named after the bus
+    /// and its base, e.g. `mmio@0xd0000000`.
+    ///
+    /// # Returns
+    /// * `true` if a device was registered at that address
+    pub fn set_name(&mut self, base: u64, name: &str) -> bool {
+        match self.ranges.get_mut(&base) {
+            Some(range) => {
+                range.name = name.to_string();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns the name of the device an access of `len` bytes at `address` goes to, or
+    /// `None` if no device covers the whole access.
+    pub fn get_name(&self, address: u64, len: usize) -> Option<&str> {
+        self.find(address, len).map(|(_, range)| range.name.as_str())
+    }
+
+    /// Forwards a guest read to the device owning `address`.
+    ///
+    /// # Returns
+    /// * `true` if a device handled the read and filled `data`
+    /// * `false` if no device covers the whole access
+    pub fn read(&self, address: u64, data: &mut [u8]) -> bool {
+        match self.find(address, data.len()) {
+            Some((base, range)) => {
+                range.device.lock().unwrap_or_else(|e| e.into_inner()).read_mmio(address - base, data);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Forwards a guest write to the device owning `address`.
+    ///
+    /// # Returns
+    /// * `true` if a device handled the write
+    /// * `false` if no device covers the whole access
+    pub fn write(&self, address: u64, data: &[u8]) -> bool {
+        match self.find(address, data.len()) {
+            Some((base, range)) => {
+                range.device.lock().unwrap_or_else(|e| e.into_inner()).write_mmio(address - base, data);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns the range containing the `len` bytes at `address`, with its base.
+    fn find(&self, address: u64, len: usize) -> Option<(u64, &MmioRange)> {
+        let (base, range) = self.ranges.range(..=address).next_back()?;
+        let offset = address - base;
+        if offset.checked_add(len as u64)? <= range.len {
+            Some((*base, range))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns `true` for the access widths a guest can issue (1, 2, 4 or 8 bytes).
+pub fn is_valid_access_width(len: usize) -> bool {
+    matches!(len, 1 | 2 | 4 | 8)
+}
+
+/// Decodes a little-endian MMIO data buffer of up to 8 bytes.
+pub fn read_le(data: &[u8]) -> u64 {
+    data.iter().take(8).enumerate().fold(0u64, |value, (i, byte)| value | (*byte as u64) << (8 * i))
+}
+
+/// Encodes `value` little-endian into `data`, truncated to its length (up to 8 bytes).
+pub fn write_le(data: &mut [u8], value: u64) {
+    for (i, byte) in data.iter_mut().take(8).enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Serves a read of `data.len()` bytes at `offset` from 32-bit registers.
+///
+/// `register` returns the value of the 32-bit register at a 4-byte aligned offset.
+/// Narrow reads return the addressed bytes of that register and 8-byte reads combine
+/// two consecutive registers. Reads of an invalid width return zeroes.
+pub fn read_register_bytes(offset: u64, data: &mut [u8], register: impl Fn(u64) -> u32) {
+    if !is_valid_access_width(data.len()) {
+        data.fill(0);
+        return;
+    }
+
+    let aligned = offset & !3;
+    let shift = 8 * (offset & 3);
+    let value = if data.len() == 8 {
+        (register(aligned) as u64) | (register(aligned + 4) as u64) << 32
+    } else {
+        (register(aligned) as u64) >> shift
+    };
+    write_le(data, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device remembering the last write and answering reads with its offset.
+    #[derive(Default)]
+    struct RecordingDevice {
+        last_write: Option<(u64, u64)>,
+    }
+
+    impl MmioDevice for RecordingDevice {
+        fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+            write_le(data, offset);
+        }
+
+        fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+            self.last_write = Some((offset, read_le(data)));
+        }
+    }
+
+    #[test]
+    fn test_bus_dispatches_to_registered_device() {
+        let device = Arc::new(Mutex::new(RecordingDevice::default()));
+        let mut bus = MmioBus::new();
+        bus.register(0xd000_0000, 0x1000, device.clone()).expect("Range should register");
+
+        let mut data = [0u8; 4];
+        assert!(bus.read(0xd000_0070, &mut data));
+        assert_eq!(read_le(&data), 0x70);
+
+        assert!(bus.write(0xd000_0050, &[1, 0, 0, 0]));
+        assert_eq!(device.lock().unwrap().last_write, Some((0x50, 1)));
+
+        // Accesses outside the range, or crossing its end, are not claimed
+        assert!(!bus.read(0xd000_1000, &mut data));
+        assert!(!bus.write(0xd000_0ffe, &[0; 4]));
+        assert!(!bus.read(0xcfff_fffc, &mut data));
+
+        assert_eq!(bus.get_name(0xd000_0070, 4), Some("mmio@0xd0000000"));
+        assert!(bus.set_name(0xd000_0000, "virtio-blk0"));
+        assert!(!bus.set_name(0xd000_0070, "virtio-blk1"));
+        assert_eq!(bus.get_name(0xd000_0070, 4), Some("virtio-blk0"));
+        assert_eq!(bus.get_name(0xd000_0ffe, 4), None);
+    }
+
+    #[test]
+    fn test_bus_rejects_overlapping_ranges() {
+        let mut bus = MmioBus::new();
+        bus.register(0x1000, 0x1000, Arc::new(Mutex::new(RecordingDevice::default()))).unwrap();
+
+        assert!(bus.register(0x1800, 0x1000, Arc::new(Mutex::new(RecordingDevice::default()))).is_err());
+        assert!(bus.register(0x0800, 0x1000, Arc::new(Mutex::new(RecordingDevice::default()))).is_err());
+        assert!(bus.register(0x3000, 0, Arc::new(Mutex::new(RecordingDevice::default()))).is_err());
+        assert!(bus.register(0x2000, 0x1000, Arc::new(Mutex::new(RecordingDevice::default()))).is_ok());
+
+        assert!(bus.unregister(0x1000));
+        assert!(bus.register(0x0800, 0x800, Arc::new(Mutex::new(RecordingDevice::default()))).is_ok());
+    }
+
+    #[test]
+    fn test_read_write_le_round_trip() {
+        let mut data = [0u8; 4];
+        write_le(&mut data, 0x1122_3344);
+        assert_eq!(data, [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(read_le(&data), 0x1122_3344);
+
+        let mut byte = [0u8; 1];
+        write_le(&mut byte, 0x1122_3344);
+        assert_eq!(byte, [0x44]);
+    }
+
+    #[test]
+    fn test_read_register_bytes_widths() {
+        let register = |offset: u64| match offset {
+            0x0 => 0x7472_6976,
+            0x4 => 0x2,
+            _ => 0,
+        };
+
+        let mut dword = [0u8; 4];
+        read_register_bytes(0x0, &mut dword, register);
+        assert_eq!(read_le(&dword), 0x7472_6976);
+
+        let mut byte = [0u8; 1];
+        read_register_bytes(0x1, &mut byte, register);
+        assert_eq!(byte, [0x69]);
+
+        let mut word = [0u8; 2];
+        read_register_bytes(0x2, &mut word, register);
+        assert_eq!(read_le(&word), 0x7472);
+
+        let mut qword = [0u8; 8];
+        read_register_bytes(0x0, &mut qword, register);
+        assert_eq!(read_le(&qword), 0x0000_0002_7472_6976);
+
+        let mut invalid = [0xFFu8; 3];
+        read_register_bytes(0x0, &mut invalid, register);
+        assert_eq!(invalid, [0, 0, 0]);
+    }
+}
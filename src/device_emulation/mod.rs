@@ -1 +1,29 @@
-pub mod block_device;
\ No newline at end of file
+pub mod block_device;
+pub mod io_worker;
+pub mod virtqueue_config;
+pub mod mmio;
+pub mod pio;
+pub mod legacy;
+pub mod serial;
+pub mod virtio_core;
+pub mod virtio_mmio;
+pub mod pci;
+pub mod virtio_pci;
+pub mod gic;
+pub mod shared_memory;
+pub mod framebuffer;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod console;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod rng;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod balloon;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod pmem;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod virtio_9p;
@@ -0,0 +1,560 @@
+//! Minimal PCI host bridge for the virtio-pci devices.
+//!
+//! A single bus 0 holds the host bridge in slot 0 and up to 31 devices with one
+//! function each. The guest reaches their configuration space through the legacy
+//! configuration ports (`legacy::PciConfigPorts`) or the ECAM window (`PciEcam`), and
+//! their memory BARs through the MMIO window (`PciMmioWindow`) the `PciBarAllocator`
+//! hands addresses out of. BARs are routed to the address the guest currently
+//! programmed, so a guest moving them within the window keeps reaching the device.
+
+use std::sync::{Arc, Mutex};
+use crate::device_emulation::mmio::{read_register_bytes, MmioDevice};
+use crate::error::VmError;
+
+/// Size of the ECAM window of bus 0: 32 slots of 8 functions of 4 KiB.
+pub const PCI_ECAM_SIZE: u64 = 0x10_0000;
+/// Number of device slots on the bus, the host bridge's included.
+pub const PCI_SLOT_COUNT: usize = 32;
+/// Number of BARs of a type 0 configuration header.
+pub const PCI_BAR_COUNT: usize = 6;
+/// Capability ID of a vendor specific capability.
+pub const PCI_CAP_ID_VENDOR: u8 = 0x09;
+/// Interrupt pin INTA#.
+pub const PCI_INTERRUPT_PIN_A: u8 = 1;
+
+/// Size of the conventional configuration space; the extended space reads as zeroes.
+const PCI_CONFIG_SPACE_SIZE: usize = 256;
+/// Offset of the command register.
+const PCI_COMMAND: usize = 0x04;
+/// Offset of the status register.
+const PCI_STATUS: usize = 0x06;
+/// Offset of the first BAR.
+const PCI_BAR0: usize = 0x10;
+/// Offset of the pointer to the first capability.
+const PCI_CAPABILITY_LIST: usize = 0x34;
+/// Offset of the interrupt line register.
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+/// Offset of the first capability, right after the type 0 header.
+const PCI_CAPABILITY_START: usize = 0x40;
+/// Command bit enabling the memory BARs.
+const PCI_COMMAND_MEMORY: u32 = 0x2;
+/// Command bits the guest may change: I/O and memory decoding, bus mastering, parity
+/// and SERR# reporting, INTx disable.
+const PCI_COMMAND_WRITABLE: u32 = 0x0547;
+/// Status bit telling the device has a capability list.
+const PCI_STATUS_CAP_LIST: u32 = 0x10;
+/// Vendor and device ID of the host bridge (Red Hat, QEMU PCIe host bridge).
+const HOST_BRIDGE_VENDOR_ID: u16 = 0x1b36;
+const HOST_BRIDGE_DEVICE_ID: u16 = 0x0008;
+/// Class code of a host bridge.
+const PCI_CLASS_BRIDGE_HOST: u32 = 0x06_0000;
+
+/// Type 0 configuration header and capabilities of a PCI function.
+///
+/// Registers are kept as 32-bit values with a mask of the bits the guest may write;
+/// writing all ones to a BAR and reading it back yields its size, as the guest expects
+/// when sizing BARs.
+pub struct PciConfiguration {
+    registers: [u32; PCI_CONFIG_SPACE_SIZE / 4],
+    writable: [u32; PCI_CONFIG_SPACE_SIZE / 4],
+    bar_sizes: [u64; PCI_BAR_COUNT],
+    /// Offset of the last capability added, to link the next one
+    last_capability: Option<usize>,
+    /// Offset the next capability is placed at
+    next_capability: usize,
+}
+
+impl PciConfiguration {
+    /// Creates the configuration header of a function without BARs or capabilities.
+    ///
+    /// # Arguments
+    /// * `vendor_id` - Vendor ID
+    /// * `device_id` - Device ID
+    /// * `class_code` - Base class, subclass and programming interface, e.g. 0x060000
+    /// * `revision` - Revision ID
+    /// * `subsystem_vendor_id` - Subsystem vendor ID
+    /// * `subsystem_id` - Subsystem ID
+    pub fn new(vendor_id: u16, device_id: u16, class_code: u32, revision: u8, subsystem_vendor_id: u16, subsystem_id: u16) -> Self {
+        let mut registers = [0u32; PCI_CONFIG_SPACE_SIZE / 4];
+        registers[0] = vendor_id as u32 | (device_id as u32) << 16;
+        registers[2] = (class_code & 0xff_ffff) << 8 | revision as u32;
+        registers[11] = subsystem_vendor_id as u32 | (subsystem_id as u32) << 16;
+        let mut writable = [0u32; PCI_CONFIG_SPACE_SIZE / 4];
+        writable[PCI_COMMAND / 4] = PCI_COMMAND_WRITABLE;
+        // Cache line size and latency timer
+        writable[3] = 0xffff;
+        writable[PCI_INTERRUPT_LINE / 4] = 0xff;
+        PciConfiguration {
+            registers,
+            writable,
+            bar_sizes: [0; PCI_BAR_COUNT],
+            last_capability: None,
+            next_capability: PCI_CAPABILITY_START,
+        }
+    }
+
+    /// Adds a 32-bit, non-prefetchable memory BAR.
+    ///
+    /// # Arguments
+    /// * `index` - BAR number, 0 to 5
+    /// * `address` - Guest physical address the BAR starts at, usually from a `PciBarAllocator`
+    /// * `size` - Size of the BAR, a power of two of at least 16 bytes
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the BAR doesn't exist or is already set, the size is invalid,
+    ///   or the BAR isn't aligned on its size below 4 GiB
+    pub fn add_memory_bar(&mut self, index: usize, address: u64, size: u64) -> Result<(), VmError> {
+        if index >= PCI_BAR_COUNT || self.bar_sizes[index] != 0 {
+            return Err(VmError::config(format!("BAR {} doesn't exist or is already set", index)));
+        }
+        if size < 16 || !size.is_power_of_two() || !address.is_multiple_of(size) || address + size > 1 << 32 {
+            return Err(VmError::config(format!("invalid memory BAR at {:#x} with size {:#x}", address, size)));
+        }
+        self.registers[PCI_BAR0 / 4 + index] = address as u32;
+        self.writable[PCI_BAR0 / 4 + index] = !(size as u32 - 1);
+        self.bar_sizes[index] = size;
+        Ok(())
+    }
+
+    /// Returns the address the guest programmed in BAR `index`, `None` if the BAR isn't set.
+    pub fn get_bar_address(&self, index: usize) -> Option<u64> {
+        match self.bar_sizes.get(index) {
+            Some(&size) if size != 0 => Some((self.registers[PCI_BAR0 / 4 + index] & !0xf) as u64),
+            _ => None
+        }
+    }
+
+    /// Returns the size of BAR `index`, 0 if the BAR isn't set.
+    pub fn get_bar_size(&self, index: usize) -> u64 {
+        self.bar_sizes.get(index).copied().unwrap_or(0)
+    }
+
+    /// Returns `true` once the guest enabled the memory BARs in the command register.
+    pub fn is_memory_enabled(&self) -> bool {
+        self.registers[PCI_COMMAND / 4] & PCI_COMMAND_MEMORY != 0
+    }
+
+    /// Sets the interrupt pin of the function and the line it is routed to.
+    ///
+    /// # Arguments
+    /// * `pin` - Interrupt pin, `PCI_INTERRUPT_PIN_A` for the only pin of a single-function device
+    /// * `line` - Interrupt line, the GSI the pin raises
+    pub fn set_interrupt(&mut self, pin: u8, line: u8) {
+        self.registers[PCI_INTERRUPT_LINE / 4] = (self.registers[PCI_INTERRUPT_LINE / 4] & 0xffff_0000) | (pin as u32) << 8 | line as u32;
+    }
+
+    /// Returns the interrupt line register.
+    pub fn get_interrupt_line(&self) -> u8 {
+        self.registers[PCI_INTERRUPT_LINE / 4] as u8
+    }
+
+    /// Appends a capability to the capability list.
+    ///
+    /// # Arguments
+    /// * `id` - Capability ID
+    /// * `body` - Capability after its ID and next pointer
+    ///
+    /// # Returns
+    /// * `Ok(offset)` - the offset of the capability in the configuration space
+    /// * `Err(VmError)` if the capability doesn't fit in the configuration space
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> Result<usize, VmError> {
+        let offset = self.next_capability;
+        let end = offset + 2 + body.len();
+        if end > PCI_CONFIG_SPACE_SIZE {
+            return Err(VmError::config(format!("capability {:#x} of {} bytes doesn't fit in the configuration space", id, body.len())));
+        }
+        match self.last_capability {
+            Some(last) => self.set_byte(last + 1, offset as u8),
+            None => {
+                self.set_byte(PCI_CAPABILITY_LIST, offset as u8);
+                self.registers[PCI_STATUS / 4] |= PCI_STATUS_CAP_LIST << 16;
+            }
+        }
+        self.set_byte(offset, id);
+        self.set_byte(offset + 1, 0);
+        for (index, byte) in body.iter().enumerate() {
+            self.set_byte(offset + 2 + index, *byte);
+        }
+        self.last_capability = Some(offset);
+        self.next_capability = end.next_multiple_of(4);
+        Ok(offset)
+    }
+
+    /// Returns the 32-bit register at `offset`, which is 4-byte aligned.
+    pub fn read_register(&self, offset: u64) -> u32 {
+        self.registers.get(offset as usize / 4).copied().unwrap_or(0)
+    }
+
+    /// Handles a guest write of `data` at `offset`; read-only bits keep their value.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        for (index, byte) in data.iter().enumerate() {
+            let position = offset as usize + index;
+            if position >= PCI_CONFIG_SPACE_SIZE {
+                break;
+            }
+            let shift = 8 * (position % 4);
+            let mask = self.writable[position / 4] & 0xff << shift;
+            let register = &mut self.registers[position / 4];
+            *register = (*register & !mask) | ((*byte as u32) << shift & mask);
+        }
+    }
+
+    fn set_byte(&mut self, position: usize, value: u8) {
+        let shift = 8 * (position % 4);
+        let register = &mut self.registers[position / 4];
+        *register = (*register & !(0xff << shift)) | (value as u32) << shift;
+    }
+}
+
+/// A function on the PCI bus.
+pub trait PciDevice: Send {
+    /// Returns the configuration space of the function.
+    fn get_config(&self) -> &PciConfiguration;
+
+    /// Returns the configuration space of the function mutably.
+    fn get_config_mut(&mut self) -> &mut PciConfiguration;
+
+    /// Handles a guest read of `data.len()` bytes at `offset` in BAR `bar`.
+    fn read_bar(&mut self, bar: usize, offset: u64, data: &mut [u8]);
+
+    /// Handles a guest write of `data` at `offset` in BAR `bar`.
+    fn write_bar(&mut self, bar: usize, offset: u64, data: &[u8]);
+}
+
+/// Host bridge in slot 0, which guests look for before trusting the configuration ports.
+pub struct PciHostBridge {
+    config: PciConfiguration,
+}
+
+impl PciHostBridge {
+    /// Creates the host bridge.
+    pub fn new() -> Self {
+        PciHostBridge { config: PciConfiguration::new(HOST_BRIDGE_VENDOR_ID, HOST_BRIDGE_DEVICE_ID, PCI_CLASS_BRIDGE_HOST, 0, 0, 0) }
+    }
+}
+
+impl Default for PciHostBridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciDevice for PciHostBridge {
+    fn get_config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn get_config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn read_bar(&mut self, _bar: usize, _offset: u64, data: &mut [u8]) {
+        data.fill(0xff);
+    }
+
+    fn write_bar(&mut self, _bar: usize, _offset: u64, _data: &[u8]) {}
+}
+
+/// PCI bus 0 with the host bridge and the devices added to it.
+///
+/// Like the `MmioBus`, the bus is filled before the vCPUs start and then shared by all
+/// of them; each function sits behind its own lock.
+pub struct PciBus {
+    /// Functions indexed by slot, the host bridge first
+    slots: Vec<Arc<Mutex<dyn PciDevice>>>,
+}
+
+impl PciBus {
+    /// Creates a bus holding only the host bridge.
+    pub fn new() -> Self {
+        PciBus { slots: vec![Arc::new(Mutex::new(PciHostBridge::new()))] }
+    }
+
+    /// Plugs `device` into the next free slot.
+    ///
+    /// # Returns
+    /// * `Ok(slot)` - the slot of the device
+    /// * `Err(VmError)` if every slot is taken
+    pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> Result<u8, VmError> {
+        if self.slots.len() == PCI_SLOT_COUNT {
+            return Err(VmError::config(format!("PCI bus is full, at most {} devices are supported", PCI_SLOT_COUNT - 1)));
+        }
+        self.slots.push(device);
+        Ok((self.slots.len() - 1) as u8)
+    }
+
+    /// Handles a guest read of the configuration space of a function.
+    ///
+    /// Functions that don't exist read as all ones, which tells the guest the address is empty.
+    pub fn read_config(&self, bus: u8, slot: u8, function: u8, offset: u64, data: &mut [u8]) {
+        match self.get_function(bus, slot, function) {
+            Some(device) => {
+                let device = device.lock().unwrap_or_else(|e| e.into_inner());
+                read_register_bytes(offset, data, |register| device.get_config().read_register(register));
+            },
+            None => data.fill(0xff)
+        }
+    }
+
+    /// Handles a guest write to the configuration space of a function; writes to
+    /// functions that don't exist are dropped.
+    pub fn write_config(&self, bus: u8, slot: u8, function: u8, offset: u64, data: &[u8]) {
+        if let Some(device) = self.get_function(bus, slot, function) {
+            device.lock().unwrap_or_else(|e| e.into_inner()).get_config_mut().write(offset, data);
+        }
+    }
+
+    /// Forwards a guest read to the memory BAR containing `address`.
+    ///
+    /// # Returns
+    /// * `true` if a device handled the read and filled `data`
+    /// * `false` if no enabled BAR covers the whole access
+    pub fn read_bar(&self, address: u64, data: &mut [u8]) -> bool {
+        let len = data.len() as u64;
+        self.with_bar(address, len, |device, bar, offset| device.read_bar(bar, offset, data))
+    }
+
+    /// Forwards a guest write to the memory BAR containing `address`.
+    ///
+    /// # Returns
+    /// * `true` if a device handled the write
+    /// * `false` if no enabled BAR covers the whole access
+    pub fn write_bar(&self, address: u64, data: &[u8]) -> bool {
+        self.with_bar(address, data.len() as u64, |device, bar, offset| device.write_bar(bar, offset, data))
+    }
+
+    /// Returns the function at the given address; only function 0 of bus 0 exists.
+    fn get_function(&self, bus: u8, slot: u8, function: u8) -> Option<&Arc<Mutex<dyn PciDevice>>> {
+        if bus != 0 || function != 0 {
+            return None;
+        }
+        self.slots.get(slot as usize)
+    }
+
+    /// Calls `access` with the device owning the `len` bytes at `address`, the BAR and
+    /// the offset in it.
+    fn with_bar(&self, address: u64, len: u64, access: impl FnOnce(&mut dyn PciDevice, usize, u64)) -> bool {
+        for device in &self.slots {
+            let mut device = device.lock().unwrap_or_else(|e| e.into_inner());
+            let config = device.get_config();
+            if !config.is_memory_enabled() {
+                continue;
+            }
+            let found = (0..PCI_BAR_COUNT).find_map(|bar| {
+                let base = config.get_bar_address(bar)?;
+                let offset = address.checked_sub(base)?;
+                (offset.checked_add(len)? <= config.get_bar_size(bar)).then_some((bar, offset))
+            });
+            if let Some((bar, offset)) = found {
+                access(&mut *device, bar, offset);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Default for PciBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ECAM window of bus 0, mapping the 4 KiB configuration space of every function.
+pub struct PciEcam {
+    bus: Arc<PciBus>,
+}
+
+impl PciEcam {
+    /// Creates the ECAM window of `bus`; it is registered on the MMIO bus for `PCI_ECAM_SIZE` bytes.
+    pub fn new(bus: Arc<PciBus>) -> Self {
+        PciEcam { bus }
+    }
+
+    /// Splits an offset in the window into bus, slot, function and register offset.
+    fn decode(offset: u64) -> (u8, u8, u8, u64) {
+        ((offset >> 20) as u8, (offset >> 15) as u8 & 0x1f, (offset >> 12) as u8 & 0x7, offset & 0xfff)
+    }
+}
+
+impl MmioDevice for PciEcam {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        let (bus, slot, function, register) = Self::decode(offset);
+        self.bus.read_config(bus, slot, function, register, data);
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        let (bus, slot, function, register) = Self::decode(offset);
+        self.bus.write_config(bus, slot, function, register, data);
+    }
+}
+
+/// MMIO window the memory BARs of the bus live in.
+///
+/// The whole window is registered once on the MMIO bus; accesses go to the BAR the
+/// guest currently programmed at the address and read as all ones elsewhere.
+pub struct PciMmioWindow {
+    bus: Arc<PciBus>,
+    /// Guest physical address the window starts at
+    base: u64,
+}
+
+impl PciMmioWindow {
+    /// Creates the window of `bus` starting at `base`.
+    pub fn new(bus: Arc<PciBus>, base: u64) -> Self {
+        PciMmioWindow { bus, base }
+    }
+}
+
+impl MmioDevice for PciMmioWindow {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        if !self.bus.read_bar(self.base + offset, data) {
+            data.fill(0xff);
+        }
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        self.bus.write_bar(self.base + offset, data);
+    }
+}
+
+/// Hands out naturally aligned addresses for memory BARs from the MMIO window.
+#[derive(Debug)]
+pub struct PciBarAllocator {
+    next: u64,
+    end: u64,
+}
+
+impl PciBarAllocator {
+    /// Creates an allocator for the `size` bytes starting at `base`.
+    pub fn new(base: u64, size: u64) -> Self {
+        PciBarAllocator { next: base, end: base.saturating_add(size) }
+    }
+
+    /// Allocates a BAR of `size` bytes aligned on its size.
+    ///
+    /// # Returns
+    /// * `Ok(address)` - the guest physical address of the BAR
+    /// * `Err(VmError)` if the size isn't a power of two or the window is exhausted
+    pub fn allocate(&mut self, size: u64) -> Result<u64, VmError> {
+        if !size.is_power_of_two() {
+            return Err(VmError::config(format!("BAR size {:#x} isn't a power of two", size)));
+        }
+        match self.next.checked_next_multiple_of(size) {
+            Some(address) if address.checked_add(size).is_some_and(|end| end <= self.end) => {
+                self.next = address + size;
+                Ok(address)
+            },
+            _ => Err(VmError::config(format!("no room left for a BAR of {:#x} bytes in the PCI MMIO window", size)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_config_u32(bus: &PciBus, slot: u8, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        bus.read_config(0, slot, 0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// Device with one 4 KiB BAR echoing the offset accessed.
+    struct TestDevice {
+        config: PciConfiguration,
+        written: Vec<(u64, u8)>,
+    }
+
+    impl PciDevice for TestDevice {
+        fn get_config(&self) -> &PciConfiguration {
+            &self.config
+        }
+
+        fn get_config_mut(&mut self) -> &mut PciConfiguration {
+            &mut self.config
+        }
+
+        fn read_bar(&mut self, _bar: usize, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn write_bar(&mut self, _bar: usize, offset: u64, data: &[u8]) {
+            self.written.push((offset, data[0]));
+        }
+    }
+
+    #[test]
+    fn test_configuration_space_and_bar_sizing() {
+        let mut config = PciConfiguration::new(0x1af4, 0x1042, 0x01_0000, 1, 0x1af4, 0x1100);
+        config.add_memory_bar(0, 0xe000_4000, 0x4000).unwrap();
+        assert!(config.add_memory_bar(0, 0xe000_8000, 0x4000).is_err());
+        assert!(config.add_memory_bar(1, 0xe000_1000, 0x4000).is_err()); // Misaligned
+        let offset = config.add_capability(PCI_CAP_ID_VENDOR, &[4, 1, 2]).unwrap();
+        assert_eq!(offset, PCI_CAPABILITY_START);
+        assert_eq!(config.add_capability(PCI_CAP_ID_VENDOR, &[4, 2, 2]).unwrap(), PCI_CAPABILITY_START + 8);
+
+        assert_eq!(config.read_register(0), 0x1042_1af4);
+        assert_eq!(config.read_register(0x08), 0x0100_0001);
+        assert_eq!(config.read_register(PCI_COMMAND as u64) >> 16, PCI_STATUS_CAP_LIST);
+        assert_eq!(config.read_register(PCI_CAPABILITY_LIST as u64), PCI_CAPABILITY_START as u32);
+        assert_eq!(config.read_register(PCI_CAPABILITY_START as u64), 0x0104_4809);
+
+        // Sizing the BAR, then restoring it, the way the guest does
+        config.write(PCI_BAR0 as u64, &u32::MAX.to_le_bytes());
+        assert_eq!(config.read_register(PCI_BAR0 as u64), 0xffff_c000);
+        config.write(PCI_BAR0 as u64, &0xe000_4000u32.to_le_bytes());
+        assert_eq!(config.get_bar_address(0), Some(0xe000_4000));
+        assert_eq!(config.get_bar_address(1), None);
+
+        // Read-only registers keep their value
+        config.write(0, &u32::MAX.to_le_bytes());
+        assert_eq!(config.read_register(0), 0x1042_1af4);
+        config.write(PCI_COMMAND as u64, &[0x06, 0x00]);
+        assert!(config.is_memory_enabled());
+    }
+
+    #[test]
+    fn test_bus_routes_config_and_bar_accesses() {
+        let mut bus = PciBus::new();
+        let mut config = PciConfiguration::new(0x1af4, 0x1041, 0x02_0000, 1, 0x1af4, 0x1100);
+        config.add_memory_bar(0, 0xe000_0000, 0x1000).unwrap();
+        let device = Arc::new(Mutex::new(TestDevice { config, written: Vec::new() }));
+        assert_eq!(bus.add_device(device.clone()).unwrap(), 1);
+        let bus = Arc::new(bus);
+
+        assert_eq!(read_config_u32(&bus, 0, 0x08) >> 8, PCI_CLASS_BRIDGE_HOST);
+        assert_eq!(read_config_u32(&bus, 1, 0), 0x1041_1af4);
+        assert_eq!(read_config_u32(&bus, 2, 0), u32::MAX);
+        let mut ecam = PciEcam::new(bus.clone());
+        let mut vendor = [0u8; 2];
+        ecam.read_mmio(1 << 15, &mut vendor);
+        assert_eq!(u16::from_le_bytes(vendor), 0x1af4);
+
+        // BARs only decode once the guest enabled memory accesses
+        let mut window = PciMmioWindow::new(bus.clone(), 0xe000_0000);
+        let mut data = [0u8; 4];
+        window.read_mmio(0x10, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        ecam.write_mmio((1 << 15) + PCI_COMMAND as u64, &[0x02]);
+        window.read_mmio(0x10, &mut data);
+        assert_eq!(data, [0x10; 4]);
+        window.write_mmio(0x20, &[7]);
+        assert_eq!(device.lock().unwrap().written, vec![(0x20, 7)]);
+        window.read_mmio(0x1000, &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn test_bar_allocator_aligns_and_runs_out() {
+        let mut allocator = PciBarAllocator::new(0xe000_1000, 0x10000);
+        assert_eq!(allocator.allocate(0x1000).unwrap(), 0xe000_1000);
+        assert_eq!(allocator.allocate(0x4000).unwrap(), 0xe000_4000);
+        assert!(allocator.allocate(0x3000).is_err());
+        assert!(allocator.allocate(0x10000).is_err());
+        assert_eq!(allocator.allocate(0x8000).unwrap(), 0xe000_8000);
+        assert_eq!(allocator.allocate(0x1000).unwrap(), 0xe001_0000);
+        assert!(allocator.allocate(0x1000).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! x86 I/O port bus.
+//!
+//! Guest `in`/`out` instructions arrive as a port number plus a 1, 2 or 4 byte
+//! little-endian data buffer, the way KVM reports them in `VcpuExit::IoIn`/`IoOut`.
+//! The `PortIoBus` routes each access to the device owning the port.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use crate::error::VmError;
+
+/// A device exposing registers in the x86 I/O port space.
+///
+/// Offsets are relative to the first port the device was registered at.
+pub trait PioDevice: Send {
+    /// Handles a guest `in` of `data.len()` bytes at `offset`.
+    fn read_pio(&mut self, offset: u16, data: &mut [u8]);
+
+    /// Handles a guest `out` of `data` at `offset`.
+    fn write_pio(&mut self, offset: u16, data: &[u8]);
+}
+
+/// A port range claimed by a device on the bus.
+struct PioRange {
+    len: u16,
+    /// Name the device is reported under, e.g. in the exit latencies
+    name: String,
+    device: Arc<Mutex<dyn PioDevice>>,
+}
+
+/// Routes guest port I/O to the devices registered for the accessed port.
+///
+/// Like the `MmioBus`, the bus is filled before the vCPUs start and then shared by
+/// all of them, with each device behind its own lock.
+#[derive(Default)]
+pub struct PortIoBus {
+    ranges: BTreeMap<u16, PioRange>,
+}
+
+impl PortIoBus {
+    /// Creates a bus with no device registered.
+    pub fn new() -> Self {
+        PortIoBus { ranges: BTreeMap::new() }
+    }
+
+    /// Registers `device` for the `len` ports starting at `base`.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the range is empty, goes past port 0xffff or overlaps a registered range
+    pub fn register(&mut self, base: u16, len: u16, device: Arc<Mutex<dyn PioDevice>>) -> Result<(), VmError> {
+        let end = base as u32 + len as u32;
+        if len == 0 || end > 0x1_0000 {
+            return Err(VmError::config(format!("invalid I/O port range {:#x} with length {:#x}", base, len)));
+        }
+        let last = (end - 1) as u16;
+        let overlaps_previous = self.ranges.range(..=last).next_back()
+            .is_some_and(|(other_base, other)| *other_base as u32 + other.len as u32 > base as u32);
+        if overlaps_previous {
+            return Err(VmError::config(format!("I/O ports {:#x}-{:#x} overlap a registered device", base, end - 1)));
+        }
+
+        self.ranges.insert(base, PioRange { len, name: format!("pio@{:#x}", base), device });
+        Ok(())
+    }
+
+    /// Removes the device registered at `base`.
+    ///
+    /// # Returns
+    /// * `true` if a device was registered at that port
+    pub fn unregister(&mut self, base: u16) -> bool {
+        self.ranges.remove(&base).is_some()
+    }
+
+    /// Names the device registered at `base`, which is otherwise named after the bus
+    /// and its base, e.g. `pio@0x60`.
+    ///
+    /// # Returns
+    /// * `true` if a device was registered at that port
+    pub fn set_name(&mut self, base: u16, name: &str) -> bool {
+        match self.ranges.get_mut(&base) {
+            Some(range) => {
+                range.name = name.to_string();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns the name of the device an access of `len` bytes at `port` goes to, or
+    /// `None` if no device covers the whole access.
+    pub fn get_name(&self, port: u16, len: usize) -> Option<&str> {
+        self.find(port, len).map(|(_, range)| range.name.as_str())
+    }
+
+    /// Forwards a guest `in` to the device owning `port`.
+    ///
+    /// # Returns
+    /// * `true` if a device handled the read and filled `data`
+    /// * `false` if no device covers the whole access
+    pub fn read(&self, port: u16, data: &mut [u8]) -> bool {
+        match self.find(port, data.len()) {
+            Some((base, range)) => {
+                range.device.lock().unwrap_or_else(|e| e.into_inner()).read_pio(port - base, data);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Forwards a guest `out` to the device owning `port`.
+    ///
+    /// # Returns
+    /// * `true` if a device handled the write
+    /// * `false` if no device covers the whole access
+    pub fn write(&self, port: u16, data: &[u8]) -> bool {
+        match self.find(port, data.len()) {
+            Some((base, range)) => {
+                range.device.lock().unwrap_or_else(|e| e.into_inner()).write_pio(port - base, data);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns the range containing the `len` ports at `port`, with its base.
+    fn find(&self, port: u16, len: usize) -> Option<(u16, &PioRange)> {
+        let (base, range) = self.ranges.range(..=port).next_back()?;
+        if (port - base) as usize + len <= range.len as usize {
+            Some((*base, range))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device answering reads with the accessed offset.
+    struct OffsetDevice;
+
+    impl PioDevice for OffsetDevice {
+        fn read_pio(&mut self, offset: u16, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn write_pio(&mut self, _offset: u16, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_bus_dispatches_by_port() {
+        let mut bus = PortIoBus::new();
+        bus.register(0x3f8, 8, Arc::new(Mutex::new(OffsetDevice))).expect("Range should register");
+        bus.register(0xcf8, 8, Arc::new(Mutex::new(OffsetDevice))).expect("Range should register");
+
+        let mut data = [0u8; 1];
+        assert!(bus.read(0x3fd, &mut data));
+        assert_eq!(data, [5]);
+        assert!(bus.write(0xcfc, &[0; 4]));
+
+        // Unclaimed ports and accesses crossing the end of a range are not handled
+        assert!(!bus.read(0x80, &mut data));
+        assert!(!bus.write(0x3fe, &[0; 4]));
+    }
+
+    #[test]
+    fn test_bus_rejects_invalid_ranges() {
+        let mut bus = PortIoBus::new();
+        bus.register(0x60, 1, Arc::new(Mutex::new(OffsetDevice))).unwrap();
+
+        assert!(bus.register(0x5c, 8, Arc::new(Mutex::new(OffsetDevice))).is_err());
+        assert!(bus.register(0x70, 0, Arc::new(Mutex::new(OffsetDevice))).is_err());
+        assert!(bus.register(0xfffe, 4, Arc::new(Mutex::new(OffsetDevice))).is_err());
+        assert!(bus.register(0xfff8, 8, Arc::new(Mutex::new(OffsetDevice))).is_ok());
+        assert!(bus.register(0x64, 1, Arc::new(Mutex::new(OffsetDevice))).is_ok());
+
+        assert!(bus.unregister(0x60));
+        assert!(bus.register(0x5c, 8, Arc::new(Mutex::new(OffsetDevice))).is_ok());
+    }
+}
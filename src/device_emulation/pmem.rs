@@ -0,0 +1,184 @@
+//! virtio-pmem persistent memory device.
+//!
+//! A host file is mapped into the guest physical address space, past guest RAM, and
+//! the device tells the guest driver where through its configuration space. The guest
+//! accesses the file's pages directly, with no request per access, so a DAX mounted
+//! filesystem on `/dev/pmem<n>` lets guest programs mmap them. Guest writes land in
+//! the host page cache; the driver asks the device to flush them to the file's
+//! storage when the guest syncs, which the device does before answering.
+
+use std::sync::Arc;
+use virtio_queue::{QueueSync, QueueT};
+use vm_memory::{Bytes, GuestMemoryMmap};
+use crate::device_emulation::shared_memory::SharedMemoryMapping;
+use crate::device_emulation::virtio_mmio::{VirtioDevice, VirtqueueState};
+use crate::error::VmError;
+use crate::vm_setup::setup_utils::{PmemDevice, SharedMemoryBacking, SharedMemoryRegion, PMEM_ALIGNMENT};
+
+/// Virtio device ID of a persistent memory device.
+const VIRTIO_ID_PMEM: u32 = 27;
+/// Size of the request queue.
+pub const PMEM_QUEUE_SIZE: u16 = 32;
+/// Request type asking the device to flush guest writes to the storage.
+pub const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+/// Response to a request carried out.
+pub const VIRTIO_PMEM_RESP_OK: u32 = 0;
+/// Response to a request that failed or isn't supported.
+pub const VIRTIO_PMEM_RESP_EIO: u32 = 1;
+
+/// Maps the file backing `pmem`, creating or growing it to the configured size.
+///
+/// # Returns
+/// * `Ok(SharedMemoryMapping)` on success
+/// * `Err(VmError)` if the device has no size and the file can't be read, its size isn't
+///   a non-zero multiple of `PMEM_ALIGNMENT`, or the file can't be mapped
+pub fn map_pmem_file(pmem: &PmemDevice) -> Result<SharedMemoryMapping, VmError> {
+    let path = pmem.get_path();
+    let size = match pmem.get_size() {
+        Some(size) => size,
+        None => match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(VmError::io(format!("failed to read the size of pmem file {}: {}", path.display(), e), e))
+        }
+    };
+    if size == 0 || size % PMEM_ALIGNMENT != 0 {
+        return Err(VmError::config(format!("pmem file {} is {} bytes, not a non-zero multiple of {}", path.display(), size, PMEM_ALIGNMENT)));
+    }
+    SharedMemoryMapping::open(&SharedMemoryRegion::new(SharedMemoryBacking::Path(path.to_path_buf()), size))
+}
+
+/// virtio-pmem device with its single request queue.
+pub struct VirtioPmem {
+    mem: GuestMemoryMmap,
+    queue: QueueSync,
+    /// Host mapping of the file, which the backend also maps into the guest
+    mapping: Arc<SharedMemoryMapping>,
+    /// Guest physical address the file is mapped at
+    guest_address: u64,
+    /// Flush requests carried out so far
+    flushes: u64,
+}
+
+impl VirtioPmem {
+    /// Creates a persistent memory device.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory holding the virtqueue
+    /// * `mapping` - Host mapping of the file, mapped into the guest by the backend
+    /// * `guest_address` - Guest physical address the backend mapped the file at
+    ///
+    /// # Returns
+    /// * `Ok(VirtioPmem)` on success
+    /// * `Err(VmError)` if the virtqueue couldn't be created
+    pub fn new(mem: GuestMemoryMmap, mapping: Arc<SharedMemoryMapping>, guest_address: u64) -> Result<Self, VmError> {
+        match QueueSync::new(PMEM_QUEUE_SIZE) {
+            Ok(queue) => Ok(VirtioPmem { mem, queue, mapping, guest_address, flushes: 0 }),
+            Err(e) => Err(VmError::device_source(format!("failed to create virtqueue: {}", e), e))
+        }
+    }
+
+    /// Returns the request queue, e.g. to set it up without a guest driver.
+    pub fn get_queue_mut(&mut self) -> &mut QueueSync {
+        &mut self.queue
+    }
+
+    /// Returns the number of flush requests carried out so far.
+    pub fn get_flushes(&self) -> u64 {
+        self.flushes
+    }
+
+    /// Returns the configuration space: `start`, then `size`.
+    fn get_config_space(&self) -> [u8; 16] {
+        let mut config = [0u8; 16];
+        config[..8].copy_from_slice(&self.guest_address.to_le_bytes());
+        config[8..].copy_from_slice(&self.mapping.get_size().to_le_bytes());
+        config
+    }
+
+    /// Carries out the requests the driver made available. Flushes run on the calling
+    /// vCPU, which the guest waits on anyway.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the answered requests
+    fn process_request_queue(&mut self) -> bool {
+        if !self.queue.ready() {
+            return false;
+        }
+        let mut used_any = false;
+        while let Some(chain) = self.queue.pop_descriptor_chain(&self.mem) {
+            let head_index = chain.head_index();
+            let request_type = chain.clone().readable().next()
+                .filter(|descriptor| descriptor.len() >= 4)
+                .and_then(|descriptor| self.mem.read_obj::<u32>(descriptor.addr()).ok());
+            let response = match request_type {
+                Some(VIRTIO_PMEM_REQ_TYPE_FLUSH) => match self.mapping.flush() {
+                    Ok(()) => {
+                        self.flushes += 1;
+                        VIRTIO_PMEM_RESP_OK
+                    },
+                    Err(_) => VIRTIO_PMEM_RESP_EIO
+                },
+                _ => VIRTIO_PMEM_RESP_EIO
+            };
+            // A request without room for the response is returned empty
+            let written = match chain.writable().next() {
+                Some(descriptor) if descriptor.len() >= 4 && self.mem.write_obj(response, descriptor.addr()).is_ok() => 4,
+                _ => 0
+            };
+            if self.queue.add_used(&self.mem, head_index, written).is_err() {
+                break;
+            }
+            used_any = true;
+        }
+        used_any && self.queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+}
+
+impl VirtioDevice for VirtioPmem {
+    fn get_device_type(&self) -> u32 {
+        VIRTIO_ID_PMEM
+    }
+
+    fn get_device_features(&self) -> u64 {
+        0
+    }
+
+    fn get_num_queues(&self) -> usize {
+        1
+    }
+
+    fn get_queue_max_size(&self, _index: usize) -> u16 {
+        PMEM_QUEUE_SIZE
+    }
+
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+        match index {
+            0 => state.apply(&mut self.queue, &self.mem),
+            _ => Err(VmError::device(format!("no virtqueue {}", index)))
+        }
+    }
+
+    fn deactivate_queue(&mut self, index: usize) {
+        if index == 0 {
+            self.queue.reset();
+        }
+    }
+
+    fn process_queue(&mut self, index: usize) -> bool {
+        index == 0 && self.process_request_queue()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.get_config_space();
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = offset.checked_add(index as u64)
+                .and_then(|position| config.get(position as usize))
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.queue.reset();
+    }
+}
@@ -0,0 +1,174 @@
+//! virtio-rng entropy device.
+//!
+//! Freshly booted guests have little entropy of their own, and cloud images generate
+//! their SSH host keys early on, blocking until the kernel's random pool is seeded.
+//! The device fills every buffer the guest driver hands it with random bytes from the
+//! host's `getrandom`, which the guest kernel mixes into its pool.
+
+use virtio_queue::{QueueSync, QueueT};
+use vm_memory::{Bytes, GuestMemoryMmap};
+use crate::device_emulation::virtio_mmio::{VirtioDevice, VirtqueueState};
+use crate::error::VmError;
+
+/// Virtio device ID of an entropy source.
+const VIRTIO_ID_RNG: u32 = 4;
+/// Size of the request queue.
+pub const RNG_QUEUE_SIZE: u16 = 64;
+/// Most random bytes handed out per request; the guest asks again for more.
+pub const MAX_RNG_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Fills `buf` with random bytes from the host kernel, blocking until its pool is seeded.
+#[cfg(target_os = "linux")]
+fn fill_random(buf: &mut [u8]) -> Result<(), VmError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        // SAFETY: the pointer and length describe the writable rest of `buf`
+        let result = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+        if result < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(VmError::device_source(format!("getrandom failed: {}", e), e));
+        }
+        filled += result as usize;
+    }
+    Ok(())
+}
+
+/// Fills `buf` with random bytes from the host kernel, 256 bytes at a time as
+/// `getentropy` allows.
+#[cfg(target_os = "macos")]
+fn fill_random(buf: &mut [u8]) -> Result<(), VmError> {
+    for chunk in buf.chunks_mut(256) {
+        // SAFETY: the pointer and length describe `chunk`, which is at most 256 bytes
+        if unsafe { libc::getentropy(chunk.as_mut_ptr().cast(), chunk.len()) } != 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(VmError::device_source(format!("getentropy failed: {}", e), e));
+        }
+    }
+    Ok(())
+}
+
+/// virtio-rng device with its single request queue.
+pub struct VirtioRng {
+    mem: GuestMemoryMmap,
+    queue: QueueSync,
+    /// Random bytes handed out so far
+    bytes_provided: u64,
+}
+
+impl VirtioRng {
+    /// Creates an entropy device.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory holding the virtqueue
+    ///
+    /// # Returns
+    /// * `Ok(VirtioRng)` on success
+    /// * `Err(VmError)` if the virtqueue couldn't be created
+    pub fn new(mem: GuestMemoryMmap) -> Result<Self, VmError> {
+        match QueueSync::new(RNG_QUEUE_SIZE) {
+            Ok(queue) => Ok(VirtioRng { mem, queue, bytes_provided: 0 }),
+            Err(e) => Err(VmError::device_source(format!("failed to create virtqueue: {}", e), e))
+        }
+    }
+
+    /// Returns the request queue, e.g. to set it up without a guest driver.
+    pub fn get_queue_mut(&mut self) -> &mut QueueSync {
+        &mut self.queue
+    }
+
+    /// Returns the number of random bytes handed to the guest so far.
+    pub fn get_bytes_provided(&self) -> u64 {
+        self.bytes_provided
+    }
+
+    /// Fills the buffers the driver made available with random bytes.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the filled buffers
+    fn fill_request_queue(&mut self) -> bool {
+        if !self.queue.ready() {
+            return false;
+        }
+        let mut used_any = false;
+        while let Some(chain) = self.queue.pop_descriptor_chain(&self.mem) {
+            let head_index = chain.head_index();
+            let mut written = 0usize;
+            for descriptor in chain.writable() {
+                let len = (descriptor.len() as usize).min(MAX_RNG_REQUEST_SIZE - written);
+                let mut random = vec![0u8; len];
+                // A buffer left unfilled is returned empty and the guest asks again
+                if fill_random(&mut random).is_err() || self.mem.write_slice(&random, descriptor.addr()).is_err() {
+                    break;
+                }
+                written += len;
+                if written == MAX_RNG_REQUEST_SIZE {
+                    break;
+                }
+            }
+            if self.queue.add_used(&self.mem, head_index, written as u32).is_err() {
+                break;
+            }
+            self.bytes_provided += written as u64;
+            used_any = true;
+        }
+        used_any && self.queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn get_device_type(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn get_device_features(&self) -> u64 {
+        0
+    }
+
+    fn get_num_queues(&self) -> usize {
+        1
+    }
+
+    fn get_queue_max_size(&self, _index: usize) -> u16 {
+        RNG_QUEUE_SIZE
+    }
+
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+        match index {
+            0 => state.apply(&mut self.queue, &self.mem),
+            _ => Err(VmError::device(format!("no virtqueue {}", index)))
+        }
+    }
+
+    fn deactivate_queue(&mut self, index: usize) {
+        if index == 0 {
+            self.queue.reset();
+        }
+    }
+
+    fn process_queue(&mut self, index: usize) -> bool {
+        index == 0 && self.fill_request_queue()
+    }
+
+    fn reset(&mut self) {
+        self.queue.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_random() {
+        let mut first = [0u8; 1000];
+        let mut second = [0u8; 1000];
+        fill_random(&mut first).unwrap();
+        fill_random(&mut second).unwrap();
+        assert_ne!(first, second);
+        assert!(first.iter().any(|byte| *byte != 0));
+    }
+}
@@ -0,0 +1,350 @@
+//! 16550A compatible UART.
+//!
+//! The UART is what a guest kernel uses as its first console (`console=ttyS0`). Guest
+//! output written to the transmit holding register goes straight to a host `Write`
+//! sink, so the transmitter is always empty; host input is queued with `queue_input`
+//! and read by the guest from the receive buffer. On x86 the UART sits on the COM1 I/O
+//! ports, on ARM it is mapped as an MMIO device with one byte per register.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use crate::device_emulation::mmio::MmioDevice;
+use crate::device_emulation::pio::PioDevice;
+use crate::utils::signals::Interrupt;
+
+/// First I/O port of the COM1 UART.
+pub const COM1_PORT_BASE: u16 = 0x3f8;
+/// Number of I/O ports of a UART.
+pub const COM_PORT_COUNT: u16 = 8;
+/// Legacy interrupt line of COM1.
+pub const COM1_IRQ: u32 = 4;
+/// Most bytes of host input held until the guest reads them; further input is dropped.
+pub const SERIAL_INPUT_CAPACITY: usize = 4096;
+
+/// Receive buffer (read) / transmit holding register (write); divisor latch low with DLAB set.
+const UART_DATA: u16 = 0;
+/// Interrupt enable register; divisor latch high with DLAB set.
+const UART_IER: u16 = 1;
+/// Interrupt identification (read) / FIFO control (write).
+const UART_IIR_FCR: u16 = 2;
+/// Line control register.
+const UART_LCR: u16 = 3;
+/// Modem control register.
+const UART_MCR: u16 = 4;
+/// Line status register.
+const UART_LSR: u16 = 5;
+/// Modem status register.
+const UART_MSR: u16 = 6;
+/// Scratch register.
+const UART_SCR: u16 = 7;
+
+const IER_RECEIVED_DATA: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RECEIVED_DATA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RECEIVE: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_LOOPBACK: u8 = 0x10;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+/// Modem status outside loopback: carrier detect, data set ready and clear to send.
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// Divisor for 9600 baud, the reset value of the divisor latch.
+const DEFAULT_BAUD_DIVISOR: u16 = 12;
+
+/// A 16550A UART connected to a host output sink.
+///
+/// The line settings (divisor, word length, parity) are stored for the guest to read
+/// back but otherwise ignored. Loopback mode is supported because Linux uses it to
+/// detect the UART.
+pub struct Serial {
+    output: Box<dyn Write + Send>,
+    interrupt: Option<Box<dyn Interrupt>>,
+    input: VecDeque<u8>,
+    interrupt_enable: u8,
+    /// Set when the transmitter became empty and the guest hasn't read the IIR since
+    thr_empty_pending: bool,
+    fifo_enabled: bool,
+    line_control: u8,
+    modem_control: u8,
+    scratch: u8,
+    baud_divisor: u16,
+}
+
+impl Serial {
+    /// Creates a UART writing guest output to `output`, without an interrupt line.
+    ///
+    /// The guest then has to poll the line status register, which Linux does for
+    /// console output but not for input.
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Serial {
+            output,
+            interrupt: None,
+            input: VecDeque::new(),
+            interrupt_enable: 0,
+            thr_empty_pending: false,
+            fifo_enabled: false,
+            line_control: 0,
+            modem_control: 0,
+            scratch: 0,
+            baud_divisor: DEFAULT_BAUD_DIVISOR,
+        }
+    }
+
+    /// Creates a UART writing guest output to `output` and raising `interrupt`.
+    pub fn with_interrupt(output: Box<dyn Write + Send>, interrupt: Box<dyn Interrupt>) -> Self {
+        Serial { interrupt: Some(interrupt), ..Serial::new(output) }
+    }
+
+    /// Queues host input for the guest to read.
+    ///
+    /// # Returns
+    /// * Number of bytes queued; input beyond `SERIAL_INPUT_CAPACITY` pending bytes is dropped
+    pub fn queue_input(&mut self, data: &[u8]) -> usize {
+        let queued = data.len().min(SERIAL_INPUT_CAPACITY - self.input.len());
+        self.input.extend(&data[..queued]);
+        if queued > 0 && self.interrupt_enable & IER_RECEIVED_DATA != 0 {
+            self.raise_interrupt();
+        }
+        queued
+    }
+
+    /// Returns the number of input bytes the guest hasn't read yet.
+    pub fn get_pending_input(&self) -> usize {
+        self.input.len()
+    }
+
+    /// Handles a guest read of register `offset`.
+    pub fn read_register(&mut self, offset: u16) -> u8 {
+        let dlab = self.line_control & LCR_DLAB != 0;
+        match offset {
+            UART_DATA if dlab => self.baud_divisor as u8,
+            UART_DATA => self.input.pop_front().unwrap_or(0),
+            UART_IER if dlab => (self.baud_divisor >> 8) as u8,
+            UART_IER => self.interrupt_enable,
+            UART_IIR_FCR => {
+                let id = self.get_interrupt_id();
+                // Reading the IIR acknowledges a transmitter empty interrupt
+                if id == IIR_THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                if self.fifo_enabled { id | IIR_FIFO_ENABLED } else { id }
+            },
+            UART_LCR => self.line_control,
+            UART_MCR => self.modem_control,
+            UART_LSR => {
+                let data_ready = if self.input.is_empty() { 0 } else { LSR_DATA_READY };
+                data_ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            },
+            UART_MSR if self.modem_control & MCR_LOOPBACK != 0 => {
+                // DTR, RTS, OUT1 and OUT2 are looped back to DSR, CTS, RI and DCD
+                let mcr = self.modem_control;
+                ((mcr & 0x01) << 5) | ((mcr & 0x02) << 3) | ((mcr & 0x04) << 4) | ((mcr & 0x08) << 4)
+            },
+            UART_MSR => MSR_CONNECTED,
+            UART_SCR => self.scratch,
+            _ => 0,
+        }
+    }
+
+    /// Handles a guest write of `value` to register `offset`.
+    pub fn write_register(&mut self, offset: u16, value: u8) {
+        let dlab = self.line_control & LCR_DLAB != 0;
+        match offset {
+            UART_DATA if dlab => self.baud_divisor = (self.baud_divisor & 0xff00) | value as u16,
+            UART_DATA => {
+                if self.modem_control & MCR_LOOPBACK != 0 {
+                    self.queue_input(&[value]);
+                } else {
+                    // The console is best effort: a failing sink must not stop the guest
+                    let _ = self.output.write_all(&[value]).and_then(|_| self.output.flush());
+                }
+                self.thr_empty_pending = true;
+                if self.interrupt_enable & IER_THR_EMPTY != 0 {
+                    self.raise_interrupt();
+                }
+            },
+            UART_IER if dlab => self.baud_divisor = (self.baud_divisor & 0x00ff) | (value as u16) << 8,
+            UART_IER => {
+                let enabled = value & !self.interrupt_enable;
+                self.interrupt_enable = value & IER_MASK;
+                // The transmitter is always empty, so enabling its interrupt fires it right away
+                if enabled & IER_THR_EMPTY != 0 {
+                    self.thr_empty_pending = true;
+                }
+                if self.get_interrupt_id() != IIR_NO_INTERRUPT {
+                    self.raise_interrupt();
+                }
+            },
+            UART_IIR_FCR => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RECEIVE != 0 {
+                    self.input.clear();
+                }
+            },
+            UART_LCR => self.line_control = value,
+            UART_MCR => self.modem_control = value,
+            UART_SCR => self.scratch = value,
+            _ => {},
+        }
+    }
+
+    /// Returns the highest priority pending interrupt as reported in the IIR.
+    fn get_interrupt_id(&self) -> u8 {
+        if self.interrupt_enable & IER_RECEIVED_DATA != 0 && !self.input.is_empty() {
+            IIR_RECEIVED_DATA
+        } else if self.interrupt_enable & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    fn raise_interrupt(&self) {
+        if let Some(interrupt) = &self.interrupt {
+            // A lost interrupt only delays the guest until its next poll
+            let _ = interrupt.trigger();
+        }
+    }
+}
+
+impl PioDevice for Serial {
+    fn read_pio(&mut self, offset: u16, data: &mut [u8]) {
+        data.fill(0);
+        data[0] = self.read_register(offset);
+    }
+
+    fn write_pio(&mut self, offset: u16, data: &[u8]) {
+        if let Some(value) = data.first() {
+            self.write_register(offset, *value);
+        }
+    }
+}
+
+impl MmioDevice for Serial {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        if offset < COM_PORT_COUNT as u64 {
+            data[0] = self.read_register(offset as u16);
+        }
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        if offset < COM_PORT_COUNT as u64 && !data.is_empty() {
+            self.write_register(offset as u16, data[0]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::error::VmError;
+    use crate::utils::signals::TriggerMode;
+
+    /// Sink sharing what the UART writes with the test.
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Interrupt counting how often it was triggered.
+    struct CountingInterrupt(Arc<AtomicUsize>);
+
+    impl Interrupt for CountingInterrupt {
+        fn get_trigger_mode(&self) -> TriggerMode {
+            TriggerMode::Edge
+        }
+
+        fn assert(&self) -> Result<(), VmError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn deassert(&self) -> Result<(), VmError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_and_divisor_latch() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut serial = Serial::new(Box::new(SharedSink(written.clone())));
+
+        // 115200 baud: divisor 1 written with DLAB set does not reach the output
+        serial.write_pio(UART_LCR, &[LCR_DLAB | 0x03]);
+        serial.write_pio(UART_DATA, &[1]);
+        serial.write_pio(UART_IER, &[0]);
+        serial.write_pio(UART_LCR, &[0x03]);
+        assert_eq!(serial.baud_divisor, 1);
+
+        serial.write_pio(UART_DATA, b"o");
+        serial.write_pio(UART_DATA, b"k");
+        assert_eq!(written.lock().unwrap().as_slice(), b"ok");
+        assert_eq!(serial.read_register(UART_LSR), LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY);
+    }
+
+    #[test]
+    fn test_input_raises_interrupt() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut serial = Serial::with_interrupt(Box::new(std::io::sink()), Box::new(CountingInterrupt(count.clone())));
+
+        // Nothing is raised while the receive interrupt is disabled
+        serial.queue_input(b"h");
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        serial.write_register(UART_IER, IER_RECEIVED_DATA);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        serial.queue_input(b"i");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        assert_eq!(serial.read_register(UART_IIR_FCR), IIR_RECEIVED_DATA);
+        assert_eq!(serial.read_register(UART_LSR) & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(serial.read_register(UART_DATA), b'h');
+        assert_eq!(serial.read_register(UART_DATA), b'i');
+        assert_eq!(serial.read_register(UART_IIR_FCR), IIR_NO_INTERRUPT);
+        assert_eq!(serial.read_register(UART_LSR) & LSR_DATA_READY, 0);
+
+        assert_eq!(serial.queue_input(&[0; SERIAL_INPUT_CAPACITY + 1]), SERIAL_INPUT_CAPACITY);
+    }
+
+    #[test]
+    fn test_loopback_and_thr_empty_interrupt() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut serial = Serial::new(Box::new(SharedSink(written.clone())));
+
+        // The loopback check Linux runs while probing the port
+        serial.write_register(UART_MCR, MCR_LOOPBACK | 0x0a);
+        assert_eq!(serial.read_register(UART_MSR) & 0xf0, 0x90);
+        serial.write_register(UART_DATA, b'x');
+        assert_eq!(serial.read_register(UART_DATA), b'x');
+        assert!(written.lock().unwrap().is_empty());
+        serial.write_register(UART_MCR, 0);
+
+        // Enabling the transmitter empty interrupt reports it once
+        serial.write_register(UART_IER, IER_THR_EMPTY);
+        assert_eq!(serial.read_register(UART_IIR_FCR), IIR_THR_EMPTY);
+        assert_eq!(serial.read_register(UART_IIR_FCR), IIR_NO_INTERRUPT);
+    }
+}
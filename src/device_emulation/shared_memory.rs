@@ -0,0 +1,246 @@
+//! ivshmem-style shared memory between host processes and the guest.
+//!
+//! A `SharedMemoryRegion` maps a host file or memfd into the guest physical address
+//! space, so both sides see each other's writes without a copy or a VM exit. The guest
+//! finds each region through a small register window at a fixed address, 0xd002_0000
+//! for the first region of a KVM guest and the next ones 4 KiB apart, which it reads
+//! through `/dev/mem` or a UIO driver before mapping the region itself:
+//!
+//! | Offset | Register                                           |
+//! |--------|----------------------------------------------------|
+//! | 0x00   | Magic value, `"ishm"` little-endian                |
+//! | 0x04   | Layout version, currently 1                        |
+//! | 0x08   | Guest physical address of the region, low 32 bits  |
+//! | 0x0c   | Guest physical address of the region, high 32 bits |
+//! | 0x10   | Size of the region in bytes, low 32 bits           |
+//! | 0x14   | Size of the region in bytes, high 32 bits          |
+//!
+//! The registers are read-only. Synchronizing the accesses is left to the programs
+//! sharing the region, e.g. with atomics kept in it.
+
+use std::fs::File;
+use memmap2::{MmapMut, MmapOptions};
+use crate::device_emulation::mmio::{read_register_bytes, MmioDevice};
+use crate::error::VmError;
+use crate::vm_setup::setup_utils::{SharedMemoryBacking, SharedMemoryRegion};
+
+/// Size of the register window of a shared-memory region.
+pub const SHARED_MEMORY_REGISTERS_SIZE: u64 = 0x1000;
+/// Value of the magic register, `"ishm"` read as a little-endian 32-bit value.
+pub const SHARED_MEMORY_MAGIC: u32 = 0x6d68_7369;
+/// Version of the register layout.
+pub const SHARED_MEMORY_VERSION: u32 = 1;
+
+const REG_MAGIC: u64 = 0x00;
+const REG_VERSION: u64 = 0x04;
+const REG_ADDRESS_LOW: u64 = 0x08;
+const REG_ADDRESS_HIGH: u64 = 0x0c;
+const REG_SIZE_LOW: u64 = 0x10;
+const REG_SIZE_HIGH: u64 = 0x14;
+
+/// Host mapping of a shared-memory region, which the backend registers with the
+/// hypervisor as guest memory.
+pub struct SharedMemoryMapping {
+    mmap: MmapMut,
+}
+
+impl SharedMemoryMapping {
+    /// Maps the file backing `region`, creating or growing it to the size of the region.
+    ///
+    /// The mapping is shared, so writes through it reach the file and every other
+    /// process mapping it.
+    ///
+    /// # Returns
+    /// * `Ok(SharedMemoryMapping)` on success
+    /// * `Err(VmError)` if the file can't be opened, resized or mapped
+    pub fn open(region: &SharedMemoryRegion) -> Result<Self, VmError> {
+        let (file, name) = match region.get_backing() {
+            SharedMemoryBacking::Path(path) => match File::options().read(true).write(true).create(true).truncate(false).open(path) {
+                Ok(file) => (file, path.display().to_string()),
+                Err(e) => return Err(VmError::io(format!("failed to open shared memory {}: {}", path.display(), e), e))
+            },
+            SharedMemoryBacking::File(file) => match file.try_clone() {
+                Ok(file) => (file, "file".to_string()),
+                Err(e) => return Err(VmError::io(format!("failed to duplicate the shared memory file: {}", e), e))
+            }
+        };
+        let file_size = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(VmError::io(format!("failed to read the size of shared memory {}: {}", name, e), e))
+        };
+        if file_size < region.get_size() && let Err(e) = file.set_len(region.get_size()) {
+            return Err(VmError::io(format!("failed to grow shared memory {} to {} bytes: {}", name, region.get_size(), e), e));
+        }
+
+        let mmap = match unsafe { MmapOptions::new().len(region.get_size() as usize).map_mut(&file) } {
+            Ok(mmap) => mmap,
+            Err(e) => return Err(VmError::io(format!("failed to map shared memory {}: {}", name, e), e))
+        };
+        Ok(SharedMemoryMapping { mmap })
+    }
+
+    /// Maps `size` bytes of zeroed anonymous memory, for host memory the guest reaches
+    /// without any file behind it, e.g. a framebuffer.
+    ///
+    /// # Returns
+    /// * `Ok(SharedMemoryMapping)` on success
+    /// * `Err(VmError)` if the memory can't be mapped
+    pub fn anonymous(size: u64) -> Result<Self, VmError> {
+        match MmapOptions::new().len(size as usize).map_anon() {
+            Ok(mmap) => Ok(SharedMemoryMapping { mmap }),
+            Err(e) => Err(VmError::io(format!("failed to map {} bytes of anonymous memory: {}", size, e), e))
+        }
+    }
+
+    /// Returns the size of the mapping in bytes.
+    pub fn get_size(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    /// Returns the host virtual address of the mapping.
+    pub fn get_host_address(&self) -> u64 {
+        self.mmap.as_ptr() as u64
+    }
+
+    /// Returns the content of the region as the host sees it.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Writes the modified pages of the mapping back to the file and waits for them
+    /// to reach the storage.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the pages are on the storage
+    /// * `Err(VmError)` if writing them back failed
+    pub fn flush(&self) -> Result<(), VmError> {
+        match self.mmap.flush() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to flush shared memory: {}", e), e))
+        }
+    }
+}
+
+/// Register window describing where a shared-memory region sits in guest memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedMemoryDevice {
+    guest_address: u64,
+    size: u64,
+}
+
+impl SharedMemoryDevice {
+    /// Creates the registers of a region of `size` bytes mapped at `guest_address`.
+    pub fn new(guest_address: u64, size: u64) -> Self {
+        SharedMemoryDevice { guest_address, size }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            REG_MAGIC => SHARED_MEMORY_MAGIC,
+            REG_VERSION => SHARED_MEMORY_VERSION,
+            REG_ADDRESS_LOW => self.guest_address as u32,
+            REG_ADDRESS_HIGH => (self.guest_address >> 32) as u32,
+            REG_SIZE_LOW => self.size as u32,
+            REG_SIZE_HIGH => (self.size >> 32) as u32,
+            _ => 0
+        }
+    }
+}
+
+impl MmioDevice for SharedMemoryDevice {
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        read_register_bytes(offset, data, |offset| self.read_register(offset));
+    }
+
+    fn write_mmio(&mut self, _offset: u64, _data: &[u8]) {}
+}
+
+/// Creates an anonymous memfd of `size` bytes to back a shared-memory region.
+///
+/// The host keeps the returned file to map the region itself or to pass it on to
+/// another process, e.g. over a Unix socket.
+///
+/// # Returns
+/// * `Ok(File)` on success
+/// * `Err(VmError)` if the memfd can't be created or resized
+#[cfg(target_os = "linux")]
+pub fn create_memfd(name: &str, size: u64) -> Result<File, VmError> {
+    use std::ffi::CString;
+    use std::os::fd::FromRawFd;
+
+    let c_name = match CString::new(name) {
+        Ok(c_name) => c_name,
+        Err(_) => return Err(VmError::config(format!("memfd name {:?} contains a NUL byte", name)))
+    };
+    let fd = unsafe { libc::memfd_create(c_name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(VmError::io(format!("failed to create memfd {}: {}", name, e), e));
+    }
+    // SAFETY: memfd_create returned a new descriptor nothing else owns
+    let file = unsafe { File::from_raw_fd(fd) };
+    if let Err(e) = file.set_len(size) {
+        return Err(VmError::io(format!("failed to grow memfd {} to {} bytes: {}", name, size, e), e));
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::mmio::read_le;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_mapping_grows_and_shares_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("region");
+        let region = SharedMemoryRegion::new(SharedMemoryBacking::Path(path.clone()), 0x2000);
+        let mut mapping = SharedMemoryMapping::open(&region).expect("Mapping should succeed");
+        assert_eq!(mapping.get_size(), 0x2000);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0x2000);
+
+        // Writes through the mapping reach the file, and the other way around
+        mapping.mmap[0x1000..0x1005].copy_from_slice(b"guest");
+        assert_eq!(&std::fs::read(&path).unwrap()[0x1000..0x1005], b"guest");
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(0x10)).unwrap();
+        file.write_all(b"host").unwrap();
+        assert_eq!(&mapping.as_slice()[0x10..0x14], b"host");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_memfd_mapping_is_visible_through_the_file() {
+        let file = Arc::new(create_memfd("shm-test", 0x1000).expect("memfd should be created"));
+        let region = SharedMemoryRegion::new(SharedMemoryBacking::File(file.clone()), 0x1000);
+        let mut mapping = SharedMemoryMapping::open(&region).unwrap();
+        mapping.mmap[..4].copy_from_slice(b"data");
+
+        let mut data = [0u8; 4];
+        (&*file).read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"data");
+    }
+
+    #[test]
+    fn test_registers_describe_the_region() {
+        let mut device = SharedMemoryDevice::new(0x10_0000_0000, 0x20_0000);
+        let mut dword = [0u8; 4];
+        device.read_mmio(REG_MAGIC, &mut dword);
+        assert_eq!(&dword, b"ishm");
+        device.read_mmio(REG_VERSION, &mut dword);
+        assert_eq!(read_le(&dword), 1);
+
+        let mut qword = [0u8; 8];
+        device.read_mmio(REG_ADDRESS_LOW, &mut qword);
+        assert_eq!(read_le(&qword), 0x10_0000_0000);
+        device.read_mmio(REG_SIZE_LOW, &mut qword);
+        assert_eq!(read_le(&qword), 0x20_0000);
+
+        // Writes are ignored
+        device.write_mmio(REG_SIZE_LOW, &[0; 4]);
+        device.read_mmio(REG_SIZE_LOW, &mut dword);
+        assert_eq!(read_le(&dword), 0x20_0000);
+    }
+}
@@ -0,0 +1,182 @@
+//! virtio-9p shared directory device.
+//!
+//! Exposes a host directory to the guest, which mounts it by its tag:
+//! `mount -t 9p -o trans=virtio,version=9p2000.L <tag> /mnt`. Every request the
+//! driver makes available holds a 9P message followed by the buffers for the reply,
+//! answered by the `server::P9Server` of the device.
+
+pub mod protocol;
+pub mod server;
+
+use std::path::Path;
+use virtio_queue::{QueueSync, QueueT};
+use virtio_queue::desc::split::Descriptor;
+use vm_memory::{Bytes, GuestMemoryMmap};
+use crate::device_emulation::virtio_9p::server::{P9Server, P9_MAX_MSIZE};
+use crate::device_emulation::virtio_mmio::{VirtioDevice, VirtqueueState};
+use crate::error::VmError;
+use crate::vm_setup::setup_utils::MAX_MOUNT_TAG_LEN;
+
+/// Virtio device ID of a 9P transport.
+const VIRTIO_ID_9P: u32 = 9;
+/// Feature bit telling the configuration space holds the mount tag.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+/// Size of the request queue.
+pub const P9_QUEUE_SIZE: u16 = 128;
+
+/// virtio-9p device with its single request queue.
+pub struct Virtio9p {
+    mem: GuestMemoryMmap,
+    queue: QueueSync,
+    /// Tag the guest mounts the directory by
+    tag: String,
+    server: P9Server,
+}
+
+impl Virtio9p {
+    /// Creates a device sharing a host directory.
+    ///
+    /// # Arguments
+    /// * `mem` - Guest physical memory holding the virtqueue and the messages
+    /// * `root` - Host directory exposed to the guest
+    /// * `tag` - Tag the guest mounts the directory by
+    ///
+    /// # Returns
+    /// * `Ok(Virtio9p)` on success
+    /// * `Err(VmError)` if `root` isn't a directory, the tag is empty or longer than
+    ///   `MAX_MOUNT_TAG_LEN` bytes, or the virtqueue couldn't be created
+    pub fn new(mem: GuestMemoryMmap, root: &Path, tag: &str) -> Result<Self, VmError> {
+        if tag.is_empty() || tag.len() > MAX_MOUNT_TAG_LEN {
+            return Err(VmError::config(format!("mount tag {:?} must be 1 to {} bytes long", tag, MAX_MOUNT_TAG_LEN)));
+        }
+        let server = P9Server::new(root)?;
+        match QueueSync::new(P9_QUEUE_SIZE) {
+            Ok(queue) => Ok(Virtio9p { mem, queue, tag: tag.to_string(), server }),
+            Err(e) => Err(VmError::device_source(format!("failed to create virtqueue: {}", e), e))
+        }
+    }
+
+    /// Returns the request queue, e.g. to set it up without a guest driver.
+    pub fn get_queue_mut(&mut self) -> &mut QueueSync {
+        &mut self.queue
+    }
+
+    /// Returns the tag the guest mounts the directory by.
+    pub fn get_tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns the configuration space: the length of the tag, then the tag.
+    fn get_config_space(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    /// Answers the requests the driver made available. Requests whose message can't be
+    /// read from guest memory are returned without reply.
+    ///
+    /// # Returns
+    /// * `true` if the guest asked to be interrupted for the answered requests
+    fn process_request_queue(&mut self) -> bool {
+        if !self.queue.ready() {
+            return false;
+        }
+        let mut used_any = false;
+        while let Some(chain) = self.queue.pop_descriptor_chain(&self.mem) {
+            let head_index = chain.head_index();
+            let descriptors: Vec<Descriptor> = chain.into_iter().collect();
+            let written = self.answer_request(&descriptors).unwrap_or(0);
+            if self.queue.add_used(&self.mem, head_index, written).is_err() {
+                break;
+            }
+            used_any = true;
+        }
+        used_any && self.queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+
+    /// Reads the message in the device-readable `descriptors` and writes the reply to
+    /// the device-writable ones.
+    ///
+    /// # Returns
+    /// * `Some(len)` - the length of the reply
+    /// * `None` - if a buffer isn't in guest memory or the message is larger than any
+    ///   the server agrees to
+    fn answer_request(&mut self, descriptors: &[Descriptor]) -> Option<u32> {
+        let mut request = Vec::new();
+        let mut reply_room = 0usize;
+        for descriptor in descriptors {
+            if descriptor.is_write_only() {
+                reply_room += descriptor.len() as usize;
+            } else {
+                let start = request.len();
+                if start + descriptor.len() as usize > P9_MAX_MSIZE as usize {
+                    return None;
+                }
+                request.resize(start + descriptor.len() as usize, 0);
+                self.mem.read_slice(&mut request[start..], descriptor.addr()).ok()?;
+            }
+        }
+        let reply = self.server.handle_message(&request, reply_room);
+
+        let mut written = 0usize;
+        for descriptor in descriptors.iter().filter(|descriptor| descriptor.is_write_only()) {
+            if written == reply.len() {
+                break;
+            }
+            let len = (descriptor.len() as usize).min(reply.len() - written);
+            self.mem.write_slice(&reply[written..written + len], descriptor.addr()).ok()?;
+            written += len;
+        }
+        Some(written as u32)
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn get_device_type(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn get_device_features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn get_num_queues(&self) -> usize {
+        1
+    }
+
+    fn get_queue_max_size(&self, _index: usize) -> u16 {
+        P9_QUEUE_SIZE
+    }
+
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+        match index {
+            0 => state.apply(&mut self.queue, &self.mem),
+            _ => Err(VmError::device(format!("no virtqueue {}", index)))
+        }
+    }
+
+    fn deactivate_queue(&mut self, index: usize) {
+        if index == 0 {
+            self.queue.reset();
+        }
+    }
+
+    fn process_queue(&mut self, index: usize) -> bool {
+        index == 0 && self.process_request_queue()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.get_config_space();
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = offset.checked_add(index as u64)
+                .and_then(|position| config.get(position as usize))
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.queue.reset();
+    }
+}
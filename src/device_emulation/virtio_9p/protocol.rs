@@ -0,0 +1,194 @@
+//! 9P2000.L message encoding.
+//!
+//! Every message starts with its total size (4 bytes), its type (1 byte) and the tag
+//! the client matches the reply with (2 bytes). Integers are little endian and strings
+//! are prefixed by their length on 2 bytes.
+
+/// Protocol version spoken by the server, the Linux dialect of 9P.
+pub const P9_VERSION: &str = "9P2000.L";
+/// Size of the header of every message.
+pub const P9_HEADER_SIZE: usize = 7;
+/// Size of the header of `Rread`, before the data.
+pub const P9_READ_HEADER_SIZE: usize = P9_HEADER_SIZE + 4;
+
+/// Reply carrying the Linux error number a request failed with.
+pub const P9_RLERROR: u8 = 7;
+
+// Request types; the reply to a request has the next type
+pub const P9_TSTATFS: u8 = 8;
+pub const P9_TLOPEN: u8 = 12;
+pub const P9_TLCREATE: u8 = 14;
+pub const P9_TSYMLINK: u8 = 16;
+pub const P9_TREADLINK: u8 = 22;
+pub const P9_TGETATTR: u8 = 24;
+pub const P9_TSETATTR: u8 = 26;
+pub const P9_TXATTRWALK: u8 = 30;
+pub const P9_TREADDIR: u8 = 40;
+pub const P9_TFSYNC: u8 = 50;
+pub const P9_TLOCK: u8 = 52;
+pub const P9_TGETLOCK: u8 = 54;
+pub const P9_TLINK: u8 = 70;
+pub const P9_TMKDIR: u8 = 72;
+pub const P9_TRENAMEAT: u8 = 74;
+pub const P9_TUNLINKAT: u8 = 76;
+pub const P9_TVERSION: u8 = 100;
+pub const P9_TATTACH: u8 = 104;
+pub const P9_TFLUSH: u8 = 108;
+pub const P9_TWALK: u8 = 110;
+pub const P9_TREAD: u8 = 116;
+pub const P9_TWRITE: u8 = 118;
+pub const P9_TCLUNK: u8 = 120;
+pub const P9_TREMOVE: u8 = 122;
+
+/// Qid type of a directory.
+pub const P9_QTDIR: u8 = 0x80;
+/// Qid type of a symbolic link.
+pub const P9_QTSYMLINK: u8 = 0x02;
+/// Qid type of a regular file.
+pub const P9_QTFILE: u8 = 0x00;
+
+/// Server side identity of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    /// `P9_QTDIR`, `P9_QTSYMLINK` or `P9_QTFILE`
+    pub kind: u8,
+    /// Changes when the file changes; always 0 as clients don't cache on it
+    pub version: u32,
+    /// Unique among the files of the share, the host inode number
+    pub path: u64,
+}
+
+/// Reads the fields of a message, failing once past its end.
+pub struct WireReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> WireReader<'a> {
+    /// Starts reading at the beginning of `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        WireReader { data, position: 0 }
+    }
+
+    /// Returns the next `len` bytes.
+    pub fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(len)?;
+        let bytes = self.data.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Option<u8> {
+        self.read_bytes(1).map(|bytes| bytes[0])
+    }
+
+    pub fn read_u16(&mut self) -> Option<u16> {
+        self.read_bytes(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Option<u32> {
+        self.read_bytes(4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Option<u64> {
+        let bytes = self.read_bytes(8)?;
+        let mut value = [0u8; 8];
+        value.copy_from_slice(bytes);
+        Some(u64::from_le_bytes(value))
+    }
+
+    /// Returns the next string, `None` if it isn't UTF-8.
+    pub fn read_string(&mut self) -> Option<String> {
+        let len = self.read_u16()? as usize;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+/// Builds a message, filling in its size once complete.
+pub struct WireWriter {
+    data: Vec<u8>,
+}
+
+impl WireWriter {
+    /// Starts a message of type `kind` answering the request tagged `tag`.
+    pub fn new(kind: u8, tag: u16) -> Self {
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(&[0u8; 4]);
+        data.push(kind);
+        data.extend_from_slice(&tag.to_le_bytes());
+        WireWriter { data }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes `value`, truncated to the longest string the format allows.
+    pub fn write_string(&mut self, value: &str) {
+        let bytes = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+        self.write_u16(bytes.len() as u16);
+        self.write_bytes(bytes);
+    }
+
+    pub fn write_qid(&mut self, qid: &Qid) {
+        self.write_u8(qid.kind);
+        self.write_u32(qid.version);
+        self.write_u64(qid.path);
+    }
+
+    /// Returns the size of the message so far.
+    pub fn get_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Completes the message.
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        self.data[..4].copy_from_slice(&size.to_le_bytes());
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_round_trip() {
+        let mut writer = WireWriter::new(P9_TWALK, 0x1234);
+        writer.write_u32(1);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_string("etc");
+        writer.write_qid(&Qid { kind: P9_QTDIR, version: 0, path: 42 });
+        let message = writer.finish();
+        assert_eq!(message.len(), P9_HEADER_SIZE + 4 + 8 + 5 + 13);
+
+        let mut reader = WireReader::new(&message);
+        assert_eq!(reader.read_u32(), Some(message.len() as u32));
+        assert_eq!(reader.read_u8(), Some(P9_TWALK));
+        assert_eq!(reader.read_u16(), Some(0x1234));
+        assert_eq!(reader.read_u32(), Some(1));
+        assert_eq!(reader.read_u64(), Some(u64::MAX - 1));
+        assert_eq!(reader.read_string().as_deref(), Some("etc"));
+        assert_eq!(reader.read_u8(), Some(P9_QTDIR));
+        assert_eq!(reader.read_bytes(12).map(|bytes| bytes.len()), Some(12));
+        assert_eq!(reader.read_u8(), None);
+    }
+}
@@ -0,0 +1,1035 @@
+//! 9P2000.L file server exporting a host directory.
+//!
+//! The guest names files through fids, handles it attaches to the root of the share
+//! and walks down from there one path component at a time. A fid stays confined to
+//! the shared directory: walks refuse `..` past the root and never go through a
+//! symbolic link. Every request resolves the path of its fid again from a descriptor
+//! of the shared directory, opening one directory at a time without following
+//! symbolic links, and then reaches the file relative to its directory without
+//! following a final symbolic link either. A link the guest swaps in for a directory
+//! a fid went through therefore leads nowhere. The guest resolves the links it finds
+//! itself, as a Linux guest does anyway. Files keep the ownership and permissions they
+//! have on the host; files created by the guest belong to the user running the VM.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use crate::device_emulation::virtio_9p::protocol::*;
+use crate::error::VmError;
+
+/// Largest message size the server agrees to.
+pub const P9_MAX_MSIZE: u32 = 512 * 1024;
+
+// Linux error numbers the guest expects, whatever the host
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const ELOOP: u32 = 40;
+const EOPNOTSUPP: u32 = 95;
+
+// Linux open flags of Tlopen and Tlcreate
+const L_O_ACCMODE: u32 = 0o3;
+const L_O_WRONLY: u32 = 0o1;
+const L_O_RDWR: u32 = 0o2;
+const L_O_TRUNC: u32 = 0o1000;
+const L_O_APPEND: u32 = 0o2000;
+
+// Attributes changed by Tsetattr
+const P9_SETATTR_MODE: u32 = 0x1;
+const P9_SETATTR_UID: u32 = 0x2;
+const P9_SETATTR_GID: u32 = 0x4;
+const P9_SETATTR_SIZE: u32 = 0x8;
+const P9_SETATTR_ATIME: u32 = 0x10;
+const P9_SETATTR_MTIME: u32 = 0x20;
+const P9_SETATTR_ATIME_SET: u32 = 0x80;
+const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Attributes filled in by Rgetattr: everything up to the block count.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+/// `f_type` reported by Rstatfs.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+/// Flag of Tunlinkat removing a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+/// Longest symbolic link target read by Treadlink.
+const MAX_LINK_TARGET: usize = 4096;
+/// Lock status of Rlock granting the lock.
+const P9_LOCK_SUCCESS: u8 = 0;
+/// Lock type of Rgetlock telling no other lock is in the way.
+const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+/// File the guest holds a fid to.
+struct Fid {
+    /// Path relative to the shared directory
+    path: PathBuf,
+    /// File opened by Tlopen or Tlcreate; directories stay unopened
+    file: Option<File>,
+    /// Names in the directory, listed when the guest starts reading it
+    dir_entries: Vec<String>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Fid { path, file: None, dir_entries: Vec::new() }
+    }
+}
+
+/// File server answering the 9P requests of one guest.
+pub struct P9Server {
+    /// Shared directory, canonicalized
+    root: PathBuf,
+    /// Shared directory, open; every path is resolved from it
+    root_dir: OwnedFd,
+    /// Message size agreed on with Tversion
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl P9Server {
+    /// Creates a server exporting `root`.
+    ///
+    /// # Returns
+    /// * `Ok(P9Server)` on success
+    /// * `Err(VmError)` if `root` isn't an existing directory
+    pub fn new(root: &Path) -> Result<Self, VmError> {
+        let root = match fs::canonicalize(root) {
+            Ok(root) => root,
+            Err(e) => return Err(VmError::io(format!("failed to resolve shared directory {}: {}", root.display(), e), e))
+        };
+        if !root.is_dir() {
+            return Err(VmError::config(format!("shared directory {} is not a directory", root.display())));
+        }
+        let root_dir = match OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC).open(&root) {
+            Ok(root_dir) => OwnedFd::from(root_dir),
+            Err(e) => return Err(VmError::io(format!("failed to open shared directory {}: {}", root.display(), e), e))
+        };
+        Ok(P9Server { root, root_dir, msize: P9_MAX_MSIZE, fids: HashMap::new() })
+    }
+
+    /// Returns the shared directory.
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Answers the request `request`.
+    ///
+    /// # Arguments
+    /// * `request` - Complete 9P message from the guest
+    /// * `max_reply_len` - Room for the reply; reads are shortened to fit
+    ///
+    /// # Returns
+    /// * The reply, an Rlerror if the request failed or is malformed
+    pub fn handle_message(&mut self, request: &[u8], max_reply_len: usize) -> Vec<u8> {
+        let mut reader = WireReader::new(request);
+        let (Some(_), Some(kind), Some(tag)) = (reader.read_u32(), reader.read_u8(), reader.read_u16()) else {
+            return get_error_reply(u16::MAX, EINVAL);
+        };
+        // Data of a read has to fit in both the agreed message size and the reply buffer
+        let max_count = (self.msize as usize).min(max_reply_len).saturating_sub(P9_READ_HEADER_SIZE) as u32;
+        let reply = match kind {
+            P9_TVERSION => self.version(tag, &mut reader),
+            P9_TATTACH => self.attach(tag, &mut reader),
+            P9_TWALK => self.walk(tag, &mut reader),
+            P9_TLOPEN => self.lopen(tag, &mut reader),
+            P9_TLCREATE => self.lcreate(tag, &mut reader),
+            P9_TSYMLINK => self.symlink(tag, &mut reader),
+            P9_TREADLINK => self.readlink(tag, &mut reader),
+            P9_TGETATTR => self.getattr(tag, &mut reader),
+            P9_TSETATTR => self.setattr(tag, &mut reader),
+            P9_TSTATFS => self.statfs(tag, &mut reader),
+            P9_TREADDIR => self.readdir(tag, &mut reader, max_count),
+            P9_TFSYNC => self.fsync(tag, &mut reader),
+            P9_TLOCK => self.lock(tag, &mut reader),
+            P9_TGETLOCK => self.getlock(tag, &mut reader),
+            P9_TLINK => self.link(tag, &mut reader),
+            P9_TMKDIR => self.mkdir(tag, &mut reader),
+            P9_TRENAMEAT => self.renameat(tag, &mut reader),
+            P9_TUNLINKAT => self.unlinkat(tag, &mut reader),
+            P9_TREAD => self.read(tag, &mut reader, max_count),
+            P9_TWRITE => self.write(tag, &mut reader),
+            P9_TCLUNK => self.clunk(tag, &mut reader),
+            P9_TREMOVE => self.remove(tag, &mut reader),
+            // Requests are answered one at a time, so none is left to flush
+            P9_TFLUSH => Ok(WireWriter::new(P9_TFLUSH + 1, tag)),
+            // Extended attributes aren't exported
+            P9_TXATTRWALK => Err(EOPNOTSUPP),
+            _ => Err(EOPNOTSUPP),
+        };
+        match reply {
+            Ok(reply) => reply.finish(),
+            Err(errno) => get_error_reply(tag, errno)
+        }
+    }
+
+    /// Tversion: agrees on the message size and starts a new session.
+    fn version(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let msize = reader.read_u32().ok_or(EINVAL)?;
+        let version = reader.read_string().ok_or(EINVAL)?;
+        self.msize = msize.min(P9_MAX_MSIZE);
+        self.fids.clear();
+        let mut reply = WireWriter::new(P9_TVERSION + 1, tag);
+        reply.write_u32(self.msize);
+        reply.write_string(if version == P9_VERSION { P9_VERSION } else { "unknown" });
+        Ok(reply)
+    }
+
+    /// Tattach: points a new fid at the root of the share.
+    fn attach(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        if self.fids.contains_key(&fid) {
+            return Err(EBADF);
+        }
+        let qid = self.get_qid(Path::new(""))?;
+        self.fids.insert(fid, Fid::new(PathBuf::new()));
+        let mut reply = WireWriter::new(P9_TATTACH + 1, tag);
+        reply.write_qid(&qid);
+        Ok(reply)
+    }
+
+    /// Twalk: points `newfid` at the file reached from `fid` through the given names.
+    /// A walk failing part way answers with the qids of the names walked, leaving
+    /// `newfid` unset.
+    fn walk(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let newfid = reader.read_u32().ok_or(EINVAL)?;
+        let count = reader.read_u16().ok_or(EINVAL)?;
+        let mut path = self.get_fid(fid)?.path.clone();
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(EBADF);
+        }
+        let mut qids = Vec::with_capacity(count as usize);
+        for index in 0..count {
+            let name = reader.read_string().ok_or(EINVAL)?;
+            match self.walk_name(&path, &name) {
+                Ok((next, qid)) => {
+                    path = next;
+                    qids.push(qid);
+                },
+                Err(errno) if index == 0 => return Err(errno),
+                Err(_) => break,
+            }
+        }
+        if qids.len() == count as usize {
+            self.fids.insert(newfid, Fid::new(path));
+        }
+        let mut reply = WireWriter::new(P9_TWALK + 1, tag);
+        reply.write_u16(qids.len() as u16);
+        for qid in &qids {
+            reply.write_qid(qid);
+        }
+        Ok(reply)
+    }
+
+    /// Returns the path and qid of `name` in the directory `path`.
+    fn walk_name(&self, path: &Path, name: &str) -> Result<(PathBuf, Qid), u32> {
+        if !is_dir(&self.get_stat(path)?) {
+            return Err(ENOTDIR);
+        }
+        let next = match name {
+            ".." => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            _ => path.join(check_name(name)?),
+        };
+        let qid = self.get_qid(&next)?;
+        Ok((next, qid))
+    }
+
+    /// Tlopen: opens the file of a fid.
+    fn lopen(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let flags = reader.read_u32().ok_or(EINVAL)?;
+        let (dir, name) = self.open_parent(&self.get_fid(fid)?.path)?;
+        let stat = stat_at(&dir, &name)?;
+        if is_symlink(&stat) {
+            return Err(ELOOP);
+        }
+        // Opening fails if the file was replaced by a link meanwhile
+        let file = match is_dir(&stat) {
+            true => None,
+            false => Some(File::from(open_at(&dir, &name, get_open_flags(flags), 0)?)),
+        };
+        let fid = self.get_fid_mut(fid)?;
+        fid.file = file;
+        fid.dir_entries.clear();
+        let mut reply = WireWriter::new(P9_TLOPEN + 1, tag);
+        reply.write_qid(&get_qid(&stat));
+        reply.write_u32(0);
+        Ok(reply)
+    }
+
+    /// Tlcreate: creates a regular file in the directory of a fid, which then refers to
+    /// the new file, open.
+    fn lcreate(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let name = reader.read_string().ok_or(EINVAL)?;
+        let flags = reader.read_u32().ok_or(EINVAL)?;
+        let mode = reader.read_u32().ok_or(EINVAL)?;
+        let dir_path = self.get_fid(fid)?.path.clone();
+        let name = check_name(&name)?;
+        let dir = self.open_dir(&dir_path)?;
+        let c_name = get_c_name(OsStr::new(name))?;
+        // Creating needs write access, whatever the guest asked for
+        let mut open_flags = get_open_flags(flags);
+        if open_flags & libc::O_ACCMODE == libc::O_RDONLY {
+            open_flags = (open_flags & !libc::O_ACCMODE) | libc::O_RDWR;
+        }
+        let file = File::from(open_at(&dir, &c_name, open_flags | libc::O_CREAT | libc::O_EXCL, (mode & 0o7777) as libc::mode_t)?);
+        let qid = get_qid(&stat_at(&dir, &c_name)?);
+        let path = dir_path.join(name);
+        let fid = self.get_fid_mut(fid)?;
+        *fid = Fid::new(path);
+        fid.file = Some(file);
+        let mut reply = WireWriter::new(P9_TLCREATE + 1, tag);
+        reply.write_qid(&qid);
+        reply.write_u32(0);
+        Ok(reply)
+    }
+
+    /// Tsymlink: creates a symbolic link in the directory of a fid.
+    fn symlink(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let name = reader.read_string().ok_or(EINVAL)?;
+        let target = reader.read_string().ok_or(EINVAL)?;
+        let dir = self.open_dir(&self.get_fid(fid)?.path)?;
+        let name = get_c_name(OsStr::new(check_name(&name)?))?;
+        let target = CString::new(target).map_err(|_| EINVAL)?;
+        // SAFETY: both strings are NUL terminated and `dir` is an open directory
+        check_result(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+        let mut reply = WireWriter::new(P9_TSYMLINK + 1, tag);
+        reply.write_qid(&get_qid(&stat_at(&dir, &name)?));
+        Ok(reply)
+    }
+
+    /// Treadlink: returns the target of a symbolic link.
+    fn readlink(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let (dir, name) = self.open_parent(&self.get_fid(fid)?.path)?;
+        let mut target = vec![0u8; MAX_LINK_TARGET];
+        // SAFETY: `name` is NUL terminated and readlinkat writes at most `target.len()` bytes
+        let len = unsafe { libc::readlinkat(dir.as_raw_fd(), name.as_ptr(), target.as_mut_ptr().cast(), target.len()) };
+        if len < 0 {
+            return Err(get_errno(&std::io::Error::last_os_error()));
+        }
+        let mut reply = WireWriter::new(P9_TREADLINK + 1, tag);
+        reply.write_string(&String::from_utf8_lossy(&target[..len as usize]));
+        Ok(reply)
+    }
+
+    /// Tgetattr: returns the attributes of a file.
+    // The fields of `stat` have narrower or signed types on macOS
+    #[allow(clippy::unnecessary_cast)]
+    fn getattr(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let stat = self.get_stat(&self.get_fid(fid)?.path)?;
+        let mut reply = WireWriter::new(P9_TGETATTR + 1, tag);
+        reply.write_u64(P9_GETATTR_BASIC);
+        reply.write_qid(&get_qid(&stat));
+        reply.write_u32(stat.st_mode as u32);
+        reply.write_u32(stat.st_uid);
+        reply.write_u32(stat.st_gid);
+        reply.write_u64(stat.st_nlink as u64);
+        reply.write_u64(stat.st_rdev as u64);
+        reply.write_u64(stat.st_size as u64);
+        reply.write_u64(stat.st_blksize as u64);
+        reply.write_u64(stat.st_blocks as u64);
+        for (seconds, nanoseconds) in [
+            (stat.st_atime, stat.st_atime_nsec),
+            (stat.st_mtime, stat.st_mtime_nsec),
+            (stat.st_ctime, stat.st_ctime_nsec),
+        ] {
+            reply.write_u64(seconds as u64);
+            reply.write_u64(nanoseconds as u64);
+        }
+        // Birth time, generation and data version aren't reported
+        for _ in 0..4 {
+            reply.write_u64(0);
+        }
+        Ok(reply)
+    }
+
+    /// Tsetattr: changes the mode, owner, size or times of a file.
+    fn setattr(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let valid = reader.read_u32().ok_or(EINVAL)?;
+        let mode = reader.read_u32().ok_or(EINVAL)?;
+        let uid = reader.read_u32().ok_or(EINVAL)?;
+        let gid = reader.read_u32().ok_or(EINVAL)?;
+        let size = reader.read_u64().ok_or(EINVAL)?;
+        let atime = (reader.read_u64().ok_or(EINVAL)?, reader.read_u64().ok_or(EINVAL)?);
+        let mtime = (reader.read_u64().ok_or(EINVAL)?, reader.read_u64().ok_or(EINVAL)?);
+        let (dir, name) = self.open_parent(&self.get_fid(fid)?.path)?;
+        let stat = stat_at(&dir, &name)?;
+
+        if valid & P9_SETATTR_MODE != 0 {
+            // Changing the mode of a link isn't possible on Linux; AT_SYMLINK_NOFOLLOW
+            // keeps a link swapped in meanwhile from passing the change to its target
+            if is_symlink(&stat) {
+                return Err(EOPNOTSUPP);
+            }
+            // SAFETY: `name` is NUL terminated and `dir` is an open directory
+            check_result(unsafe { libc::fchmodat(dir.as_raw_fd(), name.as_ptr(), (mode & 0o7777) as libc::mode_t, libc::AT_SYMLINK_NOFOLLOW) })?;
+        }
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            // An id of -1 leaves it as it is
+            let uid = if valid & P9_SETATTR_UID != 0 { uid } else { u32::MAX };
+            let gid = if valid & P9_SETATTR_GID != 0 { gid } else { u32::MAX };
+            // SAFETY: `name` is NUL terminated and `dir` is an open directory
+            check_result(unsafe { libc::fchownat(dir.as_raw_fd(), name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+        }
+        if valid & P9_SETATTR_SIZE != 0 {
+            let file = File::from(open_at(&dir, &name, libc::O_WRONLY, 0)?);
+            file.set_len(size).map_err(|e| get_errno(&e))?;
+        }
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let get_time = |changed: u32, set: u32, (seconds, nanoseconds): (u64, u64)| match (valid & changed != 0, valid & set != 0) {
+                (false, _) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                (true, false) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+                (true, true) => libc::timespec { tv_sec: seconds as libc::time_t, tv_nsec: nanoseconds as _ },
+            };
+            let times = [
+                get_time(P9_SETATTR_ATIME, P9_SETATTR_ATIME_SET, atime),
+                get_time(P9_SETATTR_MTIME, P9_SETATTR_MTIME_SET, mtime),
+            ];
+            // SAFETY: `name` is NUL terminated and `times` holds the two timestamps utimensat reads
+            check_result(unsafe { libc::utimensat(dir.as_raw_fd(), name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+        }
+        Ok(WireWriter::new(P9_TSETATTR + 1, tag))
+    }
+
+    /// Tstatfs: returns the usage of the host file system holding the share.
+    fn statfs(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let path = &self.get_fid(fid)?.path;
+        // Any other file is on the file system of its directory
+        let dir = match self.open_dir(path) {
+            Ok(dir) => dir,
+            Err(_) => self.open_parent(path)?.0,
+        };
+        // SAFETY: fstatvfs only writes the structure it is given
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `dir` is open and `stat` is a valid statvfs structure
+        check_result(unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut stat) })?;
+        let mut reply = WireWriter::new(P9_TSTATFS + 1, tag);
+        reply.write_u32(V9FS_MAGIC);
+        reply.write_u32(stat.f_bsize as u32);
+        reply.write_u64(stat.f_blocks as u64);
+        reply.write_u64(stat.f_bfree as u64);
+        reply.write_u64(stat.f_bavail as u64);
+        reply.write_u64(stat.f_files as u64);
+        reply.write_u64(stat.f_ffree as u64);
+        reply.write_u64(stat.f_fsid as u64);
+        reply.write_u32(stat.f_namemax as u32);
+        Ok(reply)
+    }
+
+    /// Treaddir: returns the entries of a directory from `offset` on, as many as fit in
+    /// `count` bytes. The offset of an entry is its index in the listing plus one; the
+    /// directory is listed again when the guest reads it from the start.
+    fn readdir(&mut self, tag: u16, reader: &mut WireReader, max_count: u32) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let offset = reader.read_u64().ok_or(EINVAL)?;
+        let count = reader.read_u32().ok_or(EINVAL)?.min(max_count) as usize;
+        let dir = self.open_dir(&self.get_fid(fid)?.path)?;
+        if offset == 0 {
+            let names = list_dir(dir.try_clone().map_err(|e| get_errno(&e))?)?;
+            self.get_fid_mut(fid)?.dir_entries = names;
+        }
+
+        // Entries are encoded like the body of a message, whose header is then dropped
+        let mut entries = WireWriter::new(0, 0);
+        let fid = self.get_fid(fid)?;
+        for (index, name) in fid.dir_entries.iter().enumerate().skip(offset as usize) {
+            // Entries removed since the listing are left out
+            let Ok(stat) = get_c_name(OsStr::new(name)).and_then(|name| stat_at(&dir, &name)) else { continue };
+            let entry_len = 13 + 8 + 1 + 2 + name.len();
+            if entries.get_len() - P9_HEADER_SIZE + entry_len > count {
+                break;
+            }
+            entries.write_qid(&get_qid(&stat));
+            entries.write_u64(index as u64 + 1);
+            entries.write_u8(get_dirent_type(&stat));
+            entries.write_string(name);
+        }
+        let entries = entries.finish();
+        let mut reply = WireWriter::new(P9_TREADDIR + 1, tag);
+        reply.write_u32((entries.len() - P9_HEADER_SIZE) as u32);
+        reply.write_bytes(&entries[P9_HEADER_SIZE..]);
+        Ok(reply)
+    }
+
+    /// Tfsync: flushes an open file to the host's storage.
+    fn fsync(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let datasync = reader.read_u32().ok_or(EINVAL)?;
+        if let Some(file) = &self.get_fid(fid)?.file {
+            let synced = if datasync != 0 { file.sync_data() } else { file.sync_all() };
+            synced.map_err(|e| get_errno(&e))?;
+        }
+        Ok(WireWriter::new(P9_TFSYNC + 1, tag))
+    }
+
+    /// Tlock: grants every lock. The guest is the only client of the share, so its own
+    /// kernel already keeps its processes' locks apart.
+    fn lock(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        self.get_fid(fid)?;
+        let mut reply = WireWriter::new(P9_TLOCK + 1, tag);
+        reply.write_u8(P9_LOCK_SUCCESS);
+        Ok(reply)
+    }
+
+    /// Tgetlock: reports that no other lock is in the way, see `lock`.
+    fn getlock(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let _kind = reader.read_u8().ok_or(EINVAL)?;
+        let start = reader.read_u64().ok_or(EINVAL)?;
+        let length = reader.read_u64().ok_or(EINVAL)?;
+        let proc_id = reader.read_u32().ok_or(EINVAL)?;
+        let client_id = reader.read_string().ok_or(EINVAL)?;
+        self.get_fid(fid)?;
+        let mut reply = WireWriter::new(P9_TGETLOCK + 1, tag);
+        reply.write_u8(P9_LOCK_TYPE_UNLCK);
+        reply.write_u64(start);
+        reply.write_u64(length);
+        reply.write_u32(proc_id);
+        reply.write_string(&client_id);
+        Ok(reply)
+    }
+
+    /// Tlink: creates a hard link to the file of a fid in the directory of another.
+    fn link(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let dir_fid = reader.read_u32().ok_or(EINVAL)?;
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let name = reader.read_string().ok_or(EINVAL)?;
+        let (target_dir, target) = self.open_parent(&self.get_fid(fid)?.path)?;
+        let dir = self.open_dir(&self.get_fid(dir_fid)?.path)?;
+        let name = get_c_name(OsStr::new(check_name(&name)?))?;
+        // SAFETY: both names are NUL terminated and both directories are open; without
+        // AT_SYMLINK_FOLLOW a link to a symbolic link is created rather than to its target
+        check_result(unsafe { libc::linkat(target_dir.as_raw_fd(), target.as_ptr(), dir.as_raw_fd(), name.as_ptr(), 0) })?;
+        Ok(WireWriter::new(P9_TLINK + 1, tag))
+    }
+
+    /// Tmkdir: creates a directory in the directory of a fid.
+    fn mkdir(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let name = reader.read_string().ok_or(EINVAL)?;
+        let mode = reader.read_u32().ok_or(EINVAL)?;
+        let dir = self.open_dir(&self.get_fid(fid)?.path)?;
+        let name = get_c_name(OsStr::new(check_name(&name)?))?;
+        // SAFETY: `name` is NUL terminated and `dir` is an open directory
+        check_result(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), (mode & 0o7777) as libc::mode_t) })?;
+        let mut reply = WireWriter::new(P9_TMKDIR + 1, tag);
+        reply.write_qid(&get_qid(&stat_at(&dir, &name)?));
+        Ok(reply)
+    }
+
+    /// Trenameat: renames a file, moving the fids that refer to it or to files below it.
+    fn renameat(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let old_dir_fid = reader.read_u32().ok_or(EINVAL)?;
+        let old_name = reader.read_string().ok_or(EINVAL)?;
+        let new_dir_fid = reader.read_u32().ok_or(EINVAL)?;
+        let new_name = reader.read_string().ok_or(EINVAL)?;
+        let old_dir_path = self.get_fid(old_dir_fid)?.path.clone();
+        let new_dir_path = self.get_fid(new_dir_fid)?.path.clone();
+        let old_name = check_name(&old_name)?;
+        let new_name = check_name(&new_name)?;
+        let old_dir = self.open_dir(&old_dir_path)?;
+        let new_dir = self.open_dir(&new_dir_path)?;
+        let (old_c_name, new_c_name) = (get_c_name(OsStr::new(old_name))?, get_c_name(OsStr::new(new_name))?);
+        // SAFETY: both names are NUL terminated and both directories are open
+        check_result(unsafe { libc::renameat(old_dir.as_raw_fd(), old_c_name.as_ptr(), new_dir.as_raw_fd(), new_c_name.as_ptr()) })?;
+        let old_path = old_dir_path.join(old_name);
+        let new_path = new_dir_path.join(new_name);
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.path.strip_prefix(&old_path) {
+                fid.path = new_path.join(rest);
+            }
+        }
+        Ok(WireWriter::new(P9_TRENAMEAT + 1, tag))
+    }
+
+    /// Tunlinkat: removes a file, or a directory with `AT_REMOVEDIR`, from the
+    /// directory of a fid.
+    fn unlinkat(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let name = reader.read_string().ok_or(EINVAL)?;
+        let flags = reader.read_u32().ok_or(EINVAL)?;
+        let dir = self.open_dir(&self.get_fid(fid)?.path)?;
+        let name = get_c_name(OsStr::new(check_name(&name)?))?;
+        let host_flags = if flags & AT_REMOVEDIR != 0 { libc::AT_REMOVEDIR } else { 0 };
+        // SAFETY: `name` is NUL terminated and `dir` is an open directory
+        check_result(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), host_flags) })?;
+        Ok(WireWriter::new(P9_TUNLINKAT + 1, tag))
+    }
+
+    /// Tread: reads from an open file, at most `max_count` bytes.
+    fn read(&mut self, tag: u16, reader: &mut WireReader, max_count: u32) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let offset = reader.read_u64().ok_or(EINVAL)?;
+        let count = reader.read_u32().ok_or(EINVAL)?.min(max_count);
+        let file = self.get_fid(fid)?.file.as_ref().ok_or(EBADF)?;
+        let mut data = vec![0u8; count as usize];
+        let len = file.read_at(&mut data, offset).map_err(|e| get_errno(&e))?;
+        let mut reply = WireWriter::new(P9_TREAD + 1, tag);
+        reply.write_u32(len as u32);
+        reply.write_bytes(&data[..len]);
+        Ok(reply)
+    }
+
+    /// Twrite: writes to an open file.
+    fn write(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let offset = reader.read_u64().ok_or(EINVAL)?;
+        let count = reader.read_u32().ok_or(EINVAL)?;
+        let data = reader.read_bytes(count as usize).ok_or(EINVAL)?;
+        let file = self.get_fid(fid)?.file.as_ref().ok_or(EBADF)?;
+        let len = file.write_at(data, offset).map_err(|e| get_errno(&e))?;
+        let mut reply = WireWriter::new(P9_TWRITE + 1, tag);
+        reply.write_u32(len as u32);
+        Ok(reply)
+    }
+
+    /// Tclunk: forgets a fid, closing its file.
+    fn clunk(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        self.fids.remove(&fid).ok_or(EBADF)?;
+        Ok(WireWriter::new(P9_TCLUNK + 1, tag))
+    }
+
+    /// Tremove: removes the file of a fid and forgets the fid, even if the removal fails.
+    fn remove(&mut self, tag: u16, reader: &mut WireReader) -> Result<WireWriter, u32> {
+        let fid = reader.read_u32().ok_or(EINVAL)?;
+        let fid = self.fids.remove(&fid).ok_or(EBADF)?;
+        let (dir, name) = self.open_parent(&fid.path)?;
+        let host_flags = if is_dir(&stat_at(&dir, &name)?) { libc::AT_REMOVEDIR } else { 0 };
+        // SAFETY: `name` is NUL terminated and `dir` is an open directory
+        check_result(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), host_flags) })?;
+        Ok(WireWriter::new(P9_TREMOVE + 1, tag))
+    }
+
+    fn get_fid(&self, fid: u32) -> Result<&Fid, u32> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn get_fid_mut(&mut self, fid: u32) -> Result<&mut Fid, u32> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    /// Opens the directory `path`, relative to the share, one component at a time
+    /// without following symbolic links, so no link along the path leads out of the share.
+    fn open_dir(&self, path: &Path) -> Result<OwnedFd, u32> {
+        let mut dir = self.root_dir.try_clone().map_err(|e| get_errno(&e))?;
+        for component in path.components() {
+            dir = open_at(&dir, &get_c_name(component.as_os_str())?, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        }
+        Ok(dir)
+    }
+
+    /// Opens the directory holding `path`, relative to the share, see `open_dir`, and
+    /// returns it with the name of `path` in it. The root of the share is `.` in itself.
+    fn open_parent(&self, path: &Path) -> Result<(OwnedFd, CString), u32> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok((self.open_dir(parent)?, get_c_name(name)?)),
+            _ => Ok((self.open_dir(path)?, c".".to_owned())),
+        }
+    }
+
+    /// Returns the status of `path`, relative to the share, without following a
+    /// final symbolic link.
+    fn get_stat(&self, path: &Path) -> Result<libc::stat, u32> {
+        let (dir, name) = self.open_parent(path)?;
+        stat_at(&dir, &name)
+    }
+
+    fn get_qid(&self, path: &Path) -> Result<Qid, u32> {
+        self.get_stat(path).map(|stat| get_qid(&stat))
+    }
+}
+
+/// Returns an Rlerror reply to the request tagged `tag`.
+fn get_error_reply(tag: u16, errno: u32) -> Vec<u8> {
+    let mut reply = WireWriter::new(P9_RLERROR, tag);
+    reply.write_u32(errno);
+    reply.finish()
+}
+
+/// Fails unless `name` is a single path component other than `.` and `..`.
+fn check_name(name: &str) -> Result<&str, u32> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(EINVAL);
+    }
+    Ok(name)
+}
+
+/// Returns the host open flags of the Linux open `flags` of the guest. Truncating
+/// needs write access.
+fn get_open_flags(flags: u32) -> libc::c_int {
+    let mut open_flags = match flags & L_O_ACCMODE {
+        L_O_WRONLY => libc::O_WRONLY,
+        L_O_RDWR => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    };
+    if flags & L_O_TRUNC != 0 && open_flags != libc::O_RDONLY {
+        open_flags |= libc::O_TRUNC;
+    }
+    if flags & L_O_APPEND != 0 {
+        open_flags |= libc::O_APPEND;
+    }
+    open_flags
+}
+
+/// Returns `name` as a C string, which a name from the guest never fails to be.
+fn get_c_name(name: &OsStr) -> Result<CString, u32> {
+    CString::new(name.as_bytes()).map_err(|_| EINVAL)
+}
+
+/// Opens `name` in the directory `dir` with the host open `flags`, never following a
+/// final symbolic link.
+fn open_at(dir: &OwnedFd, name: &CStr, flags: libc::c_int, mode: libc::mode_t) -> Result<OwnedFd, u32> {
+    // SAFETY: `name` is NUL terminated and `dir` is open
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, libc::c_uint::from(mode)) };
+    if fd < 0 {
+        return Err(get_errno(&std::io::Error::last_os_error()));
+    }
+    // SAFETY: openat returned a new descriptor nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Returns the status of `name` in the directory `dir`, without following a final
+/// symbolic link.
+fn stat_at(dir: &OwnedFd, name: &CStr) -> Result<libc::stat, u32> {
+    // SAFETY: fstatat only writes the structure it is given
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    // SAFETY: `name` is NUL terminated, `dir` is open and `stat` is a valid stat structure
+    check_result(unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) })?;
+    Ok(stat)
+}
+
+/// Returns the names in the directory `dir`, sorted, without `.` and `..`.
+fn list_dir(dir: OwnedFd) -> Result<Vec<String>, u32> {
+    let fd = dir.into_raw_fd();
+    // SAFETY: fdopendir takes over `fd` on success, closed by closedir
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let e = std::io::Error::last_os_error();
+        // SAFETY: `fd` is still owned here since fdopendir failed
+        unsafe { libc::close(fd) };
+        return Err(get_errno(&e));
+    }
+    let mut names = Vec::new();
+    loop {
+        // SAFETY: `stream` is open; the entry stays valid until the next readdir
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        // SAFETY: `d_name` of an entry is NUL terminated
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        match name.to_str() {
+            Ok(".") | Ok("..") | Err(_) => {},
+            Ok(name) => names.push(name.to_string()),
+        }
+    }
+    // SAFETY: `stream` is open and not used afterwards
+    unsafe { libc::closedir(stream) };
+    names.sort();
+    Ok(names)
+}
+
+/// Fails with the error number of the last call unless `result` is 0.
+fn check_result(result: libc::c_int) -> Result<(), u32> {
+    match result {
+        0 => Ok(()),
+        _ => Err(get_errno(&std::io::Error::last_os_error()))
+    }
+}
+
+/// Returns `true` if `stat` is the status of a directory.
+fn is_dir(stat: &libc::stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+/// Returns `true` if `stat` is the status of a symbolic link.
+fn is_symlink(stat: &libc::stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFLNK
+}
+
+fn get_qid(stat: &libc::stat) -> Qid {
+    let kind = if is_dir(stat) {
+        P9_QTDIR
+    } else if is_symlink(stat) {
+        P9_QTSYMLINK
+    } else {
+        P9_QTFILE
+    };
+    Qid { kind, version: 0, path: stat.st_ino }
+}
+
+/// Returns the `d_type` of a Treaddir entry.
+fn get_dirent_type(stat: &libc::stat) -> u8 {
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFIFO => 1,
+        libc::S_IFCHR => 2,
+        libc::S_IFDIR => 4,
+        libc::S_IFBLK => 6,
+        libc::S_IFREG => 8,
+        libc::S_IFLNK => 10,
+        libc::S_IFSOCK => 12,
+        _ => 0,
+    }
+}
+
+/// Returns the Linux error number the guest expects for `e`.
+#[cfg(target_os = "linux")]
+fn get_errno(e: &std::io::Error) -> u32 {
+    e.raw_os_error().map(|errno| errno as u32).unwrap_or(EIO)
+}
+
+/// Returns the Linux error number the guest expects for `e`; host error numbers differ.
+#[cfg(not(target_os = "linux"))]
+fn get_errno(e: &std::io::Error) -> u32 {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::NotFound => 2,
+        ErrorKind::PermissionDenied if e.raw_os_error() == Some(libc::EPERM) => 1,
+        ErrorKind::PermissionDenied => 13,
+        ErrorKind::AlreadyExists => 17,
+        ErrorKind::NotADirectory => ENOTDIR,
+        ErrorKind::IsADirectory => 21,
+        ErrorKind::DirectoryNotEmpty => 39,
+        ErrorKind::InvalidInput => EINVAL,
+        ErrorKind::StorageFull => 28,
+        ErrorKind::ReadOnlyFilesystem => 30,
+        ErrorKind::FilesystemLoop => ELOOP,
+        _ => EIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    // Helper: send a request of type `kind` with the body built by `body`
+    fn send(server: &mut P9Server, kind: u8, body: impl FnOnce(&mut WireWriter)) -> Vec<u8> {
+        let mut request = WireWriter::new(kind, 1);
+        body(&mut request);
+        server.handle_message(&request.finish(), P9_MAX_MSIZE as usize)
+    }
+
+    // Helper: return the type of a reply and its body
+    fn split_reply(reply: &[u8]) -> (u8, &[u8]) {
+        assert_eq!(u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize, reply.len());
+        (reply[4], &reply[P9_HEADER_SIZE..])
+    }
+
+    // Helper: walk `fid` to `newfid` through `names`
+    fn walk(server: &mut P9Server, fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+        send(server, P9_TWALK, |request| {
+            request.write_u32(fid);
+            request.write_u32(newfid);
+            request.write_u16(names.len() as u16);
+            for name in names {
+                request.write_string(name);
+            }
+        })
+    }
+
+    fn attach(server: &mut P9Server) {
+        let reply = send(server, P9_TVERSION, |request| {
+            request.write_u32(1024 * 1024);
+            request.write_string(P9_VERSION);
+        });
+        let (kind, body) = split_reply(&reply);
+        assert_eq!(kind, P9_TVERSION + 1);
+        assert_eq!(&body[..4], &P9_MAX_MSIZE.to_le_bytes());
+        let reply = send(server, P9_TATTACH, |request| {
+            request.write_u32(0);
+            request.write_u32(u32::MAX);
+            request.write_string("root");
+            request.write_string("");
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_TATTACH + 1);
+    }
+
+    #[test]
+    fn test_read_and_write_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs/readme"), b"hello from the host").unwrap();
+        let mut server = P9Server::new(dir.path()).unwrap();
+        attach(&mut server);
+
+        // Open and read the host file
+        let reply = walk(&mut server, 0, 1, &["docs", "readme"]);
+        let (kind, body) = split_reply(&reply);
+        assert_eq!((kind, &body[..2]), (P9_TWALK + 1, &2u16.to_le_bytes()[..]));
+        assert_eq!(body[2], P9_QTDIR);
+        let reply = send(&mut server, P9_TLOPEN, |request| {
+            request.write_u32(1);
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_TLOPEN + 1);
+        let reply = send(&mut server, P9_TREAD, |request| {
+            request.write_u32(1);
+            request.write_u64(11);
+            request.write_u32(100);
+        });
+        let (kind, body) = split_reply(&reply);
+        assert_eq!(kind, P9_TREAD + 1);
+        assert_eq!(&body[4..], b"the host");
+
+        // Create a file from the guest and write to it
+        walk(&mut server, 0, 2, &["docs"]);
+        let reply = send(&mut server, P9_TLCREATE, |request| {
+            request.write_u32(2);
+            request.write_string("notes");
+            request.write_u32(L_O_WRONLY);
+            request.write_u32(0o640);
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_TLCREATE + 1);
+        let reply = send(&mut server, P9_TWRITE, |request| {
+            request.write_u32(2);
+            request.write_u64(0);
+            request.write_u32(5);
+            request.write_bytes(b"guest");
+        });
+        assert_eq!(split_reply(&reply), (P9_TWRITE + 1, &5u32.to_le_bytes()[..]));
+        assert_eq!(fs::read(dir.path().join("docs/notes")).unwrap(), b"guest");
+        assert_eq!(fs::metadata(dir.path().join("docs/notes")).unwrap().mode() & 0o777, 0o640);
+
+        // List the directory
+        walk(&mut server, 0, 3, &["docs"]);
+        let reply = send(&mut server, P9_TREADDIR, |request| {
+            request.write_u32(3);
+            request.write_u64(0);
+            request.write_u32(4096);
+        });
+        let (kind, body) = split_reply(&reply);
+        assert_eq!(kind, P9_TREADDIR + 1);
+        let mut entries = WireReader::new(&body[4..]);
+        let mut names = Vec::new();
+        while entries.read_bytes(13 + 8 + 1).is_some() {
+            names.push(entries.read_string().unwrap());
+        }
+        assert_eq!(names, ["notes", "readme"]);
+
+        // Clunked fids are gone
+        let reply = send(&mut server, P9_TCLUNK, |request| request.write_u32(1));
+        assert_eq!(split_reply(&reply).0, P9_TCLUNK + 1);
+        let reply = send(&mut server, P9_TCLUNK, |request| request.write_u32(1));
+        assert_eq!(split_reply(&reply), (P9_RLERROR, &EBADF.to_le_bytes()[..]));
+    }
+
+    #[test]
+    fn test_walks_stay_in_the_share() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let share = dir.path().join("share");
+        fs::create_dir(&share).unwrap();
+        fs::write(dir.path().join("secret"), b"host only").unwrap();
+        std::os::unix::fs::symlink(dir.path(), share.join("escape")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret"), share.join("secret")).unwrap();
+        let mut server = P9Server::new(&share).unwrap();
+        attach(&mut server);
+
+        // `..` stops at the root of the share
+        let (kind, _) = split_reply(&walk(&mut server, 0, 1, &["..", ".."]));
+        assert_eq!(kind, P9_TWALK + 1);
+        let reply = walk(&mut server, 1, 2, &["secret"]);
+        assert_eq!(split_reply(&reply).0, P9_TWALK + 1);
+
+        // Links are neither walked through nor opened
+        let reply = walk(&mut server, 0, 3, &["escape", "secret"]);
+        let (kind, body) = split_reply(&reply);
+        assert_eq!((kind, &body[..2], body[2]), (P9_TWALK + 1, &1u16.to_le_bytes()[..], P9_QTSYMLINK));
+        let reply = send(&mut server, P9_TCLUNK, |request| request.write_u32(3));
+        assert_eq!(split_reply(&reply), (P9_RLERROR, &EBADF.to_le_bytes()[..]));
+        let reply = send(&mut server, P9_TLOPEN, |request| {
+            request.write_u32(2);
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply), (P9_RLERROR, &ELOOP.to_le_bytes()[..]));
+        let reply = walk(&mut server, 0, 4, &["../secret"]);
+        assert_eq!(split_reply(&reply), (P9_RLERROR, &EINVAL.to_le_bytes()[..]));
+    }
+
+    #[test]
+    fn test_stale_fids_stay_in_the_share() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let share = dir.path().join("share");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(share.join("a")).unwrap();
+        fs::write(share.join("a/file"), b"shared").unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("file"), b"host only").unwrap();
+        fs::set_permissions(outside.join("file"), fs::Permissions::from_mode(0o600)).unwrap();
+        let mut server = P9Server::new(&share).unwrap();
+        attach(&mut server);
+        walk(&mut server, 0, 1, &["a"]);
+        walk(&mut server, 0, 2, &["a", "file"]);
+
+        // The guest replaces the directory its fids went through with a link out of the share
+        fs::remove_file(share.join("a/file")).unwrap();
+        let reply = send(&mut server, P9_TUNLINKAT, |request| {
+            request.write_u32(0);
+            request.write_string("a");
+            request.write_u32(AT_REMOVEDIR);
+        });
+        assert_eq!(split_reply(&reply).0, P9_TUNLINKAT + 1);
+        let reply = send(&mut server, P9_TSYMLINK, |request| {
+            request.write_u32(0);
+            request.write_string("a");
+            request.write_string(outside.to_str().unwrap());
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_TSYMLINK + 1);
+
+        // Nothing reaches the host directory through the stale fids
+        let reply = send(&mut server, P9_TLCREATE, |request| {
+            request.write_u32(1);
+            request.write_string("created");
+            request.write_u32(L_O_WRONLY);
+            request.write_u32(0o644);
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_RLERROR);
+        let reply = send(&mut server, P9_TMKDIR, |request| {
+            request.write_u32(1);
+            request.write_string("created");
+            request.write_u32(0o755);
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_RLERROR);
+        let reply = send(&mut server, P9_TSYMLINK, |request| {
+            request.write_u32(1);
+            request.write_string("created");
+            request.write_string("/");
+            request.write_u32(0);
+        });
+        assert_eq!(split_reply(&reply).0, P9_RLERROR);
+        assert!(!outside.join("created").exists());
+
+        let reply = send(&mut server, P9_TLOPEN, |request| {
+            request.write_u32(2);
+            request.write_u32(L_O_RDWR | L_O_TRUNC);
+        });
+        assert_eq!(split_reply(&reply).0, P9_RLERROR);
+        let reply = send(&mut server, P9_TSETATTR, |request| {
+            request.write_u32(2);
+            request.write_u32(P9_SETATTR_MODE);
+            request.write_u32(0o666);
+            request.write_bytes(&[0; 4 + 4 + 8 + 4 * 8]);
+        });
+        assert_eq!(split_reply(&reply).0, P9_RLERROR);
+        let reply = send(&mut server, P9_TREMOVE, |request| request.write_u32(2));
+        assert_eq!(split_reply(&reply).0, P9_RLERROR);
+        assert_eq!(fs::read(outside.join("file")).unwrap(), b"host only");
+        assert_eq!(fs::metadata(outside.join("file")).unwrap().mode() & 0o777, 0o600);
+    }
+}
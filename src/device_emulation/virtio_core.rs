@@ -0,0 +1,277 @@
+//! Device status and feature negotiation shared by every virtio device.
+//!
+//! A virtio driver brings a device up by writing the status register in a fixed
+//! order: ACKNOWLEDGE, DRIVER, then its features followed by FEATURES_OK, and
+//! finally DRIVER_OK. `VirtioDeviceCore` tracks where the driver is in that sequence,
+//! refuses out of order writes and resets everything when the driver writes 0.
+
+use crate::error::VmError;
+
+/// Status bit: the guest noticed the device.
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 0x01;
+/// Status bit: the guest has a driver for the device.
+pub const VIRTIO_STATUS_DRIVER: u32 = 0x02;
+/// Status bit: the driver is set up and the device is live.
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 0x04;
+/// Status bit: the driver finished feature negotiation.
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 0x08;
+/// Status bit: the device hit an error and needs a reset.
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+/// Status bit: the driver gave up on the device.
+pub const VIRTIO_STATUS_FAILED: u32 = 0x80;
+
+/// Feature bit every virtio 1.0+ (non-legacy) device offers.
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+/// Step of the driver initialization sequence a device is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VirtioDeviceState {
+    /// Freshly created or reset; the driver hasn't touched the device yet
+    #[default]
+    Reset,
+    /// ACKNOWLEDGE is set
+    Acknowledged,
+    /// DRIVER is set; the driver is reading features and writing its own
+    Driver,
+    /// FEATURES_OK is set; the negotiated features are frozen
+    FeaturesOk,
+    /// DRIVER_OK is set; the device processes its queues
+    DriverOk,
+    /// The driver set FAILED; only a reset brings the device back
+    Failed,
+}
+
+/// Status register and feature negotiation state of a virtio device.
+///
+/// Transports forward the status and feature registers here and check
+/// `is_driver_ok` before touching the virtqueues.
+#[derive(Debug, Clone)]
+pub struct VirtioDeviceCore {
+    /// Virtio device ID (2 for block, 1 for network, ...)
+    device_type: u32,
+    /// Features offered by the device
+    device_features: u64,
+    /// Features written by the driver
+    driver_features: u64,
+    /// Which 32-bit half of the device features the driver reads
+    device_features_sel: u32,
+    /// Which 32-bit half of the driver features the driver writes
+    driver_features_sel: u32,
+    /// Raw value of the status register
+    status: u32,
+    state: VirtioDeviceState,
+}
+
+impl VirtioDeviceCore {
+    /// Creates the core of a device in the reset state.
+    ///
+    /// # Arguments
+    /// * `device_type` - Virtio device ID
+    /// * `device_features` - Features offered by the device; `VIRTIO_F_VERSION_1` is always added
+    pub fn new(device_type: u32, device_features: u64) -> Self {
+        VirtioDeviceCore {
+            device_type,
+            device_features: device_features | (1 << VIRTIO_F_VERSION_1),
+            driver_features: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            status: 0,
+            state: VirtioDeviceState::Reset,
+        }
+    }
+
+    /// Returns the virtio device ID.
+    pub fn get_device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    /// Returns the raw status register.
+    pub fn get_status(&self) -> u32 {
+        self.status
+    }
+
+    /// Returns the step of the initialization sequence the device is in.
+    pub fn get_state(&self) -> VirtioDeviceState {
+        self.state
+    }
+
+    /// Returns `true` once the driver set DRIVER_OK and the queues may be used.
+    pub fn is_driver_ok(&self) -> bool {
+        self.state == VirtioDeviceState::DriverOk
+    }
+
+    /// Returns the features offered by the device.
+    pub fn get_device_features(&self) -> u64 {
+        self.device_features
+    }
+
+    /// Returns the features accepted by the driver.
+    pub fn get_driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// Returns `true` if the driver accepted feature bit `bit`.
+    pub fn has_feature(&self, bit: u32) -> bool {
+        bit < 64 && self.driver_features & (1 << bit) != 0
+    }
+
+    /// Selects the 32-bit half of the device features returned by `read_device_features`.
+    pub fn set_device_features_sel(&mut self, sel: u32) {
+        self.device_features_sel = sel;
+    }
+
+    /// Selects the 32-bit half of the driver features written by `write_driver_features`.
+    pub fn set_driver_features_sel(&mut self, sel: u32) {
+        self.driver_features_sel = sel;
+    }
+
+    /// Returns the selected 32-bit half of the device features, 0 past bit 63.
+    pub fn read_device_features(&self) -> u32 {
+        match self.device_features_sel {
+            0 => self.device_features as u32,
+            1 => (self.device_features >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Stores the selected 32-bit half of the driver features.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the driver isn't negotiating features (DRIVER set, FEATURES_OK not set)
+    pub fn write_driver_features(&mut self, value: u32) -> Result<(), VmError> {
+        if self.state != VirtioDeviceState::Driver {
+            return Err(VmError::device(format!("driver features written in state {:?}", self.state)));
+        }
+        match self.driver_features_sel {
+            0 => self.driver_features = (self.driver_features & !0xffff_ffff) | value as u64,
+            1 => self.driver_features = (self.driver_features & 0xffff_ffff) | (value as u64) << 32,
+            sel => return Err(VmError::device(format!("invalid driver features select {}", sel))),
+        }
+        Ok(())
+    }
+
+    /// Handles a driver write to the status register.
+    ///
+    /// Writing 0 resets the device. Otherwise the write must keep every bit already
+    /// set and add the next step of the sequence. FEATURES_OK is refused (left clear,
+    /// as the specification asks) when the driver accepted features the device doesn't
+    /// offer.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the status was updated or the device reset
+    /// * `Err(VmError)` if the write skipped a step, cleared bits or negotiated unknown
+    ///   features; the status register is left unchanged
+    pub fn write_status(&mut self, value: u32) -> Result<(), VmError> {
+        if value == 0 {
+            self.reset();
+            return Ok(());
+        }
+        if self.state == VirtioDeviceState::Failed {
+            return Err(VmError::device("device failed and must be reset"));
+        }
+        if value & VIRTIO_STATUS_FAILED != 0 {
+            self.status |= value;
+            self.state = VirtioDeviceState::Failed;
+            return Ok(());
+        }
+        if value & self.status != self.status {
+            return Err(VmError::device(format!("status {:#x} clears bits of {:#x} without a reset", value, self.status)));
+        }
+
+        let added = value & !self.status;
+        let next = match (self.state, added) {
+            (_, 0) => return Ok(()),
+            (VirtioDeviceState::Reset, VIRTIO_STATUS_ACKNOWLEDGE) => VirtioDeviceState::Acknowledged,
+            (VirtioDeviceState::Acknowledged, VIRTIO_STATUS_DRIVER) => VirtioDeviceState::Driver,
+            (VirtioDeviceState::Driver, VIRTIO_STATUS_FEATURES_OK) => {
+                let unsupported = self.driver_features & !self.device_features;
+                if unsupported != 0 {
+                    return Err(VmError::device(format!("driver accepted unsupported features {:#x}", unsupported)));
+                }
+                VirtioDeviceState::FeaturesOk
+            },
+            (VirtioDeviceState::FeaturesOk, VIRTIO_STATUS_DRIVER_OK) => VirtioDeviceState::DriverOk,
+            (state, _) => return Err(VmError::device(format!("status {:#x} is not a valid step from state {:?}", value, state))),
+        };
+
+        self.status = value;
+        self.state = next;
+        Ok(())
+    }
+
+    /// Marks the device as needing a reset after an unrecoverable device error.
+    pub fn set_needs_reset(&mut self) {
+        self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+    }
+
+    /// Returns the device to its initial state; the negotiated features are forgotten.
+    pub fn reset(&mut self) {
+        self.driver_features = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.status = 0;
+        self.state = VirtioDeviceState::Reset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIRTIO_BLK_F_FLUSH: u32 = 9;
+
+    fn negotiate(core: &mut VirtioDeviceCore, features: u64) -> Result<(), VmError> {
+        core.write_status(VIRTIO_STATUS_ACKNOWLEDGE)?;
+        core.write_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER)?;
+        core.set_driver_features_sel(0);
+        core.write_driver_features(features as u32)?;
+        core.set_driver_features_sel(1);
+        core.write_driver_features((features >> 32) as u32)?;
+        core.write_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER | VIRTIO_STATUS_FEATURES_OK)
+    }
+
+    #[test]
+    fn test_full_initialization_sequence() {
+        let mut core = VirtioDeviceCore::new(2, 1 << VIRTIO_BLK_F_FLUSH);
+        assert_eq!(core.read_device_features(), 1 << VIRTIO_BLK_F_FLUSH);
+        core.set_device_features_sel(1);
+        assert_eq!(core.read_device_features(), 1);
+
+        negotiate(&mut core, (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_BLK_F_FLUSH)).expect("Negotiation should succeed");
+        assert_eq!(core.get_state(), VirtioDeviceState::FeaturesOk);
+        assert!(!core.is_driver_ok());
+
+        core.write_status(core.get_status() | VIRTIO_STATUS_DRIVER_OK).unwrap();
+        assert!(core.is_driver_ok());
+        assert!(core.has_feature(VIRTIO_BLK_F_FLUSH));
+        assert_eq!(core.get_status(), 0x0f);
+    }
+
+    #[test]
+    fn test_out_of_order_and_unsupported_features_are_refused() {
+        let mut core = VirtioDeviceCore::new(2, 0);
+        assert!(core.write_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER_OK).is_err());
+        assert!(core.write_driver_features(1).is_err());
+        assert_eq!(core.get_status(), 0);
+
+        // Bit 9 isn't offered, so FEATURES_OK stays clear
+        assert!(negotiate(&mut core, 1 << VIRTIO_BLK_F_FLUSH).is_err());
+        assert_eq!(core.get_status() & VIRTIO_STATUS_FEATURES_OK, 0);
+        assert_eq!(core.get_state(), VirtioDeviceState::Driver);
+    }
+
+    #[test]
+    fn test_reset_and_failed() {
+        let mut core = VirtioDeviceCore::new(2, 0);
+        negotiate(&mut core, 1 << VIRTIO_F_VERSION_1).unwrap();
+        core.write_status(core.get_status() | VIRTIO_STATUS_FAILED).unwrap();
+        assert_eq!(core.get_state(), VirtioDeviceState::Failed);
+        assert!(core.write_status(core.get_status() | VIRTIO_STATUS_DRIVER_OK).is_err());
+
+        core.write_status(0).unwrap();
+        assert_eq!(core.get_state(), VirtioDeviceState::Reset);
+        assert_eq!(core.get_driver_features(), 0);
+        assert_eq!(core.get_status(), 0);
+    }
+}
@@ -0,0 +1,681 @@
+//! virtio-mmio transport shared by every virtio device.
+//!
+//! `MmioTransport` owns the virtio-mmio register block (identification, feature
+//! negotiation, queue setup, status, interrupt status) and the device's interrupt
+//! line, and hands queue activation, notifications and configuration space accesses
+//! to the wrapped `VirtioDevice`. A new device type only implements `VirtioDevice`.
+//!
+//! The guest driver configures every queue itself: it selects the queue with
+//! QueueSel, reads QueueNumMax, writes its size and the guest physical addresses of
+//! the descriptor table and both rings, then writes 1 to QueueReady.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::device_emulation::mmio::{is_valid_access_width, read_le, read_register_bytes, MmioDevice};
+use crate::device_emulation::virtio_core::{VirtioDeviceCore, VirtioDeviceState};
+use crate::error::VmError;
+use crate::utils::signals::Interrupt;
+
+/// Magic value register ("virt").
+pub const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+/// Transport version register.
+pub const VIRTIO_MMIO_VERSION: u64 = 0x004;
+/// Virtio device ID register.
+pub const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+/// Vendor ID register.
+pub const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+/// Device features register, 32 bits selected by DeviceFeaturesSel.
+pub const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+/// Device features word selector.
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+/// Driver features register, 32 bits selected by DriverFeaturesSel.
+pub const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+/// Driver features word selector.
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+/// Selects the virtqueue the queue registers below refer to.
+pub const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+/// Largest size the selected queue supports; 0 if the queue doesn't exist.
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+/// Size of the selected queue chosen by the driver.
+pub const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+/// Ready flag of the selected queue; writing 1 activates it.
+pub const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+/// Queue notification register; the value written is the queue index.
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+/// Pending interrupt reasons.
+pub const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+/// Interrupt acknowledgement; clears the reasons written.
+pub const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+/// Device status register.
+pub const VIRTIO_MMIO_STATUS: u64 = 0x070;
+/// Low 32 bits of the selected queue's descriptor table address.
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+/// High 32 bits of the selected queue's descriptor table address.
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+/// Low 32 bits of the selected queue's available ring (driver area) address.
+pub const VIRTIO_MMIO_QUEUE_AVAIL_LOW: u64 = 0x090;
+/// High 32 bits of the selected queue's available ring (driver area) address.
+pub const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: u64 = 0x094;
+/// Low 32 bits of the selected queue's used ring (device area) address.
+pub const VIRTIO_MMIO_QUEUE_USED_LOW: u64 = 0x0a0;
+/// High 32 bits of the selected queue's used ring (device area) address.
+pub const VIRTIO_MMIO_QUEUE_USED_HIGH: u64 = 0x0a4;
+/// Configuration space generation counter.
+pub const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+/// Start of the device specific configuration space.
+pub const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+/// Size of the register window of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+/// Interrupt reason: a virtqueue has new used buffers.
+pub const VIRTIO_MMIO_INT_VRING: u32 = 0x1;
+/// Interrupt reason: the configuration space changed.
+pub const VIRTIO_MMIO_INT_CONFIG: u32 = 0x2;
+
+/// "virt" in little-endian.
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+/// virtio-mmio version 2 (virtio 1.0 and later).
+const MMIO_VERSION: u32 = 2;
+/// Vendor ID reported to the guest ("QEMU", which guest drivers don't care about).
+const MMIO_VENDOR_ID: u32 = 0x554d_4551;
+
+/// Setup of a virtqueue as written by the driver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtqueueState {
+    /// Largest size the device supports for the queue
+    pub max_size: u16,
+    /// Size chosen by the driver
+    pub size: u16,
+    /// Set once the driver activated the queue and the device accepted it
+    pub ready: bool,
+    /// Guest physical address of the descriptor table
+    pub desc_table: u64,
+    /// Guest physical address of the available ring
+    pub avail_ring: u64,
+    /// Guest physical address of the used ring
+    pub used_ring: u64,
+}
+
+impl VirtqueueState {
+    /// Creates the state of a queue the driver hasn't configured yet.
+    pub fn new(max_size: u16) -> Self {
+        VirtqueueState { max_size, ..Default::default() }
+    }
+
+    /// Applies the driver's setup to `queue` and marks it ready.
+    ///
+    /// # Arguments
+    /// * `queue` - Virtqueue of the device
+    /// * `mem` - Guest physical memory the addresses refer to
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the size isn't a power of two up to the maximum, or the
+    ///   descriptor table or rings don't fit in guest memory; `queue` is left not ready
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    pub fn apply(&self, queue: &mut virtio_queue::QueueSync, mem: &vm_memory::GuestMemoryMmap) -> Result<(), VmError> {
+        use virtio_queue::QueueT;
+
+        if self.size == 0 || !self.size.is_power_of_two() || self.size > self.max_size {
+            return Err(VmError::device(format!("invalid queue size {} (maximum {})", self.size, self.max_size)));
+        }
+        queue.set_size(self.size);
+        queue.set_desc_table_address(Some(self.desc_table as u32), Some((self.desc_table >> 32) as u32));
+        queue.set_avail_ring_address(Some(self.avail_ring as u32), Some((self.avail_ring >> 32) as u32));
+        queue.set_used_ring_address(Some(self.used_ring as u32), Some((self.used_ring >> 32) as u32));
+        queue.set_ready(true);
+        if !queue.is_valid(mem) {
+            queue.set_ready(false);
+            return Err(VmError::device("virtqueue doesn't fit in guest memory"));
+        }
+        Ok(())
+    }
+}
+
+/// Device side of a virtio device, independent of the transport.
+pub trait VirtioDevice: Send {
+    /// Returns the virtio device ID (2 for block, 3 for console, ...).
+    fn get_device_type(&self) -> u32;
+
+    /// Returns the device specific features offered to the driver.
+    fn get_device_features(&self) -> u64;
+
+    /// Returns the number of virtqueues of the device.
+    fn get_num_queues(&self) -> usize;
+
+    /// Returns the largest size queue `index` supports.
+    fn get_queue_max_size(&self, index: usize) -> u16;
+
+    /// Activates queue `index` with the setup the driver wrote, usually through `VirtqueueState::apply`.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the queue is ready for use
+    /// * `Err(VmError)` if the setup is invalid; the queue stays inactive
+    fn activate_queue(&mut self, index: usize, state: &VirtqueueState) -> Result<(), VmError>;
+
+    /// Deactivates queue `index` after the driver wrote 0 to QueueReady.
+    fn deactivate_queue(&mut self, index: usize);
+
+    /// Processes the buffers the driver made available in queue `index`.
+    ///
+    /// Only called once the driver set DRIVER_OK.
+    ///
+    /// # Returns
+    /// * `true` if the driver asked to be interrupted for the buffers used
+    fn process_queue(&mut self, index: usize) -> bool;
+
+    /// Reads the device specific configuration space; reads as zeroes by default.
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    /// Writes the device specific configuration space; ignored by default.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Returns the device to its initial state after the driver reset it.
+    fn reset(&mut self);
+}
+
+/// Raises the used-buffer interrupt of a virtio transport from another thread, e.g. an
+/// I/O worker completing requests after the notification that queued them was handled.
+#[derive(Clone)]
+pub struct VringNotifier {
+    interrupt: Arc<dyn Interrupt>,
+    interrupt_status: Arc<AtomicU32>,
+}
+
+impl VringNotifier {
+    /// Creates a notifier setting `VIRTIO_MMIO_INT_VRING` in `interrupt_status`, which is
+    /// also the used-buffer bit of the virtio-pci ISR status.
+    pub(crate) fn new(interrupt: Arc<dyn Interrupt>, interrupt_status: Arc<AtomicU32>) -> Self {
+        VringNotifier { interrupt, interrupt_status }
+    }
+
+    /// Tells the driver the device used buffers.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be delivered
+    pub fn notify(&self) -> Result<(), VmError> {
+        self.interrupt_status.fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+        self.interrupt.trigger()
+    }
+}
+
+/// A virtio device exposed to the guest through the virtio-mmio register layout.
+pub struct MmioTransport<D: VirtioDevice> {
+    device: D,
+    /// Device status and feature negotiation
+    core: VirtioDeviceCore,
+    /// Interrupt line of the device
+    interrupt: Arc<dyn Interrupt>,
+    /// Pending interrupt reasons reported through the InterruptStatus register, shared
+    /// with the `VringNotifier`s handed out
+    interrupt_status: Arc<AtomicU32>,
+    /// Queue setup written by the driver, one entry per device queue
+    queues: Vec<VirtqueueState>,
+    /// Queue the queue registers refer to
+    queue_sel: u32,
+    /// Bumped on every configuration space change so the driver can detect torn reads
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> MmioTransport<D> {
+    /// Wraps `device` in a virtio-mmio register block.
+    ///
+    /// # Arguments
+    /// * `device` - Virtio device served by the transport
+    /// * `interrupt` - Interrupt line raised when the device used buffers
+    pub fn new(device: D, interrupt: Box<dyn Interrupt>) -> Self {
+        let core = VirtioDeviceCore::new(device.get_device_type(), device.get_device_features());
+        let queues = (0..device.get_num_queues()).map(|index| VirtqueueState::new(device.get_queue_max_size(index))).collect();
+        MmioTransport {
+            device,
+            core,
+            interrupt: Arc::from(interrupt),
+            interrupt_status: Arc::new(AtomicU32::new(0)),
+            queues,
+            queue_sel: 0,
+            config_generation: 0,
+        }
+    }
+
+    /// Returns the wrapped device.
+    pub fn get_device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the wrapped device mutably.
+    pub fn get_device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the status and feature negotiation state.
+    pub fn get_core(&self) -> &VirtioDeviceCore {
+        &self.core
+    }
+
+    /// Returns the interrupt line of the device.
+    pub fn get_interrupt(&self) -> &dyn Interrupt {
+        self.interrupt.as_ref()
+    }
+
+    /// Returns the setup of queue `index` as written by the driver.
+    pub fn get_queue_state(&self, index: usize) -> Option<&VirtqueueState> {
+        self.queues.get(index)
+    }
+
+    /// Returns the pending interrupt reasons.
+    pub fn get_interrupt_status(&self) -> u32 {
+        self.interrupt_status.load(Ordering::SeqCst)
+    }
+
+    /// Returns a handle raising the used-buffer interrupt from another thread, for
+    /// devices completing requests after `process_queue` returned.
+    pub fn get_vring_notifier(&self) -> VringNotifier {
+        VringNotifier::new(Arc::clone(&self.interrupt), Arc::clone(&self.interrupt_status))
+    }
+
+    /// Raises the device interrupt for `reason` (`VIRTIO_MMIO_INT_VRING` or `VIRTIO_MMIO_INT_CONFIG`).
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be delivered
+    pub fn signal(&mut self, reason: u32) -> Result<(), VmError> {
+        self.interrupt_status.fetch_or(reason, Ordering::SeqCst);
+        self.interrupt.trigger()
+    }
+
+    /// Tells the driver the device changed its configuration space, e.g. a disk was resized.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be delivered
+    pub fn signal_config_change(&mut self) -> Result<(), VmError> {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.signal(VIRTIO_MMIO_INT_CONFIG)
+    }
+
+    /// Re-asserts a level-triggered interrupt the hypervisor deasserted on end-of-interrupt.
+    ///
+    /// To be called when the interrupt's resample event fires. The line is raised again
+    /// if the guest hasn't acknowledged every interrupt reason yet.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be re-asserted
+    #[cfg(target_os = "linux")]
+    pub fn handle_resample(&self) -> Result<(), VmError> {
+        if let Some(resample) = self.interrupt.get_resample_event() {
+            // Drain the event; it is non-blocking so a spurious call reads nothing
+            let _ = resample.read();
+        }
+        if self.get_interrupt_status() != 0 {
+            return self.interrupt.assert();
+        }
+        Ok(())
+    }
+
+    /// Resets the transport and the device, as when the driver writes 0 to Status.
+    pub fn reset(&mut self) {
+        self.core.reset();
+        self.device.reset();
+        self.interrupt_status.store(0, Ordering::SeqCst);
+        for queue in &mut self.queues {
+            *queue = VirtqueueState::new(queue.max_size);
+        }
+        self.queue_sel = 0;
+        let _ = self.interrupt.deassert();
+    }
+
+    /// Returns the selected queue if the driver may change its setup.
+    ///
+    /// Queues are set up between FEATURES_OK and DRIVER_OK, or later for queues the
+    /// driver only adds once running, and never while they are active.
+    fn get_configurable_queue(&mut self) -> Option<&mut VirtqueueState> {
+        if !matches!(self.core.get_state(), VirtioDeviceState::FeaturesOk | VirtioDeviceState::DriverOk) {
+            return None;
+        }
+        self.queues.get_mut(self.queue_sel as usize).filter(|queue| !queue.ready)
+    }
+
+    /// Handles a write to QueueReady for the selected queue.
+    fn write_queue_ready(&mut self, ready: bool) {
+        let index = self.queue_sel as usize;
+        let Some(queue) = self.queues.get_mut(index) else { return };
+        if ready && !queue.ready {
+            if !matches!(self.core.get_state(), VirtioDeviceState::FeaturesOk | VirtioDeviceState::DriverOk) {
+                return;
+            }
+            // A refused setup leaves QueueReady at 0; the driver notices when reading it back
+            queue.ready = self.device.activate_queue(index, queue).is_ok();
+        } else if !ready && queue.ready {
+            self.device.deactivate_queue(index);
+            queue.ready = false;
+        }
+    }
+
+    /// Returns the value of the 32-bit register at the given aligned offset.
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => MMIO_MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => MMIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.core.get_device_type(),
+            VIRTIO_MMIO_VENDOR_ID => MMIO_VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => self.core.read_device_features(),
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel,
+            VIRTIO_MMIO_QUEUE_NUM_MAX => self.queues.get(self.queue_sel as usize).map_or(0, |queue| queue.max_size as u32),
+            VIRTIO_MMIO_QUEUE_NUM => self.queues.get(self.queue_sel as usize).map_or(0, |queue| queue.size as u32),
+            VIRTIO_MMIO_QUEUE_READY => self.queues.get(self.queue_sel as usize).is_some_and(|queue| queue.ready) as u32,
+            VIRTIO_MMIO_QUEUE_DESC_LOW => self.read_queue_address(|queue| queue.desc_table) as u32,
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => (self.read_queue_address(|queue| queue.desc_table) >> 32) as u32,
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.read_queue_address(|queue| queue.avail_ring) as u32,
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => (self.read_queue_address(|queue| queue.avail_ring) >> 32) as u32,
+            VIRTIO_MMIO_QUEUE_USED_LOW => self.read_queue_address(|queue| queue.used_ring) as u32,
+            VIRTIO_MMIO_QUEUE_USED_HIGH => (self.read_queue_address(|queue| queue.used_ring) >> 32) as u32,
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.get_interrupt_status(),
+            VIRTIO_MMIO_STATUS => self.core.get_status(),
+            VIRTIO_MMIO_CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    /// Returns an address of the selected queue, 0 if no such queue exists.
+    fn read_queue_address(&self, address: impl Fn(&VirtqueueState) -> u64) -> u64 {
+        self.queues.get(self.queue_sel as usize).map_or(0, address)
+    }
+
+    /// Replaces the low or high 32 bits of an address of the selected queue.
+    fn write_queue_address(&mut self, value: u32, high: bool, address: impl Fn(&mut VirtqueueState) -> &mut u64) {
+        if let Some(queue) = self.get_configurable_queue() {
+            let address = address(queue);
+            *address = if high {
+                (*address & 0xffff_ffff) | (value as u64) << 32
+            } else {
+                (*address & !0xffff_ffff) | value as u64
+            };
+        }
+    }
+
+    /// Handles a write to a register of the common register block.
+    fn write_register(&mut self, offset: u64, value: u64) {
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.core.set_device_features_sel(value as u32),
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.core.set_driver_features_sel(value as u32),
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                // Features written outside of negotiation are dropped
+                let _ = self.core.write_driver_features(value as u32);
+            },
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value as u32,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                // The size is checked against QueueNumMax when the queue is activated
+                if let Some(queue) = self.get_configurable_queue() {
+                    queue.size = value as u16;
+                }
+            },
+            VIRTIO_MMIO_QUEUE_READY => self.write_queue_ready(value & 1 != 0),
+            VIRTIO_MMIO_QUEUE_DESC_LOW => self.write_queue_address(value as u32, false, |queue| &mut queue.desc_table),
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => self.write_queue_address(value as u32, true, |queue| &mut queue.desc_table),
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.write_queue_address(value as u32, false, |queue| &mut queue.avail_ring),
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.write_queue_address(value as u32, true, |queue| &mut queue.avail_ring),
+            VIRTIO_MMIO_QUEUE_USED_LOW => self.write_queue_address(value as u32, false, |queue| &mut queue.used_ring),
+            VIRTIO_MMIO_QUEUE_USED_HIGH => self.write_queue_address(value as u32, true, |queue| &mut queue.used_ring),
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                // Guest notified the device that there are new buffers in the given virtqueue
+                let index = value as usize;
+                let ready = self.queues.get(index).is_some_and(|queue| queue.ready);
+                if self.core.is_driver_ok() && ready && self.device.process_queue(index) {
+                    let _ = self.signal(VIRTIO_MMIO_INT_VRING);
+                }
+            },
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                // Guest handled the interrupt reasons it writes back; a level-triggered line
+                // drops once nothing is pending anymore
+                let pending = self.interrupt_status.fetch_and(!(value as u32), Ordering::SeqCst) & !(value as u32);
+                if pending == 0 {
+                    let _ = self.interrupt.deassert();
+                    // A notifier may have raised a reason in between
+                    if self.get_interrupt_status() != 0 {
+                        let _ = self.interrupt.assert();
+                    }
+                }
+            },
+            VIRTIO_MMIO_STATUS if value == 0 => self.reset(),
+            VIRTIO_MMIO_STATUS => {
+                // A refused step leaves the status unchanged; the driver notices when reading it back
+                let _ = self.core.write_status(value as u32);
+            },
+            _ => {},
+        }
+    }
+}
+
+impl<D: VirtioDevice> MmioDevice for MmioTransport<D> {
+    /// Handles a guest MMIO read at the given offset.
+    ///
+    /// Register accesses may be 1, 2, 4 or 8 bytes wide; narrow reads return the
+    /// addressed bytes of the containing 32-bit register. The configuration space is
+    /// read by the device.
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.device.read_config(offset - VIRTIO_MMIO_CONFIG, data);
+            return;
+        }
+        read_register_bytes(offset, data, |register| self.read_register(register));
+    }
+
+    /// Handles a guest MMIO write at the given offset.
+    ///
+    /// Writes of an invalid width are ignored, as are writes to read-only or unknown
+    /// registers and status or feature writes out of sequence. InterruptACK accepts any
+    /// width, typically a single byte.
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        if !is_valid_access_width(data.len()) {
+            return;
+        }
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.device.write_config(offset - VIRTIO_MMIO_CONFIG, data);
+            return;
+        }
+        self.write_register(offset, read_le(data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use crate::utils::signals::TriggerMode;
+
+    /// Interrupt counting how often it was triggered.
+    struct CountingInterrupt(Arc<AtomicU32>);
+
+    impl Interrupt for CountingInterrupt {
+        fn get_trigger_mode(&self) -> TriggerMode {
+            TriggerMode::Edge
+        }
+
+        fn assert(&self) -> Result<(), VmError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn deassert(&self) -> Result<(), VmError> {
+            Ok(())
+        }
+    }
+
+    /// Device with one queue that always asks for an interrupt.
+    #[derive(Default)]
+    struct TestDevice {
+        notified: Vec<usize>,
+        activated: Vec<VirtqueueState>,
+        resets: u32,
+    }
+
+    impl VirtioDevice for TestDevice {
+        fn get_device_type(&self) -> u32 {
+            4
+        }
+
+        fn get_device_features(&self) -> u64 {
+            0
+        }
+
+        fn get_num_queues(&self) -> usize {
+            1
+        }
+
+        fn get_queue_max_size(&self, _index: usize) -> u16 {
+            256
+        }
+
+        fn activate_queue(&mut self, _index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+            if !state.size.is_power_of_two() {
+                return Err(VmError::device("invalid queue size"));
+            }
+            self.activated.push(*state);
+            Ok(())
+        }
+
+        fn deactivate_queue(&mut self, _index: usize) {
+            self.activated.clear();
+        }
+
+        fn process_queue(&mut self, index: usize) -> bool {
+            self.notified.push(index);
+            true
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    fn read_u32(transport: &mut MmioTransport<TestDevice>, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        transport.read_mmio(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_u32(transport: &mut MmioTransport<TestDevice>, offset: u64, value: u32) {
+        transport.write_mmio(offset, &value.to_le_bytes());
+    }
+
+    /// Walks the status register up to FEATURES_OK.
+    fn negotiate_features(transport: &mut MmioTransport<TestDevice>) {
+        for status in [0x1, 0x3, 0xb] {
+            write_u32(transport, VIRTIO_MMIO_STATUS, status);
+        }
+    }
+
+    /// Sets queue 0 up the way a guest driver does.
+    fn setup_queue(transport: &mut MmioTransport<TestDevice>, size: u32) {
+        write_u32(transport, VIRTIO_MMIO_QUEUE_SEL, 0);
+        write_u32(transport, VIRTIO_MMIO_QUEUE_NUM, size);
+        write_u32(transport, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x1000);
+        write_u32(transport, VIRTIO_MMIO_QUEUE_DESC_HIGH, 0x1);
+        write_u32(transport, VIRTIO_MMIO_QUEUE_AVAIL_LOW, 0x2000);
+        write_u32(transport, VIRTIO_MMIO_QUEUE_USED_LOW, 0x3000);
+        write_u32(transport, VIRTIO_MMIO_QUEUE_READY, 1);
+    }
+
+    #[test]
+    fn test_identification_and_config_space() {
+        let mut transport = MmioTransport::new(TestDevice::default(), Box::new(CountingInterrupt(Arc::default())));
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_MAGIC_VALUE), MMIO_MAGIC_VALUE);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_VERSION), 2);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_DEVICE_ID), 4);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_CONFIG + 8), 0x0808_0808);
+    }
+
+    #[test]
+    fn test_config_change() {
+        let triggered = Arc::new(AtomicU32::new(0));
+        let mut transport = MmioTransport::new(TestDevice::default(), Box::new(CountingInterrupt(triggered.clone())));
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_CONFIG_GENERATION), 0);
+
+        transport.signal_config_change().unwrap();
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_CONFIG_GENERATION), 1);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_INTERRUPT_STATUS), VIRTIO_MMIO_INT_CONFIG);
+        assert_eq!(triggered.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_vring_notifier_from_another_thread() {
+        let triggered = Arc::new(AtomicU32::new(0));
+        let mut transport = MmioTransport::new(TestDevice::default(), Box::new(CountingInterrupt(triggered.clone())));
+        let notifier = transport.get_vring_notifier();
+        std::thread::spawn(move || notifier.notify().unwrap()).join().unwrap();
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_INTERRUPT_STATUS), VIRTIO_MMIO_INT_VRING);
+        assert_eq!(triggered.load(Ordering::SeqCst), 1);
+
+        write_u32(&mut transport, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING);
+        assert_eq!(transport.get_interrupt_status(), 0);
+    }
+
+    #[test]
+    fn test_notify_requires_driver_ok_and_raises_interrupt() {
+        let triggered = Arc::new(AtomicU32::new(0));
+        let mut transport = MmioTransport::new(TestDevice::default(), Box::new(CountingInterrupt(triggered.clone())));
+
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+        assert!(transport.get_device().notified.is_empty());
+
+        negotiate_features(&mut transport);
+        setup_queue(&mut transport, 128);
+        write_u32(&mut transport, VIRTIO_MMIO_STATUS, 0xf);
+        assert_eq!(transport.get_core().get_state(), VirtioDeviceState::DriverOk);
+
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_NOTIFY, 7); // No such queue
+        assert_eq!(transport.get_device().notified, vec![0]);
+        assert_eq!(triggered.load(Ordering::SeqCst), 1);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_INTERRUPT_STATUS), VIRTIO_MMIO_INT_VRING);
+
+        transport.write_mmio(VIRTIO_MMIO_INTERRUPT_ACK, &[VIRTIO_MMIO_INT_VRING as u8]);
+        assert_eq!(transport.get_interrupt_status(), 0);
+
+        write_u32(&mut transport, VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(transport.get_device().resets, 1);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_STATUS), 0);
+        assert_eq!(transport.get_queue_state(0), Some(&VirtqueueState::new(256)));
+    }
+
+    #[test]
+    fn test_queue_setup() {
+        let mut transport = MmioTransport::new(TestDevice::default(), Box::new(CountingInterrupt(Arc::default())));
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM_MAX), 256);
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_SEL, 1);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM_MAX), 0); // No such queue
+
+        // Queue registers are ignored before feature negotiation completed
+        setup_queue(&mut transport, 128);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_READY), 0);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM), 0);
+
+        // A size the device refuses leaves the queue inactive
+        negotiate_features(&mut transport);
+        setup_queue(&mut transport, 100);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_READY), 0);
+
+        setup_queue(&mut transport, 128);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_READY), 1);
+        let expected = VirtqueueState { max_size: 256, size: 128, ready: false, desc_table: 0x1_0000_1000, avail_ring: 0x2000, used_ring: 0x3000 };
+        assert_eq!(transport.get_device().activated, vec![expected]);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_DESC_HIGH), 0x1);
+
+        // An active queue can't be changed until the driver deactivates it
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM, 64);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM), 128);
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_READY, 0);
+        assert!(transport.get_device().activated.is_empty());
+        write_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM, 64);
+        assert_eq!(read_u32(&mut transport, VIRTIO_MMIO_QUEUE_NUM), 64);
+    }
+}
@@ -0,0 +1,587 @@
+//! virtio-pci transport, in the virtio 1.0 layout.
+//!
+//! `VirtioPciTransport` exposes a `VirtioDevice` as a function of the `PciBus`. Its
+//! single memory BAR holds the common configuration, the ISR status, the device
+//! configuration and the notification registers, each announced to the driver by a
+//! vendor specific capability. The device raises legacy INTx on the line the VMM
+//! routed it to; no MSI-X capability is offered, so the driver shares that interrupt
+//! between the queues and configuration changes.
+//!
+//! Queue setup mirrors `MmioTransport`: the driver selects a queue, writes its size and
+//! the addresses of its rings, then enables it.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::device_emulation::mmio::{is_valid_access_width, read_le, read_register_bytes, write_le};
+use crate::device_emulation::pci::{PciConfiguration, PciDevice, PCI_CAP_ID_VENDOR, PCI_INTERRUPT_PIN_A};
+use crate::device_emulation::virtio_core::{VirtioDeviceCore, VirtioDeviceState};
+use crate::device_emulation::virtio_mmio::{VirtioDevice, VirtqueueState, VringNotifier};
+use crate::error::VmError;
+use crate::utils::signals::Interrupt;
+
+/// PCI vendor ID of virtio devices.
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+/// Device ID of a virtio 1.0 device of type 0; the device type is added to it.
+pub const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+/// Size of the memory BAR of a virtio-pci device.
+pub const VIRTIO_PCI_BAR_SIZE: u64 = 0x4000;
+
+// Regions of the BAR, each announced by a capability
+pub const VIRTIO_PCI_COMMON_CFG_OFFSET: u64 = 0x0000;
+pub const VIRTIO_PCI_ISR_OFFSET: u64 = 0x1000;
+pub const VIRTIO_PCI_DEVICE_CFG_OFFSET: u64 = 0x2000;
+pub const VIRTIO_PCI_NOTIFY_OFFSET: u64 = 0x3000;
+/// Distance between the notification registers of consecutive queues.
+pub const VIRTIO_PCI_NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+// Registers of the common configuration, see struct virtio_pci_common_cfg
+pub const VIRTIO_PCI_COMMON_DFSELECT: u64 = 0x00;
+pub const VIRTIO_PCI_COMMON_DF: u64 = 0x04;
+pub const VIRTIO_PCI_COMMON_GFSELECT: u64 = 0x08;
+pub const VIRTIO_PCI_COMMON_GF: u64 = 0x0c;
+pub const VIRTIO_PCI_COMMON_MSIX: u64 = 0x10;
+pub const VIRTIO_PCI_COMMON_NUMQ: u64 = 0x12;
+pub const VIRTIO_PCI_COMMON_STATUS: u64 = 0x14;
+pub const VIRTIO_PCI_COMMON_CFGGENERATION: u64 = 0x15;
+pub const VIRTIO_PCI_COMMON_Q_SELECT: u64 = 0x16;
+pub const VIRTIO_PCI_COMMON_Q_SIZE: u64 = 0x18;
+pub const VIRTIO_PCI_COMMON_Q_MSIX: u64 = 0x1a;
+pub const VIRTIO_PCI_COMMON_Q_ENABLE: u64 = 0x1c;
+pub const VIRTIO_PCI_COMMON_Q_NOFF: u64 = 0x1e;
+pub const VIRTIO_PCI_COMMON_Q_DESCLO: u64 = 0x20;
+pub const VIRTIO_PCI_COMMON_Q_DESCHI: u64 = 0x24;
+pub const VIRTIO_PCI_COMMON_Q_AVAILLO: u64 = 0x28;
+pub const VIRTIO_PCI_COMMON_Q_AVAILHI: u64 = 0x2c;
+pub const VIRTIO_PCI_COMMON_Q_USEDLO: u64 = 0x30;
+pub const VIRTIO_PCI_COMMON_Q_USEDHI: u64 = 0x34;
+/// Size of the common configuration.
+const VIRTIO_PCI_COMMON_CFG_SIZE: u64 = 0x38;
+
+/// ISR bit: a virtqueue has new used buffers.
+pub const VIRTIO_PCI_ISR_QUEUE: u32 = 0x1;
+/// ISR bit: the configuration space changed.
+pub const VIRTIO_PCI_ISR_CONFIG: u32 = 0x2;
+
+/// MSI-X vector telling the driver no vector is assigned.
+const VIRTIO_MSI_NO_VECTOR: u32 = 0xffff;
+/// Revision ID of a virtio 1.0 device without legacy interface.
+const VIRTIO_PCI_REVISION: u8 = 1;
+/// Subsystem ID of the virtio devices, as QEMU reports it.
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x1100;
+
+// Types of the virtio capabilities
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// Returns the PCI class code of a virtio device type; the driver ignores it, but the
+/// guest lists the device under it.
+fn get_class_code(device_type: u32) -> u32 {
+    match device_type {
+        // Ethernet controller
+        1 => 0x02_0000,
+        // SCSI storage controller, as QEMU reports block devices
+        2 => 0x01_0000,
+        // Unassigned class
+        _ => 0xff_0000
+    }
+}
+
+/// Returns the body of a virtio capability pointing at `length` bytes at `offset` in BAR 0.
+fn virtio_capability(cfg_type: u8, offset: u64, length: u64, extra: &[u8]) -> Vec<u8> {
+    let mut body = vec![(2 + 14 + extra.len()) as u8, cfg_type, 0, 0, 0, 0];
+    body.extend_from_slice(&(offset as u32).to_le_bytes());
+    body.extend_from_slice(&(length as u32).to_le_bytes());
+    body.extend_from_slice(extra);
+    body
+}
+
+/// A virtio device exposed to the guest as a virtio-pci function.
+pub struct VirtioPciTransport<D: VirtioDevice> {
+    device: D,
+    /// PCI configuration space with the BAR and the virtio capabilities
+    config: PciConfiguration,
+    /// Device status and feature negotiation
+    core: VirtioDeviceCore,
+    /// Interrupt line of the device
+    interrupt: Arc<dyn Interrupt>,
+    /// Pending interrupt reasons reported through the ISR status, shared with the
+    /// `VringNotifier`s handed out
+    isr_status: Arc<AtomicU32>,
+    /// Queue setup written by the driver, one entry per device queue
+    queues: Vec<VirtqueueState>,
+    /// Queue the queue registers refer to
+    queue_select: u16,
+    /// Bumped on every configuration space change so the driver can detect torn reads
+    config_generation: u8,
+}
+
+impl<D: VirtioDevice> VirtioPciTransport<D> {
+    /// Wraps `device` in a virtio-pci function.
+    ///
+    /// # Arguments
+    /// * `device` - Virtio device served by the transport
+    /// * `interrupt` - Interrupt line raised when the device used buffers
+    /// * `irq_line` - Interrupt line reported to the guest, the GSI `interrupt` raises
+    /// * `bar_address` - Guest physical address of the `VIRTIO_PCI_BAR_SIZE` bytes of the BAR
+    ///
+    /// # Returns
+    /// * `Ok(VirtioPciTransport)` on success
+    /// * `Err(VmError)` if the BAR address isn't aligned on the BAR size below 4 GiB
+    pub fn new(device: D, interrupt: Box<dyn Interrupt>, irq_line: u8, bar_address: u64) -> Result<Self, VmError> {
+        let device_type = device.get_device_type();
+        let mut config = PciConfiguration::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+            get_class_code(device_type),
+            VIRTIO_PCI_REVISION,
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_SUBSYSTEM_ID,
+        );
+        config.add_memory_bar(0, bar_address, VIRTIO_PCI_BAR_SIZE)?;
+        config.add_capability(PCI_CAP_ID_VENDOR, &virtio_capability(VIRTIO_PCI_CAP_COMMON_CFG, VIRTIO_PCI_COMMON_CFG_OFFSET, VIRTIO_PCI_COMMON_CFG_SIZE, &[]))?;
+        let multiplier = VIRTIO_PCI_NOTIFY_OFF_MULTIPLIER.to_le_bytes();
+        let notify_size = (device.get_num_queues() as u64 * VIRTIO_PCI_NOTIFY_OFF_MULTIPLIER as u64).max(VIRTIO_PCI_NOTIFY_OFF_MULTIPLIER as u64);
+        config.add_capability(PCI_CAP_ID_VENDOR, &virtio_capability(VIRTIO_PCI_CAP_NOTIFY_CFG, VIRTIO_PCI_NOTIFY_OFFSET, notify_size, &multiplier))?;
+        config.add_capability(PCI_CAP_ID_VENDOR, &virtio_capability(VIRTIO_PCI_CAP_ISR_CFG, VIRTIO_PCI_ISR_OFFSET, 1, &[]))?;
+        config.add_capability(PCI_CAP_ID_VENDOR, &virtio_capability(VIRTIO_PCI_CAP_DEVICE_CFG, VIRTIO_PCI_DEVICE_CFG_OFFSET, 0x1000, &[]))?;
+        config.set_interrupt(PCI_INTERRUPT_PIN_A, irq_line);
+
+        let core = VirtioDeviceCore::new(device_type, device.get_device_features());
+        let queues = (0..device.get_num_queues()).map(|index| VirtqueueState::new(device.get_queue_max_size(index))).collect();
+        Ok(VirtioPciTransport {
+            device,
+            config,
+            core,
+            interrupt: Arc::from(interrupt),
+            isr_status: Arc::new(AtomicU32::new(0)),
+            queues,
+            queue_select: 0,
+            config_generation: 0,
+        })
+    }
+
+    /// Returns the wrapped device.
+    pub fn get_device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the wrapped device mutably.
+    pub fn get_device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the status and feature negotiation state.
+    pub fn get_core(&self) -> &VirtioDeviceCore {
+        &self.core
+    }
+
+    /// Returns the setup of queue `index` as written by the driver.
+    pub fn get_queue_state(&self, index: usize) -> Option<&VirtqueueState> {
+        self.queues.get(index)
+    }
+
+    /// Returns the pending interrupt reasons without clearing them.
+    pub fn get_isr_status(&self) -> u32 {
+        self.isr_status.load(Ordering::SeqCst)
+    }
+
+    /// Returns a handle raising the used-buffer interrupt from another thread, for
+    /// devices completing requests after `process_queue` returned.
+    pub fn get_vring_notifier(&self) -> VringNotifier {
+        VringNotifier::new(Arc::clone(&self.interrupt), Arc::clone(&self.isr_status))
+    }
+
+    /// Raises the device interrupt for `reason` (`VIRTIO_PCI_ISR_QUEUE` or `VIRTIO_PCI_ISR_CONFIG`).
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be delivered
+    pub fn signal(&mut self, reason: u32) -> Result<(), VmError> {
+        self.isr_status.fetch_or(reason, Ordering::SeqCst);
+        self.interrupt.trigger()
+    }
+
+    /// Tells the driver the device changed its configuration space, e.g. a disk was resized.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be delivered
+    pub fn signal_config_change(&mut self) -> Result<(), VmError> {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.signal(VIRTIO_PCI_ISR_CONFIG)
+    }
+
+    /// Re-asserts a level-triggered interrupt the hypervisor deasserted on end-of-interrupt.
+    ///
+    /// To be called when the interrupt's resample event fires. The line is raised again
+    /// if the guest hasn't read the ISR status since the interrupt was raised.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the interrupt couldn't be re-asserted
+    #[cfg(target_os = "linux")]
+    pub fn handle_resample(&self) -> Result<(), VmError> {
+        if let Some(resample) = self.interrupt.get_resample_event() {
+            // Drain the event; it is non-blocking so a spurious call reads nothing
+            let _ = resample.read();
+        }
+        if self.get_isr_status() != 0 {
+            return self.interrupt.assert();
+        }
+        Ok(())
+    }
+
+    /// Resets the transport and the device, as when the driver writes 0 to the device status.
+    pub fn reset(&mut self) {
+        self.core.reset();
+        self.device.reset();
+        self.isr_status.store(0, Ordering::SeqCst);
+        for queue in &mut self.queues {
+            *queue = VirtqueueState::new(queue.max_size);
+        }
+        self.queue_select = 0;
+        let _ = self.interrupt.deassert();
+    }
+
+    /// Returns the selected queue if the driver may change its setup.
+    fn get_configurable_queue(&mut self) -> Option<&mut VirtqueueState> {
+        if !matches!(self.core.get_state(), VirtioDeviceState::FeaturesOk | VirtioDeviceState::DriverOk) {
+            return None;
+        }
+        self.queues.get_mut(self.queue_select as usize).filter(|queue| !queue.ready)
+    }
+
+    /// Handles a write of 1 to queue_enable for the selected queue. A queue the driver
+    /// didn't size gets the largest size the device supports.
+    fn enable_queue(&mut self) {
+        let index = self.queue_select as usize;
+        if self.get_configurable_queue().is_none() {
+            return;
+        }
+        let queue = &mut self.queues[index];
+        if queue.size == 0 {
+            queue.size = queue.max_size;
+        }
+        // A refused setup leaves queue_enable at 0; the driver notices when reading it back
+        queue.ready = self.device.activate_queue(index, queue).is_ok();
+    }
+
+    /// Returns the value of the 32-bit register of the common configuration at the given aligned offset.
+    fn read_common_register(&self, offset: u64) -> u32 {
+        let queue = self.queues.get(self.queue_select as usize);
+        match offset {
+            VIRTIO_PCI_COMMON_DF => self.core.read_device_features(),
+            VIRTIO_PCI_COMMON_MSIX => VIRTIO_MSI_NO_VECTOR | (self.queues.len() as u32) << 16,
+            VIRTIO_PCI_COMMON_STATUS => {
+                (self.core.get_status() & 0xff) | (self.config_generation as u32) << 8 | (self.queue_select as u32) << 16
+            },
+            VIRTIO_PCI_COMMON_Q_SIZE => {
+                // The size reads as the maximum until the driver picks a smaller one
+                let size = queue.map_or(0, |queue| if queue.size == 0 { queue.max_size } else { queue.size });
+                size as u32 | VIRTIO_MSI_NO_VECTOR << 16
+            },
+            VIRTIO_PCI_COMMON_Q_ENABLE => match queue {
+                Some(queue) => queue.ready as u32 | (self.queue_select as u32) << 16,
+                None => 0
+            },
+            VIRTIO_PCI_COMMON_Q_DESCLO => queue.map_or(0, |queue| queue.desc_table as u32),
+            VIRTIO_PCI_COMMON_Q_DESCHI => queue.map_or(0, |queue| (queue.desc_table >> 32) as u32),
+            VIRTIO_PCI_COMMON_Q_AVAILLO => queue.map_or(0, |queue| queue.avail_ring as u32),
+            VIRTIO_PCI_COMMON_Q_AVAILHI => queue.map_or(0, |queue| (queue.avail_ring >> 32) as u32),
+            VIRTIO_PCI_COMMON_Q_USEDLO => queue.map_or(0, |queue| queue.used_ring as u32),
+            VIRTIO_PCI_COMMON_Q_USEDHI => queue.map_or(0, |queue| (queue.used_ring >> 32) as u32),
+            _ => 0,
+        }
+    }
+
+    /// Replaces an address of the selected queue: all of it on an 8-byte write, else
+    /// the low or high 32 bits.
+    fn write_queue_address(&mut self, value: u64, len: usize, high: bool, address: impl Fn(&mut VirtqueueState) -> &mut u64) {
+        if let Some(queue) = self.get_configurable_queue() {
+            let address = address(queue);
+            *address = match (len, high) {
+                (8, false) => value,
+                (_, false) => (*address & !0xffff_ffff) | (value & 0xffff_ffff),
+                (_, true) => (*address & 0xffff_ffff) | (value & 0xffff_ffff) << 32,
+            };
+        }
+    }
+
+    /// Handles a write to a register of the common configuration.
+    fn write_common(&mut self, offset: u64, data: &[u8]) {
+        let value = read_le(data);
+        match offset {
+            VIRTIO_PCI_COMMON_DFSELECT => self.core.set_device_features_sel(value as u32),
+            VIRTIO_PCI_COMMON_GFSELECT => self.core.set_driver_features_sel(value as u32),
+            VIRTIO_PCI_COMMON_GF => {
+                // Features written outside of negotiation are dropped
+                let _ = self.core.write_driver_features(value as u32);
+            },
+            VIRTIO_PCI_COMMON_STATUS if value as u8 == 0 => self.reset(),
+            VIRTIO_PCI_COMMON_STATUS => {
+                // A refused step leaves the status unchanged; the driver notices when reading it back
+                let _ = self.core.write_status(value as u8 as u32);
+            },
+            VIRTIO_PCI_COMMON_Q_SELECT => self.queue_select = value as u16,
+            VIRTIO_PCI_COMMON_Q_SIZE => {
+                // The size is checked against the maximum when the queue is enabled
+                if let Some(queue) = self.get_configurable_queue() {
+                    queue.size = value as u16;
+                }
+            },
+            // Queues can only be disabled by resetting the device
+            VIRTIO_PCI_COMMON_Q_ENABLE if value & 1 != 0 => self.enable_queue(),
+            VIRTIO_PCI_COMMON_Q_DESCLO => self.write_queue_address(value, data.len(), false, |queue| &mut queue.desc_table),
+            VIRTIO_PCI_COMMON_Q_DESCHI => self.write_queue_address(value, data.len(), true, |queue| &mut queue.desc_table),
+            VIRTIO_PCI_COMMON_Q_AVAILLO => self.write_queue_address(value, data.len(), false, |queue| &mut queue.avail_ring),
+            VIRTIO_PCI_COMMON_Q_AVAILHI => self.write_queue_address(value, data.len(), true, |queue| &mut queue.avail_ring),
+            VIRTIO_PCI_COMMON_Q_USEDLO => self.write_queue_address(value, data.len(), false, |queue| &mut queue.used_ring),
+            VIRTIO_PCI_COMMON_Q_USEDHI => self.write_queue_address(value, data.len(), true, |queue| &mut queue.used_ring),
+            // MSI-X vectors stay unassigned as the device has no MSI-X capability
+            _ => {},
+        }
+    }
+
+    /// Handles a read of the ISR status, which returns the pending reasons and clears them.
+    fn read_isr(&mut self, data: &mut [u8]) {
+        let status = self.isr_status.swap(0, Ordering::SeqCst);
+        write_le(data, status as u64);
+        let _ = self.interrupt.deassert();
+        // A notifier may have raised a reason in between
+        if self.get_isr_status() != 0 {
+            let _ = self.interrupt.assert();
+        }
+    }
+
+    /// Handles a queue notification for queue `index`.
+    fn notify_queue(&mut self, index: usize) {
+        let ready = self.queues.get(index).is_some_and(|queue| queue.ready);
+        if self.core.is_driver_ok() && ready && self.device.process_queue(index) {
+            let _ = self.signal(VIRTIO_PCI_ISR_QUEUE);
+        }
+    }
+}
+
+impl<D: VirtioDevice> PciDevice for VirtioPciTransport<D> {
+    fn get_config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn get_config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    /// Handles a guest read in the BAR.
+    ///
+    /// Common configuration fields may be read with any width; narrow reads return the
+    /// addressed bytes of the containing 32-bit register. Reading the ISR status
+    /// acknowledges the interrupt.
+    fn read_bar(&mut self, bar: usize, offset: u64, data: &mut [u8]) {
+        match offset {
+            _ if bar != 0 => data.fill(0),
+            VIRTIO_PCI_COMMON_CFG_OFFSET..VIRTIO_PCI_ISR_OFFSET => {
+                let offset = offset - VIRTIO_PCI_COMMON_CFG_OFFSET;
+                read_register_bytes(offset, data, |register| self.read_common_register(register));
+            },
+            VIRTIO_PCI_ISR_OFFSET => self.read_isr(data),
+            VIRTIO_PCI_DEVICE_CFG_OFFSET..VIRTIO_PCI_NOTIFY_OFFSET => {
+                self.device.read_config(offset - VIRTIO_PCI_DEVICE_CFG_OFFSET, data);
+            },
+            _ => data.fill(0),
+        }
+    }
+
+    /// Handles a guest write in the BAR.
+    ///
+    /// Writes of an invalid width are ignored, as are writes to read-only or unknown
+    /// registers and status or feature writes out of sequence. A write to the
+    /// notification register of a queue processes it.
+    fn write_bar(&mut self, bar: usize, offset: u64, data: &[u8]) {
+        if bar != 0 || !is_valid_access_width(data.len()) {
+            return;
+        }
+        match offset {
+            VIRTIO_PCI_COMMON_CFG_OFFSET..VIRTIO_PCI_ISR_OFFSET => self.write_common(offset - VIRTIO_PCI_COMMON_CFG_OFFSET, data),
+            VIRTIO_PCI_DEVICE_CFG_OFFSET..VIRTIO_PCI_NOTIFY_OFFSET => {
+                self.device.write_config(offset - VIRTIO_PCI_DEVICE_CFG_OFFSET, data);
+            },
+            VIRTIO_PCI_NOTIFY_OFFSET..VIRTIO_PCI_BAR_SIZE => {
+                self.notify_queue(((offset - VIRTIO_PCI_NOTIFY_OFFSET) / VIRTIO_PCI_NOTIFY_OFF_MULTIPLIER as u64) as usize);
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::pci::PciBus;
+    use crate::utils::signals::TriggerMode;
+    use std::sync::Mutex;
+
+    /// Interrupt counting how often it was triggered.
+    struct CountingInterrupt(Arc<AtomicU32>);
+
+    impl Interrupt for CountingInterrupt {
+        fn get_trigger_mode(&self) -> TriggerMode {
+            TriggerMode::Edge
+        }
+
+        fn assert(&self) -> Result<(), VmError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn deassert(&self) -> Result<(), VmError> {
+            Ok(())
+        }
+    }
+
+    /// Block-like device with two queues that always asks for an interrupt.
+    #[derive(Default)]
+    struct TestDevice {
+        notified: Vec<usize>,
+        activated: Vec<VirtqueueState>,
+    }
+
+    impl VirtioDevice for TestDevice {
+        fn get_device_type(&self) -> u32 {
+            2
+        }
+
+        fn get_device_features(&self) -> u64 {
+            0
+        }
+
+        fn get_num_queues(&self) -> usize {
+            2
+        }
+
+        fn get_queue_max_size(&self, _index: usize) -> u16 {
+            256
+        }
+
+        fn activate_queue(&mut self, _index: usize, state: &VirtqueueState) -> Result<(), VmError> {
+            self.activated.push(*state);
+            Ok(())
+        }
+
+        fn deactivate_queue(&mut self, _index: usize) {}
+
+        fn process_queue(&mut self, index: usize) -> bool {
+            self.notified.push(index);
+            true
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn reset(&mut self) {
+            self.activated.clear();
+        }
+    }
+
+    fn create_transport(triggered: Arc<AtomicU32>) -> VirtioPciTransport<TestDevice> {
+        VirtioPciTransport::new(TestDevice::default(), Box::new(CountingInterrupt(triggered)), 5, 0xe000_0000).unwrap()
+    }
+
+    fn read_u32(transport: &mut VirtioPciTransport<TestDevice>, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        transport.read_bar(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn read_u16(transport: &mut VirtioPciTransport<TestDevice>, offset: u64) -> u16 {
+        let mut data = [0u8; 2];
+        transport.read_bar(0, offset, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_identification_and_capabilities() {
+        let transport = create_transport(Arc::default());
+        let mut bus = PciBus::new();
+        let slot = bus.add_device(Arc::new(Mutex::new(transport))).unwrap();
+
+        let mut id = [0u8; 4];
+        bus.read_config(0, slot, 0, 0, &mut id);
+        assert_eq!(u32::from_le_bytes(id), 0x1042_1af4);
+        let mut pin = [0u8; 2];
+        bus.read_config(0, slot, 0, 0x3c, &mut pin);
+        assert_eq!(pin, [5, PCI_INTERRUPT_PIN_A]);
+
+        // Walk the capability list the way the driver does, collecting the virtio ones
+        let mut next = [0u8; 1];
+        bus.read_config(0, slot, 0, 0x34, &mut next);
+        let mut found = Vec::new();
+        while next[0] != 0 {
+            let mut capability = [0u8; 16];
+            bus.read_config(0, slot, 0, next[0] as u64, &mut capability[..4]);
+            for word in 1..4 {
+                bus.read_config(0, slot, 0, next[0] as u64 + 4 * word, &mut capability[4 * word as usize..4 * word as usize + 4]);
+            }
+            assert_eq!(capability[0], PCI_CAP_ID_VENDOR);
+            found.push((capability[3], u32::from_le_bytes([capability[8], capability[9], capability[10], capability[11]])));
+            next[0] = capability[1];
+        }
+        assert_eq!(found, vec![
+            (VIRTIO_PCI_CAP_COMMON_CFG, VIRTIO_PCI_COMMON_CFG_OFFSET as u32),
+            (VIRTIO_PCI_CAP_NOTIFY_CFG, VIRTIO_PCI_NOTIFY_OFFSET as u32),
+            (VIRTIO_PCI_CAP_ISR_CFG, VIRTIO_PCI_ISR_OFFSET as u32),
+            (VIRTIO_PCI_CAP_DEVICE_CFG, VIRTIO_PCI_DEVICE_CFG_OFFSET as u32),
+        ]);
+    }
+
+    #[test]
+    fn test_queue_setup_and_notification() {
+        let triggered = Arc::new(AtomicU32::new(0));
+        let mut transport = create_transport(triggered.clone());
+        assert_eq!(read_u16(&mut transport, VIRTIO_PCI_COMMON_NUMQ), 2);
+        assert_eq!(read_u32(&mut transport, VIRTIO_PCI_DEVICE_CFG_OFFSET + 4), 0x0404_0404);
+
+        // Feature negotiation, then queue 1 set up by halves
+        for status in [0x1, 0x3] {
+            transport.write_bar(0, VIRTIO_PCI_COMMON_STATUS, &[status]);
+        }
+        transport.write_bar(0, VIRTIO_PCI_COMMON_GFSELECT, &1u32.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_GF, &1u32.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_STATUS, &[0xb]);
+        assert_eq!(transport.get_core().get_state(), VirtioDeviceState::FeaturesOk);
+
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_SELECT, &1u16.to_le_bytes());
+        assert_eq!(read_u16(&mut transport, VIRTIO_PCI_COMMON_Q_SIZE), 256);
+        assert_eq!(read_u16(&mut transport, VIRTIO_PCI_COMMON_Q_NOFF), 1);
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_SIZE, &128u16.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_DESCLO, &0x1000u32.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_DESCHI, &1u32.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_AVAILLO, &0x2000u64.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_USEDLO, &0x3000u32.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_Q_ENABLE, &1u16.to_le_bytes());
+        assert_eq!(read_u16(&mut transport, VIRTIO_PCI_COMMON_Q_ENABLE), 1);
+        let expected = VirtqueueState { max_size: 256, size: 128, ready: false, desc_table: 0x1_0000_1000, avail_ring: 0x2000, used_ring: 0x3000 };
+        assert_eq!(transport.get_device().activated, vec![expected]);
+
+        // Notifications are only processed once the driver is ready
+        transport.write_bar(0, VIRTIO_PCI_NOTIFY_OFFSET + 4, &1u16.to_le_bytes());
+        assert!(transport.get_device().notified.is_empty());
+        transport.write_bar(0, VIRTIO_PCI_COMMON_STATUS, &[0xf]);
+        transport.write_bar(0, VIRTIO_PCI_NOTIFY_OFFSET + 4, &1u16.to_le_bytes());
+        transport.write_bar(0, VIRTIO_PCI_NOTIFY_OFFSET, &0u16.to_le_bytes()); // Queue 0 isn't enabled
+        assert_eq!(transport.get_device().notified, vec![1]);
+        assert_eq!(triggered.load(Ordering::SeqCst), 1);
+
+        // Reading the ISR status acknowledges the interrupt
+        let mut isr = [0u8; 1];
+        transport.read_bar(0, VIRTIO_PCI_ISR_OFFSET, &mut isr);
+        assert_eq!(isr, [VIRTIO_PCI_ISR_QUEUE as u8]);
+        assert_eq!(transport.get_isr_status(), 0);
+
+        transport.write_bar(0, VIRTIO_PCI_COMMON_STATUS, &[0]);
+        assert_eq!(transport.get_core().get_status(), 0);
+        assert_eq!(transport.get_queue_state(1), Some(&VirtqueueState::new(256)));
+    }
+}
@@ -0,0 +1,123 @@
+//! Sizing of the virtqueues exposed by virtio devices.
+//!
+//! By default the number of queues follows the guest vCPU count so every vCPU can
+//! submit I/O on its own queue, and the queue size shrinks as queues are added so the
+//! total number of descriptors stays roughly constant. Benchmarks can override both
+//! values explicitly.
+
+use crate::error::VmError;
+
+/// Largest queue size allowed by the virtio specification for split virtqueues.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+/// Upper bound of automatically created queues, regardless of the vCPU count.
+pub const MAX_AUTO_QUEUES: u16 = 16;
+/// Total number of descriptors spread over the automatically sized queues.
+const AUTO_DESCRIPTOR_BUDGET: u32 = 1024;
+/// Smallest queue size picked by automatic sizing.
+const MIN_AUTO_QUEUE_SIZE: u16 = 128;
+
+/// Number and size of the virtqueues of a device, and the MSI-X vectors they need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtqueueConfig {
+    /// Number of request queues
+    num_queues: u16,
+    /// Descriptors per queue (power of two)
+    queue_size: u16,
+    /// MSI-X vectors: one per queue plus one for configuration changes
+    msix_vectors: u16,
+}
+
+impl Default for VirtqueueConfig {
+    /// A single queue of 1024 descriptors, as used by a guest with one vCPU.
+    fn default() -> Self {
+        VirtqueueConfig::for_vcpus(1)
+    }
+}
+
+impl VirtqueueConfig {
+    /// Creates an explicit queue configuration.
+    ///
+    /// # Arguments
+    /// * `num_queues` - Number of request queues, at least 1
+    /// * `queue_size` - Descriptors per queue, a power of two up to `MAX_QUEUE_SIZE`
+    ///
+    /// # Returns
+    /// * `Ok(VirtqueueConfig)` on success
+    /// * `Err(VmError)` if one of the values is out of range
+    pub fn new(num_queues: u16, queue_size: u16) -> Result<VirtqueueConfig, VmError> {
+        if num_queues == 0 {
+            return Err(VmError::config("virtqueue count must be greater than zero"));
+        }
+        if queue_size == 0 || !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+            return Err(VmError::config(format!("virtqueue size {} must be a power of two between 1 and {}", queue_size, MAX_QUEUE_SIZE)));
+        }
+        Ok(VirtqueueConfig { num_queues, queue_size, msix_vectors: num_queues + 1 })
+    }
+
+    /// Sizes the queues for a guest with `vcpus` vCPUs.
+    ///
+    /// One queue is created per vCPU (up to `MAX_AUTO_QUEUES`) and the 1024
+    /// descriptor budget is divided between them, never going below 128 descriptors
+    /// per queue.
+    pub fn for_vcpus(vcpus: u32) -> VirtqueueConfig {
+        let num_queues = vcpus.clamp(1, MAX_AUTO_QUEUES as u32) as u16;
+        let per_queue = (AUTO_DESCRIPTOR_BUDGET / num_queues as u32).next_power_of_two();
+        // Rounding up may exceed the budget for non power of two counts; round back down
+        let per_queue = if per_queue * num_queues as u32 > AUTO_DESCRIPTOR_BUDGET { per_queue / 2 } else { per_queue };
+        let queue_size = (per_queue as u16).max(MIN_AUTO_QUEUE_SIZE);
+        VirtqueueConfig { num_queues, queue_size, msix_vectors: num_queues + 1 }
+    }
+
+    /// Returns the number of request queues.
+    pub fn get_num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// Returns the number of descriptors per queue.
+    pub fn get_queue_size(&self) -> u16 {
+        self.queue_size
+    }
+
+    /// Returns the number of MSI-X vectors the device needs.
+    pub fn get_msix_vectors(&self) -> u16 {
+        self.msix_vectors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_vcpu_keeps_one_large_queue() {
+        let config = VirtqueueConfig::for_vcpus(1);
+        assert_eq!(config.get_num_queues(), 1);
+        assert_eq!(config.get_queue_size(), 1024);
+        assert_eq!(config.get_msix_vectors(), 2);
+        assert_eq!(VirtqueueConfig::default(), config);
+    }
+
+    #[test]
+    fn test_queues_follow_vcpu_count() {
+        let config = VirtqueueConfig::for_vcpus(4);
+        assert_eq!(config.get_num_queues(), 4);
+        assert_eq!(config.get_queue_size(), 256);
+        assert_eq!(config.get_msix_vectors(), 5);
+
+        let config = VirtqueueConfig::for_vcpus(3);
+        assert_eq!(config.get_num_queues(), 3);
+        assert_eq!(config.get_queue_size(), 256);
+
+        let config = VirtqueueConfig::for_vcpus(64);
+        assert_eq!(config.get_num_queues(), MAX_AUTO_QUEUES);
+        assert_eq!(config.get_queue_size(), 128);
+    }
+
+    #[test]
+    fn test_explicit_config_validation() {
+        assert!(VirtqueueConfig::new(2, 512).is_ok());
+        assert!(VirtqueueConfig::new(0, 512).is_err());
+        assert!(VirtqueueConfig::new(1, 300).is_err());
+        assert!(VirtqueueConfig::new(1, 0).is_err());
+    }
+}
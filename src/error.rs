@@ -0,0 +1,156 @@
+//! Crate-wide error type.
+//!
+//! Every fallible public API returns `Result<_, VmError>`. The variant tells the
+//! caller which subsystem failed, the message keeps the human readable context and
+//! the underlying library error (if any) stays reachable through `Error::source`.
+
+use thiserror::Error;
+
+/// Boxed lower level error preserved as the source of a `VmError`.
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Error returned by the fallible APIs of the crate.
+#[derive(Debug, Error)]
+pub enum VmError {
+    /// The host hypervisor (KVM, WHP or Hypervisor.framework) rejected an operation.
+    #[error("{message}")]
+    Hypervisor {
+        message: String,
+        #[source]
+        source: Option<BoxedError>,
+    },
+    /// Guest or host memory could not be allocated, mapped or accessed.
+    #[error("{message}")]
+    Memory {
+        message: String,
+        #[source]
+        source: Option<BoxedError>,
+    },
+    /// An emulated device failed or was misconfigured.
+    #[error("{message}")]
+    Device {
+        message: String,
+        #[source]
+        source: Option<BoxedError>,
+    },
+    /// A disk, kernel or distribution image could not be found, fetched or parsed.
+    #[error("{message}")]
+    Image {
+        message: String,
+        #[source]
+        source: Option<BoxedError>,
+    },
+    /// The guest agent reported an error or didn't follow the protocol.
+    #[error("{message}")]
+    Agent {
+        message: String,
+        #[source]
+        source: Option<BoxedError>,
+    },
+    /// The requested configuration is invalid or refers to unknown resources.
+    #[error("{message}")]
+    Config {
+        message: String,
+    },
+    /// A host filesystem or network I/O operation failed.
+    #[error("{message}")]
+    Io {
+        message: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl VmError {
+    /// Creates a `VmError::Hypervisor` without an underlying source error.
+    pub fn hypervisor(message: impl Into<String>) -> VmError {
+        VmError::Hypervisor { message: message.into(), source: None }
+    }
+
+    /// Creates a `VmError::Hypervisor` wrapping the error that caused it.
+    pub fn hypervisor_source(message: impl Into<String>, source: impl Into<BoxedError>) -> VmError {
+        VmError::Hypervisor { message: message.into(), source: Some(source.into()) }
+    }
+
+    /// Creates a `VmError::Memory` without an underlying source error.
+    pub fn memory(message: impl Into<String>) -> VmError {
+        VmError::Memory { message: message.into(), source: None }
+    }
+
+    /// Creates a `VmError::Memory` wrapping the error that caused it.
+    pub fn memory_source(message: impl Into<String>, source: impl Into<BoxedError>) -> VmError {
+        VmError::Memory { message: message.into(), source: Some(source.into()) }
+    }
+
+    /// Creates a `VmError::Device` without an underlying source error.
+    pub fn device(message: impl Into<String>) -> VmError {
+        VmError::Device { message: message.into(), source: None }
+    }
+
+    /// Creates a `VmError::Device` wrapping the error that caused it.
+    pub fn device_source(message: impl Into<String>, source: impl Into<BoxedError>) -> VmError {
+        VmError::Device { message: message.into(), source: Some(source.into()) }
+    }
+
+    /// Creates a `VmError::Image` without an underlying source error.
+    pub fn image(message: impl Into<String>) -> VmError {
+        VmError::Image { message: message.into(), source: None }
+    }
+
+    /// Creates a `VmError::Image` wrapping the error that caused it.
+    pub fn image_source(message: impl Into<String>, source: impl Into<BoxedError>) -> VmError {
+        VmError::Image { message: message.into(), source: Some(source.into()) }
+    }
+
+    /// Creates a `VmError::Agent` without an underlying source error.
+    pub fn agent(message: impl Into<String>) -> VmError {
+        VmError::Agent { message: message.into(), source: None }
+    }
+
+    /// Creates a `VmError::Agent` wrapping the error that caused it.
+    pub fn agent_source(message: impl Into<String>, source: impl Into<BoxedError>) -> VmError {
+        VmError::Agent { message: message.into(), source: Some(source.into()) }
+    }
+
+    /// Creates a `VmError::Config`.
+    pub fn config(message: impl Into<String>) -> VmError {
+        VmError::Config { message: message.into() }
+    }
+
+    /// Creates a `VmError::Io` wrapping the I/O error that caused it.
+    pub fn io(message: impl Into<String>, source: std::io::Error) -> VmError {
+        VmError::Io { message: message.into(), source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_display_uses_message() {
+        let err = VmError::memory("Failed to map memory");
+        assert_eq!(err.to_string(), "Failed to map memory");
+    }
+
+    #[test]
+    fn test_source_is_preserved() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let err = VmError::io("failed to open disk image: missing", io_err);
+
+        let source = err.source().expect("source should be preserved");
+        let io_source = source.downcast_ref::<std::io::Error>().expect("source should be an io::Error");
+        assert_eq!(io_source.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_boxed_source_is_preserved() {
+        let inner = std::io::Error::other("hypervisor busy");
+        let err = VmError::hypervisor_source("Failed to create VM: hypervisor busy", inner);
+
+        assert!(matches!(err, VmError::Hypervisor { .. }));
+        assert!(err.source().is_some());
+        assert!(VmError::device("no queue").source().is_none());
+    }
+}
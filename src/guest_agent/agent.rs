@@ -0,0 +1,249 @@
+//! Guest side of the guest agent protocol.
+//!
+//! The reference agent binary (`asgard-agent`) runs `serve` on a virtio-serial port or
+//! a vsock connection inside the guest. Requests are handled one at a time, in order.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use crate::error::VmError;
+use crate::guest_agent::protocol::{read_frame, write_frame, InterfaceAddress, Request, Response, AGENT_PROTOCOL_VERSION, MAX_FRAME_SIZE};
+
+/// Default virtio-serial port the agent listens on, as named by udev from the port name.
+pub const DEFAULT_AGENT_PORT: &str = "/dev/virtio-ports/org.asgard.agent.0";
+
+/// Serves requests from `stream` until the host closes it or asks for a shutdown.
+///
+/// Failing requests, and requests whose response doesn't fit in a frame, are answered
+/// with `Response::Error`; only a broken stream or a malformed request ends the loop
+/// with an error.
+///
+/// # Returns
+/// * `Ok(())` when the stream ended or the shutdown was started
+/// * `Err(VmError)` if the stream failed or the host sent a malformed request
+pub fn serve<S: Read + Write>(stream: &mut S) -> Result<(), VmError> {
+    while let Some(payload) = read_frame(stream)? {
+        let request = Request::decode(&payload)?;
+        let shutdown = match request {
+            Request::Shutdown { reboot } => Some(reboot),
+            _ => None,
+        };
+        write_frame(stream, &encode_response(handle_request(request)))?;
+
+        // The response must be out before the guest goes down
+        if let Some(reboot) = shutdown {
+            let program = if reboot { "reboot" } else { "poweroff" };
+            return match Command::new(program).status() {
+                Ok(_) => Ok(()),
+                Err(e) => Err(VmError::io(format!("failed to run {}: {}", program, e), e))
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Executes one request and returns its response.
+///
+/// `Request::Shutdown` is only acknowledged here; `serve` performs it once the
+/// response was sent.
+pub fn handle_request(request: Request) -> Response {
+    let result = match request {
+        Request::Ping { nonce } => Ok(Response::Pong { nonce, version: AGENT_PROTOCOL_VERSION }),
+        Request::Exec { program, args, stdin } => exec(&program, &args, stdin),
+        Request::WriteFile { path, mode, data } => write_file(&path, mode, &data).map(|_| Response::Ok),
+        Request::ReadFile { path } => match std::fs::read(&path) {
+            Ok(data) => Ok(Response::FileData { data }),
+            Err(e) => Err(format!("failed to read {}: {}", path, e))
+        },
+        Request::Shutdown { .. } => Ok(Response::Ok),
+        Request::GetAddresses => get_addresses().map(|addresses| Response::Addresses { addresses }),
+    };
+    result.unwrap_or_else(|message| Response::Error { message })
+}
+
+/// Encodes a response, replacing one too large for a frame with an error.
+fn encode_response(response: Response) -> Vec<u8> {
+    let payload = response.encode();
+    if payload.len() <= MAX_FRAME_SIZE {
+        return payload;
+    }
+    let message = format!("response of {} bytes exceeds the {} byte frame limit", payload.len(), MAX_FRAME_SIZE);
+    Response::Error { message }.encode()
+}
+
+/// Runs `program` to completion, feeding it `stdin` and collecting its output.
+fn exec(program: &str, args: &[String], stdin: Vec<u8>) -> Result<Response, String> {
+    let mut child = match Command::new(program).args(args)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => return Err(format!("failed to run {}: {}", program, e))
+    };
+
+    // Feed stdin from another thread so a program filling its output pipes first can't deadlock us
+    let mut child_stdin = child.stdin.take();
+    let feeder = std::thread::spawn(move || {
+        if let Some(pipe) = child_stdin.as_mut() {
+            // A program that exits without reading its input is not an error
+            let _ = pipe.write_all(&stdin);
+        }
+    });
+    let output = child.wait_with_output();
+    let _ = feeder.join();
+
+    match output {
+        Ok(output) => Ok(Response::ExecResult {
+            exit_code: output.status.code().unwrap_or(-1),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        }),
+        Err(e) => Err(format!("failed to wait for {}: {}", program, e))
+    }
+}
+
+/// Lists the IPv4 and IPv6 addresses of the network interfaces, except loopback ones.
+#[cfg(unix)]
+fn get_addresses() -> Result<Vec<InterfaceAddress>, String> {
+    use std::ffi::CStr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: on success `list` holds a linked list, freed below
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(format!("failed to list network interfaces: {}", std::io::Error::last_os_error()));
+    }
+    let mut addresses = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: the entries and what they point to stay valid until freeifaddrs
+        let interface = unsafe { &*entry };
+        entry = interface.ifa_next;
+        if interface.ifa_addr.is_null() || interface.ifa_flags & libc::IFF_LOOPBACK as libc::c_uint != 0 {
+            continue;
+        }
+        // SAFETY: the family tells which sockaddr structure ifa_addr points to
+        let address = unsafe {
+            match (*interface.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let address = &*(interface.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)))
+                },
+                libc::AF_INET6 => {
+                    let address = &*(interface.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(address.sin6_addr.s6_addr))
+                },
+                _ => continue,
+            }
+        };
+        // SAFETY: ifa_name is a NUL-terminated string
+        let name = unsafe { CStr::from_ptr(interface.ifa_name) }.to_string_lossy().into_owned();
+        addresses.push(InterfaceAddress { interface: name, address });
+    }
+    // SAFETY: `list` came from getifaddrs and isn't used afterwards
+    unsafe { libc::freeifaddrs(list) };
+    Ok(addresses)
+}
+
+/// Lists the addresses of the network interfaces; only Unix guests support it.
+#[cfg(not(unix))]
+fn get_addresses() -> Result<Vec<InterfaceAddress>, String> {
+    Err("listing network interfaces is not supported on this guest".to_string())
+}
+
+/// Replaces the content of `path` and applies the permission bits in `mode`.
+fn write_file(path: &str, mode: u32, data: &[u8]) -> Result<(), String> {
+    if let Err(e) = std::fs::write(path, data) {
+        return Err(format!("failed to write {}: {}", path, e));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)) {
+            return Err(format!("failed to set the mode of {}: {}", path, e));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("motd").to_string_lossy().into_owned();
+
+        let written = handle_request(Request::WriteFile { path: path.clone(), mode: 0o600, data: b"hello".to_vec() });
+        assert_eq!(written, Response::Ok);
+        assert_eq!(handle_request(Request::ReadFile { path }), Response::FileData { data: b"hello".to_vec() });
+
+        let missing = dir.path().join("missing").to_string_lossy().into_owned();
+        assert!(matches!(handle_request(Request::ReadFile { path: missing }), Response::Error { .. }));
+    }
+
+    // Helper: in-memory stream fed with `input` that collects what is written to it
+    struct MemoryStream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve_answers_oversized_responses_with_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        std::fs::File::create(&path).unwrap().set_len(MAX_FRAME_SIZE as u64).unwrap();
+
+        let mut input = Vec::new();
+        write_frame(&mut input, &Request::ReadFile { path: path.to_string_lossy().into_owned() }.encode()).unwrap();
+        write_frame(&mut input, &Request::Ping { nonce: 7 }.encode()).unwrap();
+        let mut stream = MemoryStream { input: std::io::Cursor::new(input), output: Vec::new() };
+        serve(&mut stream).expect("The agent should keep serving");
+
+        let mut output = stream.output.as_slice();
+        let response = Response::decode(&read_frame(&mut output).unwrap().unwrap()).unwrap();
+        assert!(matches!(response, Response::Error { message } if message.contains("frame limit")));
+        let response = Response::decode(&read_frame(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(response, Response::Pong { nonce: 7, version: AGENT_PROTOCOL_VERSION });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exec_request() {
+        let request = Request::Exec {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), "cat; echo err >&2; exit 3".to_string()],
+            stdin: b"in".to_vec(),
+        };
+        assert_eq!(handle_request(request), Response::ExecResult { exit_code: 3, stdout: b"in".to_vec(), stderr: b"err\n".to_vec() });
+        assert!(matches!(
+            handle_request(Request::Exec { program: "/nonexistent".to_string(), args: Vec::new(), stdin: Vec::new() }),
+            Response::Error { .. }
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_get_addresses_request() {
+        let Response::Addresses { addresses } = handle_request(Request::GetAddresses) else {
+            panic!("expected the addresses of the network interfaces");
+        };
+        assert!(addresses.iter().all(|address| !address.address.is_loopback() && !address.interface.is_empty()));
+    }
+}
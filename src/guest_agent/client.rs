@@ -0,0 +1,188 @@
+//! Host side of the guest agent protocol.
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::error::VmError;
+use crate::guest_agent::protocol::{read_frame_async, write_frame_async, InterfaceAddress, Request, Response};
+
+/// Output of a program run in the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecOutput {
+    /// Exit code, -1 if the program was killed by a signal
+    pub exit_code: i32,
+    /// Everything the program wrote to its standard output
+    pub stdout: Vec<u8>,
+    /// Everything the program wrote to its standard error
+    pub stderr: Vec<u8>,
+}
+
+impl ExecOutput {
+    /// Returns `true` if the program exited with code 0.
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+/// Client talking to the agent running in a guest.
+///
+/// The stream is whatever connects the host to the agent: the host end of a
+/// virtio-serial port such as the `ConsoleStream` of a `VirtioConsole`, a vsock
+/// connection, or any other byte stream.
+pub struct GuestAgentClient<S> {
+    stream: S,
+    next_nonce: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> GuestAgentClient<S> {
+    /// Creates a client talking over `stream`.
+    pub fn new(stream: S) -> Self {
+        GuestAgentClient { stream, next_nonce: 1 }
+    }
+
+    /// Returns the stream, e.g. to close it.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Checks that the agent answers.
+    ///
+    /// # Returns
+    /// * `Ok(u32)` with the protocol version of the agent
+    /// * `Err(VmError)` if the agent didn't answer correctly
+    pub async fn ping(&mut self) -> Result<u32, VmError> {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        match self.call(Request::Ping { nonce }).await? {
+            Response::Pong { nonce: echoed, version } if echoed == nonce => Ok(version),
+            Response::Pong { nonce: echoed, .. } => Err(VmError::agent(format!("guest agent answered ping {} with {}", nonce, echoed))),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Runs a program in the guest and waits for it to exit.
+    ///
+    /// # Arguments
+    /// * `program` - Program to run, looked up in the agent's `PATH`
+    /// * `args` - Arguments of the program
+    /// * `stdin` - Data fed to the program's standard input
+    ///
+    /// # Returns
+    /// * `Ok(ExecOutput)` once the program exited, whatever its exit code
+    /// * `Err(VmError)` if the program couldn't be started or the agent didn't answer
+    pub async fn exec(&mut self, program: &str, args: &[&str], stdin: &[u8]) -> Result<ExecOutput, VmError> {
+        let request = Request::Exec {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            stdin: stdin.to_vec(),
+        };
+        match self.call(request).await? {
+            Response::ExecResult { exit_code, stdout, stderr } => Ok(ExecOutput { exit_code, stdout, stderr }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Creates or replaces a file in the guest.
+    ///
+    /// # Arguments
+    /// * `path` - Absolute path of the file in the guest
+    /// * `mode` - Unix permission bits of the file
+    /// * `data` - New content of the file
+    pub async fn write_file(&mut self, path: &str, mode: u32, data: &[u8]) -> Result<(), VmError> {
+        match self.call(Request::WriteFile { path: path.to_string(), mode, data: data.to_vec() }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Reads a whole file from the guest.
+    pub async fn read_file(&mut self, path: &str) -> Result<Vec<u8>, VmError> {
+        match self.call(Request::ReadFile { path: path.to_string() }).await? {
+            Response::FileData { data } => Ok(data),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Lists the IP addresses of the guest's network interfaces, e.g. to connect to a
+    /// guest that got its address over DHCP.
+    ///
+    /// # Returns
+    /// * `Ok(addresses)` of all interfaces but loopback ones
+    /// * `Err(VmError)` if the guest couldn't list them or its agent predates protocol
+    ///   version 2
+    pub async fn get_ip_addresses(&mut self) -> Result<Vec<InterfaceAddress>, VmError> {
+        match self.call(Request::GetAddresses).await? {
+            Response::Addresses { addresses } => Ok(addresses),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Asks the guest to power off, or to reboot if `reboot` is set.
+    ///
+    /// Returns once the agent acknowledged the request, not when the guest is down;
+    /// use `VmHandle::wait` for that.
+    pub async fn shutdown(&mut self, reboot: bool) -> Result<(), VmError> {
+        match self.call(Request::Shutdown { reboot }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends a request and waits for its response, turning agent errors into `VmError`s.
+    async fn call(&mut self, request: Request) -> Result<Response, VmError> {
+        write_frame_async(&mut self.stream, &request.encode()).await?;
+        let payload = match read_frame_async(&mut self.stream).await? {
+            Some(payload) => payload,
+            None => return Err(VmError::agent("guest agent closed the connection")),
+        };
+        match Response::decode(&payload)? {
+            Response::Error { message } => Err(VmError::agent(format!("guest agent error: {}", message))),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> VmError {
+    VmError::agent(format!("unexpected guest agent response {:?}", response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest_agent::agent::handle_request;
+
+    /// Answers requests on the agent end of a duplex stream like the agent would.
+    async fn run_fake_agent(mut stream: tokio::io::DuplexStream) {
+        while let Ok(Some(payload)) = read_frame_async(&mut stream).await {
+            let response = handle_request(Request::decode(&payload).unwrap());
+            write_frame_async(&mut stream, &response.encode()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_against_agent() {
+        let (host, guest) = tokio::io::duplex(4096);
+        tokio::spawn(run_fake_agent(guest));
+        let mut client = GuestAgentClient::new(host);
+
+        assert_eq!(client.ping().await.unwrap(), crate::guest_agent::protocol::AGENT_PROTOCOL_VERSION);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config").to_string_lossy().into_owned();
+        client.write_file(&path, 0o644, &[7; 10_000]).await.unwrap();
+        assert_eq!(client.read_file(&path).await.unwrap(), vec![7; 10_000]);
+
+        let missing = client.read_file(&dir.path().join("missing").to_string_lossy()).await;
+        assert!(matches!(missing, Err(VmError::Agent { .. })));
+        // The connection survives a failed request
+        assert!(client.ping().await.is_ok());
+        #[cfg(unix)]
+        assert!(client.get_ip_addresses().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection() {
+        let (host, guest) = tokio::io::duplex(64);
+        drop(guest);
+        let mut client = GuestAgentClient::new(host);
+        assert!(client.ping().await.is_err());
+    }
+}
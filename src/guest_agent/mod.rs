@@ -0,0 +1,9 @@
+//! Guest agent: provisioning a running guest without SSH or networking.
+//!
+//! A small agent inside the guest (the `asgard-agent` binary) executes programs, reports
+//! its IP addresses and copies files on behalf of the host. Host and agent exchange the
+//! messages defined in `protocol` over a virtio-serial port or a vsock connection.
+
+pub mod protocol;
+pub mod client;
+pub mod agent;
@@ -0,0 +1,406 @@
+//! Wire format of the guest agent protocol.
+//!
+//! Every message travels in a frame: a little-endian `u32` payload length followed by
+//! the payload. A payload starts with a one byte message tag; integers are little
+//! endian, byte strings and strings carry a `u32` length prefix and lists a `u32`
+//! element count. The host sends one request and waits for its response before
+//! sending the next, so no request id is needed. IP addresses travel as strings.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::error::VmError;
+
+/// Version of the protocol, reported by the agent in its ping response.
+pub const AGENT_PROTOCOL_VERSION: u32 = 2;
+/// Largest payload accepted in a frame.
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+
+const TAG_PING: u8 = 0x01;
+const TAG_EXEC: u8 = 0x02;
+const TAG_WRITE_FILE: u8 = 0x03;
+const TAG_READ_FILE: u8 = 0x04;
+const TAG_SHUTDOWN: u8 = 0x05;
+const TAG_GET_ADDRESSES: u8 = 0x06;
+const TAG_PONG: u8 = 0x81;
+const TAG_EXEC_RESULT: u8 = 0x82;
+const TAG_FILE_DATA: u8 = 0x83;
+const TAG_OK: u8 = 0x84;
+const TAG_ADDRESSES: u8 = 0x86;
+const TAG_ERROR: u8 = 0xff;
+
+/// Request sent by the host to the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Checks that the agent is alive; answered with `Response::Pong`
+    Ping { nonce: u64 },
+    /// Runs a program to completion; answered with `Response::ExecResult`
+    Exec { program: String, args: Vec<String>, stdin: Vec<u8> },
+    /// Creates or replaces a guest file; answered with `Response::Ok`
+    WriteFile { path: String, mode: u32, data: Vec<u8> },
+    /// Reads a whole guest file; answered with `Response::FileData`
+    ReadFile { path: String },
+    /// Powers the guest off or reboots it; answered with `Response::Ok` before the agent acts
+    Shutdown { reboot: bool },
+    /// Lists the IP addresses of the guest's network interfaces; answered with
+    /// `Response::Addresses`. Needs protocol version 2
+    GetAddresses,
+}
+
+/// IP address assigned to a network interface of the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    /// Name of the interface in the guest, e.g. `eth0`
+    pub interface: String,
+    pub address: IpAddr,
+}
+
+/// Response sent by the agent to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Echoes the nonce of a ping with the agent's protocol version
+    Pong { nonce: u64, version: u32 },
+    /// Exit code (-1 if the program was killed by a signal) and output of a program
+    ExecResult { exit_code: i32, stdout: Vec<u8>, stderr: Vec<u8> },
+    /// Content of a guest file
+    FileData { data: Vec<u8> },
+    /// The request succeeded and returns nothing
+    Ok,
+    /// The request failed in the guest
+    Error { message: String },
+    /// IP addresses of the guest's network interfaces, loopback excluded
+    Addresses { addresses: Vec<InterfaceAddress> },
+}
+
+impl Request {
+    /// Serializes the request into a frame payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Request::Ping { nonce } => {
+                out.push(TAG_PING);
+                put_u64(&mut out, *nonce);
+            },
+            Request::Exec { program, args, stdin } => {
+                out.push(TAG_EXEC);
+                put_bytes(&mut out, program.as_bytes());
+                put_u32(&mut out, args.len() as u32);
+                for arg in args {
+                    put_bytes(&mut out, arg.as_bytes());
+                }
+                put_bytes(&mut out, stdin);
+            },
+            Request::WriteFile { path, mode, data } => {
+                out.push(TAG_WRITE_FILE);
+                put_bytes(&mut out, path.as_bytes());
+                put_u32(&mut out, *mode);
+                put_bytes(&mut out, data);
+            },
+            Request::ReadFile { path } => {
+                out.push(TAG_READ_FILE);
+                put_bytes(&mut out, path.as_bytes());
+            },
+            Request::Shutdown { reboot } => {
+                out.push(TAG_SHUTDOWN);
+                out.push(*reboot as u8);
+            },
+            Request::GetAddresses => out.push(TAG_GET_ADDRESSES),
+        }
+        out
+    }
+
+    /// Parses a frame payload.
+    ///
+    /// # Returns
+    /// * `Ok(Request)` on success
+    /// * `Err(VmError)` if the payload is truncated, has trailing bytes or an unknown tag
+    pub fn decode(payload: &[u8]) -> Result<Request, VmError> {
+        let mut decoder = Decoder { data: payload };
+        let request = match decoder.get_u8()? {
+            TAG_PING => Request::Ping { nonce: decoder.get_u64()? },
+            TAG_EXEC => {
+                let program = decoder.get_string()?;
+                let count = decoder.get_u32()?;
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    args.push(decoder.get_string()?);
+                }
+                Request::Exec { program, args, stdin: decoder.get_bytes()? }
+            },
+            TAG_WRITE_FILE => Request::WriteFile { path: decoder.get_string()?, mode: decoder.get_u32()?, data: decoder.get_bytes()? },
+            TAG_READ_FILE => Request::ReadFile { path: decoder.get_string()? },
+            TAG_SHUTDOWN => Request::Shutdown { reboot: decoder.get_u8()? != 0 },
+            TAG_GET_ADDRESSES => Request::GetAddresses,
+            tag => return Err(VmError::agent(format!("unknown guest agent request tag {:#x}", tag))),
+        };
+        decoder.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    /// Serializes the response into a frame payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Response::Pong { nonce, version } => {
+                out.push(TAG_PONG);
+                put_u64(&mut out, *nonce);
+                put_u32(&mut out, *version);
+            },
+            Response::ExecResult { exit_code, stdout, stderr } => {
+                out.push(TAG_EXEC_RESULT);
+                put_u32(&mut out, *exit_code as u32);
+                put_bytes(&mut out, stdout);
+                put_bytes(&mut out, stderr);
+            },
+            Response::FileData { data } => {
+                out.push(TAG_FILE_DATA);
+                put_bytes(&mut out, data);
+            },
+            Response::Ok => out.push(TAG_OK),
+            Response::Error { message } => {
+                out.push(TAG_ERROR);
+                put_bytes(&mut out, message.as_bytes());
+            },
+            Response::Addresses { addresses } => {
+                out.push(TAG_ADDRESSES);
+                put_u32(&mut out, addresses.len() as u32);
+                for address in addresses {
+                    put_bytes(&mut out, address.interface.as_bytes());
+                    put_bytes(&mut out, address.address.to_string().as_bytes());
+                }
+            },
+        }
+        out
+    }
+
+    /// Parses a frame payload.
+    ///
+    /// # Returns
+    /// * `Ok(Response)` on success
+    /// * `Err(VmError)` if the payload is truncated, has trailing bytes or an unknown tag
+    pub fn decode(payload: &[u8]) -> Result<Response, VmError> {
+        let mut decoder = Decoder { data: payload };
+        let response = match decoder.get_u8()? {
+            TAG_PONG => Response::Pong { nonce: decoder.get_u64()?, version: decoder.get_u32()? },
+            TAG_EXEC_RESULT => Response::ExecResult {
+                exit_code: decoder.get_u32()? as i32,
+                stdout: decoder.get_bytes()?,
+                stderr: decoder.get_bytes()?,
+            },
+            TAG_FILE_DATA => Response::FileData { data: decoder.get_bytes()? },
+            TAG_OK => Response::Ok,
+            TAG_ERROR => Response::Error { message: decoder.get_string()? },
+            TAG_ADDRESSES => {
+                let count = decoder.get_u32()?;
+                let mut addresses = Vec::new();
+                for _ in 0..count {
+                    let interface = decoder.get_string()?;
+                    let address = match decoder.get_string()?.parse() {
+                        Ok(address) => address,
+                        Err(e) => return Err(VmError::agent_source(format!("invalid IP address in guest agent message: {}", e), e))
+                    };
+                    addresses.push(InterfaceAddress { interface, address });
+                }
+                Response::Addresses { addresses }
+            },
+            tag => return Err(VmError::agent(format!("unknown guest agent response tag {:#x}", tag))),
+        };
+        decoder.finish()?;
+        Ok(response)
+    }
+}
+
+/// Writes `payload` as one frame.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), VmError> {
+    check_frame_size(payload.len())?;
+    let frame = [&(payload.len() as u32).to_le_bytes()[..], payload].concat();
+    match writer.write_all(&frame).and_then(|_| writer.flush()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to send guest agent frame: {}", e), e))
+    }
+}
+
+/// Reads one frame.
+///
+/// # Returns
+/// * `Ok(Some(Vec<u8>))` with the payload
+/// * `Ok(None)` if the stream ended between two frames
+/// * `Err(VmError)` if the stream failed, ended inside a frame or the frame is too large
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, VmError> {
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {},
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(VmError::io(format!("failed to receive guest agent frame: {}", e), e)),
+    }
+    let len = u32::from_le_bytes(header) as usize;
+    check_frame_size(len)?;
+    let mut payload = vec![0u8; len];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => Ok(Some(payload)),
+        Err(e) => Err(VmError::io(format!("failed to receive guest agent frame: {}", e), e))
+    }
+}
+
+/// Writes `payload` as one frame to an async stream.
+pub async fn write_frame_async<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<(), VmError> {
+    check_frame_size(payload.len())?;
+    let frame = [&(payload.len() as u32).to_le_bytes()[..], payload].concat();
+    if let Err(e) = writer.write_all(&frame).await {
+        return Err(VmError::io(format!("failed to send guest agent frame: {}", e), e));
+    }
+    match writer.flush().await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to send guest agent frame: {}", e), e))
+    }
+}
+
+/// Reads one frame from an async stream, with the same results as `read_frame`.
+pub async fn read_frame_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, VmError> {
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {},
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(VmError::io(format!("failed to receive guest agent frame: {}", e), e)),
+    }
+    let len = u32::from_le_bytes(header) as usize;
+    check_frame_size(len)?;
+    let mut payload = vec![0u8; len];
+    match reader.read_exact(&mut payload).await {
+        Ok(_) => Ok(Some(payload)),
+        Err(e) => Err(VmError::io(format!("failed to receive guest agent frame: {}", e), e))
+    }
+}
+
+fn check_frame_size(len: usize) -> Result<(), VmError> {
+    if len > MAX_FRAME_SIZE {
+        return Err(VmError::agent(format!("guest agent frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_SIZE)));
+    }
+    Ok(())
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Cursor over a payload being decoded.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VmError> {
+        if self.data.len() < len {
+            return Err(VmError::agent("truncated guest agent message"));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn get_u8(&mut self) -> Result<u8, VmError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn get_u32(&mut self) -> Result<u32, VmError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn get_u64(&mut self) -> Result<u64, VmError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn get_bytes(&mut self) -> Result<Vec<u8>, VmError> {
+        let len = self.get_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn get_string(&mut self) -> Result<String, VmError> {
+        match String::from_utf8(self.get_bytes()?) {
+            Ok(string) => Ok(string),
+            Err(e) => Err(VmError::agent_source(format!("invalid UTF-8 in guest agent message: {}", e), e))
+        }
+    }
+
+    fn finish(&self) -> Result<(), VmError> {
+        if !self.data.is_empty() {
+            return Err(VmError::agent(format!("{} trailing bytes in guest agent message", self.data.len())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_round_trip() {
+        let requests = [
+            Request::Ping { nonce: 7 },
+            Request::Exec { program: "/bin/sh".to_string(), args: vec!["-c".to_string(), "true".to_string()], stdin: b"x".to_vec() },
+            Request::WriteFile { path: "/etc/hostname".to_string(), mode: 0o644, data: b"vm\n".to_vec() },
+            Request::ReadFile { path: "/etc/os-release".to_string() },
+            Request::Shutdown { reboot: true },
+            Request::GetAddresses,
+        ];
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+
+        let responses = [
+            Response::Pong { nonce: 7, version: AGENT_PROTOCOL_VERSION },
+            Response::ExecResult { exit_code: -1, stdout: b"out".to_vec(), stderr: Vec::new() },
+            Response::FileData { data: vec![0; 3] },
+            Response::Ok,
+            Response::Error { message: "no such file".to_string() },
+            Response::Addresses {
+                addresses: vec![
+                    InterfaceAddress { interface: "eth0".to_string(), address: "10.0.2.15".parse().unwrap() },
+                    InterfaceAddress { interface: "eth0".to_string(), address: "fe80::5054:ff:fe12:3456".parse().unwrap() },
+                ],
+            },
+        ];
+        for response in responses {
+            assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_malformed_payloads_are_rejected() {
+        let ping = Request::Ping { nonce: 1 }.encode();
+        assert!(Request::decode(&ping[..ping.len() - 1]).is_err());
+        assert!(Request::decode(&[ping.as_slice(), &[0]].concat()).is_err());
+        assert!(Request::decode(&[0x42]).is_err());
+        assert!(Response::decode(&[]).is_err());
+        let bad_address = [&[TAG_ADDRESSES, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0][..], b"1.2"].concat();
+        assert!(Response::decode(&bad_address).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"abc").unwrap();
+        write_frame(&mut stream, b"").unwrap();
+
+        let mut reader = stream.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        // A frame cut short is an error, not the end of the stream
+        assert!(read_frame(&mut &stream[..5]).is_err());
+        assert!(read_frame(&mut &(u32::MAX.to_le_bytes())[..]).is_err());
+    }
+}
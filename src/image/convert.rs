@@ -0,0 +1,252 @@
+//! Conversion of disk images between formats.
+//!
+//! Distributions publish images as qcow2 or raw files and Hyper-V hosts use VHDX,
+//! while mapped disks (`map_disk_image`) need raw images. Raw images, ISOs and qcow2
+//! images are converted natively through their `DiskBackend`s, skipping zeroed ranges
+//! so the output stays sparse. VHDX images go through `qemu-img`, when it is installed.
+
+use std::fs::File;
+use std::path::Path;
+use std::process::Command;
+use super::format::{detect_image_format, ImageFormat};
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, FileBackend, SECTOR_SIZE};
+use crate::device_emulation::block_device::qcow2::Qcow2Backend;
+use crate::error::VmError;
+
+/// Sectors copied at once.
+const COPY_SECTORS: u64 = 2048;
+
+/// Outcome of a conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvertReport {
+    /// Detected format of the source image
+    pub source_format: ImageFormat,
+    /// Format of the converted image
+    pub target_format: ImageFormat,
+    /// Size of the disk in bytes
+    pub disk_size: u64,
+    /// Bytes of disk data written; zeroed ranges are skipped. Unknown when `qemu-img` converted
+    pub data_written: Option<u64>,
+    /// Whether `qemu-img` did the conversion
+    pub used_qemu_img: bool,
+}
+
+/// Whether `qemu-img` can be run.
+pub fn is_qemu_img_available() -> bool {
+    Command::new("qemu-img").arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// Converts the image at `source` into a `target_format` image at `target`.
+///
+/// The source format is detected from its content. The target is written next to
+/// its final path and only appears once complete, replacing any file there.
+///
+/// # Arguments
+/// * `source` - Image to convert; it is never modified
+/// * `target` - Path of the converted image
+/// * `target_format` - Format of the converted image
+///
+/// # Returns
+/// * `Ok(ConvertReport)` once the converted image is in place
+/// * `Err(VmError)` if the target format is `Iso`, the source couldn't be read or
+///   uses unsupported features, a raw source isn't made of whole sectors, VHDX is
+///   involved and `qemu-img` isn't installed or failed, or the target couldn't be
+///   written
+pub fn convert_image(source: &Path, target: &Path, target_format: ImageFormat) -> Result<ConvertReport, VmError> {
+    let source_format = detect_image_format(source)?;
+    if target_format == ImageFormat::Iso {
+        return Err(VmError::config("images can't be converted to ISOs"));
+    }
+    let directory = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let output = match tempfile::Builder::new().prefix(".tmp").tempfile_in(directory) {
+        Ok(output) => output,
+        Err(e) => return Err(VmError::io(format!("failed to create temporary image in {}: {}", directory.display(), e), e))
+    };
+
+    let report = if source_format == ImageFormat::Vhdx || target_format == ImageFormat::Vhdx {
+        convert_with_qemu_img(source, source_format, output.path(), target_format)?
+    } else {
+        let mut disk: Box<dyn DiskBackend> = match source_format {
+            ImageFormat::Qcow2 => Box::new(Qcow2Backend::open(source, true)?),
+            _ => Box::new(open_raw_source(source)?),
+        };
+        let disk_size = disk.capacity() * SECTOR_SIZE;
+        let mut converted: Box<dyn DiskBackend> = match target_format {
+            ImageFormat::Qcow2 => Box::new(Qcow2Backend::create(output.path(), disk_size)?),
+            _ => {
+                if let Err(e) = output.as_file().set_len(disk_size) {
+                    return Err(VmError::io(format!("failed to resize {}: {}", output.path().display(), e), e));
+                }
+                Box::new(FileBackend::open(output.path())?)
+            },
+        };
+        let data_written = copy_disk(disk.as_mut(), converted.as_mut())?;
+        converted.flush()?;
+        ConvertReport { source_format, target_format, disk_size, data_written: Some(data_written), used_qemu_img: false }
+    };
+
+    if let Err(e) = output.persist(target) {
+        return Err(VmError::io(format!("failed to create {}: {}", target.display(), e.error), e.error));
+    }
+    Ok(report)
+}
+
+/// Opens a raw source image read-only.
+fn open_raw_source(source: &Path) -> Result<FileBackend, VmError> {
+    let file = match File::open(source) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", source.display(), e), e))
+    };
+    match file.metadata() {
+        Ok(metadata) if !metadata.len().is_multiple_of(SECTOR_SIZE) => {
+            return Err(VmError::image(format!("raw image {} isn't made of whole sectors", source.display())));
+        },
+        Ok(_) => {},
+        Err(e) => return Err(VmError::io(format!("failed to read size of {}: {}", source.display(), e), e))
+    }
+    FileBackend::from_file(file)
+}
+
+/// Copies every non-zero range of `source` to the freshly created `target`.
+///
+/// # Returns
+/// * `Ok(bytes)` with the number of bytes written
+/// * `Err(VmError)` if reading or writing failed
+fn copy_disk(source: &mut dyn DiskBackend, target: &mut dyn DiskBackend) -> Result<u64, VmError> {
+    let capacity = source.capacity();
+    let mut buffer = vec![0u8; (COPY_SECTORS * SECTOR_SIZE) as usize];
+    let mut written = 0u64;
+    let mut sector = 0u64;
+    while sector < capacity {
+        let sectors = COPY_SECTORS.min(capacity - sector);
+        let chunk = &mut buffer[..(sectors * SECTOR_SIZE) as usize];
+        source.read_sectors(sector, chunk)?;
+        // A new target reads as zeroes already
+        if chunk.iter().any(|&byte| byte != 0) {
+            target.write_sectors(sector, chunk)?;
+            written += chunk.len() as u64;
+        }
+        sector += sectors;
+    }
+    Ok(written)
+}
+
+/// Converts an image with `qemu-img convert`.
+fn convert_with_qemu_img(source: &Path, source_format: ImageFormat, target: &Path, target_format: ImageFormat) -> Result<ConvertReport, VmError> {
+    if !is_qemu_img_available() {
+        return Err(VmError::image(format!(
+            "converting {} images to {} needs qemu-img, which isn't installed",
+            source_format.as_str(),
+            target_format.as_str()
+        )));
+    }
+    let output = match Command::new("qemu-img")
+        .arg("convert")
+        .args(["-f", source_format.as_str(), "-O", target_format.as_str()])
+        .arg(source)
+        .arg(target)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => return Err(VmError::io(format!("failed to run qemu-img: {}", e), e))
+    };
+    if !output.status.success() {
+        return Err(VmError::image(format!("qemu-img convert failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+
+    let disk_size = match target_format {
+        ImageFormat::Qcow2 => Qcow2Backend::open(target, true)?.capacity() * SECTOR_SIZE,
+        ImageFormat::Raw | ImageFormat::Iso => match target.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(VmError::io(format!("failed to read size of {}: {}", target.display(), e), e))
+        },
+        // qemu-img knows the virtual size of VHDX images
+        ImageFormat::Vhdx => get_virtual_size(target)?,
+    };
+    Ok(ConvertReport { source_format, target_format, disk_size, data_written: None, used_qemu_img: true })
+}
+
+/// Reads the virtual size of an image from `qemu-img info`.
+fn get_virtual_size(path: &Path) -> Result<u64, VmError> {
+    let output = match Command::new("qemu-img").args(["info", "--output=json"]).arg(path).output() {
+        Ok(output) => output,
+        Err(e) => return Err(VmError::io(format!("failed to run qemu-img: {}", e), e))
+    };
+    let info: serde_json::Value = match serde_json::from_slice(&output.stdout) {
+        Ok(info) => info,
+        Err(e) => return Err(VmError::image(format!("failed to parse qemu-img info of {}: {}", path.display(), e)))
+    };
+    match info["virtual-size"].as_u64() {
+        Some(size) => Ok(size),
+        None => Err(VmError::image(format!("qemu-img info of {} has no virtual size", path.display())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Helper: raw disk content with data in the first and last megabyte
+    fn disk_content() -> Vec<u8> {
+        let mut content = vec![0u8; 4 << 20];
+        content[..512].fill(0x11);
+        let end = content.len();
+        content[end - 1024..end - 512].fill(0x22);
+        content
+    }
+
+    #[test]
+    fn test_convert_raw_to_qcow2_and_back() {
+        let dir = TempDir::new().unwrap();
+        let raw = dir.path().join("disk.img");
+        std::fs::write(&raw, disk_content()).unwrap();
+
+        let qcow2 = dir.path().join("disk.qcow2");
+        let report = convert_image(&raw, &qcow2, ImageFormat::Qcow2).expect("Conversion to qcow2 should succeed");
+        assert_eq!(report.source_format, ImageFormat::Raw);
+        assert_eq!(report.disk_size, 4 << 20);
+        assert_eq!(report.data_written, Some(2 * COPY_SECTORS * SECTOR_SIZE), "Zeroed ranges are skipped");
+        assert!(!report.used_qemu_img);
+        assert!(std::fs::metadata(&qcow2).unwrap().len() < 4 << 20);
+
+        let back = dir.path().join("back.img");
+        let report = convert_image(&qcow2, &back, ImageFormat::Raw).expect("Conversion to raw should succeed");
+        assert_eq!(report.source_format, ImageFormat::Qcow2);
+        assert_eq!(std::fs::read(&back).unwrap(), disk_content());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3, "No temporary file is left behind");
+    }
+
+    #[test]
+    fn test_convert_rejects_partial_sectors() {
+        let dir = TempDir::new().unwrap();
+        let raw = dir.path().join("odd.img");
+        std::fs::write(&raw, vec![1u8; 1000]).unwrap();
+        assert!(convert_image(&raw, &dir.path().join("odd.qcow2"), ImageFormat::Qcow2).is_err());
+        assert!(!dir.path().join("odd.qcow2").exists());
+    }
+
+    #[test]
+    fn test_convert_vhdx_with_qemu_img() {
+        if !is_qemu_img_available() {
+            eprintln!("qemu-img not found, skipping");
+            return;
+        }
+        let dir = TempDir::new().unwrap();
+        let raw = dir.path().join("disk.img");
+        std::fs::write(&raw, disk_content()).unwrap();
+
+        let vhdx = dir.path().join("disk.vhdx");
+        let report = convert_image(&raw, &vhdx, ImageFormat::Vhdx).expect("Conversion to VHDX should succeed");
+        assert!(report.used_qemu_img);
+        assert_eq!(report.disk_size, 4 << 20);
+        assert_eq!(detect_image_format(&vhdx).unwrap(), ImageFormat::Vhdx);
+
+        let back = dir.path().join("back.img");
+        convert_image(&vhdx, &back, ImageFormat::Raw).expect("Conversion from VHDX should succeed");
+        assert_eq!(std::fs::read(&back).unwrap(), disk_content());
+    }
+}
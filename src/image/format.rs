@@ -0,0 +1,169 @@
+//! Disk image format detection.
+//!
+//! Images are told apart by their content rather than their file name, as
+//! distributions publish qcow2 images, raw disks and ISOs under all sorts of
+//! extensions. Only the signatures at the start of the file are read.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use crate::device_emulation::block_device::qcow2::QCOW2_MAGIC;
+use crate::error::VmError;
+
+/// Signature at the start of every VHDX image.
+pub const VHDX_SIGNATURE: &[u8; 8] = b"vhdxfile";
+/// Standard identifier of the first ISO 9660 volume descriptor, in sector 16 of 2048 bytes.
+pub const ISO9660_IDENTIFIER: &[u8; 5] = b"CD001";
+/// Offset of the ISO 9660 standard identifier.
+const ISO9660_IDENTIFIER_OFFSET: usize = 16 * 2048 + 1;
+/// Signature of a GPT header, in the second sector.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Format of a disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Plain sector-by-sector copy of a disk, or a bare filesystem
+    Raw,
+    /// QEMU copy-on-write image
+    Qcow2,
+    /// Hyper-V virtual hard disk
+    Vhdx,
+    /// ISO 9660 CD image; hybrid ISOs carrying a partition table too count as ISOs
+    Iso,
+}
+
+/// Partition table found on a raw disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    /// MBR partition table
+    Mbr,
+    /// GUID partition table behind a protective MBR
+    Gpt,
+}
+
+impl ImageFormat {
+    /// Returns the name of the format as `qemu-img` knows it; ISOs are raw images to it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormat::Raw | ImageFormat::Iso => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Vhdx => "vhdx",
+        }
+    }
+
+    /// Returns the format suggested by the extension of `path`, if it has a known one.
+    pub fn from_extension(path: &Path) -> Option<ImageFormat> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "img" | "raw" => Some(ImageFormat::Raw),
+            "qcow2" => Some(ImageFormat::Qcow2),
+            "vhdx" => Some(ImageFormat::Vhdx),
+            "iso" => Some(ImageFormat::Iso),
+            _ => None,
+        }
+    }
+
+    /// Whether the guest sees the image file byte for byte, so it can be mapped.
+    pub fn is_raw(&self) -> bool {
+        matches!(self, ImageFormat::Raw | ImageFormat::Iso)
+    }
+}
+
+/// Reads up to `len` bytes from the start of the image at `path`.
+fn read_header(path: &Path, len: usize) -> Result<Vec<u8>, VmError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path.display(), e), e))
+    };
+    let mut header = Vec::with_capacity(len);
+    if let Err(e) = file.take(len as u64).read_to_end(&mut header) {
+        return Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e));
+    }
+    Ok(header)
+}
+
+/// Detects the format of the image at `path` from its signatures: the qcow2 magic
+/// `QFI\xfb`, the VHDX signature and the ISO 9660 volume descriptor. Anything else,
+/// including empty files, is a raw disk.
+///
+/// # Returns
+/// * `Ok(ImageFormat)` on success
+/// * `Err(VmError)` if the image couldn't be read
+pub fn detect_image_format(path: &Path) -> Result<ImageFormat, VmError> {
+    let header = read_header(path, ISO9660_IDENTIFIER_OFFSET + ISO9660_IDENTIFIER.len())?;
+    if header.starts_with(&QCOW2_MAGIC.to_be_bytes()) {
+        Ok(ImageFormat::Qcow2)
+    } else if header.starts_with(VHDX_SIGNATURE) {
+        Ok(ImageFormat::Vhdx)
+    } else if header.get(ISO9660_IDENTIFIER_OFFSET..) == Some(&ISO9660_IDENTIFIER[..]) {
+        Ok(ImageFormat::Iso)
+    } else {
+        Ok(ImageFormat::Raw)
+    }
+}
+
+/// Detects the partition table of the raw disk at `path`.
+///
+/// # Returns
+/// * `Ok(Some(PartitionTable))` if the disk has an MBR, or a GPT behind it
+/// * `Ok(None)` if the disk has no partition table, e.g. a bare filesystem
+/// * `Err(VmError)` if the image couldn't be read
+pub fn detect_partition_table(path: &Path) -> Result<Option<PartitionTable>, VmError> {
+    let header = read_header(path, 1024)?;
+    if header.get(510..512) != Some(&[0x55, 0xaa][..]) {
+        return Ok(None);
+    }
+    if header.get(512..520) == Some(&GPT_SIGNATURE[..]) {
+        Ok(Some(PartitionTable::Gpt))
+    } else {
+        Ok(Some(PartitionTable::Mbr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::block_device::qcow2::Qcow2Backend;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_detect_image_format() {
+        let dir = TempDir::new().unwrap();
+        let qcow2 = dir.path().join("debian.img");
+        Qcow2Backend::create(&qcow2, 1 << 20).unwrap();
+        let vhdx = dir.path().join("disk.bin");
+        std::fs::write(&vhdx, b"vhdxfile\0\0\0\0").unwrap();
+        let mut iso = vec![0u8; 64 << 10];
+        iso[510..512].copy_from_slice(&[0x55, 0xaa]);
+        iso[ISO9660_IDENTIFIER_OFFSET..ISO9660_IDENTIFIER_OFFSET + 5].copy_from_slice(ISO9660_IDENTIFIER);
+        std::fs::write(dir.path().join("hybrid.img"), &iso).unwrap();
+        std::fs::write(dir.path().join("empty.img"), b"").unwrap();
+
+        // The extension doesn't matter
+        assert_eq!(detect_image_format(&qcow2).unwrap(), ImageFormat::Qcow2);
+        assert_eq!(detect_image_format(&vhdx).unwrap(), ImageFormat::Vhdx);
+        assert_eq!(detect_image_format(&dir.path().join("hybrid.img")).unwrap(), ImageFormat::Iso);
+        assert_eq!(detect_image_format(&dir.path().join("empty.img")).unwrap(), ImageFormat::Raw);
+        assert!(detect_image_format(&dir.path().join("missing.img")).is_err());
+
+        assert_eq!(ImageFormat::from_extension(Path::new("debian.QCOW2")), Some(ImageFormat::Qcow2));
+        assert_eq!(ImageFormat::from_extension(Path::new("mint.iso")), Some(ImageFormat::Iso));
+        assert_eq!(ImageFormat::from_extension(Path::new("notes.txt")), None);
+    }
+
+    #[test]
+    fn test_detect_partition_table() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.img");
+        let mut disk = vec![0u8; 4096];
+        std::fs::write(&path, &disk).unwrap();
+        assert_eq!(detect_partition_table(&path).unwrap(), None);
+
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+        std::fs::write(&path, &disk).unwrap();
+        assert_eq!(detect_partition_table(&path).unwrap(), Some(PartitionTable::Mbr));
+
+        disk[512..520].copy_from_slice(GPT_SIGNATURE);
+        std::fs::write(&path, &disk).unwrap();
+        assert_eq!(detect_partition_table(&path).unwrap(), Some(PartitionTable::Gpt));
+    }
+}
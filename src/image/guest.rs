@@ -0,0 +1,162 @@
+//! Guest operating systems of disk images and how they are booted.
+//!
+//! Linux guests boot through a kernel loaded straight into guest memory, picked off
+//! their disk or CD-ROM by `select_boot_device` when none is given. BSD and Windows
+//! guests need firmware to boot from their disk instead. `GuestImage` keeps the guest
+//! of an image together with its detected format, so it is attached with the block
+//! backend and boot method it needs. Windows images aren't downloaded: users supply
+//! their own installer ISO or VHDX disk.
+
+use std::path::{Path, PathBuf};
+use super::format::{detect_image_format, ImageFormat};
+use crate::error::VmError;
+use crate::vm_setup::setup_utils::{DiskBackendType, DiskDevice, VmSetupBuilder};
+
+/// Operating system running in a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GuestOs {
+    Linux,
+    FreeBsd,
+    OpenBsd,
+    Windows,
+}
+
+impl GuestOs {
+    /// Returns a lowercase name of the operating system
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GuestOs::Linux => "linux",
+            GuestOs::FreeBsd => "freebsd",
+            GuestOs::OpenBsd => "openbsd",
+            GuestOs::Windows => "windows",
+        }
+    }
+
+    /// Returns how guests of the operating system are booted
+    pub fn get_boot_method(&self) -> BootMethod {
+        match self {
+            GuestOs::Linux => BootMethod::DirectKernel,
+            GuestOs::FreeBsd | GuestOs::OpenBsd | GuestOs::Windows => BootMethod::Firmware,
+        }
+    }
+}
+
+/// How a guest is started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMethod {
+    /// Its kernel is loaded straight into guest memory, see `VmSetup::get_boot_order`
+    DirectKernel,
+    /// Firmware boots it from its disk or CD-ROM, like a physical machine
+    Firmware,
+}
+
+/// Disk image of a guest, with the format detected from its content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestImage {
+    path: PathBuf,
+    os: GuestOs,
+    format: ImageFormat,
+}
+
+impl GuestImage {
+    /// Opens the image at `path` holding an `os` guest, e.g. a downloaded distribution
+    /// image or a user-supplied Windows installer.
+    ///
+    /// # Returns
+    /// * `Ok(GuestImage)` on success
+    /// * `Err(VmError)` if the image couldn't be read
+    pub fn open(path: impl Into<PathBuf>, os: GuestOs) -> Result<GuestImage, VmError> {
+        let path = path.into();
+        let format = detect_image_format(&path)?;
+        Ok(GuestImage { path, os, format })
+    }
+
+    /// Get the path of the image.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+    /// Get the operating system of the guest.
+    pub fn get_os(&self) -> GuestOs {
+        self.os
+    }
+    /// Get the format of the image.
+    pub fn get_format(&self) -> ImageFormat {
+        self.format
+    }
+    /// Get how the guest is booted.
+    pub fn get_boot_method(&self) -> BootMethod {
+        self.os.get_boot_method()
+    }
+    /// Whether the image is an installer, attached as a CD-ROM rather than a disk.
+    pub fn is_installer(&self) -> bool {
+        self.format == ImageFormat::Iso
+    }
+
+    /// Returns the backend serving the image as a disk.
+    ///
+    /// # Returns
+    /// * `Ok(DiskBackendType)` on success
+    /// * `Err(VmError)` if no backend handles the format, e.g. VHDX, which has to be
+    ///   converted first
+    pub fn get_disk_backend(&self) -> Result<DiskBackendType, VmError> {
+        match self.format {
+            ImageFormat::Raw | ImageFormat::Iso | ImageFormat::Qcow2 => Ok(DiskBackendType::Auto),
+            ImageFormat::Vhdx => Err(VmError::image(format!(
+                "{} is a VHDX image, convert it with `convert_image` first",
+                self.path.display()
+            ))),
+        }
+    }
+
+    /// Attaches the image to a VM: installers as its CD-ROM, other images as its next disk.
+    ///
+    /// # Returns
+    /// * `Ok(VmSetupBuilder)` with the image attached
+    /// * `Err(VmError)` if no backend handles the format of a disk image
+    pub fn attach(&self, builder: VmSetupBuilder) -> Result<VmSetupBuilder, VmError> {
+        if self.is_installer() {
+            return Ok(builder.cdrom_image(&self.path));
+        }
+        Ok(builder.disk(DiskDevice::new(&self.path).backend(self.get_disk_backend()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::block_device::qcow2::Qcow2Backend;
+    use crate::image::format::{ISO9660_IDENTIFIER, VHDX_SIGNATURE};
+    use crate::vm_setup::setup_utils::VmSetup;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_guest_image() {
+        let dir = TempDir::new().unwrap();
+        let freebsd = dir.path().join("freebsd-14.1.qcow2");
+        Qcow2Backend::create(&freebsd, 1 << 20).unwrap();
+        let installer = dir.path().join("windows.iso");
+        let mut iso = vec![0u8; 64 << 10];
+        iso[16 * 2048 + 1..16 * 2048 + 6].copy_from_slice(ISO9660_IDENTIFIER);
+        std::fs::write(&installer, &iso).unwrap();
+        let vhdx = dir.path().join("windows.vhdx");
+        std::fs::write(&vhdx, VHDX_SIGNATURE).unwrap();
+
+        let freebsd = GuestImage::open(&freebsd, GuestOs::FreeBsd).unwrap();
+        assert_eq!(freebsd.get_format(), ImageFormat::Qcow2);
+        assert_eq!(freebsd.get_boot_method(), BootMethod::Firmware);
+        assert_eq!(freebsd.get_disk_backend().unwrap(), DiskBackendType::Auto);
+        let installer = GuestImage::open(&installer, GuestOs::Windows).unwrap();
+        assert!(installer.is_installer());
+        let vhdx = GuestImage::open(&vhdx, GuestOs::Windows).unwrap();
+        assert_eq!(vhdx.get_format(), ImageFormat::Vhdx);
+        assert!(vhdx.get_disk_backend().unwrap_err().to_string().contains("convert it with `convert_image` first"));
+        assert_eq!(GuestOs::Linux.get_boot_method(), BootMethod::DirectKernel);
+
+        let builder = installer.attach(VmSetup::builder(64, 1)).unwrap();
+        let setup = freebsd.attach(builder).unwrap().build().unwrap();
+        assert_eq!(setup.get_cdrom_image(), Some(installer.get_path()));
+        assert_eq!(setup.get_disk_image(), Some(freebsd.get_path()));
+        assert!(vhdx.attach(VmSetup::builder(64, 1)).is_err());
+        assert!(GuestImage::open(dir.path().join("missing.img"), GuestOs::OpenBsd).is_err());
+    }
+}
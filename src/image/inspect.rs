@@ -0,0 +1,348 @@
+//! Offline inspection of what a disk image contains.
+//!
+//! `inspect_disk` lists the partitions of an image with `list_partitions` and probes
+//! each for a filesystem by its superblock signature, reporting its type, label and
+//! UUID the way `blkid` does. Nothing is mounted and no external tool runs, so tools
+//! built on the crate can show what an image holds before booting it on any host.
+
+use std::path::Path;
+use super::format::{detect_image_format, ImageFormat, PartitionTable};
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, SECTOR_SIZE};
+use crate::error::VmError;
+use crate::kernel_setup::guest_fs::{list_partitions, read_disk_bytes, Partition};
+use crate::vm_setup::disk_setup::open_disk;
+use crate::vm_setup::setup_utils::DiskDevice;
+
+/// Magic number of ext superblocks.
+const EXT_MAGIC: u16 = 0xef53;
+/// Compatible ext feature: the filesystem has a journal, as ext3 and ext4 do.
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// Incompatible ext features only ext4 uses: extents, 64-bit block numbers and flexible
+/// block groups.
+const EXT4_INCOMPAT_FEATURES: u32 = 0x40 | 0x80 | 0x200;
+/// Magic number of UFS2 superblocks.
+const UFS2_MAGIC: u32 = 0x1954_0119;
+/// Byte offset of the UFS2 superblock.
+const UFS2_SUPERBLOCK_OFFSET: u64 = 65536;
+/// Byte offset of the Btrfs superblock.
+const BTRFS_SUPERBLOCK_OFFSET: u64 = 65536;
+
+/// Type of a filesystem found on a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemType {
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Vfat,
+    Ntfs,
+    Iso9660,
+    /// FreeBSD's UFS2
+    Ufs2,
+    /// Linux swap space
+    Swap,
+}
+
+impl FilesystemType {
+    /// Returns the name `blkid` and `mount` use for the filesystem type
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilesystemType::Ext2 => "ext2",
+            FilesystemType::Ext3 => "ext3",
+            FilesystemType::Ext4 => "ext4",
+            FilesystemType::Xfs => "xfs",
+            FilesystemType::Btrfs => "btrfs",
+            FilesystemType::Vfat => "vfat",
+            FilesystemType::Ntfs => "ntfs",
+            FilesystemType::Iso9660 => "iso9660",
+            FilesystemType::Ufs2 => "ufs",
+            FilesystemType::Swap => "swap",
+        }
+    }
+}
+
+/// Filesystem found on a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemInfo {
+    pub fs_type: FilesystemType,
+    /// Volume label, if one is set
+    pub label: Option<String>,
+    /// UUID or serial number formatted like `blkid` does, if the filesystem has one
+    pub uuid: Option<String>,
+}
+
+/// Partition of an inspected disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Region of the disk holding the partition
+    pub partition: Partition,
+    /// Filesystem on the partition, if a known one was found
+    pub filesystem: Option<FilesystemInfo>,
+}
+
+/// What a disk image contains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskInfo {
+    pub format: ImageFormat,
+    /// Size of the disk the guest sees, in bytes
+    pub size: u64,
+    /// Partition table of the disk, `None` for a bare filesystem
+    pub partition_table: Option<PartitionTable>,
+    /// Partitions in table order; the whole disk if it has no partition table
+    pub partitions: Vec<PartitionInfo>,
+}
+
+/// Lists the partitions of the disk image at `path` with the filesystems they hold.
+///
+/// The image is opened read-only, so it may belong to a VM that is running.
+///
+/// # Arguments
+/// * `path` - Path to a raw, qcow2 or ISO image
+///
+/// # Returns
+/// * `Ok(DiskInfo)` on success
+/// * `Err(VmError)` if the image couldn't be read, is a VHDX image or has a malformed GPT
+pub fn inspect_disk(path: &Path) -> Result<DiskInfo, VmError> {
+    let format = detect_image_format(path)?;
+    let mut disk = open_disk(&DiskDevice::new(path).read_only(true))?;
+    let size = disk.capacity() * SECTOR_SIZE;
+    let found = list_partitions(disk.as_mut())?;
+    // FAT and NTFS boot sectors end with the MBR signature too, but a partition table
+    // never describes a partition at the start of the disk
+    let partition_table = if found.first().is_some_and(|partition| partition.offset > 0) {
+        read_partition_table(disk.as_mut())?
+    } else {
+        None
+    };
+    let mut partitions = Vec::new();
+    for partition in found {
+        let filesystem = probe_filesystem(disk.as_mut(), partition)?;
+        partitions.push(PartitionInfo { partition, filesystem });
+    }
+    Ok(DiskInfo { format, size, partition_table, partitions })
+}
+
+/// Reads the kind of partition table of a disk that has one.
+fn read_partition_table(disk: &mut dyn DiskBackend) -> Result<Option<PartitionTable>, VmError> {
+    let mut header = [0u8; 2 * SECTOR_SIZE as usize];
+    disk.read_sectors(0, &mut header)?;
+    if header[510..512] != [0x55, 0xaa] {
+        return Ok(None);
+    }
+    if &header[512..520] == b"EFI PART" {
+        Ok(Some(PartitionTable::Gpt))
+    } else {
+        Ok(Some(PartitionTable::Mbr))
+    }
+}
+
+/// Reads `len` bytes at `offset` within a partition.
+///
+/// # Returns
+/// * `Ok(Some(data))` on success
+/// * `Ok(None)` if the range reaches past the end of the partition
+/// * `Err(VmError)` if the disk couldn't be read
+fn read_partition(disk: &mut dyn DiskBackend, partition: Partition, offset: u64, len: usize) -> Result<Option<Vec<u8>>, VmError> {
+    if offset + len as u64 > partition.size {
+        return Ok(None);
+    }
+    let mut data = vec![0u8; len];
+    read_disk_bytes(disk, partition.offset + offset, &mut data)?;
+    Ok(Some(data))
+}
+
+/// Identifies the filesystem on a partition from its superblock.
+///
+/// # Returns
+/// * `Ok(Some(FilesystemInfo))` if a known filesystem was found
+/// * `Ok(None)` if the partition holds something else
+/// * `Err(VmError)` if the disk couldn't be read
+fn probe_filesystem(disk: &mut dyn DiskBackend, partition: Partition) -> Result<Option<FilesystemInfo>, VmError> {
+    // Boot sector shared by FAT and NTFS, which also starts XFS superblocks
+    if let Some(boot) = read_partition(disk, partition, 0, 512)? {
+        if &boot[0..4] == b"XFSB" {
+            return Ok(Some(FilesystemInfo {
+                fs_type: FilesystemType::Xfs,
+                label: get_label(&boot[108..120]),
+                uuid: Some(format_uuid(&boot[32..48])),
+            }));
+        }
+        if &boot[3..11] == b"NTFS    " {
+            let serial = u64::from_le_bytes(boot[72..80].try_into().unwrap());
+            // The label lives in a file of the MFT, which isn't read
+            return Ok(Some(FilesystemInfo { fs_type: FilesystemType::Ntfs, label: None, uuid: Some(format!("{:016X}", serial)) }));
+        }
+        if boot[510..512] == [0x55, 0xaa] {
+            // FAT12/16 and FAT32 keep the same extended boot record at different offsets
+            let record = if &boot[82..87] == b"FAT32" { 64 } else if &boot[54..57] == b"FAT" { 36 } else { 0 };
+            if record != 0 {
+                let serial = u32::from_le_bytes(boot[record + 3..record + 7].try_into().unwrap());
+                return Ok(Some(FilesystemInfo {
+                    fs_type: FilesystemType::Vfat,
+                    label: get_label(&boot[record + 7..record + 18]).filter(|label| label != "NO NAME"),
+                    uuid: Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)),
+                }));
+            }
+        }
+    }
+
+    if let Some(superblock) = read_partition(disk, partition, 1024, 1024)? && u16::from_le_bytes([superblock[56], superblock[57]]) == EXT_MAGIC {
+        let le32 = |at: usize| u32::from_le_bytes(superblock[at..at + 4].try_into().unwrap());
+        let fs_type = if le32(96) & EXT4_INCOMPAT_FEATURES != 0 {
+            FilesystemType::Ext4
+        } else if le32(92) & EXT_COMPAT_HAS_JOURNAL != 0 {
+            FilesystemType::Ext3
+        } else {
+            FilesystemType::Ext2
+        };
+        return Ok(Some(FilesystemInfo {
+            fs_type,
+            label: get_label(&superblock[120..136]),
+            uuid: Some(format_uuid(&superblock[104..120])),
+        }));
+    }
+
+    // Swap signature at the end of the first 4 KiB page
+    if let Some(page) = read_partition(disk, partition, 0, 4096)? && &page[4086..4096] == b"SWAPSPACE2" {
+        return Ok(Some(FilesystemInfo {
+            fs_type: FilesystemType::Swap,
+            label: get_label(&page[1052..1068]),
+            uuid: Some(format_uuid(&page[1036..1052])),
+        }));
+    }
+
+    if let Some(descriptor) = read_partition(disk, partition, 16 * 2048, 2048)?
+        && descriptor[0] == 1
+        && &descriptor[1..6] == b"CD001"
+    {
+        return Ok(Some(FilesystemInfo { fs_type: FilesystemType::Iso9660, label: get_label(&descriptor[40..72]), uuid: None }));
+    }
+
+    if let Some(superblock) = read_partition(disk, partition, BTRFS_SUPERBLOCK_OFFSET, 4096)? && &superblock[64..72] == b"_BHRfS_M" {
+        return Ok(Some(FilesystemInfo {
+            fs_type: FilesystemType::Btrfs,
+            label: get_label(&superblock[299..555]),
+            uuid: Some(format_uuid(&superblock[32..48])),
+        }));
+    }
+
+    if let Some(superblock) = read_partition(disk, partition, UFS2_SUPERBLOCK_OFFSET, 1376)?
+        && u32::from_le_bytes(superblock[1372..1376].try_into().unwrap()) == UFS2_MAGIC
+    {
+        return Ok(Some(FilesystemInfo { fs_type: FilesystemType::Ufs2, label: get_label(&superblock[680..712]), uuid: None }));
+    }
+    Ok(None)
+}
+
+/// Returns a label padded with NULs or spaces, or `None` if it is blank.
+fn get_label(raw: &[u8]) -> Option<String> {
+    let end = raw.iter().position(|&byte| byte == 0).unwrap_or(raw.len());
+    let label = String::from_utf8_lossy(&raw[..end]).trim_end().to_string();
+    if label.is_empty() { None } else { Some(label) }
+}
+
+/// Formats 16 bytes stored in order as a UUID.
+fn format_uuid(raw: &[u8]) -> String {
+    let hex: String = raw.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::block_device::qcow2::Qcow2Backend;
+    use crate::vm_setup::cloudinit::{write_seed_image, CloudInitSeed, SeedFormat};
+    use tempfile::TempDir;
+
+    // Helper: an ext4 superblock labelled "rootfs" at 1 KiB of a partition
+    fn write_ext4_superblock(partition: &mut [u8]) {
+        let superblock = &mut partition[1024..2048];
+        superblock[56..58].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        superblock[92..96].copy_from_slice(&EXT_COMPAT_HAS_JOURNAL.to_le_bytes());
+        superblock[96..100].copy_from_slice(&0x2c2u32.to_le_bytes());
+        superblock[104..120].copy_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0, 1, 2, 3, 4, 5, 6, 7]);
+        superblock[120..126].copy_from_slice(b"rootfs");
+    }
+
+    #[test]
+    fn test_inspect_partitioned_disk() {
+        let dir = TempDir::new().unwrap();
+        let mut disk = vec![0u8; 4 << 20];
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+        // Swap in sectors 2048..4096, ext4 in the rest
+        for (entry, (start, sectors, partition_type)) in [(2048u32, 2048u32, 0x82u8), (4096, 4096, 0x83)].into_iter().enumerate() {
+            let at = 446 + 16 * entry;
+            disk[at + 4] = partition_type;
+            disk[at + 8..at + 12].copy_from_slice(&start.to_le_bytes());
+            disk[at + 12..at + 16].copy_from_slice(&sectors.to_le_bytes());
+        }
+        let swap = &mut disk[1 << 20..2 << 20];
+        swap[4086..4096].copy_from_slice(b"SWAPSPACE2");
+        swap[1036..1052].copy_from_slice(&[0xab; 16]);
+        write_ext4_superblock(&mut disk[2 << 20..]);
+        let path = dir.path().join("disk.img");
+        std::fs::write(&path, &disk).unwrap();
+
+        let info = inspect_disk(&path).unwrap();
+        assert_eq!(info.format, ImageFormat::Raw);
+        assert_eq!(info.size, 4 << 20);
+        assert_eq!(info.partition_table, Some(PartitionTable::Mbr));
+        assert_eq!(info.partitions, vec![
+            PartitionInfo {
+                partition: Partition { offset: 1 << 20, size: 1 << 20 },
+                filesystem: Some(FilesystemInfo {
+                    fs_type: FilesystemType::Swap,
+                    label: None,
+                    uuid: Some("abababab-abab-abab-abab-abababababab".to_string()),
+                }),
+            },
+            PartitionInfo {
+                partition: Partition { offset: 2 << 20, size: 2 << 20 },
+                filesystem: Some(FilesystemInfo {
+                    fs_type: FilesystemType::Ext4,
+                    label: Some("rootfs".to_string()),
+                    uuid: Some("12345678-9abc-def0-0001-020304050607".to_string()),
+                }),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_inspect_seed_images() {
+        let dir = TempDir::new().unwrap();
+        let seed = CloudInitSeed::builder("vm-1").build().unwrap();
+        for (format, fs_type, label) in [(SeedFormat::Iso, FilesystemType::Iso9660, "cidata"), (SeedFormat::Vfat, FilesystemType::Vfat, "CIDATA")] {
+            let path = dir.path().join(format!("seed-{}.img", fs_type.as_str()));
+            write_seed_image(&seed, &path, format).unwrap();
+            let info = inspect_disk(&path).unwrap();
+            assert_eq!(info.partition_table, None);
+            assert_eq!(info.partitions.len(), 1);
+            let filesystem = info.partitions[0].filesystem.clone().expect("seed filesystem");
+            assert_eq!(filesystem.fs_type, fs_type);
+            assert_eq!(filesystem.label.as_deref(), Some(label));
+        }
+    }
+
+    #[test]
+    fn test_inspect_qcow2_and_unknown() {
+        let dir = TempDir::new().unwrap();
+        let qcow2 = dir.path().join("disk.qcow2");
+        let mut backend = Qcow2Backend::create(&qcow2, 1 << 20).unwrap();
+        let mut filesystem = vec![0u8; 4096];
+        write_ext4_superblock(&mut filesystem);
+        backend.write_sectors(0, &filesystem).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+
+        let info = inspect_disk(&qcow2).unwrap();
+        assert_eq!(info.format, ImageFormat::Qcow2);
+        assert_eq!(info.partitions[0].filesystem.as_ref().unwrap().fs_type, FilesystemType::Ext4);
+
+        let blank = dir.path().join("blank.img");
+        std::fs::write(&blank, vec![0u8; 1 << 20]).unwrap();
+        let info = inspect_disk(&blank).unwrap();
+        assert_eq!(info.partitions, vec![PartitionInfo { partition: Partition { offset: 0, size: 1 << 20 }, filesystem: None }]);
+        assert!(inspect_disk(&dir.path().join("missing.img")).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+//! Disk image handling independent of any hypervisor.
+
+pub mod convert;
+pub mod format;
+pub mod guest;
+pub mod inspect;
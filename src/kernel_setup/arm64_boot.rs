@@ -0,0 +1,439 @@
+//! ARM64 Linux boot protocol.
+//!
+//! Implements direct kernel boot per Documentation/arch/arm64/booting.rst: the
+//! `Image` header is parsed (a gzip compressed `vmlinuz` is inflated first), the kernel
+//! is placed at a 2 MiB aligned base plus its `text_offset`, the initrd follows it and
+//! a minimal flattened device tree describing memory, CPUs, PSCI, the architected
+//! timer, the emulated devices and the command line is placed at the top of RAM. The
+//! boot vCPU starts at the kernel with X0 holding the device tree address, X1-X3 zero
+//! and interrupts masked.
+//!
+//! Everything here is plain byte manipulation so the layout can be built and tested
+//! without Hypervisor.framework; `macos_setup` copies the results into guest memory.
+
+use std::io::Read;
+use flate2::read::GzDecoder;
+use crate::device_emulation::gic::{GicVersion, GICC_SIZE, GICR_SIZE};
+use crate::error::VmError;
+
+/// Guest physical address of the start of RAM when booting a kernel.
+pub const ARM64_RAM_BASE: u64 = 0x4000_0000;
+/// Value of CPSR at kernel entry: EL1h with D, A, I and F masked.
+pub const ARM64_BOOT_CPSR: u64 = 0x3c5;
+/// Maximum size of the device tree blob allowed by the boot protocol.
+pub const FDT_MAX_SIZE: usize = 0x20_0000;
+
+/// "ARM\x64" magic at offset 56 of the Image header.
+const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241;
+/// Size of the Image header.
+const ARM64_IMAGE_HEADER_SIZE: usize = 64;
+/// `text_offset` assumed for kernels older than 3.17, whose `image_size` is zero.
+const LEGACY_TEXT_OFFSET: u64 = 0x8_0000;
+/// The kernel must be placed at a 2 MiB aligned base (plus `text_offset`).
+const KERNEL_BASE_ALIGN: u64 = 0x20_0000;
+/// Alignment of the initrd.
+const INITRD_ALIGN: u64 = 0x1000;
+/// Header flag telling the kernel is big-endian.
+const IMAGE_FLAG_BE: u64 = 1 << 0;
+/// Phandle the device tree gives the interrupt controller.
+const GIC_PHANDLE: u32 = 1;
+/// Size of the register window of a virtio-mmio device.
+const VIRTIO_MMIO_REGION_SIZE: u32 = 0x1000;
+
+/// PSCI 0.2 function identifiers (SMC32 calling convention).
+pub const PSCI_VERSION: u32 = 0x8400_0000;
+pub const PSCI_CPU_OFF: u32 = 0x8400_0002;
+pub const PSCI_CPU_ON: u32 = 0xC400_0003;
+pub const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+pub const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+/// PSCI return value for unsupported functions.
+pub const PSCI_NOT_SUPPORTED: u64 = (-1i64) as u64;
+
+/// Header of an ARM64 `Image` kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arm64ImageHeader {
+    /// Offset of the kernel from the 2 MiB aligned base
+    pub text_offset: u64,
+    /// Memory the kernel occupies once running, including bss
+    pub image_size: u64,
+    /// Kernel flags (endianness, page size, placement)
+    pub flags: u64,
+}
+
+impl Arm64ImageHeader {
+    /// Parses the header of an uncompressed `Image`.
+    ///
+    /// # Returns
+    /// * `Ok(Arm64ImageHeader)` on success
+    /// * `Err(VmError)` if the magic is missing or the kernel is big-endian
+    pub fn parse(kernel: &[u8]) -> Result<Arm64ImageHeader, VmError> {
+        if kernel.len() < ARM64_IMAGE_HEADER_SIZE {
+            return Err(VmError::image("kernel is too small to hold an ARM64 Image header"));
+        }
+        let read_u64 = |off: usize| u64::from_le_bytes(kernel[off..off + 8].try_into().unwrap());
+        let magic = u32::from_le_bytes(kernel[56..60].try_into().unwrap());
+        if magic != ARM64_IMAGE_MAGIC {
+            return Err(VmError::image("kernel is not an ARM64 Image: magic missing"));
+        }
+
+        let flags = read_u64(24);
+        if flags & IMAGE_FLAG_BE != 0 {
+            return Err(VmError::image("big-endian ARM64 kernels are not supported"));
+        }
+
+        let image_size = read_u64(16);
+        let (text_offset, image_size) = if image_size == 0 {
+            (LEGACY_TEXT_OFFSET, kernel.len() as u64)
+        } else {
+            (read_u64(8), image_size)
+        };
+
+        Ok(Arm64ImageHeader { text_offset, image_size, flags })
+    }
+}
+
+/// Inflates a gzip compressed kernel (`vmlinuz` as shipped by most arm64 distros).
+///
+/// Kernels without the gzip magic are returned unchanged.
+pub fn decompress_kernel(kernel: &[u8]) -> Result<Vec<u8>, VmError> {
+    if !kernel.starts_with(&[0x1f, 0x8b]) {
+        return Ok(kernel.to_vec());
+    }
+    let mut image = Vec::new();
+    match GzDecoder::new(kernel).read_to_end(&mut image) {
+        Ok(_) => Ok(image),
+        Err(e) => Err(VmError::image_source(format!("Failed to decompress gzip kernel: {}", e), e))
+    }
+}
+
+/// Where the kernel, initrd and device tree go in guest memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arm64BootLayout {
+    /// Load address of the kernel, which is also its entry point
+    pub kernel_addr: u64,
+    /// Address and size of the initrd, if any
+    pub initrd: Option<(u64, usize)>,
+    /// Address of the device tree blob, passed in X0
+    pub fdt_addr: u64,
+}
+
+/// Places the kernel, initrd and device tree in guest RAM.
+///
+/// # Arguments
+/// * `header` - Header of the kernel to load
+/// * `initrd_size` - Size of the initrd, if one is loaded
+/// * `ram_base` - Guest physical address of the start of RAM
+/// * `ram_size` - Size of guest RAM in bytes
+///
+/// # Returns
+/// * `Ok(Arm64BootLayout)` on success
+/// * `Err(VmError)` if the components don't fit in guest RAM
+pub fn compute_boot_layout(header: &Arm64ImageHeader, initrd_size: Option<usize>, ram_base: u64, ram_size: u64) -> Result<Arm64BootLayout, VmError> {
+    let ram_end = ram_base + ram_size;
+    let kernel_addr = ram_base.next_multiple_of(KERNEL_BASE_ALIGN) + header.text_offset;
+    let kernel_end = kernel_addr + header.image_size;
+
+    // The device tree sits in the last 2 MiB of RAM
+    if ram_size < FDT_MAX_SIZE as u64 {
+        return Err(VmError::memory("guest RAM is too small to hold a device tree"));
+    }
+    let fdt_addr = (ram_end - FDT_MAX_SIZE as u64) & !(KERNEL_BASE_ALIGN - 1);
+    if kernel_end > fdt_addr {
+        return Err(VmError::memory(format!("not enough guest RAM for the kernel: {} bytes needed", header.image_size)));
+    }
+
+    let initrd = match initrd_size {
+        Some(size) => {
+            let addr = kernel_end.next_multiple_of(INITRD_ALIGN);
+            if addr + size as u64 > fdt_addr {
+                return Err(VmError::memory(format!("not enough guest RAM for the initrd: {} bytes needed above {:#x}", size, addr)));
+            }
+            Some((addr, size))
+        },
+        None => None
+    };
+
+    Ok(Arm64BootLayout { kernel_addr, initrd, fdt_addr })
+}
+
+/// Interrupt controller of an ARM64 guest and the guest physical addresses of its registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arm64Gic {
+    /// GICv2 distributor and CPU interface
+    V2 { distributor: u64, cpu_interface: u64 },
+    /// GICv3 distributor and the redistributors of the CPUs, one after the other
+    V3 { distributor: u64, redistributors: u64 },
+}
+
+/// Emulated devices described to the guest next to RAM and the CPUs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Arm64Devices {
+    /// Interrupt controller the devices and the timer signal
+    pub gic: Option<Arm64Gic>,
+    /// Register window address and SPI of each virtio-mmio device
+    pub virtio_mmio: Vec<(u64, u32)>,
+}
+
+/// Builds a minimal device tree for a Linux guest.
+///
+/// The tree describes the RAM range, `cpu_count` PSCI-enabled CPUs, the architected
+/// timer, the command line and the initrd location.
+///
+/// # Returns
+/// * `Ok(Vec<u8>)` holding the flattened device tree
+/// * `Err(VmError)` if the blob exceeds `FDT_MAX_SIZE`
+pub fn build_fdt(cmdline: &str, ram_base: u64, ram_size: u64, initrd: Option<(u64, usize)>, cpu_count: u32) -> Result<Vec<u8>, VmError> {
+    build_fdt_with_devices(cmdline, ram_base, ram_size, initrd, cpu_count, &Arm64Devices::default())
+}
+
+/// Builds the device tree of `build_fdt`, adding the interrupt controller and the
+/// virtio-mmio devices of `devices`.
+///
+/// # Returns
+/// * `Ok(Vec<u8>)` holding the flattened device tree
+/// * `Err(VmError)` if the blob exceeds `FDT_MAX_SIZE` or virtio-mmio devices have no
+///   interrupt controller to signal
+pub fn build_fdt_with_devices(cmdline: &str, ram_base: u64, ram_size: u64, initrd: Option<(u64, usize)>, cpu_count: u32,
+                              devices: &Arm64Devices) -> Result<Vec<u8>, VmError> {
+    if devices.gic.is_none() && !devices.virtio_mmio.is_empty() {
+        return Err(VmError::config("virtio-mmio devices need an interrupt controller"));
+    }
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "linux,dummy-virt");
+    if devices.gic.is_some() {
+        fdt.property_u32("interrupt-parent", GIC_PHANDLE);
+    }
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", cmdline);
+    if let Some((addr, size)) = initrd {
+        fdt.property_u64("linux,initrd-start", addr);
+        fdt.property_u64("linux,initrd-end", addr + size as u64);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", ram_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &[(ram_base >> 32) as u32, ram_base as u32, (ram_size >> 32) as u32, ram_size as u32]);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    for cpu in 0..cpu_count {
+        fdt.begin_node(&format!("cpu@{:x}", cpu));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_string("compatible", "arm,arm-v8");
+        fdt.property_u32("reg", cpu);
+        fdt.property_string("enable-method", "psci");
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("psci");
+    fdt.property_string("compatible", "arm,psci-0.2");
+    fdt.property_string("method", "hvc");
+    fdt.end_node();
+
+    // Secure, non-secure, virtual and hypervisor timer PPIs, level-triggered
+    fdt.begin_node("timer");
+    fdt.property_string("compatible", "arm,armv8-timer");
+    fdt.property_cells("interrupts", &[1, 13, 0xf08, 1, 14, 0xf08, 1, 11, 0xf08, 1, 10, 0xf08]);
+    fdt.property_null("always-on");
+    fdt.end_node();
+
+    if let Some(gic) = devices.gic {
+        let (compatible, distributor, version, (second, second_size)) = match gic {
+            Arm64Gic::V2 { distributor, cpu_interface } => ("arm,cortex-a15-gic", distributor, GicVersion::V2, (cpu_interface, GICC_SIZE)),
+            Arm64Gic::V3 { distributor, redistributors } => {
+                ("arm,gic-v3", distributor, GicVersion::V3, (redistributors, GICR_SIZE * cpu_count as u64))
+            },
+        };
+        let distributor_size = version.get_distributor_size();
+        fdt.begin_node(&format!("intc@{:x}", distributor));
+        fdt.property_string("compatible", compatible);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 3);
+        fdt.property_cells("reg", &[
+            (distributor >> 32) as u32, distributor as u32, (distributor_size >> 32) as u32, distributor_size as u32,
+            (second >> 32) as u32, second as u32, (second_size >> 32) as u32, second_size as u32,
+        ]);
+        fdt.property_u32("phandle", GIC_PHANDLE);
+        fdt.end_node();
+    }
+
+    // Level-triggered SPIs: the transports hold their line until the guest acknowledges
+    for (base, spi) in &devices.virtio_mmio {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &[(base >> 32) as u32, *base as u32, 0, VIRTIO_MMIO_REGION_SIZE]);
+        fdt.property_cells("interrupts", &[0, *spi, 4]);
+        fdt.property_null("dma-coherent");
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+
+    let blob = fdt.finish();
+    if blob.len() > FDT_MAX_SIZE {
+        return Err(VmError::config(format!("device tree is {} bytes, at most {} are allowed", blob.len(), FDT_MAX_SIZE)));
+    }
+    Ok(blob)
+}
+
+/// PSCI request issued by the guest through `hvc #0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciCall {
+    /// Query the implemented PSCI version
+    Version,
+    /// Power the calling CPU off
+    CpuOff,
+    /// Power another CPU on; secondary bring-up isn't supported yet
+    CpuOn,
+    /// Power the whole system off
+    SystemOff,
+    /// Reset the whole system
+    SystemReset,
+    /// Any other function
+    Unsupported(u32),
+}
+
+impl PsciCall {
+    /// Decodes the PSCI function identifier passed in W0.
+    pub fn from_function_id(function_id: u32) -> PsciCall {
+        match function_id {
+            PSCI_VERSION => PsciCall::Version,
+            PSCI_CPU_OFF => PsciCall::CpuOff,
+            // CPU_ON exists in both SMC32 and SMC64 flavours
+            PSCI_CPU_ON | 0x8400_0003 => PsciCall::CpuOn,
+            PSCI_SYSTEM_OFF => PsciCall::SystemOff,
+            PSCI_SYSTEM_RESET => PsciCall::SystemReset,
+            other => PsciCall::Unsupported(other),
+        }
+    }
+}
+
+/// Minimal flattened device tree (DTB version 17) serializer.
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    const FDT_MAGIC: u32 = 0xd00d_feed;
+    const FDT_BEGIN_NODE: u32 = 0x1;
+    const FDT_END_NODE: u32 = 0x2;
+    const FDT_PROP: u32 = 0x3;
+    const FDT_END: u32 = 0x9;
+    const HEADER_SIZE: usize = 40;
+    /// One empty memory reservation entry terminating the reservation map.
+    const RSVMAP_SIZE: usize = 16;
+
+    fn new() -> FdtWriter {
+        FdtWriter { structure: Vec::new(), strings: Vec::new() }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.push_u32(Self::FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+
+    fn end_node(&mut self) {
+        self.push_u32(Self::FDT_END_NODE);
+    }
+
+    /// Returns the offset of `name` in the strings block, adding it if needed.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut needle = name.as_bytes().to_vec();
+        needle.push(0);
+        let mut offset = 0;
+        for entry in self.strings.split_inclusive(|b| *b == 0) {
+            if entry == needle.as_slice() {
+                return offset as u32;
+            }
+            offset += entry.len();
+        }
+        self.strings.extend_from_slice(&needle);
+        offset as u32
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(Self::FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    /// Terminates the structure block and assembles header, reservation map, structure and strings.
+    fn finish(mut self) -> Vec<u8> {
+        self.push_u32(Self::FDT_END);
+
+        let off_mem_rsvmap = Self::HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + Self::RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            Self::FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            17, // version
+            16, // last compatible version
+            0,  // boot CPU
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0u8; Self::RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
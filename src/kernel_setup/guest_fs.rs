@@ -0,0 +1,724 @@
+//! Read-only access to the files of a disk image without mounting it.
+//!
+//! `list_partitions` reads MBR and GPT partition tables, and `ExtFilesystem` reads
+//! ext2/3/4 filesystems: directories, and regular files through block maps or extent
+//! trees. That is enough to pick the kernel out of a guest's `/boot` on any host.
+//! Symbolic links are listed but not followed.
+
+use std::cmp::Ordering;
+use crate::error::VmError;
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, SECTOR_SIZE};
+
+/// Inode number of the root directory of ext filesystems.
+const ROOT_INODE: u32 = 2;
+/// Magic number of ext superblocks.
+const EXT_MAGIC: u16 = 0xef53;
+/// Magic number of extent tree nodes.
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Incompatible feature: the filesystem has 64-bit block numbers.
+const INCOMPAT_64BIT: u32 = 0x80;
+/// Incompatible features the reader can't handle: compression and meta block groups.
+const INCOMPAT_UNSUPPORTED: u32 = 0x1 | 0x10;
+/// Inode flag: the data is mapped by an extent tree.
+const INODE_FLAG_EXTENTS: u32 = 0x80000;
+/// Inode flag: the data is stored in the inode itself.
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+/// Largest file `read_file` loads into memory.
+const MAX_FILE_SIZE: u64 = 1 << 30;
+/// Deepest extent tree accepted; ext4 never builds more than 5 levels.
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+/// Region of a disk holding one partition, or the whole disk if it has no partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// Byte offset of the partition on the disk
+    pub offset: u64,
+    /// Size of the partition in bytes
+    pub size: u64,
+}
+
+/// Type of a directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Devices, sockets and pipes
+    Other,
+}
+
+/// Entry of a directory listed by `ExtFilesystem::read_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+    pub kind: EntryKind,
+}
+
+/// Reads `buf.len()` bytes at any byte `offset` of a disk.
+///
+/// # Returns
+/// * `Ok(())` if the whole buffer was filled
+/// * `Err(VmError)` if the range is out of the disk or the read failed
+pub fn read_disk_bytes(disk: &mut dyn DiskBackend, offset: u64, buf: &mut [u8]) -> Result<(), VmError> {
+    let first = offset / SECTOR_SIZE;
+    let end = match offset.checked_add(buf.len() as u64) {
+        Some(end) => end.div_ceil(SECTOR_SIZE),
+        None => return Err(VmError::image(format!("read of {} bytes at offset {} overflows", buf.len(), offset)))
+    };
+    let mut sectors = vec![0u8; ((end - first) * SECTOR_SIZE) as usize];
+    disk.read_sectors(first, &mut sectors)?;
+    let start = (offset % SECTOR_SIZE) as usize;
+    buf.copy_from_slice(&sectors[start..start + buf.len()]);
+    Ok(())
+}
+
+/// Lists the partitions of a disk from its GPT or MBR partition table; extended MBR
+/// partitions are skipped. A disk without a partition table is a single partition.
+///
+/// # Returns
+/// * `Ok(partitions)` in table order
+/// * `Err(VmError)` if the disk couldn't be read or the GPT is malformed
+pub fn list_partitions(disk: &mut dyn DiskBackend) -> Result<Vec<Partition>, VmError> {
+    let whole_disk = vec![Partition { offset: 0, size: disk.capacity() * SECTOR_SIZE }];
+    if disk.capacity() == 0 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    disk.read_sectors(0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xaa] {
+        return Ok(whole_disk);
+    }
+
+    let mut partitions = Vec::new();
+    for entry in mbr[446..510].chunks_exact(16) {
+        let partition_type = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        match partition_type {
+            0xee => return list_gpt_partitions(disk),
+            0x00 | 0x05 | 0x0f | 0x85 => {},
+            _ if sectors > 0 => partitions.push(Partition { offset: start * SECTOR_SIZE, size: sectors * SECTOR_SIZE }),
+            _ => {},
+        }
+    }
+    Ok(if partitions.is_empty() { whole_disk } else { partitions })
+}
+
+/// Lists the partitions of a GUID partition table; checksums aren't verified.
+fn list_gpt_partitions(disk: &mut dyn DiskBackend) -> Result<Vec<Partition>, VmError> {
+    let mut header = [0u8; SECTOR_SIZE as usize];
+    disk.read_sectors(1, &mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        return Err(VmError::image("protective MBR without a GPT header"));
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let count = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if count > 1024 || !(128..=4096).contains(&entry_size) {
+        return Err(VmError::image(format!("unsupported GPT of {} entries of {} bytes", count, entry_size)));
+    }
+
+    let mut entries = vec![0u8; count * entry_size];
+    read_disk_bytes(disk, entries_lba.saturating_mul(SECTOR_SIZE), &mut entries)?;
+    let mut partitions = Vec::new();
+    for entry in entries.chunks_exact(entry_size) {
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        // Unused entries have a zero type GUID
+        if entry[..16].iter().any(|&byte| byte != 0) && last >= first {
+            let offset = first.checked_mul(SECTOR_SIZE);
+            let size = (last - first).checked_add(1).and_then(|sectors| sectors.checked_mul(SECTOR_SIZE));
+            match (offset, size) {
+                (Some(offset), Some(size)) => partitions.push(Partition { offset, size }),
+                _ => return Err(VmError::image(format!("GPT partition of sectors {} to {} is out of range", first, last)))
+            }
+        }
+    }
+    Ok(partitions)
+}
+
+/// Runs of the data of an inode, collected from its block map or extent tree.
+///
+/// The map comes from the disk, so the work spent on it is bounded by the size of the
+/// file: a corrupt or crafted map pointing many entries at the same blocks fails
+/// instead of expanding into an unbounded number of runs.
+struct RunMap {
+    /// Runs of (first logical block, first physical block, length in blocks)
+    runs: Vec<(u64, u64, u64)>,
+    /// Blocks of the file; the map isn't followed past them
+    blocks: u64,
+    /// Map blocks that may still be read
+    reads_left: u64,
+}
+
+impl RunMap {
+    /// Creates an empty map of a file of `blocks` blocks.
+    fn new(blocks: u64) -> RunMap {
+        // A sparse file reaches a block through up to 3 indirect blocks
+        RunMap { runs: Vec::new(), blocks, reads_left: blocks.saturating_mul(3) + MAX_EXTENT_DEPTH as u64 }
+    }
+
+    /// Adds a run, dropping it if it starts past the end of the file.
+    fn push(&mut self, logical: u64, physical: u64, len: u64) -> Result<(), VmError> {
+        if logical >= self.blocks {
+            return Ok(());
+        }
+        if self.runs.len() as u64 >= self.blocks {
+            return Err(VmError::image("ext file maps more runs than it has blocks"));
+        }
+        self.runs.push((logical, physical, len));
+        Ok(())
+    }
+
+    /// Accounts for reading one more block of the map.
+    fn take_read(&mut self) -> Result<(), VmError> {
+        match self.reads_left.checked_sub(1) {
+            Some(left) => {
+                self.reads_left = left;
+                Ok(())
+            },
+            None => Err(VmError::image("ext block map is larger than the file it maps"))
+        }
+    }
+}
+
+/// Inode fields the reader needs.
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    /// Block map or extent tree root
+    block: [u8; 60],
+}
+
+/// Read-only ext2, ext3 or ext4 filesystem on a partition of a disk.
+pub struct ExtFilesystem<'a> {
+    disk: &'a mut dyn DiskBackend,
+    /// Byte offset of the filesystem on the disk
+    offset: u64,
+    block_size: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    /// Size of a group descriptor, 32 or more with 64-bit block numbers
+    descriptor_size: u64,
+    /// Byte offset of the group descriptor table within the filesystem
+    descriptor_table: u64,
+    is_64bit: bool,
+}
+
+impl<'a> ExtFilesystem<'a> {
+    /// Opens the filesystem on `partition`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(ExtFilesystem))` if the partition holds an ext filesystem
+    /// * `Ok(None)` if it holds something else
+    /// * `Err(VmError)` if the disk couldn't be read or the filesystem uses features
+    ///   the reader doesn't support
+    pub fn open(disk: &'a mut dyn DiskBackend, partition: Partition) -> Result<Option<ExtFilesystem<'a>>, VmError> {
+        if partition.size < 2048 {
+            return Ok(None);
+        }
+        let mut superblock = [0u8; 1024];
+        read_disk_bytes(disk, partition.offset + 1024, &mut superblock)?;
+        let le16 = |at: usize| u16::from_le_bytes(superblock[at..at + 2].try_into().unwrap());
+        let le32 = |at: usize| u32::from_le_bytes(superblock[at..at + 4].try_into().unwrap());
+        if le16(56) != EXT_MAGIC {
+            return Ok(None);
+        }
+
+        let incompatible = le32(96);
+        if incompatible & INCOMPAT_UNSUPPORTED != 0 {
+            return Err(VmError::image(format!("unsupported ext filesystem features {:#x}", incompatible)));
+        }
+        let log_block_size = le32(24);
+        if log_block_size > 6 {
+            return Err(VmError::image(format!("invalid ext block size 2^{}", 10 + log_block_size)));
+        }
+        let block_size = 1024u64 << log_block_size;
+        let inode_size = if le32(76) == 0 { 128 } else { le16(88) as u64 };
+        let is_64bit = incompatible & INCOMPAT_64BIT != 0;
+        let descriptor_size = if is_64bit { (le16(254) as u64).max(32) } else { 32 };
+        let inodes_per_group = le32(40) as u64;
+        if inodes_per_group == 0 || !(128..=block_size).contains(&inode_size) {
+            return Err(VmError::image("invalid ext superblock"));
+        }
+
+        Ok(Some(ExtFilesystem {
+            disk,
+            offset: partition.offset,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            descriptor_size,
+            descriptor_table: (le32(20) as u64 + 1) * block_size,
+            is_64bit,
+        }))
+    }
+
+    /// Lists the directory at the absolute `path`, without `.` and `..`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(entries))` if `path` is a directory
+    /// * `Ok(None)` if it doesn't exist or isn't a directory
+    /// * `Err(VmError)` if the filesystem couldn't be read
+    pub fn read_dir(&mut self, path: &str) -> Result<Option<Vec<DirEntry>>, VmError> {
+        let inode = match self.lookup(path)? {
+            Some((inode, EntryKind::Directory)) => inode,
+            _ => return Ok(None),
+        };
+        let inode = self.read_inode(inode)?;
+        self.read_dir_inode(&inode).map(Some)
+    }
+
+    /// Reads the regular file at the absolute `path`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(data))` if `path` is a regular file
+    /// * `Ok(None)` if it doesn't exist or isn't a regular file
+    /// * `Err(VmError)` if the filesystem couldn't be read or the file is too large
+    pub fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>, VmError> {
+        let inode = match self.lookup(path)? {
+            Some((inode, EntryKind::File)) => inode,
+            _ => return Ok(None),
+        };
+        let inode = self.read_inode(inode)?;
+        self.read_inode_data(&inode).map(Some)
+    }
+
+    /// Resolves an absolute path to its inode number and kind.
+    fn lookup(&mut self, path: &str) -> Result<Option<(u32, EntryKind)>, VmError> {
+        let mut current = (ROOT_INODE, EntryKind::Directory);
+        for component in path.split('/').filter(|component| !component.is_empty() && *component != ".") {
+            if current.1 != EntryKind::Directory {
+                return Ok(None);
+            }
+            let directory = self.read_inode(current.0)?;
+            let entries = self.read_dir_inode(&directory)?;
+            current = match entries.into_iter().find(|entry| entry.name == component) {
+                Some(entry) => (entry.inode, entry.kind),
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(current))
+    }
+
+    /// Reads from the filesystem at a byte offset relative to its start.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VmError> {
+        match self.offset.checked_add(offset) {
+            Some(offset) => read_disk_bytes(self.disk, offset, buf),
+            None => Err(VmError::image(format!("ext filesystem offset {} is out of range", offset)))
+        }
+    }
+
+    /// Returns the byte offset of `block` within the filesystem.
+    fn get_block_offset(&self, block: u64) -> Result<u64, VmError> {
+        match block.checked_mul(self.block_size) {
+            Some(offset) => Ok(offset),
+            None => Err(VmError::image(format!("ext block {} is out of range", block)))
+        }
+    }
+
+    /// Reads an inode from the inode table of its block group.
+    fn read_inode(&mut self, number: u32) -> Result<Inode, VmError> {
+        if number == 0 {
+            return Err(VmError::image("invalid ext inode number 0"));
+        }
+        let group = (number as u64 - 1) / self.inodes_per_group;
+        let index = (number as u64 - 1) % self.inodes_per_group;
+        let mut descriptor = vec![0u8; self.descriptor_size as usize];
+        self.read(self.descriptor_table + group * self.descriptor_size, &mut descriptor)?;
+        let mut table = u32::from_le_bytes(descriptor[8..12].try_into().unwrap()) as u64;
+        if self.is_64bit && self.descriptor_size >= 64 {
+            table |= (u32::from_le_bytes(descriptor[40..44].try_into().unwrap()) as u64) << 32;
+        }
+
+        let mut raw = [0u8; 128];
+        let offset = match self.get_block_offset(table)?.checked_add(index * self.inode_size) {
+            Some(offset) => offset,
+            None => return Err(VmError::image(format!("ext inode table at block {} is out of range", table)))
+        };
+        self.read(offset, &mut raw)?;
+        let le32 = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        Ok(Inode {
+            mode: u16::from_le_bytes([raw[0], raw[1]]),
+            size: le32(4) as u64 | (le32(108) as u64) << 32,
+            flags: le32(32),
+            block: raw[40..100].try_into().unwrap(),
+        })
+    }
+
+    /// Parses the entries of a directory inode.
+    fn read_dir_inode(&mut self, inode: &Inode) -> Result<Vec<DirEntry>, VmError> {
+        let data = self.read_inode_data(inode)?;
+        let mut entries = Vec::new();
+        let mut position = 0;
+        while position + 8 <= data.len() {
+            let number = u32::from_le_bytes(data[position..position + 4].try_into().unwrap());
+            let record_len = u16::from_le_bytes([data[position + 4], data[position + 5]]) as usize;
+            let name_len = data[position + 6] as usize;
+            if record_len < 8 || position + 8 + name_len > data.len() {
+                break;
+            }
+            let name = String::from_utf8_lossy(&data[position + 8..position + 8 + name_len]).into_owned();
+            // Unused records and checksum tails have no inode
+            if number != 0 && name != "." && name != ".." {
+                let kind = match data[position + 7] {
+                    1 => EntryKind::File,
+                    2 => EntryKind::Directory,
+                    7 => EntryKind::Symlink,
+                    0 => get_entry_kind(self.read_inode(number)?.mode),
+                    _ => EntryKind::Other,
+                };
+                entries.push(DirEntry { name, inode: number, kind });
+            }
+            position += record_len;
+        }
+        Ok(entries)
+    }
+
+    /// Reads the whole data of an inode; holes and unwritten extents read as zeroes.
+    fn read_inode_data(&mut self, inode: &Inode) -> Result<Vec<u8>, VmError> {
+        if inode.size > MAX_FILE_SIZE {
+            return Err(VmError::image(format!("file of {} bytes is too large to read", inode.size)));
+        }
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            return Err(VmError::image("ext inline data is not supported"));
+        }
+        let mut map = RunMap::new(inode.size.div_ceil(self.block_size));
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.map_extents(&inode.block, MAX_EXTENT_DEPTH, &mut map)?;
+        } else {
+            for (index, pointer) in inode.block.chunks_exact(4).enumerate() {
+                let block = u32::from_le_bytes(pointer.try_into().unwrap()) as u64;
+                match index {
+                    0..=11 => self.map_indirect(block, 0, index as u64, &mut map)?,
+                    12 => self.map_indirect(block, 1, 12, &mut map)?,
+                    13 => self.map_indirect(block, 2, 12 + self.block_size / 4, &mut map)?,
+                    _ => self.map_indirect(block, 3, 12 + self.block_size / 4 + (self.block_size / 4).pow(2), &mut map)?,
+                }
+            }
+        }
+
+        let mut data = vec![0u8; inode.size as usize];
+        for (logical, physical, len) in map.runs {
+            let start = logical * self.block_size;
+            let end = (start + len * self.block_size).min(inode.size);
+            let offset = self.get_block_offset(physical)?;
+            self.read(offset, &mut data[start as usize..end as usize])?;
+        }
+        Ok(data)
+    }
+
+    /// Collects the runs of an extent tree node, descending at most `depth` levels.
+    fn map_extents(&mut self, node: &[u8], depth: u16, map: &mut RunMap) -> Result<(), VmError> {
+        let le16 = |at: usize| u16::from_le_bytes([node[at], node[at + 1]]);
+        let le32 = |at: usize| u32::from_le_bytes(node[at..at + 4].try_into().unwrap());
+        if node.len() < 12 || le16(0) != EXTENT_MAGIC || le16(6) > depth {
+            return Err(VmError::image("corrupt ext extent tree"));
+        }
+        let entries = (le16(2) as usize).min((node.len() - 12) / 12);
+        for entry in 0..entries {
+            let at = 12 + 12 * entry;
+            if le16(6) == 0 {
+                let len = le16(at + 4);
+                // Lengths above 32768 mark unwritten extents, which read as zeroes
+                if len <= 32768 {
+                    let physical = (le16(at + 6) as u64) << 32 | le32(at + 8) as u64;
+                    map.push(le32(at) as u64, physical, len as u64)?;
+                }
+            } else if (le32(at) as u64) < map.blocks {
+                map.take_read()?;
+                let child = (le16(at + 8) as u64) << 32 | le32(at + 4) as u64;
+                let mut block = vec![0u8; self.block_size as usize];
+                let offset = self.get_block_offset(child)?;
+                self.read(offset, &mut block)?;
+                self.map_extents(&block, le16(6) - 1, map)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the runs of a block map pointer at `level` of indirection, mapping
+    /// logical blocks from `logical`.
+    fn map_indirect(&mut self, block: u64, level: u32, logical: u64, map: &mut RunMap) -> Result<(), VmError> {
+        if block == 0 || logical >= map.blocks {
+            return Ok(());
+        }
+        if level == 0 {
+            return map.push(logical, block, 1);
+        }
+        map.take_read()?;
+        let mut pointers = vec![0u8; self.block_size as usize];
+        let offset = self.get_block_offset(block)?;
+        self.read(offset, &mut pointers)?;
+        let span = (self.block_size / 4).pow(level - 1);
+        for (index, pointer) in pointers.chunks_exact(4).enumerate() {
+            let pointer = u32::from_le_bytes(pointer.try_into().unwrap()) as u64;
+            self.map_indirect(pointer, level - 1, logical + index as u64 * span, map)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the kind of an inode from its mode.
+fn get_entry_kind(mode: u16) -> EntryKind {
+    match mode & 0xf000 {
+        0x8000 => EntryKind::File,
+        0x4000 => EntryKind::Directory,
+        0xa000 => EntryKind::Symlink,
+        _ => EntryKind::Other,
+    }
+}
+
+/// Compares file names the way version numbers sort, so `vmlinuz-6.10` comes after
+/// `vmlinuz-6.9`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|byte| byte.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|byte| byte.is_ascii_digit()).count();
+                let (a_digits, b_digits) = (trim_zeroes(&a[..a_len]), trim_zeroes(&b[..b_len]));
+                let ordering = a_digits.len().cmp(&b_digits.len()).then(a_digits.cmp(b_digits));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (&a[a_len..], &b[b_len..]);
+            },
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                (a, b) = (&a[1..], &b[1..]);
+            },
+        }
+    }
+}
+
+/// Strips leading zeroes off a run of digits.
+fn trim_zeroes(digits: &[u8]) -> &[u8] {
+    let zeroes = digits.iter().take_while(|&&digit| digit == b'0').count();
+    &digits[zeroes..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::process::Command;
+    use crate::device_emulation::block_device::disk_backend::FileBackend;
+
+    // Helper: builds a filesystem image from a directory with mkfs, or returns None
+    // when e2fsprogs isn't installed
+    fn make_filesystem(dir: &std::path::Path, mkfs: &str, args: &[&str]) -> Option<Vec<u8>> {
+        let image = dir.join(format!("{}.img", mkfs));
+        File::create(&image).unwrap().set_len(8 << 20).unwrap();
+        let output = Command::new(mkfs).args(["-q", "-F"]).args(args).arg("-d").arg(dir.join("root")).arg(&image).output();
+        if !output.is_ok_and(|output| output.status.success()) {
+            return None;
+        }
+        Some(std::fs::read(&image).unwrap())
+    }
+
+    // Helper: files of the test filesystems
+    fn populate(dir: &std::path::Path) -> Vec<u8> {
+        let large: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::create_dir_all(dir.join("root/boot/grub")).unwrap();
+        std::fs::write(dir.join("root/boot/vmlinuz-6.1.0-10"), &large).unwrap();
+        std::fs::write(dir.join("root/boot/config"), b"CONFIG_VIRTIO=y\n").unwrap();
+        std::os::unix::fs::symlink("boot/vmlinuz-6.1.0-10", dir.join("root/vmlinuz")).unwrap();
+        large
+    }
+
+    // Helper: writes `image` at `offset` of a new raw disk
+    fn make_disk(dir: &std::path::Path, header: &[u8], offset: usize, image: &[u8]) -> FileBackend {
+        let mut disk = vec![0u8; offset + image.len()];
+        disk[..header.len()].copy_from_slice(header);
+        disk[offset..].copy_from_slice(image);
+        let path = dir.join("disk.img");
+        std::fs::write(&path, &disk).unwrap();
+        FileBackend::open(&path).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_ext_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let large = populate(dir.path());
+        // 1 KiB blocks put the large file behind double indirect blocks
+        for (mkfs, args) in [("mkfs.ext2", &["-b", "1024"][..]), ("mkfs.ext4", &[][..])] {
+            let Some(image) = make_filesystem(dir.path(), mkfs, args) else {
+                return;
+            };
+            let mut disk = make_disk(dir.path(), &[], 0, &image);
+            let partitions = list_partitions(&mut disk).unwrap();
+            assert_eq!(partitions, vec![Partition { offset: 0, size: 8 << 20 }]);
+            let mut filesystem = ExtFilesystem::open(&mut disk, partitions[0]).unwrap().expect("ext filesystem");
+
+            let mut names: Vec<(String, EntryKind)> = filesystem.read_dir("/boot").unwrap().unwrap()
+                .into_iter().map(|entry| (entry.name, entry.kind)).collect();
+            names.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(names, [
+                ("config".to_string(), EntryKind::File),
+                ("grub".to_string(), EntryKind::Directory),
+                ("vmlinuz-6.1.0-10".to_string(), EntryKind::File),
+            ], "{}", mkfs);
+            assert_eq!(filesystem.read_file("/boot/vmlinuz-6.1.0-10").unwrap().unwrap(), large, "{}", mkfs);
+            assert_eq!(filesystem.read_file("/boot/config").unwrap().unwrap(), b"CONFIG_VIRTIO=y\n");
+            assert!(filesystem.read_file("/vmlinuz").unwrap().is_none());
+            assert!(filesystem.read_file("/boot/missing").unwrap().is_none());
+            assert!(filesystem.read_dir("/boot/config").unwrap().is_none());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_partition_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        populate(dir.path());
+        let Some(image) = make_filesystem(dir.path(), "mkfs.ext4", &[]) else {
+            return;
+        };
+
+        // MBR with a Linux partition at 1 MiB
+        let mut mbr = vec![0u8; 512];
+        mbr[446 + 4] = 0x83;
+        mbr[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&((8 << 20) / 512u32).to_le_bytes());
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+        let mut disk = make_disk(dir.path(), &mbr, 1 << 20, &image);
+        let partitions = list_partitions(&mut disk).unwrap();
+        assert_eq!(partitions, vec![Partition { offset: 1 << 20, size: 8 << 20 }]);
+        assert!(ExtFilesystem::open(&mut disk, partitions[0]).unwrap().is_some());
+
+        // GPT with an empty entry, a BIOS boot partition and the filesystem
+        let mut gpt = vec![0u8; 2048 + 3 * 128];
+        gpt[446 + 4] = 0xee;
+        gpt[510..512].copy_from_slice(&[0x55, 0xaa]);
+        gpt[512..520].copy_from_slice(b"EFI PART");
+        gpt[512 + 72..512 + 80].copy_from_slice(&4u64.to_le_bytes());
+        gpt[512 + 80..512 + 84].copy_from_slice(&3u32.to_le_bytes());
+        gpt[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        for (entry, first, last) in [(1usize, 34u64, 2047u64), (2, 2048, 2048 + (8 << 20) / 512 - 1)] {
+            let at = 2048 + entry * 128;
+            gpt[at] = 0x42;
+            gpt[at + 32..at + 40].copy_from_slice(&first.to_le_bytes());
+            gpt[at + 40..at + 48].copy_from_slice(&last.to_le_bytes());
+        }
+        let mut disk = make_disk(dir.path(), &gpt, 1 << 20, &image);
+        let partitions = list_partitions(&mut disk).unwrap();
+        assert_eq!(partitions, vec![Partition { offset: 34 * 512, size: 2014 * 512 }, Partition { offset: 1 << 20, size: 8 << 20 }]);
+        assert!(ExtFilesystem::open(&mut disk, partitions[0]).unwrap().is_none());
+        let mut filesystem = ExtFilesystem::open(&mut disk, partitions[1]).unwrap().unwrap();
+        assert!(filesystem.read_dir("/boot/grub").unwrap().unwrap().is_empty());
+    }
+
+    // Helper: extent tree whose index blocks 1 to 4 point every entry at the next block,
+    // above a full leaf in block 5 mapping logical block `logical`; it expands into 84^5
+    // runs if followed
+    fn make_crafted_extent_tree(logical: u32) -> (Vec<u8>, [u8; 60]) {
+        let mut image = vec![0u8; 6 * 1024];
+        for level in 1..=5u16 {
+            let node = &mut image[level as usize * 1024..(level as usize + 1) * 1024];
+            node[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+            node[2..4].copy_from_slice(&84u16.to_le_bytes());
+            node[6..8].copy_from_slice(&(5 - level).to_le_bytes());
+            for at in (12..1020).step_by(12) {
+                if level == 5 {
+                    node[at..at + 4].copy_from_slice(&logical.to_le_bytes());
+                    node[at + 4..at + 6].copy_from_slice(&1u16.to_le_bytes());
+                    node[at + 8..at + 12].copy_from_slice(&5u32.to_le_bytes());
+                } else {
+                    node[at + 4..at + 8].copy_from_slice(&(level as u32 + 1).to_le_bytes());
+                }
+            }
+        }
+        let mut root = [0u8; 60];
+        root[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        root[2..4].copy_from_slice(&1u16.to_le_bytes());
+        root[6..8].copy_from_slice(&5u16.to_le_bytes());
+        root[16..20].copy_from_slice(&1u32.to_le_bytes());
+        (image, root)
+    }
+
+    #[test]
+    fn test_crafted_extent_trees_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        // Runs within the file, then runs past its end that only cost block reads
+        for logical in [0, 100] {
+            let (image, root) = make_crafted_extent_tree(logical);
+            let mut disk = make_disk(dir.path(), &[], 0, &image);
+            let mut filesystem = ExtFilesystem {
+                disk: &mut disk,
+                offset: 0,
+                block_size: 1024,
+                inodes_per_group: 1,
+                inode_size: 128,
+                descriptor_size: 32,
+                descriptor_table: 0,
+                is_64bit: false,
+            };
+            let inode = Inode { mode: 0x8000, size: 4096, flags: INODE_FLAG_EXTENTS, block: root };
+            assert!(filesystem.read_inode_data(&inode).is_err(), "leaf extents at block {}", logical);
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_block_numbers_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        // Group descriptor with a 64-bit inode table block overflowing the byte offset
+        let mut descriptor = [0u8; 64];
+        descriptor[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        descriptor[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut disk = make_disk(dir.path(), &descriptor, 4096, &[]);
+        let mut filesystem = ExtFilesystem {
+            disk: &mut disk,
+            offset: 0,
+            block_size: 65536,
+            inodes_per_group: 1,
+            inode_size: 128,
+            descriptor_size: 64,
+            descriptor_table: 0,
+            is_64bit: true,
+        };
+        assert!(filesystem.read_inode(1).is_err());
+
+        // A leaf extent at the last 48-bit physical block, past the end of the disk
+        // once the offset of the filesystem is added
+        filesystem.offset = 1 << 20;
+        let mut root = [0u8; 60];
+        root[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        root[2..4].copy_from_slice(&1u16.to_le_bytes());
+        root[16..18].copy_from_slice(&1u16.to_le_bytes());
+        root[18..20].copy_from_slice(&0xffffu16.to_le_bytes());
+        root[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        let inode = Inode { mode: 0x8000, size: 4096, flags: INODE_FLAG_EXTENTS, block: root };
+        assert!(filesystem.read_inode_data(&inode).is_err());
+
+        // A GPT entry whose sectors overflow the byte offset and size
+        let mut gpt = vec![0u8; 2048 + 128];
+        gpt[446 + 4] = 0xee;
+        gpt[510..512].copy_from_slice(&[0x55, 0xaa]);
+        gpt[512..520].copy_from_slice(b"EFI PART");
+        gpt[512 + 72..512 + 80].copy_from_slice(&4u64.to_le_bytes());
+        gpt[512 + 80..512 + 84].copy_from_slice(&1u32.to_le_bytes());
+        gpt[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        gpt[2048] = 0x42;
+        gpt[2048 + 40..2048 + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut disk = make_disk(dir.path(), &gpt, gpt.len(), &[]);
+        assert!(list_partitions(&mut disk).is_err());
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("vmlinuz-6.10.0", "vmlinuz-6.9.0"), Ordering::Greater);
+        assert_eq!(compare_versions("vmlinuz-6.1.0-9", "vmlinuz-6.1.0-10"), Ordering::Less);
+        assert_eq!(compare_versions("vmlinuz-5.4", "vmlinuz-5.04"), Ordering::Equal);
+        assert_eq!(compare_versions("vmlinuz", "vmlinuz-6.1"), Ordering::Less);
+    }
+}
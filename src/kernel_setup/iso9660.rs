@@ -0,0 +1,279 @@
+//! Read-only access to the files of ISO 9660 images, such as installer CD-ROMs.
+//!
+//! `IsoFilesystem` reads the primary volume descriptor, directories and single-extent
+//! files, which is enough to pick the kernel and initrd off installation media.
+//! Rock Ridge names are used when the image has them; plain ISO 9660 names are
+//! matched without their `;1` version suffix. Names always match case-insensitively.
+
+use crate::error::VmError;
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, SECTOR_SIZE};
+use super::guest_fs::read_disk_bytes;
+
+/// Size of ISO 9660 logical blocks.
+const LOGICAL_BLOCK_SIZE: u64 = 2048;
+/// Byte offset of the first volume descriptor; the system area comes before it.
+const VOLUME_DESCRIPTORS_OFFSET: u64 = 16 * LOGICAL_BLOCK_SIZE;
+/// Volume descriptors looked through for the primary one.
+const MAX_VOLUME_DESCRIPTORS: u64 = 32;
+/// Type of the primary volume descriptor.
+const PRIMARY_VOLUME_DESCRIPTOR: u8 = 1;
+/// Type of the descriptor ending the set.
+const VOLUME_DESCRIPTOR_TERMINATOR: u8 = 255;
+/// File flag: the record describes a directory.
+const FLAG_DIRECTORY: u8 = 0x02;
+/// Largest file `read_file` loads into memory.
+const MAX_FILE_SIZE: u64 = 1 << 30;
+/// Largest directory read; installer media have a few hundred entries per directory.
+const MAX_DIRECTORY_SIZE: u64 = 16 << 20;
+
+/// Directory record of a file or directory.
+#[derive(Debug, Clone)]
+struct IsoRecord {
+    name: String,
+    /// Byte offset of the data on the disk
+    offset: u64,
+    size: u64,
+    is_directory: bool,
+}
+
+/// Read-only ISO 9660 filesystem spanning a whole disk.
+pub struct IsoFilesystem<'a> {
+    disk: &'a mut dyn DiskBackend,
+    root: IsoRecord,
+}
+
+impl<'a> IsoFilesystem<'a> {
+    /// Opens the ISO 9660 filesystem of `disk`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(IsoFilesystem))` if the disk holds an ISO 9660 filesystem
+    /// * `Ok(None)` if it holds something else
+    /// * `Err(VmError)` if the disk couldn't be read or the root directory is malformed
+    pub fn open(disk: &'a mut dyn DiskBackend) -> Result<Option<IsoFilesystem<'a>>, VmError> {
+        let disk_size = disk.capacity() * SECTOR_SIZE;
+        let mut descriptor = [0u8; LOGICAL_BLOCK_SIZE as usize];
+        for index in 0..MAX_VOLUME_DESCRIPTORS {
+            let offset = VOLUME_DESCRIPTORS_OFFSET + index * LOGICAL_BLOCK_SIZE;
+            if offset + LOGICAL_BLOCK_SIZE > disk_size {
+                return Ok(None);
+            }
+            read_disk_bytes(disk, offset, &mut descriptor)?;
+            if &descriptor[1..6] != b"CD001" {
+                return Ok(None);
+            }
+            match descriptor[0] {
+                PRIMARY_VOLUME_DESCRIPTOR => {
+                    let root = match parse_record(&descriptor[156..190]) {
+                        Some(root) if root.is_directory => root,
+                        _ => return Err(VmError::image("ISO 9660 root directory record is malformed"))
+                    };
+                    return Ok(Some(IsoFilesystem { disk, root }));
+                },
+                VOLUME_DESCRIPTOR_TERMINATOR => return Ok(None),
+                _ => {},
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the regular file at the absolute `path`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(data))` if `path` is a file
+    /// * `Ok(None)` if it doesn't exist or is a directory
+    /// * `Err(VmError)` if the filesystem couldn't be read or the file is too large
+    pub fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>, VmError> {
+        let mut record = self.root.clone();
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !record.is_directory {
+                return Ok(None);
+            }
+            match self.read_dir(&record)?.into_iter().find(|entry| entry.name.eq_ignore_ascii_case(component)) {
+                Some(entry) => record = entry,
+                None => return Ok(None),
+            }
+        }
+        if record.is_directory {
+            return Ok(None);
+        }
+        if record.size > MAX_FILE_SIZE {
+            return Err(VmError::image(format!("{} is too large to be loaded ({} bytes)", path, record.size)));
+        }
+        let mut data = vec![0u8; record.size as usize];
+        read_disk_bytes(self.disk, record.offset, &mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Lists the records of a directory, without `.` and `..`.
+    fn read_dir(&mut self, directory: &IsoRecord) -> Result<Vec<IsoRecord>, VmError> {
+        if directory.size > MAX_DIRECTORY_SIZE {
+            return Err(VmError::image(format!("ISO 9660 directory of {} bytes is too large", directory.size)));
+        }
+        let mut data = vec![0u8; directory.size as usize];
+        read_disk_bytes(self.disk, directory.offset, &mut data)?;
+
+        let mut records = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let length = data[position] as usize;
+            // Records don't cross block boundaries: the rest of the block is padding
+            if length == 0 {
+                position = (position / LOGICAL_BLOCK_SIZE as usize + 1) * LOGICAL_BLOCK_SIZE as usize;
+                continue;
+            }
+            let record = match data.get(position..position + length).and_then(parse_record) {
+                Some(record) => record,
+                None => return Err(VmError::image(format!("malformed ISO 9660 directory record at offset {}", directory.offset + position as u64)))
+            };
+            if record.name != "." && record.name != ".." {
+                records.push(record);
+            }
+            position += length;
+        }
+        Ok(records)
+    }
+}
+
+/// Parses a directory record.
+///
+/// # Returns
+/// * `Some(IsoRecord)` with the Rock Ridge name if there is one
+/// * `None` if the record is truncated
+fn parse_record(record: &[u8]) -> Option<IsoRecord> {
+    let name_length = *record.get(32)? as usize;
+    let name = record.get(33..33 + name_length)?;
+    let offset = u32::from_le_bytes(record[2..6].try_into().unwrap()) as u64 * LOGICAL_BLOCK_SIZE;
+    let size = u32::from_le_bytes(record[10..14].try_into().unwrap()) as u64;
+    let is_directory = record[25] & FLAG_DIRECTORY != 0;
+
+    // The system use area follows the name, padded to an even offset
+    let system_use = record.get(33 + name_length + (1 - name_length % 2)..).unwrap_or(&[]);
+    let name = match (name, get_rock_ridge_name(system_use)) {
+        ([0], _) => ".".to_string(),
+        ([1], _) => "..".to_string(),
+        (_, Some(name)) => name,
+        (name, None) => {
+            let name = String::from_utf8_lossy(name);
+            let name = name.split(';').next().unwrap_or_default();
+            // Files without an extension are recorded with a trailing dot
+            name.strip_suffix('.').unwrap_or(name).to_string()
+        },
+    };
+    Some(IsoRecord { name, offset, size, is_directory })
+}
+
+/// Returns the name held by the Rock Ridge `NM` entries of a system use area, if any.
+fn get_rock_ridge_name(system_use: &[u8]) -> Option<String> {
+    let mut name = Vec::new();
+    let mut position = 0;
+    while position + 4 <= system_use.len() {
+        let length = system_use[position + 2] as usize;
+        if length < 4 || position + length > system_use.len() {
+            break;
+        }
+        if &system_use[position..position + 2] == b"NM" && length >= 5 {
+            name.extend_from_slice(&system_use[position + 5..position + length]);
+            // Without the continue flag the name is complete
+            if system_use[position + 4] & 0x01 == 0 {
+                return Some(String::from_utf8_lossy(&name).into_owned());
+            }
+        }
+        position += length;
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::device_emulation::block_device::disk_backend::FileBackend;
+
+    // Helper: directory record pointing at `block`, with an optional Rock Ridge name
+    fn record(name: &[u8], block: u32, size: u32, is_directory: bool, rock_ridge_name: Option<&str>) -> Vec<u8> {
+        let mut record = vec![0u8; 33];
+        record[2..6].copy_from_slice(&block.to_le_bytes());
+        record[6..10].copy_from_slice(&block.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = if is_directory { FLAG_DIRECTORY } else { 0 };
+        record[32] = name.len() as u8;
+        record.extend_from_slice(name);
+        if name.len().is_multiple_of(2) {
+            record.push(0);
+        }
+        if let Some(rock_ridge_name) = rock_ridge_name {
+            record.extend_from_slice(&[b'N', b'M', 5 + rock_ridge_name.len() as u8, 1, 0]);
+            record.extend_from_slice(rock_ridge_name.as_bytes());
+        }
+        if record.len() % 2 == 1 {
+            record.push(0);
+        }
+        record[0] = record.len() as u8;
+        record
+    }
+
+    // Helper: ISO image holding `files`, each given as a directory, a plain ISO 9660
+    // name, an optional Rock Ridge name and the content
+    pub(crate) fn make_iso(files: &[(&str, &str, Option<&str>, &[u8])]) -> Vec<u8> {
+        let block = LOGICAL_BLOCK_SIZE as usize;
+        let mut directories: Vec<&str> = files.iter().map(|file| file.0).collect();
+        directories.dedup();
+        // Blocks: 16 primary descriptor, 17 terminator, 18 root, then one per
+        // directory, then the file data
+        let directory_block = |index: usize| 19 + index as u32;
+        let mut data_block = 19 + directories.len() as u32;
+        let mut image = vec![0u8; block * data_block as usize];
+
+        let mut root = record(&[0], 18, block as u32, true, None);
+        root.extend(record(&[1], 18, block as u32, true, None));
+        for (index, directory) in directories.iter().enumerate() {
+            root.extend(record(directory.to_ascii_uppercase().as_bytes(), directory_block(index), block as u32, true, None));
+            let mut listing = record(&[0], directory_block(index), block as u32, true, None);
+            listing.extend(record(&[1], 18, block as u32, true, None));
+            for (_, name, rock_ridge_name, content) in files.iter().filter(|file| file.0 == *directory) {
+                listing.extend(record(name.as_bytes(), data_block, content.len() as u32, false, *rock_ridge_name));
+                let start = data_block as usize * block;
+                image.resize(start + content.len().div_ceil(block) * block, 0);
+                image[start..start + content.len()].copy_from_slice(content);
+                data_block += content.len().div_ceil(block) as u32;
+            }
+            let start = directory_block(index) as usize * block;
+            image[start..start + listing.len()].copy_from_slice(&listing);
+        }
+        image[18 * block..18 * block + root.len()].copy_from_slice(&root);
+
+        let primary = 16 * block;
+        image[primary] = PRIMARY_VOLUME_DESCRIPTOR;
+        image[primary + 1..primary + 6].copy_from_slice(b"CD001");
+        let root_record = record(&[0], 18, block as u32, true, None);
+        image[primary + 156..primary + 156 + root_record.len()].copy_from_slice(&root_record);
+        image[17 * block] = VOLUME_DESCRIPTOR_TERMINATOR;
+        image[17 * block + 1..17 * block + 6].copy_from_slice(b"CD001");
+        image
+    }
+
+    #[test]
+    fn test_read_iso_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let kernel: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let image = make_iso(&[
+            ("boot", "VMLINUZ_.;1", Some("vmlinuz-virt"), &kernel),
+            ("boot", "CONFIG.;1", None, b"CONFIG_VIRTIO=y\n"),
+        ]);
+        let path = dir.path().join("alpine.iso");
+        std::fs::write(&path, &image).unwrap();
+        let mut disk = FileBackend::open(&path).unwrap();
+        let mut filesystem = IsoFilesystem::open(&mut disk).unwrap().expect("ISO 9660 filesystem");
+
+        assert_eq!(filesystem.read_file("/boot/vmlinuz-virt").unwrap(), Some(kernel));
+        assert_eq!(filesystem.read_file("/BOOT/config").unwrap(), Some(b"CONFIG_VIRTIO=y\n".to_vec()));
+        assert_eq!(filesystem.read_file("/boot").unwrap(), None);
+        assert_eq!(filesystem.read_file("/boot/initramfs-virt").unwrap(), None);
+        assert_eq!(filesystem.read_file("/boot/config/x").unwrap(), None);
+
+        // Disks without the descriptor aren't ISOs
+        std::fs::write(&path, vec![0u8; 64 << 10]).unwrap();
+        let mut disk = FileBackend::open(&path).unwrap();
+        assert!(IsoFilesystem::open(&mut disk).unwrap().is_none());
+    }
+}
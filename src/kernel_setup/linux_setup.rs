@@ -1,115 +1,240 @@
-use tempfile::TempDir;
-use std::fs::{read, create_dir, read_dir};
-use std::process::Command;
+use std::fs::File;
+use std::path::Path;
+use super::guest_fs::{compare_versions, list_partitions, DirEntry, EntryKind, ExtFilesystem};
+use super::iso9660::IsoFilesystem;
 use super::setup_utils::KernelComponents;
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, FileBackend};
+use crate::device_emulation::block_device::qcow2::Qcow2Backend;
+use crate::error::VmError;
+use crate::image::format::{detect_image_format, ImageFormat};
+
+/// Directories searched for kernels, in order; the root directory covers a separate
+/// boot partition.
+const BOOT_DIRECTORIES: [&str; 2] = ["/boot", "/"];
+
+/// Kernels of installation and live media, in order, each with the initrds that may
+/// go with it: Ubuntu, Debian installer and live images, Fedora and Rocky, Alpine,
+/// openSUSE, then the generic isolinux layout.
+const ISO_KERNELS: [(&str, &[&str]); 9] = [
+    ("/casper/vmlinuz", &["/casper/initrd", "/casper/initrd.gz", "/casper/initrd.lz"]),
+    ("/install.amd/vmlinuz", &["/install.amd/initrd.gz"]),
+    ("/install.a64/vmlinuz", &["/install.a64/initrd.gz"]),
+    ("/live/vmlinuz", &["/live/initrd.img"]),
+    ("/images/pxeboot/vmlinuz", &["/images/pxeboot/initrd.img"]),
+    ("/boot/vmlinuz-virt", &["/boot/initramfs-virt"]),
+    ("/boot/x86_64/loader/linux", &["/boot/x86_64/loader/initrd"]),
+    ("/boot/aarch64/loader/linux", &["/boot/aarch64/loader/initrd"]),
+    ("/isolinux/vmlinuz", &["/isolinux/initrd.img", "/isolinux/initrd.gz"]),
+];
 
 /// Extracts kernel components (vmlinuz and optionally initrd) from a QCOW2 disk image.
 ///
-/// This function uses `guestmount` to mount the QCOW2 image and then looks for kernel
-/// files under the `/boot` directory. It returns a `KernelComponents` struct containing
-/// the raw bytes of the kernel and optionally the initrd image.
+/// The image is read natively, without mounting it: see `extract_kernel_components`
+/// for how the files are found.
 ///
 /// # Arguments
 /// * `qcow2_path` - Path to the `.qcow2` disk image file.
 ///
 /// # Returns
 /// * `Ok(KernelComponents)` - On success, contains the loaded kernel and optionally initrd.
-/// * `Err(String)` - If any step fails, returns a descriptive error message.
-pub fn extract_kernel_components_from_qcow2(qcow2_path: &str) -> Result<KernelComponents, String> {
-    // Create a temporary directory to mount the image
-    let temp_dir = match TempDir::new() {
-        Ok(d) => d,
-        Err(_) => return Err(format!("failed during temp_dir creation"))
-    };
+/// * `Err(VmError)` - If any step fails, returns a descriptive error.
+pub fn extract_kernel_components_from_qcow2(qcow2_path: &str) -> Result<KernelComponents, VmError> {
+    let mut disk = Qcow2Backend::open(Path::new(qcow2_path), true)?;
+    extract_kernel_components(&mut disk)
+}
 
-    // Create a mount point inside the temp directory
-    let mount_dir = temp_dir.path().join("mount");
-    if let Err(e) = create_dir(&mount_dir) {
-        return Err(format!("{:?}", e));
-    };
-
-    // Convert mount path to a string slice
-    let mount_str = match mount_dir.to_str() {
-        Some(s) => s,
-        None => return Err("failed during converting mount of type DirEntry to &str".to_string())
-    };
+/// Extracts kernel components from a raw or qcow2 disk image.
+///
+/// The format is detected from the image's content, so a qcow2 image named `.img`
+/// is read as qcow2; see `extract_kernel_components` for how the files are found.
+///
+/// # Arguments
+/// * `path` - Path to the disk image file.
+///
+/// # Returns
+/// * `Ok(KernelComponents)` - On success, contains the loaded kernel and optionally initrd.
+/// * `Err(VmError)` - If the image has another format or any step fails.
+pub fn extract_kernel_components_from_image(path: &str) -> Result<KernelComponents, VmError> {
+    let mut disk = open_disk_image(path)?;
+    extract_kernel_components(disk.as_mut())
+}
 
-    // Use guestmount to mount the qcow2 image at the mount point
-    let auto_mount_exit_status = match Command::new("guestmount")
-        .args(&["-a", qcow2_path, "-i", mount_str])
-        .output() {
-        Ok(s) => s,
-        Err(e) => return Err(format!("{:?}", e))
-    };
+/// Extracts the `System.map` of the kernel `extract_kernel_components_from_image` picks
+/// from a raw or qcow2 disk image, for `kernel_introspection::KernelSymbols`.
+///
+/// # Arguments
+/// * `path` - Path to the disk image file.
+///
+/// # Returns
+/// * `Ok(Some(String))` - The symbol map of the kernel.
+/// * `Ok(None)` - If the kernel has no `System.map` next to it.
+/// * `Err(VmError)` - If the image has another format, holds no kernel or couldn't be read.
+pub fn extract_system_map_from_image(path: &str) -> Result<Option<String>, VmError> {
+    let mut disk = open_disk_image(path)?;
+    extract_system_map(disk.as_mut())
+}
 
-    // Check if guestmount succeeded
-    if !auto_mount_exit_status.status.success() {
-        return Err(format!(
-            "guestmount failed with stderr: {}",
-            String::from_utf8_lossy(&auto_mount_exit_status.stderr)
-        ));
+/// Opens a raw or qcow2 disk image read-only, detecting the format from its content.
+fn open_disk_image(path: &str) -> Result<Box<dyn DiskBackend>, VmError> {
+    match detect_image_format(Path::new(path))? {
+        ImageFormat::Qcow2 => Ok(Box::new(Qcow2Backend::open(Path::new(path), true)?)),
+        ImageFormat::Raw => match File::open(path) {
+            Ok(file) => Ok(Box::new(FileBackend::from_file(file)?)),
+            Err(e) => Err(VmError::io(format!("failed to open disk image {}: {}", path, e), e))
+        },
+        format => Err(VmError::image(format!("can't look for a kernel in {:?} image {}", format, path)))
     }
+}
 
-    // Construct path to the /boot directory inside the mounted image
-    let boot_dir = mount_dir.as_path().join("boot");
-    let path_to_boot_dir = match boot_dir.as_path().to_str() {
-        Some(p) => p,
-        None => return Err("failed during accessing boot directory".to_string())
+/// Extracts kernel components from an installation or live ISO image.
+///
+/// The kernel is looked up at the paths distributions put it on their media, see
+/// `ISO_KERNELS`; the first one present wins, along with its initrd if there is one.
+///
+/// # Arguments
+/// * `path` - Path to the `.iso` image file.
+///
+/// # Returns
+/// * `Ok(KernelComponents)` - On success, contains the loaded kernel and optionally initrd.
+/// * `Err(VmError)` - If the image isn't an ISO 9660 image, holds no known kernel or
+///   couldn't be read.
+pub fn extract_kernel_components_from_iso(path: &str) -> Result<KernelComponents, VmError> {
+    let mut disk = match File::open(path) {
+        Ok(file) => FileBackend::from_file(file)?,
+        Err(e) => return Err(VmError::io(format!("failed to open ISO image {}: {}", path, e), e))
     };
-
-    // Read entries inside /boot to locate kernel and initrd files
-    let boot_entries = match read_dir(path_to_boot_dir) {
-        Ok(e) => e,
-        Err(e) => return Err(format!("failed during fetching entries conatined in boot directory"))
+    let mut filesystem = match IsoFilesystem::open(&mut disk)? {
+        Some(filesystem) => filesystem,
+        None => return Err(VmError::image(format!("{} is not an ISO 9660 image", path)))
     };
+    for (kernel_path, initrd_paths) in ISO_KERNELS {
+        let kernel = match filesystem.read_file(kernel_path)? {
+            Some(kernel) => kernel,
+            None => continue
+        };
+        let mut initrd = None;
+        for initrd_path in initrd_paths {
+            initrd = filesystem.read_file(initrd_path)?;
+            if initrd.is_some() {
+                break;
+            }
+        }
+        return Ok(KernelComponents { kernel, initrd });
+    }
+    Err(VmError::image(format!("no kernel found at the known locations of {}", path)))
+}
 
-    let mut path_to_vmlinuz_file: Option<String> = None;
-    let mut path_to_initrd_file: Option<String> = None;
-
-    // Loop over all files in /boot to find vmlinuz and initrd.img
-    for entry_res in boot_entries {
-        let entry = match entry_res {
-            Ok(e) => e,
-            Err(e) => return Err(format!("{:?}", e)),
+/// Extracts kernel components from any disk.
+///
+/// Looks through the ext filesystems of the disk for regular files named `vmlinuz*`
+/// under `/boot`, or at the root of a separate boot partition, and picks the newest
+/// version. The initrd is the `initrd.img`, `initrd` or `initramfs` file of the same
+/// version, if there is one.
+///
+/// # Arguments
+/// * `disk` - Disk holding the guest's boot files, e.g. a `Qcow2Backend` or `FileBackend`
+///
+/// # Returns
+/// * `Ok(KernelComponents)` - On success, contains the loaded kernel and optionally initrd.
+/// * `Err(VmError)` - If the disk couldn't be read or holds no kernel.
+pub fn extract_kernel_components(disk: &mut dyn DiskBackend) -> Result<KernelComponents, VmError> {
+    for partition in list_partitions(disk)? {
+        let mut filesystem = match ExtFilesystem::open(disk, partition)? {
+            Some(filesystem) => filesystem,
+            None => continue
         };
-        let filename = entry.file_name().to_string_lossy().into_owned();
-        if filename.starts_with("vmlinuz") {
-            // Found kernel image
-            path_to_vmlinuz_file = match entry.path().to_str() {
-                Some(p) => Some(p.to_owned()),
-                None => return Err(format!("failed to convert path to string slice"))
+        for directory in BOOT_DIRECTORIES {
+            let entries = match filesystem.read_dir(directory)? {
+                Some(entries) => entries,
+                None => continue
+            };
+            let kernel = match select_kernel(&entries) {
+                Some(kernel) => kernel,
+                None => continue
+            };
+
+            let kernel_bytes = match filesystem.read_file(&format!("{}/{}", directory.trim_end_matches('/'), kernel))? {
+                Some(bytes) => bytes,
+                None => return Err(VmError::image(format!("failed to read kernel image {}", kernel)))
+            };
+            let initrd = match select_initrd(&entries, kernel) {
+                Some(initrd) => filesystem.read_file(&format!("{}/{}", directory.trim_end_matches('/'), initrd))?,
+                None => None
             };
+            return Ok(KernelComponents { kernel: kernel_bytes, initrd });
         }
-        else if filename.starts_with("initrd.img") {
-            // Found initrd image
-            path_to_initrd_file = match entry.path().to_str() {
-                Some(p) => Some(p.to_owned()),
-                None => return Err(format!("failed to convert path to string slice"))
+    }
+    Err(VmError::image("vmlinuz file not found in boot directory"))
+}
+
+/// Extracts the `System.map` of the kernel `extract_kernel_components` picks from any disk.
+///
+/// The map is the `System.map` file of the same version in the kernel's directory.
+///
+/// # Returns
+/// * `Ok(Some(String))` - The symbol map of the kernel.
+/// * `Ok(None)` - If the kernel has no `System.map` next to it.
+/// * `Err(VmError)` - If the disk couldn't be read, holds no kernel or the map isn't text.
+pub fn extract_system_map(disk: &mut dyn DiskBackend) -> Result<Option<String>, VmError> {
+    for partition in list_partitions(disk)? {
+        let mut filesystem = match ExtFilesystem::open(disk, partition)? {
+            Some(filesystem) => filesystem,
+            None => continue
+        };
+        for directory in BOOT_DIRECTORIES {
+            let entries = match filesystem.read_dir(directory)? {
+                Some(entries) => entries,
+                None => continue
+            };
+            let kernel = match select_kernel(&entries) {
+                Some(kernel) => kernel,
+                None => continue
+            };
+            let system_map = match select_system_map(&entries, kernel) {
+                Some(system_map) => system_map,
+                None => return Ok(None)
+            };
+            return match filesystem.read_file(&format!("{}/{}", directory.trim_end_matches('/'), system_map))? {
+                Some(bytes) => match String::from_utf8(bytes) {
+                    Ok(text) => Ok(Some(text)),
+                    Err(e) => Err(VmError::image_source(format!("{} is not a text file: {}", system_map, e), e))
+                },
+                None => Ok(None)
             };
         }
     }
+    Err(VmError::image("vmlinuz file not found in boot directory"))
+}
 
-    // If kernel image is missing, fail
-    let path_to_vmlinuz_file = match path_to_vmlinuz_file {
-        Some(p) => p,
-        None => return Err(format!("vmlinuz file not found in boot directory"))
-    };
+/// Returns the name of the newest kernel among the regular files of a directory.
+fn select_kernel(entries: &[DirEntry]) -> Option<&str> {
+    entries.iter()
+        .filter(|entry| entry.kind == EntryKind::File && (entry.name == "vmlinuz" || entry.name.starts_with("vmlinuz-")))
+        .map(|entry| entry.name.as_str())
+        .max_by(|a, b| compare_versions(a, b))
+}
 
-    // Read kernel file into memory
-    let vmlinuz_file_bytes: Vec<u8> = match read(path_to_vmlinuz_file) {
-        Ok(b) => b,
-        Err(e) => return Err(format!("{:?}", e))
+/// Returns the name of the `System.map` belonging to `kernel` among the regular files of a directory.
+fn select_system_map<'a>(entries: &'a [DirEntry], kernel: &str) -> Option<&'a str> {
+    let candidate = match kernel.strip_prefix("vmlinuz-") {
+        Some(version) => format!("System.map-{}", version),
+        None => "System.map".to_string(),
     };
+    entries.iter().find(|entry| entry.kind == EntryKind::File && entry.name == candidate).map(|entry| entry.name.as_str())
+}
 
-    // Read initrd file if present and return both as KernelComponents
-    match path_to_initrd_file {
-        Some(p) => {
-            let initrd_file_bytes: Vec<u8> = match read(p) {
-                Ok(b) => b,
-                Err(e) => return Err(format!("{:?}", e))
-            };
-            Ok(KernelComponents {kernel: vmlinuz_file_bytes, initrd: Some(initrd_file_bytes)})
-        },
-        None => Ok(KernelComponents {kernel: vmlinuz_file_bytes, initrd: None})
-    }
-}
\ No newline at end of file
+/// Returns the name of the initrd belonging to `kernel` among the regular files of a directory.
+fn select_initrd<'a>(entries: &'a [DirEntry], kernel: &str) -> Option<&'a str> {
+    let candidates = match kernel.strip_prefix("vmlinuz-") {
+        Some(version) => vec![
+            format!("initrd.img-{}", version),
+            format!("initrd-{}.img", version),
+            format!("initramfs-{}.img", version),
+            format!("initrd-{}", version),
+        ],
+        None => vec!["initrd.img".to_string(), "initrd".to_string(), "initramfs.img".to_string()],
+    };
+    candidates.iter().find_map(|candidate| {
+        entries.iter().find(|entry| entry.kind == EntryKind::File && entry.name == *candidate).map(|entry| entry.name.as_str())
+    })
+}
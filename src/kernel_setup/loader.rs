@@ -0,0 +1,190 @@
+//! Loading of extracted kernel components into guest memory.
+//!
+//! `load_kernel` copies the kernel, the optional initrd and the kernel command line
+//! into guest RAM at the addresses expected by the Linux boot protocol and reports
+//! where everything ended up, so the VM setup can point the boot vCPU at the kernel.
+
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use super::setup_utils::KernelComponents;
+use super::x86_64_boot::BzImageHeader;
+use crate::error::VmError;
+
+/// Guest physical address the protected-mode kernel is loaded at (1 MiB).
+pub const KERNEL_LOAD_ADDR: u64 = 0x10_0000;
+/// Guest physical address of the NUL terminated kernel command line.
+pub const CMDLINE_ADDR: u64 = 0x2_0000;
+/// Maximum command line length including the NUL terminator.
+pub const CMDLINE_MAX_SIZE: usize = 2048;
+/// Highest address an initrd may end at, as older kernels can't access memory above it.
+pub const INITRD_ADDR_MAX: u64 = 0x37FF_FFFF;
+/// Offset of the 64-bit entry point from the start of a bzImage protected-mode kernel.
+const BZIMAGE_64BIT_ENTRY_OFFSET: u64 = 0x200;
+/// Alignment of the initrd in guest memory.
+const INITRD_ALIGN: u64 = 0x1000;
+
+/// Where the kernel components were placed in guest memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedKernel {
+    /// Address the boot vCPU must start executing at
+    pub entry_point: GuestAddress,
+    /// First byte after the loaded kernel
+    pub kernel_end: GuestAddress,
+    /// Address and size of the initrd, if one was loaded
+    pub initrd: Option<(GuestAddress, usize)>,
+    /// Address of the NUL terminated command line
+    pub cmdline_addr: GuestAddress,
+    /// Size of the command line including the NUL terminator
+    pub cmdline_size: usize,
+}
+
+/// Copies the kernel, initrd and command line into guest memory.
+///
+/// Both bzImage and uncompressed ELF (`vmlinux`) kernels are accepted. A bzImage has
+/// its real-mode setup code stripped and the protected-mode part loaded at
+/// `KERNEL_LOAD_ADDR`; an ELF kernel has its loadable segments copied to their
+/// physical addresses. The initrd is placed page aligned as high as possible below
+/// both the end of guest RAM and `INITRD_ADDR_MAX`.
+///
+/// # Arguments
+/// * `guest_memory` - Guest RAM, which must cover the low 1 MiB and the kernel
+/// * `components` - Kernel and optional initrd extracted from the distribution image
+/// * `cmdline` - Kernel command line
+///
+/// # Returns
+/// * `Ok(LoadedKernel)` describing where everything was loaded
+/// * `Err(VmError)` if the kernel format is unknown or a component doesn't fit
+pub fn load_kernel(guest_memory: &mut GuestMemoryMmap, components: &KernelComponents, cmdline: &str) -> Result<LoadedKernel, VmError> {
+    let (entry_point, kernel_end) = if is_bzimage(&components.kernel) {
+        load_bzimage(guest_memory, &components.kernel)?
+    } else if components.kernel.starts_with(b"\x7fELF") {
+        load_elf(guest_memory, &components.kernel)?
+    } else {
+        return Err(VmError::image("unsupported kernel format: expected bzImage or ELF"));
+    };
+
+    let initrd = match &components.initrd {
+        Some(initrd) => Some(load_initrd(guest_memory, initrd, kernel_end)?),
+        None => None
+    };
+
+    let cmdline_size = load_cmdline(guest_memory, cmdline)?;
+
+    Ok(LoadedKernel {
+        entry_point,
+        kernel_end,
+        initrd,
+        cmdline_addr: GuestAddress(CMDLINE_ADDR),
+        cmdline_size,
+    })
+}
+
+/// Returns `true` if `kernel` carries the "HdrS" boot protocol signature.
+fn is_bzimage(kernel: &[u8]) -> bool {
+    kernel.len() > 0x206 && &kernel[0x202..0x206] == b"HdrS"
+}
+
+/// Writes `data` at `addr`, mapping failures to a `VmError`.
+fn write_guest(guest_memory: &GuestMemoryMmap, data: &[u8], addr: GuestAddress, what: &str) -> Result<(), VmError> {
+    match guest_memory.write_slice(data, addr) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::memory_source(format!("failed to write {} at {:#x}: {}", what, addr.0, e), e))
+    }
+}
+
+/// Loads the protected-mode part of a bzImage at `KERNEL_LOAD_ADDR`.
+fn load_bzimage(guest_memory: &GuestMemoryMmap, kernel: &[u8]) -> Result<(GuestAddress, GuestAddress), VmError> {
+    let setup_size = BzImageHeader::parse(kernel)?.get_setup_size();
+    if setup_size >= kernel.len() {
+        return Err(VmError::image("bzImage is truncated: no protected-mode kernel after the setup code"));
+    }
+
+    let payload = &kernel[setup_size..];
+    write_guest(guest_memory, payload, GuestAddress(KERNEL_LOAD_ADDR), "kernel")?;
+
+    Ok((
+        GuestAddress(KERNEL_LOAD_ADDR + BZIMAGE_64BIT_ENTRY_OFFSET),
+        GuestAddress(KERNEL_LOAD_ADDR + payload.len() as u64),
+    ))
+}
+
+/// Loads the `PT_LOAD` segments of a 64-bit little-endian ELF kernel.
+fn load_elf(guest_memory: &GuestMemoryMmap, kernel: &[u8]) -> Result<(GuestAddress, GuestAddress), VmError> {
+    let read_u16 = |off: usize| kernel.get(off..off.checked_add(2)?).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let read_u32 = |off: usize| kernel.get(off..off.checked_add(4)?).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let read_u64 = |off: usize| kernel.get(off..off.checked_add(8)?).map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+
+    // EI_CLASS 2 = 64-bit, EI_DATA 1 = little-endian
+    if kernel.get(4) != Some(&2) || kernel.get(5) != Some(&1) {
+        return Err(VmError::image("only 64-bit little-endian ELF kernels are supported"));
+    }
+
+    let (entry, phoff, phentsize, phnum) = match (read_u64(0x18), read_u64(0x20), read_u16(0x36), read_u16(0x38)) {
+        (Some(entry), Some(phoff), Some(phentsize), Some(phnum)) => (entry, phoff as usize, phentsize as usize, phnum as usize),
+        _ => return Err(VmError::image("ELF kernel header is truncated"))
+    };
+
+    let mut kernel_end = 0u64;
+    for i in 0..phnum {
+        let ph = match i.checked_mul(phentsize).and_then(|ph| ph.checked_add(phoff)) {
+            Some(ph) => ph,
+            None => return Err(VmError::image("ELF program header table lies outside the kernel file"))
+        };
+        // p_type 1 = PT_LOAD
+        if read_u32(ph) != Some(1) {
+            continue;
+        }
+        let (offset, paddr, filesz, memsz) = match (read_u64(ph + 0x08), read_u64(ph + 0x18), read_u64(ph + 0x20), read_u64(ph + 0x28)) {
+            (Some(o), Some(p), Some(f), Some(m)) => (o as usize, p, f as usize, m),
+            _ => return Err(VmError::image("ELF program header is truncated"))
+        };
+        let data = match offset.checked_add(filesz).and_then(|end| kernel.get(offset..end)) {
+            Some(d) => d,
+            None => return Err(VmError::image("ELF segment lies outside the kernel file"))
+        };
+        let segment_end = match paddr.checked_add(memsz) {
+            Some(end) => end,
+            None => return Err(VmError::image("ELF segment lies past the end of the address space"))
+        };
+        write_guest(guest_memory, data, GuestAddress(paddr), "kernel segment")?;
+        kernel_end = kernel_end.max(segment_end);
+    }
+
+    if kernel_end == 0 {
+        return Err(VmError::image("ELF kernel has no loadable segments"));
+    }
+
+    Ok((GuestAddress(entry), GuestAddress(kernel_end)))
+}
+
+/// Places the initrd as high as possible in guest RAM, above the kernel.
+fn load_initrd(guest_memory: &GuestMemoryMmap, initrd: &[u8], kernel_end: GuestAddress) -> Result<(GuestAddress, usize), VmError> {
+    let top = guest_memory.last_addr().0.min(INITRD_ADDR_MAX) + 1;
+    let size = initrd.len() as u64;
+    let addr = match top.checked_sub(size) {
+        Some(start) => start & !(INITRD_ALIGN - 1),
+        None => return Err(VmError::memory("initrd is larger than guest memory"))
+    };
+    if addr < kernel_end.0 {
+        return Err(VmError::memory(format!(
+            "not enough guest memory for the initrd: {} bytes needed above {:#x}", size, kernel_end.0
+        )));
+    }
+
+    write_guest(guest_memory, initrd, GuestAddress(addr), "initrd")?;
+    Ok((GuestAddress(addr), initrd.len()))
+}
+
+/// Writes the NUL terminated command line at `CMDLINE_ADDR` and returns its size.
+fn load_cmdline(guest_memory: &GuestMemoryMmap, cmdline: &str) -> Result<usize, VmError> {
+    if cmdline.as_bytes().contains(&0) {
+        return Err(VmError::config("kernel command line must not contain NUL bytes"));
+    }
+    let mut bytes = cmdline.as_bytes().to_vec();
+    bytes.push(0);
+    if bytes.len() > CMDLINE_MAX_SIZE {
+        return Err(VmError::config(format!("kernel command line is longer than {} bytes", CMDLINE_MAX_SIZE - 1)));
+    }
+
+    write_guest(guest_memory, &bytes, GuestAddress(CMDLINE_ADDR), "kernel command line")?;
+    Ok(bytes.len())
+}
@@ -1,3 +1,10 @@
 pub mod setup_utils;
+pub mod guest_fs;
+pub mod iso9660;
+pub mod arm64_boot;
 #[cfg(target_os = "linux")]
-pub mod linux_setup;
\ No newline at end of file
+pub mod linux_setup;
+#[cfg(target_os = "linux")]
+pub mod loader;
+#[cfg(target_os = "linux")]
+pub mod x86_64_boot;
@@ -8,7 +8,7 @@
 /// * `kernel` - The raw kernel binary as a byte vector (usually vmlinux or bzImage).
 /// * `initrd` - Optional byte vector containing an initrd image (e.g., initramfs),
 ///              which provides a temporary root filesystem during early boot.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KernelComponents {
     pub kernel: Vec<u8>,             // Raw contents of the kernel image
     pub initrd: Option<Vec<u8>>      // Optional initrd/initramfs contents
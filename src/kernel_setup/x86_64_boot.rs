@@ -0,0 +1,468 @@
+//! x86_64 Linux boot protocol.
+//!
+//! Implements the 64-bit entry of the Linux x86 boot protocol
+//! (Documentation/arch/x86/boot.rst): the bzImage setup header is parsed, the zero
+//! page (`struct boot_params`) is built with the command line, initrd and E820 memory
+//! map, identity mapped page tables and a flat GDT are written to low memory, and the
+//! boot vCPU is put in long mode with RIP at the kernel's 64-bit entry point and RSI
+//! pointing at the zero page.
+
+use kvm_bindings::{kvm_segment, kvm_sregs, kvm_regs};
+use kvm_ioctls::VcpuFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use super::loader::{LoadedKernel, CMDLINE_MAX_SIZE, INITRD_ADDR_MAX};
+use crate::error::VmError;
+
+/// Guest physical address of the GDT.
+pub const GDT_ADDR: u64 = 0x500;
+/// Guest physical address of the zero page (`struct boot_params`).
+pub const ZERO_PAGE_ADDR: u64 = 0x7000;
+/// Initial stack pointer of the boot vCPU.
+pub const BOOT_STACK_POINTER: u64 = 0x8ff0;
+/// Guest physical address of the PML4 table.
+pub const PML4_ADDR: u64 = 0x9000;
+/// Guest physical address of the page directory pointer table.
+pub const PDPTE_ADDR: u64 = 0xa000;
+/// Guest physical address of the page directory mapping the first GiB.
+pub const PDE_ADDR: u64 = 0xb000;
+/// Start of the EBDA; RAM below it is usable by the guest.
+pub const EBDA_START: u64 = 0x9fc00;
+/// Start of high memory, where the protected-mode kernel lives.
+pub const HIGH_MEMORY_START: u64 = 0x10_0000;
+
+/// E820 type of usable RAM.
+pub const E820_RAM: u32 = 1;
+/// E820 type of reserved memory.
+pub const E820_RESERVED: u32 = 2;
+
+/// Selector of the 64-bit code segment in the boot GDT.
+const BOOT_CODE_SELECTOR: u16 = 0x08;
+/// Selector of the data segment in the boot GDT.
+const BOOT_DATA_SELECTOR: u16 = 0x10;
+/// Selector of the TSS in the boot GDT.
+const BOOT_TSS_SELECTOR: u16 = 0x18;
+
+/// "HdrS" boot protocol signature.
+const HDRS_MAGIC: u32 = 0x5372_6448;
+/// Boot sector signature at offset 0x1fe.
+const BOOT_FLAG_MAGIC: u16 = 0xAA55;
+/// `xloadflags` bit telling the kernel has a 64-bit entry point at +0x200.
+const XLF_KERNEL_64: u16 = 1 << 0;
+/// `loadflags` bit telling the heap_end_ptr field is valid.
+const CAN_USE_HEAP: u8 = 1 << 7;
+/// `type_of_loader` value for boot loaders without an assigned id.
+const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+/// Maximum number of E820 entries in the zero page.
+const E820_MAX_ENTRIES: usize = 128;
+/// `orig_video_isVGA` value of a linear framebuffer left by EFI firmware, which the
+/// efifb and simpledrm drivers take over.
+const VIDEO_TYPE_EFI: u8 = 0x70;
+/// `capabilities` bit telling `ext_lfb_base` holds the upper half of the framebuffer address.
+const VIDEO_CAPABILITY_64BIT_BASE: u32 = 1 << 1;
+
+// Offsets inside the zero page, see arch/x86/include/uapi/asm/bootparam.h
+const BP_EXT_RAMDISK_IMAGE: usize = 0x0c0;
+const BP_EXT_RAMDISK_SIZE: usize = 0x0c4;
+const BP_EXT_CMD_LINE_PTR: usize = 0x0c8;
+const BP_E820_ENTRIES: usize = 0x1e8;
+const BP_SETUP_HEADER: usize = 0x1f1;
+const BP_TYPE_OF_LOADER: usize = 0x210;
+const BP_LOADFLAGS: usize = 0x211;
+const BP_RAMDISK_IMAGE: usize = 0x218;
+const BP_RAMDISK_SIZE: usize = 0x21c;
+const BP_HEAP_END_PTR: usize = 0x224;
+const BP_CMD_LINE_PTR: usize = 0x228;
+const BP_E820_TABLE: usize = 0x2d0;
+const BOOT_PARAMS_SIZE: usize = 0x1000;
+
+// Offsets inside `struct screen_info`, at the start of the zero page, see
+// include/uapi/linux/screen_info.h
+const SI_ORIG_VIDEO_IS_VGA: usize = 0x0f;
+const SI_LFB_WIDTH: usize = 0x12;
+const SI_LFB_HEIGHT: usize = 0x14;
+const SI_LFB_DEPTH: usize = 0x16;
+const SI_LFB_BASE: usize = 0x18;
+const SI_LFB_SIZE: usize = 0x1c;
+const SI_LFB_LINELENGTH: usize = 0x24;
+const SI_RED_SIZE: usize = 0x26;
+const SI_CAPABILITIES: usize = 0x36;
+const SI_EXT_LFB_BASE: usize = 0x3a;
+/// Size of `struct screen_info`.
+pub const SCREEN_INFO_SIZE: usize = 0x40;
+
+/// Setup header of a bzImage kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BzImageHeader {
+    /// Number of 512-byte setup sectors following the boot sector
+    pub setup_sects: u8,
+    /// Boot protocol version, e.g. 0x020f
+    pub version: u16,
+    /// Boot protocol option flags
+    pub loadflags: u8,
+    /// Highest address the initrd may occupy
+    pub initrd_addr_max: u32,
+    /// Extended load flags (protocol 2.12+)
+    pub xloadflags: u16,
+    /// Maximum command line size without the NUL terminator (protocol 2.06+)
+    pub cmdline_size: u32,
+    /// Raw setup header bytes, copied verbatim into the zero page
+    raw: Vec<u8>,
+}
+
+impl BzImageHeader {
+    /// Parses the setup header of a bzImage.
+    ///
+    /// # Returns
+    /// * `Ok(BzImageHeader)` on success
+    /// * `Err(VmError)` if the image isn't a bzImage or lacks a 64-bit entry point
+    pub fn parse(kernel: &[u8]) -> Result<BzImageHeader, VmError> {
+        let read_u16 = |off: usize| kernel.get(off..off + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let read_u32 = |off: usize| kernel.get(off..off + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+        if read_u32(0x202) != Some(HDRS_MAGIC) || read_u16(0x1fe) != Some(BOOT_FLAG_MAGIC) {
+            return Err(VmError::image("kernel is not a bzImage: boot protocol signature missing"));
+        }
+
+        // The header ends at 0x202 plus the value of the byte at 0x201; the fields read
+        // below must be there even if it claims to end earlier
+        let header_end = 0x202 + kernel[0x201] as usize;
+        if kernel.len() < header_end.max(0x268) {
+            return Err(VmError::image("bzImage setup header is truncated"));
+        }
+        let raw = kernel[BP_SETUP_HEADER..header_end].to_vec();
+
+        let version = read_u16(0x206).unwrap_or(0);
+        if version < 0x0206 {
+            return Err(VmError::image(format!("boot protocol {:#06x} is too old, 2.06 or newer is required", version)));
+        }
+
+        let xloadflags = if version >= 0x020c { read_u16(0x236).unwrap_or(0) } else { 0 };
+        if version >= 0x020c && xloadflags & XLF_KERNEL_64 == 0 {
+            return Err(VmError::image("bzImage has no 64-bit entry point"));
+        }
+
+        Ok(BzImageHeader {
+            setup_sects: kernel[0x1f1],
+            version,
+            loadflags: kernel[0x211],
+            initrd_addr_max: read_u32(0x22c).unwrap_or(0x37FF_FFFF),
+            xloadflags,
+            cmdline_size: read_u32(0x238).unwrap_or(255),
+            raw,
+        })
+    }
+
+    /// Builds the header handed to an uncompressed ELF kernel, which carries none.
+    ///
+    /// Only the fields the kernel checks on the 64-bit entry path are filled in.
+    pub fn for_elf_kernel() -> BzImageHeader {
+        let mut raw = vec![0u8; 0x268 - BP_SETUP_HEADER];
+        let mut put = |off: usize, bytes: &[u8]| raw[off - BP_SETUP_HEADER..off - BP_SETUP_HEADER + bytes.len()].copy_from_slice(bytes);
+        put(0x1fe, &BOOT_FLAG_MAGIC.to_le_bytes());
+        put(0x202, &HDRS_MAGIC.to_le_bytes());
+        put(0x206, &0x020fu16.to_le_bytes());
+        put(0x22c, &(INITRD_ADDR_MAX as u32).to_le_bytes());
+        put(0x236, &XLF_KERNEL_64.to_le_bytes());
+        put(0x238, &(CMDLINE_MAX_SIZE as u32 - 1).to_le_bytes());
+
+        BzImageHeader {
+            setup_sects: 0,
+            version: 0x020f,
+            loadflags: 0,
+            initrd_addr_max: INITRD_ADDR_MAX as u32,
+            xloadflags: XLF_KERNEL_64,
+            cmdline_size: CMDLINE_MAX_SIZE as u32 - 1,
+            raw,
+        }
+    }
+
+    /// Returns the size of the real-mode setup code preceding the protected-mode kernel.
+    pub fn get_setup_size(&self) -> usize {
+        // A setup_sects value of 0 means 4 for historical reasons
+        let setup_sects = if self.setup_sects == 0 { 4 } else { self.setup_sects as usize };
+        (setup_sects + 1) * 512
+    }
+}
+
+/// One entry of the E820 memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub entry_type: u32,
+}
+
+/// Builds the E820 map of a guest with RAM from 0 to `mem_size`.
+///
+/// Conventional memory below the EBDA and everything from 1 MiB up is RAM; the
+/// legacy VGA/BIOS hole in between is reserved.
+pub fn build_e820_map(mem_size: u64) -> Vec<E820Entry> {
+    let mut entries = vec![E820Entry { addr: 0, size: EBDA_START.min(mem_size), entry_type: E820_RAM }];
+    if mem_size > EBDA_START {
+        entries.push(E820Entry {
+            addr: EBDA_START,
+            size: HIGH_MEMORY_START.min(mem_size) - EBDA_START,
+            entry_type: E820_RESERVED,
+        });
+    }
+    if mem_size > HIGH_MEMORY_START {
+        entries.push(E820Entry { addr: HIGH_MEMORY_START, size: mem_size - HIGH_MEMORY_START, entry_type: E820_RAM });
+    }
+    entries
+}
+
+/// Linear framebuffer of 32-bit XRGB pixels handed to the kernel as its screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinearFramebuffer {
+    /// Guest physical address of the first pixel
+    pub base: u64,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Bytes between the start of two rows
+    pub stride: u32,
+}
+
+/// Builds the `screen_info` describing `framebuffer` to the kernel as one left by EFI
+/// firmware, with the red, green and blue bytes at bits 16, 8 and 0.
+///
+/// # Returns
+/// * `Ok` with the `screen_info` structure
+/// * `Err(VmError)` if the framebuffer doesn't fit the 16-bit size fields
+pub fn build_screen_info(framebuffer: &LinearFramebuffer) -> Result<[u8; SCREEN_INFO_SIZE], VmError> {
+    let (Ok(width), Ok(height), Ok(stride)) = (u16::try_from(framebuffer.width), u16::try_from(framebuffer.height), u16::try_from(framebuffer.stride)) else {
+        return Err(VmError::config(format!("a {}x{} framebuffer is too large for the boot protocol", framebuffer.width, framebuffer.height)));
+    };
+    let size = framebuffer.stride as u64 * framebuffer.height as u64;
+
+    let mut info = [0u8; SCREEN_INFO_SIZE];
+    info[SI_ORIG_VIDEO_IS_VGA] = VIDEO_TYPE_EFI;
+    info[SI_LFB_WIDTH..SI_LFB_WIDTH + 2].copy_from_slice(&width.to_le_bytes());
+    info[SI_LFB_HEIGHT..SI_LFB_HEIGHT + 2].copy_from_slice(&height.to_le_bytes());
+    info[SI_LFB_DEPTH..SI_LFB_DEPTH + 2].copy_from_slice(&32u16.to_le_bytes());
+    info[SI_LFB_BASE..SI_LFB_BASE + 4].copy_from_slice(&(framebuffer.base as u32).to_le_bytes());
+    info[SI_LFB_SIZE..SI_LFB_SIZE + 4].copy_from_slice(&(size as u32).to_le_bytes());
+    info[SI_LFB_LINELENGTH..SI_LFB_LINELENGTH + 2].copy_from_slice(&stride.to_le_bytes());
+    // Size and position of red, green, blue and the unused byte
+    info[SI_RED_SIZE..SI_RED_SIZE + 8].copy_from_slice(&[8, 16, 8, 8, 8, 0, 8, 24]);
+    info[SI_CAPABILITIES..SI_CAPABILITIES + 4].copy_from_slice(&VIDEO_CAPABILITY_64BIT_BASE.to_le_bytes());
+    info[SI_EXT_LFB_BASE..SI_EXT_LFB_BASE + 4].copy_from_slice(&((framebuffer.base >> 32) as u32).to_le_bytes());
+    Ok(info)
+}
+
+/// Hands `framebuffer` to the kernel through the zero page written by
+/// `setup_boot_environment`, which must be called first.
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the framebuffer is too large or the zero page can't be written
+pub fn write_screen_info(guest_memory: &GuestMemoryMmap, framebuffer: &LinearFramebuffer) -> Result<(), VmError> {
+    write_guest(guest_memory, &build_screen_info(framebuffer)?, ZERO_PAGE_ADDR, "screen info")
+}
+
+/// Builds the zero page handed to the kernel in RSI.
+///
+/// # Arguments
+/// * `header` - Setup header of the kernel
+/// * `loaded` - Where the loader placed the command line and initrd
+/// * `e820` - Guest memory map
+///
+/// # Returns
+/// * `Ok` with the 4 KiB `boot_params` structure
+/// * `Err(VmError)` if the command line or memory map don't fit the protocol limits
+pub fn build_boot_params(header: &BzImageHeader, loaded: &LoadedKernel, e820: &[E820Entry]) -> Result<Vec<u8>, VmError> {
+    if loaded.cmdline_size > header.cmdline_size as usize + 1 {
+        return Err(VmError::config(format!("kernel command line exceeds the kernel's limit of {} bytes", header.cmdline_size)));
+    }
+    if e820.len() > E820_MAX_ENTRIES {
+        return Err(VmError::config(format!("E820 map has {} entries, at most {} are supported", e820.len(), E820_MAX_ENTRIES)));
+    }
+
+    let mut params = vec![0u8; BOOT_PARAMS_SIZE];
+    let put_u32 = |params: &mut [u8], off: usize, value: u32| params[off..off + 4].copy_from_slice(&value.to_le_bytes());
+
+    params[BP_SETUP_HEADER..BP_SETUP_HEADER + header.raw.len()].copy_from_slice(&header.raw);
+    params[BP_TYPE_OF_LOADER] = LOADER_TYPE_UNDEFINED;
+    params[BP_LOADFLAGS] = header.loadflags | CAN_USE_HEAP;
+    params[BP_HEAP_END_PTR..BP_HEAP_END_PTR + 2].copy_from_slice(&0xfe00u16.to_le_bytes());
+
+    let cmdline = loaded.cmdline_addr.0;
+    put_u32(&mut params, BP_CMD_LINE_PTR, cmdline as u32);
+    put_u32(&mut params, BP_EXT_CMD_LINE_PTR, (cmdline >> 32) as u32);
+
+    if let Some((addr, size)) = loaded.initrd {
+        if addr.0 + size as u64 > header.initrd_addr_max as u64 + 1 {
+            return Err(VmError::memory(format!("initrd at {:#x} ends above the kernel's limit {:#x}", addr.0, header.initrd_addr_max)));
+        }
+        put_u32(&mut params, BP_RAMDISK_IMAGE, addr.0 as u32);
+        put_u32(&mut params, BP_EXT_RAMDISK_IMAGE, (addr.0 >> 32) as u32);
+        put_u32(&mut params, BP_RAMDISK_SIZE, size as u32);
+        put_u32(&mut params, BP_EXT_RAMDISK_SIZE, ((size as u64) >> 32) as u32);
+    }
+
+    params[BP_E820_ENTRIES] = e820.len() as u8;
+    for (i, entry) in e820.iter().enumerate() {
+        let off = BP_E820_TABLE + i * 20;
+        params[off..off + 8].copy_from_slice(&entry.addr.to_le_bytes());
+        params[off + 8..off + 16].copy_from_slice(&entry.size.to_le_bytes());
+        params[off + 16..off + 20].copy_from_slice(&entry.entry_type.to_le_bytes());
+    }
+
+    Ok(params)
+}
+
+/// Writes the zero page, the identity mapped page tables and the boot GDT.
+///
+/// # Arguments
+/// * `guest_memory` - Guest RAM starting at address 0
+/// * `kernel` - Raw bzImage the setup header is taken from, or an ELF kernel
+/// * `loaded` - Result of `load_kernel` for that kernel
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the kernel isn't a usable bzImage or guest memory is too small
+pub fn setup_boot_environment(guest_memory: &GuestMemoryMmap, kernel: &[u8], loaded: &LoadedKernel) -> Result<(), VmError> {
+    let header = if kernel.starts_with(b"\x7fELF") { BzImageHeader::for_elf_kernel() } else { BzImageHeader::parse(kernel)? };
+    let mem_size = guest_memory.last_addr().0 + 1;
+    let params = build_boot_params(&header, loaded, &build_e820_map(mem_size))?;
+
+    write_guest(guest_memory, &params, ZERO_PAGE_ADDR, "zero page")?;
+    write_page_tables(guest_memory)?;
+    write_gdt(guest_memory)
+}
+
+/// Writes `data` at `addr`, mapping failures to a `VmError`.
+fn write_guest(guest_memory: &GuestMemoryMmap, data: &[u8], addr: u64, what: &str) -> Result<(), VmError> {
+    match guest_memory.write_slice(data, GuestAddress(addr)) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::memory_source(format!("failed to write {} at {:#x}: {}", what, addr, e), e))
+    }
+}
+
+/// Identity maps the first GiB with 2 MiB pages.
+fn write_page_tables(guest_memory: &GuestMemoryMmap) -> Result<(), VmError> {
+    // Present | writable
+    const PAGE_PRESENT_RW: u64 = 0x3;
+    // Present | writable | huge page
+    const PAGE_PRESENT_RW_HUGE: u64 = 0x83;
+
+    write_guest(guest_memory, &(PDPTE_ADDR | PAGE_PRESENT_RW).to_le_bytes(), PML4_ADDR, "PML4")?;
+    write_guest(guest_memory, &(PDE_ADDR | PAGE_PRESENT_RW).to_le_bytes(), PDPTE_ADDR, "PDPTE")?;
+
+    let mut pde = Vec::with_capacity(512 * 8);
+    for i in 0..512u64 {
+        pde.extend_from_slice(&((i << 21) | PAGE_PRESENT_RW_HUGE).to_le_bytes());
+    }
+    write_guest(guest_memory, &pde, PDE_ADDR, "page directory")
+}
+
+/// Boot GDT: null, 64-bit code, data and TSS descriptors.
+const BOOT_GDT: [u64; 4] = [
+    0,
+    gdt_entry(0xa09b, 0, 0xfffff),
+    gdt_entry(0xc093, 0, 0xfffff),
+    gdt_entry(0x808b, 0, 0xfffff),
+];
+
+/// Encodes a segment descriptor from its flags, base and limit.
+const fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
+    ((base as u64 & 0xff00_0000) << 32)
+        | ((base as u64 & 0x00ff_ffff) << 16)
+        | (limit as u64 & 0x0000_ffff)
+        | ((limit as u64 & 0x000f_0000) << 32)
+        | ((flags as u64 & 0xf0ff) << 40)
+}
+
+/// Expands a GDT descriptor into the segment register state KVM expects.
+fn kvm_segment_from_gdt(entry: u64, selector: u16) -> kvm_segment {
+    let limit = ((entry & 0x0000_0000_0000_ffff) | ((entry & 0x000f_0000_0000_0000) >> 32)) as u32;
+    let granularity = ((entry >> 55) & 1) as u8;
+    kvm_segment {
+        base: ((entry >> 16) & 0x00ff_ffff) | ((entry >> 32) & 0xff00_0000),
+        // With 4 KiB granularity the limit counts pages
+        limit: if granularity == 1 { (limit << 12) | 0xfff } else { limit },
+        selector,
+        type_: ((entry >> 40) & 0xf) as u8,
+        present: ((entry >> 47) & 1) as u8,
+        dpl: ((entry >> 45) & 3) as u8,
+        db: ((entry >> 54) & 1) as u8,
+        s: ((entry >> 44) & 1) as u8,
+        l: ((entry >> 53) & 1) as u8,
+        g: granularity,
+        avl: ((entry >> 52) & 1) as u8,
+        unusable: if (entry >> 47) & 1 == 0 { 1 } else { 0 },
+        padding: 0,
+    }
+}
+
+fn write_gdt(guest_memory: &GuestMemoryMmap) -> Result<(), VmError> {
+    let gdt: Vec<u8> = BOOT_GDT.iter().flat_map(|e| e.to_le_bytes()).collect();
+    write_guest(guest_memory, &gdt, GDT_ADDR, "GDT")
+}
+
+/// Returns the special registers of a vCPU entering the kernel in long mode.
+pub fn get_boot_sregs(mut sregs: kvm_sregs) -> kvm_sregs {
+    // Control register bits
+    const X86_CR0_PE: u64 = 1 << 0;
+    const X86_CR0_PG: u64 = 1 << 31;
+    const X86_CR4_PAE: u64 = 1 << 5;
+    const EFER_LME: u64 = 1 << 8;
+    const EFER_LMA: u64 = 1 << 10;
+
+    let code = kvm_segment_from_gdt(BOOT_GDT[1], BOOT_CODE_SELECTOR);
+    let data = kvm_segment_from_gdt(BOOT_GDT[2], BOOT_DATA_SELECTOR);
+    let tss = kvm_segment_from_gdt(BOOT_GDT[3], BOOT_TSS_SELECTOR);
+
+    sregs.gdt.base = GDT_ADDR;
+    sregs.gdt.limit = (std::mem::size_of_val(&BOOT_GDT) - 1) as u16;
+    sregs.idt.base = 0;
+    sregs.idt.limit = 0;
+
+    sregs.cs = code;
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+    sregs.tr = tss;
+
+    sregs.cr3 = PML4_ADDR;
+    sregs.cr4 |= X86_CR4_PAE;
+    sregs.cr0 |= X86_CR0_PE | X86_CR0_PG;
+    sregs.efer |= EFER_LME | EFER_LMA;
+    sregs
+}
+
+/// Puts the boot vCPU in long mode at the kernel entry point.
+///
+/// # Arguments
+/// * `vcpu` - The bootstrap processor
+/// * `entry_point` - 64-bit kernel entry point returned by `load_kernel`
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if KVM rejects the register state
+pub fn configure_boot_vcpu(vcpu: &VcpuFd, entry_point: GuestAddress) -> Result<(), VmError> {
+    let sregs = match vcpu.get_sregs() {
+        Ok(sregs) => sregs,
+        Err(e) => return Err(VmError::hypervisor_source(format!("Failed to get special registers: {}", e), e))
+    };
+    if let Err(e) = vcpu.set_sregs(&get_boot_sregs(sregs)) {
+        return Err(VmError::hypervisor_source(format!("Failed to set special registers: {}", e), e));
+    }
+
+    let regs = kvm_regs {
+        rip: entry_point.0,
+        rsi: ZERO_PAGE_ADDR,
+        rsp: BOOT_STACK_POINTER,
+        rbp: BOOT_STACK_POINTER,
+        rflags: 0x2,
+        ..Default::default()
+    };
+    if let Err(e) = vcpu.set_regs(&regs) {
+        return Err(VmError::hypervisor_source(format!("Failed to set registers: {}", e), e));
+    }
+
+    Ok(())
+}
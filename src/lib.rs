@@ -1,5 +1,14 @@
+pub mod error;
 pub mod vm_setup;
+pub mod vm_manager;
 pub mod utils;
 pub mod device_emulation;
+pub mod kernel_setup;
+pub mod guest_agent;
+pub mod image;
 #[cfg(target_os = "windows")]
-mod windows_bindings;
\ No newline at end of file
+mod windows_bindings;
+#[cfg(target_os = "macos")]
+mod macos_bindings;
+#[cfg(target_os = "linux")]
+mod linux_bindings;
\ No newline at end of file
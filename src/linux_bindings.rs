@@ -0,0 +1,175 @@
+//! Host information of Linux hosts, read from procfs and sysfs.
+
+use std::collections::BTreeSet;
+use std::fs::read_to_string;
+use std::path::Path;
+use crate::error::VmError;
+
+/// Memory statistics of the host.
+const MEMINFO_PATH: &str = "/proc/meminfo";
+/// Root of the CPU devices in sysfs.
+const SYSFS_CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// Processor layout of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// Online logical CPUs (hardware threads)
+    pub logical_cpus: u32,
+    /// Physical cores the online CPUs belong to
+    pub cores: u32,
+    /// Sockets the online CPUs belong to
+    pub packages: u32,
+}
+
+impl CpuTopology {
+    /// Returns the number of hardware threads per core, 2 with SMT on most hosts.
+    pub fn get_threads_per_core(&self) -> u32 {
+        self.logical_cpus / self.cores.max(1)
+    }
+}
+
+/// Reads a file of procfs or sysfs.
+fn read_host_file(path: &Path) -> Result<String, VmError> {
+    match read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) => Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e))
+    }
+}
+
+/// Parses the total and available memory, in bytes, out of `/proc/meminfo` content.
+pub fn parse_meminfo(meminfo: &str) -> Result<(u64, u64), VmError> {
+    let field = |name: &str| {
+        meminfo.lines()
+            .filter_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .find_map(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kib| kib * 1024)
+    };
+    let total = match field("MemTotal") {
+        Some(total) => total,
+        None => return Err(VmError::memory("meminfo has no MemTotal"))
+    };
+    // Kernels before 3.14 don't estimate the available memory
+    let available = match field("MemAvailable") {
+        Some(available) => available,
+        None => field("MemFree").unwrap_or(0) + field("Cached").unwrap_or(0),
+    };
+    Ok((total, available))
+}
+
+/// Retrieves total and available physical memory on the host system.
+/// Returns a tuple: (total_physical_memory_bytes, available_physical_memory_bytes)
+pub fn get_physical_memory_info() -> Result<(u64, u64), VmError> {
+    parse_meminfo(&read_host_file(Path::new(MEMINFO_PATH))?)
+}
+
+/// Parses a sysfs CPU list such as `0-3,8,10-11`.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u32>, VmError> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        match (first.parse::<u32>(), last.parse::<u32>()) {
+            (Ok(first), Ok(last)) if first <= last => cpus.extend(first..=last),
+            _ => return Err(VmError::config(format!("invalid CPU list {:?}", list)))
+        }
+    }
+    Ok(cpus)
+}
+
+/// Reads the processor layout of the online CPUs below `cpu_dir`, a sysfs CPU directory.
+fn read_cpu_topology(cpu_dir: &Path) -> Result<CpuTopology, VmError> {
+    let cpus = parse_cpu_list(&read_host_file(&cpu_dir.join("online"))?)?;
+    let mut cores = BTreeSet::new();
+    let mut packages = BTreeSet::new();
+    for cpu in &cpus {
+        let topology = cpu_dir.join(format!("cpu{}/topology", cpu));
+        let read_id = |name: &str| -> Result<i64, VmError> {
+            let path = topology.join(name);
+            match read_host_file(&path)?.trim().parse() {
+                Ok(id) => Ok(id),
+                Err(_) => Err(VmError::config(format!("invalid CPU topology id in {}", path.display())))
+            }
+        };
+        let package = read_id("physical_package_id")?;
+        packages.insert(package);
+        cores.insert((package, read_id("core_id")?));
+    }
+    Ok(CpuTopology { logical_cpus: cpus.len() as u32, cores: cores.len() as u32, packages: packages.len() as u32 })
+}
+
+/// Retrieves the processor layout of the host.
+pub fn get_cpu_topology() -> Result<CpuTopology, VmError> {
+    read_cpu_topology(Path::new(SYSFS_CPU_DIR))
+}
+
+/// Checks that the host can give a VM `memory_size` bytes of RAM, warning when its
+/// `cpu_cores_count` vCPUs outnumber the host's logical CPUs. KVM lets vCPUs share
+/// CPUs, so that is no error.
+///
+/// # Returns
+/// * `Ok(())` if the VM fits the host
+/// * `Err(VmError)` if the VM wants more memory than the host has available, or the
+///   host couldn't be queried
+pub fn check_host_resources(memory_size: u64, cpu_cores_count: u32) -> Result<(), VmError> {
+    let (_, available) = get_physical_memory_info()?;
+    if memory_size > available {
+        return Err(VmError::memory(format!(
+            "VM memory of {} MiB exceeds the {} MiB of available host memory",
+            memory_size >> 20,
+            available >> 20
+        )));
+    }
+
+    // The topology only informs the warning, so hosts hiding it still run VMs
+    if let Ok(topology) = get_cpu_topology() && cpu_cores_count > topology.logical_cpus {
+        eprintln!(
+            "{} vCPUs exceed the {} logical CPUs of the host, they will share CPUs",
+            cpu_cores_count, topology.logical_cpus
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16318912 kB\nMemFree:         1203384 kB\nMemAvailable:    9876544 kB\nCached:          7000000 kB\n";
+        assert_eq!(parse_meminfo(meminfo).unwrap(), (16318912 * 1024, 9876544 * 1024));
+        // Old kernels without MemAvailable
+        assert_eq!(parse_meminfo("MemTotal: 2048 kB\nMemFree: 512 kB\nCached: 256 kB\n").unwrap(), (2048 * 1024, 768 * 1024));
+        assert!(parse_meminfo("MemFree: 512 kB\n").is_err());
+
+        let (total, available) = get_physical_memory_info().expect("Should get memory info on Linux");
+        assert!(total > 0 && available <= total);
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n").unwrap(), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_read_cpu_topology() {
+        // Two packages of two cores with two threads each
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("online"), "0-7\n").unwrap();
+        for cpu in 0..8 {
+            let topology = dir.path().join(format!("cpu{}/topology", cpu));
+            std::fs::create_dir_all(&topology).unwrap();
+            std::fs::write(topology.join("physical_package_id"), format!("{}\n", cpu / 4)).unwrap();
+            std::fs::write(topology.join("core_id"), format!("{}\n", cpu % 2)).unwrap();
+        }
+        let topology = read_cpu_topology(dir.path()).unwrap();
+        assert_eq!(topology, CpuTopology { logical_cpus: 8, cores: 4, packages: 2 });
+        assert_eq!(topology.get_threads_per_core(), 2);
+
+        assert!(check_host_resources(u64::MAX, 1).is_err());
+        assert!(check_host_resources(1 << 20, 1).is_ok());
+    }
+}
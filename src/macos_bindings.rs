@@ -0,0 +1,198 @@
+use std::ffi::CString;
+use applevisor::{Mappable, Mapping};
+use vm_memory::{GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use crate::error::VmError;
+
+/// Physical cores of the host, by kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreCounts {
+    /// Performance (P) cores; every core of an Intel Mac counts as one
+    pub performance: u32,
+    /// Efficiency (E) cores
+    pub efficiency: u32,
+}
+
+impl CoreCounts {
+    /// Returns the number of physical cores.
+    pub fn total(&self) -> u32 {
+        self.performance + self.efficiency
+    }
+}
+
+/// Reads the integer sysctl `name`.
+///
+/// # Returns
+/// * `Ok(Some(value))` on success
+/// * `Ok(None)` if the host doesn't know `name`, e.g. core kinds on Intel Macs
+/// * `Err(VmError)` if the sysctl couldn't be read
+fn read_sysctl(name: &str) -> Result<Option<u64>, VmError> {
+    let c_name = match CString::new(name) {
+        Ok(c_name) => c_name,
+        Err(_) => return Err(VmError::config(format!("invalid sysctl name {:?}", name)))
+    };
+    let mut value = [0u8; 8];
+    let mut len = value.len();
+    // SAFETY: `value` is valid for `len` bytes and the name is NUL terminated
+    let result = unsafe {
+        libc::sysctlbyname(c_name.as_ptr(), value.as_mut_ptr().cast(), &mut len, std::ptr::null_mut(), 0)
+    };
+    if result != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENOENT) {
+            return Ok(None);
+        }
+        return Err(VmError::io(format!("failed to read sysctl {}: {}", name, e), e));
+    }
+    match len {
+        4 => Ok(Some(u32::from_ne_bytes([value[0], value[1], value[2], value[3]]) as u64)),
+        8 => Ok(Some(u64::from_ne_bytes(value))),
+        _ => Err(VmError::config(format!("sysctl {} has an unexpected size of {} bytes", name, len)))
+    }
+}
+
+/// Reads an integer sysctl every macOS host has.
+fn read_required_sysctl(name: &str) -> Result<u64, VmError> {
+    match read_sysctl(name)? {
+        Some(value) => Ok(value),
+        None => Err(VmError::config(format!("sysctl {} doesn't exist on this host", name)))
+    }
+}
+
+/// Retrieves total and available physical memory on the host system.
+/// Returns a tuple: (total_physical_memory_bytes, available_physical_memory_bytes)
+///
+/// The total is `hw.memsize`. The available memory is estimated from
+/// `kern.memorystatus_level`, the percentage of memory the kernel considers free,
+/// so it moves in steps of one percent.
+pub fn get_physical_memory_info() -> Result<(u64, u64), VmError> {
+    let total = read_required_sysctl("hw.memsize")?;
+    let level = read_required_sysctl("kern.memorystatus_level")?.min(100);
+    Ok((total, (total as u128 * level as u128 / 100) as u64))
+}
+
+/// Retrieves the physical core counts of the host.
+///
+/// Apple Silicon reports one performance level per core kind, fastest first; hosts
+/// without performance levels have performance cores only.
+pub fn get_core_counts() -> Result<CoreCounts, VmError> {
+    let levels = read_sysctl("hw.nperflevels")?.unwrap_or(0);
+    if levels == 0 {
+        let cores = read_required_sysctl("hw.physicalcpu")?;
+        return Ok(CoreCounts { performance: cores as u32, efficiency: 0 });
+    }
+
+    let performance = read_required_sysctl("hw.perflevel0.physicalcpu")?;
+    // Any slower level counts as efficiency cores
+    let mut efficiency = 0;
+    for level in 1..levels {
+        efficiency += read_sysctl(&format!("hw.perflevel{}.physicalcpu", level))?.unwrap_or(0);
+    }
+    Ok(CoreCounts { performance: performance as u32, efficiency: efficiency as u32 })
+}
+
+/// Checks that the host can give a VM `memory_size` bytes of RAM and `cpu_cores_count`
+/// vCPUs, warning when the vCPUs outnumber the performance cores and may therefore
+/// run on efficiency cores.
+///
+/// # Returns
+/// * `Ok(())` if the VM fits the host
+/// * `Err(VmError)` if the VM wants more memory or vCPUs than the host has, or the
+///   host couldn't be queried
+pub fn check_host_resources(memory_size: u64, cpu_cores_count: u32) -> Result<(), VmError> {
+    let (total, _) = get_physical_memory_info()?;
+    if memory_size > total {
+        return Err(VmError::memory(format!(
+            "VM memory of {} MiB exceeds the {} MiB of the host",
+            memory_size >> 20,
+            total >> 20
+        )));
+    }
+
+    let cores = get_core_counts()?;
+    if cpu_cores_count > cores.total() {
+        return Err(VmError::config(format!("{} vCPUs exceed the {} cores of the host", cpu_cores_count, cores.total())));
+    }
+    if cpu_cores_count > cores.performance {
+        eprintln!(
+            "{} vCPUs exceed the {} performance cores of the host, some may run on efficiency cores",
+            cpu_cores_count, cores.performance
+        );
+    }
+    Ok(())
+}
+
+/// Suggests a VM size for this host: a quarter of its memory, at least 512 MiB,
+/// and one vCPU per performance core but one, so the host stays responsive.
+///
+/// # Returns
+/// * `Ok((mega_bytes, cpu_cores_count))` as taken by `VmSetup::builder`
+/// * `Err(VmError)` if the host couldn't be queried
+pub fn get_recommended_resources() -> Result<(u32, u32), VmError> {
+    let (total, _) = get_physical_memory_info()?;
+    let cores = get_core_counts()?;
+    let mega_bytes = ((total >> 20) / 4).clamp(512, u32::MAX as u64) as u32;
+    Ok((mega_bytes, cores.performance.saturating_sub(1).max(1)))
+}
+
+/// Exposes guest RAM allocated by applevisor through the vm-memory traits, so the
+/// virtio devices access it the same way as on the other hosts.
+///
+/// The returned memory aliases `mem` without owning it: it must be dropped, together
+/// with every device holding a clone, before `mem` is unmapped.
+///
+/// # Returns
+/// * `Ok(GuestMemoryMmap)` covering the mapping at its guest address
+/// * `Err(VmError)` if `mem` isn't mapped into the guest yet
+pub fn wrap_guest_memory(mem: &Mapping) -> Result<GuestMemoryMmap, VmError> {
+    let guest_addr = match mem.get_guest_addr() {
+        Some(guest_addr) => guest_addr,
+        None => return Err(VmError::memory("guest memory must be mapped before it can be shared with devices"))
+    };
+    // SAFETY: the host range is allocated by `mem` for its whole size and stays mapped
+    // as long as the caller keeps `mem`; a raw region never unmaps it
+    let region = match unsafe {
+        MmapRegion::build_raw(mem.get_host_addr() as *mut u8, mem.get_size(), libc::PROT_READ | libc::PROT_WRITE, libc::MAP_ANONYMOUS | libc::MAP_PRIVATE)
+    } {
+        Ok(region) => region,
+        Err(e) => return Err(VmError::memory_source(format!("failed to wrap guest memory: {}", e), e))
+    };
+    let region = match GuestRegionMmap::new(region, GuestAddress(guest_addr)) {
+        Ok(region) => region,
+        Err(e) => return Err(VmError::memory_source(format!("failed to wrap guest memory at {:#x}: {}", guest_addr, e), e))
+    };
+    match GuestMemoryMmap::from_regions(vec![region]) {
+        Ok(guest_memory) => Ok(guest_memory),
+        Err(e) => Err(VmError::memory_source(format!("failed to wrap guest memory at {:#x}: {}", guest_addr, e), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test retrieving physical memory information yields sane values
+    #[test]
+    fn test_get_physical_memory_info() {
+        let (total, available) = get_physical_memory_info().expect("Should get memory info on macOS");
+        assert!(total >= 512 * 1024 * 1024, "Total memory seems too small (< 512 MB)");
+        assert!(available <= total, "Available memory should not exceed total memory");
+    }
+
+    /// Test the core counts add up to the cores the host reports
+    #[test]
+    fn test_get_core_counts() {
+        let cores = get_core_counts().expect("Should get core counts on macOS");
+        assert!(cores.performance > 0, "Every host has performance cores");
+        assert_eq!(cores.total() as u64, read_sysctl("hw.physicalcpu").unwrap().unwrap());
+        assert_eq!(read_sysctl("hw.no_such_sysctl").unwrap(), None);
+    }
+
+    /// Test the recommended size passes the host checks
+    #[test]
+    fn test_recommended_resources_fit_host() {
+        let (mega_bytes, cpu_cores_count) = get_recommended_resources().unwrap();
+        assert!(check_host_resources((mega_bytes as u64) << 20, cpu_cores_count).is_ok());
+        assert!(check_host_resources(u64::MAX, 1).is_err());
+        assert!(check_host_resources(1 << 20, u32::MAX).is_err());
+    }
+}
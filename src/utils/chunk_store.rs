@@ -0,0 +1,577 @@
+//! Content-addressed, deduplicated storage of disk images.
+//!
+//! Images are split into fixed-size chunks named after their SHA-256 digest, so two
+//! versions of the same distribution image only store the chunks that differ between
+//! them. An image is described by a manifest listing its chunks in order; chunks that
+//! are entirely zero aren't stored at all and come back as holes when the image is
+//! reassembled.
+//!
+//! Layout below the store's root:
+//! * `chunks/<first two hex digits>/<digest>` - chunk contents
+//! * `manifests/<image name>` - `asgard-chunks 1 <chunk size> <image size>`, then one
+//!   digest (or `zero`) per line
+
+use std::collections::HashSet;
+use std::fs::{read_dir, remove_file, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use crate::error::VmError;
+
+/// Size of the chunks images are split into.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Largest chunk size accepted, chunks are read into memory whole.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+/// First line of every manifest, followed by the chunk size and the image size.
+const MANIFEST_HEADER: &str = "asgard-chunks 1";
+/// Granularity at which `copy_sparse` leaves holes.
+const SPARSE_BLOCK_SIZE: usize = 64 * 1024;
+/// Manifest entry of a chunk containing only zeroes.
+const ZERO_CHUNK: &str = "zero";
+
+/// Outcome of storing an image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Size of the image
+    pub image_size: u64,
+    /// Number of chunks the image was split into
+    pub chunks: usize,
+    /// Chunks that weren't in the store yet
+    pub new_chunks: usize,
+    /// Bytes written for the new chunks
+    pub bytes_written: u64,
+}
+
+/// Chunk list of an image, as kept in manifests.
+///
+/// The same format describes published images for delta downloads, see
+/// `delta_download`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkManifest {
+    /// Size of the chunks; the last one may be shorter
+    pub chunk_size: usize,
+    /// Size of the image
+    pub image_size: u64,
+    /// SHA-256 digest of every chunk in hex, or `None` for chunks containing only zeroes
+    pub chunks: Vec<Option<String>>,
+}
+
+impl ChunkManifest {
+    /// Splits the image at `path` into `chunk_size` chunks and hashes them.
+    ///
+    /// # Returns
+    /// * `Ok(ChunkManifest)` on success
+    /// * `Err(VmError)` if `chunk_size` is zero or above `MAX_CHUNK_SIZE`, or the image couldn't be read
+    pub fn from_image(path: &Path, chunk_size: usize) -> Result<ChunkManifest, VmError> {
+        for_each_chunk(path, chunk_size, |_, _, _| Ok(()))
+    }
+
+    /// Parses a manifest.
+    ///
+    /// # Returns
+    /// * `Some(ChunkManifest)` if `text` is a well-formed manifest
+    /// * `None` otherwise, including chunk sizes above `MAX_CHUNK_SIZE`
+    pub fn parse(text: &str) -> Option<ChunkManifest> {
+        let mut lines = text.lines();
+        let (chunk_size, image_size) = lines.next()?.strip_prefix(MANIFEST_HEADER)?.trim().split_once(' ')?;
+        let chunk_size = chunk_size.parse::<usize>().ok().filter(|chunk_size| (1..=MAX_CHUNK_SIZE).contains(chunk_size))?;
+        let image_size = image_size.parse::<u64>().ok()?;
+
+        let mut chunks = Vec::new();
+        for line in lines {
+            let is_digest = line.len() == 64 && line.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+            if line == ZERO_CHUNK {
+                chunks.push(None);
+            } else if is_digest {
+                chunks.push(Some(line.to_string()));
+            } else {
+                return None;
+            }
+        }
+        if chunks.len() as u64 != image_size.div_ceil(chunk_size as u64) {
+            return None;
+        }
+        Some(ChunkManifest { chunk_size, image_size, chunks })
+    }
+
+    /// Formats the manifest as `parse` reads it.
+    pub fn render(&self) -> String {
+        let mut text = format!("{} {} {}\n", MANIFEST_HEADER, self.chunk_size, self.image_size);
+        for chunk in &self.chunks {
+            text.push_str(chunk.as_deref().unwrap_or(ZERO_CHUNK));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Returns the byte range of the image covered by chunk `index`, empty past the end of the image.
+    pub fn get_chunk_range(&self, index: usize) -> Range<u64> {
+        let start = (index as u64).checked_mul(self.chunk_size as u64).map_or(self.image_size, |start| start.min(self.image_size));
+        start..start.checked_add(self.chunk_size as u64).map_or(self.image_size, |end| end.min(self.image_size))
+    }
+}
+
+/// Chunk store in one directory.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    root: PathBuf,
+    chunk_size: usize,
+}
+
+impl ChunkStore {
+    /// Opens the chunk store in `root` using `DEFAULT_CHUNK_SIZE`, creating it if needed.
+    ///
+    /// # Returns
+    /// * `Ok(ChunkStore)` on success
+    /// * `Err(VmError)` if the directories couldn't be created
+    pub fn new(root: impl Into<PathBuf>) -> Result<ChunkStore, VmError> {
+        Self::with_chunk_size(root, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Opens the chunk store in `root`, splitting newly stored images into `chunk_size` chunks.
+    ///
+    /// Images already stored keep the chunk size they were stored with; only images
+    /// stored with the same chunk size share chunks.
+    ///
+    /// # Returns
+    /// * `Ok(ChunkStore)` on success
+    /// * `Err(VmError)` if `chunk_size` is zero or above `MAX_CHUNK_SIZE`, or the directories couldn't be created
+    pub fn with_chunk_size(root: impl Into<PathBuf>, chunk_size: usize) -> Result<ChunkStore, VmError> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(VmError::config(format!("chunk size must be between 1 and {} bytes", MAX_CHUNK_SIZE)));
+        }
+        let store = ChunkStore { root: root.into(), chunk_size };
+        for dir in [store.root.join("chunks"), store.root.join("manifests")] {
+            if let Err(e) = std::fs::create_dir_all(&dir) {
+                return Err(VmError::io(format!("failed to create {}: {}", dir.display(), e), e));
+            }
+        }
+        Ok(store)
+    }
+
+    /// Returns the directory of the store.
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns `true` if an image named `name` is stored.
+    pub fn contains(&self, name: &str) -> bool {
+        check_image_name(name).is_ok() && self.get_manifest_path(name).is_file()
+    }
+
+    /// Returns the names of the stored images, sorted.
+    pub fn list_images(&self) -> Result<Vec<String>, VmError> {
+        let dir = self.root.join("manifests");
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", dir.display(), e), e))
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    // Manifests being written are hidden temporary files
+                    if !name.starts_with('.') {
+                        names.push(name);
+                    }
+                },
+                Err(e) => return Err(VmError::io(format!("failed to read {}: {}", dir.display(), e), e))
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Stores the image at `source` under `name`, replacing an image of the same name.
+    ///
+    /// # Returns
+    /// * `Ok(StoreStats)` on success
+    /// * `Err(VmError)` if the name is invalid or the image couldn't be read or stored
+    pub fn store_image(&self, name: &str, source: &Path) -> Result<StoreStats, VmError> {
+        check_image_name(name)?;
+        let mut stats = StoreStats::default();
+        let manifest = for_each_chunk(source, self.chunk_size, |_, chunk, digest| {
+            if self.write_chunk(digest, chunk)? {
+                stats.new_chunks += 1;
+                stats.bytes_written += chunk.len() as u64;
+            }
+            Ok(())
+        })?;
+        stats.image_size = manifest.image_size;
+        stats.chunks = manifest.chunks.len();
+        write_atomically(&self.get_manifest_path(name), manifest.render().as_bytes())?;
+        Ok(stats)
+    }
+
+    /// Reassembles the image `name` into `destination`, replacing it if it exists.
+    ///
+    /// Zero chunks are left as holes, so the result is sparse where the original was
+    /// zero. Every chunk is checked against its digest.
+    ///
+    /// # Returns
+    /// * `Ok(u64)` with the size of the image
+    /// * `Err(VmError)` if the image is unknown, a chunk is missing or corrupted, or
+    ///   `destination` couldn't be written
+    pub fn assemble_image(&self, name: &str, destination: &Path) -> Result<u64, VmError> {
+        let manifest = self.read_manifest(name)?;
+        let parent = destination.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let mut output = match NamedTempFile::new_in(parent) {
+            Ok(output) => output,
+            Err(e) => return Err(VmError::io(format!("failed to create a file in {}: {}", parent.display(), e), e))
+        };
+
+        let mut buffer = Vec::with_capacity(manifest.chunk_size);
+        for (index, entry) in manifest.chunks.iter().enumerate() {
+            let Some(entry) = entry else { continue };
+            let path = self.get_chunk_path(entry);
+            buffer.clear();
+            let read = File::open(&path).and_then(|mut chunk| chunk.read_to_end(&mut buffer));
+            if let Err(e) = read {
+                return Err(VmError::io(format!("failed to read chunk {} of image {}: {}", entry, name, e), e));
+            }
+            if get_digest(&buffer) != *entry {
+                return Err(VmError::image(format!("chunk {} of image {} is corrupted", entry, name)));
+            }
+            let written = output.as_file_mut().seek(SeekFrom::Start(manifest.get_chunk_range(index).start))
+                .and_then(|_| output.as_file_mut().write_all(&buffer));
+            if let Err(e) = written {
+                return Err(VmError::io(format!("failed to write {}: {}", destination.display(), e), e));
+            }
+        }
+        // Covers trailing zero chunks, which were never written
+        if let Err(e) = output.as_file().set_len(manifest.image_size) {
+            return Err(VmError::io(format!("failed to resize {}: {}", destination.display(), e), e));
+        }
+        if let Err(e) = output.persist(destination) {
+            return Err(VmError::io(format!("failed to create {}: {}", destination.display(), e.error), e.error));
+        }
+        Ok(manifest.image_size)
+    }
+
+    /// Forgets the image `name`. Its chunks stay until `collect_garbage` runs.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the image is unknown or its manifest couldn't be deleted
+    pub fn remove_image(&self, name: &str) -> Result<(), VmError> {
+        check_image_name(name)?;
+        let path = self.get_manifest_path(name);
+        match remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(VmError::image(format!("no image {} in the chunk store", name))),
+            Err(e) => Err(VmError::io(format!("failed to remove {}: {}", path.display(), e), e))
+        }
+    }
+
+    /// Deletes the chunks no stored image refers to.
+    ///
+    /// Must not run concurrently with `store_image`, which could be about to refer to
+    /// a chunk it found in the store.
+    ///
+    /// # Returns
+    /// * `Ok(u64)` with the number of bytes freed
+    /// * `Err(VmError)` if a manifest couldn't be read or a chunk couldn't be deleted
+    pub fn collect_garbage(&self) -> Result<u64, VmError> {
+        let mut referenced: HashSet<String> = HashSet::new();
+        for name in self.list_images()? {
+            referenced.extend(self.read_manifest(&name)?.chunks.into_iter().flatten());
+        }
+
+        let mut freed = 0;
+        let chunks_dir = self.root.join("chunks");
+        for path in list_files(&chunks_dir)? {
+            for chunk in list_files(&path)? {
+                let digest = chunk.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                if referenced.contains(&digest) {
+                    continue;
+                }
+                let size = chunk.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                match remove_file(&chunk) {
+                    Ok(()) => freed += size,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {},
+                    Err(e) => return Err(VmError::io(format!("failed to remove {}: {}", chunk.display(), e), e))
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    fn get_manifest_path(&self, name: &str) -> PathBuf {
+        self.root.join("manifests").join(name)
+    }
+
+    fn get_chunk_path(&self, digest: &str) -> PathBuf {
+        self.root.join("chunks").join(&digest[..2]).join(digest)
+    }
+
+    /// Writes a chunk unless the store has it already.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the chunk was written
+    /// * `Ok(false)` if the store already had it
+    fn write_chunk(&self, digest: &str, chunk: &[u8]) -> Result<bool, VmError> {
+        let path = self.get_chunk_path(digest);
+        if path.is_file() {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() && let Err(e) = std::fs::create_dir_all(parent) {
+            return Err(VmError::io(format!("failed to create {}: {}", parent.display(), e), e));
+        }
+        write_atomically(&path, chunk)?;
+        Ok(true)
+    }
+
+    /// Reads the manifest of `name`.
+    ///
+    /// # Returns
+    /// * `Ok(ChunkManifest)` on success
+    /// * `Err(VmError)` if the image is unknown or the manifest is malformed
+    fn read_manifest(&self, name: &str) -> Result<ChunkManifest, VmError> {
+        check_image_name(name)?;
+        let path = self.get_manifest_path(name);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(VmError::image(format!("no image {} in the chunk store", name))),
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e))
+        };
+        match ChunkManifest::parse(&text) {
+            Some(manifest) => Ok(manifest),
+            None => Err(VmError::image(format!("manifest of image {} is malformed", name)))
+        }
+    }
+}
+
+/// Image names are plain file names.
+fn check_image_name(name: &str) -> Result<(), VmError> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) || name.chars().any(char::is_control) {
+        return Err(VmError::config(format!("invalid image name {:?}", name)));
+    }
+    Ok(())
+}
+
+/// Splits the image at `path` into `chunk_size` chunks, calling `visit` with the index
+/// of every chunk that isn't all zeroes, the chunk and its digest.
+///
+/// # Returns
+/// * `Ok(ChunkManifest)` describing the image
+/// * `Err(VmError)` if `chunk_size` is zero or above `MAX_CHUNK_SIZE`, the image couldn't be read or `visit` failed
+pub(crate) fn for_each_chunk<F>(path: &Path, chunk_size: usize, mut visit: F) -> Result<ChunkManifest, VmError>
+where
+    F: FnMut(usize, &[u8], &str) -> Result<(), VmError>,
+{
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(VmError::config(format!("chunk size must be between 1 and {} bytes", MAX_CHUNK_SIZE)));
+    }
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path.display(), e), e))
+    };
+
+    let mut manifest = ChunkManifest { chunk_size, image_size: 0, chunks: Vec::new() };
+    let mut buffer = vec![0u8; chunk_size];
+    loop {
+        let len = match read_full(&mut file, &mut buffer) {
+            Ok(len) => len,
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e))
+        };
+        if len == 0 {
+            return Ok(manifest);
+        }
+        let chunk = &buffer[..len];
+        manifest.image_size += len as u64;
+        if chunk.iter().all(|&byte| byte == 0) {
+            manifest.chunks.push(None);
+            continue;
+        }
+        let digest = get_digest(chunk);
+        visit(manifest.chunks.len(), chunk, &digest)?;
+        manifest.chunks.push(Some(digest));
+    }
+}
+
+/// Returns the SHA-256 digest of `data` in hex.
+pub(crate) fn get_digest(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Reader passing data through while computing its size and SHA-256 digest.
+pub(crate) struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub(crate) fn new(inner: R) -> DigestReader<R> {
+        DigestReader { inner, hasher: Sha256::new(), len: 0 }
+    }
+
+    /// Returns the number of bytes read so far and their digest in hex.
+    pub(crate) fn finish(self) -> (u64, String) {
+        (self.len, to_hex(&self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+/// Copies `reader` into the empty `file`, seeking over blocks of zeroes instead of
+/// writing them so the file stays sparse.
+///
+/// # Returns
+/// * `Ok(size)` with the number of bytes copied, which is also the new file size
+/// * `Err(io::Error)` if reading or writing failed
+pub(crate) fn copy_sparse(reader: &mut impl Read, file: &mut File) -> std::io::Result<u64> {
+    let mut buffer = vec![0u8; SPARSE_BLOCK_SIZE];
+    let mut size = 0u64;
+    loop {
+        let len = read_full(reader, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        let block = &buffer[..len];
+        if block.iter().all(|&byte| byte == 0) {
+            file.seek(SeekFrom::Current(len as i64))?;
+        } else {
+            file.write_all(block)?;
+        }
+        size += len as u64;
+    }
+    // Extends the file over trailing zeroes that were skipped
+    file.set_len(size)?;
+    Ok(size)
+}
+
+/// Reads until `buffer` is full or the end of the input.
+pub(crate) fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Writes `path` through a hidden temporary file so readers never see partial content.
+fn write_atomically(path: &Path, content: &[u8]) -> Result<(), VmError> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let mut file = match tempfile::Builder::new().prefix(".tmp").tempfile_in(parent) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to create a file in {}: {}", parent.display(), e), e))
+    };
+    if let Err(e) = file.write_all(content) {
+        return Err(VmError::io(format!("failed to write {}: {}", path.display(), e), e));
+    }
+    if let Err(e) = file.persist(path) {
+        return Err(VmError::io(format!("failed to create {}: {}", path.display(), e.error), e.error));
+    }
+    Ok(())
+}
+
+/// Lists the entries of a directory, skipping hidden temporary files.
+fn list_files(dir: &Path) -> Result<Vec<PathBuf>, VmError> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(VmError::io(format!("failed to read {}: {}", dir.display(), e), e))
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) if !entry.file_name().to_string_lossy().starts_with('.') => paths.push(entry.path()),
+            Ok(_) => {},
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", dir.display(), e), e))
+        }
+    }
+    Ok(paths)
+}
+
+/// Formats `bytes` as lowercase hexadecimal.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = 4096;
+
+    // Helper: image of `chunks` distinct chunks, with chunk `zero` left zero
+    fn make_image(chunks: usize, zero: usize, seed: u8) -> Vec<u8> {
+        let mut image = vec![0u8; chunks * CHUNK + 100];
+        for (index, chunk) in image.chunks_mut(CHUNK).enumerate() {
+            if index != zero {
+                chunk.fill(seed.wrapping_add(index as u8));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_store_deduplicates_and_reassembles() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChunkStore::with_chunk_size(dir.path().join("store"), CHUNK).unwrap();
+
+        let v1 = make_image(8, 3, 1);
+        std::fs::write(dir.path().join("v1.img"), &v1).unwrap();
+        let stats = store.store_image("distro-v1", &dir.path().join("v1.img")).unwrap();
+        assert_eq!(stats, StoreStats { image_size: v1.len() as u64, chunks: 9, new_chunks: 8, bytes_written: 7 * CHUNK as u64 + 100 });
+
+        // A new version changing a single chunk only adds that chunk
+        let mut v2 = v1.clone();
+        v2[5 * CHUNK] = 0xff;
+        std::fs::write(dir.path().join("v2.img"), &v2).unwrap();
+        let stats = store.store_image("distro-v2", &dir.path().join("v2.img")).unwrap();
+        assert_eq!((stats.chunks, stats.new_chunks), (9, 1));
+        assert_eq!(store.list_images().unwrap(), vec!["distro-v1", "distro-v2"]);
+
+        let output = dir.path().join("out.img");
+        assert_eq!(store.assemble_image("distro-v1", &output).unwrap(), v1.len() as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), v1);
+        store.assemble_image("distro-v2", &output).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), v2);
+
+        // Only the chunk unique to v1 goes away with it
+        store.remove_image("distro-v1").unwrap();
+        assert_eq!(store.collect_garbage().unwrap(), CHUNK as u64);
+        assert!(!store.contains("distro-v1"));
+        store.assemble_image("distro-v2", &output).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), v2);
+    }
+
+    #[test]
+    fn test_corrupted_chunk_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ChunkStore::with_chunk_size(dir.path(), CHUNK).unwrap();
+        let image = dir.path().join("image");
+        std::fs::write(&image, make_image(2, 9, 7)).unwrap();
+        store.store_image("image", &image).unwrap();
+
+        let manifest = store.read_manifest("image").unwrap();
+        assert_eq!(ChunkManifest::parse(&manifest.render()), Some(manifest.clone()));
+        assert_eq!(manifest.get_chunk_range(2), 2 * CHUNK as u64..manifest.image_size);
+        assert!(manifest.get_chunk_range(usize::MAX).is_empty());
+        let oversized = ChunkManifest { chunk_size: MAX_CHUNK_SIZE + 1, image_size: 1, chunks: vec![None] };
+        assert_eq!(ChunkManifest::parse(&oversized.render()), None);
+        assert!(ChunkStore::with_chunk_size(dir.path(), MAX_CHUNK_SIZE + 1).is_err());
+        assert_eq!(ChunkManifest::from_image(&image, CHUNK).unwrap(), manifest);
+        std::fs::write(store.get_chunk_path(manifest.chunks[1].as_ref().unwrap()), [1u8; CHUNK]).unwrap();
+        assert!(matches!(store.assemble_image("image", &dir.path().join("out")), Err(VmError::Image { .. })));
+
+        assert!(store.store_image("../escape", &image).is_err());
+        assert!(store.assemble_image("missing", &dir.path().join("out")).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Delta downloads of updated images.
+//!
+//! Mirrors can publish a chunk index next to an image (`<image url>.chunks`, in the
+//! manifest format of the chunk store, see `write_chunk_index`). Refreshing a cached
+//! image then only fetches the chunks it doesn't already have, with HTTP range
+//! requests; everything else is copied from the cached copy. Images without an index
+//! are downloaded in full.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use reqwest::blocking::Client;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use tempfile::NamedTempFile;
+use crate::error::VmError;
+use crate::utils::chunk_store::{get_digest, read_full, ChunkManifest};
+
+/// Appended to the URL or path of an image to get the one of its chunk index.
+pub const CHUNK_INDEX_SUFFIX: &str = ".chunks";
+/// Largest number of bytes fetched by a single range request.
+const MAX_RANGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Outcome of `refresh_image`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeltaReport {
+    /// Size of the refreshed image
+    pub image_size: u64,
+    /// Bytes copied from the cached copy
+    pub reused_bytes: u64,
+    /// Bytes downloaded
+    pub downloaded_bytes: u64,
+    /// `true` if there was no chunk index and the whole image was downloaded
+    pub full_download: bool,
+}
+
+/// Writes the chunk index of `image` next to it, for publishing along with the image.
+///
+/// # Arguments
+/// * `image` - Image to index
+/// * `chunk_size` - Size of the chunks; smaller chunks make smaller deltas but bigger indexes
+///
+/// # Returns
+/// * `Ok(PathBuf)` with the path of the index
+/// * `Err(VmError)` if the image couldn't be read or the index couldn't be written
+pub fn write_chunk_index(image: &Path, chunk_size: usize) -> Result<PathBuf, VmError> {
+    let manifest = ChunkManifest::from_image(image, chunk_size)?;
+    let mut path = image.as_os_str().to_owned();
+    path.push(CHUNK_INDEX_SUFFIX);
+    let path = PathBuf::from(path);
+    if let Err(e) = std::fs::write(&path, manifest.render()) {
+        return Err(VmError::io(format!("failed to write {}: {}", path.display(), e), e));
+    }
+    Ok(path)
+}
+
+/// Brings the cached image at `cached` up to date with the one at `url`.
+///
+/// Only the chunks missing from the cached copy are downloaded, wherever they are in
+/// it; the new image is assembled next to the cached one and replaces it once every
+/// chunk has been checked against the index. Without a cached copy, or if the server
+/// publishes no index, the whole image is downloaded.
+///
+/// # Arguments
+/// * `url` - URL of the up to date image
+/// * `cached` - Cached copy to refresh, which doesn't need to exist
+///
+/// # Returns
+/// * `Ok(DeltaReport)` once `cached` holds the new image
+/// * `Err(VmError)` if the download failed, the server doesn't support range requests,
+///   or the downloaded data doesn't match the index
+pub fn refresh_image(url: &str, cached: &Path) -> Result<DeltaReport, VmError> {
+    let client = Client::new();
+    let index_url = format!("{}{}", url, CHUNK_INDEX_SUFFIX);
+    let response = match client.get(&index_url).send() {
+        Ok(response) => response,
+        Err(e) => return Err(VmError::image_source(format!("failed to download {}: {}", index_url, e), e)),
+    };
+    if response.status() == StatusCode::NOT_FOUND {
+        return download_full(&client, url, cached);
+    }
+    let text = match response.error_for_status().and_then(|response| response.text()) {
+        Ok(text) => text,
+        Err(e) => return Err(VmError::image_source(format!("failed to download {}: {}", index_url, e), e)),
+    };
+    let manifest = match ChunkManifest::parse(&text) {
+        Some(manifest) => manifest,
+        None => return Err(VmError::image(format!("chunk index {} is malformed", index_url))),
+    };
+
+    let mut output = create_output(cached)?;
+    if let Err(e) = output.as_file().set_len(manifest.image_size) {
+        return Err(VmError::io(format!("failed to resize {}: {}", output.path().display(), e), e));
+    }
+    let mut report = DeltaReport { image_size: manifest.image_size, ..Default::default() };
+    let missing = reuse_cached_chunks(&manifest, cached, output.as_file_mut(), &mut report)?;
+
+    // Fetch runs of consecutive missing chunks with one request each
+    let chunks_per_request = (MAX_RANGE_SIZE / manifest.chunk_size as u64).max(1) as usize;
+    let mut start = 0;
+    while start < missing.len() {
+        let mut end = start + 1;
+        while end < missing.len() && missing[end] == missing[end - 1] + 1 && end - start < chunks_per_request {
+            end += 1;
+        }
+        fetch_chunks(&client, url, &manifest, &missing[start..end], output.as_file_mut())?;
+        report.downloaded_bytes += missing[start..end].iter().map(|&index| {
+            let range = manifest.get_chunk_range(index);
+            range.end - range.start
+        }).sum::<u64>();
+        start = end;
+    }
+
+    if let Err(e) = output.persist(cached) {
+        return Err(VmError::io(format!("failed to replace {}: {}", cached.display(), e.error), e.error));
+    }
+    Ok(report)
+}
+
+/// Copies the chunks of `manifest` found in the cached copy into `output`.
+///
+/// # Returns
+/// * `Ok(Vec<usize>)` with the indexes of the chunks still to download, in order
+/// * `Err(VmError)` if the cached copy couldn't be read or `output` written
+fn reuse_cached_chunks(manifest: &ChunkManifest, cached: &Path, output: &mut File, report: &mut DeltaReport) -> Result<Vec<usize>, VmError> {
+    let mut local_chunks: HashMap<&str, usize> = HashMap::new();
+    let local = if cached.is_file() { Some(ChunkManifest::from_image(cached, manifest.chunk_size)?) } else { None };
+    if let Some(local) = &local {
+        for (index, digest) in local.chunks.iter().enumerate() {
+            if let Some(digest) = digest {
+                local_chunks.entry(digest.as_str()).or_insert(index);
+            }
+        }
+    }
+    let mut source = match local {
+        Some(_) => match File::open(cached) {
+            Ok(file) => Some(file),
+            Err(e) => return Err(VmError::io(format!("failed to open {}: {}", cached.display(), e), e)),
+        },
+        None => None,
+    };
+
+    let mut missing = Vec::new();
+    let mut buffer = vec![0u8; manifest.chunk_size];
+    for (index, digest) in manifest.chunks.iter().enumerate() {
+        // Zero chunks stay holes in the new image
+        let Some(digest) = digest else { continue };
+        let (Some(&local_index), Some(source)) = (local_chunks.get(digest.as_str()), source.as_mut()) else {
+            missing.push(index);
+            continue;
+        };
+        let range = manifest.get_chunk_range(index);
+        let chunk = &mut buffer[..(range.end - range.start) as usize];
+        let copied = source.seek(SeekFrom::Start(local_index as u64 * manifest.chunk_size as u64))
+            .and_then(|_| read_full(source, chunk))
+            .and_then(|_| output.seek(SeekFrom::Start(range.start)))
+            .and_then(|_| output.write_all(chunk));
+        if let Err(e) = copied {
+            return Err(VmError::io(format!("failed to copy a chunk of {}: {}", cached.display(), e), e));
+        }
+        report.reused_bytes += chunk.len() as u64;
+    }
+    Ok(missing)
+}
+
+/// Downloads the consecutive chunks `indexes` of the image at `url` into `output`,
+/// checking each against the index.
+fn fetch_chunks(client: &Client, url: &str, manifest: &ChunkManifest, indexes: &[usize], output: &mut File) -> Result<(), VmError> {
+    let start = manifest.get_chunk_range(indexes[0]).start;
+    let end = manifest.get_chunk_range(indexes[indexes.len() - 1]).end;
+    let mut response = match client.get(url).header(RANGE, format!("bytes={}-{}", start, end - 1)).send() {
+        Ok(response) => response,
+        Err(e) => return Err(VmError::image_source(format!("failed to download {}: {}", url, e), e)),
+    };
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(VmError::image(format!("{} answered a range request with {}; delta downloads need range support", url, response.status())));
+    }
+
+    let mut buffer = vec![0u8; manifest.chunk_size];
+    for &index in indexes {
+        let range = manifest.get_chunk_range(index);
+        let chunk = &mut buffer[..(range.end - range.start) as usize];
+        if let Err(e) = response.read_exact(chunk) {
+            return Err(VmError::io(format!("failed to download {}: {}", url, e), e));
+        }
+        if manifest.chunks[index].as_deref() != Some(get_digest(chunk).as_str()) {
+            return Err(VmError::image(format!("chunk {} of {} doesn't match its index", index, url)));
+        }
+        let written = output.seek(SeekFrom::Start(range.start)).and_then(|_| output.write_all(chunk));
+        if let Err(e) = written {
+            return Err(VmError::io(format!("failed to write a chunk of {}: {}", url, e), e));
+        }
+    }
+    Ok(())
+}
+
+/// Downloads the whole image at `url` over `cached`.
+fn download_full(client: &Client, url: &str, cached: &Path) -> Result<DeltaReport, VmError> {
+    let mut response = match client.get(url).send().and_then(|response| response.error_for_status()) {
+        Ok(response) => response,
+        Err(e) => return Err(VmError::image_source(format!("failed to download {}: {}", url, e), e)),
+    };
+    let mut output = create_output(cached)?;
+    let size = match std::io::copy(&mut response, output.as_file_mut()) {
+        Ok(size) => size,
+        Err(e) => return Err(VmError::io(format!("failed to download {}: {}", url, e), e)),
+    };
+    if let Err(e) = output.persist(cached) {
+        return Err(VmError::io(format!("failed to replace {}: {}", cached.display(), e.error), e.error));
+    }
+    Ok(DeltaReport { image_size: size, downloaded_bytes: size, full_download: true, ..Default::default() })
+}
+
+/// Creates the hidden file the new image is assembled in, next to `cached`.
+fn create_output(cached: &Path) -> Result<NamedTempFile, VmError> {
+    let parent = cached.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match tempfile::Builder::new().prefix(".delta").tempfile_in(parent) {
+        Ok(output) => Ok(output),
+        Err(e) => Err(VmError::io(format!("failed to create a file in {}: {}", parent.display(), e), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    const CHUNK: usize = 4096;
+
+    // Helper: serve the files of `dir` over HTTP with range support, returning the base URL
+    fn serve(dir: PathBuf) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let path = request.split(' ').nth(1).unwrap().trim_start_matches('/').to_string();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                }
+                let (status, body) = match (std::fs::read(dir.join(&path)), range) {
+                    (Ok(data), Some((start, end))) => ("206 Partial Content", data[start..=end].to_vec()),
+                    (Ok(data), None) => ("200 OK", data),
+                    (Err(_), _) => ("404 Not Found", Vec::new()),
+                };
+                let header = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        url
+    }
+
+    // Helper: image of `chunks` distinct chunks
+    fn make_image(chunks: usize, seed: u8) -> Vec<u8> {
+        (0..chunks * CHUNK + 10).map(|offset| seed.wrapping_add((offset / CHUNK) as u8)).collect()
+    }
+
+    #[test]
+    fn test_refresh_downloads_changed_chunks_only() {
+        let remote = tempfile::tempdir().unwrap();
+        let local = tempfile::tempdir().unwrap();
+        let url = serve(remote.path().to_path_buf());
+        let cached = local.path().join("distro.img");
+
+        let v1 = make_image(6, 1);
+        std::fs::write(&cached, &v1).unwrap();
+        // v2 changes chunk 2, zeroes chunk 4, moves chunk 5 to the front and adds two chunks
+        let mut v2 = v1.clone();
+        v2[2 * CHUNK] = 0xff;
+        v2[4 * CHUNK..5 * CHUNK].fill(0);
+        v2.copy_within(5 * CHUNK..6 * CHUNK, 0);
+        v2.truncate(6 * CHUNK);
+        v2.extend(vec![0x77; 2 * CHUNK + 5]);
+        std::fs::write(remote.path().join("distro.img"), &v2).unwrap();
+        write_chunk_index(&remote.path().join("distro.img"), CHUNK).unwrap();
+
+        let report = refresh_image(&format!("{}/distro.img", url), &cached).unwrap();
+        assert_eq!(std::fs::read(&cached).unwrap(), v2);
+        assert!(!report.full_download);
+        // Chunks 2, 6, 7 and the shorter last one are new
+        assert_eq!(report.downloaded_bytes, 2 * CHUNK as u64 + 5 + CHUNK as u64);
+        assert_eq!(report.reused_bytes, 4 * CHUNK as u64);
+        assert_eq!(report.image_size, v2.len() as u64);
+    }
+
+    #[test]
+    fn test_refresh_without_index_downloads_everything() {
+        let remote = tempfile::tempdir().unwrap();
+        let local = tempfile::tempdir().unwrap();
+        let url = serve(remote.path().to_path_buf());
+        let image = make_image(3, 9);
+        std::fs::write(remote.path().join("plain.img"), &image).unwrap();
+
+        let cached = local.path().join("plain.img");
+        let report = refresh_image(&format!("{}/plain.img", url), &cached).unwrap();
+        assert!(report.full_download);
+        assert_eq!(std::fs::read(&cached).unwrap(), image);
+
+        // A corrupted index is caught and leaves the cached copy alone
+        let index = write_chunk_index(&remote.path().join("plain.img"), CHUNK).unwrap();
+        let text = std::fs::read_to_string(&index).unwrap();
+        let first_digest = text.lines().nth(1).unwrap().to_string();
+        std::fs::write(&index, text.replace(&first_digest, &"0".repeat(64))).unwrap();
+        std::fs::write(&cached, b"old").unwrap();
+        assert!(refresh_image(&format!("{}/plain.img", url), &cached).is_err());
+        assert_eq!(std::fs::read(&cached).unwrap(), b"old");
+    }
+}
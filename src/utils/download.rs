@@ -0,0 +1,537 @@
+//! Resumable, verified downloads of large images.
+//!
+//! Data is written to `<destination>.part` first. When a transfer breaks off, the
+//! next attempt asks the server for the rest with an HTTP range request instead of
+//! starting over, and a partial file left behind by an earlier run is picked up the
+//! same way. Once complete, the file is checked against its expected checksum, if
+//! one is given, and only then renamed to its destination. Callers can follow the
+//! transfer with a progress callback, and async callers can download without blocking
+//! their runtime.
+
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256, Sha512};
+use crate::error::VmError;
+use crate::utils::chunk_store::{read_full, to_hex};
+use crate::vm_manager::gc::PARTIAL_DOWNLOAD_SUFFIX;
+
+/// Bytes read from the network at once.
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+/// Shortest time between two progress reports.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Hash function of a published checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+/// Expected digest of a downloaded file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    /// Hash function the digest was made with
+    pub algorithm: ChecksumAlgorithm,
+    /// Lowercase hex digest
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Parses a hex SHA-256 or SHA-512 digest, telling them apart by their length.
+    ///
+    /// # Returns
+    /// * `Ok(Checksum)` on success
+    /// * `Err(VmError)` if `digest` isn't a hex digest of either length
+    pub fn parse(digest: &str) -> Result<Checksum, VmError> {
+        let algorithm = match digest.len() {
+            64 => ChecksumAlgorithm::Sha256,
+            128 => ChecksumAlgorithm::Sha512,
+            _ => return Err(VmError::config(format!("{:?} isn't a SHA-256 or SHA-512 digest", digest)))
+        };
+        if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(VmError::config(format!("{:?} isn't a hex digest", digest)));
+        }
+        Ok(Checksum { algorithm, digest: digest.to_ascii_lowercase() })
+    }
+}
+
+/// How often and how patiently failed transfers are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made in total, at least one
+    pub max_attempts: u32,
+    /// Wait before the first retry; it doubles with every further retry
+    pub initial_delay: Duration,
+    /// Longest wait between two attempts
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy { max_attempts: 5, initial_delay: Duration::from_secs(1), max_delay: Duration::from_secs(30) }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the wait before attempt `attempt`, counted from 1.
+    pub fn get_delay(&self, attempt: u32) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(attempt - 2).unwrap_or(u32::MAX);
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+/// Outcome of `download_file`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadReport {
+    /// Size of the downloaded file
+    pub size: u64,
+    /// Bytes transferred over all attempts
+    pub downloaded_bytes: u64,
+    /// Bytes found in a partial file of an earlier run and kept
+    pub resumed_bytes: u64,
+    /// Attempts it took
+    pub attempts: u32,
+    /// `true` if the file was checked against a checksum
+    pub verified: bool,
+}
+
+/// Progress of a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// Bytes of the file on disk, including those of earlier attempts and runs
+    pub downloaded: u64,
+    /// Size of the file, if the server announced it
+    pub total: Option<u64>,
+    /// Average transfer rate of the current attempt, in bytes per second
+    pub bytes_per_second: u64,
+}
+
+/// Failure of one download attempt.
+enum AttemptError {
+    /// Worth another attempt, e.g. a dropped connection
+    Retry(VmError),
+    /// Won't get better by retrying, e.g. a missing file
+    Fatal(VmError),
+}
+
+/// Returns the path a download to `destination` is written to until it is complete.
+pub fn get_partial_path(destination: &Path) -> PathBuf {
+    let mut path = destination.as_os_str().to_owned();
+    path.push(PARTIAL_DOWNLOAD_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Downloads `url` to `destination`, resuming interrupted transfers.
+///
+/// See the module documentation. A partial file whose checksum turns out wrong is
+/// deleted, so the next call starts over.
+///
+/// # Arguments
+/// * `url` - URL of the file
+/// * `destination` - Path of the downloaded file; it is replaced if it exists
+/// * `checksum` - Digest the complete file must have
+/// * `policy` - Retry policy for failed transfers
+///
+/// # Returns
+/// * `Ok(DownloadReport)` once `destination` holds the complete file
+/// * `Err(VmError)` if the server refused the download, every attempt failed, or the
+///   file doesn't match `checksum`
+pub fn download_file(url: &str, destination: &Path, checksum: Option<&Checksum>, policy: &RetryPolicy) -> Result<DownloadReport, VmError> {
+    download_file_with_progress(url, destination, checksum, policy, &mut |_| {})
+}
+
+/// Downloads `url` to `destination` like `download_file`, calling `progress` as data
+/// comes in, a few times a second at most and once at the end of every attempt.
+pub fn download_file_with_progress(
+    url: &str,
+    destination: &Path,
+    checksum: Option<&Checksum>,
+    policy: &RetryPolicy,
+    progress: &mut dyn FnMut(DownloadProgress),
+) -> Result<DownloadReport, VmError> {
+    let client = Client::new();
+    let partial = get_partial_path(destination);
+    let mut report = DownloadReport { resumed_bytes: get_file_size(&partial), ..Default::default() };
+
+    loop {
+        report.attempts += 1;
+        std::thread::sleep(policy.get_delay(report.attempts));
+        match download_attempt(&client, url, &partial, &mut report, progress) {
+            Ok(()) => break,
+            Err(AttemptError::Retry(e)) if report.attempts < policy.max_attempts => {
+                eprintln!("Download of {} failed, retrying: {}", url, e);
+            },
+            Err(AttemptError::Retry(e)) | Err(AttemptError::Fatal(e)) => return Err(e),
+        }
+    }
+
+    if let Some(checksum) = checksum {
+        let digest = compute_checksum(&partial, checksum.algorithm)?;
+        if digest != checksum.digest {
+            let _ = remove_file(&partial);
+            return Err(VmError::image(format!("{} has digest {}, expected {}", url, digest, checksum.digest)));
+        }
+        report.verified = true;
+    }
+    report.size = get_file_size(&partial);
+    if let Err(e) = rename(&partial, destination) {
+        return Err(VmError::io(format!("failed to move {} to {}: {}", partial.display(), destination.display(), e), e));
+    }
+    Ok(report)
+}
+
+/// Transfers what is missing from `partial`, reporting progress along the way.
+fn download_attempt(
+    client: &Client,
+    url: &str,
+    partial: &Path,
+    report: &mut DownloadReport,
+    progress: &mut dyn FnMut(DownloadProgress),
+) -> Result<(), AttemptError> {
+    let offset = get_file_size(partial);
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    let mut response = match request.send() {
+        Ok(response) => response,
+        Err(e) => return Err(AttemptError::Retry(VmError::image_source(format!("failed to download {}: {}", url, e), e)))
+    };
+
+    let append = match response.status() {
+        StatusCode::PARTIAL_CONTENT if offset > 0 => true,
+        // The server ignores ranges, so the file comes whole
+        StatusCode::OK => false,
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+            // Nothing is left to fetch if the partial file is as long as the file
+            let size = response.headers().get(CONTENT_RANGE)
+                .and_then(|range| range.to_str().ok())
+                .and_then(|range| range.strip_prefix("bytes */"))
+                .and_then(|size| size.parse::<u64>().ok());
+            if size == Some(offset) {
+                return Ok(());
+            }
+            let _ = remove_file(partial);
+            return Err(AttemptError::Retry(VmError::image(format!("partial download of {} is longer than the file", url))));
+        },
+        status if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT => {
+            return Err(AttemptError::Retry(VmError::image(format!("failed to download {}: {}", url, status))));
+        },
+        status => return Err(AttemptError::Fatal(VmError::image(format!("failed to download {}: {}", url, status)))),
+    };
+
+    let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(partial);
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) => return Err(AttemptError::Fatal(VmError::io(format!("failed to open {}: {}", partial.display(), e), e)))
+    };
+    if !append {
+        report.resumed_bytes = 0;
+    }
+    let start = if append { offset } else { 0 };
+    let total = response.content_length().map(|len| start + len);
+    let started = Instant::now();
+    let mut last_update: Option<Instant> = None;
+    let mut written = start;
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let copied = loop {
+        let len = match response.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = file.write_all(&buffer[..len]) {
+            return Err(AttemptError::Fatal(VmError::io(format!("failed to write {}: {}", partial.display(), e), e)));
+        }
+        written += len as u64;
+        report.downloaded_bytes += len as u64;
+        if last_update.is_none_or(|last| last.elapsed() >= PROGRESS_INTERVAL) {
+            last_update = Some(Instant::now());
+            progress(get_progress(written, total, written - start, started));
+        }
+    };
+    progress(get_progress(written, total, written - start, started));
+    match copied {
+        Ok(()) => Ok(()),
+        Err(e) => Err(AttemptError::Retry(VmError::io(format!("failed to download {}: {}", url, e), e)))
+    }
+}
+
+/// Builds the progress of an attempt that wrote `attempt_bytes` since `started`.
+fn get_progress(downloaded: u64, total: Option<u64>, attempt_bytes: u64, started: Instant) -> DownloadProgress {
+    let elapsed = started.elapsed().as_secs_f64();
+    let bytes_per_second = if elapsed > 0.0 { (attempt_bytes as f64 / elapsed) as u64 } else { 0 };
+    DownloadProgress { downloaded, total, bytes_per_second }
+}
+
+/// Downloads `url` to `destination` like `download_file` without blocking the async
+/// runtime, calling `progress` from the calling task as data comes in.
+///
+/// Must be called from within a Tokio runtime.
+pub async fn download_file_async(
+    url: &str,
+    destination: &Path,
+    checksum: Option<&Checksum>,
+    policy: &RetryPolicy,
+    progress: impl FnMut(DownloadProgress),
+) -> Result<DownloadReport, VmError> {
+    let (url, destination, checksum, policy) = (url.to_string(), destination.to_path_buf(), checksum.cloned(), *policy);
+    run_with_progress(move |progress| download_file_with_progress(&url, &destination, checksum.as_ref(), &policy, progress), progress).await
+}
+
+/// Runs the blocking `job` on the blocking thread pool, forwarding the progress it
+/// reports to `progress` on the calling task.
+///
+/// reqwest's async client is built for an older Tokio than this crate's runtime, so
+/// transfers run on the blocking client.
+pub(crate) async fn run_with_progress<T: Send + 'static>(
+    job: impl FnOnce(&mut dyn FnMut(DownloadProgress)) -> Result<T, VmError> + Send + 'static,
+    mut progress: impl FnMut(DownloadProgress),
+) -> Result<T, VmError> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::task::spawn_blocking(move || job(&mut |update| {
+        let _ = sender.send(update);
+    }));
+    // The channel closes once the job is done with its sender
+    while let Some(update) = receiver.recv().await {
+        progress(update);
+    }
+    match task.await {
+        Ok(result) => result,
+        Err(e) => Err(VmError::io(format!("download task join error: {}", e), std::io::Error::other(e)))
+    }
+}
+
+/// Returns the size of the file at `path`, 0 if it doesn't exist.
+fn get_file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+/// Computes the lowercase hex digest of the file at `path`.
+///
+/// # Returns
+/// * `Ok(String)` on success
+/// * `Err(VmError)` if the file couldn't be read
+pub fn compute_checksum(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String, VmError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path.display(), e), e))
+    };
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => hash_file(&mut file, path, Sha256::new()),
+        ChecksumAlgorithm::Sha512 => hash_file(&mut file, path, Sha512::new()),
+    }
+}
+
+/// Feeds the content of `file` to `hasher`.
+fn hash_file<D: Digest>(file: &mut File, path: &Path, mut hasher: D) -> Result<String, VmError> {
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let len = match read_full(file, &mut buffer) {
+            Ok(len) => len,
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e))
+        };
+        if len == 0 {
+            return Ok(to_hex(&hasher.finalize()));
+        }
+        hasher.update(&buffer[..len]);
+    }
+}
+
+/// Finds the checksum of `file_name` in a checksum file as published by
+/// distributions: `SHA256SUMS` style lines of `<digest>  <name>`, with a `*` before
+/// binary names, or BSD style lines of `SHA512 (<name>) = <digest>`.
+pub fn parse_checksum_file(content: &str, file_name: &str) -> Option<Checksum> {
+    for line in content.lines() {
+        let line = line.trim();
+        let (name, digest) = match line.split_once(" (") {
+            Some((_, rest)) if line.starts_with("SHA") => match rest.split_once(") = ") {
+                Some((name, digest)) => (name, digest),
+                None => continue,
+            },
+            _ => match line.split_once(char::is_whitespace) {
+                Some((digest, name)) => (name.trim_start().trim_start_matches('*'), digest),
+                None => continue,
+            },
+        };
+        if name == file_name && let Ok(checksum) = Checksum::parse(digest.trim()) {
+            return Some(checksum);
+        }
+    }
+    None
+}
+
+/// Downloads a small text document, such as a checksum file or an index page, of up
+/// to 16 MiB.
+///
+/// # Returns
+/// * `Ok(String)` with the content
+/// * `Err(VmError)` if the download failed
+pub fn fetch_page(url: &str) -> Result<String, VmError> {
+    let response = match Client::new().get(url).send().and_then(|response| response.error_for_status()) {
+        Ok(response) => response,
+        Err(e) => return Err(VmError::image_source(format!("failed to download {}: {}", url, e), e))
+    };
+    let mut content = String::new();
+    if let Err(e) = response.take(16 * 1024 * 1024).read_to_string(&mut content) {
+        return Err(VmError::io(format!("failed to download {}: {}", url, e), e));
+    }
+    Ok(content)
+}
+
+/// Downloads the checksum file at `url` and finds the checksum of `file_name` in it.
+///
+/// # Returns
+/// * `Ok(Checksum)` on success
+/// * `Err(VmError)` if the download failed or the file lists no checksum of `file_name`
+pub fn fetch_checksum(url: &str, file_name: &str) -> Result<Checksum, VmError> {
+    let content = fetch_page(url)?;
+    match parse_checksum_file(&content, file_name) {
+        Some(checksum) => Ok(checksum),
+        None => Err(VmError::image(format!("{} lists no checksum of {}", url, file_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Helper: serve `data` with range support, breaking the first `broken` responses
+    // off halfway. Returns the URL and the number of requests served
+    fn serve(data: Vec<u8>, broken: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image.img", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut start = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        start = Some(value.trim().trim_end_matches('-').parse::<usize>().unwrap());
+                    }
+                }
+                let count = served.fetch_add(1, Ordering::SeqCst);
+                let header = match start {
+                    Some(start) if start >= data.len() => {
+                        format!("HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", data.len())
+                    },
+                    Some(start) => format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", data.len() - start),
+                    None => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", data.len()),
+                };
+                let body = &data[start.unwrap_or(0).min(data.len())..];
+                stream.write_all(header.as_bytes()).unwrap();
+                // Broken responses end early, short of their announced length
+                let body = if count < broken { &body[..body.len() / 2] } else { body };
+                stream.write_all(body).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy { max_attempts: 3, initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(5) }
+    }
+
+    #[test]
+    fn test_download_resumes_after_interruption() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let checksum = Checksum::parse(&to_hex(&Sha512::digest(&data))).unwrap();
+        let (url, requests) = serve(data.clone(), 1);
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("image.img");
+
+        let report = download_file(&url, &destination, Some(&checksum), &quick_policy()).expect("Download should succeed");
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        assert_eq!(report.attempts, 2);
+        assert_eq!(report.downloaded_bytes, data.len() as u64, "The retry only fetches the rest");
+        assert!(report.verified);
+        assert!(!get_partial_path(&destination).exists());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_download_picks_up_partial_file() {
+        let data = vec![7u8; 10_000];
+        let (url, _) = serve(data.clone(), 0);
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("image.img");
+
+        // A complete partial file only needs verifying
+        std::fs::write(get_partial_path(&destination), &data).unwrap();
+        let report = download_file(&url, &destination, None, &quick_policy()).unwrap();
+        assert_eq!((report.resumed_bytes, report.downloaded_bytes), (10_000, 0));
+
+        std::fs::write(get_partial_path(&destination), &data[..4000]).unwrap();
+        let report = download_file(&url, &destination, None, &quick_policy()).unwrap();
+        assert_eq!((report.resumed_bytes, report.downloaded_bytes), (4000, 6000));
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+
+        // A wrong checksum deletes the partial file
+        let wrong = Checksum::parse(&"0".repeat(64)).unwrap();
+        assert!(download_file(&url, &destination, Some(&wrong), &quick_policy()).is_err());
+        assert!(!get_partial_path(&destination).exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_async_reports_progress() {
+        let data = vec![3u8; 1_000_000];
+        let (url, _) = serve(data.clone(), 0);
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("image.img");
+
+        let mut updates = Vec::new();
+        let report = download_file_async(&url, &destination, None, &quick_policy(), |update| updates.push(update)).await.unwrap();
+        assert_eq!(report.size, 1_000_000);
+        let last = updates.last().expect("Progress should be reported");
+        assert_eq!((last.downloaded, last.total), (1_000_000, Some(1_000_000)));
+        assert!(updates.windows(2).all(|pair| pair[0].downloaded <= pair[1].downloaded));
+    }
+
+    #[test]
+    fn test_download_gives_up() {
+        let (url, requests) = serve(vec![1u8; 1000], usize::MAX);
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("image.img");
+        assert!(download_file(&url, &destination, None, &quick_policy()).is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn test_parse_checksum_file() {
+        let sha256 = "a".repeat(64);
+        let sha512 = "B".repeat(128);
+        let sums = format!("{}  other.img\n{} *ubuntu.img\nSHA512 (debian.qcow2) = {}\n", "c".repeat(64), sha256, sha512);
+        assert_eq!(parse_checksum_file(&sums, "ubuntu.img"), Some(Checksum { algorithm: ChecksumAlgorithm::Sha256, digest: sha256 }));
+        assert_eq!(parse_checksum_file(&sums, "debian.qcow2").unwrap().digest, "b".repeat(128));
+        assert_eq!(parse_checksum_file(&sums, "mint.iso"), None);
+        assert!(Checksum::parse("abc").is_err());
+        assert!(Checksum::parse(&"g".repeat(64)).is_err());
+
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.get_delay(1), Duration::ZERO);
+        assert_eq!(policy.get_delay(3), Duration::from_secs(2));
+        assert_eq!(policy.get_delay(40), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,450 @@
+//! Directory of cached disk images shared by the VMs of a host.
+//!
+//! Every regular file directly inside the store's root is a cached image; hidden
+//! files (names starting with a dot) are in-progress downloads or other temporary
+//! files and are left alone. Long-running hosts call `ImageStore::prune` now and then
+//! to evict images that haven't been used for a while or to stay under a size budget.
+//!
+//! Images can also be kept deduplicated in the store's chunk store (the hidden
+//! `.chunks` directory); `checkout` reassembles them into the store when a VM needs
+//! one, so pruning a reassembled image only costs disk space, not a new download.
+//!
+//! Distribution images are looked up and downloaded through the store too. Unless
+//! told otherwise it lives in the per-user cache directory, see `get_default_root`.
+
+use std::ffi::OsString;
+use std::fs::{read_dir, remove_file, File, FileTimes, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use crate::error::VmError;
+use crate::utils::chunk_store::{ChunkStore, StoreStats};
+use crate::utils::download::{run_with_progress, DownloadProgress, RetryPolicy};
+use crate::utils::img_setup::{download_distribution_image, is_distribution_image, Release};
+
+/// Directory of the chunk store inside the image store.
+const CHUNKS_DIR: &str = ".chunks";
+/// Directory of this crate inside the per-user cache directory.
+const CACHE_DIR_NAME: &str = "asgard-manager";
+
+/// A cached image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedImage {
+    /// File name of the image inside the store
+    pub name: String,
+    /// Full path of the image
+    pub path: PathBuf,
+    /// Disk space taken by the image; sparse images only count their allocated blocks
+    pub disk_usage: u64,
+    /// Last time the image was used or modified
+    pub last_used: SystemTime,
+}
+
+/// Which images `ImageStore::prune` evicts. Limits left at `None` don't apply.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrunePolicy {
+    /// Evict images unused for longer than this
+    pub max_age: Option<Duration>,
+    /// Evict the least recently used images until the store takes at most this many bytes
+    pub max_total_size: Option<u64>,
+    /// Names of images that are never evicted, e.g. those of running VMs
+    pub keep: Vec<String>,
+}
+
+/// Outcome of `ImageStore::prune`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Images that were deleted, least recently used first
+    pub removed: Vec<CachedImage>,
+    /// Disk space freed by the deletions
+    pub freed_bytes: u64,
+}
+
+/// Cache of disk images kept in one directory.
+#[derive(Debug, Clone)]
+pub struct ImageStore {
+    root: PathBuf,
+}
+
+/// Returns the default root of the image store: `asgard-manager/images` in the per-user
+/// cache directory, which is `$XDG_CACHE_HOME` (or `~/.cache`) on Linux,
+/// `~/Library/Caches` on macOS and `%LOCALAPPDATA%` on Windows.
+///
+/// # Returns
+/// * `Ok(PathBuf)` with the default root
+/// * `Err(VmError)` if the environment doesn't tell where the cache directory is
+pub fn get_default_root() -> Result<PathBuf, VmError> {
+    match default_root_from(|name| std::env::var_os(name)) {
+        Some(root) => Ok(root),
+        None => Err(VmError::config("can't find the cache directory for the image store, pass its root explicitly")),
+    }
+}
+
+/// Computes the default root from the environment variables `var` returns.
+fn default_root_from(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    // Relative paths are invalid, as the XDG base directory specification says
+    let absolute = |name: &str| var(name).map(PathBuf::from).filter(|path| path.is_absolute());
+    let cache = if cfg!(target_os = "windows") {
+        absolute("LOCALAPPDATA")?
+    } else if cfg!(target_os = "macos") {
+        absolute("HOME")?.join("Library").join("Caches")
+    } else {
+        match absolute("XDG_CACHE_HOME") {
+            Some(cache) => cache,
+            None => absolute("HOME")?.join(".cache"),
+        }
+    };
+    Some(cache.join(CACHE_DIR_NAME).join("images"))
+}
+
+impl ImageStore {
+    /// Opens the store in `root`, creating the directory if needed.
+    ///
+    /// # Returns
+    /// * `Ok(ImageStore)` on success
+    /// * `Err(VmError)` if the directory couldn't be created
+    pub fn new(root: impl Into<PathBuf>) -> Result<ImageStore, VmError> {
+        let root = root.into();
+        if let Err(e) = std::fs::create_dir_all(&root) {
+            return Err(VmError::io(format!("failed to create image store {}: {}", root.display(), e), e));
+        }
+        Ok(ImageStore { root })
+    }
+
+    /// Opens the store in its default root, see `get_default_root`.
+    ///
+    /// # Returns
+    /// * `Ok(ImageStore)` on success
+    /// * `Err(VmError)` if the default root is unknown or couldn't be created
+    pub fn open_default() -> Result<ImageStore, VmError> {
+        ImageStore::new(get_default_root()?)
+    }
+
+    /// Returns the directory holding the images.
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the path the image of `release` is downloaded to, whether it exists or not.
+    /// A bare `Distribution` stands for its latest LTS release.
+    pub fn path_for(&self, release: impl Into<Release>) -> PathBuf {
+        self.get_image_path(&release.into().get_image_file_name())
+    }
+
+    /// Looks for an image of `release` in the store: the one `download` fetched. For the
+    /// latest LTS release, which a bare `Distribution` stands for, the most recently used
+    /// image named after the distribution with its extension, e.g. `debian-server.qcow2`,
+    /// is taken otherwise.
+    ///
+    /// # Returns
+    /// * `Ok(Some(PathBuf))` with the path of the image
+    /// * `Ok(None)` if the store has no image of `release`
+    /// * `Err(VmError)` if the directory couldn't be read
+    pub fn lookup(&self, release: impl Into<Release>) -> Result<Option<PathBuf>, VmError> {
+        let release = release.into();
+        let path = self.path_for(release.clone());
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+        if release.get_version().is_some() {
+            return Ok(None);
+        }
+        let distribution = release.get_distribution();
+        let images = self.list()?;
+        Ok(images.into_iter().rev().find(|image| is_distribution_image(distribution, &image.name)).map(|image| image.path))
+    }
+
+    /// Downloads the image of `release` into the store with the default retry policy,
+    /// unless `lookup` finds one already.
+    ///
+    /// # Returns
+    /// * `Ok(PathBuf)` with the path of the image
+    /// * `Err(VmError)` if the release couldn't be resolved, or the download failed or
+    ///   didn't match the published checksum
+    pub fn download(&self, release: impl Into<Release>) -> Result<PathBuf, VmError> {
+        self.download_with_policy(release, &RetryPolicy::default())
+    }
+
+    /// Like `download`, retrying failed transfers as `policy` says.
+    pub fn download_with_policy(&self, release: impl Into<Release>, policy: &RetryPolicy) -> Result<PathBuf, VmError> {
+        download_distribution_image(self, &release.into(), policy, &mut |_| {})
+    }
+
+    /// Like `download`, without blocking the async runtime. `progress` is called from the
+    /// calling task as the image comes in. Must be called from within a Tokio runtime.
+    pub async fn download_async(&self, release: impl Into<Release>, progress: impl FnMut(DownloadProgress)) -> Result<PathBuf, VmError> {
+        let store = self.clone();
+        let release = release.into();
+        run_with_progress(
+            move |progress| download_distribution_image(&store, &release, &RetryPolicy::default(), progress),
+            progress,
+        ).await
+    }
+
+    /// Returns the path an image named `name` has in the store, whether it exists or not.
+    pub fn get_image_path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    /// Opens the chunk store holding the deduplicated images of this store.
+    ///
+    /// # Returns
+    /// * `Ok(ChunkStore)` on success
+    /// * `Err(VmError)` if its directories couldn't be created
+    pub fn get_chunk_store(&self) -> Result<ChunkStore, VmError> {
+        ChunkStore::new(self.root.join(CHUNKS_DIR))
+    }
+
+    /// Adds the cached image `name` to the chunk store, sharing the chunks it has in
+    /// common with the images already there. The cached copy is left in place; pruning
+    /// it later keeps the image available through `checkout`.
+    ///
+    /// # Returns
+    /// * `Ok(StoreStats)` on success
+    /// * `Err(VmError)` if the image doesn't exist or couldn't be stored
+    pub fn deduplicate(&self, name: &str) -> Result<StoreStats, VmError> {
+        self.get_chunk_store()?.store_image(name, &self.get_image_path(name))
+    }
+
+    /// Returns the path of the cached image `name`, reassembling it from the chunk
+    /// store first if it isn't cached. The image is marked as used either way.
+    ///
+    /// # Returns
+    /// * `Ok(PathBuf)` with the path of the image
+    /// * `Err(VmError)` if the image is neither cached nor in the chunk store, or
+    ///   couldn't be reassembled
+    pub fn checkout(&self, name: &str) -> Result<PathBuf, VmError> {
+        let path = self.get_image_path(name);
+        if !path.is_file() {
+            let chunks = self.get_chunk_store()?;
+            if !chunks.contains(name) {
+                return Err(VmError::image(format!("image {} isn't in image store {}", name, self.root.display())));
+            }
+            chunks.assemble_image(name, &path)?;
+        }
+        self.mark_used(name)?;
+        Ok(path)
+    }
+
+    /// Records that the image `name` was just used, so age-based pruning keeps it.
+    ///
+    /// Access times aren't reliably maintained by every filesystem, so callers
+    /// starting a VM from a cached image should mark it explicitly.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the image doesn't exist or its access time couldn't be set
+    pub fn mark_used(&self, name: &str) -> Result<(), VmError> {
+        let path = self.get_image_path(name);
+        let file = match File::options().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => return Err(VmError::io(format!("failed to open cached image {}: {}", path.display(), e), e))
+        };
+        if let Err(e) = file.set_times(FileTimes::new().set_accessed(SystemTime::now())) {
+            return Err(VmError::io(format!("failed to update the access time of {}: {}", path.display(), e), e));
+        }
+        Ok(())
+    }
+
+    /// Lists the cached images, least recently used first.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<CachedImage>)` on success
+    /// * `Err(VmError)` if the directory couldn't be read
+    pub fn list(&self) -> Result<Vec<CachedImage>, VmError> {
+        let entries = match read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) => return Err(VmError::io(format!("failed to read image store {}: {}", self.root.display(), e), e))
+        };
+
+        let mut images = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err(VmError::io(format!("failed to read image store {}: {}", self.root.display(), e), e))
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // Deleted since the directory was read
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(VmError::io(format!("failed to stat {}: {}", entry.path().display(), e), e))
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            images.push(CachedImage {
+                name,
+                path: entry.path(),
+                disk_usage: get_disk_usage(&metadata),
+                last_used: get_last_used(&metadata),
+            });
+        }
+        images.sort_by(|a, b| a.last_used.cmp(&b.last_used).then_with(|| a.name.cmp(&b.name)));
+        Ok(images)
+    }
+
+    /// Deletes the images selected by `policy`.
+    ///
+    /// Images older than `max_age` go first, then the least recently used ones until
+    /// the store fits in `max_total_size`. A VM still using a deleted image keeps
+    /// working on Unix hosts since it holds the file open. Every image is attempted
+    /// even if an earlier deletion fails.
+    ///
+    /// # Returns
+    /// * `Ok(PruneReport)` with the deleted images
+    /// * `Err(VmError)` if the store couldn't be read or some images couldn't be deleted
+    pub fn prune(&self, policy: &PrunePolicy) -> Result<PruneReport, VmError> {
+        let now = SystemTime::now();
+        let images = self.list()?;
+        let mut remaining: u64 = images.iter().map(|image| image.disk_usage).sum();
+
+        let mut report = PruneReport::default();
+        let mut failures: Vec<String> = Vec::new();
+        for image in images.into_iter().filter(|image| !policy.keep.contains(&image.name)) {
+            // An image used "in the future" (clock changes) counts as fresh
+            let too_old = policy.max_age.is_some_and(|max_age| now.duration_since(image.last_used).is_ok_and(|age| age > max_age));
+            let too_big = policy.max_total_size.is_some_and(|max_total_size| remaining > max_total_size);
+            if !too_old && !too_big {
+                continue;
+            }
+            match remove_file(&image.path) {
+                Ok(()) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => {
+                    failures.push(format!("{}: {}", image.path.display(), e));
+                    continue;
+                }
+            }
+            remaining = remaining.saturating_sub(image.disk_usage);
+            report.freed_bytes += image.disk_usage;
+            report.removed.push(image);
+        }
+
+        if failures.is_empty() {
+            Ok(report)
+        } else {
+            Err(VmError::image(format!("failed to prune image store {}: {}", self.root.display(), failures.join(", "))))
+        }
+    }
+}
+
+/// Returns the space a file takes on disk.
+fn get_disk_usage(metadata: &Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.blocks() * 512
+    }
+    #[cfg(not(unix))]
+    metadata.len()
+}
+
+/// Returns the latest of the access and modification times of a file.
+fn get_last_used(metadata: &Metadata) -> SystemTime {
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    match metadata.accessed() {
+        Ok(accessed) => accessed.max(modified),
+        Err(_) => modified,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    // Helper: add an image of `size` bytes last used `age` ago
+    fn add_image(store: &ImageStore, name: &str, size: usize, age: Duration) {
+        let path = store.get_image_path(name);
+        std::fs::write(&path, vec![0xAB; size]).unwrap();
+        let time = SystemTime::now() - age;
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_times(FileTimes::new().set_accessed(time).set_modified(time)).unwrap();
+    }
+
+    fn names(images: &[CachedImage]) -> Vec<&str> {
+        images.iter().map(|image| image.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_list_skips_hidden_files_and_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(dir.path().join("images")).unwrap();
+        add_image(&store, "new.img", 4096, DAY);
+        add_image(&store, "old.qcow2", 4096, 10 * DAY);
+        std::fs::write(store.get_image_path(".new.img.part"), b"partial").unwrap();
+        std::fs::create_dir(store.get_image_path("extracted")).unwrap();
+
+        let images = store.list().unwrap();
+        assert_eq!(names(&images), vec!["old.qcow2", "new.img"]);
+        assert!(images[0].disk_usage >= 4096);
+
+        store.mark_used("old.qcow2").unwrap();
+        assert_eq!(names(&store.list().unwrap()), vec!["new.img", "old.qcow2"]);
+    }
+
+    #[test]
+    fn test_prune_by_age_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        add_image(&store, "a.img", 64 * 1024, 30 * DAY);
+        add_image(&store, "b.img", 64 * 1024, 20 * DAY);
+        add_image(&store, "c.img", 64 * 1024, 10 * DAY);
+        add_image(&store, "d.img", 64 * 1024, DAY);
+
+        // Nothing to do without limits
+        assert_eq!(store.prune(&PrunePolicy::default()).unwrap(), PruneReport::default());
+
+        let report = store.prune(&PrunePolicy { max_age: Some(25 * DAY), ..Default::default() }).unwrap();
+        assert_eq!(names(&report.removed), vec!["a.img"]);
+        assert_eq!(report.freed_bytes, report.removed[0].disk_usage);
+
+        // "b.img" is kept, so the least recently used image after it goes instead
+        let usage = store.list().unwrap()[0].disk_usage;
+        let policy = PrunePolicy { max_total_size: Some(2 * usage), keep: vec!["b.img".to_string()], ..Default::default() };
+        let report = store.prune(&policy).unwrap();
+        assert_eq!(names(&report.removed), vec!["c.img"]);
+        assert_eq!(names(&store.list().unwrap()), vec!["b.img", "d.img"]);
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn test_default_root_from_xdg_cache() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| OsString::from(value))
+        };
+        assert_eq!(
+            default_root_from(env(&[("XDG_CACHE_HOME", "/var/cache/user"), ("HOME", "/home/user")])),
+            Some(PathBuf::from("/var/cache/user/asgard-manager/images"))
+        );
+        // A relative XDG_CACHE_HOME is ignored
+        assert_eq!(
+            default_root_from(env(&[("XDG_CACHE_HOME", "cache"), ("HOME", "/home/user")])),
+            Some(PathBuf::from("/home/user/.cache/asgard-manager/images"))
+        );
+        assert_eq!(default_root_from(env(&[])), None);
+    }
+
+    #[test]
+    fn test_checkout_reassembles_pruned_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        add_image(&store, "distro.img", 3 << 20, 30 * DAY);
+        store.deduplicate("distro.img").unwrap();
+        // The chunk store doesn't show up as a cached image
+        assert_eq!(names(&store.list().unwrap()), vec!["distro.img"]);
+
+        // Reading the image for deduplication may have refreshed its access time
+        add_image(&store, "distro.img", 3 << 20, 30 * DAY);
+        store.prune(&PrunePolicy { max_age: Some(DAY), ..Default::default() }).unwrap();
+        assert!(!store.get_image_path("distro.img").exists());
+
+        let path = store.checkout("distro.img").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0xAB; 3 << 20]);
+        assert!(store.checkout("other.img").is_err());
+    }
+}
@@ -1,27 +1,142 @@
-use std::fs::{File, read_dir};
-use reqwest::blocking::Client;
 use std::env;
-
-/// Supported Linux distributions
-#[derive(Copy, Clone)]
+use std::fs::{remove_file, rename, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use xz2::read::XzDecoder;
+use crate::error::VmError;
+use crate::image::guest::GuestOs;
+use crate::kernel_setup::guest_fs::compare_versions;
+use crate::utils::download::{download_file_with_progress, fetch_checksum, fetch_page, get_partial_path, DownloadProgress, RetryPolicy};
+use crate::utils::image_store::ImageStore;
+
+/// Supported guest distributions, Linux and BSD
+///
+/// Everything about a distribution lives in its `CATALOG` entry, so adding one takes
+/// a variant here and an entry there. Windows isn't one: its images are supplied by
+/// the user, see `GuestImage`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Distribution {
     Debian,
     Ubuntu,
     Mint,
+    Fedora,
+    Alpine,
+    Arch,
+    Rocky,
+    OpenSuse,
+    FreeBsd,
+    OpenBsd,
 }
 
 impl Distribution {
     /// Returns a lowercase string representation of the distribution
     pub fn as_str(&self) -> &str {
-        match self {
-            Distribution::Debian => "debian",
-            Distribution::Ubuntu => "ubuntu",
-            Distribution::Mint => "mint",
+        self.get_info().name
+    }
+
+    /// Returns the operating system of the distribution's guests
+    pub fn get_guest_os(&self) -> GuestOs {
+        self.get_info().guest_os
+    }
+
+    /// Returns every supported distribution, in catalog order
+    pub fn get_all() -> Vec<Distribution> {
+        CATALOG.iter().map(|info| info.distribution).collect()
+    }
+
+    /// Returns the distribution called `name`, as returned by `as_str`
+    pub fn from_name(name: &str) -> Option<Distribution> {
+        CATALOG.iter().find(|info| info.name == name).map(|info| info.distribution)
+    }
+
+    /// Returns the file name the image of the latest LTS release is downloaded to
+    pub fn get_image_file_name(&self) -> String {
+        self.latest_lts().get_image_file_name()
+    }
+
+    /// Returns the given release of the distribution, e.g. `Distribution::Ubuntu.release("24.04")`
+    ///
+    /// Debian releases can be given by codename too, e.g. `bookworm` for `12`. Whether
+    /// the release exists is only known once it is downloaded.
+    pub fn release(&self, version: &str) -> Release {
+        let codenames = self.get_info().codenames;
+        let version = match codenames.iter().find(|(_, codename)| *codename == version) {
+            Some((number, _)) => number,
+            None => version,
+        };
+        Release { distribution: *self, version: Some(version.to_string()) }
+    }
+
+    /// Returns the latest long-term support release of the distribution, resolved
+    /// when its image is downloaded
+    pub fn latest_lts(&self) -> Release {
+        Release { distribution: *self, version: None }
+    }
+
+    /// Lists the releases of the distribution, oldest first, by reading the index page
+    /// of its cloud images
+    ///
+    /// Distributions whose image URLs can't be derived from their version only list
+    /// the release of their catalog entry.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<String>)` with the release versions
+    /// * `Err(VmError)` if the index page couldn't be downloaded
+    pub fn list_releases(&self) -> Result<Vec<String>, VmError> {
+        let info = self.get_info();
+        match info.releases_index {
+            Some(index) => Ok(parse_release_index(info, &fetch_page(index)?)),
+            None => Ok(vec![info.default_version.to_string()]),
         }
     }
+
+    /// Returns the catalog entry of the distribution
+    fn get_info(&self) -> &'static DistributionInfo {
+        match CATALOG.iter().find(|info| info.distribution == *self) {
+            Some(info) => info,
+            None => unreachable!("{:?} is missing from the distribution catalog", self),
+        }
+    }
+}
+
+/// Release of a distribution whose image can be downloaded
+///
+/// Created by `Distribution::release` and `Distribution::latest_lts`; a bare
+/// `Distribution` converts into its latest LTS release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    distribution: Distribution,
+    /// Version of the release; `None` for the latest LTS release
+    version: Option<String>,
+}
+
+impl Release {
+    /// Returns the distribution of the release
+    pub fn get_distribution(&self) -> Distribution {
+        self.distribution
+    }
+
+    /// Returns the version of the release, or `None` for the latest LTS release
+    pub fn get_version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Returns the file name the image of the release is downloaded to, e.g.
+    /// `ubuntu-24.04.img`, or `ubuntu-lts.img` for the latest LTS release
+    pub fn get_image_file_name(&self) -> String {
+        let version = self.version.as_deref().unwrap_or("lts");
+        format!("{}-{}{}", self.distribution.as_str(), version, distribution_img_extension(self.distribution))
+    }
+}
+
+impl From<Distribution> for Release {
+    fn from(distribution: Distribution) -> Release {
+        distribution.latest_lts()
+    }
 }
 
 /// CPU architecture enumeration for image compatibility
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum Architecture {
     X86,      // 32-bit Intel/AMD
     X86_64,   // 64-bit Intel/AMD
@@ -30,6 +145,321 @@ enum Architecture {
     Unknown,  // Unrecognized architecture
 }
 
+impl Architecture {
+    /// Returns the name Rust gives the architecture
+    fn as_str(&self) -> &'static str {
+        match self {
+            Architecture::X86 => "x86",
+            Architecture::X86_64 => "x86_64",
+            Architecture::ARM => "arm",
+            Architecture::ARM64 => "aarch64",
+            Architecture::Unknown => "unknown",
+        }
+    }
+}
+
+/// Image of a distribution for one CPU architecture
+///
+/// The URL and checksum file name are templates: `{version}` stands for the release
+/// version and `{codename}` for its codename.
+#[derive(Debug)]
+struct ImageSource {
+    architecture: Architecture,
+    /// Direct download URL of the image
+    url: &'static str,
+    /// Checksum file covering the image, in the directory of the image
+    checksum_file: &'static str,
+}
+
+/// Catalog entry of a distribution
+#[derive(Debug)]
+struct DistributionInfo {
+    distribution: Distribution,
+    /// Operating system of the guests, telling how they boot
+    guest_os: GuestOs,
+    /// Lowercase name; cached images are recognized by it
+    name: &'static str,
+    /// Name shown in messages
+    display_name: &'static str,
+    /// Extension of the images, with its dot
+    extension: &'static str,
+    /// Images by architecture; architectures without one aren't supported
+    sources: &'static [ImageSource],
+    /// Release the image is downloaded for when the distribution has no release index
+    default_version: &'static str,
+    /// Page linking to a directory per release; `None` if the image URLs can't be
+    /// derived from the version, so only `default_version` can be downloaded
+    releases_index: Option<&'static str>,
+    /// Suffix of the release directories of the index after the version, e.g. `-RELEASE`
+    index_suffix: &'static str,
+    /// Whether a listed release is a long-term support release
+    is_lts: fn(&str) -> bool,
+    /// Codenames of the releases, by version, for distributions naming them so
+    codenames: &'static [(&'static str, &'static str)],
+}
+
+/// Every release counts as long-term support
+fn is_any_release(_: &str) -> bool {
+    true
+}
+
+/// Ubuntu LTS releases come out in April of even years, e.g. `24.04`
+fn is_ubuntu_lts(version: &str) -> bool {
+    match version.split_once('.') {
+        Some((year, "04")) => year.parse::<u32>().is_ok_and(|year| year % 2 == 0),
+        _ => false,
+    }
+}
+
+/// Rocky Linux directories named after a major version follow its latest minor release
+fn is_rocky_major(version: &str) -> bool {
+    !version.contains('.')
+}
+
+/// openSUSE Leap 42 came before Leap 15 despite its number
+fn is_opensuse_leap(version: &str) -> bool {
+    !version.starts_with("42.")
+}
+
+/// Catalog of the supported distributions
+static CATALOG: &[DistributionInfo] = &[
+    DistributionInfo {
+        distribution: Distribution::Debian,
+        guest_os: GuestOs::Linux,
+        name: "debian",
+        display_name: "Debian",
+        extension: ".qcow2",
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://cloud.debian.org/images/cloud/{codename}/latest/debian-{version}-generic-amd64.qcow2",
+                checksum_file: "SHA512SUMS",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://cloud.debian.org/images/cloud/{codename}/latest/debian-{version}-generic-arm64.qcow2",
+                checksum_file: "SHA512SUMS",
+            },
+        ],
+        default_version: "12",
+        releases_index: Some("https://cloud.debian.org/images/cloud/"),
+        index_suffix: "",
+        is_lts: is_any_release,
+        codenames: &[("10", "buster"), ("11", "bullseye"), ("12", "bookworm"), ("13", "trixie")],
+    },
+    DistributionInfo {
+        distribution: Distribution::Ubuntu,
+        guest_os: GuestOs::Linux,
+        name: "ubuntu",
+        display_name: "Ubuntu",
+        extension: ".img",
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://cloud-images.ubuntu.com/releases/{version}/release/ubuntu-{version}-server-cloudimg-amd64.img",
+                checksum_file: "SHA256SUMS",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://cloud-images.ubuntu.com/releases/{version}/release/ubuntu-{version}-server-cloudimg-arm64.img",
+                checksum_file: "SHA256SUMS",
+            },
+        ],
+        default_version: "24.04",
+        releases_index: Some("https://cloud-images.ubuntu.com/releases/"),
+        index_suffix: "",
+        is_lts: is_ubuntu_lts,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::Mint,
+        guest_os: GuestOs::Linux,
+        name: "mint",
+        display_name: "Linux Mint",
+        extension: ".iso",
+        // Linux Mint is not officially available for ARM64
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://mirrors.edge.kernel.org/linuxmint/stable/{version}/linuxmint-{version}-cinnamon-64bit.iso",
+                checksum_file: "sha256sum.txt",
+            },
+        ],
+        default_version: "21.3",
+        releases_index: Some("https://mirrors.edge.kernel.org/linuxmint/stable/"),
+        index_suffix: "",
+        is_lts: is_any_release,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::Fedora,
+        guest_os: GuestOs::Linux,
+        name: "fedora",
+        display_name: "Fedora Cloud",
+        extension: ".qcow2",
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://download.fedoraproject.org/pub/fedora/linux/releases/40/Cloud/x86_64/images/Fedora-Cloud-Base-Generic.x86_64-40-1.14.qcow2",
+                checksum_file: "Fedora-Cloud-40-1.14-x86_64-CHECKSUM",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://download.fedoraproject.org/pub/fedora/linux/releases/40/Cloud/aarch64/images/Fedora-Cloud-Base-Generic.aarch64-40-1.14.qcow2",
+                checksum_file: "Fedora-Cloud-40-1.14-aarch64-CHECKSUM",
+            },
+        ],
+        default_version: "40",
+        releases_index: None,
+        index_suffix: "",
+        is_lts: is_any_release,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::Alpine,
+        guest_os: GuestOs::Linux,
+        name: "alpine",
+        display_name: "Alpine Linux (virt)",
+        extension: ".iso",
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://dl-cdn.alpinelinux.org/alpine/v3.20/releases/x86_64/alpine-virt-3.20.3-x86_64.iso",
+                checksum_file: "alpine-virt-3.20.3-x86_64.iso.sha256",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://dl-cdn.alpinelinux.org/alpine/v3.20/releases/aarch64/alpine-virt-3.20.3-aarch64.iso",
+                checksum_file: "alpine-virt-3.20.3-aarch64.iso.sha256",
+            },
+        ],
+        default_version: "3.20.3",
+        releases_index: None,
+        index_suffix: "",
+        is_lts: is_any_release,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::Arch,
+        guest_os: GuestOs::Linux,
+        name: "arch",
+        display_name: "Arch Linux",
+        extension: ".qcow2",
+        // Arch Linux only publishes x86_64 cloud images
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://geo.mirror.pkgbuild.com/images/latest/Arch-Linux-x86_64-cloudimg.qcow2",
+                checksum_file: "Arch-Linux-x86_64-cloudimg.qcow2.SHA256",
+            },
+        ],
+        default_version: "latest",
+        releases_index: None,
+        index_suffix: "",
+        is_lts: is_any_release,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::Rocky,
+        guest_os: GuestOs::Linux,
+        name: "rocky",
+        display_name: "Rocky Linux",
+        extension: ".qcow2",
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://dl.rockylinux.org/pub/rocky/{version}/images/x86_64/Rocky-{version}-GenericCloud-Base.latest.x86_64.qcow2",
+                checksum_file: "CHECKSUM",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://dl.rockylinux.org/pub/rocky/{version}/images/aarch64/Rocky-{version}-GenericCloud-Base.latest.aarch64.qcow2",
+                checksum_file: "CHECKSUM",
+            },
+        ],
+        default_version: "9",
+        releases_index: Some("https://dl.rockylinux.org/pub/rocky/"),
+        index_suffix: "",
+        is_lts: is_rocky_major,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::OpenSuse,
+        guest_os: GuestOs::Linux,
+        name: "opensuse",
+        display_name: "openSUSE Leap",
+        extension: ".qcow2",
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://download.opensuse.org/distribution/leap/{version}/appliances/openSUSE-Leap-{version}-Minimal-VM.x86_64-Cloud.qcow2",
+                checksum_file: "openSUSE-Leap-{version}-Minimal-VM.x86_64-Cloud.qcow2.sha256",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://download.opensuse.org/distribution/leap/{version}/appliances/openSUSE-Leap-{version}-Minimal-VM.aarch64-Cloud.qcow2",
+                checksum_file: "openSUSE-Leap-{version}-Minimal-VM.aarch64-Cloud.qcow2.sha256",
+            },
+        ],
+        default_version: "15.6",
+        releases_index: Some("https://download.opensuse.org/distribution/leap/"),
+        index_suffix: "",
+        is_lts: is_opensuse_leap,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::FreeBsd,
+        guest_os: GuestOs::FreeBsd,
+        name: "freebsd",
+        display_name: "FreeBSD",
+        extension: ".qcow2",
+        // FreeBSD only publishes its VM images compressed; they are unpacked once verified
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://download.freebsd.org/releases/VM-IMAGES/{version}-RELEASE/amd64/Latest/FreeBSD-{version}-RELEASE-amd64-BASIC-CLOUDINIT-ufs.qcow2.xz",
+                checksum_file: "CHECKSUM.SHA256",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://download.freebsd.org/releases/VM-IMAGES/{version}-RELEASE/aarch64/Latest/FreeBSD-{version}-RELEASE-arm64-aarch64-BASIC-CLOUDINIT-ufs.qcow2.xz",
+                checksum_file: "CHECKSUM.SHA256",
+            },
+        ],
+        default_version: "14.1",
+        releases_index: Some("https://download.freebsd.org/releases/VM-IMAGES/"),
+        index_suffix: "-RELEASE",
+        is_lts: is_any_release,
+        codenames: &[],
+    },
+    DistributionInfo {
+        distribution: Distribution::OpenBsd,
+        guest_os: GuestOs::OpenBsd,
+        name: "openbsd",
+        display_name: "OpenBSD",
+        extension: ".img",
+        // OpenBSD publishes no cloud images; its installer disk image stands in, and
+        // names its files after the version without the dot
+        sources: &[
+            ImageSource {
+                architecture: Architecture::X86_64,
+                url: "https://cdn.openbsd.org/pub/OpenBSD/7.6/amd64/install76.img",
+                checksum_file: "SHA256",
+            },
+            ImageSource {
+                architecture: Architecture::ARM64,
+                url: "https://cdn.openbsd.org/pub/OpenBSD/7.6/arm64/install76.img",
+                checksum_file: "SHA256",
+            },
+        ],
+        default_version: "7.6",
+        releases_index: None,
+        index_suffix: "",
+        is_lts: is_any_release,
+        codenames: &[],
+    },
+];
+
 /// Detects the current system architecture using compile-time constants
 fn detect_architecture() -> Architecture {
     match env::consts::ARCH {
@@ -43,114 +473,201 @@ fn detect_architecture() -> Architecture {
 
 /// Maps a distribution to its expected disk image file extension
 fn distribution_img_extension(distribution: Distribution) -> &'static str {
-    match distribution {
-        Distribution::Debian => ".qcow2",
-        Distribution::Ubuntu => ".img",
-        Distribution::Mint => ".iso",
-    }
+    distribution.get_info().extension
 }
 
-/// Returns a direct download URL for a given distribution, based on detected architecture
-fn get_url_to_linux_distribution_download(distribution: Distribution) -> Result<String, String> {
-    let cpu_architecture = detect_architecture();
+/// Returns the image of a distribution for the given architecture
+fn get_image_source(distribution: Distribution, architecture: Architecture) -> Result<&'static ImageSource, VmError> {
+    let info = distribution.get_info();
+    match info.sources.iter().find(|source| source.architecture == architecture) {
+        Some(source) => Ok(source),
+        None => Err(VmError::image(format!("{} has no image for the {} architecture", info.display_name, architecture.as_str()))),
+    }
+}
 
-    match cpu_architecture {
-        Architecture::X86_64 => match distribution {
-            Distribution::Debian => Ok("https://cloud.debian.org/images/cloud/bullseye/latest/debian-11-generic-amd64.qcow2".to_string()),
-            Distribution::Ubuntu => Ok("https://cloud-images.ubuntu.com/releases/22.04/release/ubuntu-22.04-server-cloudimg-amd64.img".to_string()),
-            Distribution::Mint => Ok("https://mirrors.edge.kernel.org/linuxmint/stable/21.3/linuxmint-21.3-cinnamon-64bit.iso".to_string()),
-        },
-        Architecture::ARM64 => match distribution {
-            Distribution::Debian => Ok("https://cloud.debian.org/images/cloud/bullseye/latest/debian-11-generic-arm64.qcow2".to_string()),
-            Distribution::Ubuntu => Ok("https://cloud-images.ubuntu.com/releases/22.04/release/ubuntu-22.04-server-cloudimg-arm64.img".to_string()),
-            Distribution::Mint => Err("Linux Mint is not officially available for ARM64 architecture".to_string()),
-        },
-        _ => Err("Device architecture is not supported for cloud image installation.".to_string()),
+/// Fills the `{version}` and `{codename}` placeholders of a catalog template
+fn expand_template(info: &DistributionInfo, template: &str, version: &str) -> Result<String, VmError> {
+    let expanded = template.replace("{version}", version);
+    if !expanded.contains("{codename}") {
+        return Ok(expanded);
+    }
+    match info.codenames.iter().find(|(number, _)| *number == version) {
+        Some((_, codename)) => Ok(expanded.replace("{codename}", codename)),
+        None => Err(VmError::image(format!("{} {} is not a known release", info.display_name, version))),
     }
 }
 
-/// Checks whether an image file for the specified distribution is present in the current directory
-pub fn check_if_linux_distribution_img_present_in_current_dir(distribution: Distribution) -> Result<(), String> {
-    let entries = match read_dir(".") {
-        Ok(entries) => entries,
-        Err(e) => return Err(format!("{:?}", e)),
-    };
+/// Returns the download URL of the image of a release and the URL of the checksum
+/// file covering it, for the given architecture
+fn get_image_urls(distribution: Distribution, version: &str, architecture: Architecture) -> Result<(String, String), VmError> {
+    let info = distribution.get_info();
+    let source = get_image_source(distribution, architecture)?;
+    let url = expand_template(info, source.url, version)?;
+    let directory = url.rsplit_once('/').map_or(url.as_str(), |(directory, _)| directory);
+    let checksum_url = format!("{}/{}", directory, expand_template(info, source.checksum_file, version)?);
+    Ok((url, checksum_url))
+}
 
-    for entry in entries {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(e) => return Err(format!("{:?}", e)),
+/// Returns the direct download URL of the latest release of the catalog for a given
+/// distribution, based on detected architecture
+fn get_url_to_linux_distribution_download(distribution: Distribution) -> Result<String, VmError> {
+    let version = distribution.get_info().default_version;
+    Ok(get_image_urls(distribution, version, detect_architecture())?.0)
+}
+
+/// Lists the releases linked from the index page of a distribution, oldest first
+///
+/// Links to version directories such as `24.04/`, or `14.1-RELEASE/` for an index
+/// suffix of `-RELEASE`, are kept, as are links to known codenames, which are listed
+/// by version.
+fn parse_release_index(info: &DistributionInfo, page: &str) -> Vec<String> {
+    let mut releases: Vec<String> = Vec::new();
+    for link in page.split("href=\"").skip(1).filter_map(|rest| rest.split('"').next()) {
+        let name = link.trim_start_matches("./").trim_end_matches('/');
+        let name = match name.strip_suffix(info.index_suffix) {
+            Some(name) => name,
+            None => continue,
+        };
+        let version = match info.codenames.iter().find(|(_, codename)| *codename == name) {
+            Some((number, _)) => number,
+            None if name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c.is_ascii_digit() || c == '.') => name,
+            None => continue,
         };
-        let filename = entry.file_name().to_string_lossy().into_owned();
-        if filename.contains(distribution.as_str()) && filename.ends_with(distribution_img_extension(distribution)) {
-            return Ok(());
+        if !releases.iter().any(|release| release == version) {
+            releases.push(version.to_string());
         }
     }
-
-    Err(format!("{} image file not found in this directory", distribution.as_str()))
+    releases.sort_by(|a, b| compare_versions(a, b));
+    releases
 }
 
-/// Downloads the Linux image for the specified distribution, if not already present
-pub fn download_linux_lts_image(distribution: Distribution) -> Result<(), String> {
-    match check_if_linux_distribution_img_present_in_current_dir(distribution) {
-        Ok(_) => {
-            let filename = format!("{}-lts.img", distribution.as_str());
-
-            // Get the download URL for the specified distribution and architecture
-            let url = match get_url_to_linux_distribution_download(distribution) {
-                Ok(url) => url,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-
-            // Create a blocking HTTP client
-            let client = Client::new();
-
-            // Send the HTTP GET request
-            let mut response = match client.get(&url).send() {
-                Ok(response) => response,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-
-            // Open a local file for writing the image
-            let mut file = match File::create(filename) {
-                Ok(file) => file,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-
-            // Copy the downloaded bytes to the local file
-            if let Err(e) = std::io::copy(&mut response, &mut file) {
-                return Err(format!("{:?}", e));
+/// Returns the version of a release, looking the latest LTS release up in the index
+/// of the distribution
+fn resolve_version(release: &Release) -> Result<String, VmError> {
+    let info = release.distribution.get_info();
+    match (&release.version, info.releases_index) {
+        (Some(version), Some(_)) => Ok(version.clone()),
+        (Some(version), None) if version == info.default_version => Ok(version.clone()),
+        (Some(version), None) => Err(VmError::image(format!(
+            "{} images can only be downloaded for release {}, not {}",
+            info.display_name, info.default_version, version
+        ))),
+        (None, None) => Ok(info.default_version.to_string()),
+        (None, Some(index)) => {
+            let releases = release.distribution.list_releases()?;
+            match releases.into_iter().rev().find(|version| (info.is_lts)(version)) {
+                Some(version) => Ok(version),
+                None => Err(VmError::image(format!("no {} LTS release is listed at {}", info.display_name, index))),
             }
-
-            Ok(())
-        }
-        Err(e) => Err(format!("{:?}", e)),
+        },
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::{self, File};
-    use std::io::Write;
-    use std::path::PathBuf;
+/// Whether `filename` names an image of the specified distribution: it contains the
+/// distribution name and ends with its image extension, e.g. `debian-server.qcow2`
+pub(crate) fn is_distribution_image(distribution: Distribution, filename: &str) -> bool {
+    filename.contains(distribution.as_str()) && filename.ends_with(distribution_img_extension(distribution))
+}
 
-    fn setup_temp_test_dir(name: &str) -> (PathBuf, PathBuf) {
-        let current_dir = env::current_dir().unwrap();
-        let temp_dir = current_dir.join(name);
+/// Downloads the image of a Linux release into the default image store, if not
+/// already present, and returns its path
+///
+/// A bare `Distribution` stands for its latest LTS release, e.g.
+/// `download_linux_lts_image(Distribution::Debian)`; pick another release with
+/// `Distribution::release`. The image is checked against the checksum the
+/// distribution publishes for it. An interrupted download is resumed, by the retries
+/// of `policy` and by the next call.
+pub fn download_linux_lts_image_with_policy(release: impl Into<Release>, policy: &RetryPolicy) -> Result<PathBuf, VmError> {
+    ImageStore::open_default()?.download_with_policy(release, policy)
+}
 
-        if temp_dir.exists() {
-            fs::remove_dir_all(&temp_dir).unwrap();
-        }
+/// Downloads the image of a Linux release into the default image store, if not
+/// already present, with the default retry policy
+pub fn download_linux_lts_image(release: impl Into<Release>) -> Result<PathBuf, VmError> {
+    ImageStore::open_default()?.download(release)
+}
+
+/// Downloads the image of a Linux release into the default image store, if not
+/// already present, without blocking the async runtime
+///
+/// `progress` is called from the calling task as the image comes in, so GUIs and CLIs
+/// can show bytes downloaded, total size and transfer rate. Must be called from within
+/// a Tokio runtime.
+pub async fn download_linux_lts_image_async(release: impl Into<Release>, progress: impl FnMut(DownloadProgress)) -> Result<PathBuf, VmError> {
+    ImageStore::open_default()?.download_async(release, progress).await
+}
+
+/// Downloads and verifies the image of `release` into `store`, unless the store
+/// already has one, and returns the path of the image
+pub(crate) fn download_distribution_image(
+    store: &ImageStore,
+    release: &Release,
+    policy: &RetryPolicy,
+    progress: &mut dyn FnMut(DownloadProgress),
+) -> Result<PathBuf, VmError> {
+    if let Some(path) = store.lookup(release.clone())? {
+        return Ok(path);
+    }
+    let path = store.path_for(release.clone());
 
-        fs::create_dir(&temp_dir).unwrap();
+    // Get the download URL and the published checksum for the release and architecture
+    let version = resolve_version(release)?;
+    let (url, checksum_url) = get_image_urls(release.distribution, &version, detect_architecture())?;
+    let published_name = url.rsplit('/').next().unwrap_or(&url);
+    let checksum = fetch_checksum(&checksum_url, published_name)?;
 
-        (current_dir, temp_dir)
+    if !url.ends_with(".xz") {
+        download_file_with_progress(&url, &path, Some(&checksum), policy, progress)?;
+        return Ok(path);
     }
+    // The checksum covers the compressed image, which is kept hidden in the store
+    // until it is unpacked
+    let packed = store.get_image_path(&format!(".{}.xz", release.get_image_file_name()));
+    download_file_with_progress(&url, &packed, Some(&checksum), policy, progress)?;
+    let unpacked = unpack_xz_image(&packed, &path);
+    let _ = remove_file(&packed);
+    unpacked?;
+    Ok(path)
+}
 
-    fn cleanup_and_restore(original_dir: PathBuf, temp_dir: PathBuf) {
-        env::set_current_dir(original_dir).unwrap();
-        fs::remove_dir_all(temp_dir).unwrap();
+/// Unpacks the xz-compressed image at `packed` to `path`, which only appears once
+/// complete
+fn unpack_xz_image(packed: &Path, path: &Path) -> Result<(), VmError> {
+    let input = match File::open(packed) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", packed.display(), e), e))
+    };
+    let partial = get_partial_path(path);
+    let mut output = match File::create(&partial) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to create {}: {}", partial.display(), e), e))
+    };
+    if let Err(e) = std::io::copy(&mut XzDecoder::new(BufReader::new(input)), &mut output) {
+        let _ = remove_file(&partial);
+        return Err(VmError::image_source(format!("failed to unpack {}: {}", packed.display(), e), e));
+    }
+    if let Err(e) = rename(&partial, path) {
+        return Err(VmError::io(format!("failed to move {} to {}: {}", partial.display(), path.display(), e), e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    // Helper: an image store in a fresh temporary directory holding `files`
+    fn store_with_files(files: &[&str]) -> (TempDir, ImageStore) {
+        let dir = TempDir::new().unwrap();
+        let store = ImageStore::new(dir.path()).unwrap();
+        for name in files {
+            let mut file = File::create(store.get_image_path(name)).unwrap();
+            writeln!(file, "dummy image").unwrap();
+        }
+        (dir, store)
     }
 
     #[test]
@@ -166,77 +683,177 @@ mod tests {
         assert_eq!(distribution_img_extension(Distribution::Debian), ".qcow2");
         assert_eq!(distribution_img_extension(Distribution::Ubuntu), ".img");
         assert_eq!(distribution_img_extension(Distribution::Mint), ".iso");
+        assert_eq!(distribution_img_extension(Distribution::Fedora), ".qcow2");
+        assert_eq!(distribution_img_extension(Distribution::Alpine), ".iso");
     }
 
     #[test]
-    fn test_get_url_to_linux_distribution_download_known_arch() {
-        let result = get_url_to_linux_distribution_download(Distribution::Ubuntu);
-        assert!(result.is_ok());
-        let url = result.unwrap();
-        assert!(url.contains("ubuntu"));
-        assert!(url.ends_with(".img") || url.ends_with(".iso") || url.ends_with(".qcow2"));
+    fn test_get_image_file_name() {
+        assert_eq!(Distribution::Debian.get_image_file_name(), "debian-lts.qcow2");
+        assert_eq!(Distribution::Ubuntu.get_image_file_name(), "ubuntu-lts.img");
+        assert_eq!(Distribution::Mint.get_image_file_name(), "mint-lts.iso");
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_in_current_dir_found() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_present");
-
-        // Create dummy image file with correct extension for Ubuntu
-        let extension = distribution_img_extension(Distribution::Ubuntu);
-        let file_path = temp_dir.join(format!("ubuntu-lts{}", extension));
-        let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "dummy image").unwrap();
-
-        std::env::set_current_dir(&temp_dir).unwrap();
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Ubuntu);
-        assert!(result.is_ok());
+    fn test_get_image_urls() {
+        let (url, checksum_url) = get_image_urls(Distribution::Ubuntu, "24.04", Architecture::X86_64).unwrap();
+        assert_eq!(url, "https://cloud-images.ubuntu.com/releases/24.04/release/ubuntu-24.04-server-cloudimg-amd64.img");
+        assert_eq!(checksum_url, "https://cloud-images.ubuntu.com/releases/24.04/release/SHA256SUMS");
+        let (url, _) = get_image_urls(Distribution::Debian, "12", Architecture::ARM64).unwrap();
+        assert_eq!(url, "https://cloud.debian.org/images/cloud/bookworm/latest/debian-12-generic-arm64.qcow2");
+        let (_, checksum_url) = get_image_urls(Distribution::Fedora, "40", Architecture::ARM64).unwrap();
+        assert_eq!(
+            checksum_url,
+            "https://download.fedoraproject.org/pub/fedora/linux/releases/40/Cloud/aarch64/images/Fedora-Cloud-40-1.14-aarch64-CHECKSUM"
+        );
+        let (_, checksum_url) = get_image_urls(Distribution::OpenSuse, "16.0", Architecture::X86_64).unwrap();
+        assert!(checksum_url.ends_with("/leap/16.0/appliances/openSUSE-Leap-16.0-Minimal-VM.x86_64-Cloud.qcow2.sha256"));
+        let (url, checksum_url) = get_image_urls(Distribution::FreeBsd, "14.1", Architecture::ARM64).unwrap();
+        assert!(url.ends_with("/14.1-RELEASE/aarch64/Latest/FreeBSD-14.1-RELEASE-arm64-aarch64-BASIC-CLOUDINIT-ufs.qcow2.xz"));
+        assert!(checksum_url.ends_with("/14.1-RELEASE/aarch64/Latest/CHECKSUM.SHA256"));
+        assert_eq!(
+            get_image_urls(Distribution::Debian, "7", Architecture::X86_64).unwrap_err().to_string(),
+            "Debian 7 is not a known release"
+        );
+    }
 
-        cleanup_and_restore(original_dir, temp_dir);
+    #[test]
+    fn test_release() {
+        let release = Distribution::Ubuntu.release("24.04");
+        assert_eq!(release.get_distribution(), Distribution::Ubuntu);
+        assert_eq!(release.get_version(), Some("24.04"));
+        assert_eq!(release.get_image_file_name(), "ubuntu-24.04.img");
+        // Debian codenames stand for their version
+        assert_eq!(Distribution::Debian.release("bookworm"), Distribution::Debian.release("12"));
+        assert_eq!(Release::from(Distribution::Mint), Distribution::Mint.latest_lts());
+        assert_eq!(Distribution::Mint.latest_lts().get_version(), None);
+
+        assert_eq!(resolve_version(&Distribution::Ubuntu.release("24.10")).unwrap(), "24.10");
+        assert_eq!(resolve_version(&Distribution::Fedora.latest_lts()).unwrap(), "40");
+        assert_eq!(resolve_version(&Distribution::Fedora.release("40")).unwrap(), "40");
+        assert_eq!(
+            resolve_version(&Distribution::Fedora.release("41")).unwrap_err().to_string(),
+            "Fedora Cloud images can only be downloaded for release 40, not 41"
+        );
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_in_current_dir_found_debian() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_present_debian");
+    fn test_parse_release_index() {
+        let page = r#"<a href="../">../</a> <a href="20.04/">20.04/</a> <a href="24.04/">24.04/</a>
+            <a href="22.04/">22.04/</a> <a href="24.10/">24.10/</a> <a href="jammy/">jammy/</a>
+            <a href="streams/">streams/</a> <a href="22.04/">22.04/</a>"#;
+        let info = Distribution::Ubuntu.get_info();
+        let releases = parse_release_index(info, page);
+        assert_eq!(releases, vec!["20.04", "22.04", "24.04", "24.10"]);
+        assert_eq!(releases.iter().rev().find(|version| (info.is_lts)(version)).unwrap(), "24.04");
+
+        let page = r#"<a href="bookworm/">bookworm/</a> <a href="bullseye/">bullseye/</a> <a href="sid/">sid/</a>"#;
+        assert_eq!(parse_release_index(Distribution::Debian.get_info(), page), vec!["11", "12"]);
+
+        let page = r#"<a href="13.3-RELEASE/">13.3-RELEASE/</a> <a href="14.1-RELEASE/">14.1-RELEASE/</a>
+            <a href="15.0-CURRENT/">15.0-CURRENT/</a> <a href="14.2-BETA1/">14.2-BETA1/</a>"#;
+        assert_eq!(parse_release_index(Distribution::FreeBsd.get_info(), page), vec!["13.3", "14.1"]);
+    }
 
-        // Create dummy image file with correct extension for Debian
-        let extension = distribution_img_extension(Distribution::Debian);
-        let file_path = temp_dir.join(format!("debian-server{}", extension));
-        let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "dummy debian image").unwrap();
+    #[test]
+    fn test_lts_rules() {
+        assert!(is_ubuntu_lts("24.04"));
+        assert!(!is_ubuntu_lts("23.04"));
+        assert!(!is_ubuntu_lts("24.10"));
+        assert!(is_rocky_major("9") && !is_rocky_major("9.4"));
+        assert!(is_opensuse_leap("15.6") && !is_opensuse_leap("42.3"));
+    }
 
-        std::env::set_current_dir(&temp_dir).unwrap();
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Debian);
-        assert!(result.is_ok());
+    #[test]
+    fn test_catalog_covers_every_distribution() {
+        let all = Distribution::get_all();
+        assert_eq!(all.len(), 10);
+        for distribution in all {
+            let info = distribution.get_info();
+            assert_eq!(Distribution::from_name(info.name), Some(distribution));
+            assert!(info.extension.starts_with('.'));
+            assert!(!info.sources.is_empty(), "{:?} has no image", distribution);
+            for source in info.sources {
+                let url = source.url.trim_end_matches(".xz");
+                assert!(url.starts_with("https://") && url.ends_with(info.extension), "{}", source.url);
+                assert!(get_image_urls(distribution, info.default_version, source.architecture).is_ok());
+            }
+        }
+        assert_eq!(Distribution::from_name("gentoo"), None);
+    }
 
-        cleanup_and_restore(original_dir, temp_dir);
+    #[test]
+    fn test_guest_os() {
+        assert_eq!(Distribution::Ubuntu.get_guest_os(), GuestOs::Linux);
+        assert_eq!(Distribution::FreeBsd.get_guest_os(), GuestOs::FreeBsd);
+        assert_eq!(Distribution::OpenBsd.get_guest_os(), GuestOs::OpenBsd);
+        assert_eq!(Distribution::FreeBsd.release("14.1").get_image_file_name(), "freebsd-14.1.qcow2");
+        assert_eq!(Distribution::OpenBsd.get_image_file_name(), "openbsd-lts.img");
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_in_current_dir_found_mint() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_present_mint");
+    fn test_unpack_xz_image() {
+        use xz2::write::XzEncoder;
+        let (dir, store) = store_with_files(&[]);
+        let packed = store.get_image_path(".freebsd-lts.qcow2.xz");
+        let mut encoder = XzEncoder::new(File::create(&packed).unwrap(), 6);
+        encoder.write_all(&vec![0x5a; 1 << 16]).unwrap();
+        encoder.finish().unwrap();
+
+        let path = store.path_for(Distribution::FreeBsd);
+        unpack_xz_image(&packed, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0x5a; 1 << 16]);
+        assert!(!get_partial_path(&path).exists());
+        assert!(unpack_xz_image(&dir.path().join("missing.xz"), &path).is_err());
+    }
 
-        // Create dummy image file with correct extension for Mint
-        let extension = distribution_img_extension(Distribution::Mint);
-        let file_path = temp_dir.join(format!("mint-cinnamon{}", extension));
-        let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "dummy mint image").unwrap();
+    #[test]
+    fn test_get_image_source_per_architecture() {
+        let source = get_image_source(Distribution::Alpine, Architecture::ARM64).unwrap();
+        assert!(source.url.contains("aarch64"));
+        assert!(get_image_source(Distribution::Rocky, Architecture::X86_64).unwrap().url.contains("x86_64"));
+        let error = get_image_source(Distribution::Arch, Architecture::ARM64).unwrap_err();
+        assert_eq!(error.to_string(), "Arch Linux has no image for the aarch64 architecture");
+        assert!(get_image_source(Distribution::Debian, Architecture::Unknown).is_err());
+    }
 
-        std::env::set_current_dir(&temp_dir).unwrap();
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Mint);
+    #[test]
+    fn test_get_url_to_linux_distribution_download_known_arch() {
+        let result = get_url_to_linux_distribution_download(Distribution::Ubuntu);
         assert!(result.is_ok());
+        let url = result.unwrap();
+        assert!(url.contains("ubuntu"));
+        assert!(url.ends_with(".img") || url.ends_with(".iso") || url.ends_with(".qcow2"));
+    }
 
-        cleanup_and_restore(original_dir, temp_dir);
+    #[test]
+    fn test_lookup_finds_downloaded_image() {
+        let (_dir, store) = store_with_files(&["ubuntu-lts.img"]);
+        assert_eq!(store.lookup(Distribution::Ubuntu).unwrap(), Some(store.path_for(Distribution::Ubuntu)));
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_in_current_dir_not_found() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_not_present");
+    fn test_lookup_finds_image_named_after_distribution() {
+        let (_dir, store) = store_with_files(&["debian-server.qcow2", "mint-cinnamon.iso"]);
+        assert_eq!(store.lookup(Distribution::Debian).unwrap(), Some(store.get_image_path("debian-server.qcow2")));
+        assert_eq!(store.lookup(Distribution::Mint).unwrap(), Some(store.get_image_path("mint-cinnamon.iso")));
+    }
 
-        std::env::set_current_dir(&temp_dir).unwrap();
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Mint);
-        assert!(result.is_err());
+    #[test]
+    fn test_lookup_release() {
+        let (_dir, store) = store_with_files(&["ubuntu-22.04.img", "debian-12.qcow2"]);
+        assert_eq!(store.lookup(Distribution::Ubuntu.release("22.04")).unwrap(), Some(store.get_image_path("ubuntu-22.04.img")));
+        // Other releases don't stand in for the one asked for
+        assert_eq!(store.lookup(Distribution::Ubuntu.release("24.04")).unwrap(), None);
+        assert_eq!(store.lookup(Distribution::Debian.release("bookworm")).unwrap(), Some(store.get_image_path("debian-12.qcow2")));
+        // Any image of the distribution does for the latest LTS release
+        assert!(store.lookup(Distribution::Ubuntu).unwrap().is_some());
+    }
 
-        cleanup_and_restore(original_dir, temp_dir);
+    #[test]
+    fn test_lookup_empty_store() {
+        let (_dir, store) = store_with_files(&[]);
+        assert_eq!(store.lookup(Distribution::Mint).unwrap(), None);
     }
 
     #[test]
@@ -244,93 +861,34 @@ mod tests {
         assert_eq!(Distribution::Ubuntu.as_str(), "ubuntu");
         assert_eq!(Distribution::Debian.as_str(), "debian");
         assert_eq!(Distribution::Mint.as_str(), "mint");
+        assert_eq!(Distribution::OpenSuse.as_str(), "opensuse");
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_with_wrong_extension() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_wrong_extension");
-
-        // Create a file that contains the distro name but wrong extension
-        // Ubuntu should have .img, but we create .iso
-        let file_path = temp_dir.join("ubuntu-lts.iso");
-        let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "dummy iso").unwrap();
-
-        env::set_current_dir(&temp_dir).unwrap();
-
-        // Should not find .img files for Ubuntu, so returns Err
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Ubuntu);
-        assert!(result.is_err());
-
-        cleanup_and_restore(original_dir, temp_dir);
+    fn test_lookup_ignores_wrong_extension() {
+        // Ubuntu should have .img and Debian .qcow2
+        let (_dir, store) = store_with_files(&["ubuntu-lts.iso", "debian-server.img"]);
+        assert_eq!(store.lookup(Distribution::Ubuntu).unwrap(), None);
+        assert_eq!(store.lookup(Distribution::Debian).unwrap(), None);
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_with_wrong_extension_debian() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_wrong_extension_debian");
-
-        // Create a file that contains the distro name but wrong extension
-        // Debian should have .qcow2, but we create .img
-        let file_path = temp_dir.join("debian-server.img");
-        let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "dummy img").unwrap();
-
-        env::set_current_dir(&temp_dir).unwrap();
-
-        // Should not find .qcow2 files for Debian, so returns Err
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Debian);
-        assert!(result.is_err());
-
-        cleanup_and_restore(original_dir, temp_dir);
+    fn test_lookup_is_case_sensitive() {
+        let (_dir, store) = store_with_files(&["Ubuntu-lts.img"]);
+        assert_eq!(store.lookup(Distribution::Ubuntu).unwrap(), None);
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_case_sensitive() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_case_sensitive");
-
-        // Create .img file with uppercase distribution name (should NOT match if case-sensitive)
-        let extension = distribution_img_extension(Distribution::Ubuntu);
-        let file_path = temp_dir.join(format!("Ubuntu-lts{}", extension));
-        let mut file = File::create(&file_path).unwrap();
-        writeln!(file, "dummy img").unwrap();
-
-        env::set_current_dir(&temp_dir).unwrap();
-
-        // Should NOT find, assuming case-sensitive matching
-        let result = check_if_linux_distribution_img_present_in_current_dir(Distribution::Ubuntu);
-        assert!(result.is_err());
-
-        cleanup_and_restore(original_dir, temp_dir);
+    fn test_lookup_ignores_partial_downloads() {
+        let (_dir, store) = store_with_files(&["debian-lts.qcow2.part"]);
+        assert_eq!(store.lookup(Distribution::Debian).unwrap(), None);
     }
 
     #[test]
-    fn test_check_if_linux_distribution_img_present_multiple_files() {
-        let (original_dir, temp_dir) = setup_temp_test_dir("test_img_multiple_files");
-
-        // Create multiple files, one with correct distribution and extension
-        let mut file1 = File::create(temp_dir.join("some-random-file.txt")).unwrap();
-        writeln!(file1, "random content").unwrap();
-
-        let mut file2 = File::create(temp_dir.join("ubuntu-desktop.img")).unwrap();
-        writeln!(file2, "ubuntu image").unwrap();
-
-        let mut file3 = File::create(temp_dir.join("debian-server.qcow2")).unwrap();
-        writeln!(file3, "debian image").unwrap();
-
-        env::set_current_dir(&temp_dir).unwrap();
-
-        // Should find Ubuntu .img file
-        let result_ubuntu = check_if_linux_distribution_img_present_in_current_dir(Distribution::Ubuntu);
-        assert!(result_ubuntu.is_ok());
-
-        // Should find Debian .qcow2 file
-        let result_debian = check_if_linux_distribution_img_present_in_current_dir(Distribution::Debian);
-        assert!(result_debian.is_ok());
-
-        // Should NOT find Mint .iso file
-        let result_mint = check_if_linux_distribution_img_present_in_current_dir(Distribution::Mint);
-        assert!(result_mint.is_err());
-
-        cleanup_and_restore(original_dir, temp_dir);
+    fn test_lookup_multiple_files() {
+        let (_dir, store) = store_with_files(&["some-random-file.txt", "ubuntu-desktop.img", "debian-server.qcow2"]);
+        assert!(store.lookup(Distribution::Ubuntu).unwrap().is_some());
+        assert!(store.lookup(Distribution::Debian).unwrap().is_some());
+        assert!(store.lookup(Distribution::Mint).unwrap().is_none());
     }
-}
\ No newline at end of file
+}
@@ -1,2 +1,7 @@
+pub mod chunk_store;
+pub mod delta_download;
+pub mod download;
 pub mod img_setup;
+pub mod image_store;
+pub mod oci_registry;
 pub mod signals;
\ No newline at end of file
@@ -0,0 +1,596 @@
+//! Distribution of disk images through OCI registries.
+//!
+//! Images are pushed as OCI artifacts: a manifest of type `ARTIFACT_TYPE` whose only
+//! layer is the gzip-compressed raw image, with a small config blob recording the
+//! image size. Any registry implementing the OCI distribution API (`registry:2`,
+//! Harbor, GHCR, ...) stores them next to container images, with its usual access
+//! control. Registries asking for a bearer token get one from their token service,
+//! using the client's credentials if it has any. Pulls verify the layer digest before
+//! the image replaces anything.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+use flate2::Compression;
+use flate2::read::{GzDecoder, GzEncoder};
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use reqwest::{StatusCode, Url};
+use serde_json::{json, Value};
+use crate::error::VmError;
+use crate::utils::chunk_store::{copy_sparse, get_digest, DigestReader};
+
+/// Artifact type of the manifests of pushed images.
+pub const ARTIFACT_TYPE: &str = "application/vnd.asgard.disk-image.v1";
+/// Media type of the layer holding the gzip-compressed raw image.
+pub const LAYER_MEDIA_TYPE: &str = "application/vnd.asgard.disk-image.layer.v1.raw+gzip";
+/// Media type of the config blob.
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.asgard.disk-image.config.v1+json";
+/// Media type of OCI image manifests.
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// Annotation carrying the file name of the image.
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+/// Tag used when a reference names none.
+const DEFAULT_TAG: &str = "latest";
+
+/// Image in a registry, e.g. `registry.example.com/team/golden/debian:12` or
+/// `localhost:5000/debian@sha256:...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciReference {
+    /// Registry host, with its port if it isn't the default one
+    pub registry: String,
+    /// Repository within the registry
+    pub repository: String,
+    /// Tag, or manifest digest (`sha256:<hex>`)
+    pub reference: String,
+}
+
+impl OciReference {
+    /// Parses a reference; the registry host must be given, there is no default registry.
+    ///
+    /// # Returns
+    /// * `Ok(OciReference)` on success
+    /// * `Err(VmError)` if the reference lacks a registry or repository, or has invalid characters
+    pub fn parse(text: &str) -> Result<OciReference, VmError> {
+        let invalid = || VmError::config(format!("invalid image reference {:?}", text));
+        let (name, reference) = match text.split_once('@') {
+            Some((name, digest)) if parse_digest(digest).is_some() => (name, digest.to_string()),
+            Some(_) => return Err(invalid()),
+            None => match text.rsplit_once(':') {
+                // A colon before the last slash separates the registry port
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+                _ => (text, DEFAULT_TAG.to_string()),
+            },
+        };
+        let Some((registry, repository)) = name.split_once('/') else {
+            return Err(invalid());
+        };
+
+        let is_host = registry.contains(['.', ':']) || registry == "localhost";
+        let is_repository = repository.split('/').all(|component| {
+            !component.is_empty() && component.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || b"._-".contains(&byte))
+        });
+        let is_tag = !reference.is_empty() && reference.len() <= 128
+            && reference.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"._-:".contains(&byte));
+        if !is_host || !is_repository || !is_tag {
+            return Err(invalid());
+        }
+        Ok(OciReference { registry: registry.to_string(), repository: repository.to_string(), reference })
+    }
+}
+
+impl std::fmt::Display for OciReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if parse_digest(&self.reference).is_some() { '@' } else { ':' };
+        write!(f, "{}/{}{}{}", self.registry, self.repository, separator, self.reference)
+    }
+}
+
+/// Outcome of `RegistryClient::push_image`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PushReport {
+    /// Digest of the pushed manifest, to pull exactly this image later
+    pub digest: String,
+    /// Size of the image
+    pub image_size: u64,
+    /// Size of the compressed layer
+    pub layer_size: u64,
+    /// Bytes uploaded; blobs the registry already had are skipped
+    pub uploaded_bytes: u64,
+}
+
+/// Outcome of `RegistryClient::pull_image`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PullReport {
+    /// Digest of the pulled manifest
+    pub digest: String,
+    /// Size of the image
+    pub image_size: u64,
+    /// Bytes downloaded for the layer
+    pub downloaded_bytes: u64,
+}
+
+/// Client pushing and pulling images to and from OCI registries.
+pub struct RegistryClient {
+    client: Client,
+    /// User name and password or access token, for basic auth and token services
+    credentials: Option<(String, String)>,
+    /// Talk plain HTTP, for local registries without TLS
+    plain_http: bool,
+    /// Bearer token from the last authentication, reused until it is refused
+    token: Mutex<Option<String>>,
+}
+
+impl RegistryClient {
+    /// Creates an anonymous client talking HTTPS.
+    pub fn new() -> RegistryClient {
+        RegistryClient { client: Client::new(), credentials: None, plain_http: false, token: Mutex::new(None) }
+    }
+    /// Authenticate with the given user name and password or access token.
+    pub fn with_credentials(mut self, username: &str, password: &str) -> RegistryClient {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+    /// Talk plain HTTP instead of HTTPS, e.g. to a `registry:2` on localhost.
+    pub fn with_plain_http(mut self, plain_http: bool) -> RegistryClient {
+        self.plain_http = plain_http;
+        self
+    }
+
+    /// Pushes the raw image at `image` to `reference`.
+    ///
+    /// # Returns
+    /// * `Ok(PushReport)` once the manifest is stored
+    /// * `Err(VmError)` if the image couldn't be read or the registry refused a request
+    pub fn push_image(&self, image: &Path, reference: &OciReference) -> Result<PushReport, VmError> {
+        let (layer, image_size) = compress_image(image)?;
+        let mut reader = match layer.try_clone() {
+            Ok(layer) => DigestReader::new(layer),
+            Err(e) => return Err(VmError::io(format!("failed to read compressed {}: {}", image.display(), e), e))
+        };
+        if let Err(e) = std::io::copy(&mut reader, &mut std::io::sink()) {
+            return Err(VmError::io(format!("failed to read compressed {}: {}", image.display(), e), e));
+        }
+        let (layer_size, layer_digest) = reader.finish();
+        let layer_digest = format!("sha256:{}", layer_digest);
+
+        let config = json!({ "imageSize": image_size, "format": "raw" }).to_string().into_bytes();
+        let config_digest = format!("sha256:{}", get_digest(&config));
+        let mut uploaded_bytes = 0;
+        if self.push_blob(reference, &config_digest, config.len() as u64, &|| Ok(Body::from(config.clone())))? {
+            uploaded_bytes += config.len() as u64;
+        }
+        let open_layer = || {
+            let mut layer = match layer.try_clone() {
+                Ok(layer) => layer,
+                Err(e) => return Err(VmError::io(format!("failed to read compressed {}: {}", image.display(), e), e))
+            };
+            match layer.seek(SeekFrom::Start(0)) {
+                Ok(_) => Ok(Body::sized(layer, layer_size)),
+                Err(e) => Err(VmError::io(format!("failed to read compressed {}: {}", image.display(), e), e))
+            }
+        };
+        if self.push_blob(reference, &layer_digest, layer_size, &open_layer)? {
+            uploaded_bytes += layer_size;
+        }
+
+        let title = image.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "artifactType": ARTIFACT_TYPE,
+            "config": { "mediaType": CONFIG_MEDIA_TYPE, "digest": config_digest, "size": config.len() },
+            "layers": [{
+                "mediaType": LAYER_MEDIA_TYPE,
+                "digest": layer_digest,
+                "size": layer_size,
+                "annotations": { TITLE_ANNOTATION: title }
+            }]
+        }).to_string().into_bytes();
+        let url = self.get_url(reference, &format!("manifests/{}", reference.reference))?;
+        let response = self.send(&|client| {
+            Ok(client.put(url.clone()).header(CONTENT_TYPE, MANIFEST_MEDIA_TYPE).body(manifest.clone()))
+        })?;
+        check_status(response, StatusCode::CREATED, &format!("storing the manifest of {}", reference))?;
+
+        Ok(PushReport { digest: format!("sha256:{}", get_digest(&manifest)), image_size, layer_size, uploaded_bytes })
+    }
+
+    /// Pulls the image at `reference` into `destination`, replacing it once the image
+    /// is complete and verified. Runs of zeroes are left sparse.
+    ///
+    /// # Returns
+    /// * `Ok(PullReport)` once `destination` holds the image
+    /// * `Err(VmError)` if the reference isn't a disk image, the download failed or
+    ///   doesn't match its digest, or `destination` couldn't be written
+    pub fn pull_image(&self, reference: &OciReference, destination: &Path) -> Result<PullReport, VmError> {
+        let url = self.get_url(reference, &format!("manifests/{}", reference.reference))?;
+        let response = self.send(&|client| Ok(client.get(url.clone()).header(ACCEPT, MANIFEST_MEDIA_TYPE)))?;
+        let response = check_status(response, StatusCode::OK, &format!("fetching the manifest of {}", reference))?;
+        let manifest = match response.bytes() {
+            Ok(manifest) => manifest,
+            Err(e) => return Err(VmError::image_source(format!("failed to fetch the manifest of {}: {}", reference, e), e))
+        };
+        let digest = format!("sha256:{}", get_digest(&manifest));
+        if parse_digest(&reference.reference).is_some() && digest != reference.reference {
+            return Err(VmError::image(format!("the manifest of {} doesn't match its digest", reference)));
+        }
+        let (layer_digest, layer_size) = match parse_manifest(&manifest) {
+            Some(layer) => layer,
+            None => return Err(VmError::image(format!("{} is not a disk image", reference)))
+        };
+
+        let url = self.get_url(reference, &format!("blobs/{}", layer_digest))?;
+        let response = self.send(&|client| Ok(client.get(url.clone())))?;
+        let response = check_status(response, StatusCode::OK, &format!("downloading {}", reference))?;
+        let parent = destination.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let mut output = match tempfile::Builder::new().prefix(".pull").tempfile_in(parent) {
+            Ok(output) => output,
+            Err(e) => return Err(VmError::io(format!("failed to create a file in {}: {}", parent.display(), e), e))
+        };
+        let mut decoder = GzDecoder::new(DigestReader::new(response.take(layer_size)));
+        let image_size = match copy_sparse(&mut decoder, output.as_file_mut()) {
+            Ok(size) => size,
+            Err(e) => return Err(VmError::io(format!("failed to download {}: {}", reference, e), e))
+        };
+        // Drain what the decoder didn't need, so the digest covers the whole layer
+        let mut reader = decoder.into_inner();
+        if let Err(e) = std::io::copy(&mut reader, &mut std::io::sink()) {
+            return Err(VmError::io(format!("failed to download {}: {}", reference, e), e));
+        }
+        let (downloaded_bytes, downloaded_digest) = reader.finish();
+        if downloaded_bytes != layer_size || format!("sha256:{}", downloaded_digest) != layer_digest {
+            return Err(VmError::image(format!("the layer of {} doesn't match its digest", reference)));
+        }
+        if let Err(e) = output.persist(destination) {
+            return Err(VmError::io(format!("failed to replace {}: {}", destination.display(), e.error), e.error));
+        }
+
+        Ok(PullReport { digest, image_size, downloaded_bytes })
+    }
+
+    /// Uploads a blob unless the registry already has it.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the blob was uploaded, `Ok(false)` if it was already there
+    /// * `Err(VmError)` if the body couldn't be created or the registry refused the upload
+    fn push_blob(&self, reference: &OciReference, digest: &str, size: u64, body: &dyn Fn() -> Result<Body, VmError>) -> Result<bool, VmError> {
+        let url = self.get_url(reference, &format!("blobs/{}", digest))?;
+        let response = self.send(&|client| Ok(client.head(url.clone())))?;
+        if response.status() == StatusCode::OK {
+            return Ok(false);
+        }
+
+        let url = self.get_url(reference, "blobs/uploads/")?;
+        let response = self.send(&|client| Ok(client.post(url.clone())))?;
+        let response = check_status(response, StatusCode::ACCEPTED, &format!("starting an upload to {}", reference))?;
+        // The upload URL may be relative and may already carry a query
+        let location = response.headers().get(LOCATION).and_then(|location| location.to_str().ok());
+        let mut upload = match location.and_then(|location| url.join(location).ok()) {
+            Some(upload) => upload,
+            None => return Err(VmError::image(format!("the registry of {} sent no upload location", reference)))
+        };
+        upload.query_pairs_mut().append_pair("digest", digest);
+        let response = self.send(&|client| {
+            Ok(client.put(upload.clone()).header(CONTENT_TYPE, "application/octet-stream").body(body()?))
+        })?;
+        check_status(response, StatusCode::CREATED, &format!("uploading {} bytes to {}", size, reference))?;
+        Ok(true)
+    }
+
+    /// Returns the URL of `path` below the repository of `reference` in the v2 API.
+    fn get_url(&self, reference: &OciReference, path: &str) -> Result<Url, VmError> {
+        let scheme = if self.plain_http { "http" } else { "https" };
+        let url = format!("{}://{}/v2/{}/{}", scheme, reference.registry, reference.repository, path);
+        match Url::parse(&url) {
+            Ok(url) => Ok(url),
+            Err(e) => Err(VmError::config(format!("invalid registry URL {}: {}", url, e)))
+        }
+    }
+
+    /// Sends the request made by `build`, authenticating and sending it again if the
+    /// registry asks for credentials.
+    fn send(&self, build: &dyn Fn(&Client) -> Result<RequestBuilder, VmError>) -> Result<Response, VmError> {
+        let token = self.token.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut request = build(&self.client)?;
+        if let Some(token) = &token {
+            request = request.bearer_auth(token);
+        }
+        let response = send_request(request)?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response.headers().get(WWW_AUTHENTICATE).and_then(|challenge| challenge.to_str().ok()).unwrap_or("");
+        let request = build(&self.client)?;
+        let request = if challenge.len() >= 7 && challenge[..7].eq_ignore_ascii_case("bearer ") {
+            let token = self.fetch_token(&challenge[7..])?;
+            *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(token.clone());
+            request.bearer_auth(token)
+        } else {
+            match &self.credentials {
+                Some((username, password)) => request.basic_auth(username, Some(password)),
+                None => return Ok(response),
+            }
+        };
+        send_request(request)
+    }
+
+    /// Gets a bearer token from the token service named in a `WWW-Authenticate` challenge.
+    fn fetch_token(&self, challenge: &str) -> Result<String, VmError> {
+        let parameters = parse_challenge(challenge);
+        let get = |key: &str| parameters.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value.as_str());
+        let mut url = match get("realm").and_then(|realm| Url::parse(realm).ok()) {
+            Some(url) => url,
+            None => return Err(VmError::image(format!("registry sent an unusable authentication challenge: {}", challenge)))
+        };
+        for key in ["service", "scope"] {
+            if let Some(value) = get(key) {
+                url.query_pairs_mut().append_pair(key, value);
+            }
+        }
+
+        let mut request = self.client.get(url.clone());
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = check_status(send_request(request)?, StatusCode::OK, &format!("authenticating with {}", url))?;
+        let body = match response.bytes() {
+            Ok(body) => body,
+            Err(e) => return Err(VmError::image_source(format!("failed to authenticate with {}: {}", url, e), e))
+        };
+        let token = serde_json::from_slice::<Value>(&body).ok().and_then(|body| {
+            body.get("token").or_else(|| body.get("access_token")).and_then(Value::as_str).map(str::to_string)
+        });
+        match token {
+            Some(token) => Ok(token),
+            None => Err(VmError::image(format!("{} returned no token", url)))
+        }
+    }
+}
+
+impl Default for RegistryClient {
+    fn default() -> Self {
+        RegistryClient::new()
+    }
+}
+
+/// Compresses the image at `path` into an anonymous temporary file, positioned at its start.
+///
+/// # Returns
+/// * `Ok((file, image_size))` with the compressed image and the size of the original
+/// * `Err(VmError)` if reading or writing failed
+fn compress_image(path: &Path) -> Result<(File, u64), VmError> {
+    let image = match File::open(path) {
+        Ok(image) => image,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path.display(), e), e))
+    };
+    let mut layer = match tempfile::tempfile() {
+        Ok(layer) => layer,
+        Err(e) => return Err(VmError::io(format!("failed to create a temporary file: {}", e), e))
+    };
+    let mut encoder = GzEncoder::new(DigestReader::new(image), Compression::default());
+    if let Err(e) = std::io::copy(&mut encoder, &mut layer) {
+        return Err(VmError::io(format!("failed to compress {}: {}", path.display(), e), e));
+    }
+    if let Err(e) = layer.seek(SeekFrom::Start(0)) {
+        return Err(VmError::io(format!("failed to compress {}: {}", path.display(), e), e));
+    }
+    let (image_size, _) = encoder.into_inner().finish();
+    Ok((layer, image_size))
+}
+
+/// Sends a request, turning transport failures into errors.
+fn send_request(request: RequestBuilder) -> Result<Response, VmError> {
+    match request.send() {
+        Ok(response) => Ok(response),
+        Err(e) => Err(VmError::image_source(format!("registry request failed: {}", e), e))
+    }
+}
+
+/// Requires `response` to have the `expected` status.
+fn check_status(response: Response, expected: StatusCode, action: &str) -> Result<Response, VmError> {
+    if response.status() == expected {
+        return Ok(response);
+    }
+    let status = response.status();
+    let detail = response.text().unwrap_or_default();
+    Err(VmError::image(format!("{} failed with {}: {}", action, status, detail.trim())))
+}
+
+/// Returns the layer digest and size of an image manifest as written by `push_image`.
+fn parse_manifest(manifest: &[u8]) -> Option<(String, u64)> {
+    let manifest: Value = serde_json::from_slice(manifest).ok()?;
+    let is_image = manifest.get("artifactType").and_then(Value::as_str) == Some(ARTIFACT_TYPE)
+        || manifest.pointer("/config/mediaType").and_then(Value::as_str) == Some(CONFIG_MEDIA_TYPE);
+    if !is_image {
+        return None;
+    }
+    let layer = manifest.get("layers")?.as_array()?.iter()
+        .find(|layer| layer.get("mediaType").and_then(Value::as_str) == Some(LAYER_MEDIA_TYPE))?;
+    let digest = layer.get("digest")?.as_str()?;
+    parse_digest(digest)?;
+    Some((digest.to_string(), layer.get("size")?.as_u64()?))
+}
+
+/// Returns the hex part of a `sha256:<hex>` digest, if `text` is one.
+fn parse_digest(text: &str) -> Option<&str> {
+    let hex = text.strip_prefix("sha256:")?;
+    let is_hex = hex.len() == 64 && hex.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+    is_hex.then_some(hex)
+}
+
+/// Splits the parameters of a challenge, e.g. `realm="https://auth",service="registry"`.
+fn parse_challenge(challenge: &str) -> Vec<(String, String)> {
+    let mut parameters = Vec::new();
+    let mut rest = challenge.trim();
+    while let Some((name, after)) = rest.split_once('=') {
+        let (value, remainder) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, remainder)) => (value, remainder),
+                None => (quoted, ""),
+            },
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        parameters.push((name.trim().to_string(), value.to_string()));
+        rest = remainder.trim_start_matches([',', ' ']);
+    }
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    type BlobStore = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    // Helper: in-memory registry requiring a bearer token from its own token service,
+    // returning its address and the stored blobs and manifests by path
+    fn serve_registry() -> (String, BlobStore) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let store: BlobStore = Arc::default();
+        let registry_store = Arc::clone(&store);
+        let realm = format!("http://{}/token", address);
+        std::thread::spawn(move || {
+            let mut uploads = 0;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut fields = request.split(' ');
+                let (method, target) = (fields.next().unwrap().to_string(), fields.next().unwrap().to_string());
+                let (mut length, mut authorization) = (0, String::new());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(':').unwrap();
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => length = value.trim().parse().unwrap(),
+                        "authorization" => authorization = value.trim().to_string(),
+                        _ => {},
+                    }
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+                let mut store = registry_store.lock().unwrap();
+                let mut headers = String::new();
+                let (status, response): (&str, Vec<u8>) = if path == "/token" {
+                    // "user:secret" in base64
+                    match authorization.as_str() {
+                        "Basic dXNlcjpzZWNyZXQ=" => ("200 OK", br#"{"token":"t0ken"}"#.to_vec()),
+                        _ => ("401 Unauthorized", Vec::new()),
+                    }
+                } else if authorization != "Bearer t0ken" {
+                    headers = format!("WWW-Authenticate: Bearer realm=\"{}\",service=\"test\",scope=\"repository:golden:push,pull\"\r\n", realm);
+                    ("401 Unauthorized", Vec::new())
+                } else if method == "POST" {
+                    uploads += 1;
+                    headers = format!("Location: /upload/{}?session=x\r\n", uploads);
+                    ("202 Accepted", Vec::new())
+                } else if method == "PUT" && path.starts_with("/upload/") {
+                    let digest = query.split('&').find_map(|pair| pair.strip_prefix("digest=")).unwrap().replace("%3A", ":");
+                    assert_eq!(digest, format!("sha256:{}", get_digest(&body)));
+                    store.insert(format!("/v2/golden/blobs/{}", digest), body);
+                    ("201 Created", Vec::new())
+                } else if method == "PUT" {
+                    // Manifests can be fetched by tag and by digest
+                    store.insert(format!("/v2/golden/manifests/sha256:{}", get_digest(&body)), body.clone());
+                    store.insert(path.to_string(), body);
+                    ("201 Created", Vec::new())
+                } else {
+                    match store.get(path) {
+                        Some(data) if method == "GET" => ("200 OK", data.clone()),
+                        Some(_) => ("200 OK", Vec::new()),
+                        None => ("404 Not Found", Vec::new()),
+                    }
+                };
+                let header = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", status, headers, response.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (address, store)
+    }
+
+    #[test]
+    fn test_parse_references() {
+        let reference = OciReference::parse("registry.example.com/team/debian:12.5").unwrap();
+        assert_eq!(reference.registry, "registry.example.com");
+        assert_eq!(reference.repository, "team/debian");
+        assert_eq!(reference.reference, "12.5");
+        assert_eq!(OciReference::parse("localhost:5000/debian").unwrap().reference, DEFAULT_TAG);
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let pinned = OciReference::parse(&format!("localhost:5000/debian@{}", digest)).unwrap();
+        assert_eq!(pinned.registry, "localhost:5000");
+        assert_eq!(pinned.reference, digest);
+        assert_eq!(pinned.to_string(), format!("localhost:5000/debian@{}", digest));
+
+        for invalid in ["debian:12", "docker/debian", "localhost:5000/Debian", "localhost/debian@sha256:ab", "localhost/a//b"] {
+            assert!(OciReference::parse(invalid).is_err(), "{} should be refused", invalid);
+        }
+    }
+
+    #[test]
+    fn test_push_and_pull_with_token_auth() {
+        let (address, store) = serve_registry();
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("golden.img");
+        let mut data = vec![0u8; 1 << 20];
+        data[4096..8192].fill(0x5a);
+        std::fs::write(&image, &data).unwrap();
+        let reference = OciReference::parse(&format!("{}/golden:v1", address)).unwrap();
+
+        let anonymous = RegistryClient::new().with_plain_http(true);
+        assert!(anonymous.push_image(&image, &reference).is_err());
+
+        let client = RegistryClient::new().with_plain_http(true).with_credentials("user", "secret");
+        let pushed = client.push_image(&image, &reference).expect("Push should succeed");
+        assert_eq!(pushed.image_size, data.len() as u64);
+        assert!(pushed.layer_size < 64 * 1024);
+        assert!(pushed.uploaded_bytes > pushed.layer_size);
+        // The blobs are already there the second time
+        let again = client.push_image(&image, &reference).expect("Push should succeed");
+        assert_eq!((again.uploaded_bytes, again.digest.as_str()), (0, pushed.digest.as_str()));
+
+        let pulled_path = dir.path().join("pulled.img");
+        let pinned = OciReference { reference: pushed.digest.clone(), ..reference.clone() };
+        let pulled = client.pull_image(&pinned, &pulled_path).expect("Pull should succeed");
+        assert_eq!(pulled, PullReport { digest: pushed.digest.clone(), image_size: data.len() as u64, downloaded_bytes: pushed.layer_size });
+        assert_eq!(std::fs::read(&pulled_path).unwrap(), data);
+
+        // A corrupted layer is refused and leaves the destination alone
+        {
+            let mut store = store.lock().unwrap();
+            let layer = store.iter_mut().find(|(key, value)| key.contains("/blobs/") && value.len() as u64 == pushed.layer_size).unwrap().1;
+            let middle = layer.len() / 2;
+            layer[middle] ^= 0xff;
+        }
+        std::fs::write(&pulled_path, b"old").unwrap();
+        assert!(client.pull_image(&reference, &pulled_path).is_err());
+        assert_eq!(std::fs::read(&pulled_path).unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_pull_refuses_other_artifacts() {
+        let (address, store) = serve_registry();
+        let manifest = json!({ "schemaVersion": 2, "mediaType": MANIFEST_MEDIA_TYPE, "config": { "mediaType": "application/vnd.oci.image.config.v1+json" }, "layers": [] });
+        store.lock().unwrap().insert("/v2/golden/manifests/app".to_string(), manifest.to_string().into_bytes());
+        let client = RegistryClient::new().with_plain_http(true).with_credentials("user", "secret");
+        let dir = tempfile::tempdir().unwrap();
+        let reference = OciReference::parse(&format!("{}/golden:app", address)).unwrap();
+        assert!(client.pull_image(&reference, &dir.path().join("app.img")).is_err());
+        assert!(!dir.path().join("app.img").exists());
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use crate::error::VmError;
+
+/// First GSI handed out for legacy (irqchip pin) interrupts; 0-4 are taken by the
+/// timer, keyboard, PIC cascade and serial ports.
+pub const LEGACY_GSI_BASE: u32 = 5;
+/// Last GSI routable to an IOAPIC pin.
+pub const LEGACY_GSI_MAX: u32 = 23;
+/// First GSI handed out for MSI routes, above every IOAPIC pin.
+pub const MSI_GSI_BASE: u32 = 24;
+/// Last GSI handed out for MSI routes.
+pub const MSI_GSI_MAX: u32 = 4095;
+
+/// What to do when a device asks for a GSI another device already uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GsiConflictPolicy {
+    /// Fail the device setup with an error naming both devices
+    #[default]
+    Fail,
+    /// Give the device the next free GSI of the same kind instead
+    AutoAssign,
+}
+
+/// Kind of interrupt a GSI is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GsiKind {
+    /// Interrupt wired to an irqchip pin (INTx, legacy ISA devices)
+    Legacy,
+    /// Message signalled interrupt
+    Msi,
+}
+
+impl GsiKind {
+    fn range(self) -> (u32, u32) {
+        match self {
+            GsiKind::Legacy => (LEGACY_GSI_BASE, LEGACY_GSI_MAX),
+            GsiKind::Msi => (MSI_GSI_BASE, MSI_GSI_MAX),
+        }
+    }
+}
+
+/// Tracks which device owns each GSI of a VM.
+///
+/// Devices either request a specific GSI or let the allocator pick one. Two devices
+/// never silently share a GSI: a conflicting request fails or is moved to a free GSI,
+/// depending on the policy.
+#[derive(Debug, Clone, Default)]
+pub struct GsiAllocator {
+    policy: GsiConflictPolicy,
+    owners: BTreeMap<u32, String>,
+}
+
+impl GsiAllocator {
+    /// Creates an allocator with no GSI in use.
+    pub fn new(policy: GsiConflictPolicy) -> Self {
+        GsiAllocator { policy, owners: BTreeMap::new() }
+    }
+
+    /// Reserves a GSI for `owner`.
+    ///
+    /// # Arguments
+    /// * `owner` - Name of the device, used in error messages
+    /// * `kind` - Whether the GSI is wired to an irqchip pin or used for an MSI
+    /// * `requested` - GSI required by the device, or `None` to pick the lowest free one
+    ///
+    /// # Returns
+    /// * `Ok(u32)` with the GSI reserved for the device
+    /// * `Err(VmError)` if the requested GSI is in use and the policy is `Fail`, or no GSI is left
+    pub fn allocate(&mut self, owner: &str, kind: GsiKind, requested: Option<u32>) -> Result<u32, VmError> {
+        let gsi = match requested {
+            Some(gsi) => match self.owners.get(&gsi) {
+                None => gsi,
+                Some(current) => match self.policy {
+                    GsiConflictPolicy::Fail => return Err(VmError::config(format!(
+                        "GSI {} requested by {} is already used by {}", gsi, owner, current
+                    ))),
+                    GsiConflictPolicy::AutoAssign => self.find_free(owner, kind)?,
+                }
+            },
+            None => self.find_free(owner, kind)?,
+        };
+
+        self.owners.insert(gsi, owner.to_string());
+        Ok(gsi)
+    }
+
+    /// Frees a GSI so another device can use it.
+    ///
+    /// # Returns
+    /// * The name of the device that owned the GSI, if any
+    pub fn release(&mut self, gsi: u32) -> Option<String> {
+        self.owners.remove(&gsi)
+    }
+
+    /// Returns the device owning `gsi`, if any.
+    pub fn get_owner(&self, gsi: u32) -> Option<&str> {
+        self.owners.get(&gsi).map(|owner| owner.as_str())
+    }
+
+    /// Returns the conflict policy.
+    pub fn get_policy(&self) -> GsiConflictPolicy {
+        self.policy
+    }
+
+    fn find_free(&self, owner: &str, kind: GsiKind) -> Result<u32, VmError> {
+        let (first, last) = kind.range();
+        match (first..=last).find(|gsi| !self.owners.contains_key(gsi)) {
+            Some(gsi) => Ok(gsi),
+            None => Err(VmError::config(format!("no free {:?} GSI left for {}", kind, owner)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explicit_conflict_fails_with_both_owners() {
+        let mut allocator = GsiAllocator::new(GsiConflictPolicy::Fail);
+        assert_eq!(allocator.allocate("virtio-blk0", GsiKind::Legacy, Some(5)).unwrap(), 5);
+
+        let err = allocator.allocate("virtio-net0", GsiKind::Legacy, Some(5)).unwrap_err().to_string();
+        assert!(err.contains("virtio-blk0") && err.contains("virtio-net0"), "unexpected error: {}", err);
+        assert_eq!(allocator.get_owner(5), Some("virtio-blk0"));
+    }
+
+    #[test]
+    fn test_auto_assign_moves_conflicting_device() {
+        let mut allocator = GsiAllocator::new(GsiConflictPolicy::AutoAssign);
+        allocator.allocate("virtio-blk0", GsiKind::Legacy, Some(5)).unwrap();
+        assert_eq!(allocator.allocate("virtio-net0", GsiKind::Legacy, Some(5)).unwrap(), 6);
+        assert_eq!(allocator.allocate("virtio-rng0", GsiKind::Legacy, None).unwrap(), 7);
+        assert_eq!(allocator.allocate("virtio-blk0-msi", GsiKind::Msi, None).unwrap(), MSI_GSI_BASE);
+    }
+
+    #[test]
+    fn test_release_and_exhaustion() {
+        let mut allocator = GsiAllocator::default();
+        for gsi in LEGACY_GSI_BASE..=LEGACY_GSI_MAX {
+            allocator.allocate(&format!("dev{}", gsi), GsiKind::Legacy, None).unwrap();
+        }
+        assert!(allocator.allocate("extra", GsiKind::Legacy, None).is_err());
+
+        assert_eq!(allocator.release(9).as_deref(), Some("dev9"));
+        assert_eq!(allocator.allocate("extra", GsiKind::Legacy, None).unwrap(), 9);
+    }
+}
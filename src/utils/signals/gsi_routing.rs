@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use kvm_bindings::{
+    kvm_irq_routing_entry, KvmIrqRouting, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI,
+};
+use kvm_ioctls::VmFd;
+use crate::error::VmError;
+
+/// Number of IOAPIC pins emulated by KVM.
+pub const IOAPIC_NUM_PINS: u32 = 24;
+/// Number of legacy ISA interrupts, wired to both the PICs and the IOAPIC.
+pub const ISA_NUM_IRQS: u32 = 16;
+/// Maximum number of routing entries KVM accepts.
+pub const MAX_IRQ_ROUTES: usize = 4096;
+
+/// Interrupt controller a GSI can be routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqChip {
+    /// Master 8259 PIC, ISA IRQs 0-7
+    PicMaster,
+    /// Slave 8259 PIC, ISA IRQs 8-15
+    PicSlave,
+    /// IOAPIC
+    Ioapic,
+}
+
+impl IrqChip {
+    fn as_kvm(self) -> u32 {
+        match self {
+            IrqChip::PicMaster => KVM_IRQCHIP_PIC_MASTER,
+            IrqChip::PicSlave => KVM_IRQCHIP_PIC_SLAVE,
+            IrqChip::Ioapic => KVM_IRQCHIP_IOAPIC,
+        }
+    }
+}
+
+/// Destination of a GSI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GsiRoute {
+    /// A pin of an in-kernel interrupt controller
+    Irqchip { chip: IrqChip, pin: u32 },
+    /// A message signalled interrupt
+    Msi { address: u64, data: u32 },
+}
+
+/// The GSI routing table of a KVM VM.
+///
+/// `KVM_SET_GSI_ROUTING` replaces the whole table, so every route the VM needs is kept
+/// here and the complete table is pushed to KVM with `apply` whenever devices are added
+/// or removed. A GSI may have several irqchip routes (an ISA IRQ drives both a PIC and
+/// an IOAPIC pin) but only one MSI route.
+#[derive(Debug, Clone, Default)]
+pub struct GsiRoutingTable {
+    routes: BTreeMap<u32, Vec<GsiRoute>>,
+}
+
+impl GsiRoutingTable {
+    /// Creates an empty routing table.
+    pub fn new() -> Self {
+        GsiRoutingTable { routes: BTreeMap::new() }
+    }
+
+    /// Creates a table holding KVM's default x86 routing.
+    ///
+    /// GSIs 0-15 are routed to the PICs and the IOAPIC pin of the same number, GSIs
+    /// 16-23 to IOAPIC pins only.
+    pub fn with_default_routes() -> Self {
+        let mut table = GsiRoutingTable::new();
+        for gsi in 0..IOAPIC_NUM_PINS {
+            let mut routes = Vec::with_capacity(2);
+            if gsi < 8 {
+                routes.push(GsiRoute::Irqchip { chip: IrqChip::PicMaster, pin: gsi });
+            } else if gsi < ISA_NUM_IRQS {
+                routes.push(GsiRoute::Irqchip { chip: IrqChip::PicSlave, pin: gsi - 8 });
+            }
+            routes.push(GsiRoute::Irqchip { chip: IrqChip::Ioapic, pin: gsi });
+            table.routes.insert(gsi, routes);
+        }
+        table
+    }
+
+    /// Routes `gsi` to a pin of an in-kernel interrupt controller.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the pin doesn't exist, the GSI already has an MSI route or
+    ///   the table is full
+    pub fn add_irqchip_route(&mut self, gsi: u32, chip: IrqChip, pin: u32) -> Result<(), VmError> {
+        let max_pin = match chip {
+            IrqChip::PicMaster | IrqChip::PicSlave => 8,
+            IrqChip::Ioapic => IOAPIC_NUM_PINS,
+        };
+        if pin >= max_pin {
+            return Err(VmError::config(format!("{:?} has no pin {}", chip, pin)));
+        }
+        if self.get_routes(gsi).iter().any(|route| matches!(route, GsiRoute::Msi { .. })) {
+            return Err(VmError::config(format!("GSI {} is already routed to an MSI", gsi)));
+        }
+
+        let route = GsiRoute::Irqchip { chip, pin };
+        if self.get_routes(gsi).contains(&route) {
+            return Ok(());
+        }
+        self.check_capacity()?;
+        self.routes.entry(gsi).or_default().push(route);
+        Ok(())
+    }
+
+    /// Routes `gsi` to an MSI with the given address and data, replacing any previous MSI route.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the GSI is routed to an irqchip pin or the table is full
+    pub fn add_msi_route(&mut self, gsi: u32, address: u64, data: u32) -> Result<(), VmError> {
+        match self.routes.get(&gsi).map(|routes| routes.as_slice()) {
+            Some([GsiRoute::Msi { .. }]) => {},
+            Some(_) => return Err(VmError::config(format!("GSI {} is already routed to an irqchip pin", gsi))),
+            None => self.check_capacity()?,
+        }
+        self.routes.insert(gsi, vec![GsiRoute::Msi { address, data }]);
+        Ok(())
+    }
+
+    /// Removes every route of `gsi`.
+    ///
+    /// # Returns
+    /// * The routes that were removed, empty if the GSI wasn't routed
+    pub fn remove_routes(&mut self, gsi: u32) -> Vec<GsiRoute> {
+        self.routes.remove(&gsi).unwrap_or_default()
+    }
+
+    /// Returns the routes of `gsi`.
+    pub fn get_routes(&self, gsi: u32) -> &[GsiRoute] {
+        self.routes.get(&gsi).map(|routes| routes.as_slice()).unwrap_or(&[])
+    }
+
+    /// Returns `true` if `gsi` has at least one route.
+    pub fn is_routed(&self, gsi: u32) -> bool {
+        self.routes.contains_key(&gsi)
+    }
+
+    /// Returns the total number of routing entries.
+    pub fn len(&self) -> usize {
+        self.routes.values().map(|routes| routes.len()).sum()
+    }
+
+    /// Returns `true` if the table has no routes.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Builds the KVM routing entries, ordered by GSI.
+    pub fn build_entries(&self) -> Vec<kvm_irq_routing_entry> {
+        let mut entries = Vec::with_capacity(self.len());
+        for (gsi, routes) in &self.routes {
+            for route in routes {
+                let mut entry = kvm_irq_routing_entry { gsi: *gsi, ..Default::default() };
+                match *route {
+                    GsiRoute::Irqchip { chip, pin } => {
+                        entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+                        entry.u.irqchip.irqchip = chip.as_kvm();
+                        entry.u.irqchip.pin = pin;
+                    },
+                    GsiRoute::Msi { address, data } => {
+                        entry.type_ = KVM_IRQ_ROUTING_MSI;
+                        entry.u.msi.address_lo = address as u32;
+                        entry.u.msi.address_hi = (address >> 32) as u32;
+                        entry.u.msi.data = data;
+                    },
+                }
+                entries.push(entry);
+            }
+        }
+        entries
+    }
+
+    /// Installs the table in KVM with `KVM_SET_GSI_ROUTING`.
+    ///
+    /// # Arguments
+    /// * `vm_fd` - VM whose in-kernel irqchip was already created
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if KVM rejected the table
+    pub fn apply(&self, vm_fd: &VmFd) -> Result<(), VmError> {
+        let routing = match KvmIrqRouting::from_entries(&self.build_entries()) {
+            Ok(routing) => routing,
+            Err(e) => return Err(VmError::hypervisor(format!("Failed to build GSI routing table: {:?}", e)))
+        };
+        match vm_fd.set_gsi_routing(&routing) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::hypervisor_source(format!("Failed to set GSI routing: {}", e), e))
+        }
+    }
+
+    fn check_capacity(&self) -> Result<(), VmError> {
+        if self.len() >= MAX_IRQ_ROUTES {
+            return Err(VmError::config(format!("GSI routing table is full ({} entries)", MAX_IRQ_ROUTES)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_routes_match_kvm() {
+        let table = GsiRoutingTable::with_default_routes();
+        assert_eq!(table.get_routes(3), &[
+            GsiRoute::Irqchip { chip: IrqChip::PicMaster, pin: 3 },
+            GsiRoute::Irqchip { chip: IrqChip::Ioapic, pin: 3 },
+        ]);
+        assert_eq!(table.get_routes(10)[0], GsiRoute::Irqchip { chip: IrqChip::PicSlave, pin: 2 });
+        assert_eq!(table.get_routes(20), &[GsiRoute::Irqchip { chip: IrqChip::Ioapic, pin: 20 }]);
+        assert_eq!(table.len(), 16 * 2 + 8);
+    }
+
+    #[test]
+    fn test_msi_routes_are_added_and_removed() {
+        let mut table = GsiRoutingTable::with_default_routes();
+        table.add_msi_route(24, 0xfee0_0000, 0x4031).expect("MSI route should be added");
+        table.add_msi_route(24, 0xfee0_1000, 0x4032).expect("MSI route should be replaced");
+        assert_eq!(table.get_routes(24), &[GsiRoute::Msi { address: 0xfee0_1000, data: 0x4032 }]);
+
+        // A GSI is either an irqchip pin or an MSI
+        assert!(table.add_msi_route(5, 0xfee0_0000, 0).is_err());
+        assert!(table.add_irqchip_route(24, IrqChip::Ioapic, 5).is_err());
+        assert!(table.add_irqchip_route(30, IrqChip::Ioapic, 24).is_err());
+
+        assert_eq!(table.remove_routes(24).len(), 1);
+        assert!(!table.is_routed(24));
+    }
+
+    #[test]
+    fn test_build_entries() {
+        let mut table = GsiRoutingTable::new();
+        table.add_irqchip_route(5, IrqChip::Ioapic, 5).unwrap();
+        table.add_msi_route(24, 0x1_fee0_0000, 0x31).unwrap();
+
+        let entries = table.build_entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].gsi, entries[0].type_), (5, KVM_IRQ_ROUTING_IRQCHIP));
+        // SAFETY: the union variant matches the entry type
+        unsafe {
+            assert_eq!(entries[0].u.irqchip.irqchip, KVM_IRQCHIP_IOAPIC);
+            assert_eq!(entries[1].u.msi.address_hi, 1);
+            assert_eq!(entries[1].u.msi.data, 0x31);
+        }
+    }
+}
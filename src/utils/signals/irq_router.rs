@@ -0,0 +1,238 @@
+use std::sync::{Arc, Mutex};
+use kvm_ioctls::VmFd;
+use crate::error::VmError;
+use super::TriggerMode;
+use super::gsi_allocator::{GsiAllocator, GsiConflictPolicy, GsiKind, MSI_GSI_BASE};
+use super::gsi_routing::{GsiRoute, GsiRoutingTable};
+use super::linux::IrqfdInterrupt;
+
+/// Address and data a device writes to signal a message signalled interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MsiMessage {
+    /// Address the message is written to, selecting the destination APIC
+    pub address: u64,
+    /// Data written, selecting the vector and delivery mode
+    pub data: u32,
+}
+
+/// GSIs in use and the routing table KVM was given for them.
+struct RouterState {
+    allocator: GsiAllocator,
+    table: GsiRoutingTable,
+}
+
+/// Owner of the interrupt routing of a KVM VM with an in-kernel irqchip.
+///
+/// Devices get their GSIs and irqfd interrupts from the router instead of picking GSI
+/// numbers themselves. Legacy GSIs use the default irqchip routes; MSI and MSI-X
+/// vectors get GSIs above the IOAPIC pins, routed to the message the guest programmed.
+/// Every change to the routes pushes the whole table to KVM, as `KVM_SET_GSI_ROUTING`
+/// replaces it. The router is shared as an `Arc` so a PCI device can reroute its
+/// vectors when the guest reprograms them.
+pub struct IrqRouter {
+    vm_fd: Arc<VmFd>,
+    state: Mutex<RouterState>,
+}
+
+impl IrqRouter {
+    /// Creates a router holding KVM's default routes, with no GSI allocated.
+    ///
+    /// # Arguments
+    /// * `vm_fd` - VM whose interrupts are routed
+    /// * `policy` - What to do when a device asks for a GSI already in use
+    pub fn new(vm_fd: VmFd, policy: GsiConflictPolicy) -> Self {
+        IrqRouter {
+            vm_fd: Arc::new(vm_fd),
+            state: Mutex::new(RouterState { allocator: GsiAllocator::new(policy), table: GsiRoutingTable::with_default_routes() }),
+        }
+    }
+
+    /// Returns the VM whose interrupts are routed.
+    pub fn get_vm(&self) -> &VmFd {
+        &self.vm_fd
+    }
+
+    /// Returns the device owning `gsi`, if any.
+    pub fn get_owner(&self, gsi: u32) -> Option<String> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.allocator.get_owner(gsi).map(|owner| owner.to_string())
+    }
+
+    /// Returns the routes of `gsi`.
+    pub fn get_routes(&self, gsi: u32) -> Vec<GsiRoute> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.table.get_routes(gsi).to_vec()
+    }
+
+    /// Installs the routing table in KVM. The in-kernel irqchip must exist.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if KVM rejected the table
+    pub fn apply(&self) -> Result<(), VmError> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.table.apply(&self.vm_fd)
+    }
+
+    /// Reserves a GSI wired to an irqchip pin and creates an irqfd interrupt on it.
+    ///
+    /// # Arguments
+    /// * `owner` - Name of the device, reported on conflicts
+    /// * `gsi` - GSI required by the device, or `None` to use any free one
+    /// * `trigger_mode` - Level-triggered lines get a resample eventfd
+    ///
+    /// # Returns
+    /// * `Ok(IrqfdInterrupt)` on the reserved GSI
+    /// * `Err(VmError)` if no GSI could be reserved or the irqfd couldn't be registered
+    pub fn create_interrupt(&self, owner: &str, gsi: Option<u32>, trigger_mode: TriggerMode) -> Result<IrqfdInterrupt, VmError> {
+        let gsi = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.allocator.allocate(owner, GsiKind::Legacy, gsi)?
+        };
+        self.register(gsi, trigger_mode)
+    }
+
+    /// Reserves a GSI routed to `message` and creates an irqfd interrupt on it.
+    ///
+    /// # Returns
+    /// * `Ok(IrqfdInterrupt)` signalling the MSI
+    /// * `Err(VmError)` if no MSI GSI is left or KVM rejected the route or the irqfd
+    pub fn create_msi_interrupt(&self, owner: &str, message: MsiMessage) -> Result<IrqfdInterrupt, VmError> {
+        let gsi = self.allocate_msi(owner)?;
+        if let Err(e) = self.update_msi(gsi, Some(message)) {
+            self.release(gsi)?;
+            return Err(e);
+        }
+        self.register(gsi, TriggerMode::Edge)
+    }
+
+    /// Reserves `count` MSI-X vectors of a device and creates their irqfd interrupts.
+    ///
+    /// The vectors start masked, without a route; `update_msi` routes each one once the
+    /// guest programmed and unmasked its MSI-X table entry.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<IrqfdInterrupt>)` with one interrupt per vector, in vector order
+    /// * `Err(VmError)` if not enough MSI GSIs are left or an irqfd couldn't be registered
+    pub fn create_msix_interrupts(&self, owner: &str, count: u16) -> Result<Vec<IrqfdInterrupt>, VmError> {
+        let mut interrupts = Vec::with_capacity(count as usize);
+        for vector in 0..count {
+            let interrupt = self.allocate_msi(&format!("{}-msix{}", owner, vector))
+                .and_then(|gsi| self.register(gsi, TriggerMode::Edge));
+            match interrupt {
+                Ok(interrupt) => interrupts.push(interrupt),
+                Err(e) => {
+                    for interrupt in &interrupts {
+                        self.release(interrupt.get_gsi())?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(interrupts)
+    }
+
+    /// Routes the MSI GSI `gsi` to `message`, or masks it with `None`.
+    ///
+    /// # Returns
+    /// * `Ok(())` once KVM uses the new routes
+    /// * `Err(VmError)` if `gsi` isn't an allocated MSI GSI or KVM rejected the table
+    pub fn update_msi(&self, gsi: u32, message: Option<MsiMessage>) -> Result<(), VmError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if gsi < MSI_GSI_BASE || state.allocator.get_owner(gsi).is_none() {
+            return Err(VmError::config(format!("GSI {} isn't an allocated MSI GSI", gsi)));
+        }
+        let previous = match message {
+            Some(message) => {
+                let previous = state.table.get_routes(gsi).to_vec();
+                state.table.add_msi_route(gsi, message.address, message.data)?;
+                previous
+            },
+            None => state.table.remove_routes(gsi),
+        };
+        if let Err(e) = state.table.apply(&self.vm_fd) {
+            // Keep the table in sync with what KVM still uses
+            state.table.remove_routes(gsi);
+            if let Some(GsiRoute::Msi { address, data }) = previous.first() {
+                state.table.add_msi_route(gsi, *address, *data)?;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Frees `gsi` and removes its MSI route, if it had one.
+    ///
+    /// # Returns
+    /// * `Ok(Some(String))` with the device that owned the GSI
+    /// * `Ok(None)` if the GSI wasn't allocated
+    /// * `Err(VmError)` if KVM rejected the table without the MSI route
+    pub fn release(&self, gsi: u32) -> Result<Option<String>, VmError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let owner = state.allocator.release(gsi);
+        if owner.is_some() && gsi >= MSI_GSI_BASE && !state.table.remove_routes(gsi).is_empty() {
+            state.table.apply(&self.vm_fd)?;
+        }
+        Ok(owner)
+    }
+
+    fn allocate_msi(&self, owner: &str) -> Result<u32, VmError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.allocator.allocate(owner, GsiKind::Msi, None)
+    }
+
+    fn register(&self, gsi: u32, trigger_mode: TriggerMode) -> Result<IrqfdInterrupt, VmError> {
+        match IrqfdInterrupt::with_shared_vm(self.vm_fd.clone(), gsi, trigger_mode) {
+            Ok(interrupt) => Ok(interrupt),
+            Err(e) => {
+                self.release(gsi)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+    use super::super::Interrupt;
+
+    fn create_router() -> IrqRouter {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        #[cfg(target_arch = "x86_64")]
+        vm.create_irq_chip().expect("Failed to create IRQ chip");
+        IrqRouter::new(vm, GsiConflictPolicy::Fail)
+    }
+
+    #[test]
+    fn test_legacy_interrupts_get_distinct_gsis() {
+        let router = create_router();
+        router.apply().expect("Default routes should be accepted");
+        let first = router.create_interrupt("virtio-blk0", None, TriggerMode::Edge).unwrap();
+        let second = router.create_interrupt("virtio-blk1", None, TriggerMode::Level).unwrap();
+        assert_ne!(first.get_gsi(), second.get_gsi());
+        assert_eq!(second.get_trigger_mode(), TriggerMode::Level);
+        assert!(router.create_interrupt("virtio-net0", Some(first.get_gsi()), TriggerMode::Edge).is_err());
+        assert_eq!(router.get_owner(first.get_gsi()).as_deref(), Some("virtio-blk0"));
+    }
+
+    #[test]
+    fn test_msix_vectors_are_routed_when_unmasked() {
+        let router = create_router();
+        let vectors = router.create_msix_interrupts("virtio-pci0", 2).unwrap();
+        assert_eq!(vectors[0].get_gsi(), MSI_GSI_BASE);
+        assert!(router.get_routes(vectors[1].get_gsi()).is_empty());
+
+        let message = MsiMessage { address: 0xfee0_0000, data: 0x41 };
+        router.update_msi(vectors[1].get_gsi(), Some(message)).unwrap();
+        assert_eq!(router.get_routes(vectors[1].get_gsi()), vec![GsiRoute::Msi { address: 0xfee0_0000, data: 0x41 }]);
+        router.update_msi(vectors[1].get_gsi(), None).unwrap();
+        assert!(router.get_routes(vectors[1].get_gsi()).is_empty());
+
+        // Legacy GSIs can't be turned into MSIs
+        assert!(router.update_msi(5, Some(message)).is_err());
+        assert_eq!(router.release(vectors[0].get_gsi()).unwrap().as_deref(), Some("virtio-pci0-msix0"));
+    }
+}
@@ -1,45 +1,97 @@
+use std::sync::Arc;
 use vmm_sys_util::eventfd::EventFd;
 use kvm_ioctls::VmFd;
+use crate::error::VmError;
+use super::{Interrupt, TriggerMode};
+use super::gsi_allocator::{GsiAllocator, GsiKind};
 
-/// Struct representing a virtual interrupt mechanism using KVM irqfd.
+/// Interrupt line backed by a KVM irqfd.
 ///
-/// This is useful for virtual devices to signal interrupts to the guest OS.
-pub struct Interrupt {
+/// This is useful for virtual devices to signal interrupts to the guest OS. Edge-triggered
+/// lines inject one interrupt per eventfd write. Level-triggered lines are registered with
+/// a resample eventfd: a write asserts the GSI, KVM deasserts it when the guest signals
+/// end-of-interrupt and then signals the resample event.
+pub struct IrqfdInterrupt {
     irqfd: EventFd,     // eventfd used for signaling interrupt
-    vm_fd: VmFd,        // handle to KVM VM for ioctl calls
+    resamplefd: Option<EventFd>, // eventfd KVM signals on EOI of a level-triggered line
+    vm_fd: Arc<VmFd>,   // handle to KVM VM for ioctl calls, shared with the IrqRouter
     gsi: u32,           // guest interrupt number (IRQ line)
 }
 
-impl Interrupt {
-    /// Creates a new Interrupt instance by registering an irqfd with KVM.
+impl IrqfdInterrupt {
+    /// Creates a new edge-triggered interrupt by registering an irqfd with KVM.
     ///
     /// # Arguments
     /// * `vm_fd` - Reference to the KVM VM file descriptor.
     /// * `gsi` - Global System Interrupt (GSI) line to trigger in the guest.
     ///
     /// # Returns
-    /// A Result containing the initialized Interrupt or a String error.
-    pub fn new(vm_fd: VmFd, gsi: u32) -> Result<Self, String> {
-        // Create a new eventfd which acts as a signaling mechanism
-        let irqfd = match EventFd::new(0) {
-            Ok(e) => e,
-            Err(e) => return Err(format!("{:?}", e))
-        };
-
-        // Register the eventfd with KVM to notify the guest via specified GSI
-        match vm_fd.register_irqfd(&irqfd, gsi) {
-            Ok(_) => Ok(Interrupt { irqfd, vm_fd, gsi }),
-            Err(e) => return Err(format!("{:?}", e))
+    /// A Result containing the initialized IrqfdInterrupt or a VmError.
+    pub fn new(vm_fd: VmFd, gsi: u32) -> Result<Self, VmError> {
+        Self::with_shared_vm(Arc::new(vm_fd), gsi, TriggerMode::Edge)
+    }
+
+    /// Creates a new level-triggered interrupt, for legacy INTx emulation.
+    ///
+    /// # Arguments
+    /// * `vm_fd` - Reference to the KVM VM file descriptor.
+    /// * `gsi` - Global System Interrupt (GSI) line, routed to an irqchip pin.
+    ///
+    /// # Returns
+    /// A Result containing the initialized IrqfdInterrupt or a VmError.
+    pub fn new_level(vm_fd: VmFd, gsi: u32) -> Result<Self, VmError> {
+        Self::with_shared_vm(Arc::new(vm_fd), gsi, TriggerMode::Level)
+    }
+
+    /// Creates a new interrupt on a VM handle shared with other interrupts, as the
+    /// `IrqRouter` hands them out.
+    ///
+    /// # Arguments
+    /// * `vm_fd` - Shared KVM VM file descriptor.
+    /// * `gsi` - Global System Interrupt (GSI) line to trigger in the guest.
+    /// * `trigger_mode` - Level-triggered lines get a resample eventfd.
+    ///
+    /// # Returns
+    /// A Result containing the initialized IrqfdInterrupt or a VmError.
+    pub fn with_shared_vm(vm_fd: Arc<VmFd>, gsi: u32, trigger_mode: TriggerMode) -> Result<Self, VmError> {
+        let irqfd = create_eventfd("irq")?;
+        if trigger_mode == TriggerMode::Edge {
+            // Register the eventfd with KVM to notify the guest via specified GSI
+            return match vm_fd.register_irqfd(&irqfd, gsi) {
+                Ok(_) => Ok(IrqfdInterrupt { irqfd, resamplefd: None, vm_fd, gsi }),
+                Err(e) => Err(VmError::hypervisor_source(format!("failed to register irqfd for GSI {}: {}", gsi, e), e))
+            };
+        }
+
+        let resamplefd = create_eventfd("irq resample")?;
+        match vm_fd.register_irqfd_with_resample(&irqfd, &resamplefd, gsi) {
+            Ok(_) => Ok(IrqfdInterrupt { irqfd, resamplefd: Some(resamplefd), vm_fd, gsi }),
+            Err(e) => Err(VmError::hypervisor_source(format!("failed to register resampling irqfd for GSI {}: {}", gsi, e), e))
         }
     }
 
-    /// Triggers the interrupt by writing to the eventfd.
+    /// Creates a new edge-triggered interrupt on a GSI reserved through `allocator`.
     ///
-    /// This signals the guest OS on the specified GSI line.
-    pub fn trigger(&self) -> Result<(), String> {
-        match self.irqfd.write(1) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("{:?}", e))
+    /// A GSI already used by another device is rejected or replaced by a free one,
+    /// depending on the allocator's conflict policy, so devices never share a line by
+    /// accident.
+    ///
+    /// # Arguments
+    /// * `vm_fd` - Reference to the KVM VM file descriptor.
+    /// * `allocator` - GSI bookkeeping of the VM.
+    /// * `owner` - Name of the device, reported on conflicts.
+    /// * `gsi` - GSI required by the device, or `None` to use any free one.
+    ///
+    /// # Returns
+    /// A Result containing the initialized IrqfdInterrupt or a VmError.
+    pub fn with_allocator(vm_fd: VmFd, allocator: &mut GsiAllocator, owner: &str, gsi: Option<u32>) -> Result<Self, VmError> {
+        let gsi = allocator.allocate(owner, GsiKind::Legacy, gsi)?;
+        match Self::new(vm_fd, gsi) {
+            Ok(interrupt) => Ok(interrupt),
+            Err(e) => {
+                allocator.release(gsi);
+                Err(e)
+            }
         }
     }
 
@@ -59,9 +111,42 @@ impl Interrupt {
     }
 }
 
+impl Interrupt for IrqfdInterrupt {
+    fn get_trigger_mode(&self) -> TriggerMode {
+        if self.resamplefd.is_some() { TriggerMode::Level } else { TriggerMode::Edge }
+    }
+
+    /// Writes to the irqfd, which signals the guest OS on the GSI line.
+    fn assert(&self) -> Result<(), VmError> {
+        match self.irqfd.write(1) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to signal irqfd: {}", e), e))
+        }
+    }
+
+    /// KVM deasserts a resampling irqfd itself on end-of-interrupt, and only re-asserts
+    /// it on the next write, so there is nothing to do here.
+    fn deassert(&self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn get_resample_event(&self) -> Option<&EventFd> {
+        self.resamplefd.as_ref()
+    }
+}
+
+/// Creates a non-blocking eventfd used for interrupt signalling.
+fn create_eventfd(what: &str) -> Result<EventFd, VmError> {
+    match EventFd::new(libc::EFD_NONBLOCK) {
+        Ok(e) => Ok(e),
+        Err(e) => Err(VmError::io(format!("failed to create {} eventfd: {}", what, e), e))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::gsi_allocator::GsiConflictPolicy;
     use kvm_ioctls::{Kvm};
 
     /// Helper function to create a KVM VM instance with IRQ chip initialized.
@@ -82,8 +167,8 @@ mod tests {
         let gsi = 5; // arbitrary GSI number
 
         // Should successfully create and register the irqfd
-        let interrupt = Interrupt::new(vm_fd, gsi);
-        assert!(interrupt.is_ok(), "Interrupt::new should succeed");
+        let interrupt = IrqfdInterrupt::new(vm_fd, gsi);
+        assert!(interrupt.is_ok(), "IrqfdInterrupt::new should succeed");
     }
 
     #[test]
@@ -92,10 +177,32 @@ mod tests {
         let gsi = 5;
 
         // Create the interrupt and ensure triggering works
-        let interrupt = Interrupt::new(vm_fd, gsi).expect("Failed to create Interrupt");
+        let interrupt = IrqfdInterrupt::new(vm_fd, gsi).expect("Failed to create IrqfdInterrupt");
 
         // Trigger the interrupt, should succeed
         let result = interrupt.trigger();
-        assert!(result.is_ok(), "Interrupt::trigger should succeed");
+        assert!(result.is_ok(), "IrqfdInterrupt::trigger should succeed");
+    }
+
+    #[test]
+    fn test_level_interrupt_exposes_resample_event() {
+        let vm_fd = create_vm_fd();
+        let interrupt = IrqfdInterrupt::new_level(vm_fd, 5).expect("Failed to create level IrqfdInterrupt");
+
+        assert_eq!(interrupt.get_trigger_mode(), TriggerMode::Level);
+        assert!(interrupt.get_resample_event().is_some());
+        assert!(interrupt.assert().is_ok());
+        assert!(interrupt.deassert().is_ok());
+    }
+
+    #[test]
+    fn test_interrupt_with_allocator_detects_conflicts() {
+        let mut allocator = GsiAllocator::new(GsiConflictPolicy::Fail);
+        let first = IrqfdInterrupt::with_allocator(create_vm_fd(), &mut allocator, "virtio-blk0", Some(5))
+            .expect("First device should get GSI 5");
+        assert_eq!(first.get_gsi(), 5);
+
+        let result = IrqfdInterrupt::with_allocator(create_vm_fd(), &mut allocator, "virtio-blk1", Some(5));
+        assert!(result.is_err(), "A second device on GSI 5 should be rejected");
     }
-}
\ No newline at end of file
+}
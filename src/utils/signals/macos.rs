@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::error::VmError;
+use super::{Interrupt, TriggerMode};
+
+/// Interrupt line for the Hypervisor.framework backend.
+///
+/// Hypervisor.framework injects IRQs with `hv_vcpu_set_pending_interrupt`, which must
+/// be called from the vCPU's own thread and only applies to the next run. The line
+/// therefore only records its state; the vCPU loop calls `take_pending` before every
+/// run and marks the IRQ pending accordingly.
+#[derive(Default)]
+pub struct HvfInterrupt {
+    trigger_mode: TriggerMode,  // edge or level delivery
+    asserted: AtomicBool,       // level-triggered line state
+    edge_pending: AtomicBool,   // edge-triggered interrupt not yet injected
+}
+
+impl HvfInterrupt {
+    /// Creates a deasserted interrupt line.
+    pub fn new(trigger_mode: TriggerMode) -> Self {
+        HvfInterrupt { trigger_mode, asserted: AtomicBool::new(false), edge_pending: AtomicBool::new(false) }
+    }
+
+    /// Returns whether the vCPU must enter the guest with its IRQ pending.
+    ///
+    /// A pending edge-triggered interrupt is consumed by this call; a level-triggered
+    /// line stays pending until it is deasserted.
+    pub fn take_pending(&self) -> bool {
+        match self.trigger_mode {
+            TriggerMode::Edge => self.edge_pending.swap(false, Ordering::SeqCst),
+            TriggerMode::Level => self.asserted.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl Interrupt for HvfInterrupt {
+    fn get_trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    fn assert(&self) -> Result<(), VmError> {
+        match self.trigger_mode {
+            TriggerMode::Edge => self.edge_pending.store(true, Ordering::SeqCst),
+            TriggerMode::Level => self.asserted.store(true, Ordering::SeqCst),
+        }
+        Ok(())
+    }
+
+    fn deassert(&self) -> Result<(), VmError> {
+        self.asserted.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edge_interrupt_is_consumed() {
+        let interrupt = HvfInterrupt::new(TriggerMode::Edge);
+        assert!(!interrupt.take_pending());
+        interrupt.trigger().unwrap();
+        assert!(interrupt.take_pending());
+        assert!(!interrupt.take_pending());
+    }
+
+    #[test]
+    fn test_level_interrupt_stays_pending_until_deasserted() {
+        let interrupt = HvfInterrupt::new(TriggerMode::Level);
+        interrupt.assert().unwrap();
+        assert!(interrupt.take_pending());
+        assert!(interrupt.take_pending());
+        interrupt.deassert().unwrap();
+        assert!(!interrupt.take_pending());
+    }
+}
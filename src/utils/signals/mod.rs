@@ -1 +1,63 @@
-pub mod linux;
\ No newline at end of file
+//! Interrupt delivery from emulated devices to the guest.
+//!
+//! Devices raise interrupts through the `Interrupt` trait, which each hypervisor
+//! backend implements on top of its own delivery mechanism. Edge-triggered interrupts
+//! (MSI, legacy edge IRQs) deliver one interrupt per assertion. Level-triggered
+//! interrupts (legacy INTx) stay asserted until the device deasserts them; backends that
+//! deassert the line on their own when the guest signals end-of-interrupt expose a
+//! resample event so the device can re-assert it while it still needs service.
+
+#[cfg(target_os = "linux")]
+use vmm_sys_util::eventfd::EventFd;
+use crate::error::VmError;
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "linux")]
+pub mod gsi_routing;
+#[cfg(target_os = "linux")]
+pub mod irq_router;
+pub mod gsi_allocator;
+#[cfg(target_os = "windows")]
+pub mod windows;
+#[cfg(target_os = "macos")]
+pub mod macos;
+
+/// How an interrupt line signals the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TriggerMode {
+    /// Every assertion delivers exactly one interrupt
+    #[default]
+    Edge,
+    /// The interrupt is pending for as long as the line is asserted
+    Level,
+}
+
+/// An interrupt line a device uses to signal the guest.
+pub trait Interrupt: Send + Sync {
+    /// Returns how the line signals the guest.
+    fn get_trigger_mode(&self) -> TriggerMode;
+
+    /// Asserts the line.
+    ///
+    /// An edge-triggered line delivers one interrupt; a level-triggered line stays
+    /// asserted until `deassert` is called.
+    fn assert(&self) -> Result<(), VmError>;
+
+    /// Deasserts the line. Does nothing for edge-triggered lines.
+    fn deassert(&self) -> Result<(), VmError>;
+
+    /// Signals the guest: pulses an edge-triggered line or asserts a level-triggered one.
+    fn trigger(&self) -> Result<(), VmError> {
+        self.assert()
+    }
+
+    /// Returns the event signalled when the guest acknowledged a level-triggered
+    /// interrupt and the line was deasserted behind the device's back.
+    ///
+    /// The device must re-assert the line if it still has pending interrupt reasons.
+    #[cfg(target_os = "linux")]
+    fn get_resample_event(&self) -> Option<&EventFd> {
+        None
+    }
+}
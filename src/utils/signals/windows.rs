@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::error::VmError;
+use crate::windows_bindings::{request_interrupt, Partition};
+use super::{Interrupt, TriggerMode};
+
+/// Interrupt line delivered through the local APIC emulated by the Windows Hypervisor Platform.
+///
+/// WHP has no interrupt lines, only vectors requested on a local APIC. A level-triggered
+/// line requests a level-triggered interrupt when it becomes asserted and remembers its
+/// state so repeated assertions don't queue the vector again.
+pub struct WhpInterrupt {
+    partition: Arc<Partition>,  // partition the interrupt is requested on
+    vector: u32,                // interrupt vector delivered to the guest
+    destination: u32,           // APIC id of the target vCPU
+    trigger_mode: TriggerMode,  // edge or level delivery
+    asserted: AtomicBool,       // current state of a level-triggered line
+}
+
+impl WhpInterrupt {
+    /// Creates an interrupt delivering `vector` to the vCPU with APIC id `destination`.
+    ///
+    /// # Arguments
+    /// * `partition` - Partition the interrupt is requested on
+    /// * `vector` - Interrupt vector, 16 to 255
+    /// * `destination` - APIC id of the target vCPU
+    /// * `trigger_mode` - Edge or level delivery
+    ///
+    /// # Returns
+    /// A Result containing the WhpInterrupt or a VmError if the vector is reserved.
+    pub(crate) fn new(partition: Arc<Partition>, vector: u32, destination: u32, trigger_mode: TriggerMode) -> Result<Self, VmError> {
+        // Vectors below 16 are reserved for exceptions
+        if !(16..=255).contains(&vector) {
+            return Err(VmError::config(format!("interrupt vector {} must be between 16 and 255", vector)));
+        }
+        Ok(WhpInterrupt { partition, vector, destination, trigger_mode, asserted: AtomicBool::new(false) })
+    }
+
+    /// Returns the interrupt vector.
+    pub fn get_vector(&self) -> u32 {
+        self.vector
+    }
+}
+
+impl Interrupt for WhpInterrupt {
+    fn get_trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    fn assert(&self) -> Result<(), VmError> {
+        match self.trigger_mode {
+            TriggerMode::Edge => request_interrupt(&self.partition, self.vector, self.destination, false),
+            TriggerMode::Level => {
+                if self.asserted.swap(true, Ordering::SeqCst) {
+                    return Ok(());
+                }
+                request_interrupt(&self.partition, self.vector, self.destination, true)
+            }
+        }
+    }
+
+    fn deassert(&self) -> Result<(), VmError> {
+        self.asserted.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
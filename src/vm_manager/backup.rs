@@ -0,0 +1,667 @@
+//! Backups of the disks of managed VMs, full or incremental.
+//!
+//! A backup is a gzip-compressed tarball starting with a manifest naming the VM and
+//! its disks. Every disk is split into chunks like in the chunk store: the tarball
+//! holds the chunks that changed, then the chunk list of the disk with the SHA-256
+//! digest of every chunk. A full backup holds every chunk that isn't all zeroes. An
+//! incremental backup refers to the backup it was taken after, by file name and
+//! digest, and holds only the chunks its dirty bitmap marks, those whose digest
+//! differs from the earlier chunk list. Restoring replays the chain of backups from
+//! the full one, checks every chunk and the assembled disks against their digests,
+//! and only then replaces the disks of the VM.
+//!
+//! Backups of a chain must stay in the same directory, where a restore looks for the
+//! earlier ones. The VM must not be running while it is backed up or restored.
+//!
+//! Layout of the tarball:
+//! * `asgard-backup.txt` - `asgard-backup 1`, then `name <vm>`, `chunk-size <bytes>`,
+//!   `parent <digest> <file name>` for incremental backups and one `disk <name>` per disk
+//! * `chunks/<disk>/<index>` - content of a dirty chunk
+//! * `manifests/<disk>` - chunk list of the disk, following its chunks
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tempfile::NamedTempFile;
+use super::VmManager;
+use super::bundle::{hash_file, is_valid_name};
+use crate::error::VmError;
+use crate::utils::chunk_store::{for_each_chunk, get_digest, ChunkManifest, DEFAULT_CHUNK_SIZE};
+use crate::vm_setup::disk_setup::DISK_SNAPSHOT_EXTENSION;
+
+/// Name of the manifest, the first entry of every backup.
+pub const BACKUP_MANIFEST: &str = "asgard-backup.txt";
+/// First line of a manifest, carrying the format version.
+const MANIFEST_HEADER: &str = "asgard-backup 1";
+/// Largest manifest or chunk list a restore reads.
+const MAX_MANIFEST_SIZE: u64 = 16 << 20;
+
+/// Chunks of a disk that changed since an earlier backup, one bit per chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirtyBitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl DirtyBitmap {
+    /// Creates a bitmap of `len` clean chunks.
+    pub fn new(len: usize) -> DirtyBitmap {
+        DirtyBitmap { words: vec![0; len.div_ceil(64)], len }
+    }
+
+    /// Marks the chunks of `current` that differ from `parent`. Every chunk is dirty
+    /// without a parent or if the parent was split into chunks of another size.
+    pub fn between(parent: Option<&ChunkManifest>, current: &ChunkManifest) -> DirtyBitmap {
+        let mut bitmap = DirtyBitmap::new(current.chunks.len());
+        for (index, chunk) in current.chunks.iter().enumerate() {
+            let unchanged = parent.is_some_and(|parent| parent.chunk_size == current.chunk_size && parent.chunks.get(index) == Some(chunk));
+            if !unchanged {
+                bitmap.set(index);
+            }
+        }
+        bitmap
+    }
+
+    /// Marks chunk `index` dirty; indexes past the end are ignored.
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.words[index / 64] |= 1 << (index % 64);
+        }
+    }
+
+    /// Whether chunk `index` is dirty.
+    pub fn is_dirty(&self, index: usize) -> bool {
+        index < self.len && self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Returns the number of chunks covered by the bitmap.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the bitmap covers no chunks.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of dirty chunks.
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+}
+
+/// Outcome of a backup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupStats {
+    /// Number of disks backed up
+    pub disks: usize,
+    /// Number of chunks the disks were split into
+    pub chunks: usize,
+    /// Chunks that changed since the parent backup, all of them for a full backup
+    pub dirty_chunks: usize,
+    /// Bytes of chunk data archived, before compression
+    pub bytes_archived: u64,
+    /// Size of the backup file
+    pub backup_size: u64,
+    /// SHA-256 digest of the backup file in hex, recorded by the next incremental backup
+    pub digest: String,
+}
+
+/// Manifest of a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BackupManifest {
+    name: String,
+    chunk_size: usize,
+    /// Digest and file name of the backup this one was taken after
+    parent: Option<(String, String)>,
+    disks: Vec<String>,
+}
+
+impl BackupManifest {
+    /// Parses a manifest as written by `render`.
+    ///
+    /// # Returns
+    /// * `Some(BackupManifest)` if the manifest is well formed and its names are safe
+    ///   to use as file names
+    /// * `None` otherwise
+    fn parse(text: &str) -> Option<BackupManifest> {
+        let mut lines = text.lines();
+        if lines.next()? != MANIFEST_HEADER {
+            return None;
+        }
+
+        let mut manifest = BackupManifest { name: String::new(), chunk_size: 0, parent: None, disks: Vec::new() };
+        for line in lines {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "name" => manifest.name = value.to_string(),
+                "chunk-size" => manifest.chunk_size = value.parse().ok()?,
+                "parent" => {
+                    let (digest, file) = value.split_once(' ')?;
+                    if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte)) {
+                        return None;
+                    }
+                    manifest.parent = Some((digest.to_string(), file.to_string()));
+                },
+                "disk" => manifest.disks.push(value.to_string()),
+                _ => return None,
+            }
+        }
+
+        let mut names = HashSet::new();
+        let names_valid = is_valid_name(&manifest.name)
+            && manifest.parent.as_ref().is_none_or(|(_, file)| is_valid_name(file))
+            && manifest.disks.iter().all(|disk| is_valid_name(disk) && names.insert(disk.as_str()));
+        if !names_valid || manifest.chunk_size == 0 {
+            return None;
+        }
+        Some(manifest)
+    }
+
+    /// Formats the manifest as `parse` reads it.
+    fn render(&self) -> String {
+        let mut text = format!("{}\nname {}\nchunk-size {}\n", MANIFEST_HEADER, self.name, self.chunk_size);
+        if let Some((digest, file)) = &self.parent {
+            text.push_str(&format!("parent {} {}\n", digest, file));
+        }
+        for disk in &self.disks {
+            text.push_str(&format!("disk {}\n", disk));
+        }
+        text
+    }
+}
+
+/// Entry of a backup following its manifest.
+enum BackupEntry {
+    /// Content of chunk `index` of a disk
+    Chunk { disk: String, index: usize },
+    /// Chunk list of a disk
+    ChunkList { disk: String },
+}
+
+impl BackupEntry {
+    /// Parses the path of an entry inside the tarball.
+    fn parse(path: &str) -> Option<BackupEntry> {
+        if let Some(disk) = path.strip_prefix("manifests/") {
+            return Some(BackupEntry::ChunkList { disk: disk.to_string() });
+        }
+        let (disk, index) = path.strip_prefix("chunks/")?.split_once('/')?;
+        Some(BackupEntry::Chunk { disk: disk.to_string(), index: index.parse().ok()? })
+    }
+}
+
+/// Callback of `read_backup`, given every entry after the manifest.
+type EntryVisitor<'a> = dyn FnMut(&BackupManifest, BackupEntry, &mut dyn Read) -> Result<(), VmError> + 'a;
+
+/// Disk of a VM being restored: a new image next to the disk, replacing it once
+/// the whole chain of backups was applied and verified.
+struct RestoredDisk {
+    target: PathBuf,
+    output: NamedTempFile,
+    /// Chunk list of the last backup applied
+    chunk_list: Option<ChunkManifest>,
+    /// Digests of the chunks written by the backup being applied
+    written: HashMap<usize, String>,
+}
+
+impl VmManager {
+    /// Takes a full backup of the disks of the VM `name` into `dest`.
+    ///
+    /// # Arguments
+    /// * `name` - The VM, which must not be running
+    /// * `dest` - Path of the backup, replaced once it is complete
+    ///
+    /// # Returns
+    /// * `Ok(BackupStats)` once the backup is written
+    /// * `Err(VmError)` if the VM is unknown, two of its disks have the same file name
+    ///   or a file couldn't be read or written
+    pub fn backup_vm(&self, name: &str, dest: &Path) -> Result<BackupStats, VmError> {
+        self.write_backup(name, dest, None)
+    }
+
+    /// Takes an incremental backup of the disks of the VM `name` into `dest`, holding
+    /// the chunks that changed since the backup at `parent`.
+    ///
+    /// # Arguments
+    /// * `name` - The VM, which must not be running
+    /// * `dest` - Path of the backup, replaced once it is complete
+    /// * `parent` - Earlier backup of the VM, full or incremental, in the directory of `dest`
+    ///
+    /// # Returns
+    /// * `Ok(BackupStats)` once the backup is written
+    /// * `Err(VmError)` if the VM is unknown, `parent` isn't a backup of the VM next to
+    ///   `dest`, or a file couldn't be read or written
+    pub fn backup_vm_incremental(&self, name: &str, dest: &Path, parent: &Path) -> Result<BackupStats, VmError> {
+        self.write_backup(name, dest, Some(parent))
+    }
+
+    /// Restores the disks of the VM `name` from the backup at `backup`, replaying the
+    /// chain of backups it was taken after.
+    ///
+    /// Nothing is replaced unless every backup of the chain is intact and every
+    /// restored disk matches the digests of the last one.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the disks are restored
+    /// * `Err(VmError)` if the VM is unknown or lacks a disk of the backup, a backup of
+    ///   the chain is missing, malformed or corrupt, or a disk couldn't be written
+    pub fn restore_vm(&self, name: &str, backup: &Path) -> Result<(), VmError> {
+        let disks: HashMap<String, PathBuf> = self.get_backup_disks(name)?.into_iter().collect();
+        let directory = get_directory(backup);
+        let newest = read_backup(backup, None)?;
+        let mut manifest = newest.clone();
+        let mut chain = vec![backup.to_path_buf()];
+        loop {
+            if manifest.name != name || manifest.chunk_size != newest.chunk_size {
+                return Err(VmError::image(format!("{} isn't a backup of VM {}", chain[chain.len() - 1].display(), name)));
+            }
+            let Some((digest, file)) = &manifest.parent else { break };
+            let parent = directory.join(file);
+            if hash_file(&parent)?.1 != *digest {
+                return Err(VmError::image(format!("{} isn't the backup {} was taken after", parent.display(), chain[chain.len() - 1].display())));
+            }
+            manifest = read_backup(&parent, None)?;
+            chain.push(parent);
+        }
+
+        let mut restored = HashMap::new();
+        for disk in &newest.disks {
+            let Some(target) = disks.get(disk) else {
+                return Err(VmError::config(format!("VM {} has no disk {} to restore", name, disk)));
+            };
+            let output = match NamedTempFile::with_prefix_in(".restore", get_directory(target)) {
+                Ok(output) => output,
+                Err(e) => return Err(VmError::io(format!("failed to create a file next to {}: {}", target.display(), e), e))
+            };
+            restored.insert(disk.clone(), RestoredDisk { target: target.clone(), output, chunk_list: None, written: HashMap::new() });
+        }
+
+        // Oldest backup first, every one overwriting the chunks it marks dirty
+        for archive in chain.iter().rev() {
+            read_backup(archive, Some(&mut |manifest: &BackupManifest, entry: BackupEntry, reader: &mut dyn Read| {
+                match entry {
+                    BackupEntry::Chunk { disk, index } => {
+                        let Some(disk) = restored.get_mut(&disk) else { return Ok(()) };
+                        let mut chunk = Vec::with_capacity(manifest.chunk_size);
+                        if let Err(e) = reader.take(manifest.chunk_size as u64 + 1).read_to_end(&mut chunk) {
+                            return Err(VmError::io(format!("failed to read {}: {}", archive.display(), e), e));
+                        }
+                        if chunk.len() > manifest.chunk_size {
+                            return Err(VmError::image(format!("chunk {} in {} is too large", index, archive.display())));
+                        }
+                        write_at(&disk.output, index as u64 * manifest.chunk_size as u64, &chunk)?;
+                        disk.written.insert(index, get_digest(&chunk));
+                    },
+                    BackupEntry::ChunkList { disk: disk_name } => {
+                        let Some(disk) = restored.get_mut(&disk_name) else { return Ok(()) };
+                        let chunk_list = read_chunk_list(reader, archive)?;
+                        let bitmap = DirtyBitmap::between(disk.chunk_list.as_ref(), &chunk_list);
+                        for (index, chunk) in chunk_list.chunks.iter().enumerate().filter(|(index, _)| bitmap.is_dirty(*index)) {
+                            let intact = match chunk {
+                                Some(digest) => disk.written.remove(&index).as_ref() == Some(digest),
+                                // Chunks zeroed since the earlier backup are cleared; a new image is zero already
+                                None if disk.chunk_list.is_some() => {
+                                    let range = chunk_list.get_chunk_range(index);
+                                    write_at(&disk.output, range.start, &vec![0u8; (range.end - range.start) as usize])?;
+                                    !disk.written.contains_key(&index)
+                                },
+                                None => !disk.written.contains_key(&index),
+                            };
+                            if !intact {
+                                return Err(VmError::image(format!("chunk {} of disk {} in {} is corrupt", index, disk_name, archive.display())));
+                            }
+                        }
+                        if !disk.written.is_empty() || chunk_list.chunk_size != manifest.chunk_size {
+                            return Err(VmError::image(format!("disk {} in {} is corrupt", disk_name, archive.display())));
+                        }
+                        if let Err(e) = disk.output.as_file().set_len(chunk_list.image_size) {
+                            return Err(VmError::io(format!("failed to resize a restored disk: {}", e), e));
+                        }
+                        disk.chunk_list = Some(chunk_list);
+                    },
+                }
+                Ok(())
+            }))?;
+        }
+
+        for (disk_name, disk) in &restored {
+            let verified = match &disk.chunk_list {
+                Some(chunk_list) => ChunkManifest::from_image(disk.output.path(), newest.chunk_size)? == *chunk_list,
+                None => false,
+            };
+            if !verified {
+                return Err(VmError::image(format!("disk {} restored from {} doesn't match its digests", disk_name, backup.display())));
+            }
+        }
+        for disk in restored.into_values() {
+            if let Err(e) = disk.output.persist(&disk.target) {
+                return Err(VmError::io(format!("failed to replace {}: {}", disk.target.display(), e.error), e.error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a backup of the VM `name`, incremental if `parent` is given.
+    fn write_backup(&self, name: &str, dest: &Path, parent: Option<&Path>) -> Result<BackupStats, VmError> {
+        let disks = self.get_backup_disks(name)?;
+        let directory = get_directory(dest);
+        let mut parent_lists = HashMap::new();
+        let mut manifest = BackupManifest {
+            name: name.to_string(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            parent: None,
+            disks: disks.iter().map(|(disk, _)| disk.clone()).collect(),
+        };
+        if let Some(parent) = parent {
+            let file = parent.file_name().and_then(|file| file.to_str()).unwrap_or_default();
+            if !is_valid_name(file) || !is_same_file(&directory.join(file), parent) {
+                return Err(VmError::config(format!("{} must be in the directory of {}", parent.display(), dest.display())));
+            }
+            let parent_manifest = read_backup(parent, Some(&mut |_: &BackupManifest, entry: BackupEntry, reader: &mut dyn Read| {
+                if let BackupEntry::ChunkList { disk } = entry {
+                    parent_lists.insert(disk, read_chunk_list(reader, parent)?);
+                }
+                Ok(())
+            }))?;
+            if parent_manifest.name != name {
+                return Err(VmError::config(format!("{} is a backup of VM {}, not {}", parent.display(), parent_manifest.name, name)));
+            }
+            manifest.chunk_size = parent_manifest.chunk_size;
+            manifest.parent = Some((hash_file(parent)?.1, file.to_string()));
+        }
+
+        let output = match tempfile::Builder::new().prefix(".backup").tempfile_in(&directory) {
+            Ok(output) => output,
+            Err(e) => return Err(VmError::io(format!("failed to create backup in {}: {}", directory.display(), e), e))
+        };
+        let mut builder = tar::Builder::new(GzEncoder::new(output.as_file(), Compression::fast()));
+        append_entry(&mut builder, BACKUP_MANIFEST, manifest.render().as_bytes(), dest)?;
+        let mut stats = BackupStats { disks: disks.len(), ..BackupStats::default() };
+        for (disk, path) in &disks {
+            let parent_list = parent_lists.get(disk).filter(|list| list.chunk_size == manifest.chunk_size);
+            let chunk_list = for_each_chunk(path, manifest.chunk_size, |index, chunk, digest| {
+                let unchanged = parent_list.is_some_and(|list| list.chunks.get(index).and_then(Option::as_deref) == Some(digest));
+                if !unchanged {
+                    append_entry(&mut builder, &format!("chunks/{}/{}", disk, index), chunk, dest)?;
+                    stats.bytes_archived += chunk.len() as u64;
+                }
+                Ok(())
+            })?;
+            append_entry(&mut builder, &format!("manifests/{}", disk), chunk_list.render().as_bytes(), dest)?;
+            stats.chunks += chunk_list.chunks.len();
+            stats.dirty_chunks += DirtyBitmap::between(parent_list, &chunk_list).count();
+        }
+        let finished = builder.into_inner().and_then(|encoder| encoder.finish()).and_then(|mut file| file.flush());
+        if let Err(e) = finished {
+            return Err(VmError::io(format!("failed to write {}: {}", dest.display(), e), e));
+        }
+        if let Err(e) = output.persist(dest) {
+            return Err(VmError::io(format!("failed to create {}: {}", dest.display(), e.error), e.error));
+        }
+        (stats.backup_size, stats.digest) = hash_file(dest)?;
+        Ok(stats)
+    }
+
+    /// Returns the disks of the VM `name` by file name: its registered disks, then the
+    /// images in its `disks` directory, leaving out the snapshots taken of them.
+    ///
+    /// # Returns
+    /// * `Ok(disks)` on success
+    /// * `Err(VmError)` if the VM is unknown, two disks have the same file name or the
+    ///   directory couldn't be listed
+    pub(super) fn get_backup_disks(&self, name: &str) -> Result<Vec<(String, PathBuf)>, VmError> {
+        let vm = match self.vms.get(name) {
+            Some(vm) => vm,
+            None => return Err(VmError::config(format!("VM {} is not registered", name)))
+        };
+        let mut paths = vm.disks.clone();
+        if let Some(directory) = &vm.directory {
+            let disks_dir = directory.get_disks_dir();
+            let mut images = Vec::new();
+            match std::fs::read_dir(&disks_dir) {
+                Ok(entries) => {
+                    for entry in entries.flatten() {
+                        let is_snapshot = entry.path().extension().is_some_and(|extension| extension == DISK_SNAPSHOT_EXTENSION);
+                        if entry.file_type().is_ok_and(|file_type| file_type.is_file()) && !is_snapshot && !paths.contains(&entry.path()) {
+                            images.push(entry.path());
+                        }
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(VmError::io(format!("failed to list {}: {}", disks_dir.display(), e), e))
+            }
+            images.sort();
+            paths.extend(images);
+        }
+
+        let mut disks: Vec<(String, PathBuf)> = Vec::new();
+        for path in paths {
+            let disk = path.file_name().and_then(|file| file.to_str()).unwrap_or_default().to_string();
+            if !is_valid_name(&disk) || disks.iter().any(|(other, _)| *other == disk) {
+                return Err(VmError::config(format!("VM {} can't be backed up: disk {} has an invalid or duplicate file name", name, path.display())));
+            }
+            disks.push((disk, path));
+        }
+        Ok(disks)
+    }
+}
+
+/// Reads the backup at `backup`, passing every entry after the manifest to `visit`.
+///
+/// # Returns
+/// * `Ok(BackupManifest)` once all entries were visited, or right after the manifest
+///   without `visit`
+/// * `Err(VmError)` if the backup is malformed or couldn't be read, or `visit` failed
+fn read_backup(backup: &Path, visit: Option<&mut EntryVisitor>) -> Result<BackupManifest, VmError> {
+    let input = match File::open(backup) {
+        Ok(input) => input,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", backup.display(), e), e))
+    };
+    let mut archive = tar::Archive::new(GzDecoder::new(input));
+    let mut entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => return Err(VmError::io(format!("failed to read {}: {}", backup.display(), e), e))
+    };
+    let manifest = match entries.next() {
+        Some(Ok(entry)) if entry.path_bytes().as_ref() == BACKUP_MANIFEST.as_bytes() && entry.size() <= MAX_MANIFEST_SIZE => {
+            let mut text = String::new();
+            match entry.take(MAX_MANIFEST_SIZE).read_to_string(&mut text) {
+                Ok(_) => BackupManifest::parse(&text),
+                Err(_) => None,
+            }
+        },
+        _ => None,
+    };
+    let Some(manifest) = manifest else {
+        return Err(VmError::image(format!("{} is not a VM backup", backup.display())));
+    };
+    let Some(visit) = visit else {
+        return Ok(manifest);
+    };
+
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", backup.display(), e), e))
+        };
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        match BackupEntry::parse(&path) {
+            Some(parsed) => visit(&manifest, parsed, &mut entry)?,
+            None => return Err(VmError::image(format!("{} has an unexpected entry {:?}", backup.display(), path))),
+        }
+    }
+    Ok(manifest)
+}
+
+/// Reads the chunk list of a disk from an entry of `backup`.
+fn read_chunk_list(reader: &mut dyn Read, backup: &Path) -> Result<ChunkManifest, VmError> {
+    let mut text = String::new();
+    if let Err(e) = reader.take(MAX_MANIFEST_SIZE).read_to_string(&mut text) {
+        return Err(VmError::io(format!("failed to read {}: {}", backup.display(), e), e));
+    }
+    match ChunkManifest::parse(&text) {
+        Some(chunk_list) => Ok(chunk_list),
+        None => Err(VmError::image(format!("{} has a malformed chunk list", backup.display())))
+    }
+}
+
+/// Appends a file holding `data` to the backup at `dest` being written.
+fn append_entry(builder: &mut tar::Builder<GzEncoder<&File>>, path: &str, data: &[u8], dest: &Path) -> Result<(), VmError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    match builder.append_data(&mut header, path, data) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to write {}: {}", dest.display(), e), e))
+    }
+}
+
+/// Writes `data` at byte `offset` of a disk being restored.
+fn write_at(output: &NamedTempFile, offset: u64, data: &[u8]) -> Result<(), VmError> {
+    let mut file = output.as_file();
+    if let Err(e) = file.seek(SeekFrom::Start(offset)).and_then(|_| file.write_all(data)) {
+        return Err(VmError::io(format!("failed to write {}: {}", output.path().display(), e), e));
+    }
+    Ok(())
+}
+
+/// Returns the directory holding `path`.
+fn get_directory(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Whether two paths name the same existing file.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Helper: VM with a sparse 8 MiB disk and a small one, backed up into `dir`
+    fn make_vm(dir: &TempDir) -> (VmManager, PathBuf, PathBuf) {
+        let root = dir.path().join("root.img");
+        let file = File::create(&root).unwrap();
+        file.set_len(8 << 20).unwrap();
+        write_at_offset(&root, 3 << 20, b"superblock");
+        let data = dir.path().join("data.img");
+        std::fs::write(&data, vec![0x5a; 10_000]).unwrap();
+        let mut manager = VmManager::new();
+        manager.register_vm("dev", vec![root.clone(), data.clone()]).unwrap();
+        (manager, root, data)
+    }
+
+    // Helper: overwrite bytes of a file in place
+    fn write_at_offset(path: &Path, offset: u64, data: &[u8]) {
+        let mut file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn test_dirty_bitmap() {
+        let parent = ChunkManifest { chunk_size: 4, image_size: 12, chunks: vec![None, Some("a".repeat(64)), Some("b".repeat(64))] };
+        let current = ChunkManifest { chunk_size: 4, image_size: 16, chunks: vec![None, Some("c".repeat(64)), Some("b".repeat(64)), None] };
+        let bitmap = DirtyBitmap::between(Some(&parent), &current);
+        assert_eq!((0..4).map(|index| bitmap.is_dirty(index)).collect::<Vec<_>>(), [false, true, false, true]);
+        assert_eq!(bitmap.count(), 2);
+        assert_eq!(DirtyBitmap::between(None, &current).count(), 4);
+        let resized = ChunkManifest { chunk_size: 8, ..parent };
+        assert_eq!(DirtyBitmap::between(Some(&resized), &current).count(), 4);
+    }
+
+    #[test]
+    fn test_full_and_incremental_backups() {
+        let dir = TempDir::new().unwrap();
+        let (manager, root, data) = make_vm(&dir);
+        let full = dir.path().join("dev-1.tar.gz");
+        let stats = manager.backup_vm("dev", &full).unwrap();
+        assert_eq!((stats.disks, stats.chunks, stats.dirty_chunks), (2, 9, 9));
+        assert_eq!(stats.bytes_archived, (1 << 20) + 10_000);
+        let original_root = std::fs::read(&root).unwrap();
+
+        // Only the chunk written to is archived again
+        write_at_offset(&root, (5 << 20) + 7, b"journal");
+        let incremental = dir.path().join("dev-2.tar.gz");
+        let stats = manager.backup_vm_incremental("dev", &incremental, &full).unwrap();
+        assert_eq!((stats.chunks, stats.dirty_chunks, stats.bytes_archived), (9, 1, 1 << 20));
+        let backed_up_root = std::fs::read(&root).unwrap();
+
+        std::fs::write(&root, b"lost").unwrap();
+        std::fs::write(&data, b"lost").unwrap();
+        manager.restore_vm("dev", &incremental).unwrap();
+        assert_eq!(std::fs::read(&root).unwrap(), backed_up_root);
+        assert_eq!(std::fs::read(&data).unwrap(), vec![0x5a; 10_000]);
+        manager.restore_vm("dev", &full).unwrap();
+        assert_eq!(std::fs::read(&root).unwrap(), original_root);
+    }
+
+    #[test]
+    fn test_restore_rejects_broken_chains() {
+        let dir = TempDir::new().unwrap();
+        let (manager, root, _) = make_vm(&dir);
+        let full = dir.path().join("dev-1.tar.gz");
+        manager.backup_vm("dev", &full).unwrap();
+        write_at_offset(&root, 0, b"boot");
+        let incremental = dir.path().join("dev-2.tar.gz");
+        manager.backup_vm_incremental("dev", &incremental, &full).unwrap();
+        let current = std::fs::read(&root).unwrap();
+
+        // A parent taken again no longer matches the digest the incremental backup recorded
+        manager.backup_vm("dev", &full).unwrap();
+        assert!(manager.restore_vm("dev", &incremental).is_err());
+        std::fs::remove_file(&full).unwrap();
+        assert!(manager.restore_vm("dev", &incremental).is_err());
+        assert_eq!(std::fs::read(&root).unwrap(), current);
+
+        let other = TempDir::new().unwrap();
+        assert!(manager.backup_vm_incremental("dev", &other.path().join("dev-3.tar.gz"), &incremental).is_err());
+        assert!(manager.backup_vm("unknown", &dir.path().join("unknown.tar.gz")).is_err());
+        assert!(manager.restore_vm("dev", &dir.path().join("missing.tar.gz")).is_err());
+    }
+
+    #[test]
+    fn test_restore_rejects_corrupt_chunks() {
+        let dir = TempDir::new().unwrap();
+        let (manager, root, _) = make_vm(&dir);
+        let backup = dir.path().join("dev.tar.gz");
+        manager.backup_vm("dev", &backup).unwrap();
+
+        // Swap the content of a chunk while keeping the chunk list
+        let mut rewritten = tar::Builder::new(GzEncoder::new(File::create(dir.path().join("tampered.tar.gz")).unwrap(), Compression::fast()));
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(&backup).unwrap()));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            if path == "chunks/root.img/3" {
+                content[0] ^= 0xff;
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            rewritten.append_data(&mut header, &path, content.as_slice()).unwrap();
+        }
+        rewritten.into_inner().unwrap().finish().unwrap();
+
+        std::fs::write(&root, b"current").unwrap();
+        assert!(manager.restore_vm("dev", &dir.path().join("tampered.tar.gz")).is_err());
+        assert_eq!(std::fs::read(&root).unwrap(), b"current");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+}
@@ -0,0 +1,438 @@
+//! Export and import of VMs as single-file bundles.
+//!
+//! A bundle is a gzip-compressed tarball starting with a manifest that describes the
+//! VM, followed by its disk images and optionally a snapshot of its state. The
+//! manifest records the size and SHA-256 digest of every file, so an import verifies
+//! the whole bundle before the VM appears in the destination. The paths of the
+//! imported VM point into the destination, whatever they were on the exporting host.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use crate::error::VmError;
+use crate::utils::chunk_store::{copy_sparse, DigestReader};
+
+/// Name of the manifest, the first entry of every bundle.
+pub const BUNDLE_MANIFEST: &str = "asgard-bundle.txt";
+/// First line of a manifest, carrying the format version.
+const MANIFEST_HEADER: &str = "asgard-bundle 1";
+/// Largest manifest an import reads.
+const MAX_MANIFEST_SIZE: u64 = 64 << 10;
+
+/// What a VM is made of, as moved between hosts by bundles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VmDefinition {
+    /// Name of the VM; an import creates a directory of that name
+    pub name: String,
+    /// Memory size in megabytes
+    pub memory_mb: u32,
+    /// Number of CPU cores
+    pub cpus: u32,
+    /// Kernel command line, if the VM doesn't use the default one
+    pub kernel_cmdline: Option<String>,
+    /// Disk images, in the order they are attached
+    pub disks: Vec<PathBuf>,
+    /// Saved state of the VM to resume from, if any
+    pub snapshot: Option<PathBuf>,
+}
+
+/// Kind of a file carried by a bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BundleFileKind {
+    Disk,
+    Snapshot,
+}
+
+impl BundleFileKind {
+    /// Keyword of the kind in manifests, also the directory of its files in the tarball.
+    fn as_str(&self) -> &'static str {
+        match self {
+            BundleFileKind::Disk => "disk",
+            BundleFileKind::Snapshot => "snapshot",
+        }
+    }
+}
+
+/// File carried by a bundle, as listed in its manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BundleFile {
+    kind: BundleFileKind,
+    /// File name, unique within the bundle
+    name: String,
+    size: u64,
+    /// SHA-256 digest of the content, in hex
+    digest: String,
+}
+
+impl BundleFile {
+    /// Returns the path of the file inside the tarball.
+    fn get_archive_path(&self) -> String {
+        format!("{}/{}", self.kind.as_str(), self.name)
+    }
+}
+
+/// Manifest of a bundle: the VM definition without paths, and the files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BundleManifest {
+    name: String,
+    memory_mb: u32,
+    cpus: u32,
+    kernel_cmdline: Option<String>,
+    files: Vec<BundleFile>,
+}
+
+impl BundleManifest {
+    /// Parses a manifest as written by `render`.
+    ///
+    /// # Returns
+    /// * `Some(BundleManifest)` if the manifest is well formed and its names are safe
+    ///   to use as file names
+    /// * `None` otherwise
+    fn parse(text: &str) -> Option<BundleManifest> {
+        let mut lines = text.lines();
+        if lines.next()? != MANIFEST_HEADER {
+            return None;
+        }
+
+        let mut manifest = BundleManifest { name: String::new(), memory_mb: 0, cpus: 0, kernel_cmdline: None, files: Vec::new() };
+        for line in lines {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "name" => manifest.name = value.to_string(),
+                "memory" => manifest.memory_mb = value.parse().ok()?,
+                "cpus" => manifest.cpus = value.parse().ok()?,
+                "cmdline" => manifest.kernel_cmdline = Some(value.to_string()),
+                "disk" | "snapshot" => {
+                    let mut fields = value.splitn(3, ' ');
+                    let size = fields.next()?.parse::<u64>().ok()?;
+                    let digest = fields.next()?;
+                    let name = fields.next()?;
+                    if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte)) {
+                        return None;
+                    }
+                    let kind = if key == "disk" { BundleFileKind::Disk } else { BundleFileKind::Snapshot };
+                    manifest.files.push(BundleFile { kind, name: name.to_string(), size, digest: digest.to_string() });
+                },
+                _ => return None,
+            }
+        }
+
+        let mut names = HashSet::new();
+        let names_valid = is_valid_name(&manifest.name)
+            && manifest.files.iter().all(|file| is_valid_name(&file.name) && names.insert(file.name.as_str()));
+        let snapshots = manifest.files.iter().filter(|file| file.kind == BundleFileKind::Snapshot).count();
+        if !names_valid || manifest.memory_mb == 0 || manifest.cpus == 0 || snapshots > 1 {
+            return None;
+        }
+        Some(manifest)
+    }
+
+    /// Formats the manifest as `parse` reads it.
+    fn render(&self) -> String {
+        let mut text = format!("{}\nname {}\nmemory {}\ncpus {}\n", MANIFEST_HEADER, self.name, self.memory_mb, self.cpus);
+        if let Some(cmdline) = &self.kernel_cmdline {
+            text.push_str(&format!("cmdline {}\n", cmdline));
+        }
+        for file in &self.files {
+            text.push_str(&format!("{} {} {} {}\n", file.kind.as_str(), file.size, file.digest, file.name));
+        }
+        text
+    }
+}
+
+/// Exports a VM into a bundle at `bundle`.
+///
+/// The VM must not be running, or its disks could change while they are archived;
+/// such a bundle fails to import. Sparse disks stay sparse in the tarball.
+///
+/// # Arguments
+/// * `definition` - The VM; its disks and snapshot must have distinct file names
+/// * `bundle` - Path of the bundle, replaced once it is complete
+///
+/// # Returns
+/// * `Ok(())` once the bundle is written
+/// * `Err(VmError)` if the definition can't be bundled or a file couldn't be read or written
+pub fn export_bundle(definition: &VmDefinition, bundle: &Path) -> Result<(), VmError> {
+    let mut sources = Vec::new();
+    let mut files = Vec::new();
+    let kinds = std::iter::repeat(BundleFileKind::Disk).zip(&definition.disks)
+        .chain(definition.snapshot.iter().map(|snapshot| (BundleFileKind::Snapshot, snapshot)));
+    for (kind, path) in kinds {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => return Err(VmError::config(format!("{} has no usable file name", path.display())))
+        };
+        let (size, digest) = hash_file(path)?;
+        files.push(BundleFile { kind, name, size, digest });
+        sources.push(path);
+    }
+    let manifest = BundleManifest {
+        name: definition.name.clone(),
+        memory_mb: definition.memory_mb,
+        cpus: definition.cpus,
+        kernel_cmdline: definition.kernel_cmdline.clone(),
+        files,
+    };
+    let text = manifest.render();
+    if BundleManifest::parse(&text).as_ref() != Some(&manifest) {
+        return Err(VmError::config(format!("VM {:?} can't be bundled: invalid name, size or duplicate file names", definition.name)));
+    }
+
+    let directory = match bundle.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let output = match tempfile::Builder::new().prefix(".export").tempfile_in(directory) {
+        Ok(output) => output,
+        Err(e) => return Err(VmError::io(format!("failed to create bundle in {}: {}", directory.display(), e), e))
+    };
+    let mut builder = tar::Builder::new(GzEncoder::new(output.as_file(), Compression::fast()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(text.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    if let Err(e) = builder.append_data(&mut header, BUNDLE_MANIFEST, text.as_bytes()) {
+        return Err(VmError::io(format!("failed to write {}: {}", bundle.display(), e), e));
+    }
+    for (file, source) in manifest.files.iter().zip(sources) {
+        if let Err(e) = builder.append_path_with_name(source, file.get_archive_path()) {
+            return Err(VmError::io(format!("failed to archive {}: {}", source.display(), e), e));
+        }
+    }
+    let finished = builder.into_inner().and_then(|encoder| encoder.finish()).and_then(|mut file| file.flush());
+    if let Err(e) = finished {
+        return Err(VmError::io(format!("failed to write {}: {}", bundle.display(), e), e));
+    }
+    if let Err(e) = output.persist(bundle) {
+        return Err(VmError::io(format!("failed to create {}: {}", bundle.display(), e.error), e.error));
+    }
+    Ok(())
+}
+
+/// Imports the bundle at `bundle` into a new directory named after the VM in
+/// `destination`.
+///
+/// Files are unpacked into a staging directory and verified against the manifest;
+/// only a complete, intact VM is moved into place. Runs of zeroes are left sparse.
+///
+/// # Returns
+/// * `Ok(VmDefinition)` with the disk and snapshot paths in the new directory
+/// * `Err(VmError)` if the bundle is malformed, incomplete or corrupt, a VM with that
+///   name already exists in `destination`, or the files couldn't be written
+pub fn import_bundle(bundle: &Path, destination: &Path) -> Result<VmDefinition, VmError> {
+    let input = match File::open(bundle) {
+        Ok(input) => input,
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", bundle.display(), e), e))
+    };
+    let mut archive = tar::Archive::new(GzDecoder::new(input));
+    let mut entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => return Err(VmError::io(format!("failed to read {}: {}", bundle.display(), e), e))
+    };
+
+    let manifest = match entries.next() {
+        Some(Ok(entry)) if entry.path_bytes().as_ref() == BUNDLE_MANIFEST.as_bytes() && entry.size() <= MAX_MANIFEST_SIZE => {
+            let mut text = String::new();
+            match entry.take(MAX_MANIFEST_SIZE).read_to_string(&mut text) {
+                Ok(_) => BundleManifest::parse(&text),
+                Err(_) => None,
+            }
+        },
+        _ => None,
+    };
+    let Some(manifest) = manifest else {
+        return Err(VmError::image(format!("{} is not a VM bundle", bundle.display())));
+    };
+
+    let vm_directory = destination.join(&manifest.name);
+    if vm_directory.exists() {
+        return Err(VmError::config(format!("VM {} already exists in {}", manifest.name, destination.display())));
+    }
+    let staging = match tempfile::Builder::new().prefix(".import").tempdir_in(destination) {
+        Ok(staging) => staging,
+        Err(e) => return Err(VmError::io(format!("failed to create staging directory in {}: {}", destination.display(), e), e))
+    };
+
+    let mut unpacked = vec![false; manifest.files.len()];
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", bundle.display(), e), e))
+        };
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let index = manifest.files.iter().position(|file| file.get_archive_path() == path);
+        let Some(index) = index.filter(|&index| !unpacked[index]) else {
+            return Err(VmError::image(format!("{} has an unexpected entry {:?}", bundle.display(), path)));
+        };
+        if !matches!(entry.header().entry_type(), tar::EntryType::Regular | tar::EntryType::GNUSparse) {
+            return Err(VmError::image(format!("{} in {} is not a regular file", path, bundle.display())));
+        }
+
+        let file = &manifest.files[index];
+        let (size, digest) = unpack_file(&mut entry, &staging.path().join(&file.name))?;
+        if size != file.size || digest != file.digest {
+            return Err(VmError::image(format!("{} in {} is corrupt", path, bundle.display())));
+        }
+        unpacked[index] = true;
+    }
+    if let Some(missing) = manifest.files.iter().zip(&unpacked).find(|(_, unpacked)| !**unpacked) {
+        return Err(VmError::image(format!("{} lacks {}", bundle.display(), missing.0.get_archive_path())));
+    }
+
+    if let Err(e) = std::fs::rename(staging.path(), &vm_directory) {
+        return Err(VmError::io(format!("failed to create {}: {}", vm_directory.display(), e), e));
+    }
+    let get_paths = |kind: BundleFileKind| {
+        manifest.files.iter().filter(move |file| file.kind == kind).map(|file| vm_directory.join(&file.name))
+    };
+    Ok(VmDefinition {
+        name: manifest.name.clone(),
+        memory_mb: manifest.memory_mb,
+        cpus: manifest.cpus,
+        kernel_cmdline: manifest.kernel_cmdline.clone(),
+        disks: get_paths(BundleFileKind::Disk).collect(),
+        snapshot: get_paths(BundleFileKind::Snapshot).next(),
+    })
+}
+
+/// Returns the size and digest of the file at `path`.
+pub(crate) fn hash_file(path: &Path) -> Result<(u64, String), VmError> {
+    let mut reader = match File::open(path) {
+        Ok(file) => DigestReader::new(file),
+        Err(e) => return Err(VmError::io(format!("failed to open {}: {}", path.display(), e), e))
+    };
+    if let Err(e) = std::io::copy(&mut reader, &mut std::io::sink()) {
+        return Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e));
+    }
+    Ok(reader.finish())
+}
+
+/// Writes `reader` to a new file at `path`, leaving runs of zeroes sparse.
+///
+/// # Returns
+/// * `Ok((size, digest))` of the content written
+/// * `Err(VmError)` if reading or writing failed
+fn unpack_file(reader: &mut impl Read, path: &Path) -> Result<(u64, String), VmError> {
+    let mut file = match File::create_new(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to create {}: {}", path.display(), e), e))
+    };
+    let mut reader = DigestReader::new(reader);
+    if let Err(e) = copy_sparse(&mut reader, &mut file) {
+        return Err(VmError::io(format!("failed to unpack {}: {}", path.display(), e), e));
+    }
+    Ok(reader.finish())
+}
+
+/// Whether `name` can be used as a file name in any directory.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']) && !name.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+    use crate::utils::chunk_store::get_digest;
+
+    // Helper: VM with a sparse disk, a small disk and a snapshot inside `dir`
+    fn make_vm(dir: &Path) -> VmDefinition {
+        let root = dir.join("root.img");
+        let file = File::create(&root).unwrap();
+        file.set_len(16 << 20).unwrap();
+        (&file).seek(SeekFrom::Start(4 << 20)).unwrap();
+        (&file).write_all(b"superblock").unwrap();
+        let data = dir.join("data.img");
+        std::fs::write(&data, vec![0x5a; 10_000]).unwrap();
+        let snapshot = dir.join("state.bin");
+        std::fs::write(&snapshot, b"registers").unwrap();
+
+        VmDefinition {
+            name: "dev".to_string(),
+            memory_mb: 2048,
+            cpus: 2,
+            kernel_cmdline: Some("console=ttyS0 root=/dev/vda".to_string()),
+            disks: vec![root, data],
+            snapshot: Some(snapshot),
+        }
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let definition = make_vm(source.path());
+        let bundle = source.path().join("dev.tar.gz");
+        export_bundle(&definition, &bundle).expect("Export should succeed");
+        // The sparse disk compresses away
+        assert!(std::fs::metadata(&bundle).unwrap().len() < 1 << 20);
+
+        let imported = import_bundle(&bundle, destination.path()).expect("Import should succeed");
+        let vm_directory = destination.path().join("dev");
+        assert_eq!(imported, VmDefinition {
+            disks: vec![vm_directory.join("root.img"), vm_directory.join("data.img")],
+            snapshot: Some(vm_directory.join("state.bin")),
+            ..definition.clone()
+        });
+        for (original, copy) in definition.disks.iter().zip(&imported.disks) {
+            assert_eq!(std::fs::read(original).unwrap(), std::fs::read(copy).unwrap());
+        }
+        assert_eq!(std::fs::read(imported.snapshot.unwrap()).unwrap(), b"registers");
+
+        // Importing again would overwrite the first copy
+        assert!(import_bundle(&bundle, destination.path()).is_err());
+        assert_eq!(std::fs::read_dir(destination.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_export_rejects_clashing_file_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut definition = make_vm(dir.path());
+        definition.disks.push(definition.disks[1].clone());
+        assert!(export_bundle(&definition, &dir.path().join("dev.tar.gz")).is_err());
+        assert!(!dir.path().join("dev.tar.gz").exists());
+    }
+
+    #[test]
+    fn test_import_rejects_tampered_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let digest = get_digest(b"disk");
+        let write_bundle = |name: &str, manifest: &str, entries: &[(&str, &[u8])]| {
+            let path = dir.path().join(name);
+            let mut builder = tar::Builder::new(GzEncoder::new(File::create(&path).unwrap(), Compression::fast()));
+            for (name, data) in [(BUNDLE_MANIFEST, manifest.as_bytes())].iter().chain(entries) {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, name, *data).unwrap();
+            }
+            builder.into_inner().unwrap().finish().unwrap();
+            path
+        };
+        let manifest = format!("{}\nname vm\nmemory 512\ncpus 1\ndisk 4 {} root.img\n", MANIFEST_HEADER, digest);
+
+        let intact = write_bundle("intact.tar.gz", &manifest, &[("disk/root.img", b"disk")]);
+        assert_eq!(import_bundle(&intact, destination.path()).unwrap().disks, vec![destination.path().join("vm/root.img")]);
+        std::fs::remove_dir_all(destination.path().join("vm")).unwrap();
+
+        let bundles = [
+            write_bundle("corrupt.tar.gz", &manifest, &[("disk/root.img", b"dusk")]),
+            write_bundle("incomplete.tar.gz", &manifest, &[]),
+            write_bundle("unlisted.tar.gz", &manifest, &[("disk/root.img", b"disk"), ("disk/extra.img", b"x")]),
+            write_bundle("duplicate.tar.gz", &manifest, &[("disk/root.img", b"disk"), ("disk/root.img", b"disk")]),
+            write_bundle("escaping.tar.gz", &manifest.replace("name vm", "name ../vm"), &[("disk/root.img", b"disk")]),
+            write_bundle("future.tar.gz", &manifest.replace(MANIFEST_HEADER, "asgard-bundle 2"), &[("disk/root.img", b"disk")]),
+        ];
+        for bundle in &bundles {
+            assert!(import_bundle(bundle, destination.path()).is_err());
+        }
+        // Nothing is left behind, not even staging directories
+        assert_eq!(std::fs::read_dir(destination.path()).unwrap().count(), 0);
+    }
+}
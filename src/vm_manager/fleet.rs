@@ -0,0 +1,297 @@
+//! Launching fleets of VMs from shared base images.
+//!
+//! `VmManager::launch_many` boots a batch of VMs, with a bound on how many of them are
+//! starting at the same time, and reports how each launch went. VMs never write to
+//! their base image: each one boots from an overlay, a copy-on-write clone of the base
+//! image made by `clone_disk_image`. The overlay is a cache entry of the VM, so
+//! removing the VM reclaims it.
+//!
+//! If the manager has a root directory, every VM of the fleet gets its own directory
+//! holding its overlay and, unless the definition names another file, its console log.
+//! A definition may carry a snapshot schedule, see `schedule`, which the VM gets once
+//! it launched.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use super::bundle::is_valid_name;
+use super::VmManager;
+use super::schedule::SnapshotSchedule;
+use crate::error::VmError;
+use crate::kernel_setup::setup_utils::KernelComponents;
+use crate::vm_setup::boot_progress::BootOutcome;
+use crate::vm_setup::disk_setup::clone_disk_image;
+use crate::vm_setup::setup_utils::{SerialConsole, VmSetupBuilder};
+use crate::vm_setup::vm_handle::VmHandle;
+
+#[cfg(target_os = "linux")]
+use crate::vm_setup::linux_setup::spawn_vm;
+#[cfg(target_os = "macos")]
+use crate::vm_setup::macos_setup::spawn_vm;
+#[cfg(target_os = "windows")]
+use crate::vm_setup::windows_setup::spawn_vm;
+
+/// Extension of the overlay files created next to the base images.
+pub const OVERLAY_EXTENSION: &str = "overlay";
+
+/// A VM to launch with `VmManager::launch_many`.
+#[derive(Debug, Clone)]
+pub struct LaunchDefinition {
+    /// Name the VM is registered under
+    name: String,
+    /// Image the VM's overlay is cloned from; it is never modified
+    base_image: PathBuf,
+    /// Memory size in megabytes
+    memory_mb: u32,
+    /// Number of CPU cores
+    cpus: u32,
+    /// Kernel booted by the VM and its command line
+    kernel: Option<(KernelComponents, String)>,
+    /// File receiving the serial console output of the VM
+    serial_log: Option<PathBuf>,
+    /// Time the VM may take to boot before its launch fails
+    boot_timeout: Option<Duration>,
+    /// Schedule of the periodic snapshots of the VM's disks
+    snapshot_schedule: Option<SnapshotSchedule>,
+}
+
+impl LaunchDefinition {
+    /// Describes a 1 GiB, 1 vCPU VM named `name` booting from an overlay of `base_image`.
+    pub fn new(name: &str, base_image: impl Into<PathBuf>) -> LaunchDefinition {
+        LaunchDefinition {
+            name: name.to_string(),
+            base_image: base_image.into(),
+            memory_mb: 1024,
+            cpus: 1,
+            kernel: None,
+            serial_log: None,
+            boot_timeout: None,
+            snapshot_schedule: None,
+        }
+    }
+    /// Set the memory size in megabytes and the CPU cores count of the VM.
+    pub fn resources(mut self, mega_bytes: u32, cpu_cores_count: u32) -> LaunchDefinition {
+        self.memory_mb = mega_bytes;
+        self.cpus = cpu_cores_count;
+        self
+    }
+    /// Boot the VM with the given kernel and command line.
+    pub fn kernel(mut self, kernel: KernelComponents, cmdline: &str) -> LaunchDefinition {
+        self.kernel = Some((kernel, cmdline.to_string()));
+        self
+    }
+    /// Append the serial console output of the VM to the given file.
+    pub fn serial_log(mut self, path: impl Into<PathBuf>) -> LaunchDefinition {
+        self.serial_log = Some(path.into());
+        self
+    }
+    /// Wait for the guest to boot before the launch counts as done; the launch fails
+    /// if the guest doesn't get there within `timeout`. Boot milestones are read from
+    /// the serial console, so this needs a `serial_log`.
+    pub fn boot_timeout(mut self, timeout: Duration) -> LaunchDefinition {
+        self.boot_timeout = Some(timeout);
+        self
+    }
+    /// Snapshot the disks of the VM on the given schedule once it launched. Only VMs
+    /// with a directory of their own have their overlay snapshotted, as it is one of
+    /// their disks; for the others it's a cache entry.
+    pub fn snapshot_schedule(mut self, schedule: SnapshotSchedule) -> LaunchDefinition {
+        self.snapshot_schedule = Some(schedule);
+        self
+    }
+
+    /// Returns the name of the VM.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the path of the overlay the VM boots from when it has no directory of
+    /// its own: next to its base image.
+    pub fn get_overlay_path(&self) -> PathBuf {
+        self.base_image.with_file_name(self.get_overlay_name())
+    }
+
+    /// Returns the file name of the overlay the VM boots from.
+    fn get_overlay_name(&self) -> String {
+        format!("{}.{}", self.name, OVERLAY_EXTENSION)
+    }
+}
+
+/// Outcome of launching one VM of a fleet.
+pub struct LaunchResult {
+    /// Name of the VM
+    pub name: String,
+    /// Handle of the running VM, or why it couldn't be launched
+    pub outcome: Result<VmHandle, VmError>,
+    /// Time from the moment the VM got a launch slot until it was launched or failed
+    pub elapsed: Duration,
+}
+
+impl VmManager {
+    /// Launches VMs in parallel, at most `concurrency` at a time (at least one).
+    ///
+    /// Every VM is registered before any of them starts, so names are checked up
+    /// front. With a root directory set, VMs are created with `create_vm` and their
+    /// overlay goes into their `disks` directory. Each VM then creates its overlay, is
+    /// spawned and, if the definition has a boot timeout, holds its launch slot until
+    /// the guest booted. VMs whose launch failed are stopped and removed again,
+    /// together with their overlay; the others stay registered with their overlay as a
+    /// cache entry. Must be called from within a Tokio runtime.
+    ///
+    /// # Arguments
+    /// * `definitions` - VMs to launch; several may share a base image
+    /// * `concurrency` - Largest number of VMs launching at the same time
+    ///
+    /// # Returns
+    /// * One `LaunchResult` per definition, in the same order
+    pub async fn launch_many(&mut self, definitions: Vec<LaunchDefinition>, concurrency: usize) -> Vec<LaunchResult> {
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut launches = Vec::with_capacity(definitions.len());
+        for mut definition in definitions {
+            let name = definition.name.clone();
+            let schedule = definition.snapshot_schedule;
+            let registered = if !is_valid_name(&name) {
+                Err(VmError::config(format!("invalid VM name {:?}", name)))
+            } else if self.root_dir.is_some() {
+                match self.create_vm(&name) {
+                    Ok(directory) => {
+                        if definition.serial_log.is_none() {
+                            definition.serial_log = Some(directory.get_console_log());
+                        }
+                        Ok(directory.get_disk_path(&definition.get_overlay_name()))
+                    },
+                    Err(e) => Err(e),
+                }
+            } else {
+                self.register_vm(&name, Vec::new()).map(|()| definition.get_overlay_path())
+            };
+            let task = match registered {
+                Ok(overlay) => Ok(tokio::spawn(launch_vm(definition, overlay, Arc::clone(&semaphore)))),
+                Err(e) => Err(e),
+            };
+            launches.push((name, schedule, task));
+        }
+
+        let mut results = Vec::with_capacity(launches.len());
+        for (name, schedule, task) in launches {
+            let (overlay, outcome, elapsed) = match task {
+                Ok(task) => match task.await {
+                    Ok(launch) => launch,
+                    Err(e) => (None, Err(VmError::hypervisor_source(format!("launch task join error: {}", e), e)), Duration::ZERO)
+                },
+                Err(e) => {
+                    results.push(LaunchResult { name, outcome: Err(e), elapsed: Duration::ZERO });
+                    continue;
+                }
+            };
+            if let Some(overlay) = overlay {
+                let _ = self.add_cache_entry(&name, overlay);
+            }
+            if outcome.is_err() {
+                let _ = self.remove_vm(&name);
+            } else if let Some(schedule) = schedule {
+                let _ = self.set_snapshot_schedule(&name, Some(schedule));
+            }
+            results.push(LaunchResult { name, outcome, elapsed });
+        }
+        results
+    }
+}
+
+/// Launches one VM from `overlay` once a slot is free.
+///
+/// # Returns
+/// * The overlay if it was created, how the launch went and how long it took
+async fn launch_vm(definition: LaunchDefinition, overlay: PathBuf, semaphore: Arc<Semaphore>) -> (Option<PathBuf>, Result<VmHandle, VmError>, Duration) {
+    let _permit = match semaphore.acquire_owned().await {
+        Ok(permit) => permit,
+        Err(_) => return (None, Err(VmError::config("launch slots are gone")), Duration::ZERO)
+    };
+    let started = Instant::now();
+
+    let base_image = definition.base_image.clone();
+    let target = overlay.clone();
+    let created = match tokio::task::spawn_blocking(move || match (base_image.to_str(), target.to_str()) {
+        (Some(base_image), Some(target)) => clone_disk_image(base_image, target),
+        _ => Err(VmError::config("failed to convert path to string slice"))
+    }).await {
+        Ok(result) => result,
+        Err(e) => Err(VmError::io(format!("overlay task join error: {}", e), std::io::Error::other(e)))
+    };
+    if let Err(e) = created {
+        return (None, Err(e), started.elapsed());
+    }
+
+    let mut setup = VmSetupBuilder::new(definition.memory_mb, definition.cpus).disk_image(&overlay);
+    if let Some((kernel, cmdline)) = definition.kernel {
+        setup = setup.kernel(kernel, &cmdline);
+    }
+    if let Some(serial_log) = definition.serial_log {
+        setup = setup.serial_console(SerialConsole::File(serial_log));
+    }
+    let setup = match setup.build() {
+        Ok(setup) => setup,
+        Err(e) => return (Some(overlay), Err(e), started.elapsed())
+    };
+    let handle = spawn_vm(setup);
+
+    if let Some(timeout) = definition.boot_timeout {
+        let outcome = handle.wait_for_boot(timeout).await;
+        if outcome != BootOutcome::Booted {
+            let _ = handle.stop().await;
+            let _ = handle.wait().await;
+            let error = VmError::hypervisor(format!("VM {} didn't boot: {:?}", definition.name, outcome));
+            return (Some(overlay), Err(error), started.elapsed());
+        }
+    }
+    (Some(overlay), Ok(handle), started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_launch_many_reports_failures_per_vm() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        manager.register_vm("taken", Vec::new()).unwrap();
+        let missing = dir.path().join("missing.img");
+        let definitions = vec![
+            LaunchDefinition::new("taken", &missing),
+            LaunchDefinition::new("../escape", &missing),
+            LaunchDefinition::new("worker", &missing),
+        ];
+
+        let results = manager.launch_many(definitions, 0).await;
+        let names: Vec<&str> = results.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(names, vec!["taken", "../escape", "worker"]);
+        assert!(results.iter().all(|result| result.outcome.is_err()));
+
+        // Failed VMs are unregistered, an existing VM of the same name is kept
+        assert_eq!(manager.list_vms(), vec!["taken".to_string()]);
+        assert!(!dir.path().join("worker.overlay").exists());
+    }
+
+    #[tokio::test]
+    async fn test_launch_many_in_vm_directories() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        manager.set_root_dir(dir.path().join("vms"));
+        let definitions = vec![LaunchDefinition::new("worker", dir.path().join("missing.img"))];
+
+        let results = manager.launch_many(definitions, 1).await;
+        assert!(results[0].outcome.is_err());
+        assert!(manager.list_vms().is_empty());
+        assert!(!dir.path().join("vms/worker").exists(), "The directory of a failed VM is removed");
+    }
+
+    #[test]
+    fn test_overlay_path() {
+        let definition = LaunchDefinition::new("ci-1", "/images/base.img").resources(2048, 2);
+        assert_eq!(definition.get_overlay_path(), PathBuf::from("/images/ci-1.overlay"));
+        assert_eq!(definition.get_name(), "ci-1");
+    }
+}
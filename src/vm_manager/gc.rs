@@ -0,0 +1,400 @@
+//! Cleanup of resources leaked by runs that crashed or were killed.
+//!
+//! Temporary files are normally removed when the run that created them ends, but a
+//! crash leaves them behind: `.part` downloads and hidden temporary files next to
+//! images, control sockets nobody listens on anymore, TAP devices no process holds
+//! open and temporary directories of the former `guestmount`-based kernel
+//! extraction, possibly still mounted. `VmManager::collect_garbage` finds them and,
+//! unless asked for a dry run, removes them.
+
+use std::fs::{read_dir, read_to_string, remove_dir, remove_dir_all, remove_file};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+use super::VmManager;
+use super::vm_directory::{VmDirectory, DISKS_DIR};
+use crate::error::VmError;
+
+/// Age below which leftover files are assumed to belong to a run still in progress.
+pub const DEFAULT_GC_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+/// Suffix of partial downloads.
+pub const PARTIAL_DOWNLOAD_SUFFIX: &str = ".part";
+/// Prefixes of the hidden temporary files and directories created next to images.
+const TEMP_PREFIXES: [&str; 6] = [".tmp", ".delta", ".pull", ".export", ".import", ".build"];
+/// Mount point inside the temporary directories of `guestmount`.
+const GUEST_MOUNT_DIR: &str = "mount";
+/// Directory listing the network interfaces of the host.
+#[cfg(target_os = "linux")]
+const NET_CLASS_DIR: &str = "/sys/class/net";
+
+/// Kind of a leftover resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeftoverKind {
+    /// TAP device no process holds open
+    TapDevice,
+    /// Control socket nobody listens on
+    ControlSocket,
+    /// Partial download or other temporary file
+    PartialDownload,
+    /// Temporary `guestmount` directory
+    GuestMount,
+}
+
+/// A leftover resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leftover {
+    pub kind: LeftoverKind,
+    /// Path of the file or directory; `/sys/class/net/<name>` for TAP devices
+    pub path: PathBuf,
+    /// Bytes the leftover takes
+    pub size: u64,
+}
+
+/// What `VmManager::collect_garbage` looks at and does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcPolicy {
+    /// Only report the leftovers, without removing anything
+    pub dry_run: bool,
+    /// Files and temporary directories younger than this are left alone
+    pub min_age: Duration,
+    /// Directories searched for partial downloads besides the manager's root
+    /// directory and the VM directories, e.g. the root of the image store
+    pub directories: Vec<PathBuf>,
+    /// Directory searched for `guestmount` directories; the system's temporary
+    /// directory if `None`
+    pub temp_dir: Option<PathBuf>,
+    /// Name prefix of the TAP devices created for VMs; TAP devices are left alone
+    /// if `None`
+    pub tap_prefix: Option<String>,
+}
+
+impl Default for GcPolicy {
+    fn default() -> GcPolicy {
+        GcPolicy { dry_run: false, min_age: DEFAULT_GC_MIN_AGE, directories: Vec::new(), temp_dir: None, tap_prefix: None }
+    }
+}
+
+/// Outcome of `VmManager::collect_garbage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Leftovers that were found
+    pub leftovers: Vec<Leftover>,
+    /// Leftovers that were removed; empty for a dry run
+    pub removed: Vec<Leftover>,
+    /// Disk space freed by the removals
+    pub freed_bytes: u64,
+}
+
+impl VmManager {
+    /// Finds and removes resources leaked by earlier runs, see the `gc` module.
+    ///
+    /// Meant to run at startup. The VM directories under the root directory are
+    /// searched whether their VMs are registered or not. Every leftover is attempted
+    /// even if an earlier removal fails.
+    ///
+    /// # Returns
+    /// * `Ok(GcReport)` with the leftovers found and removed
+    /// * `Err(VmError)` if some leftovers couldn't be removed
+    pub fn collect_garbage(&self, policy: &GcPolicy) -> Result<GcReport, VmError> {
+        let now = SystemTime::now();
+        let mut leftovers = Vec::new();
+
+        let mut directories = policy.directories.clone();
+        if let Some(root_dir) = &self.root_dir {
+            directories.push(root_dir.clone());
+            for vm_dir in list_subdirectories(root_dir) {
+                let directory = VmDirectory::new(vm_dir);
+                if is_stale_socket(&directory.get_control_socket()) {
+                    leftovers.push(Leftover { kind: LeftoverKind::ControlSocket, path: directory.get_control_socket(), size: 0 });
+                }
+                directories.push(directory.get_path().to_path_buf());
+                directories.push(directory.get_path().join(DISKS_DIR));
+            }
+        }
+        for directory in &directories {
+            leftovers.extend(find_partial_downloads(directory, now, policy.min_age));
+        }
+        let temp_dir = policy.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        leftovers.extend(find_guest_mounts(&temp_dir, now, policy.min_age));
+        #[cfg(target_os = "linux")]
+        if let Some(tap_prefix) = &policy.tap_prefix {
+            leftovers.extend(find_orphaned_tap_devices(Path::new(NET_CLASS_DIR), tap_prefix));
+        }
+
+        let mut report = GcReport { leftovers, ..Default::default() };
+        if policy.dry_run {
+            return Ok(report);
+        }
+        let mut failures: Vec<String> = Vec::new();
+        for leftover in report.leftovers.clone() {
+            match remove_leftover(&leftover) {
+                Ok(()) => {
+                    report.freed_bytes += leftover.size;
+                    report.removed.push(leftover);
+                },
+                Err(e) => failures.push(format!("{}: {}", leftover.path.display(), e))
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(report)
+        } else {
+            Err(VmError::image(format!("failed to remove leftovers: {}", failures.join(", "))))
+        }
+    }
+}
+
+/// Returns the subdirectories of `directory`, none if it can't be read.
+fn list_subdirectories(directory: &Path) -> Vec<PathBuf> {
+    match read_dir(directory) {
+        Ok(entries) => entries.flatten().filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir())).map(|entry| entry.path()).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/// Whether `path` was last modified at least `min_age` before `now`.
+fn is_old_enough(path: &Path, now: SystemTime, min_age: Duration) -> bool {
+    match path.symlink_metadata().and_then(|metadata| metadata.modified()) {
+        // A file modified "in the future" (clock changes) counts as fresh
+        Ok(modified) => now.duration_since(modified).is_ok_and(|age| age >= min_age),
+        Err(_) => false
+    }
+}
+
+/// Returns the bytes taken by the files under `path`.
+fn get_tree_size(path: &Path) -> u64 {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return 0
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| get_tree_size(&entry.path())).sum(),
+        Err(_) => 0
+    }
+}
+
+/// Finds the partial downloads and temporary files directly inside `directory`.
+fn find_partial_downloads(directory: &Path, now: SystemTime, min_age: Duration) -> Vec<Leftover> {
+    let entries = match read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut leftovers = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_file = entry.file_type().is_ok_and(|file_type| file_type.is_file());
+        let temporary = TEMP_PREFIXES.iter().any(|prefix| name.starts_with(prefix));
+        if !(temporary || (is_file && name.ends_with(PARTIAL_DOWNLOAD_SUFFIX))) {
+            continue;
+        }
+        let path = entry.path();
+        // A live guest mount must never be deleted through
+        if is_old_enough(&path, now, min_age) && !is_mounted(&path.join(GUEST_MOUNT_DIR)) {
+            leftovers.push(Leftover { kind: LeftoverKind::PartialDownload, size: get_tree_size(&path), path });
+        }
+    }
+    leftovers
+}
+
+/// Finds the temporary directories of `guestmount` directly inside `temp_dir`: hidden
+/// `.tmp` directories holding only a `mount` directory.
+fn find_guest_mounts(temp_dir: &Path, now: SystemTime, min_age: Duration) -> Vec<Leftover> {
+    let mut leftovers = Vec::new();
+    for path in list_subdirectories(temp_dir) {
+        if !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(".tmp")) {
+            continue;
+        }
+        let names: Vec<String> = match read_dir(&path) {
+            Ok(entries) => entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect(),
+            Err(_) => continue
+        };
+        if names == [GUEST_MOUNT_DIR] && path.join(GUEST_MOUNT_DIR).is_dir() && is_old_enough(&path, now, min_age) {
+            leftovers.push(Leftover { kind: LeftoverKind::GuestMount, path, size: 0 });
+        }
+    }
+    leftovers
+}
+
+/// Whether nobody listens on the Unix socket at `path`.
+fn is_stale_socket(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixStream;
+
+        if !path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            return false;
+        }
+        matches!(UnixStream::connect(path), Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Finds the TAP devices named `<prefix>...` in `net_dir` that no process holds open.
+#[cfg(target_os = "linux")]
+fn find_orphaned_tap_devices(net_dir: &Path, prefix: &str) -> Vec<Leftover> {
+    let attached = get_attached_tap_devices();
+    let mut leftovers = Vec::new();
+    for path in list_subdirectories(net_dir) {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue
+        };
+        // Only TUN/TAP devices have `tun_flags`
+        if name.starts_with(prefix) && path.join("tun_flags").exists() && !attached.contains(&name) {
+            leftovers.push(Leftover { kind: LeftoverKind::TapDevice, path, size: 0 });
+        }
+    }
+    leftovers
+}
+
+/// Returns the names of the TUN/TAP devices held open by the processes we can see,
+/// from the `iff:` line of the descriptor information in `/proc`.
+#[cfg(target_os = "linux")]
+fn get_attached_tap_devices() -> std::collections::HashSet<String> {
+    let mut attached = std::collections::HashSet::new();
+    for process in list_subdirectories(Path::new("/proc")) {
+        let entries = match read_dir(process.join("fdinfo")) {
+            Ok(entries) => entries,
+            Err(_) => continue
+        };
+        for entry in entries.flatten() {
+            if let Ok(info) = read_to_string(entry.path()) {
+                attached.extend(info.lines().filter_map(|line| line.strip_prefix("iff:")).map(|name| name.trim().to_string()));
+            }
+        }
+    }
+    attached
+}
+
+/// Whether `path` is a mount point according to `/proc/self/mounts`.
+fn is_mounted(path: &Path) -> bool {
+    match read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts.lines().any(|line| line.split(' ').nth(1).is_some_and(|mount_point| Path::new(mount_point) == path)),
+        Err(_) => false
+    }
+}
+
+/// Runs a cleanup command, returning whether it succeeded.
+fn run_command(program: &str, args: &[&str]) -> bool {
+    Command::new(program).args(args).output().is_ok_and(|output| output.status.success())
+}
+
+/// Removes one leftover.
+fn remove_leftover(leftover: &Leftover) -> Result<(), VmError> {
+    let path = &leftover.path;
+    let result = match leftover.kind {
+        LeftoverKind::TapDevice => {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            if run_command("ip", &["link", "delete", &name]) {
+                return Ok(());
+            }
+            return Err(VmError::config(format!("failed to delete TAP device {}", name)));
+        },
+        LeftoverKind::ControlSocket => remove_file(path),
+        LeftoverKind::PartialDownload if path.is_dir() => remove_dir_all(path),
+        LeftoverKind::PartialDownload => remove_file(path),
+        LeftoverKind::GuestMount => {
+            // Unmount first: removing files below a live mount would delete guest files
+            let mount = path.join(GUEST_MOUNT_DIR);
+            let mount_str = mount.to_string_lossy().into_owned();
+            if is_mounted(&mount) && !run_command("guestunmount", &[&mount_str]) && !run_command("fusermount", &["-u", &mount_str]) {
+                return Err(VmError::image(format!("failed to unmount {}", mount.display())));
+            }
+            remove_dir(&mount).and_then(|_| remove_dir(path))
+        },
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to remove {}: {}", path.display(), e), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write, File, FileTimes};
+    use tempfile::TempDir;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    // Helper: makes `path` look last modified `age` ago
+    fn set_age(path: &Path, age: Duration) {
+        let time = SystemTime::now() - age;
+        File::open(path).unwrap().set_times(FileTimes::new().set_accessed(time).set_modified(time)).unwrap();
+    }
+
+    // Helper: lists the kinds and file names of leftovers
+    fn describe(leftovers: &[Leftover]) -> Vec<(LeftoverKind, String)> {
+        let mut described: Vec<(LeftoverKind, String)> = leftovers.iter()
+            .map(|leftover| (leftover.kind, leftover.path.file_name().unwrap().to_string_lossy().into_owned()))
+            .collect();
+        described.sort_by(|a, b| a.1.cmp(&b.1));
+        described
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_garbage() {
+        let dir = TempDir::new().unwrap();
+        let images = dir.path().join("images");
+        let temp = dir.path().join("tmp");
+        create_dir_all(&images).unwrap();
+        create_dir_all(temp.join(".tmpAbC/mount")).unwrap();
+        create_dir_all(temp.join(".tmpBusy/mount")).unwrap();
+        write(temp.join(".tmpBusy/notes"), b"not ours").unwrap();
+        set_age(&temp.join(".tmpAbC"), 2 * HOUR);
+        set_age(&temp.join(".tmpBusy"), 2 * HOUR);
+
+        write(images.join("debian.img"), b"image").unwrap();
+        write(images.join("ubuntu.img.part"), vec![1u8; 100]).unwrap();
+        write(images.join(".delta1234"), vec![1u8; 10]).unwrap();
+        write(images.join("fresh.img.part"), b"in progress").unwrap();
+        set_age(&images.join("ubuntu.img.part"), 2 * HOUR);
+        set_age(&images.join(".delta1234"), 2 * HOUR);
+
+        let mut manager = VmManager::new();
+        manager.set_root_dir(dir.path().join("vms"));
+        let stale = manager.create_vm("stale").unwrap();
+        let live = manager.create_vm("live").unwrap();
+        drop(std::os::unix::net::UnixListener::bind(stale.get_control_socket()).unwrap());
+        let _listener = std::os::unix::net::UnixListener::bind(live.get_control_socket()).unwrap();
+        write(live.get_disk_path("root.img.part"), b"partial").unwrap();
+        set_age(&live.get_disk_path("root.img.part"), 2 * HOUR);
+
+        let mut policy = GcPolicy { dry_run: true, directories: vec![images.clone()], temp_dir: Some(temp.clone()), ..Default::default() };
+        let expected = vec![
+            (LeftoverKind::PartialDownload, ".delta1234".to_string()),
+            (LeftoverKind::GuestMount, ".tmpAbC".to_string()),
+            (LeftoverKind::ControlSocket, "control.sock".to_string()),
+            (LeftoverKind::PartialDownload, "root.img.part".to_string()),
+            (LeftoverKind::PartialDownload, "ubuntu.img.part".to_string()),
+        ];
+        let report = manager.collect_garbage(&policy).expect("Dry run should succeed");
+        assert_eq!(describe(&report.leftovers), expected);
+        assert!(report.removed.is_empty());
+        assert!(images.join("ubuntu.img.part").exists(), "A dry run removes nothing");
+
+        policy.dry_run = false;
+        let report = manager.collect_garbage(&policy).expect("Cleanup should succeed");
+        assert_eq!(describe(&report.removed), expected);
+        assert_eq!(report.freed_bytes, 100 + 10 + 7);
+        assert!(!images.join("ubuntu.img.part").exists());
+        assert!(!temp.join(".tmpAbC").exists());
+        assert!(!stale.get_control_socket().exists());
+        assert!(live.get_control_socket().exists());
+        assert!(images.join("debian.img").exists());
+        assert!(images.join("fresh.img.part").exists());
+        assert!(temp.join(".tmpBusy/notes").exists());
+
+        assert!(manager.collect_garbage(&policy).unwrap().leftovers.is_empty());
+    }
+}
@@ -0,0 +1,433 @@
+//! Bookkeeping of managed virtual machines and the host files that belong to them.
+//!
+//! `VmManager` keeps a registry of named VMs together with their disk images and
+//! any cache entries created on their behalf, so that removing a VM also reclaims
+//! the disk space it used. VMs created with `create_vm` get a directory of their own
+//! under the manager's root directory, see `vm_directory`, and files and devices
+//! leaked by crashed runs are cleaned up with `collect_garbage`, see `gc`. VMs move
+//! between hosts as bundles, see `bundle`, their disks are backed up and restored with
+//! `backup_vm` and `restore_vm`, see `backup`, and fleets of VMs sharing base images
+//! are launched with `launch_many`, see `fleet`. Disks of VMs with a snapshot schedule
+//! are snapshotted periodically, see `schedule`.
+
+pub mod backup;
+pub mod bundle;
+pub mod fleet;
+pub mod gc;
+pub mod schedule;
+pub mod vm_directory;
+
+use std::collections::HashMap;
+use std::fs::{read_dir, remove_dir, remove_dir_all, remove_file};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::vm_setup::disk_setup::{trim_disk_image, secure_erase_disk_image};
+use crate::error::VmError;
+use self::bundle::is_valid_name;
+use self::schedule::{list_scheduled_snapshots, SnapshotSchedule};
+use self::vm_directory::VmDirectory;
+
+/// How the disk images of a VM are disposed of when the VM is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiskDisposal {
+    /// Leave the disk image files untouched.
+    Keep,
+    /// Unlink the disk image files.
+    Delete,
+    /// Deallocate all blocks of the images (hole punch) before unlinking them,
+    /// so space is reclaimed even if the files are still open elsewhere.
+    #[default]
+    Trim,
+    /// Overwrite the images with zeroes and sync them before unlinking them.
+    SecureErase,
+}
+
+/// Host-side files owned by a managed VM.
+#[derive(Debug, Clone, Default)]
+pub struct ManagedVm {
+    /// Disk image files attached to the VM
+    disks: Vec<PathBuf>,
+    /// Cache files created for the VM (overlays, extracted kernels, ...)
+    cache_entries: Vec<PathBuf>,
+    /// Directory owned by the VM, for VMs created with `create_vm`
+    directory: Option<VmDirectory>,
+    /// Schedule of the periodic snapshots of the VM's disks
+    snapshot_schedule: Option<SnapshotSchedule>,
+    /// Time the last scheduled snapshot was taken
+    last_snapshot: Option<SystemTime>,
+}
+
+impl ManagedVm {
+    /// Returns the disk image paths of the VM.
+    pub fn get_disks(&self) -> &[PathBuf] {
+        &self.disks
+    }
+
+    /// Returns the cache entries belonging to the VM.
+    pub fn get_cache_entries(&self) -> &[PathBuf] {
+        &self.cache_entries
+    }
+
+    /// Returns the directory owned by the VM, if it has one.
+    pub fn get_directory(&self) -> Option<&VmDirectory> {
+        self.directory.as_ref()
+    }
+
+    /// Returns the snapshot schedule of the VM, if it has one.
+    pub fn get_snapshot_schedule(&self) -> Option<&SnapshotSchedule> {
+        self.snapshot_schedule.as_ref()
+    }
+
+    /// Returns the time the last scheduled snapshot of the VM was taken.
+    pub fn get_last_snapshot(&self) -> Option<SystemTime> {
+        self.last_snapshot
+    }
+}
+
+/// Registry of managed VMs.
+#[derive(Debug, Default)]
+pub struct VmManager {
+    /// Managed VMs keyed by name
+    vms: HashMap<String, ManagedVm>,
+    /// Disposal policy applied to disks of removed VMs
+    disk_disposal: DiskDisposal,
+    /// Directory under which VMs created with `create_vm` get their own directory
+    root_dir: Option<PathBuf>,
+}
+
+impl VmManager {
+    /// Creates an empty manager using the default `DiskDisposal::Trim` policy.
+    pub fn new() -> VmManager {
+        VmManager::default()
+    }
+
+    /// Sets how disk images are disposed of when a VM is removed.
+    pub fn set_disk_disposal(&mut self, disk_disposal: DiskDisposal) {
+        self.disk_disposal = disk_disposal;
+    }
+
+    /// Returns the configured disk disposal policy.
+    pub fn get_disk_disposal(&self) -> DiskDisposal {
+        self.disk_disposal
+    }
+
+    /// Sets the directory under which `create_vm` creates the VM directories.
+    pub fn set_root_dir(&mut self, root_dir: impl Into<PathBuf>) {
+        self.root_dir = Some(root_dir.into());
+    }
+
+    /// Returns the root directory of the VM directories, if one is set.
+    pub fn get_root_dir(&self) -> Option<&Path> {
+        self.root_dir.as_deref()
+    }
+
+    /// Registers a VM under `name` and creates its directory `<root>/<name>`.
+    ///
+    /// Disk images put into the `disks` directory belong to the VM without being
+    /// registered one by one.
+    ///
+    /// # Returns
+    /// * `Ok(VmDirectory)` with the layout of the new directory
+    /// * `Err(VmError)` if no root directory is set, `name` can't be a directory name,
+    ///   a VM with the same name is already registered or the directory couldn't be created
+    pub fn create_vm(&mut self, name: &str) -> Result<VmDirectory, VmError> {
+        let root_dir = match &self.root_dir {
+            Some(root_dir) => root_dir,
+            None => return Err(VmError::config("no root directory set for VM directories"))
+        };
+        if !is_valid_name(name) {
+            return Err(VmError::config(format!("invalid VM name {:?}", name)));
+        }
+        if self.vms.contains_key(name) {
+            return Err(VmError::config(format!("VM {} is already registered", name)));
+        }
+
+        let directory = VmDirectory::new(root_dir.join(name));
+        directory.create()?;
+        self.vms.insert(name.to_string(), ManagedVm { directory: Some(directory.clone()), ..ManagedVm::default() });
+        Ok(directory)
+    }
+
+    /// Registers a VM under `name` with the given disk images.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if a VM with the same name is already registered
+    pub fn register_vm(&mut self, name: &str, disks: Vec<PathBuf>) -> Result<(), VmError> {
+        if self.vms.contains_key(name) {
+            return Err(VmError::config(format!("VM {} is already registered", name)));
+        }
+        self.vms.insert(name.to_string(), ManagedVm { disks, ..ManagedVm::default() });
+        Ok(())
+    }
+
+    /// Records a cache file that belongs to the VM `name` and must be removed with it.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if no VM with that name is registered
+    pub fn add_cache_entry(&mut self, name: &str, path: PathBuf) -> Result<(), VmError> {
+        match self.vms.get_mut(name) {
+            Some(vm) => {
+                vm.cache_entries.push(path);
+                Ok(())
+            },
+            None => Err(VmError::config(format!("VM {} is not registered", name)))
+        }
+    }
+
+    /// Returns the VM registered under `name`, if any.
+    pub fn get_vm(&self, name: &str) -> Option<&ManagedVm> {
+        self.vms.get(name)
+    }
+
+    /// Returns the names of all registered VMs.
+    pub fn list_vms(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.vms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Removes the VM `name`, disposing of its disks and their scheduled snapshots
+    /// according to the configured policy and deleting its cache entries.
+    ///
+    /// The directory of the VM goes too, unless it still holds kept disks or files
+    /// the manager doesn't know about.
+    ///
+    /// Every file is attempted even if an earlier one fails; the VM is unregistered
+    /// in any case and all failures are reported together.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the VM and all its files were removed
+    /// * `Err(VmError)` if the VM is unknown or some files couldn't be reclaimed
+    pub fn remove_vm(&mut self, name: &str) -> Result<(), VmError> {
+        let vm = match self.vms.remove(name) {
+            Some(vm) => vm,
+            None => return Err(VmError::config(format!("VM {} is not registered", name)))
+        };
+
+        let mut failures: Vec<String> = Vec::new();
+        for disk in &vm.disks {
+            if let Err(e) = dispose_disk(disk, self.disk_disposal) {
+                failures.push(format!("{}: {}", disk.display(), e));
+            }
+            for snapshot in list_scheduled_snapshots(disk).unwrap_or_default() {
+                if let Err(e) = dispose_disk(&snapshot, self.disk_disposal) {
+                    failures.push(format!("{}: {}", snapshot.display(), e));
+                }
+            }
+        }
+        for entry in &vm.cache_entries {
+            if let Err(e) = remove_if_exists(entry) {
+                failures.push(format!("{}: {}", entry.display(), e));
+            }
+        }
+        if let Some(directory) = &vm.directory && let Err(e) = remove_vm_directory(directory, self.disk_disposal) {
+            failures.push(format!("{}: {}", directory.get_path().display(), e));
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(VmError::image(format!("failed to reclaim files of VM {}: {}", name, failures.join(", "))))
+        }
+    }
+}
+
+/// Applies the disposal policy to a single disk image.
+///
+/// A disk that fails to be trimmed, e.g. on a file system without hole punching, is
+/// still deleted and the trim error reported; an erase failure keeps the disk.
+fn dispose_disk(path: &Path, disk_disposal: DiskDisposal) -> Result<(), VmError> {
+    if disk_disposal == DiskDisposal::Keep || !path.exists() {
+        return Ok(());
+    }
+
+    let path_str = match path.to_str() {
+        Some(p) => p,
+        None => return Err(VmError::config("failed to convert path to string slice"))
+    };
+
+    match disk_disposal {
+        DiskDisposal::Trim => {
+            // Unlinking the disk reclaims its space as well once nothing holds it open
+            let trimmed = trim_disk_image(path_str);
+            remove_if_exists(path)?;
+            return trimmed;
+        },
+        DiskDisposal::SecureErase => secure_erase_disk_image(path_str)?,
+        DiskDisposal::Delete | DiskDisposal::Keep => {}
+    }
+
+    remove_if_exists(path)
+}
+
+/// Disposes of the disks in a VM directory, deletes the other files of the layout
+/// and removes the directories that are left empty. The rest of the directory is
+/// removed even if a disk couldn't be disposed of, whose error is then returned.
+fn remove_vm_directory(directory: &VmDirectory, disk_disposal: DiskDisposal) -> Result<(), VmError> {
+    let disks_dir = directory.get_disks_dir();
+    let mut disposal_error = None;
+    match read_dir(&disks_dir) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|file_type| file_type.is_file())
+                    && let Err(e) = dispose_disk(&entry.path(), disk_disposal) {
+                    disposal_error.get_or_insert(e);
+                }
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(VmError::io(format!("failed to list {}: {}", disks_dir.display(), e), e))
+    }
+    remove_if_exists(&directory.get_console_log())?;
+    remove_if_exists(&directory.get_control_socket())?;
+    let snapshots_dir = directory.get_snapshots_dir();
+    match remove_dir_all(&snapshots_dir) {
+        Ok(()) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(VmError::io(format!("failed to remove {}: {}", snapshots_dir.display(), e), e))
+    }
+
+    // Kept disks and unknown files keep their directories
+    for path in [disks_dir.as_path(), directory.get_path()] {
+        match remove_dir(path) {
+            Ok(()) => {},
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty) => {},
+            Err(e) => return Err(VmError::io(format!("failed to remove {}: {}", path.display(), e), e))
+        }
+    }
+    match disposal_error {
+        Some(e) => Err(e),
+        None => Ok(())
+    }
+}
+
+/// Deletes a file, treating an already missing file as success.
+fn remove_if_exists(path: &Path) -> Result<(), VmError> {
+    match remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to remove {}: {}", path.display(), e), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    // Helper: create a VM with one disk and one cache entry inside `dir`
+    fn register_test_vm(manager: &mut VmManager, dir: &TempDir) -> (PathBuf, PathBuf) {
+        let disk = dir.path().join("disk.img");
+        let cache = dir.path().join("disk.overlay");
+        write(&disk, vec![0xAB; 4096]).unwrap();
+        write(&cache, b"cache").unwrap();
+        manager.register_vm("test-vm", vec![disk.clone()]).unwrap();
+        manager.add_cache_entry("test-vm", cache.clone()).unwrap();
+        (disk, cache)
+    }
+
+    #[test]
+    fn test_register_duplicate_vm_fails() {
+        let mut manager = VmManager::new();
+        assert!(manager.register_vm("vm", vec![]).is_ok());
+        assert!(manager.register_vm("vm", vec![]).is_err());
+        assert_eq!(manager.list_vms(), vec!["vm".to_string()]);
+    }
+
+    #[test]
+    fn test_remove_vm_trims_disks_and_cache() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        let (disk, cache) = register_test_vm(&mut manager, &dir);
+
+        let result = manager.remove_vm("test-vm");
+        assert!(result.is_ok(), "Expected Ok, got {:?}", result);
+        assert!(!disk.exists());
+        assert!(!cache.exists());
+        assert!(manager.get_vm("test-vm").is_none());
+    }
+
+    #[test]
+    fn test_remove_vm_secure_erase() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        manager.set_disk_disposal(DiskDisposal::SecureErase);
+        let (disk, _) = register_test_vm(&mut manager, &dir);
+
+        assert!(manager.remove_vm("test-vm").is_ok());
+        assert!(!disk.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_vm_deletes_disks_that_fail_to_trim() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        // Opening a directory for writing fails, like hole punching on a file system without it
+        let disk = dir.path().join("disk.img");
+        std::os::unix::fs::symlink(dir.path(), &disk).unwrap();
+        manager.register_vm("test-vm", vec![disk.clone()]).unwrap();
+
+        let result = manager.remove_vm("test-vm");
+        assert!(result.err().expect("The trim failure should be reported").to_string().contains("disk.img"));
+        assert!(disk.symlink_metadata().is_err(), "Disk should be deleted");
+        assert!(manager.get_vm("test-vm").is_none());
+    }
+
+    #[test]
+    fn test_remove_vm_keep_disks() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        manager.set_disk_disposal(DiskDisposal::Keep);
+        let (disk, cache) = register_test_vm(&mut manager, &dir);
+
+        assert!(manager.remove_vm("test-vm").is_ok());
+        assert!(disk.exists(), "Disk should be kept");
+        assert!(!cache.exists(), "Cache entries are always removed");
+    }
+
+    #[test]
+    fn test_create_vm_directory() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        assert!(manager.create_vm("vm").is_err(), "A root directory is required");
+        manager.set_root_dir(dir.path());
+
+        let directory = manager.create_vm("vm").expect("VM should be created");
+        assert_eq!(directory.get_path(), dir.path().join("vm"));
+        assert_eq!(manager.get_vm("vm").unwrap().get_directory(), Some(&directory));
+        assert!(manager.create_vm("vm").is_err());
+        assert!(manager.create_vm("../vm").is_err());
+
+        write(directory.get_disk_path("root.img"), vec![0xAB; 4096]).unwrap();
+        write(directory.get_console_log(), b"login:").unwrap();
+        write(directory.get_snapshots_dir().join("state"), b"state").unwrap();
+        assert!(manager.remove_vm("vm").is_ok());
+        assert!(!directory.get_path().exists());
+    }
+
+    #[test]
+    fn test_remove_vm_keeps_directory_with_kept_disks() {
+        let dir = TempDir::new().unwrap();
+        let mut manager = VmManager::new();
+        manager.set_root_dir(dir.path());
+        manager.set_disk_disposal(DiskDisposal::Keep);
+        let directory = manager.create_vm("vm").unwrap();
+        write(directory.get_disk_path("root.img"), b"disk").unwrap();
+        write(directory.get_console_log(), b"login:").unwrap();
+
+        assert!(manager.remove_vm("vm").is_ok());
+        assert!(directory.get_disk_path("root.img").exists(), "Disk should be kept");
+        assert!(!directory.get_console_log().exists());
+        assert!(!directory.get_snapshots_dir().exists());
+    }
+
+    #[test]
+    fn test_remove_unknown_vm_fails() {
+        let mut manager = VmManager::new();
+        assert!(manager.remove_vm("missing").is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! Periodic snapshots of the disks of managed VMs.
+//!
+//! A VM given a `SnapshotSchedule`, with `VmManager::set_snapshot_schedule` or in its
+//! `LaunchDefinition`, gets a snapshot of each of its disks every interval, taken with
+//! `snapshot_disk_image` and named after the Unix time it was taken at. Only the newest
+//! snapshots up to the retention count of the schedule are kept; older ones are pruned
+//! as new ones are taken, while snapshots taken by hand are left alone.
+//!
+//! `VmManager::run_snapshot_schedules` takes the snapshots that are due, and
+//! `spawn_snapshot_scheduler` runs it periodically on a Tokio runtime. Snapshots are
+//! clones of the disk images: on filesystems cloning files atomically (Btrfs, XFS)
+//! the snapshots of a running VM's disks are crash-consistent, elsewhere the VM should
+//! be paused while they are taken.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use super::{dispose_disk, DiskDisposal, VmManager};
+use crate::error::VmError;
+use crate::vm_setup::disk_setup::{get_disk_snapshot_path, list_disk_snapshots, remove_disk_snapshot, snapshot_disk_image};
+
+/// Name prefix of scheduled snapshots, followed by the Unix time they were taken at.
+pub const SCHEDULED_SNAPSHOT_PREFIX: &str = "scheduled-";
+
+/// How often the disks of a VM are snapshotted and how many snapshots are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotSchedule {
+    /// Time between two snapshots
+    interval: Duration,
+    /// Number of scheduled snapshots kept per disk
+    retention: usize,
+}
+
+impl SnapshotSchedule {
+    /// Creates a schedule taking a snapshot every `interval` and keeping the newest
+    /// `retention` ones.
+    ///
+    /// # Returns
+    /// * `Ok(SnapshotSchedule)` on success
+    /// * `Err(VmError)` if the interval is shorter than a second, the resolution of
+    ///   snapshot names, or no snapshot would be kept
+    pub fn new(interval: Duration, retention: usize) -> Result<SnapshotSchedule, VmError> {
+        if interval < Duration::from_secs(1) {
+            return Err(VmError::config(format!("snapshot interval {:?} is shorter than a second", interval)));
+        }
+        if retention == 0 {
+            return Err(VmError::config("a snapshot schedule must keep at least one snapshot"));
+        }
+        Ok(SnapshotSchedule { interval, retention })
+    }
+
+    /// Returns the time between two snapshots.
+    pub fn get_interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns the number of scheduled snapshots kept per disk.
+    pub fn get_retention(&self) -> usize {
+        self.retention
+    }
+
+    /// Whether a snapshot is due at `now`, the last one having been taken at `last`.
+    pub fn is_due(&self, last: Option<SystemTime>, now: SystemTime) -> bool {
+        match last {
+            Some(last) => now.duration_since(last).is_ok_and(|elapsed| elapsed >= self.interval),
+            None => true,
+        }
+    }
+}
+
+/// Outcome of the scheduled snapshot of one VM.
+#[derive(Debug)]
+pub struct ScheduledSnapshot {
+    /// Name of the VM
+    pub name: String,
+    /// Name of the snapshot taken of each of its disks
+    pub snapshot: String,
+    /// Older snapshots that were pruned
+    pub pruned: Vec<PathBuf>,
+    /// Whether the snapshot was taken and the old ones pruned, or what went wrong
+    pub outcome: Result<(), VmError>,
+}
+
+impl VmManager {
+    /// Sets or, with `None`, clears the snapshot schedule of the VM `name`.
+    ///
+    /// The first snapshot is due on the next run of the schedules. Snapshots taken
+    /// under an earlier schedule are kept, and pruned along with the new ones.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if no VM with that name is registered
+    pub fn set_snapshot_schedule(&mut self, name: &str, schedule: Option<SnapshotSchedule>) -> Result<(), VmError> {
+        match self.vms.get_mut(name) {
+            Some(vm) => {
+                vm.snapshot_schedule = schedule;
+                vm.last_snapshot = None;
+                Ok(())
+            },
+            None => Err(VmError::config(format!("VM {} is not registered", name)))
+        }
+    }
+
+    /// Takes the scheduled snapshots due at `now` and prunes the scheduled snapshots
+    /// beyond the retention count of each schedule.
+    ///
+    /// The snapshot covers all disks of a VM, as `backup_vm` does: if one of them
+    /// can't be snapshotted, the snapshots already taken of the others are removed
+    /// again. A failed snapshot is retried after the interval of its schedule, so a
+    /// broken disk isn't cloned over and over. Pruned snapshots are disposed of like
+    /// the disks of removed VMs, but always deleted even with `DiskDisposal::Keep`.
+    ///
+    /// # Arguments
+    /// * `now` - Current time, which names the snapshots
+    ///
+    /// # Returns
+    /// * One `ScheduledSnapshot` per VM that was due, sorted by VM name
+    pub fn run_snapshot_schedules(&mut self, now: SystemTime) -> Vec<ScheduledSnapshot> {
+        let mut due: Vec<(String, SnapshotSchedule)> = self.vms.iter()
+            .filter_map(|(name, vm)| {
+                let schedule = vm.snapshot_schedule?;
+                schedule.is_due(vm.last_snapshot, now).then(|| (name.clone(), schedule))
+            })
+            .collect();
+        due.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let snapshot = get_scheduled_snapshot_name(now);
+        let mut runs = Vec::with_capacity(due.len());
+        for (name, schedule) in due {
+            let mut pruned = Vec::new();
+            let outcome = self.take_scheduled_snapshot(&name, &snapshot, schedule.retention, &mut pruned);
+            if let Some(vm) = self.vms.get_mut(&name) {
+                vm.last_snapshot = Some(now);
+            }
+            runs.push(ScheduledSnapshot { name, snapshot: snapshot.clone(), pruned, outcome });
+        }
+        runs
+    }
+
+    /// Snapshots all disks of the VM `name` as `snapshot`, then prunes their scheduled
+    /// snapshots down to the newest `retention` ones, recording them in `pruned`.
+    fn take_scheduled_snapshot(&self, name: &str, snapshot: &str, retention: usize, pruned: &mut Vec<PathBuf>) -> Result<(), VmError> {
+        let disks = self.get_backup_disks(name)?;
+        let mut taken: Vec<&str> = Vec::new();
+        for (_, path) in &disks {
+            let result = match path.to_str() {
+                Some(path) => snapshot_disk_image(path, snapshot).map(|()| path),
+                None => Err(VmError::config("failed to convert path to string slice"))
+            };
+            match result {
+                Ok(path) => taken.push(path),
+                Err(e) => {
+                    for path in taken {
+                        let _ = remove_disk_snapshot(path, snapshot);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let disposal = match self.disk_disposal {
+            DiskDisposal::Keep => DiskDisposal::Delete,
+            disposal => disposal,
+        };
+        let mut failures: Vec<String> = Vec::new();
+        for (_, path) in &disks {
+            let snapshots = match list_scheduled_snapshots(path) {
+                Ok(snapshots) => snapshots,
+                Err(e) => {
+                    failures.push(format!("{}: {}", path.display(), e));
+                    continue;
+                }
+            };
+            for old in &snapshots[..snapshots.len().saturating_sub(retention)] {
+                match dispose_disk(old, disposal) {
+                    Ok(()) => pruned.push(old.clone()),
+                    Err(e) => failures.push(format!("{}: {}", old.display(), e)),
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(VmError::image(format!("failed to prune snapshots of VM {}: {}", name, failures.join(", "))))
+        }
+    }
+}
+
+/// Returns the name of the scheduled snapshots taken at `now`.
+pub fn get_scheduled_snapshot_name(now: SystemTime) -> String {
+    let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{}{}", SCHEDULED_SNAPSHOT_PREFIX, seconds)
+}
+
+/// Returns the paths of the scheduled snapshots of the disk image at `disk`, oldest first.
+///
+/// # Returns
+/// * `Ok(paths)` on success
+/// * `Err(VmError)` if the directory of the image couldn't be listed
+pub fn list_scheduled_snapshots(disk: &Path) -> Result<Vec<PathBuf>, VmError> {
+    let path = match disk.to_str() {
+        Some(path) => path,
+        None => return Err(VmError::config("failed to convert path to string slice"))
+    };
+    let mut snapshots: Vec<(u64, String)> = list_disk_snapshots(path)?
+        .into_iter()
+        .filter_map(|name| Some((name.strip_prefix(SCHEDULED_SNAPSHOT_PREFIX)?.parse().ok()?, name)))
+        .collect();
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, name)| PathBuf::from(get_disk_snapshot_path(path, &name))).collect())
+}
+
+/// Runs the snapshot schedules of the VMs of `manager` every `period` (at least a
+/// second) until the returned task is aborted.
+///
+/// The snapshots are taken on a blocking thread, holding the lock of the manager
+/// meanwhile. Failed snapshots are reported on stderr. Must be called from within a
+/// Tokio runtime.
+pub fn spawn_snapshot_scheduler(manager: Arc<Mutex<VmManager>>, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(period.max(Duration::from_secs(1)));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let manager = Arc::clone(&manager);
+            let runs = match tokio::task::spawn_blocking(move || {
+                manager.lock().unwrap_or_else(|e| e.into_inner()).run_snapshot_schedules(SystemTime::now())
+            }).await {
+                Ok(runs) => runs,
+                Err(e) => {
+                    eprintln!("snapshot schedules failed to run: {}", e);
+                    continue;
+                }
+            };
+            for run in runs {
+                if let Err(e) = run.outcome {
+                    eprintln!("scheduled snapshot {} of VM {} failed: {}", run.snapshot, run.name, e);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_snapshot_schedule() {
+        assert!(SnapshotSchedule::new(Duration::from_millis(500), 3).is_err());
+        assert!(SnapshotSchedule::new(Duration::from_secs(60), 0).is_err());
+
+        let schedule = SnapshotSchedule::new(Duration::from_secs(60), 3).unwrap();
+        let last = UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(schedule.is_due(None, last));
+        assert!(!schedule.is_due(Some(last), last + Duration::from_secs(59)));
+        assert!(schedule.is_due(Some(last), last + Duration::from_secs(60)));
+        assert!(!schedule.is_due(Some(last), last - Duration::from_secs(60)));
+        assert_eq!(get_scheduled_snapshot_name(last), "scheduled-1000");
+    }
+
+    #[test]
+    fn test_run_snapshot_schedules_prunes_old_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let disk = dir.path().join("disk.img");
+        write(&disk, b"state 0").unwrap();
+        let mut manager = VmManager::new();
+        manager.register_vm("scheduled", vec![disk.clone()]).unwrap();
+        manager.register_vm("unscheduled", Vec::new()).unwrap();
+        assert!(manager.set_snapshot_schedule("missing", None).is_err());
+        let schedule = SnapshotSchedule::new(Duration::from_secs(60), 2).unwrap();
+        manager.set_snapshot_schedule("scheduled", Some(schedule)).unwrap();
+        let disk_path = disk.to_str().unwrap();
+        snapshot_disk_image(disk_path, "manual").unwrap();
+
+        let start = UNIX_EPOCH + Duration::from_secs(10_000);
+        for step in 0..4u64 {
+            let now = start + Duration::from_secs(60 * step);
+            write(&disk, format!("state {}", step)).unwrap();
+            let runs = manager.run_snapshot_schedules(now);
+            assert_eq!(runs.len(), 1);
+            assert_eq!(runs[0].name, "scheduled");
+            assert!(runs[0].outcome.is_ok(), "Expected Ok, got {:?}", runs[0].outcome);
+            assert_eq!(runs[0].pruned.len(), usize::from(step >= 2));
+            assert!(manager.run_snapshot_schedules(now + Duration::from_secs(59)).is_empty());
+        }
+        assert_eq!(manager.get_vm("scheduled").unwrap().get_last_snapshot(), Some(start + Duration::from_secs(180)));
+
+        let snapshots = list_scheduled_snapshots(&disk).unwrap();
+        assert_eq!(snapshots, vec![
+            PathBuf::from(get_disk_snapshot_path(disk_path, "scheduled-10120")),
+            PathBuf::from(get_disk_snapshot_path(disk_path, "scheduled-10180")),
+        ]);
+        assert_eq!(std::fs::read(&snapshots[1]).unwrap(), b"state 3");
+        assert!(list_disk_snapshots(disk_path).unwrap().contains(&"manual".to_string()));
+
+        manager.remove_vm("scheduled").unwrap();
+        assert!(list_scheduled_snapshots(&disk).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failed_snapshot_is_rolled_back() {
+        let dir = TempDir::new().unwrap();
+        let disk = dir.path().join("disk.img");
+        write(&disk, b"disk").unwrap();
+        let mut manager = VmManager::new();
+        manager.register_vm("vm", vec![disk.clone(), dir.path().join("missing.img")]).unwrap();
+        manager.set_snapshot_schedule("vm", Some(SnapshotSchedule::new(Duration::from_secs(60), 1).unwrap())).unwrap();
+
+        let now = UNIX_EPOCH + Duration::from_secs(10_000);
+        let runs = manager.run_snapshot_schedules(now);
+        assert!(runs[0].outcome.is_err());
+        assert!(list_scheduled_snapshots(&disk).unwrap().is_empty());
+        assert!(manager.run_snapshot_schedules(now + Duration::from_secs(1)).is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+//! File layout of the directory owned by each managed VM.
+//!
+//! A VM created with `VmManager::create_vm` keeps all its host files in
+//! `<root>/<name>`:
+//!
+//! * `disks/` - disk images and overlays, disposed of like registered disks
+//! * `console.log` - serial console output
+//! * `snapshots/` - saved states of the VM
+//! * `control.sock` - control socket of the VM, e.g. for the guest agent
+//!
+//! Removing the VM removes these files and the directory itself.
+
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use crate::error::VmError;
+
+/// Directory holding the disk images of a VM.
+pub const DISKS_DIR: &str = "disks";
+/// Directory holding the snapshots of a VM.
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+/// File receiving the serial console output of a VM.
+pub const CONSOLE_LOG: &str = "console.log";
+/// Control socket of a VM.
+pub const CONTROL_SOCKET: &str = "control.sock";
+
+/// Directory of a managed VM, see the module documentation for its layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmDirectory {
+    path: PathBuf,
+}
+
+impl VmDirectory {
+    /// Describes the directory at `path` without touching the filesystem.
+    pub fn new(path: impl Into<PathBuf>) -> VmDirectory {
+        VmDirectory { path: path.into() }
+    }
+
+    /// Creates the directory and its subdirectories if they are missing.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the layout exists
+    /// * `Err(VmError)` if a directory couldn't be created
+    pub fn create(&self) -> Result<(), VmError> {
+        for directory in [self.get_disks_dir(), self.get_snapshots_dir()] {
+            if let Err(e) = create_dir_all(&directory) {
+                return Err(VmError::io(format!("failed to create {}: {}", directory.display(), e), e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path of the directory.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the directory holding the disk images.
+    pub fn get_disks_dir(&self) -> PathBuf {
+        self.path.join(DISKS_DIR)
+    }
+
+    /// Returns the path of the disk image named `name`.
+    pub fn get_disk_path(&self, name: &str) -> PathBuf {
+        self.get_disks_dir().join(name)
+    }
+
+    /// Returns the directory holding the snapshots.
+    pub fn get_snapshots_dir(&self) -> PathBuf {
+        self.path.join(SNAPSHOTS_DIR)
+    }
+
+    /// Returns the file receiving the serial console output.
+    pub fn get_console_log(&self) -> PathBuf {
+        self.path.join(CONSOLE_LOG)
+    }
+
+    /// Returns the path of the control socket.
+    pub fn get_control_socket(&self) -> PathBuf {
+        self.path.join(CONTROL_SOCKET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_vm_directory_layout() {
+        let dir = TempDir::new().unwrap();
+        let directory = VmDirectory::new(dir.path().join("vm"));
+        assert_eq!(directory.get_disk_path("root.img"), dir.path().join("vm/disks/root.img"));
+        assert_eq!(directory.get_console_log(), dir.path().join("vm/console.log"));
+        assert_eq!(directory.get_control_socket(), dir.path().join("vm/control.sock"));
+
+        directory.create().expect("Layout should be created");
+        assert!(directory.get_disks_dir().is_dir());
+        assert!(directory.get_snapshots_dir().is_dir());
+        directory.create().expect("Creating an existing layout should succeed");
+    }
+}
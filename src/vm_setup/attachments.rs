@@ -0,0 +1,118 @@
+//! Host side of the devices attached through `VmSetupBuilder`.
+//!
+//! Each platform backend declares which attachments it can provide; anything else
+//! makes `run_vm` fail up front instead of booting a VM without the requested device.
+
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+use crate::device_emulation::gic::GicVersion;
+use crate::device_emulation::serial::Serial;
+use crate::error::VmError;
+use crate::vm_setup::exit_trace::{ExitRecorder, TraceEvent};
+use crate::vm_setup::mitigations::MitigationPolicy;
+use crate::vm_setup::pmu::GuestPmu;
+use crate::vm_setup::security_features::SecurityFeaturePolicy;
+use crate::vm_setup::setup_utils::{SerialConsole, VirtioTransport, VmSetup};
+
+/// Kind of device a `VmSetup` can carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Attachment {
+    DiskImage,
+    CdromImage,
+    Kernel,
+    SerialConsole,
+    Network,
+    SharedDirectory,
+    SharedMemory,
+    Pmem,
+    Display,
+    MitigationPolicy,
+    SecurityFeatures,
+    ExitTrace,
+    PciTransport,
+    GicV3,
+    GuestPmu,
+}
+
+/// Fails if `setup` carries an attachment the backend cannot provide.
+///
+/// # Arguments
+/// * `setup` - VM configuration about to be run
+/// * `backend` - Backend name used in the error message
+/// * `supported` - Attachments the backend provides
+pub(crate) fn check_attachments(setup: &VmSetup, backend: &str, supported: &[Attachment]) -> Result<(), VmError> {
+    let requested = [
+        (Attachment::DiskImage, !setup.get_disks().is_empty(), "disk images"),
+        (Attachment::CdromImage, setup.get_cdrom_image().is_some(), "CD-ROM images"),
+        (Attachment::Kernel, setup.get_kernel().is_some(), "direct kernel boot"),
+        (Attachment::SerialConsole, *setup.get_serial_console() != SerialConsole::Disabled, "serial consoles"),
+        (Attachment::Network, !setup.get_network_devices().is_empty(), "network devices"),
+        (Attachment::SharedDirectory, !setup.get_shared_dirs().is_empty(), "shared directories"),
+        (Attachment::SharedMemory, !setup.get_shared_memory().is_empty(), "shared-memory regions"),
+        (Attachment::Pmem, !setup.get_pmem_devices().is_empty(), "pmem devices"),
+        (Attachment::Display, setup.get_display().is_some(), "displays"),
+        (Attachment::MitigationPolicy, *setup.get_mitigation_policy() != MitigationPolicy::default(), "mitigation policies"),
+        (Attachment::SecurityFeatures, *setup.get_security_features() != SecurityFeaturePolicy::default(), "security feature policies"),
+        (Attachment::ExitTrace, setup.get_exit_trace().is_some(), "exit traces"),
+        (Attachment::PciTransport, setup.get_virtio_transport() == VirtioTransport::Pci, "virtio-pci transports"),
+        (Attachment::GicV3, setup.get_gic_version() == GicVersion::V3, "GICv3 interrupt controllers"),
+        (Attachment::GuestPmu, setup.get_guest_pmu() == GuestPmu::Virtualized, "virtualized PMUs"),
+    ];
+    for (attachment, is_requested, name) in requested {
+        if is_requested && !supported.contains(&attachment) {
+            return Err(VmError::config(format!("{} are not supported by the {} backend", name, backend)));
+        }
+    }
+    Ok(())
+}
+
+/// Opens the destination of the guest serial console.
+///
+/// # Returns
+/// * `Ok(None)` if the serial console is disabled
+/// * `Ok(Some(Box<dyn Write + Send>))` writing to stdout or to the configured file
+/// * `Err(VmError)` if the output file can't be opened
+pub(crate) fn open_serial_output(serial_console: &SerialConsole) -> Result<Option<Box<dyn Write + Send>>, VmError> {
+    let output: Box<dyn Write + Send> = match serial_console {
+        SerialConsole::Disabled => return Ok(None),
+        SerialConsole::Stdout => Box::new(std::io::stdout()),
+        SerialConsole::File(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => return Err(VmError::io(format!("failed to open serial console output {}: {}", path.display(), e), e))
+        }
+    };
+    Ok(Some(output))
+}
+
+/// Feeds the host's standard input to the guest UART from a background thread.
+///
+/// The thread stops at the end of the input or, on the next input, once the VM is
+/// gone and the UART with it. The input is recorded to `recorder`, if any.
+///
+/// # Returns
+/// * `Ok(())` once the thread is running
+/// * `Err(VmError)` if the thread can't be spawned
+pub(crate) fn forward_stdin_to_serial(serial: Weak<Mutex<Serial>>, recorder: Option<Arc<ExitRecorder>>) -> Result<(), VmError> {
+    let spawned = std::thread::Builder::new().name("serial-stdin".to_string()).spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buffer = [0u8; 256];
+        loop {
+            let len = match stdin.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break
+            };
+            let Some(serial) = serial.upgrade() else { break };
+            if let Some(recorder) = &recorder {
+                recorder.record(&TraceEvent::SerialInput { data: buffer[..len].to_vec() });
+            }
+            serial.lock().unwrap_or_else(|e| e.into_inner()).queue_input(&buffer[..len]);
+        }
+    });
+    match spawned {
+        Ok(_) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to spawn the serial input thread: {}", e), e))
+    }
+}
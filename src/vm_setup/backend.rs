@@ -0,0 +1,420 @@
+//! Hypervisor backends behind a single vCPU exit loop.
+//!
+//! KVM, Windows Hypervisor Platform and Hypervisor.framework each implement
+//! `HypervisorBackend`: they create the VM, map its RAM, create and run vCPUs, and
+//! translate the exits of their hypervisor into `VcpuExit`. `run_vcpus` does the rest
+//! the same way on every host: it spawns a thread per vCPU, keeps the vCPUs obeying the
+//! `VmHandle`, samples them for the profiler, dispatches their port and MMIO accesses to
+//! the device buses, records them in the exit trace and collects the `VmExitSummary`.
+//!
+//! Exits a hypervisor needs answered before it can resume the guest stay inside the
+//! backend: WHP completes MMIO through its own instruction emulator, which calls into
+//! the bus, and answers CPUID from the CPU model, and Hypervisor.framework serves PSCI
+//! calls, WFI and the GICv3 system registers itself. They reach the loop as
+//! `VcpuExit::Handled`.
+//!
+//! The loop times each access it dispatches to a device in the `ExitLatencies` of the
+//! VM; WHP times its emulator the same way.
+
+use std::sync::Arc;
+use std::time::Instant;
+use crate::device_emulation::block_device::BlockDeviceMetrics;
+use crate::device_emulation::mmio::MmioBus;
+use crate::device_emulation::pio::PortIoBus;
+use crate::error::VmError;
+use crate::vm_setup::exit_latency::{ExitKind, ExitLatencies};
+use crate::vm_setup::exit_summary::{join_vcpus, DeviceStats, VcpuExitReason, VmExitSummary};
+use crate::vm_setup::exit_trace::{ExitRecorder, TraceEvent};
+use crate::vm_setup::guest_profiler::GuestMode;
+use crate::vm_setup::memory_monitor::{watch_host_memory, MemoryStatus};
+use crate::vm_setup::setup_utils::VmSetup;
+use crate::vm_setup::vm_handle::{RunControlHooks, VmControl};
+
+/// Reads the statistics of a disk once the vCPUs exited, by device name.
+pub(crate) type DiskMetrics = Vec<(String, Box<dyn Fn() -> BlockDeviceMetrics + Send>)>;
+
+/// Why a vCPU left the guest, the same on every hypervisor.
+///
+/// Backends translate the exits of their hypervisor into this, so the loop handling
+/// them is written once and tested with a scripted backend instead of a hypervisor.
+/// Data of device accesses borrows the vCPU: the backend completes a read with what
+/// the loop wrote to `data` the next time the vCPU runs.
+#[derive(Debug)]
+pub(crate) enum VcpuExit<'a> {
+    /// Read of `data.len()` bytes from a port
+    PioIn(u16, &'a mut [u8]),
+    /// Write of `data` to a port
+    PioOut(u16, &'a [u8]),
+    /// Read of `data.len()` bytes from a guest physical address outside RAM
+    MmioRead(u64, &'a mut [u8]),
+    /// Write of `data` to a guest physical address outside RAM
+    MmioWrite(u64, &'a [u8]),
+    /// The backend served the exit itself
+    Handled,
+    /// The vCPU was kicked out of the guest by the run control
+    Canceled,
+    /// The vCPU executed a halt instruction with nothing to wake it up
+    Hlt,
+    /// The guest powered this vCPU off
+    PoweredOff,
+    /// The guest shut the system down
+    Shutdown,
+    /// The guest asked for the system to be reset
+    Reset,
+    /// The guest faulted or left the guest for a reason the backend doesn't handle,
+    /// which stops the vCPU
+    Exception {
+        /// What happened, completing "VCPU n ...", e.g. `encountered an internal error`
+        reason: String,
+    },
+}
+
+/// Devices the vCPU loops dispatch guest I/O to.
+///
+/// Filled before the vCPUs start; the buses are only read afterwards.
+pub(crate) struct VcpuDevices {
+    pub(crate) mmio_bus: MmioBus,
+    pub(crate) pio_bus: PortIoBus,
+    /// Trace the accesses are recorded to, if any
+    pub(crate) recorder: Option<Arc<ExitRecorder>>,
+    /// Histograms the device accesses are timed in, shared with the `VmControl`
+    pub(crate) latencies: Arc<ExitLatencies>,
+}
+
+impl VcpuDevices {
+    /// Records the event built by `event` if the VM is being recorded.
+    pub(crate) fn record(&self, event: impl FnOnce() -> TraceEvent) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&event());
+        }
+    }
+
+    /// Records the host time vCPU `vcpu` spent since `started` serving an exit of `kind`
+    /// in the device named `device`, `None` if no device claimed the access.
+    pub(crate) fn record_latency(&self, vcpu: u32, device: Option<&str>, kind: ExitKind, started: Instant) {
+        self.latencies.record(vcpu, device.unwrap_or("unclaimed"), kind, started.elapsed());
+    }
+}
+
+/// A hypervisor able to run the vCPUs of a VM.
+///
+/// The backend is also the run control of its VM, so the `VmHandle` reaches the
+/// hypervisor through it.
+pub(crate) trait HypervisorBackend: RunControlHooks + 'static {
+    /// vCPU created and run on its own thread
+    type Vcpu;
+    /// Guest RAM mapped into the VM, which devices and the boot loader write to
+    type Memory;
+
+    /// Creates an empty VM configured by `setup`, without RAM or vCPUs.
+    ///
+    /// # Returns
+    /// * `Ok(Self)` with the VM
+    /// * `Err(VmError)` if the hypervisor can't create it
+    fn create_vm(setup: &VmSetup) -> Result<Self, VmError> where Self: Sized;
+
+    /// Maps `size` bytes of guest RAM at guest physical address `gpa`.
+    ///
+    /// # Returns
+    /// * `Ok(Self::Memory)` with the RAM, which must outlive the vCPUs
+    /// * `Err(VmError)` if the RAM can't be allocated or mapped
+    fn map_memory(&mut self, gpa: u64, size: usize) -> Result<Self::Memory, VmError>;
+
+    /// Creates vCPU `index` in its boot state. Called on the thread that runs it.
+    fn create_vcpu(&self, index: u32) -> Result<Self::Vcpu, VmError>;
+
+    /// Makes a vCPU reachable by `kick` while it runs on the calling thread.
+    fn register_vcpu(&self, _vcpu: &Self::Vcpu, _index: u32) {}
+
+    /// Undoes `register_vcpu` once the vCPU loop ended.
+    fn unregister_vcpu(&self, _vcpu: &Self::Vcpu, _index: u32) {}
+
+    /// Runs a vCPU until it leaves the guest.
+    ///
+    /// # Arguments
+    /// * `vcpu` - vCPU to run, created by `create_vcpu` on the calling thread
+    /// * `index` - Index of the vCPU
+    /// * `devices` - Buses of the VM, for hypervisors emulating device accesses themselves
+    ///
+    /// # Returns
+    /// * `Ok(VcpuExit)` telling why the vCPU left the guest
+    /// * `Err(VmError)` on an exit the backend can't handle or a failed run
+    fn run_vcpu<'a>(&self, vcpu: &'a mut Self::Vcpu, index: u32, devices: &VcpuDevices) -> Result<VcpuExit<'a>, VmError>;
+
+    /// Returns the privilege level and program counter a vCPU resumes the guest at,
+    /// or `None` if the backend can't tell yet.
+    fn get_guest_position(&self, vcpu: &Self::Vcpu, index: u32) -> Result<Option<(GuestMode, u64)>, VmError>;
+
+    /// Returns the memory of the host, watched while the VM runs.
+    fn get_host_memory() -> Result<MemoryStatus, VmError>;
+}
+
+/// Runs the vCPUs of a VM until they all exited, with the `VmHandle` controlling them
+/// through `control`.
+///
+/// # Arguments
+/// * `backend` - VM with its RAM mapped and its devices created
+/// * `control` - Run control shared with the `VmHandle`
+/// * `vcpu_count` - Number of vCPUs to create and run
+/// * `devices` - Buses the guest I/O is dispatched to
+/// * `disk_metrics` - Statistics of the disks, read into the summary
+///
+/// # Returns
+/// * `Ok(VmExitSummary)` with how each vCPU exited and the disk statistics
+/// * `Err(VmError)` with the first vCPU error
+pub(crate) async fn run_vcpus<B: HypervisorBackend>(backend: Arc<B>, control: Arc<VmControl>, vcpu_count: u32,
+                                                    devices: Arc<VcpuDevices>, disk_metrics: DiskMetrics) -> Result<VmExitSummary, VmError> {
+    // Let the VmHandle kick the vCPUs and reach the hypervisor
+    control.set_hooks(Box::new(Arc::clone(&backend)))?;
+
+    // Keep watching host memory while the guest runs; the watch ends with this function
+    let _memory_watch = watch_host_memory(Arc::clone(control.get_memory_monitor()), B::get_host_memory);
+
+    // Spawn a blocking task for each vCPU, which creates the vCPU on its thread
+    let started = Instant::now();
+    let mut handlers: Vec<tokio::task::JoinHandle<Result<VcpuExitReason, VmError>>> = Vec::with_capacity(vcpu_count as usize);
+    for index in 0..vcpu_count {
+        let backend = Arc::clone(&backend);
+        let control = Arc::clone(&control);
+        let devices = Arc::clone(&devices);
+        handlers.push(tokio::task::spawn_blocking(move || {
+            let mut vcpu = backend.create_vcpu(index)?;
+            // Make the vCPU reachable by the run control for as long as it runs
+            let _vcpu_guard = control.enter_vcpu();
+            backend.register_vcpu(&vcpu, index);
+            let result = control.catch_vcpu_panic(index, || run_vcpu_loop(&*backend, &mut vcpu, index, &control, &devices));
+            if let Ok(reason) = &result {
+                devices.record(|| TraceEvent::Exit { vcpu: index, reason: *reason });
+            }
+            backend.unregister_vcpu(&vcpu, index);
+            result
+        }));
+    }
+
+    // Await all vCPU tasks and collect their results
+    let vcpus = join_vcpus(handlers).await?;
+    let runtime = started.elapsed();
+    if let Some(recorder) = &devices.recorder {
+        recorder.finish()?;
+    }
+
+    let mut device_stats = Vec::new();
+    for (name, read_metrics) in disk_metrics {
+        let metrics = read_metrics();
+        device_stats.push(DeviceStats {
+            name,
+            completed_requests: metrics.completed_requests,
+            peak_inflight: metrics.peak_inflight,
+            backpressure_events: metrics.backpressure_events,
+        });
+    }
+
+    Ok(VmExitSummary {
+        vcpus,
+        runtime,
+        devices: device_stats,
+        failures: control.get_vcpu_failures(),
+        exit_latencies: devices.latencies.snapshot(),
+    })
+}
+
+/// Runs a vCPU until the guest halts, shuts down, fails or the VM is stopped.
+///
+/// MMIO and port I/O are served by the devices on the buses. An MMIO access no
+/// device claims stops the vCPU with an error, while unclaimed ports read as all
+/// ones and ignore writes, like an empty ISA bus. A reset requested by the guest
+/// stops the whole VM, an exception only the vCPU. Each access is timed while the device serves it.
+///
+/// # Returns
+/// * `Ok(VcpuExitReason)` telling how the vCPU stopped
+/// * `Err(VmError)` on an exception, an unclaimed MMIO access or a hypervisor error
+fn run_vcpu_loop<B: HypervisorBackend>(backend: &B, vcpu: &mut B::Vcpu, index: u32, control: &VmControl,
+                                       devices: &VcpuDevices) -> Result<VcpuExitReason, VmError> {
+    loop {
+        // Stay parked while paused and leave the loop once stopped
+        if !control.wait_for_run() {
+            return Ok(VcpuExitReason::Stopped);
+        }
+        // Note where the guest was if the profiler asked for a sample, typically when
+        // it kicked the vCPU out of the guest
+        if control.get_profiler().take_request(index) && let Some((mode, pc)) = backend.get_guest_position(vcpu, index)? {
+            control.get_profiler().record(index, mode, pc);
+        }
+
+        match backend.run_vcpu(vcpu, index, devices)? {
+            VcpuExit::PioIn(port, data) => {
+                let started = Instant::now();
+                if !devices.pio_bus.read(port, data) {
+                    data.fill(0xff);
+                }
+                devices.record_latency(index, devices.pio_bus.get_name(port, data.len()), ExitKind::PortRead, started);
+                devices.record(|| TraceEvent::IoIn { vcpu: index, port, data: data.to_vec() });
+            },
+            VcpuExit::PioOut(port, data) => {
+                devices.record(|| TraceEvent::IoOut { vcpu: index, port, data: data.to_vec() });
+                let started = Instant::now();
+                devices.pio_bus.write(port, data);
+                devices.record_latency(index, devices.pio_bus.get_name(port, data.len()), ExitKind::PortWrite, started);
+            },
+            VcpuExit::MmioRead(address, data) => {
+                let started = Instant::now();
+                if !devices.mmio_bus.read(address, data) {
+                    return Err(VmError::hypervisor(format!("VCPU {} encountered MMIO read at unmapped address {:x}", index, address)));
+                }
+                devices.record_latency(index, devices.mmio_bus.get_name(address, data.len()), ExitKind::MmioRead, started);
+                devices.record(|| TraceEvent::MmioRead { vcpu: index, address, data: data.to_vec() });
+            },
+            VcpuExit::MmioWrite(address, data) => {
+                devices.record(|| TraceEvent::MmioWrite { vcpu: index, address, data: data.to_vec() });
+                let started = Instant::now();
+                if !devices.mmio_bus.write(address, data) {
+                    return Err(VmError::hypervisor(format!("VCPU {} encountered MMIO write at unmapped address {:x}", index, address)));
+                }
+                devices.record_latency(index, devices.mmio_bus.get_name(address, data.len()), ExitKind::MmioWrite, started);
+            },
+            // Served by the backend, or kicked by the run control; re-check the run state
+            VcpuExit::Handled | VcpuExit::Canceled => continue,
+            VcpuExit::Hlt => return Ok(VcpuExitReason::Halted),
+            VcpuExit::PoweredOff => return Ok(VcpuExitReason::PoweredOff),
+            VcpuExit::Shutdown => return Ok(VcpuExitReason::Shutdown),
+            VcpuExit::Reset => {
+                control.request_stop();
+                return Ok(VcpuExitReason::Reset);
+            },
+            VcpuExit::Exception { reason } => {
+                // Tell where the guest was if the backend can, without hiding the exception
+                let position = match backend.get_guest_position(vcpu, index) {
+                    Ok(Some((mode, pc))) => format!(" ({:?} mode, pc {:#x})", mode, pc),
+                    _ => String::new(),
+                };
+                return Err(VmError::hypervisor(format!("VCPU {} {}{}", index, reason, position)));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::device_emulation::mmio::{read_le, write_le, MmioDevice};
+
+    /// MMIO device holding a single register.
+    #[derive(Default)]
+    struct Register {
+        value: u64,
+    }
+
+    impl MmioDevice for Register {
+        fn read_mmio(&mut self, _offset: u64, data: &mut [u8]) {
+            write_le(data, self.value);
+        }
+
+        fn write_mmio(&mut self, _offset: u64, data: &[u8]) {
+            self.value = read_le(data);
+        }
+    }
+
+    /// vCPU of `ScriptedBackend`: the exits it still has to take, and the data of the
+    /// last access.
+    struct ScriptedVcpu {
+        exits: Vec<&'static str>,
+        data: [u8; 4],
+    }
+
+    /// Backend whose vCPUs take the exits of a script, the last one first, and halt.
+    #[derive(Default)]
+    struct ScriptedBackend {
+        script: Vec<&'static str>,
+        reads: Mutex<Vec<u32>>,
+    }
+
+    impl ScriptedBackend {
+        fn new(script: &[&'static str]) -> Self {
+            ScriptedBackend { script: script.to_vec(), ..Default::default() }
+        }
+    }
+
+    impl RunControlHooks for ScriptedBackend {
+        fn kick(&self) {}
+    }
+
+    impl HypervisorBackend for ScriptedBackend {
+        type Vcpu = ScriptedVcpu;
+        type Memory = ();
+
+        fn create_vm(_setup: &VmSetup) -> Result<Self, VmError> {
+            Ok(ScriptedBackend::default())
+        }
+
+        fn map_memory(&mut self, _gpa: u64, _size: usize) -> Result<(), VmError> {
+            Ok(())
+        }
+
+        fn create_vcpu(&self, _index: u32) -> Result<ScriptedVcpu, VmError> {
+            Ok(ScriptedVcpu { exits: self.script.clone(), data: [0; 4] })
+        }
+
+        fn run_vcpu<'a>(&self, vcpu: &'a mut ScriptedVcpu, _index: u32, _devices: &VcpuDevices) -> Result<VcpuExit<'a>, VmError> {
+            // Complete the last read, like a hypervisor does when it resumes the guest
+            self.reads.lock().unwrap().push(u32::from_le_bytes(vcpu.data));
+            match vcpu.exits.pop() {
+                Some("write") => {
+                    vcpu.data = 0x1234u32.to_le_bytes();
+                    Ok(VcpuExit::MmioWrite(0x1000, &vcpu.data))
+                },
+                Some("read") => Ok(VcpuExit::MmioRead(0x1000, &mut vcpu.data)),
+                Some("read-port") => Ok(VcpuExit::PioIn(0x60, &mut vcpu.data[..1])),
+                Some("fault") => Ok(VcpuExit::Exception { reason: "caused an exception".to_string() }),
+                _ => Ok(VcpuExit::Hlt),
+            }
+        }
+
+        fn get_guest_position(&self, _vcpu: &ScriptedVcpu, _index: u32) -> Result<Option<(GuestMode, u64)>, VmError> {
+            Ok(Some((GuestMode::Kernel, 0xffff_0000)))
+        }
+
+        fn get_host_memory() -> Result<MemoryStatus, VmError> {
+            Ok(MemoryStatus { total: 1 << 30, available: 1 << 30 })
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exits_are_dispatched_to_the_buses() {
+        let mut mmio_bus = MmioBus::new();
+        mmio_bus.register(0x1000, 8, Arc::new(Mutex::new(Register::default()))).unwrap();
+        mmio_bus.set_name(0x1000, "register");
+        let devices = Arc::new(VcpuDevices { mmio_bus, pio_bus: PortIoBus::new(), recorder: None, latencies: Arc::new(ExitLatencies::new()) });
+        // The vCPU writes the register, reads it back, reads a port and halts
+        let backend = Arc::new(ScriptedBackend::new(&["halt", "read-port", "read", "write"]));
+
+        let summary = run_vcpus(Arc::clone(&backend), Arc::new(VmControl::new()), 1, devices, Vec::new()).await.unwrap();
+        assert_eq!(summary.get_reason(), VcpuExitReason::Halted);
+        // The register read back what was written, and the unclaimed port read as all ones
+        assert_eq!(*backend.reads.lock().unwrap(), vec![0, 0x1234, 0x1234, 0x12ff]);
+        // Every access was timed in the device it went to
+        let mut timed: Vec<(String, ExitKind, u64)> = summary.exit_latencies.iter()
+            .map(|latency| (latency.device.clone(), latency.kind, latency.histogram.get_count()))
+            .collect();
+        timed.sort();
+        assert_eq!(timed, vec![
+            ("register".to_string(), ExitKind::MmioRead, 1),
+            ("register".to_string(), ExitKind::MmioWrite, 1),
+            ("unclaimed".to_string(), ExitKind::PortRead, 1),
+        ]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exception_fails_the_vcpu() {
+        let devices = Arc::new(VcpuDevices {
+            mmio_bus: MmioBus::new(),
+            pio_bus: PortIoBus::new(),
+            recorder: None,
+            latencies: Arc::new(ExitLatencies::new()),
+        });
+        let backend = Arc::new(ScriptedBackend::new(&["halt", "fault"]));
+
+        let err = run_vcpus(backend, Arc::new(VmControl::new()), 1, devices, Vec::new()).await.unwrap_err();
+        assert_eq!(err.to_string(), "VCPU 0 caused an exception (Kernel mode, pc 0xffff0000)");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,126 @@
+//! Choice of the device a VM boots from.
+//!
+//! There is no firmware: the backends boot a Linux kernel directly. Booting from the
+//! disk or a CD-ROM therefore means picking the kernel off that device, trying the
+//! devices of `VmSetup::get_boot_order` in turn like a firmware boot menu would.
+
+use crate::kernel_setup::linux_setup::{extract_kernel_components_from_image, extract_kernel_components_from_iso};
+use crate::kernel_setup::setup_utils::KernelComponents;
+use crate::vm_setup::setup_utils::{BootDevice, VmSetup};
+
+/// Root filesystem of kernels booted off the disk when the command line names none:
+/// the first partition of the first virtio disk, where cloud images and installers
+/// put it.
+const DEFAULT_DISK_ROOT: &str = "root=/dev/vda1";
+
+/// Kernel a VM boots and where it came from.
+#[derive(Debug, Clone)]
+pub struct BootSelection {
+    /// Device the kernel was found on
+    pub device: BootDevice,
+    /// Kernel and initrd to load
+    pub kernel: KernelComponents,
+    /// Kernel command line, before the backend announces its devices on it
+    pub cmdline: String,
+}
+
+/// Picks the first device of the boot order that holds a kernel.
+///
+/// Devices that aren't attached are passed over; attached devices without a usable
+/// kernel are skipped with a warning, as network boot always is. `BootDevice::Disk`
+/// is the first disk of the VM, the guest's `/dev/vda`.
+///
+/// # Arguments
+/// * `setup` - VM configuration about to be run
+///
+/// # Returns
+/// * `Some(BootSelection)` with the kernel of the first bootable device
+/// * `None` if no device is bootable; the VM then starts from empty memory
+pub fn select_boot_device(setup: &VmSetup) -> Option<BootSelection> {
+    for device in setup.get_boot_order() {
+        let extracted = match device {
+            BootDevice::Kernel => match setup.get_kernel() {
+                Some(kernel) => Ok(kernel.clone()),
+                None => continue
+            },
+            BootDevice::Disk => match setup.get_disk_image() {
+                Some(path) => extract_kernel_components_from_image(&path.to_string_lossy()),
+                None => continue
+            },
+            BootDevice::Cdrom => match setup.get_cdrom_image() {
+                Some(path) => extract_kernel_components_from_iso(&path.to_string_lossy()),
+                None => continue
+            },
+            BootDevice::Network => {
+                if !setup.get_network_devices().is_empty() {
+                    eprintln!("skipping boot device Network: network boot is not supported");
+                }
+                continue;
+            },
+        };
+        let kernel = match extracted {
+            Ok(kernel) => kernel,
+            Err(e) => {
+                eprintln!("skipping boot device {:?}: {}", device, e);
+                continue;
+            }
+        };
+
+        let mut cmdline = setup.get_kernel_cmdline().to_string();
+        if *device == BootDevice::Disk && !cmdline.split_whitespace().any(|arg| arg.starts_with("root=")) {
+            cmdline.push(' ');
+            cmdline.push_str(DEFAULT_DISK_ROOT);
+        }
+        return Some(BootSelection { device: *device, kernel, cmdline });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel_setup::iso9660::tests::make_iso;
+    use crate::vm_setup::setup_utils::DEFAULT_KERNEL_CMDLINE;
+
+    #[test]
+    fn test_select_boot_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let iso = dir.path().join("installer.iso");
+        std::fs::write(&iso, make_iso(&[
+            ("casper", "INITRD.;1", Some("initrd"), b"installer initrd"),
+            ("casper", "VMLINUZ.;1", Some("vmlinuz"), b"installer kernel"),
+        ])).unwrap();
+        // A blank disk, as before the installation
+        let disk = dir.path().join("disk.img");
+        std::fs::write(&disk, vec![0u8; 1 << 20]).unwrap();
+        let direct = KernelComponents { kernel: b"direct kernel".to_vec(), initrd: None };
+
+        let setup = VmSetup::builder(64, 1).disk_image(&disk).cdrom_image(&iso).build().unwrap();
+        assert_eq!(setup.get_boot_order(), &[BootDevice::Kernel, BootDevice::Disk, BootDevice::Cdrom, BootDevice::Network]);
+        // The blank disk is skipped in favour of the installer
+        let selection = select_boot_device(&setup).expect("The CD-ROM should boot");
+        assert_eq!(selection.device, BootDevice::Cdrom);
+        assert_eq!(selection.kernel.kernel, b"installer kernel");
+        assert_eq!(selection.kernel.initrd.as_deref(), Some(&b"installer initrd"[..]));
+        assert_eq!(selection.cmdline, DEFAULT_KERNEL_CMDLINE);
+
+        let mut setup = VmSetup::builder(64, 1)
+            .disk_image(&disk)
+            .cdrom_image(&iso)
+            .kernel(direct, "console=ttyS0")
+            .boot_order(&[BootDevice::Disk, BootDevice::Kernel])
+            .build()
+            .unwrap();
+        assert_eq!(select_boot_device(&setup).unwrap().device, BootDevice::Kernel);
+
+        // Nothing bootable in the order
+        setup.set_boot_order(&[BootDevice::Disk, BootDevice::Network]);
+        assert!(select_boot_device(&setup).is_none());
+    }
+
+    #[test]
+    fn test_boot_order_is_validated() {
+        assert!(VmSetup::builder(64, 1).boot_order(&[]).build().is_err());
+        assert!(VmSetup::builder(64, 1).boot_order(&[BootDevice::Disk, BootDevice::Cdrom, BootDevice::Disk]).build().is_err());
+    }
+}
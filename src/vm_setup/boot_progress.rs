@@ -0,0 +1,217 @@
+//! Boot milestone tracking.
+//!
+//! The backends feed what the guest prints on its serial console through a
+//! `BootLogWatcher` and report pvpanic events, and `VmHandle::wait_for_boot` reads the
+//! resulting `BootProgress` to tell a guest that is still booting from one that hangs.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Line a guest prints on its serial console once it is up, for guests without a login prompt.
+pub const BOOT_COMPLETE_MARKER: &str = "asgard: boot complete";
+/// Time without any sign of life after which a guest that hasn't booted is considered hung.
+pub const BOOT_HANG_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Longest console line kept for matching; the rest of a longer line is ignored.
+const MAX_LINE_LENGTH: usize = 512;
+
+/// Console output marking each milestone, checked in order.
+const CONSOLE_MILESTONES: &[(&str, BootStage)] = &[
+    ("Kernel panic - not syncing", BootStage::Panicked),
+    (BOOT_COMPLETE_MARKER, BootStage::Booted),
+    ("as init process", BootStage::InitStarted),
+    ("Freeing unused kernel", BootStage::InitStarted),
+    ("Linux version", BootStage::KernelStarted),
+];
+
+/// Boot milestone reached by a guest, in boot order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum BootStage {
+    /// No output from the guest yet
+    #[default]
+    NotStarted,
+    /// The guest kernel printed its banner
+    KernelStarted,
+    /// The kernel handed over to init
+    InitStarted,
+    /// A login prompt or the boot complete marker appeared
+    Booted,
+    /// The guest kernel panicked
+    Panicked,
+}
+
+/// Result of waiting for a guest to boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootOutcome {
+    /// The guest finished booting
+    Booted,
+    /// The guest kernel panicked
+    Panicked,
+    /// The VM stopped before the guest finished booting
+    Stopped(BootStage),
+    /// The timeout expired while the guest was still showing signs of life
+    StillBooting(BootStage),
+    /// The timeout expired and the guest has been silent for `BOOT_HANG_THRESHOLD`
+    Hung(BootStage),
+}
+
+struct ProgressState {
+    stage: BootStage,
+    last_activity: Instant,
+}
+
+/// Boot stage of a guest and the time it last showed signs of life.
+pub struct BootProgress {
+    inner: Mutex<ProgressState>,
+}
+
+impl Default for BootProgress {
+    fn default() -> Self {
+        BootProgress::new()
+    }
+}
+
+impl BootProgress {
+    /// Creates the progress of a guest that hasn't started yet.
+    pub fn new() -> Self {
+        BootProgress { inner: Mutex::new(ProgressState { stage: BootStage::NotStarted, last_activity: Instant::now() }) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ProgressState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the latest milestone reached.
+    pub fn get_stage(&self) -> BootStage {
+        self.lock().stage
+    }
+
+    /// Returns how long the guest has been silent.
+    pub fn get_idle_time(&self) -> Duration {
+        self.lock().last_activity.elapsed()
+    }
+
+    /// Records that the guest reached `stage`. Earlier stages are ignored.
+    pub fn advance(&self, stage: BootStage) {
+        let mut inner = self.lock();
+        inner.stage = inner.stage.max(stage);
+        inner.last_activity = Instant::now();
+    }
+
+    /// Records a sign of life without a new milestone.
+    pub fn record_activity(&self) {
+        self.lock().last_activity = Instant::now();
+    }
+
+    /// Returns the outcome of a wait that ends now, given whether the VM stopped.
+    pub fn get_outcome(&self, stopped: bool) -> Option<BootOutcome> {
+        let inner = self.lock();
+        match inner.stage {
+            BootStage::Booted => Some(BootOutcome::Booted),
+            BootStage::Panicked => Some(BootOutcome::Panicked),
+            stage if stopped => Some(BootOutcome::Stopped(stage)),
+            _ => None,
+        }
+    }
+
+    /// Returns the outcome of a wait whose timeout expired.
+    pub fn get_timeout_outcome(&self) -> BootOutcome {
+        let inner = self.lock();
+        if inner.last_activity.elapsed() >= BOOT_HANG_THRESHOLD {
+            BootOutcome::Hung(inner.stage)
+        } else {
+            BootOutcome::StillBooting(inner.stage)
+        }
+    }
+}
+
+/// Serial console sink scanning guest output for boot milestones.
+///
+/// Everything written is forwarded unchanged to the wrapped sink.
+pub struct BootLogWatcher<W: Write> {
+    output: W,
+    progress: Arc<BootProgress>,
+    line: Vec<u8>,
+}
+
+impl<W: Write> BootLogWatcher<W> {
+    /// Creates a watcher forwarding to `output` and reporting to `progress`.
+    pub fn new(output: W, progress: Arc<BootProgress>) -> Self {
+        BootLogWatcher { output, progress, line: Vec::new() }
+    }
+
+    /// Checks the current line for milestones.
+    fn scan_line(&self) {
+        let line = String::from_utf8_lossy(&self.line);
+        if let Some((_, stage)) = CONSOLE_MILESTONES.iter().find(|(marker, _)| line.contains(marker)) {
+            self.progress.advance(*stage);
+        }
+    }
+}
+
+impl<W: Write> Write for BootLogWatcher<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for byte in buf {
+            match byte {
+                b'\n' => {
+                    self.scan_line();
+                    self.line.clear();
+                },
+                _ if self.line.len() < MAX_LINE_LENGTH => self.line.push(*byte),
+                _ => {},
+            }
+            // Login prompts don't end with a newline
+            if *byte == b':' && self.line.ends_with(b"login:") {
+                self.progress.advance(BootStage::Booted);
+            }
+        }
+        if !buf.is_empty() {
+            self.progress.record_activity();
+        }
+        // Every byte was scanned, so every byte has to reach the sink
+        self.output.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_milestones() {
+        let progress = Arc::new(BootProgress::new());
+        let mut watcher = BootLogWatcher::new(Vec::new(), progress.clone());
+        assert_eq!(progress.get_outcome(false), None);
+
+        watcher.write_all(b"[    0.000000] Linux version 6.6.0 (gcc)\n").unwrap();
+        assert_eq!(progress.get_stage(), BootStage::KernelStarted);
+        watcher.write_all(b"[    1.200000] Run /sbin/init as init process\n").unwrap();
+        assert_eq!(progress.get_stage(), BootStage::InitStarted);
+        assert_eq!(progress.get_outcome(true), Some(BootOutcome::Stopped(BootStage::InitStarted)));
+
+        for byte in b"\ndebian login:" {
+            watcher.write_all(&[*byte]).unwrap();
+        }
+        assert_eq!(progress.get_outcome(false), Some(BootOutcome::Booted));
+        assert!(watcher.output.ends_with(b"Run /sbin/init as init process\n\ndebian login:"));
+    }
+
+    #[test]
+    fn test_panic_and_timeout_outcomes() {
+        let progress = Arc::new(BootProgress::new());
+        progress.advance(BootStage::KernelStarted);
+        assert_eq!(progress.get_timeout_outcome(), BootOutcome::StillBooting(BootStage::KernelStarted));
+
+        let mut watcher = BootLogWatcher::new(std::io::sink(), progress.clone());
+        watcher.write_all(b"Kernel panic - not syncing: VFS: Unable to mount root fs\n").unwrap();
+        // A panic isn't undone by later output
+        watcher.write_all(b"Linux version 6.6.0\n").unwrap();
+        assert_eq!(progress.get_outcome(false), Some(BootOutcome::Panicked));
+    }
+}
@@ -0,0 +1,285 @@
+//! Incremental checkpoints of guest memory, for rolling a VM back in time.
+//!
+//! A `CheckpointLog` starts with a full copy of guest memory, leaving out zero pages,
+//! and then records at every checkpoint only the pages the guest wrote to since the
+//! one before, as reported by the hypervisor's dirty page tracking (see
+//! `MemorySlotRegistry::get_dirty_pages` on KVM). Rolling back to a checkpoint only
+//! rewrites the pages written since then, so a failed test VM can be taken back a few
+//! seconds and re-run, e.g. with debugging enabled. Only the newest checkpoints are
+//! kept: older ones are merged into the full copy.
+//!
+//! The log only covers memory. The vCPUs must be paused while a checkpoint is taken
+//! or rolled back, and their registers and the device state saved alongside it.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use crate::error::VmError;
+
+/// Granularity of the checkpoints, the page size of the dirty page tracking.
+pub const CHECKPOINT_PAGE_SIZE: u64 = 4096;
+
+/// Guest memory pages captured at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Time since the VM started at which the checkpoint was taken
+    taken_at: Duration,
+    /// Content of the captured pages by guest physical address
+    pages: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl Checkpoint {
+    /// Returns the time since the VM started at which the checkpoint was taken.
+    pub fn get_taken_at(&self) -> Duration {
+        self.taken_at
+    }
+
+    /// Returns the number of pages captured by the checkpoint.
+    pub fn get_page_count(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Checkpoints of the memory of one VM, oldest first.
+#[derive(Debug, Clone)]
+pub struct CheckpointLog {
+    /// Guest RAM regions covered by the log
+    regions: Vec<(GuestAddress, usize)>,
+    /// The oldest checkpoint holds every non-zero page, the others the pages dirtied
+    /// since the checkpoint before them
+    checkpoints: VecDeque<Checkpoint>,
+    /// Largest number of checkpoints kept
+    retention: usize,
+}
+
+impl CheckpointLog {
+    /// Starts a log with a full checkpoint of guest memory.
+    ///
+    /// # Arguments
+    /// * `memory` - Guest memory, which the paused vCPUs don't write to meanwhile
+    /// * `regions` - Page-aligned RAM regions of `memory`, as passed to
+    ///   `GuestMemoryMmap::from_ranges`
+    /// * `taken_at` - Time since the VM started
+    /// * `retention` - Largest number of checkpoints kept, including the full one
+    ///
+    /// # Returns
+    /// * `Ok(CheckpointLog)` on success
+    /// * `Err(VmError)` if no checkpoint would be kept, a region isn't page-aligned or
+    ///   memory couldn't be read
+    pub fn new(memory: &GuestMemoryMmap, regions: &[(GuestAddress, usize)], taken_at: Duration, retention: usize) -> Result<CheckpointLog, VmError> {
+        if retention == 0 {
+            return Err(VmError::config("a checkpoint log must keep at least one checkpoint"));
+        }
+        if let Some((start, size)) = regions.iter().find(|(start, size)| !start.0.is_multiple_of(CHECKPOINT_PAGE_SIZE) || !(*size as u64).is_multiple_of(CHECKPOINT_PAGE_SIZE)) {
+            return Err(VmError::memory(format!("memory region {:#x}+{:#x} is not page-aligned", start.0, size)));
+        }
+
+        let mut pages = BTreeMap::new();
+        for (start, size) in regions {
+            for address in (start.0..start.0 + *size as u64).step_by(CHECKPOINT_PAGE_SIZE as usize) {
+                let page = read_page(memory, address)?;
+                if page.iter().any(|byte| *byte != 0) {
+                    pages.insert(address, page);
+                }
+            }
+        }
+        Ok(CheckpointLog {
+            regions: regions.to_vec(),
+            checkpoints: VecDeque::from([Checkpoint { taken_at, pages }]),
+            retention,
+        })
+    }
+
+    /// Returns the checkpoints, oldest first.
+    pub fn get_checkpoints(&self) -> impl Iterator<Item = &Checkpoint> {
+        self.checkpoints.iter()
+    }
+
+    /// Returns the bytes of guest memory held by the checkpoints.
+    pub fn get_size(&self) -> u64 {
+        self.checkpoints.iter().map(|checkpoint| checkpoint.pages.len() as u64 * CHECKPOINT_PAGE_SIZE).sum()
+    }
+
+    /// Takes a checkpoint holding the pages written since the newest one, merging the
+    /// oldest checkpoints into the full one beyond the retention.
+    ///
+    /// # Arguments
+    /// * `memory` - Guest memory, which the paused vCPUs don't write to meanwhile
+    /// * `taken_at` - Time since the VM started
+    /// * `dirty_pages` - Guest physical addresses of the pages written since the newest
+    ///   checkpoint or the last rollback
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the checkpoint would be older than the newest one, a page is
+    ///   outside the RAM regions or memory couldn't be read
+    pub fn take_checkpoint(&mut self, memory: &GuestMemoryMmap, taken_at: Duration, dirty_pages: &[u64]) -> Result<(), VmError> {
+        if self.checkpoints.back().is_some_and(|newest| taken_at < newest.taken_at) {
+            return Err(VmError::config(format!("checkpoint at {:?} is older than the newest one", taken_at)));
+        }
+        let mut pages = BTreeMap::new();
+        for address in self.get_pages(dirty_pages)? {
+            pages.insert(address, read_page(memory, address)?);
+        }
+        self.checkpoints.push_back(Checkpoint { taken_at, pages });
+
+        while self.checkpoints.len() > self.retention {
+            let Some(full) = self.checkpoints.pop_front() else { break };
+            let next = &mut self.checkpoints[0];
+            let mut merged = full.pages;
+            for (address, page) in std::mem::take(&mut next.pages) {
+                if page.iter().any(|byte| *byte != 0) {
+                    merged.insert(address, page);
+                } else {
+                    merged.remove(&address);
+                }
+            }
+            next.pages = merged;
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the newest checkpoint taken at or before `at`, if any.
+    pub fn find_checkpoint(&self, at: Duration) -> Option<usize> {
+        self.checkpoints.iter().rposition(|checkpoint| checkpoint.taken_at <= at)
+    }
+
+    /// Rolls guest memory back to the checkpoint `index` and drops the checkpoints
+    /// taken after it.
+    ///
+    /// # Arguments
+    /// * `memory` - Guest memory, which the paused vCPUs don't write to meanwhile
+    /// * `index` - Index of the checkpoint among `get_checkpoints`
+    /// * `dirty_pages` - Guest physical addresses of the pages written since the newest
+    ///   checkpoint
+    ///
+    /// # Returns
+    /// * `Ok(count)` with the number of pages rewritten
+    /// * `Err(VmError)` if there is no such checkpoint, a page is outside the RAM
+    ///   regions or memory couldn't be written
+    pub fn rollback(&mut self, memory: &GuestMemoryMmap, index: usize, dirty_pages: &[u64]) -> Result<usize, VmError> {
+        if index >= self.checkpoints.len() {
+            return Err(VmError::config(format!("there is no checkpoint {}", index)));
+        }
+        let mut written = self.get_pages(dirty_pages)?;
+        for checkpoint in self.checkpoints.range(index + 1..) {
+            written.extend(checkpoint.pages.keys());
+        }
+
+        let zero_page = vec![0u8; CHECKPOINT_PAGE_SIZE as usize];
+        for address in &written {
+            let page = self.checkpoints.range(..=index).rev().find_map(|checkpoint| checkpoint.pages.get(address));
+            let content = page.map_or(zero_page.as_slice(), |page| page);
+            if let Err(e) = memory.write_slice(content, GuestAddress(*address)) {
+                return Err(VmError::memory_source(format!("failed to restore guest page {:#x}: {}", address, e), e));
+            }
+        }
+        self.checkpoints.truncate(index + 1);
+        Ok(written.len())
+    }
+
+    /// Rolls guest memory back by `duration`, to the newest checkpoint taken at or
+    /// before that time, see `rollback`.
+    ///
+    /// # Arguments
+    /// * `now` - Time since the VM started
+    ///
+    /// # Returns
+    /// * `Ok(taken_at)` with the time the restored checkpoint was taken at
+    /// * `Err(VmError)` if no checkpoint is that old or the rollback failed
+    pub fn rollback_by(&mut self, memory: &GuestMemoryMmap, now: Duration, duration: Duration, dirty_pages: &[u64]) -> Result<Duration, VmError> {
+        let index = match now.checked_sub(duration).and_then(|at| self.find_checkpoint(at)) {
+            Some(index) => index,
+            None => return Err(VmError::config(format!("no checkpoint is {:?} old", duration)))
+        };
+        self.rollback(memory, index, dirty_pages)?;
+        Ok(self.checkpoints[index].taken_at)
+    }
+
+    /// Returns the pages holding `addresses`, checking they are guest RAM.
+    fn get_pages(&self, addresses: &[u64]) -> Result<BTreeSet<u64>, VmError> {
+        let mut pages = BTreeSet::new();
+        for address in addresses {
+            let page = address - address % CHECKPOINT_PAGE_SIZE;
+            if !self.regions.iter().any(|(start, size)| page >= start.0 && page - start.0 < *size as u64) {
+                return Err(VmError::memory(format!("page {:#x} is outside guest RAM", page)));
+            }
+            pages.insert(page);
+        }
+        Ok(pages)
+    }
+}
+
+/// Copies the guest page at `address`.
+fn read_page(memory: &GuestMemoryMmap, address: u64) -> Result<Box<[u8]>, VmError> {
+    let mut page = vec![0u8; CHECKPOINT_PAGE_SIZE as usize].into_boxed_slice();
+    match memory.read_slice(&mut page, GuestAddress(address)) {
+        Ok(()) => Ok(page),
+        Err(e) => Err(VmError::memory_source(format!("failed to read guest page {:#x}: {}", address, e), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = CHECKPOINT_PAGE_SIZE;
+
+    fn read(memory: &GuestMemoryMmap, address: u64) -> u8 {
+        let mut byte = [0u8];
+        memory.read_slice(&mut byte, GuestAddress(address)).unwrap();
+        byte[0]
+    }
+
+    fn write(memory: &GuestMemoryMmap, address: u64, value: u8) {
+        memory.write_slice(&[value], GuestAddress(address)).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_restores_checkpoint() {
+        let regions = [(GuestAddress(0), 8 * PAGE as usize)];
+        let memory = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        write(&memory, 0, 1);
+        let mut log = CheckpointLog::new(&memory, &regions, Duration::ZERO, 10).unwrap();
+        assert_eq!(log.get_size(), PAGE);
+
+        write(&memory, 0, 2);
+        write(&memory, PAGE + 5, 2);
+        log.take_checkpoint(&memory, Duration::from_secs(1), &[0, PAGE + 5]).unwrap();
+        write(&memory, 2 * PAGE, 3);
+        log.take_checkpoint(&memory, Duration::from_secs(2), &[2 * PAGE]).unwrap();
+        write(&memory, 0, 4);
+        write(&memory, 3 * PAGE, 4);
+
+        let dirty = [0, 3 * PAGE];
+        assert_eq!(log.rollback_by(&memory, Duration::from_millis(2500), Duration::from_millis(1200), &dirty).unwrap(), Duration::from_secs(1));
+        assert_eq!([read(&memory, 0), read(&memory, PAGE + 5), read(&memory, 2 * PAGE), read(&memory, 3 * PAGE)], [2, 2, 0, 0]);
+        assert_eq!(log.get_checkpoints().count(), 2);
+
+        assert_eq!(log.rollback(&memory, 0, &[]).unwrap(), 2);
+        assert_eq!([read(&memory, 0), read(&memory, PAGE + 5)], [1, 0]);
+        assert!(log.rollback_by(&memory, Duration::from_secs(1), Duration::from_secs(5), &[]).is_err());
+        assert!(log.rollback(&memory, 1, &[]).is_err());
+    }
+
+    #[test]
+    fn test_old_checkpoints_are_merged() {
+        let regions = [(GuestAddress(0), 4 * PAGE as usize)];
+        let memory = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let mut log = CheckpointLog::new(&memory, &regions, Duration::ZERO, 2).unwrap();
+        assert!(CheckpointLog::new(&memory, &regions, Duration::ZERO, 0).is_err());
+        assert!(log.take_checkpoint(&memory, Duration::from_secs(1), &[4 * PAGE]).is_err());
+
+        for (second, page) in [(1u64, 0u64), (2, 1), (3, 2)] {
+            write(&memory, page * PAGE, second as u8);
+            log.take_checkpoint(&memory, Duration::from_secs(second), &[page * PAGE]).unwrap();
+        }
+        let checkpoints: Vec<(Duration, usize)> = log.get_checkpoints().map(|checkpoint| (checkpoint.get_taken_at(), checkpoint.get_page_count())).collect();
+        assert_eq!(checkpoints, vec![(Duration::from_secs(2), 2), (Duration::from_secs(3), 1)]);
+        assert!(log.take_checkpoint(&memory, Duration::from_secs(2), &[]).is_err());
+
+        assert_eq!(log.find_checkpoint(Duration::from_secs(1)), None);
+        log.rollback(&memory, 0, &[]).unwrap();
+        assert_eq!([read(&memory, 0), read(&memory, PAGE), read(&memory, 2 * PAGE)], [1, 2, 0]);
+    }
+}
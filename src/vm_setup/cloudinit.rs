@@ -0,0 +1,661 @@
+//! Cloud-init NoCloud seed images.
+//!
+//! Cloud images configure themselves on their first boot from a cloud-init
+//! datasource. The NoCloud datasource reads `user-data`, `meta-data` and, if present,
+//! `network-config` from a filesystem labelled `cidata`; `write_seed_image` builds one
+//! as an ISO 9660 image or a FAT image. Attach it to the VM after the cloud image with
+//! `VmSetupBuilder::iso_image`, or as a read-only `DiskDevice` for a FAT image.
+//!
+//! The documents are given as they are, or written by `CloudConfigBuilder` from users,
+//! SSH keys and a hostname.
+
+use std::path::Path;
+use crate::error::VmError;
+
+/// Label of the seed filesystem, which cloud-init looks for.
+pub const SEED_VOLUME_LABEL: &str = "cidata";
+
+/// Size of ISO 9660 logical blocks.
+const ISO_BLOCK_SIZE: usize = 2048;
+/// Block of the primary volume descriptor; the system area comes before it.
+const ISO_PRIMARY_DESCRIPTOR_BLOCK: usize = 16;
+/// File flag of ISO 9660 directory records describing a directory.
+const ISO_FLAG_DIRECTORY: u8 = 0x02;
+
+/// Size of FAT sectors.
+const FAT_SECTOR_SIZE: usize = 512;
+/// Sectors of the FAT image, laid out like a 1.44 MB floppy.
+const FAT_TOTAL_SECTORS: usize = 2880;
+/// Sectors of each of the two FATs.
+const FAT_SECTORS_PER_FAT: usize = 9;
+/// Entries of the root directory.
+const FAT_ROOT_ENTRIES: usize = 224;
+/// Media descriptor of 1.44 MB floppies.
+const FAT_MEDIA: u8 = 0xf0;
+/// First sector of the data area: boot sector, both FATs, then the root directory.
+const FAT_DATA_START: usize = 1 + 2 * FAT_SECTORS_PER_FAT + FAT_ROOT_ENTRIES * 32 / FAT_SECTOR_SIZE;
+
+/// Filesystem of a seed image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedFormat {
+    /// ISO 9660 with Rock Ridge names, as `genisoimage -V cidata -R` writes it
+    #[default]
+    Iso,
+    /// FAT12 with long file names, for guests without ISO 9660 support; holds up to
+    /// about 1.4 MB
+    Vfat,
+}
+
+/// Documents read by the NoCloud datasource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloudInitSeed {
+    /// `user-data`: the `#cloud-config` document, or a script starting with `#!`
+    pub user_data: String,
+    /// `meta-data`, naming at least the `instance-id`
+    pub meta_data: String,
+    /// `network-config` in the network config format of cloud-init; without one the
+    /// guest configures its first interface through DHCP
+    pub network_config: Option<String>,
+}
+
+impl CloudInitSeed {
+    /// Create a `CloudConfigBuilder` for the guest instance `instance_id`.
+    ///
+    /// cloud-init only configures a guest once per instance ID, so reusing a disk with a
+    /// new seed takes a new ID.
+    pub fn builder(instance_id: impl Into<String>) -> CloudConfigBuilder {
+        CloudConfigBuilder {
+            instance_id: instance_id.into(),
+            hostname: None,
+            ssh_authorized_keys: Vec::new(),
+            users: Vec::new(),
+            packages: Vec::new(),
+            run_commands: Vec::new(),
+            network_config: None,
+        }
+    }
+
+    /// Returns the files of the seed filesystem, by name.
+    fn get_files(&self) -> Vec<(&'static str, &[u8])> {
+        let mut files = vec![("meta-data", self.meta_data.as_bytes()), ("user-data", self.user_data.as_bytes())];
+        if let Some(network_config) = &self.network_config {
+            files.push(("network-config", network_config.as_bytes()));
+        }
+        files
+    }
+}
+
+/// User account created by cloud-init.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloudUser {
+    name: String,
+    ssh_authorized_keys: Vec<String>,
+    sudo: bool,
+    shell: Option<String>,
+}
+
+impl CloudUser {
+    /// Create the account `name`, without SSH keys or sudo rights, with the guest's
+    /// default shell.
+    pub fn new(name: impl Into<String>) -> CloudUser {
+        CloudUser { name: name.into(), ssh_authorized_keys: Vec::new(), sudo: false, shell: None }
+    }
+    /// Let the holder of the public key `key`, e.g. `ssh-ed25519 AAAA... alice@host`, log in.
+    pub fn ssh_authorized_key(mut self, key: impl Into<String>) -> CloudUser {
+        self.ssh_authorized_keys.push(key.into());
+        self
+    }
+    /// Let the user run any command as root through sudo, without a password.
+    pub fn sudo(mut self, sudo: bool) -> CloudUser {
+        self.sudo = sudo;
+        self
+    }
+    /// Set the login shell of the user.
+    pub fn shell(mut self, shell: impl Into<String>) -> CloudUser {
+        self.shell = Some(shell.into());
+        self
+    }
+    /// Get the name of the account.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Builder writing the `#cloud-config` user data and the meta data of a seed.
+#[derive(Debug, Clone)]
+pub struct CloudConfigBuilder {
+    instance_id: String,
+    hostname: Option<String>,
+    ssh_authorized_keys: Vec<String>,
+    users: Vec<CloudUser>,
+    packages: Vec<String>,
+    run_commands: Vec<String>,
+    network_config: Option<String>,
+}
+
+impl CloudConfigBuilder {
+    /// Set the hostname of the guest.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> CloudConfigBuilder {
+        self.hostname = Some(hostname.into());
+        self
+    }
+    /// Let the holder of the public key `key` log in as the default user of the image,
+    /// e.g. `debian` or `ubuntu`.
+    pub fn ssh_authorized_key(mut self, key: impl Into<String>) -> CloudConfigBuilder {
+        self.ssh_authorized_keys.push(key.into());
+        self
+    }
+    /// Create a user account next to the default user of the image.
+    pub fn user(mut self, user: CloudUser) -> CloudConfigBuilder {
+        self.users.push(user);
+        self
+    }
+    /// Install a package on the first boot.
+    pub fn package(mut self, name: impl Into<String>) -> CloudConfigBuilder {
+        self.packages.push(name.into());
+        self
+    }
+    /// Run a shell command as root at the end of the first boot, after the previous ones.
+    pub fn run_command(mut self, command: impl Into<String>) -> CloudConfigBuilder {
+        self.run_commands.push(command.into());
+        self
+    }
+    /// Set the `network-config` document as it is.
+    pub fn network_config(mut self, network_config: impl Into<String>) -> CloudConfigBuilder {
+        self.network_config = Some(network_config.into());
+        self
+    }
+
+    /// Validate the configuration and write its documents.
+    ///
+    /// # Returns
+    /// * `Ok(CloudInitSeed)` on success
+    /// * `Err(VmError)` if the instance ID is empty, the hostname isn't a valid host
+    ///   name, a user name is empty or repeated, or an SSH key spans several lines
+    pub fn build(self) -> Result<CloudInitSeed, VmError> {
+        if self.instance_id.trim().is_empty() {
+            return Err(VmError::config("cloud-init instance ID is empty"));
+        }
+        if let Some(hostname) = &self.hostname
+            && !is_valid_hostname(hostname) {
+            return Err(VmError::config(format!("{:?} is not a valid hostname", hostname)));
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if user.name.is_empty() || user.name.contains(|c: char| c.is_whitespace() || c == ':') {
+                return Err(VmError::config(format!("{:?} is not a valid user name", user.name)));
+            }
+            if self.users[..i].iter().any(|other| other.name == user.name) {
+                return Err(VmError::config(format!("user {} is given twice", user.name)));
+            }
+        }
+        let keys = self.ssh_authorized_keys.iter().chain(self.users.iter().flat_map(|user| &user.ssh_authorized_keys));
+        for key in keys {
+            if key.trim().is_empty() || key.contains(['\n', '\r']) {
+                return Err(VmError::config(format!("{:?} is not a single SSH public key", key)));
+            }
+        }
+
+        let mut meta_data = format!("instance-id: {}\n", quote(&self.instance_id));
+        if let Some(hostname) = &self.hostname {
+            meta_data.push_str(&format!("local-hostname: {}\n", quote(hostname)));
+        }
+        Ok(CloudInitSeed { user_data: self.render_user_data(), meta_data, network_config: self.network_config })
+    }
+
+    /// Renders the `#cloud-config` document.
+    fn render_user_data(&self) -> String {
+        let mut user_data = String::from("#cloud-config\n");
+        if let Some(hostname) = &self.hostname {
+            user_data.push_str(&format!("hostname: {}\n", quote(hostname)));
+        }
+        if !self.ssh_authorized_keys.is_empty() {
+            user_data.push_str("ssh_authorized_keys:\n");
+            push_list(&mut user_data, "  ", &self.ssh_authorized_keys);
+        }
+        if !self.users.is_empty() {
+            // Listing users replaces the default user unless it is kept explicitly
+            user_data.push_str("users:\n  - default\n");
+            for user in &self.users {
+                user_data.push_str(&format!("  - name: {}\n", quote(&user.name)));
+                if let Some(shell) = &user.shell {
+                    user_data.push_str(&format!("    shell: {}\n", quote(shell)));
+                }
+                if user.sudo {
+                    user_data.push_str("    sudo: \"ALL=(ALL) NOPASSWD:ALL\"\n");
+                }
+                if !user.ssh_authorized_keys.is_empty() {
+                    user_data.push_str("    ssh_authorized_keys:\n");
+                    push_list(&mut user_data, "      ", &user.ssh_authorized_keys);
+                }
+            }
+        }
+        if !self.packages.is_empty() {
+            user_data.push_str("packages:\n");
+            push_list(&mut user_data, "  ", &self.packages);
+        }
+        if !self.run_commands.is_empty() {
+            user_data.push_str("runcmd:\n");
+            push_list(&mut user_data, "  ", &self.run_commands);
+        }
+        user_data
+    }
+}
+
+/// Quotes `value` as a YAML double-quoted scalar, which JSON strings are.
+fn quote(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// Appends `items` to a YAML document as a block sequence indented by `indent`.
+fn push_list(document: &mut String, indent: &str, items: &[String]) {
+    for item in items {
+        document.push_str(&format!("{}- {}\n", indent, quote(item)));
+    }
+}
+
+/// Whether `hostname` is a valid host name: dot-separated labels of letters, digits
+/// and inner hyphens.
+fn is_valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253 && hostname.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Writes the NoCloud seed filesystem holding `seed` to `path`, replacing any file there.
+///
+/// # Arguments
+/// * `seed` - Documents to put on the seed
+/// * `path` - Path of the seed image
+/// * `format` - Filesystem of the seed image
+///
+/// # Returns
+/// * `Ok(())` once the image is written
+/// * `Err(VmError)` if the documents don't fit in a FAT seed or the image couldn't be written
+pub fn write_seed_image(seed: &CloudInitSeed, path: &Path, format: SeedFormat) -> Result<(), VmError> {
+    let files = seed.get_files();
+    let image = match format {
+        SeedFormat::Iso => build_iso_image(&files),
+        SeedFormat::Vfat => build_fat_image(&files)?,
+    };
+    match std::fs::write(path, image) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to write seed image {}: {}", path.display(), e), e))
+    }
+}
+
+/// Writes `value` in both byte orders, as ISO 9660 numbers are recorded.
+fn put_both_endian_u32(buf: &mut [u8], value: u32) {
+    buf[0..4].copy_from_slice(&value.to_le_bytes());
+    buf[4..8].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Writes `value` in both byte orders.
+fn put_both_endian_u16(buf: &mut [u8], value: u16) {
+    buf[0..2].copy_from_slice(&value.to_le_bytes());
+    buf[2..4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Builds an ISO 9660 directory record with the Rock Ridge `system_use` entries.
+fn iso_record(name: &[u8], block: usize, size: usize, is_directory: bool, system_use: &[u8]) -> Vec<u8> {
+    let mut record = vec![0u8; 33];
+    put_both_endian_u32(&mut record[2..10], block as u32);
+    put_both_endian_u32(&mut record[10..18], size as u32);
+    record[25] = if is_directory { ISO_FLAG_DIRECTORY } else { 0 };
+    put_both_endian_u16(&mut record[28..32], 1);
+    record[32] = name.len() as u8;
+    record.extend_from_slice(name);
+    // The system use area starts at an even offset
+    if name.len().is_multiple_of(2) {
+        record.push(0);
+    }
+    record.extend_from_slice(system_use);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+    record[0] = record.len() as u8;
+    record
+}
+
+/// Builds the Rock Ridge `PX` entry giving the POSIX mode of a file.
+fn rock_ridge_px(mode: u32) -> Vec<u8> {
+    let mut entry = vec![0u8; 36];
+    entry[0..4].copy_from_slice(&[b'P', b'X', 36, 1]);
+    put_both_endian_u32(&mut entry[4..12], mode);
+    put_both_endian_u32(&mut entry[12..20], 1);
+    entry
+}
+
+/// Returns the ISO 9660 level 1 name of `name`, e.g. `USER_DAT.;1` for `user-data`.
+fn iso_name(name: &str) -> String {
+    let base: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .take(8)
+        .collect();
+    format!("{}.;1", base)
+}
+
+/// Builds an ISO 9660 image holding `files` in its root directory, with Rock Ridge
+/// names and the `cidata` volume label.
+fn build_iso_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+    // Blocks: 16 primary descriptor, 17 terminator, 18 and 19 path tables, 20 root
+    // directory, then the file data
+    let root_block = 20;
+    let mut files: Vec<(String, &str, &[u8])> = files.iter().map(|(name, data)| (iso_name(name), *name, *data)).collect();
+    // Directory records are sorted by their ISO 9660 name
+    files.sort();
+
+    // The `SP` entry of the root's `.` record tells readers Rock Ridge is in use
+    let mut root_system_use = vec![b'S', b'P', 7, 1, 0xbe, 0xef, 0];
+    root_system_use.extend(rock_ridge_px(0o40555));
+    let mut root = iso_record(&[0], root_block, ISO_BLOCK_SIZE, true, &root_system_use);
+    root.extend(iso_record(&[1], root_block, ISO_BLOCK_SIZE, true, &rock_ridge_px(0o40555)));
+    let mut data_block = root_block + 1;
+    let mut data = Vec::new();
+    for (iso_name, name, content) in &files {
+        let mut system_use = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+        system_use.extend_from_slice(name.as_bytes());
+        system_use.extend(rock_ridge_px(0o100444));
+        root.extend(iso_record(iso_name.as_bytes(), data_block, content.len(), false, &system_use));
+        data.extend_from_slice(content);
+        data.resize(data.len().next_multiple_of(ISO_BLOCK_SIZE), 0);
+        data_block += content.len().div_ceil(ISO_BLOCK_SIZE);
+    }
+
+    let mut image = vec![0u8; (root_block + 1) * ISO_BLOCK_SIZE];
+    image[root_block * ISO_BLOCK_SIZE..root_block * ISO_BLOCK_SIZE + root.len()].copy_from_slice(&root);
+    image.extend(data);
+
+    // Path tables holding just the root, in little and big endian order
+    for (block, location) in [(18, (root_block as u32).to_le_bytes()), (19, (root_block as u32).to_be_bytes())] {
+        let table = block * ISO_BLOCK_SIZE;
+        image[table] = 1;
+        image[table + 2..table + 6].copy_from_slice(&location);
+        image[table + 6..table + 8].copy_from_slice(&if block == 18 { 1u16.to_le_bytes() } else { 1u16.to_be_bytes() });
+    }
+
+    let descriptor = &mut image[ISO_PRIMARY_DESCRIPTOR_BLOCK * ISO_BLOCK_SIZE..(ISO_PRIMARY_DESCRIPTOR_BLOCK + 1) * ISO_BLOCK_SIZE];
+    descriptor[0] = 1;
+    descriptor[1..6].copy_from_slice(b"CD001");
+    descriptor[6] = 1;
+    descriptor[8..72].fill(b' ');
+    descriptor[40..40 + SEED_VOLUME_LABEL.len()].copy_from_slice(SEED_VOLUME_LABEL.as_bytes());
+    put_both_endian_u32(&mut descriptor[80..88], data_block as u32);
+    put_both_endian_u16(&mut descriptor[120..124], 1);
+    put_both_endian_u16(&mut descriptor[124..128], 1);
+    put_both_endian_u16(&mut descriptor[128..132], ISO_BLOCK_SIZE as u16);
+    put_both_endian_u32(&mut descriptor[132..140], 10);
+    descriptor[140..144].copy_from_slice(&18u32.to_le_bytes());
+    descriptor[148..152].copy_from_slice(&19u32.to_be_bytes());
+    let root_record = iso_record(&[0], root_block, ISO_BLOCK_SIZE, true, &[]);
+    descriptor[156..156 + root_record.len()].copy_from_slice(&root_record);
+    descriptor[190..813].fill(b' ');
+    // Creation, modification, expiration and effective dates left unspecified
+    for date in [813, 830, 847, 864] {
+        descriptor[date..date + 16].fill(b'0');
+    }
+    descriptor[881] = 1;
+
+    let terminator = (ISO_PRIMARY_DESCRIPTOR_BLOCK + 1) * ISO_BLOCK_SIZE;
+    image[terminator] = 255;
+    image[terminator + 1..terminator + 6].copy_from_slice(b"CD001");
+    image[terminator + 6] = 1;
+    image
+}
+
+/// Sets the 12-bit FAT entry of `cluster`.
+fn set_fat12_entry(fat: &mut [u8], cluster: usize, value: u16) {
+    let offset = cluster * 3 / 2;
+    if cluster.is_multiple_of(2) {
+        fat[offset] = value as u8;
+        fat[offset + 1] = (fat[offset + 1] & 0xf0) | ((value >> 8) as u8 & 0x0f);
+    } else {
+        fat[offset] = (fat[offset] & 0x0f) | ((value as u8 & 0x0f) << 4);
+        fat[offset + 1] = (value >> 4) as u8;
+    }
+}
+
+/// Returns the 8.3 name standing in for the long name `name`, e.g. `USER-D~1` for `user-data`.
+fn fat_short_name(name: &str) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    let base: Vec<u8> = name
+        .bytes()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_')
+        .map(|c| c.to_ascii_uppercase())
+        .take(6)
+        .collect();
+    short[..base.len()].copy_from_slice(&base);
+    short[base.len()..base.len() + 2].copy_from_slice(b"~1");
+    short
+}
+
+/// Builds the long file name entries for `name`, in the order they are stored.
+fn fat_long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+    let checksum = short_name.iter().fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c));
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // The name is terminated by a null unless it fills its last entry, then padded
+    if !units.len().is_multiple_of(13) {
+        units.push(0);
+    }
+    units.resize(units.len().next_multiple_of(13), 0xffff);
+
+    let count = units.len() / 13;
+    let mut entries = Vec::with_capacity(count);
+    for (index, chunk) in units.chunks(13).enumerate().rev() {
+        let mut entry = [0u8; 32];
+        entry[0] = (index as u8 + 1) | if index + 1 == count { 0x40 } else { 0 };
+        entry[11] = 0x0f;
+        entry[13] = checksum;
+        let positions = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (position, unit) in positions.zip(chunk) {
+            entry[position..position + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Builds a FAT12 image holding `files` in its root directory, with long file names
+/// and the `CIDATA` volume label.
+fn build_fat_image(files: &[(&str, &[u8])]) -> Result<Vec<u8>, VmError> {
+    let mut image = vec![0u8; FAT_TOTAL_SECTORS * FAT_SECTOR_SIZE];
+
+    // Boot sector and BIOS parameter block
+    image[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+    image[3..11].copy_from_slice(b"ASGARD  ");
+    image[11..13].copy_from_slice(&(FAT_SECTOR_SIZE as u16).to_le_bytes());
+    image[13] = 1;
+    image[14..16].copy_from_slice(&1u16.to_le_bytes());
+    image[16] = 2;
+    image[17..19].copy_from_slice(&(FAT_ROOT_ENTRIES as u16).to_le_bytes());
+    image[19..21].copy_from_slice(&(FAT_TOTAL_SECTORS as u16).to_le_bytes());
+    image[21] = FAT_MEDIA;
+    image[22..24].copy_from_slice(&(FAT_SECTORS_PER_FAT as u16).to_le_bytes());
+    image[24..26].copy_from_slice(&18u16.to_le_bytes());
+    image[26..28].copy_from_slice(&2u16.to_le_bytes());
+    image[38] = 0x29;
+    image[39..43].copy_from_slice(&0x5eed_c1d0u32.to_le_bytes());
+    let mut label = [b' '; 11];
+    label[..SEED_VOLUME_LABEL.len()].copy_from_slice(SEED_VOLUME_LABEL.to_ascii_uppercase().as_bytes());
+    image[43..54].copy_from_slice(&label);
+    image[54..62].copy_from_slice(b"FAT12   ");
+    image[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut fat = vec![0u8; FAT_SECTORS_PER_FAT * FAT_SECTOR_SIZE];
+    set_fat12_entry(&mut fat, 0, 0xf00 | FAT_MEDIA as u16);
+    set_fat12_entry(&mut fat, 1, 0xfff);
+
+    let mut directory = Vec::new();
+    let mut label_entry = [0u8; 32];
+    label_entry[..11].copy_from_slice(&label);
+    label_entry[11] = 0x08;
+    directory.push(label_entry);
+
+    let clusters = FAT_TOTAL_SECTORS - FAT_DATA_START;
+    let mut next_cluster = 2;
+    for (name, content) in files {
+        let count = content.len().div_ceil(FAT_SECTOR_SIZE);
+        if next_cluster - 2 + count > clusters {
+            return Err(VmError::config(format!("cloud-init documents don't fit in a {} KiB FAT seed", image.len() >> 10)));
+        }
+        let first_cluster = if count == 0 { 0 } else { next_cluster };
+        for cluster in next_cluster..next_cluster + count {
+            let next = if cluster + 1 == next_cluster + count { 0xfff } else { cluster as u16 + 1 };
+            set_fat12_entry(&mut fat, cluster, next);
+            let start = (FAT_DATA_START + cluster - 2) * FAT_SECTOR_SIZE;
+            let chunk = &content[(cluster - next_cluster) * FAT_SECTOR_SIZE..content.len().min((cluster - next_cluster + 1) * FAT_SECTOR_SIZE)];
+            image[start..start + chunk.len()].copy_from_slice(chunk);
+        }
+        next_cluster += count;
+
+        let short_name = fat_short_name(name);
+        directory.extend(fat_long_name_entries(name, &short_name));
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(&short_name);
+        entry[11] = 0x20;
+        // Written on 1980-01-01, the earliest date FAT records
+        entry[24..26].copy_from_slice(&0x0021u16.to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&(content.len() as u32).to_le_bytes());
+        directory.push(entry);
+    }
+
+    for copy in 0..2 {
+        let start = (1 + copy * FAT_SECTORS_PER_FAT) * FAT_SECTOR_SIZE;
+        image[start..start + fat.len()].copy_from_slice(&fat);
+    }
+    let root = (1 + 2 * FAT_SECTORS_PER_FAT) * FAT_SECTOR_SIZE;
+    image[root..root + directory.len() * 32].copy_from_slice(&directory.concat());
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_emulation::block_device::disk_backend::FileBackend;
+    use crate::image::format::{detect_image_format, ImageFormat};
+    use crate::kernel_setup::iso9660::IsoFilesystem;
+
+    // Helper: reads the files of the root directory of a FAT12 image built above,
+    // by their long names
+    fn read_fat_files(image: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let fat = &image[FAT_SECTOR_SIZE..];
+        let root = (1 + 2 * FAT_SECTORS_PER_FAT) * FAT_SECTOR_SIZE;
+        let mut files = Vec::new();
+        let mut long_name: Vec<u16> = Vec::new();
+        for entry in image[root..root + FAT_ROOT_ENTRIES * 32].chunks(32).take_while(|entry| entry[0] != 0) {
+            if entry[11] == 0x0f {
+                let positions = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+                let units: Vec<u16> = positions.map(|i| u16::from_le_bytes([entry[i], entry[i + 1]])).collect();
+                // Entries are stored last part first
+                long_name.splice(0..0, units);
+                continue;
+            }
+            if entry[11] & 0x08 != 0 {
+                continue;
+            }
+            let name = String::from_utf16(&long_name.iter().copied().take_while(|unit| *unit != 0).collect::<Vec<_>>()).unwrap();
+            long_name.clear();
+            let size = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as usize;
+            let mut cluster = u16::from_le_bytes([entry[26], entry[27]]) as usize;
+            let mut content = Vec::new();
+            while content.len() < size {
+                let start = (FAT_DATA_START + cluster - 2) * FAT_SECTOR_SIZE;
+                content.extend_from_slice(&image[start..start + FAT_SECTOR_SIZE]);
+                let offset = cluster * 3 / 2;
+                let pair = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+                cluster = if cluster.is_multiple_of(2) { pair & 0xfff } else { pair >> 4 } as usize;
+            }
+            content.truncate(size);
+            files.push((name, content));
+        }
+        files
+    }
+
+    #[test]
+    fn test_cloud_config_builder() {
+        let seed = CloudInitSeed::builder("vm-0001")
+            .hostname("web-1")
+            .ssh_authorized_key("ssh-ed25519 AAAAC3Nza admin@host")
+            .user(CloudUser::new("alice").sudo(true).shell("/bin/bash").ssh_authorized_key("ssh-rsa AAAAB3 alice@laptop"))
+            .package("nginx")
+            .run_command("systemctl enable --now nginx")
+            .build()
+            .unwrap();
+
+        assert_eq!(seed.meta_data, "instance-id: \"vm-0001\"\nlocal-hostname: \"web-1\"\n");
+        assert_eq!(seed.user_data, "#cloud-config\n\
+            hostname: \"web-1\"\n\
+            ssh_authorized_keys:\n  - \"ssh-ed25519 AAAAC3Nza admin@host\"\n\
+            users:\n  - default\n  - name: \"alice\"\n    shell: \"/bin/bash\"\n    sudo: \"ALL=(ALL) NOPASSWD:ALL\"\n\
+            \x20   ssh_authorized_keys:\n      - \"ssh-rsa AAAAB3 alice@laptop\"\n\
+            packages:\n  - \"nginx\"\n\
+            runcmd:\n  - \"systemctl enable --now nginx\"\n");
+        assert_eq!(seed.network_config, None);
+        // Values are quoted, so YAML syntax in them stays text
+        let seed = CloudInitSeed::builder("vm-0002").run_command("echo \"a: b\" # c").build().unwrap();
+        assert!(seed.user_data.contains("  - \"echo \\\"a: b\\\" # c\"\n"));
+
+        assert!(CloudInitSeed::builder(" ").build().is_err());
+        assert!(CloudInitSeed::builder("vm").hostname("-web").build().is_err());
+        assert!(CloudInitSeed::builder("vm").hostname("web_1").build().is_err());
+        assert!(CloudInitSeed::builder("vm").user(CloudUser::new("bob")).user(CloudUser::new("bob")).build().is_err());
+        assert!(CloudInitSeed::builder("vm").ssh_authorized_key("ssh-rsa A\nssh-rsa B").build().is_err());
+    }
+
+    #[test]
+    fn test_write_iso_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seed.iso");
+        let seed = CloudInitSeed {
+            user_data: "#cloud-config\nhostname: \"web-1\"\n".to_string(),
+            meta_data: "instance-id: vm-0001\n".to_string(),
+            network_config: Some("version: 2\nethernets:\n  eth0:\n    dhcp4: true\n".to_string()),
+        };
+        write_seed_image(&seed, &path, SeedFormat::Iso).unwrap();
+        assert_eq!(detect_image_format(&path).unwrap(), ImageFormat::Iso);
+        let image = std::fs::read(&path).unwrap();
+        assert_eq!(&image[16 * ISO_BLOCK_SIZE + 40..16 * ISO_BLOCK_SIZE + 47], b"cidata ");
+
+        let mut disk = FileBackend::open(&path).unwrap();
+        let mut filesystem = IsoFilesystem::open(&mut disk).unwrap().expect("ISO 9660 filesystem");
+        assert_eq!(filesystem.read_file("/user-data").unwrap().unwrap(), seed.user_data.as_bytes());
+        assert_eq!(filesystem.read_file("/meta-data").unwrap().unwrap(), seed.meta_data.as_bytes());
+        assert_eq!(filesystem.read_file("/network-config").unwrap().unwrap(), seed.network_config.unwrap().as_bytes());
+    }
+
+    #[test]
+    fn test_write_vfat_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seed.img");
+        let user_data: String = (0..100).map(|i| format!("# comment line {}\n", i)).collect();
+        let seed = CloudInitSeed { user_data, meta_data: "instance-id: vm-0001\n".to_string(), network_config: None };
+        write_seed_image(&seed, &path, SeedFormat::Vfat).unwrap();
+
+        let image = std::fs::read(&path).unwrap();
+        assert_eq!(image.len(), FAT_TOTAL_SECTORS * FAT_SECTOR_SIZE);
+        assert_eq!(&image[43..54], b"CIDATA     ");
+        assert_eq!(read_fat_files(&image), vec![
+            ("meta-data".to_string(), seed.meta_data.clone().into_bytes()),
+            ("user-data".to_string(), seed.user_data.clone().into_bytes()),
+        ]);
+
+        let seed = CloudInitSeed { user_data: "x".repeat(2 << 20), meta_data: String::new(), network_config: None };
+        assert!(write_seed_image(&seed, &path, SeedFormat::Vfat).is_err());
+    }
+
+    #[test]
+    fn test_fat_names() {
+        assert_eq!(&fat_short_name("network-config"), b"NETWOR~1   ");
+        let entries = fat_long_name_entries("network-config", &fat_short_name("network-config"));
+        assert_eq!(entries.len(), 2);
+        // The last part comes first and is flagged as such
+        assert_eq!(entries[0][0], 0x42);
+        assert_eq!(entries[1][0], 0x01);
+        assert_eq!(iso_name("user-data"), "USER_DAT.;1");
+    }
+}
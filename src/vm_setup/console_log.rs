@@ -0,0 +1,297 @@
+//! Timestamped record of what a guest prints on its serial console.
+//!
+//! The backends feed the console output through a `ConsoleRecorder`, which splits it
+//! into `ConsoleLine`s kept in the VM's `ConsoleLog`. `VmHandle::console_lines` reads
+//! them back as they come in, so automated tests can wait for a pattern such as
+//! `login:` instead of sleeping.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Console lines kept for readers that fall behind or start late; older ones are dropped.
+pub const MAX_CONSOLE_LINES: usize = 10_000;
+/// Longest console line kept; the rest of a longer line is dropped.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// A line of guest console output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleLine {
+    /// Time since the VM was started at which the line began
+    pub timestamp: Duration,
+    /// Text of the line, without its line ending; invalid UTF-8 is replaced
+    pub text: String,
+}
+
+struct LogState {
+    /// Latest complete lines
+    lines: VecDeque<ConsoleLine>,
+    /// Index of `lines[0]` among every line the guest printed
+    first_index: u64,
+    /// Bytes of the line being printed and the time it began
+    partial: Vec<u8>,
+    partial_timestamp: Duration,
+    /// Set once the VM stopped; no more lines follow
+    closed: bool,
+}
+
+impl LogState {
+    /// Completes the partial line, dropping the oldest line if the log is full.
+    fn complete_line(&mut self) {
+        let text = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial.clear();
+        if self.lines.len() == MAX_CONSOLE_LINES {
+            self.lines.pop_front();
+            self.first_index += 1;
+        }
+        self.lines.push_back(ConsoleLine { timestamp: self.partial_timestamp, text });
+    }
+}
+
+/// Console output of a VM, shared by its backend and its readers.
+pub struct ConsoleLog {
+    inner: Mutex<LogState>,
+    started: Instant,
+    /// Bumped on every change, to wake the readers up
+    version: watch::Sender<u64>,
+}
+
+impl Default for ConsoleLog {
+    fn default() -> Self {
+        ConsoleLog::new()
+    }
+}
+
+impl ConsoleLog {
+    /// Creates the empty log of a VM starting now.
+    pub fn new() -> Self {
+        ConsoleLog {
+            inner: Mutex::new(LogState {
+                lines: VecDeque::new(),
+                first_index: 0,
+                partial: Vec::new(),
+                partial_timestamp: Duration::ZERO,
+                closed: false,
+            }),
+            started: Instant::now(),
+            version: watch::channel(0).0,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn notify(&self) {
+        self.version.send_modify(|version| *version += 1);
+    }
+
+    /// Appends console output, completing a line at every `\n`. Carriage returns are dropped.
+    pub fn write(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let mut inner = self.lock();
+        for byte in bytes {
+            if inner.partial.is_empty() {
+                inner.partial_timestamp = self.started.elapsed();
+            }
+            match byte {
+                b'\n' => inner.complete_line(),
+                b'\r' => {},
+                _ if inner.partial.len() < MAX_LINE_LENGTH => inner.partial.push(*byte),
+                _ => {},
+            }
+        }
+        drop(inner);
+        self.notify();
+    }
+
+    /// Marks the end of the output, once the VM stopped. A pending partial line is kept
+    /// as the last line.
+    pub fn close(&self) {
+        let mut inner = self.lock();
+        if inner.closed {
+            return;
+        }
+        if !inner.partial.is_empty() {
+            inner.complete_line();
+        }
+        inner.closed = true;
+        drop(inner);
+        self.notify();
+    }
+
+    /// Returns the complete lines printed so far, up to `MAX_CONSOLE_LINES`.
+    pub fn get_lines(&self) -> Vec<ConsoleLine> {
+        self.lock().lines.iter().cloned().collect()
+    }
+}
+
+/// Reader of a `ConsoleLog`, yielding every kept line from the first one on.
+///
+/// Created by `VmHandle::console_lines`. A reader falling more than
+/// `MAX_CONSOLE_LINES` behind skips the lines dropped in between.
+pub struct ConsoleLines {
+    log: Arc<ConsoleLog>,
+    changes: watch::Receiver<u64>,
+    next_index: u64,
+}
+
+impl ConsoleLines {
+    /// Creates a reader starting at the oldest kept line of `log`.
+    pub fn new(log: Arc<ConsoleLog>) -> Self {
+        let changes = log.version.subscribe();
+        ConsoleLines { log, changes, next_index: 0 }
+    }
+
+    /// Waits for the next complete line.
+    ///
+    /// # Returns
+    /// * `Some(ConsoleLine)` as soon as the guest finished printing it
+    /// * `None` once the VM stopped and every line was read
+    pub async fn next(&mut self) -> Option<ConsoleLine> {
+        loop {
+            self.changes.borrow_and_update();
+            {
+                let inner = self.log.lock();
+                self.next_index = self.next_index.max(inner.first_index);
+                if let Some(line) = inner.lines.get((self.next_index - inner.first_index) as usize) {
+                    self.next_index += 1;
+                    return Some(line.clone());
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            // The reader keeps the log and with it the sender alive, so this can't fail
+            let _ = self.changes.changed().await;
+        }
+    }
+
+    /// Waits for a line containing `pattern`, skipping the lines before it.
+    ///
+    /// The line being printed is matched too, as prompts such as `login:` aren't
+    /// followed by a newline; it is then returned before it is complete, and once more
+    /// by `next` when it is.
+    ///
+    /// # Returns
+    /// * `Some(ConsoleLine)` with the first line containing `pattern`
+    /// * `None` if the VM stopped without printing it
+    pub async fn wait_for(&mut self, pattern: &str) -> Option<ConsoleLine> {
+        loop {
+            self.changes.borrow_and_update();
+            {
+                let inner = self.log.lock();
+                self.next_index = self.next_index.max(inner.first_index);
+                let start = (self.next_index - inner.first_index) as usize;
+                for line in inner.lines.iter().skip(start) {
+                    self.next_index += 1;
+                    if line.text.contains(pattern) {
+                        return Some(line.clone());
+                    }
+                }
+                let partial = String::from_utf8_lossy(&inner.partial);
+                if partial.contains(pattern) {
+                    return Some(ConsoleLine { timestamp: inner.partial_timestamp, text: partial.into_owned() });
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            let _ = self.changes.changed().await;
+        }
+    }
+}
+
+/// Serial console sink recording guest output in a `ConsoleLog`.
+///
+/// Everything written is forwarded unchanged to the wrapped sink.
+pub struct ConsoleRecorder<W: Write> {
+    output: W,
+    log: Arc<ConsoleLog>,
+}
+
+impl<W: Write> ConsoleRecorder<W> {
+    /// Creates a recorder forwarding to `output` and recording into `log`.
+    pub fn new(output: W, log: Arc<ConsoleLog>) -> Self {
+        ConsoleRecorder { output, log }
+    }
+}
+
+impl<W: Write> Write for ConsoleRecorder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.log.write(buf);
+        // Every byte was recorded, so every byte has to reach the sink
+        self.output.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_are_split_and_timestamped() {
+        let log = Arc::new(ConsoleLog::new());
+        let mut recorder = ConsoleRecorder::new(Vec::new(), Arc::clone(&log));
+        recorder.write_all(b"Linux version 6.6.0\r\nRun /sbin/").unwrap();
+        recorder.write_all(b"init as init process\n").unwrap();
+        recorder.write_all(b"debian login: ").unwrap();
+
+        let lines = log.get_lines();
+        assert_eq!(lines.iter().map(|line| line.text.as_str()).collect::<Vec<_>>(), vec![
+            "Linux version 6.6.0",
+            "Run /sbin/init as init process",
+        ]);
+        assert!(lines[0].timestamp <= lines[1].timestamp);
+        assert_eq!(recorder.output, b"Linux version 6.6.0\r\nRun /sbin/init as init process\ndebian login: ");
+
+        // The prompt becomes the last line once the VM stopped
+        log.close();
+        assert_eq!(log.get_lines().last().unwrap().text, "debian login: ");
+    }
+
+    #[test]
+    fn test_old_lines_are_dropped() {
+        let log = ConsoleLog::new();
+        for i in 0..MAX_CONSOLE_LINES + 5 {
+            log.write(format!("line {}\n", i).as_bytes());
+        }
+        let lines = log.get_lines();
+        assert_eq!(lines.len(), MAX_CONSOLE_LINES);
+        assert_eq!(lines[0].text, "line 5");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_console_lines_stream() {
+        let log = Arc::new(ConsoleLog::new());
+        log.write(b"early line\n");
+        let mut lines = ConsoleLines::new(Arc::clone(&log));
+
+        let writer = Arc::clone(&log);
+        let guest = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            writer.write(b"Welcome to Debian\n");
+            writer.write(b"debian login:");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            writer.close();
+        });
+
+        // Lines printed before the reader was created are yielded too
+        assert_eq!(lines.next().await.unwrap().text, "early line");
+        assert_eq!(lines.wait_for("login:").await.unwrap().text, "debian login:");
+        // The partial line is returned again once the VM stopped and completed it
+        assert_eq!(lines.next().await.unwrap().text, "debian login:");
+        assert_eq!(lines.next().await, None);
+        assert_eq!(lines.wait_for("login:").await, None);
+        guest.await.unwrap();
+    }
+}
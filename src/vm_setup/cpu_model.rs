@@ -0,0 +1,443 @@
+//! Backend-agnostic CPU model used to present a stable CPU to guests.
+//!
+//! A `CpuModel` decides which of the CPUID feature flags known to this module are
+//! visible to the guest. Each hypervisor backend translates it into its own CPUID
+//! filtering mechanism (`KVM_SET_CPUID2` on Linux, the CPUID result list partition
+//! property on Windows), so a VM started on different hosts sees the same feature set
+//! as long as every host supports the selected model.
+
+use crate::error::VmError;
+
+/// CPUID output register holding a feature bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// One CPUID leaf as reported by the host, independent of the hypervisor API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuidEntry {
+    /// CPUID leaf (value of EAX on input)
+    pub function: u32,
+    /// CPUID subleaf (value of ECX on input)
+    pub index: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// x86 CPU features managed by `CpuModel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CpuFeature {
+    Sse3,
+    Pclmulqdq,
+    Ssse3,
+    Fma,
+    Cx16,
+    Sse41,
+    Sse42,
+    Movbe,
+    Popcnt,
+    Aes,
+    Xsave,
+    Avx,
+    F16c,
+    Rdrand,
+    Bmi1,
+    Hle,
+    Avx2,
+    Bmi2,
+    Erms,
+    Rtm,
+    Avx512f,
+    Avx512dq,
+    Rdseed,
+    Adx,
+    Avx512cd,
+    Sha,
+    Avx512bw,
+    Avx512vl,
+    LahfLm,
+    Abm,
+    Rdtscp,
+    Pdpe1gb,
+}
+
+/// First CPUID leaf of the hypervisor range, holding the hypervisor vendor signature.
+pub const HYPERVISOR_CPUID_LEAF: u32 = 0x4000_0000;
+/// Last CPUID leaf of the hypervisor range.
+pub const HYPERVISOR_CPUID_LEAF_END: u32 = 0x4000_00ff;
+/// Hypervisor vendor signature returned in EBX, ECX and EDX of `HYPERVISOR_CPUID_LEAF`.
+pub const HYPERVISOR_SIGNATURE: [u8; 12] = *b"AsgardMgrVMM";
+/// Bit of leaf 1 ECX telling the guest it runs under a hypervisor.
+const HYPERVISOR_PRESENT_BIT: u32 = 31;
+
+/// Every feature managed by `CpuModel`, in CPUID order.
+const ALL_FEATURES: [CpuFeature; 32] = [
+    CpuFeature::Sse3, CpuFeature::Pclmulqdq, CpuFeature::Ssse3, CpuFeature::Fma,
+    CpuFeature::Cx16, CpuFeature::Sse41, CpuFeature::Sse42, CpuFeature::Movbe,
+    CpuFeature::Popcnt, CpuFeature::Aes, CpuFeature::Xsave, CpuFeature::Avx,
+    CpuFeature::F16c, CpuFeature::Rdrand, CpuFeature::Bmi1, CpuFeature::Hle,
+    CpuFeature::Avx2, CpuFeature::Bmi2, CpuFeature::Erms, CpuFeature::Rtm,
+    CpuFeature::Avx512f, CpuFeature::Avx512dq, CpuFeature::Rdseed, CpuFeature::Adx,
+    CpuFeature::Avx512cd, CpuFeature::Sha, CpuFeature::Avx512bw, CpuFeature::Avx512vl,
+    CpuFeature::LahfLm, CpuFeature::Abm, CpuFeature::Rdtscp, CpuFeature::Pdpe1gb,
+];
+
+/// Features added by the x86-64-v2 micro-architecture level.
+const X86_64_V2_FEATURES: [CpuFeature; 7] = [
+    CpuFeature::Cx16, CpuFeature::LahfLm, CpuFeature::Popcnt, CpuFeature::Sse3,
+    CpuFeature::Sse41, CpuFeature::Sse42, CpuFeature::Ssse3,
+];
+
+/// Features added by the x86-64-v3 micro-architecture level.
+const X86_64_V3_FEATURES: [CpuFeature; 9] = [
+    CpuFeature::Avx, CpuFeature::Avx2, CpuFeature::Bmi1, CpuFeature::Bmi2,
+    CpuFeature::F16c, CpuFeature::Fma, CpuFeature::Abm, CpuFeature::Movbe,
+    CpuFeature::Xsave,
+];
+
+/// Features added by the x86-64-v4 micro-architecture level.
+const X86_64_V4_FEATURES: [CpuFeature; 5] = [
+    CpuFeature::Avx512f, CpuFeature::Avx512bw, CpuFeature::Avx512cd,
+    CpuFeature::Avx512dq, CpuFeature::Avx512vl,
+];
+
+impl CpuFeature {
+    /// Returns the lower-case flag name as used by `/proc/cpuinfo`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CpuFeature::Sse3 => "sse3",
+            CpuFeature::Pclmulqdq => "pclmulqdq",
+            CpuFeature::Ssse3 => "ssse3",
+            CpuFeature::Fma => "fma",
+            CpuFeature::Cx16 => "cx16",
+            CpuFeature::Sse41 => "sse4_1",
+            CpuFeature::Sse42 => "sse4_2",
+            CpuFeature::Movbe => "movbe",
+            CpuFeature::Popcnt => "popcnt",
+            CpuFeature::Aes => "aes",
+            CpuFeature::Xsave => "xsave",
+            CpuFeature::Avx => "avx",
+            CpuFeature::F16c => "f16c",
+            CpuFeature::Rdrand => "rdrand",
+            CpuFeature::Bmi1 => "bmi1",
+            CpuFeature::Hle => "hle",
+            CpuFeature::Avx2 => "avx2",
+            CpuFeature::Bmi2 => "bmi2",
+            CpuFeature::Erms => "erms",
+            CpuFeature::Rtm => "rtm",
+            CpuFeature::Avx512f => "avx512f",
+            CpuFeature::Avx512dq => "avx512dq",
+            CpuFeature::Rdseed => "rdseed",
+            CpuFeature::Adx => "adx",
+            CpuFeature::Avx512cd => "avx512cd",
+            CpuFeature::Sha => "sha_ni",
+            CpuFeature::Avx512bw => "avx512bw",
+            CpuFeature::Avx512vl => "avx512vl",
+            CpuFeature::LahfLm => "lahf_lm",
+            CpuFeature::Abm => "abm",
+            CpuFeature::Rdtscp => "rdtscp",
+            CpuFeature::Pdpe1gb => "pdpe1gb",
+        }
+    }
+
+    /// Looks a feature up by its flag name.
+    pub fn from_name(name: &str) -> Option<CpuFeature> {
+        ALL_FEATURES.iter().copied().find(|f| f.name() == name)
+    }
+
+    /// Returns where the feature bit lives: `(leaf, subleaf, register, bit)`.
+    pub fn location(&self) -> (u32, u32, CpuidRegister, u32) {
+        match self {
+            CpuFeature::Sse3 => (0x1, 0, CpuidRegister::Ecx, 0),
+            CpuFeature::Pclmulqdq => (0x1, 0, CpuidRegister::Ecx, 1),
+            CpuFeature::Ssse3 => (0x1, 0, CpuidRegister::Ecx, 9),
+            CpuFeature::Fma => (0x1, 0, CpuidRegister::Ecx, 12),
+            CpuFeature::Cx16 => (0x1, 0, CpuidRegister::Ecx, 13),
+            CpuFeature::Sse41 => (0x1, 0, CpuidRegister::Ecx, 19),
+            CpuFeature::Sse42 => (0x1, 0, CpuidRegister::Ecx, 20),
+            CpuFeature::Movbe => (0x1, 0, CpuidRegister::Ecx, 22),
+            CpuFeature::Popcnt => (0x1, 0, CpuidRegister::Ecx, 23),
+            CpuFeature::Aes => (0x1, 0, CpuidRegister::Ecx, 25),
+            CpuFeature::Xsave => (0x1, 0, CpuidRegister::Ecx, 26),
+            CpuFeature::Avx => (0x1, 0, CpuidRegister::Ecx, 28),
+            CpuFeature::F16c => (0x1, 0, CpuidRegister::Ecx, 29),
+            CpuFeature::Rdrand => (0x1, 0, CpuidRegister::Ecx, 30),
+            CpuFeature::Bmi1 => (0x7, 0, CpuidRegister::Ebx, 3),
+            CpuFeature::Hle => (0x7, 0, CpuidRegister::Ebx, 4),
+            CpuFeature::Avx2 => (0x7, 0, CpuidRegister::Ebx, 5),
+            CpuFeature::Bmi2 => (0x7, 0, CpuidRegister::Ebx, 8),
+            CpuFeature::Erms => (0x7, 0, CpuidRegister::Ebx, 9),
+            CpuFeature::Rtm => (0x7, 0, CpuidRegister::Ebx, 11),
+            CpuFeature::Avx512f => (0x7, 0, CpuidRegister::Ebx, 16),
+            CpuFeature::Avx512dq => (0x7, 0, CpuidRegister::Ebx, 17),
+            CpuFeature::Rdseed => (0x7, 0, CpuidRegister::Ebx, 18),
+            CpuFeature::Adx => (0x7, 0, CpuidRegister::Ebx, 19),
+            CpuFeature::Avx512cd => (0x7, 0, CpuidRegister::Ebx, 28),
+            CpuFeature::Sha => (0x7, 0, CpuidRegister::Ebx, 29),
+            CpuFeature::Avx512bw => (0x7, 0, CpuidRegister::Ebx, 30),
+            CpuFeature::Avx512vl => (0x7, 0, CpuidRegister::Ebx, 31),
+            CpuFeature::LahfLm => (0x8000_0001, 0, CpuidRegister::Ecx, 0),
+            CpuFeature::Abm => (0x8000_0001, 0, CpuidRegister::Ecx, 5),
+            CpuFeature::Rdtscp => (0x8000_0001, 0, CpuidRegister::Edx, 27),
+            CpuFeature::Pdpe1gb => (0x8000_0001, 0, CpuidRegister::Edx, 26),
+        }
+    }
+}
+
+/// CPU model presented to the guest.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CpuModel {
+    /// Expose every feature the host hypervisor supports.
+    #[default]
+    HostPassthrough,
+    /// Baseline x86-64 (SSE2); hides every feature managed by this module.
+    X86_64,
+    /// x86-64-v2 micro-architecture level (SSE4.2, POPCNT, CX16, ...).
+    X86_64V2,
+    /// x86-64-v3 micro-architecture level (AVX2, BMI1/2, FMA, ...).
+    X86_64V3,
+    /// x86-64-v4 micro-architecture level (AVX-512 F/BW/CD/DQ/VL).
+    X86_64V4,
+    /// Exactly the listed managed features are exposed.
+    Custom(Vec<CpuFeature>),
+}
+
+impl CpuModel {
+    /// Parses a CPU model name.
+    ///
+    /// Accepted names are `host-passthrough`, `x86-64`, `x86-64-v2`, `x86-64-v3` and
+    /// `x86-64-v4`. A named model may be followed by comma separated `+flag`/`-flag`
+    /// modifiers (e.g. `x86-64-v2,+aes,-popcnt`), which yields a `CpuModel::Custom`.
+    ///
+    /// # Returns
+    /// * `Ok(CpuModel)` on success
+    /// * `Err(VmError)` if the model or one of the flags is unknown
+    pub fn from_name(name: &str) -> Result<CpuModel, VmError> {
+        let mut parts = name.split(',').map(|p| p.trim());
+        let base = match parts.next().unwrap_or("") {
+            "host-passthrough" | "host" => CpuModel::HostPassthrough,
+            "x86-64" | "x86-64-v1" => CpuModel::X86_64,
+            "x86-64-v2" => CpuModel::X86_64V2,
+            "x86-64-v3" => CpuModel::X86_64V3,
+            "x86-64-v4" => CpuModel::X86_64V4,
+            other => return Err(VmError::config(format!("unknown CPU model {}", other)))
+        };
+
+        let modifiers: Vec<&str> = parts.collect();
+        if modifiers.is_empty() {
+            return Ok(base);
+        }
+
+        let mut features = match base.get_features() {
+            Some(features) => features,
+            None => return Err(VmError::config("host-passthrough CPU model does not accept feature modifiers"))
+        };
+        for modifier in modifiers {
+            let (add, flag) = match modifier.split_at_checked(1) {
+                Some(("+", flag)) => (true, flag),
+                Some(("-", flag)) => (false, flag),
+                _ => return Err(VmError::config(format!("invalid CPU feature modifier {}", modifier)))
+            };
+            let feature = match CpuFeature::from_name(flag) {
+                Some(feature) => feature,
+                None => return Err(VmError::config(format!("unknown CPU feature {}", flag)))
+            };
+            features.retain(|f| *f != feature);
+            if add {
+                features.push(feature);
+            }
+        }
+
+        Ok(CpuModel::Custom(features))
+    }
+
+    /// Returns the managed features visible to the guest, or `None` for
+    /// `HostPassthrough` where nothing is filtered.
+    pub fn get_features(&self) -> Option<Vec<CpuFeature>> {
+        let mut features = Vec::new();
+        match self {
+            CpuModel::HostPassthrough => return None,
+            CpuModel::Custom(custom) => return Some(custom.clone()),
+            CpuModel::X86_64 => {},
+            CpuModel::X86_64V2 => features.extend_from_slice(&X86_64_V2_FEATURES),
+            CpuModel::X86_64V3 => {
+                features.extend_from_slice(&X86_64_V2_FEATURES);
+                features.extend_from_slice(&X86_64_V3_FEATURES);
+            },
+            CpuModel::X86_64V4 => {
+                features.extend_from_slice(&X86_64_V2_FEATURES);
+                features.extend_from_slice(&X86_64_V3_FEATURES);
+                features.extend_from_slice(&X86_64_V4_FEATURES);
+            },
+        }
+        Some(features)
+    }
+
+    /// Returns the CPUID leaves (subleaf 0) holding managed feature bits.
+    pub fn get_filtered_leaves() -> Vec<u32> {
+        let mut leaves: Vec<u32> = ALL_FEATURES.iter().map(|f| f.location().0).collect();
+        leaves.dedup();
+        leaves
+    }
+
+    /// Clears every managed feature bit of `entry` that the model doesn't expose.
+    ///
+    /// Bits of features the host doesn't have are never set, so a model only hides
+    /// features and the result is always supported by the host.
+    pub fn apply(&self, entry: &mut CpuidEntry) {
+        let visible = match self.get_features() {
+            Some(features) => features,
+            None => return
+        };
+
+        for feature in ALL_FEATURES.iter() {
+            let (leaf, subleaf, register, bit) = feature.location();
+            if leaf != entry.function || subleaf != entry.index || visible.contains(feature) {
+                continue;
+            }
+            let value = match register {
+                CpuidRegister::Eax => &mut entry.eax,
+                CpuidRegister::Ebx => &mut entry.ebx,
+                CpuidRegister::Ecx => &mut entry.ecx,
+                CpuidRegister::Edx => &mut entry.edx,
+            };
+            *value &= !(1u32 << bit);
+        }
+    }
+
+    /// Turns the CPUID result the host would return into the one the guest sees, for
+    /// backends answering CPUID exits themselves.
+    ///
+    /// The managed features the model hides are cleared, leaf 1 reports a hypervisor
+    /// and the hypervisor range holds the signature of this VMM without any further
+    /// leaf. Other leaves, e.g. the vendor string of leaf 0, are the host's.
+    pub fn complete_guest_entry(&self, entry: &mut CpuidEntry) {
+        match entry.function {
+            HYPERVISOR_CPUID_LEAF => {
+                let word = |index: usize| u32::from_le_bytes(HYPERVISOR_SIGNATURE[index * 4..index * 4 + 4].try_into().unwrap());
+                *entry = CpuidEntry { function: entry.function, index: entry.index, eax: HYPERVISOR_CPUID_LEAF, ebx: word(0), ecx: word(1), edx: word(2) };
+            },
+            function if function > HYPERVISOR_CPUID_LEAF && function <= HYPERVISOR_CPUID_LEAF_END => {
+                *entry = CpuidEntry { function, index: entry.index, ..CpuidEntry::default() };
+            },
+            function => {
+                self.apply(entry);
+                if function == 0x1 {
+                    entry.ecx |= 1 << HYPERVISOR_PRESENT_BIT;
+                }
+            }
+        }
+    }
+
+    /// Returns the features of the model missing from the host CPUID `entries`.
+    ///
+    /// A non-empty result means the host cannot run the model faithfully.
+    pub fn get_missing_features(&self, entries: &[CpuidEntry]) -> Vec<CpuFeature> {
+        let visible = match self.get_features() {
+            Some(features) => features,
+            None => return Vec::new()
+        };
+
+        visible.into_iter().filter(|feature| {
+            let (leaf, subleaf, register, bit) = feature.location();
+            let entry = entries.iter().find(|e| e.function == leaf && e.index == subleaf);
+            match entry {
+                Some(e) => {
+                    let value = match register {
+                        CpuidRegister::Eax => e.eax,
+                        CpuidRegister::Ebx => e.ebx,
+                        CpuidRegister::Ecx => e.ecx,
+                        CpuidRegister::Edx => e.edx,
+                    };
+                    value & (1u32 << bit) == 0
+                },
+                None => true
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_entry(function: u32) -> CpuidEntry {
+        CpuidEntry { function, index: 0, eax: u32::MAX, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX }
+    }
+
+    #[test]
+    fn test_host_passthrough_keeps_entry() {
+        let mut entry = full_entry(0x7);
+        CpuModel::HostPassthrough.apply(&mut entry);
+        assert_eq!(entry, full_entry(0x7));
+    }
+
+    #[test]
+    fn test_v2_hides_avx_but_keeps_sse42() {
+        let mut leaf1 = full_entry(0x1);
+        let mut leaf7 = full_entry(0x7);
+        CpuModel::X86_64V2.apply(&mut leaf1);
+        CpuModel::X86_64V2.apply(&mut leaf7);
+
+        assert_ne!(leaf1.ecx & (1 << 20), 0, "SSE4.2 should stay visible");
+        assert_eq!(leaf1.ecx & (1 << 28), 0, "AVX should be hidden");
+        assert_eq!(leaf7.ebx & (1 << 5), 0, "AVX2 should be hidden");
+        // Unmanaged bits are left untouched
+        assert_eq!(leaf1.edx, u32::MAX);
+    }
+
+    #[test]
+    fn test_from_name_with_modifiers() {
+        let model = CpuModel::from_name("x86-64-v2,+aes,-popcnt").unwrap();
+        let features = model.get_features().unwrap();
+        assert!(features.contains(&CpuFeature::Aes));
+        assert!(!features.contains(&CpuFeature::Popcnt));
+        assert!(features.contains(&CpuFeature::Sse42));
+
+        assert_eq!(CpuModel::from_name("x86-64-v3").unwrap(), CpuModel::X86_64V3);
+        assert!(CpuModel::from_name("pentium").is_err());
+        assert!(CpuModel::from_name("x86-64,+nosuchflag").is_err());
+        assert!(CpuModel::from_name("host-passthrough,+aes").is_err());
+    }
+
+    #[test]
+    fn test_complete_guest_entry() {
+        let mut leaf1 = full_entry(0x1);
+        leaf1.ecx &= !(1 << HYPERVISOR_PRESENT_BIT);
+        CpuModel::X86_64.complete_guest_entry(&mut leaf1);
+        assert_ne!(leaf1.ecx & (1 << HYPERVISOR_PRESENT_BIT), 0, "The guest should see a hypervisor");
+        assert_eq!(leaf1.ecx & (1 << 20), 0, "SSE4.2 should be hidden");
+
+        let mut signature = full_entry(HYPERVISOR_CPUID_LEAF);
+        CpuModel::HostPassthrough.complete_guest_entry(&mut signature);
+        assert_eq!(signature.eax, HYPERVISOR_CPUID_LEAF);
+        let mut bytes = Vec::new();
+        for value in [signature.ebx, signature.ecx, signature.edx] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(bytes, HYPERVISOR_SIGNATURE);
+
+        let mut features = full_entry(HYPERVISOR_CPUID_LEAF + 1);
+        CpuModel::HostPassthrough.complete_guest_entry(&mut features);
+        assert_eq!(features, CpuidEntry { function: HYPERVISOR_CPUID_LEAF + 1, ..CpuidEntry::default() });
+
+        // Leaf 0 keeps the vendor string of the host
+        let mut vendor = full_entry(0x0);
+        CpuModel::X86_64V4.complete_guest_entry(&mut vendor);
+        assert_eq!(vendor, full_entry(0x0));
+    }
+
+    #[test]
+    fn test_missing_features() {
+        let mut leaf1 = full_entry(0x1);
+        leaf1.ecx &= !(1 << 23); // host without POPCNT
+        let missing = CpuModel::X86_64V2.get_missing_features(&[leaf1, full_entry(0x8000_0001)]);
+        assert_eq!(missing, vec![CpuFeature::Popcnt]);
+    }
+}
@@ -1,5 +1,14 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
 use memmap2::{MmapOptions, MmapMut};
+use crate::device_emulation::block_device::disk_backend::{DiskBackend, FileBackend, MmapBackend};
+use crate::device_emulation::block_device::qcow2::Qcow2Backend;
+use crate::error::VmError;
+use crate::image::format::{detect_image_format, ImageFormat};
+use crate::utils::chunk_store::copy_sparse;
+use crate::vm_manager::bundle::is_valid_name;
+use crate::vm_setup::setup_utils::{DiskBackendType, DiskDevice};
 
 /// Creates a disk image file with the specified path and size.
 /// 
@@ -12,8 +21,8 @@ use memmap2::{MmapOptions, MmapMut};
 ///
 /// # Returns
 /// * `Ok(())` on success
-/// * `Err(String)` if the file couldn't be created or resized
-fn create_disk_image(path: &str, size: u64) -> Result<(), String> {
+/// * `Err(VmError)` if the file couldn't be created or resized
+fn create_disk_image(path: &str, size: u64) -> Result<(), VmError> {
     let path_with_img_extension = format!("{}{}", path, ".img"); // Append `.img` to the filename
 
     // Try opening the file for writing, creating it if it doesn't exist
@@ -22,12 +31,12 @@ fn create_disk_image(path: &str, size: u64) -> Result<(), String> {
         .create(true)         // Create the file if it doesn't exist
         .open(&path_with_img_extension.as_str()) { // Try to open it, or return an error
         Ok(file) => file,
-        Err(e) => return Err(format!("{:?}", e))
+        Err(e) => return Err(VmError::io(format!("failed to create disk image {}: {}", path_with_img_extension, e), e))
     };         
 
     // Resize the file to the requested length in bytes
     if let Err(e) = file.set_len(size) {
-        return Err(format!("{:?}", e));
+        return Err(VmError::io(format!("failed to resize disk image {}: {}", path_with_img_extension, e), e));
     }      
 
     Ok(())
@@ -35,33 +44,372 @@ fn create_disk_image(path: &str, size: u64) -> Result<(), String> {
 
 /// Memory-maps a disk image as a mutable buffer for direct access.
 ///
-/// Ensures the file ends in `.img` before attempting to open and map it.
+/// Only images the guest sees byte for byte can be mapped: raw disks and ISOs. The
+/// format is detected from the content, whatever the file is called.
 ///
 /// # Arguments
-/// * `path` - Path to a `.img` disk image file
+/// * `path` - Path to a raw disk image or ISO
 ///
 /// # Returns
 /// * `Ok(MmapMut)` containing the memory-mapped contents of the image
-/// * `Err(String)` if file access or mapping fails
-pub fn map_disk_image(path: &str) -> Result<MmapMut, String> {
-    // Validate file extension
-    if !path.ends_with(".img") {
-        return Err(format!("passed path is not path to .img file"));
+/// * `Err(VmError)` if the image has another format, or file access or mapping fails
+pub fn map_disk_image(path: &str) -> Result<MmapMut, VmError> {
+    // Validate image format
+    let format = detect_image_format(Path::new(path))?;
+    if !format.is_raw() {
+        return Err(VmError::image(format!("{} is a {} image, not a raw disk image", path, format.as_str())));
     }
 
     // Open file for both reading and writing
     let file = match File::options().read(true).write(true).open(path) {
         Ok(file) => file,
-        Err(e) => return Err(format!("{:?}", e))
+        Err(e) => return Err(VmError::io(format!("failed to open disk image {}: {}", path, e), e))
     };
 
     // Map the file into memory as a writable buffer
     match unsafe { MmapOptions::new().map_mut(&file) } {
         Ok(mmap) => Ok(mmap),
-        Err(e) => Err(format!("{:?}", e))
+        Err(e) => Err(VmError::io(format!("failed to map disk image {}: {}", path, e), e))
     }
 }
 
+/// Opens a disk image as the storage of a block device, picking the backend from
+/// the image's content: raw disks and ISOs are mapped, qcow2 images are read and
+/// written natively.
+///
+/// # Arguments
+/// * `path` - Path to the disk image
+///
+/// # Returns
+/// * `Ok(Box<dyn DiskBackend + Send>)` on success
+/// * `Err(VmError)` if the image couldn't be opened or has a format no backend
+///   handles, e.g. VHDX, which has to be converted first
+pub fn open_disk_image(path: &str) -> Result<Box<dyn DiskBackend + Send>, VmError> {
+    match detect_image_format(Path::new(path))? {
+        ImageFormat::Raw | ImageFormat::Iso => Ok(Box::new(MmapBackend::new(map_disk_image(path)?))),
+        ImageFormat::Qcow2 => Ok(Box::new(Qcow2Backend::open(Path::new(path), false)?)),
+        ImageFormat::Vhdx => Err(VmError::image(format!("{} is a VHDX image, convert it with `convert_image` first", path))),
+    }
+}
+
+/// Opens an ISO image as the storage of a read-only block device.
+///
+/// The file is opened for reading only, so guest writes fail and the image is
+/// never modified, even when several VMs share it.
+///
+/// # Arguments
+/// * `path` - Path to the ISO image
+///
+/// # Returns
+/// * `Ok(Box<dyn DiskBackend + Send>)` on success
+/// * `Err(VmError)` if the image couldn't be opened or isn't an ISO
+pub fn open_cdrom_image(path: &str) -> Result<Box<dyn DiskBackend + Send>, VmError> {
+    let format = detect_image_format(Path::new(path))?;
+    if format != ImageFormat::Iso {
+        return Err(VmError::image(format!("{} is a {} image, not an ISO", path, format.as_str())));
+    }
+    match File::open(path) {
+        Ok(file) => Ok(Box::new(FileBackend::from_file(file)?)),
+        Err(e) => Err(VmError::io(format!("failed to open ISO image {}: {}", path, e), e))
+    }
+}
+
+/// Opens the image of a disk with the backend it asks for.
+///
+/// Read-only disks are opened for reading only, so the image is never modified, even
+/// when several VMs share it.
+///
+/// # Arguments
+/// * `disk` - Disk attached to the VM
+///
+/// # Returns
+/// * `Ok(Box<dyn DiskBackend + Send>)` on success
+/// * `Err(VmError)` if the image couldn't be opened, has a format no backend handles,
+///   or needs a backend that isn't available
+pub fn open_disk(disk: &DiskDevice) -> Result<Box<dyn DiskBackend + Send>, VmError> {
+    let path = disk.get_path();
+    let format = detect_image_format(path)?;
+    if format == ImageFormat::Vhdx {
+        return Err(VmError::image(format!("{} is a VHDX image, convert it with `convert_image` first", path.display())));
+    }
+    if format == ImageFormat::Qcow2 && disk.get_backend() != DiskBackendType::Auto {
+        return Err(VmError::config(format!("{} is a qcow2 image, which only the Auto backend serves", path.display())));
+    }
+
+    match disk.get_backend() {
+        DiskBackendType::Auto if format == ImageFormat::Qcow2 => Ok(Box::new(Qcow2Backend::open(path, disk.is_read_only())?)),
+        DiskBackendType::Auto if !disk.is_read_only() => Ok(Box::new(MmapBackend::new(map_disk_image(&path.to_string_lossy())?))),
+        DiskBackendType::Auto | DiskBackendType::File => {
+            let opened = if disk.is_read_only() {
+                File::open(path)
+            } else {
+                OpenOptions::new().read(true).write(true).open(path)
+            };
+            match opened {
+                Ok(file) => Ok(Box::new(FileBackend::from_file(file)?)),
+                Err(e) => Err(VmError::io(format!("failed to open disk image {}: {}", path.display(), e), e))
+            }
+        },
+        #[cfg(all(target_os = "linux", feature = "linux_io_uring"))]
+        DiskBackendType::IoUring => {
+            use crate::device_emulation::block_device::uring::{IoUringBackend, IoUringOptions};
+            Ok(Box::new(IoUringBackend::new(&path.to_string_lossy(), IoUringOptions::default())?))
+        },
+        #[cfg(not(all(target_os = "linux", feature = "linux_io_uring")))]
+        DiskBackendType::IoUring => {
+            Err(VmError::config(format!("{} needs the io_uring backend, build with the `linux_io_uring` feature", path.display())))
+        },
+    }
+}
+
+/// Releases all storage blocks of a disk image while keeping its apparent size.
+///
+/// On Linux this punches a hole over the whole file with `fallocate`, which reclaims
+/// space even if another process still holds the image open or mapped. On other
+/// platforms the file is truncated to zero length instead.
+///
+/// # Arguments
+/// * `path` - Path to the disk image file
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the file couldn't be opened or deallocated
+pub fn trim_disk_image(path: &str) -> Result<(), VmError> {
+    let file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open disk image {}: {}", path, e), e))
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let len = match file.metadata() {
+            Ok(m) => m.len(),
+            Err(e) => return Err(VmError::io(format!("failed to read disk image metadata: {}", e), e))
+        };
+
+        // Nothing to deallocate in an empty file
+        if len == 0 {
+            return Ok(());
+        }
+
+        // SAFETY: the descriptor is valid for the lifetime of `file`
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                len as libc::off_t,
+            )
+        };
+        if result != 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(VmError::io(format!("failed to punch hole in disk image: {}", e), e));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        match file.set_len(0) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(VmError::io(format!("failed to truncate disk image: {}", e), e))
+        }
+    }
+}
+
+/// Overwrites the whole disk image with zeroes and syncs it to stable storage.
+///
+/// # Arguments
+/// * `path` - Path to the disk image file
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the file couldn't be opened, written or synced
+pub fn secure_erase_disk_image(path: &str) -> Result<(), VmError> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open disk image {}: {}", path, e), e))
+    };
+
+    let len = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(e) => return Err(VmError::io(format!("failed to read disk image metadata: {}", e), e))
+    };
+
+    // Overwrite in 1 MiB chunks to keep memory usage bounded for multi-GB images
+    let zeroes = vec![0u8; 1024 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(zeroes.len() as u64) as usize;
+        if let Err(e) = file.write_all(&zeroes[..chunk]) {
+            return Err(VmError::io(format!("failed to overwrite disk image: {}", e), e));
+        }
+        remaining -= chunk as u64;
+    }
+
+    match file.sync_all() {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to sync disk image: {}", e), e))
+    }
+}
+
+/// Extension of the snapshots taken with `snapshot_disk_image`.
+pub const DISK_SNAPSHOT_EXTENSION: &str = "snapshot";
+
+/// Creates `target` as a copy-on-write clone of `source`, falling back to a sparse
+/// copy on filesystems that can't share blocks between files.
+///
+/// # Arguments
+/// * `source` - Path to the disk image to clone; it is never modified
+/// * `target` - Path of the clone, which must not exist yet
+///
+/// # Returns
+/// * `Ok(())` once the clone holds the content of the source
+/// * `Err(VmError)` if the source couldn't be read, the target already exists or
+///   couldn't be written; a partial target is removed
+pub fn clone_disk_image(source: &str, target: &str) -> Result<(), VmError> {
+    let mut source_file = match File::open(source) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open disk image {}: {}", source, e), e))
+    };
+    let mut target_file = match OpenOptions::new().write(true).create_new(true).open(target) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to create disk image {}: {}", target, e), e))
+    };
+
+    #[cfg(target_os = "linux")]
+    if clone_file(&source_file, &target_file) {
+        return Ok(());
+    }
+
+    if let Err(e) = copy_sparse(&mut source_file, &mut target_file) {
+        drop(target_file);
+        let _ = remove_file(target);
+        return Err(VmError::io(format!("failed to copy {} to {}: {}", source, target, e), e));
+    }
+    Ok(())
+}
+
+/// Returns the path of the snapshot `name` of the disk image at `path`.
+pub fn get_disk_snapshot_path(path: &str, name: &str) -> String {
+    format!("{}.{}.{}", path, name, DISK_SNAPSHOT_EXTENSION)
+}
+
+/// Captures the current state of a disk image that no VM is using as the snapshot
+/// `name`, next to the image.
+///
+/// The snapshot is a clone of the image, so on filesystems sharing blocks between
+/// files (Btrfs, XFS) it only takes the space of the blocks changed later. For
+/// snapshots of a disk in use, see `SnapshotDisk`.
+///
+/// # Arguments
+/// * `path` - Path to the disk image file
+/// * `name` - Name of the snapshot
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the name is invalid or taken, or the image couldn't be cloned
+pub fn snapshot_disk_image(path: &str, name: &str) -> Result<(), VmError> {
+    if !is_valid_name(name) {
+        return Err(VmError::config(format!("invalid snapshot name {:?}", name)));
+    }
+    clone_disk_image(path, &get_disk_snapshot_path(path, name))
+}
+
+/// Returns the names of the snapshots taken of the disk image at `path` with
+/// `snapshot_disk_image`, sorted.
+///
+/// # Returns
+/// * `Ok(names)` on success
+/// * `Err(VmError)` if the directory of the image couldn't be listed
+pub fn list_disk_snapshots(path: &str) -> Result<Vec<String>, VmError> {
+    let path = Path::new(path);
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Err(VmError::config(format!("invalid disk image path {}", path.display())));
+    };
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => return Err(VmError::io(format!("failed to list {}: {}", directory.display(), e), e))
+    };
+
+    let prefix = format!("{}.", file_name);
+    let suffix = format!(".{}", DISK_SNAPSHOT_EXTENSION);
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|entry| Some(entry.strip_prefix(&prefix)?.strip_suffix(&suffix)?.to_string()))
+        .filter(|name| is_valid_name(name))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Deletes the snapshot `name` of the disk image at `path`.
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the name is invalid or the snapshot couldn't be deleted
+pub fn remove_disk_snapshot(path: &str, name: &str) -> Result<(), VmError> {
+    if !is_valid_name(name) {
+        return Err(VmError::config(format!("invalid snapshot name {:?}", name)));
+    }
+    let snapshot = get_disk_snapshot_path(path, name);
+    match remove_file(&snapshot) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to remove snapshot {}: {}", snapshot, e), e))
+    }
+}
+
+/// Rolls a disk image that no VM is using back to its snapshot `name`.
+///
+/// The image is replaced atomically by a clone of the snapshot, which is kept so the
+/// image can be reverted again, e.g. between test runs.
+///
+/// # Arguments
+/// * `path` - Path to the disk image file
+/// * `name` - Name of a snapshot taken with `snapshot_disk_image`
+///
+/// # Returns
+/// * `Ok(())` once the image holds the contents of the snapshot
+/// * `Err(VmError)` if the snapshot doesn't exist or the image couldn't be replaced
+pub fn revert_disk_image(path: &str, name: &str) -> Result<(), VmError> {
+    let snapshot = get_disk_snapshot_path(path, name);
+    if !Path::new(&snapshot).is_file() {
+        return Err(VmError::image(format!("snapshot {} of {} doesn't exist", name, path)));
+    }
+
+    let staged = format!("{}.revert", path);
+    let _ = remove_file(&staged);
+    clone_disk_image(&snapshot, &staged)?;
+    if let Err(e) = rename(&staged, path) {
+        let _ = remove_file(&staged);
+        return Err(VmError::io(format!("failed to replace disk image {}: {}", path, e), e));
+    }
+    Ok(())
+}
+
+/// Makes the empty `target` share all blocks of `source` (`FICLONE`), on filesystems
+/// like Btrfs or XFS.
+///
+/// # Returns
+/// * `true` if the clone succeeded, `false` if the filesystem can't do it
+#[cfg(target_os = "linux")]
+fn clone_file(source: &File, target: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+    // _IOW(0x94, 9, int) from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    // SAFETY: both descriptors are valid for the lifetime of the borrowed files
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+    result == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,15 +502,148 @@ mod tests {
         let result = map_disk_image("non_existent_file.img");
         assert!(result.is_err());
 
-        // Error should not be due to extension but due to missing file
-        assert!(!result.unwrap_err().contains("passed path is not path to .img file"))
+        // Error should not be due to the format but due to missing file
+        assert!(!result.unwrap_err().to_string().contains("not a raw disk image"))
     }
 
     #[test]
-    fn test_map_disk_image_failure_cause_wrong_path() {
-        // Path does not end with .img, should return extension error
-        let result = map_disk_image("existing_file.png");
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("passed path is not path to .img file"));
+    fn test_map_disk_image_detects_format_from_content() {
+        let dir = tempfile::TempDir::new().unwrap();
+        // Raw disks map whatever their extension
+        let raw = dir.path().join("disk.bin").to_string_lossy().into_owned();
+        std::fs::write(&raw, vec![0u8; 4096]).unwrap();
+        assert_eq!(map_disk_image(&raw).expect("Mapping should succeed").len(), 4096);
+
+        // qcow2 images don't map even when named like raw disks, but open natively
+        let qcow2 = dir.path().join("debian.img");
+        Qcow2Backend::create(&qcow2, 1 << 20).unwrap();
+        let qcow2 = qcow2.to_string_lossy().into_owned();
+        let result = map_disk_image(&qcow2);
+        assert!(result.unwrap_err().to_string().contains("is a qcow2 image, not a raw disk image"));
+        assert_eq!(open_disk_image(&qcow2).expect("Opening should succeed").capacity(), 2048);
+        assert_eq!(open_disk_image(&raw).unwrap().capacity(), 8);
+    }
+
+    #[test]
+    fn test_open_disk() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let raw = dir.path().join("data.img");
+        std::fs::write(&raw, vec![0u8; 4096]).unwrap();
+        let qcow2 = dir.path().join("system.qcow2");
+        Qcow2Backend::create(&qcow2, 1 << 20).unwrap();
+
+        let mut disk = open_disk(&DiskDevice::new(&raw)).expect("Opening should succeed");
+        assert!(disk.as_mapped().is_some());
+        let mut disk = open_disk(&DiskDevice::new(&raw).backend(DiskBackendType::File)).unwrap();
+        assert!(disk.as_mapped().is_none());
+        disk.write_sectors(0, &[0xAB; 512]).expect("Writable disks take writes");
+        assert_eq!(open_disk(&DiskDevice::new(&qcow2).read_only(true)).unwrap().capacity(), 2048);
+
+        // Read-only disks are opened for reading only
+        let mut disk = open_disk(&DiskDevice::new(&raw).read_only(true)).unwrap();
+        assert!(disk.write_sectors(0, &[0; 512]).is_err());
+        let mut buf = [0u8; 512];
+        disk.read_sectors(0, &mut buf).unwrap();
+        assert_eq!(buf, [0xAB; 512]);
+
+        let err = open_disk(&DiskDevice::new(&qcow2).backend(DiskBackendType::File)).err().expect("qcow2 needs the Auto backend");
+        assert!(err.to_string().contains("only the Auto backend serves"));
+        #[cfg(not(all(target_os = "linux", feature = "linux_io_uring")))]
+        assert!(open_disk(&DiskDevice::new(&raw).backend(DiskBackendType::IoUring)).is_err());
+    }
+
+    #[test]
+    fn test_trim_disk_image_keeps_size_and_zeroes_content() {
+        let path = "trim_test";
+        let full_path = format!("{}.img", path);
+        let _ = remove_file(&full_path);
+        create_disk_image(path, 8192).unwrap();
+        std::fs::write(&full_path, vec![0xAB; 8192]).unwrap();
+
+        let result = trim_disk_image(&full_path);
+        assert!(result.is_ok(), "Expected Ok, got {:?}", result);
+
+        let contents = std::fs::read(&full_path).unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(contents, vec![0u8; 8192]);
+        #[cfg(not(target_os = "linux"))]
+        assert!(contents.is_empty());
+
+        let _ = remove_file(&full_path);
+    }
+
+    #[test]
+    fn test_secure_erase_disk_image_zeroes_content() {
+        let path = "erase_test";
+        let full_path = format!("{}.img", path);
+        let _ = remove_file(&full_path);
+        std::fs::write(&full_path, vec![0xCD; 3 * 1024 * 1024 + 17]).unwrap();
+
+        let result = secure_erase_disk_image(&full_path);
+        assert!(result.is_ok(), "Expected Ok, got {:?}", result);
+
+        let contents = std::fs::read(&full_path).unwrap();
+        assert_eq!(contents.len(), 3 * 1024 * 1024 + 17);
+        assert!(contents.iter().all(|b| *b == 0));
+
+        let _ = remove_file(&full_path);
+    }
+
+    #[test]
+    fn test_clone_disk_image() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let base = dir.path().join("base.img").to_string_lossy().into_owned();
+        let clone = dir.path().join("clone.img").to_string_lossy().into_owned();
+        let mut content = vec![0u8; 256 * 1024];
+        content[..4].copy_from_slice(b"boot");
+        content[200 * 1024] = 7;
+        std::fs::write(&base, &content).unwrap();
+
+        clone_disk_image(&base, &clone).expect("Clone should be created");
+        assert_eq!(std::fs::read(&clone).unwrap(), content);
+
+        // Writes to the clone don't reach the source
+        std::fs::write(&clone, b"changed").unwrap();
+        assert_eq!(std::fs::read(&base).unwrap(), content);
+
+        // An existing file is never replaced
+        assert!(clone_disk_image(&base, &clone).is_err());
+        assert_eq!(std::fs::read(&clone).unwrap(), b"changed");
+        let other = dir.path().join("other.img").to_string_lossy().into_owned();
+        assert!(clone_disk_image(&format!("{}.missing", base), &other).is_err());
+        assert!(!Path::new(&other).exists());
+    }
+
+    #[test]
+    fn test_snapshot_and_revert_disk_image() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let image = dir.path().join("disk.img").to_string_lossy().into_owned();
+        std::fs::write(&image, b"clean").unwrap();
+
+        snapshot_disk_image(&image, "clean").expect("Snapshot should succeed");
+        assert!(snapshot_disk_image(&image, "clean").is_err());
+        assert!(snapshot_disk_image(&image, "../clean").is_err());
+        assert!(Path::new(&get_disk_snapshot_path(&image, "clean")).is_file());
+
+        for _ in 0..2 {
+            std::fs::write(&image, b"dirty").unwrap();
+            revert_disk_image(&image, "clean").expect("Revert should succeed");
+            assert_eq!(std::fs::read(&image).unwrap(), b"clean");
+        }
+        assert!(revert_disk_image(&image, "missing").is_err());
+
+        snapshot_disk_image(&image, "before-upgrade").unwrap();
+        std::fs::write(dir.path().join("disk.img.old.img"), b"other disk").unwrap();
+        assert_eq!(list_disk_snapshots(&image).unwrap(), vec!["before-upgrade", "clean"]);
+        remove_disk_snapshot(&image, "clean").expect("Removing the snapshot should succeed");
+        assert_eq!(list_disk_snapshots(&image).unwrap(), vec!["before-upgrade"]);
+        assert!(remove_disk_snapshot(&image, "clean").is_err());
+        assert!(remove_disk_snapshot(&image, "../disk").is_err());
+    }
+
+    #[test]
+    fn test_trim_and_erase_missing_file() {
+        assert!(trim_disk_image("missing_trim.img").is_err());
+        assert!(secure_erase_disk_image("missing_erase.img").is_err());
     }
 }
\ No newline at end of file
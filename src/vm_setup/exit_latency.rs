@@ -0,0 +1,310 @@
+//! Host time spent handling vCPU exits, by device and kind of exit.
+//!
+//! Every port and MMIO access a vCPU loop dispatches to a device is timed while the
+//! device serves it, lock included, and recorded in a `LatencyHistogram` for the
+//! device and kind of access. On Windows the instruction
+//! emulator is timed as a whole, decoding included. Exits the hypervisor backend
+//! serves without a device (interrupt controller registers, PSCI calls) aren't timed.
+//!
+//! The histograms are HDR-style: values are bucketed with a relative error below 1/32
+//! over the whole range of a `u64` of nanoseconds, so recording stays cheap and
+//! constant-size no matter how long the VM runs. `VmHandle::get_exit_latencies` reads
+//! them while the VM runs and `VmExitSummary::exit_latencies` once it exited, the
+//! device where the vCPUs spent the most time first.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Bits of a value kept below its most significant bit, giving 32 buckets per power of two.
+const SUB_BUCKET_BITS: u32 = 5;
+
+/// Number of buckets per power of two.
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+
+/// Values below this are counted exactly, one bucket per nanosecond.
+const LINEAR_LIMIT: u64 = 2 << SUB_BUCKET_BITS;
+
+/// Number of vCPUs recording without sharing a lock; vCPUs beyond share the shards.
+const SHARD_COUNT: usize = 64;
+
+/// Histogram of durations with a bounded relative error, in nanoseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LatencyHistogram {
+    /// Counts by bucket, only as long as the highest bucket recorded
+    counts: Vec<u64>,
+    count: u64,
+    total: u128,
+    min: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Self {
+        LatencyHistogram::default()
+    }
+
+    /// Records one duration.
+    pub fn record(&mut self, duration: Duration) {
+        let value = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let index = bucket_index(value);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.min = if self.count == 0 { value } else { self.min.min(value) };
+        self.max = self.max.max(value);
+        self.count += 1;
+        self.total += value as u128;
+    }
+
+    /// Adds the durations recorded in `other` to this histogram.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        if other.count == 0 {
+            return;
+        }
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        self.min = if self.count == 0 { other.min } else { self.min.min(other.min) };
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.total += other.total;
+    }
+
+    /// Returns the number of durations recorded.
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of the durations recorded.
+    pub fn get_total(&self) -> Duration {
+        Duration::from_nanos(u64::try_from(self.total).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the shortest duration recorded, zero if none was.
+    pub fn get_min(&self) -> Duration {
+        Duration::from_nanos(self.min)
+    }
+
+    /// Returns the longest duration recorded, zero if none was.
+    pub fn get_max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns the mean of the durations recorded, zero if none was.
+    pub fn get_mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total / count as u128) as u64),
+        }
+    }
+
+    /// Returns the duration `quantile` of the recorded durations are at most, e.g. the
+    /// 99th percentile for `0.99`, within the precision of the buckets.
+    ///
+    /// # Arguments
+    /// * `quantile` - Fraction of the durations, clamped to `0.0..=1.0`
+    ///
+    /// # Returns
+    /// * The highest duration of the bucket holding the quantile, capped at the longest
+    ///   duration recorded; zero if none was
+    pub fn get_value_at_quantile(&self, quantile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_highest(index).min(self.max));
+            }
+        }
+        Duration::from_nanos(self.max)
+    }
+}
+
+/// Returns the bucket counting `value`.
+fn bucket_index(value: u64) -> usize {
+    if value < LINEAR_LIMIT {
+        return value as usize;
+    }
+    // Keep the most significant bit and the SUB_BUCKET_BITS below it
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize - SUB_BUCKET_COUNT;
+    LINEAR_LIMIT as usize + (exponent - SUB_BUCKET_BITS - 1) as usize * SUB_BUCKET_COUNT + sub_bucket
+}
+
+/// Returns the highest value counted in bucket `index`.
+fn bucket_highest(index: usize) -> u64 {
+    if index < LINEAR_LIMIT as usize {
+        return index as u64;
+    }
+    let index = index - LINEAR_LIMIT as usize;
+    let shift = (index / SUB_BUCKET_COUNT) as u32 + 1;
+    let lowest = ((SUB_BUCKET_COUNT + index % SUB_BUCKET_COUNT) as u64) << shift;
+    lowest + ((1u64 << shift) - 1)
+}
+
+/// Kind of exit a device served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExitKind {
+    /// Guest `in` from an I/O port
+    PortRead,
+    /// Guest `out` to an I/O port
+    PortWrite,
+    /// Guest read of a device register
+    MmioRead,
+    /// Guest write to a device register
+    MmioWrite,
+    /// Memory access completed by the hypervisor's instruction emulator (WHP), decoding included
+    EmulatedMmio,
+}
+
+impl ExitKind {
+    /// Returns the name of the kind, as used in metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExitKind::PortRead => "pio-read",
+            ExitKind::PortWrite => "pio-write",
+            ExitKind::MmioRead => "mmio-read",
+            ExitKind::MmioWrite => "mmio-write",
+            ExitKind::EmulatedMmio => "emulated-mmio",
+        }
+    }
+}
+
+/// Time a device spent serving one kind of exit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitLatency {
+    /// Name of the device, e.g. `virtio-blk0`, or `unclaimed` for accesses no device claims
+    pub device: String,
+    /// Kind of exit
+    pub kind: ExitKind,
+    /// Host time spent per exit
+    pub histogram: LatencyHistogram,
+}
+
+/// Histograms recorded by the vCPUs of a shard, by device and kind of exit.
+type ShardLatencies = BTreeMap<String, BTreeMap<ExitKind, LatencyHistogram>>;
+
+/// Exit latencies of a VM, shared between the `VmHandle` and the vCPU loops.
+///
+/// Each vCPU records into its own shard, so vCPUs don't contend on a lock on every exit.
+pub struct ExitLatencies {
+    shards: Vec<Mutex<ShardLatencies>>,
+}
+
+impl Default for ExitLatencies {
+    fn default() -> Self {
+        ExitLatencies { shards: (0..SHARD_COUNT).map(|_| Mutex::new(BTreeMap::new())).collect() }
+    }
+}
+
+impl ExitLatencies {
+    /// Creates an empty set of histograms.
+    pub fn new() -> Self {
+        ExitLatencies::default()
+    }
+
+    /// Records the host time vCPU `vcpu` spent in `device` serving an exit of `kind`.
+    pub fn record(&self, vcpu: u32, device: &str, kind: ExitKind, elapsed: Duration) {
+        let mut shard = self.shards[vcpu as usize % SHARD_COUNT].lock().unwrap_or_else(|e| e.into_inner());
+        let histograms = match shard.get_mut(device) {
+            Some(histograms) => histograms,
+            None => shard.entry(device.to_string()).or_default(),
+        };
+        histograms.entry(kind).or_default().record(elapsed);
+    }
+
+    /// Returns the histograms of all vCPUs, the device and kind of exit the vCPUs spent
+    /// the most time in first.
+    pub fn snapshot(&self) -> Vec<ExitLatency> {
+        let mut merged: BTreeMap<(String, ExitKind), LatencyHistogram> = BTreeMap::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+            for (device, histograms) in shard.iter() {
+                for (kind, histogram) in histograms {
+                    merged.entry((device.clone(), *kind)).or_default().merge(histogram);
+                }
+            }
+        }
+        let mut latencies: Vec<ExitLatency> = merged.into_iter()
+            .map(|((device, kind), histogram)| ExitLatency { device, kind, histogram })
+            .collect();
+        latencies.sort_by_key(|latency| Reverse(latency.histogram.get_total()));
+        latencies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_cover_values_with_bounded_error() {
+        let mut previous = 0;
+        for value in (0..4096).chain([1_000_000, 123_456_789, u64::MAX / 3, u64::MAX]) {
+            let index = bucket_index(value);
+            assert!(index >= previous);
+            previous = index;
+            let highest = bucket_highest(index);
+            assert!(highest >= value);
+            assert!(highest - value <= value / SUB_BUCKET_COUNT as u64, "value {} in bucket up to {}", value, highest);
+        }
+        assert_eq!(bucket_highest(bucket_index(63)), 63);
+        assert_eq!(bucket_index(64), LINEAR_LIMIT as usize);
+    }
+
+    #[test]
+    fn test_histogram_quantiles() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.get_value_at_quantile(0.5), Duration::ZERO);
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.get_count(), 100);
+        assert_eq!(histogram.get_min(), Duration::from_micros(1));
+        assert_eq!(histogram.get_max(), Duration::from_micros(100));
+        assert_eq!(histogram.get_mean(), Duration::from_nanos(50_500));
+        assert_eq!(histogram.get_value_at_quantile(1.0), Duration::from_micros(100));
+        let median = histogram.get_value_at_quantile(0.5).as_nanos();
+        assert!((50_000..=50_000 + 50_000 / 32).contains(&median), "median {}", median);
+        let p99 = histogram.get_value_at_quantile(0.99).as_nanos();
+        assert!((99_000..=99_000 + 99_000 / 32).contains(&p99), "p99 {}", p99);
+
+        let mut other = LatencyHistogram::new();
+        other.record(Duration::from_millis(5));
+        histogram.merge(&other);
+        assert_eq!(histogram.get_count(), 101);
+        assert_eq!(histogram.get_max(), Duration::from_millis(5));
+        assert_eq!(histogram.get_value_at_quantile(1.0), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_snapshot_merges_vcpus_and_sorts_by_total_time() {
+        let latencies = ExitLatencies::new();
+        latencies.record(0, "serial0", ExitKind::PortWrite, Duration::from_micros(2));
+        latencies.record(1, "serial0", ExitKind::PortWrite, Duration::from_micros(3));
+        latencies.record(1, "virtio-blk0", ExitKind::MmioWrite, Duration::from_micros(40));
+        latencies.record(0, "virtio-blk0", ExitKind::MmioRead, Duration::from_micros(1));
+
+        let snapshot = latencies.snapshot();
+        let entries: Vec<(&str, ExitKind, u64)> = snapshot.iter()
+            .map(|latency| (latency.device.as_str(), latency.kind, latency.histogram.get_count()))
+            .collect();
+        assert_eq!(entries, vec![
+            ("virtio-blk0", ExitKind::MmioWrite, 1),
+            ("serial0", ExitKind::PortWrite, 2),
+            ("virtio-blk0", ExitKind::MmioRead, 1),
+        ]);
+        assert_eq!(snapshot[1].histogram.get_total(), Duration::from_micros(5));
+    }
+}
@@ -0,0 +1,149 @@
+//! Outcome of a VM run, the same on every hypervisor backend.
+//!
+//! Each backend maps the exits of its vCPU loops onto `VcpuExitReason`, so callers
+//! can tell a guest shutdown from a reset or a stop request without knowing which
+//! hypervisor ran the VM.
+
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::error::VmError;
+use crate::vm_setup::exit_latency::ExitLatency;
+
+/// Why a vCPU loop ended.
+///
+/// The variants are ordered by how much they say about the VM as a whole, see
+/// `VmExitSummary::get_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VcpuExitReason {
+    /// The VM was stopped through its `VmHandle` or by another vCPU
+    Stopped,
+    /// The vCPU executed a halt instruction with nothing to wake it up
+    Halted,
+    /// The guest powered this vCPU off (PSCI `CPU_OFF`)
+    PoweredOff,
+    /// The guest shut the system down (PSCI `SYSTEM_OFF`, or a triple fault on x86)
+    Shutdown,
+    /// The guest asked for the system to be reset
+    Reset,
+    /// The vCPU loop panicked; the other vCPUs kept running, see `VcpuFailure`
+    Panicked,
+}
+
+/// How a single vCPU ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuExit {
+    /// Index of the vCPU
+    pub vcpu: u32,
+    /// Why its loop ended
+    pub reason: VcpuExitReason,
+}
+
+/// Panic caught in a vCPU loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VcpuFailure {
+    /// Index of the vCPU
+    pub vcpu: u32,
+    /// Panic message
+    pub message: String,
+}
+
+/// Statistics of a device, read once the vCPUs exited.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeviceStats {
+    /// Name of the device, e.g. `virtio-blk0`
+    pub name: String,
+    /// Requests the device completed
+    pub completed_requests: u64,
+    /// Highest number of requests in flight at the same time
+    pub peak_inflight: usize,
+    /// Times the device suppressed guest notifications because more requests than its
+    /// queue depth were outstanding
+    pub backpressure_events: u64,
+}
+
+/// Summary of a VM run, returned by `run_vm` and `VmHandle::wait` on every backend.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VmExitSummary {
+    /// Exit of every vCPU, by index
+    pub vcpus: Vec<VcpuExit>,
+    /// Time from the start of the vCPUs until the last one exited, paused time included
+    pub runtime: Duration,
+    /// Statistics of the devices that keep any; empty on backends without emulated devices
+    pub devices: Vec<DeviceStats>,
+    /// Panics caught in the vCPU loops, in the order they happened
+    pub failures: Vec<VcpuFailure>,
+    /// Host time spent serving device exits, the most expensive device and kind of exit first
+    pub exit_latencies: Vec<ExitLatency>,
+}
+
+impl VmExitSummary {
+    /// Returns why the VM ended: the most telling vCPU exit, so a reset or shutdown
+    /// requested by one vCPU wins over the others being stopped as a consequence.
+    /// A VM without vCPUs counts as stopped.
+    pub fn get_reason(&self) -> VcpuExitReason {
+        self.vcpus.iter().map(|exit| exit.reason).max().unwrap_or(VcpuExitReason::Stopped)
+    }
+
+    /// Returns the statistics of the device called `name`, if it keeps any.
+    pub fn get_device_stats(&self, name: &str) -> Option<&DeviceStats> {
+        self.devices.iter().find(|device| device.name == name)
+    }
+}
+
+/// Awaits the vCPU tasks of a VM, where the task at index `i` runs vCPU `i`.
+///
+/// # Returns
+/// * `Ok(Vec<VcpuExit>)` once every vCPU exited cleanly
+/// * `Err(VmError)` with the first vCPU error, or if a task panicked
+pub(crate) async fn join_vcpus(handlers: Vec<JoinHandle<Result<VcpuExitReason, VmError>>>) -> Result<Vec<VcpuExit>, VmError> {
+    let mut exits = Vec::with_capacity(handlers.len());
+    for (vcpu, handler) in handlers.into_iter().enumerate() {
+        match handler.await {
+            Ok(Ok(reason)) => exits.push(VcpuExit { vcpu: vcpu as u32, reason }),
+            Ok(Err(err)) => return Err(err),
+            Err(e) => return Err(VmError::hypervisor_source(format!("VCPU {} task join error: {}", vcpu, e), e)),
+        }
+    }
+    Ok(exits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_reason() {
+        let mut summary = VmExitSummary::default();
+        assert_eq!(summary.get_reason(), VcpuExitReason::Stopped);
+
+        summary.vcpus = vec![
+            VcpuExit { vcpu: 0, reason: VcpuExitReason::Stopped },
+            VcpuExit { vcpu: 1, reason: VcpuExitReason::Reset },
+            VcpuExit { vcpu: 2, reason: VcpuExitReason::Halted },
+        ];
+        assert_eq!(summary.get_reason(), VcpuExitReason::Reset);
+
+        summary.devices.push(DeviceStats { name: "virtio-blk0".to_string(), completed_requests: 3, ..Default::default() });
+        assert_eq!(summary.get_device_stats("virtio-blk0").unwrap().completed_requests, 3);
+        assert!(summary.get_device_stats("virtio-net0").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_join_vcpus() {
+        let handlers = vec![
+            tokio::task::spawn_blocking(|| Ok(VcpuExitReason::Shutdown)),
+            tokio::task::spawn_blocking(|| Ok(VcpuExitReason::Stopped)),
+        ];
+        let exits = join_vcpus(handlers).await.unwrap();
+        assert_eq!(exits, vec![
+            VcpuExit { vcpu: 0, reason: VcpuExitReason::Shutdown },
+            VcpuExit { vcpu: 1, reason: VcpuExitReason::Stopped },
+        ]);
+
+        let handlers = vec![
+            tokio::task::spawn_blocking(|| Ok(VcpuExitReason::Halted)),
+            tokio::task::spawn_blocking(|| Err(VmError::hypervisor("VCPU 1 encountered an internal error"))),
+        ];
+        assert_eq!(join_vcpus(handlers).await.unwrap_err().to_string(), "VCPU 1 encountered an internal error");
+    }
+}
@@ -0,0 +1,350 @@
+//! Recording and replay of vCPU exits.
+//!
+//! Bugs that only show up on some hosts, or once in a hundred boots, depend on the
+//! order in which the guest touched its devices. With `VmSetupBuilder::record_exits`
+//! the KVM backend logs every port and MMIO access of its vCPUs, with the data the
+//! devices returned for reads, and the input fed to the serial console, in the order
+//! they happened. `replay_exit_trace` feeds such a trace to freshly created devices
+//! without a hypervisor and reports the first read whose result differs from the
+//! recording, so the device side of a bug reported from a production host can be
+//! reproduced and debugged locally. Devices completing requests on threads of their
+//! own, like the disks, only replay deterministically as far as the guest waited for
+//! them.
+//!
+//! Traces are text, one event per line, with addresses and data in hexadecimal:
+//! `<vcpu> io-in|io-out|mmio-read|mmio-write <address> <data>`, `<vcpu> exit <reason>`
+//! and `- serial-input <data>`.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use crate::device_emulation::mmio::MmioBus;
+use crate::device_emulation::pio::PortIoBus;
+use crate::device_emulation::serial::Serial;
+use crate::error::VmError;
+use crate::utils::chunk_store::to_hex;
+use crate::vm_setup::exit_summary::VcpuExitReason;
+
+/// Something that happened while a VM ran, in a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// vCPU `vcpu` read `data` from port `port`
+    IoIn { vcpu: u32, port: u16, data: Vec<u8> },
+    /// vCPU `vcpu` wrote `data` to port `port`
+    IoOut { vcpu: u32, port: u16, data: Vec<u8> },
+    /// vCPU `vcpu` read `data` from the MMIO address `address`
+    MmioRead { vcpu: u32, address: u64, data: Vec<u8> },
+    /// vCPU `vcpu` wrote `data` to the MMIO address `address`
+    MmioWrite { vcpu: u32, address: u64, data: Vec<u8> },
+    /// The host fed `data` to the guest's serial console
+    SerialInput { data: Vec<u8> },
+    /// The loop of vCPU `vcpu` ended
+    Exit { vcpu: u32, reason: VcpuExitReason },
+}
+
+impl TraceEvent {
+    /// Returns the line of the event in a trace, without line break.
+    pub fn to_line(&self) -> String {
+        match self {
+            TraceEvent::IoIn { vcpu, port, data } => format!("{} io-in {:x} {}", vcpu, port, encode_data(data)),
+            TraceEvent::IoOut { vcpu, port, data } => format!("{} io-out {:x} {}", vcpu, port, encode_data(data)),
+            TraceEvent::MmioRead { vcpu, address, data } => format!("{} mmio-read {:x} {}", vcpu, address, encode_data(data)),
+            TraceEvent::MmioWrite { vcpu, address, data } => format!("{} mmio-write {:x} {}", vcpu, address, encode_data(data)),
+            TraceEvent::SerialInput { data } => format!("- serial-input {}", encode_data(data)),
+            TraceEvent::Exit { vcpu, reason } => format!("{} exit {}", vcpu, get_reason_name(*reason)),
+        }
+    }
+
+    /// Parses a line written by `to_line`.
+    ///
+    /// # Returns
+    /// * `Ok(TraceEvent)` on success
+    /// * `Err(VmError)` if the line isn't a trace event
+    pub fn parse(line: &str) -> Result<TraceEvent, VmError> {
+        let invalid = || VmError::config(format!("invalid trace event {:?}", line));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let event = match fields.as_slice() {
+            ["-", "serial-input", data] => TraceEvent::SerialInput { data: decode_data(data).ok_or_else(invalid)? },
+            [vcpu, "exit", reason] => TraceEvent::Exit {
+                vcpu: vcpu.parse().map_err(|_| invalid())?,
+                reason: parse_reason_name(reason).ok_or_else(invalid)?,
+            },
+            [vcpu, kind, address, data] => {
+                let vcpu = vcpu.parse().map_err(|_| invalid())?;
+                let data = decode_data(data).ok_or_else(invalid)?;
+                match *kind {
+                    "io-in" => TraceEvent::IoIn { vcpu, port: u16::from_str_radix(address, 16).map_err(|_| invalid())?, data },
+                    "io-out" => TraceEvent::IoOut { vcpu, port: u16::from_str_radix(address, 16).map_err(|_| invalid())?, data },
+                    "mmio-read" => TraceEvent::MmioRead { vcpu, address: u64::from_str_radix(address, 16).map_err(|_| invalid())?, data },
+                    "mmio-write" => TraceEvent::MmioWrite { vcpu, address: u64::from_str_radix(address, 16).map_err(|_| invalid())?, data },
+                    _ => return Err(invalid()),
+                }
+            },
+            _ => return Err(invalid()),
+        };
+        Ok(event)
+    }
+}
+
+/// Writes the events of a running VM to a trace file, from any thread.
+pub struct ExitRecorder {
+    /// Trace file; dropped after the first failed write
+    output: Mutex<Option<BufWriter<File>>>,
+    /// Why recording stopped early, reported by `finish`
+    error: Mutex<Option<std::io::Error>>,
+}
+
+impl ExitRecorder {
+    /// Creates the trace file at `path`, replacing an existing one.
+    ///
+    /// # Returns
+    /// * `Ok(ExitRecorder)` on success
+    /// * `Err(VmError)` if the file couldn't be created
+    pub fn create(path: &Path) -> Result<ExitRecorder, VmError> {
+        match File::create(path) {
+            Ok(file) => Ok(ExitRecorder { output: Mutex::new(Some(BufWriter::new(file))), error: Mutex::new(None) }),
+            Err(e) => Err(VmError::io(format!("failed to create exit trace {}: {}", path.display(), e), e))
+        }
+    }
+
+    /// Appends `event` to the trace. A failed write stops the recording without
+    /// disturbing the VM; `finish` reports it.
+    pub fn record(&self, event: &TraceEvent) {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(writer) = output.as_mut() else { return };
+        if let Err(e) = writeln!(writer, "{}", event.to_line()) {
+            *output = None;
+            *self.error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+        }
+    }
+
+    /// Flushes the trace once the VM stopped.
+    ///
+    /// # Returns
+    /// * `Ok(())` if every event was written
+    /// * `Err(VmError)` if the trace is incomplete
+    pub fn finish(&self) -> Result<(), VmError> {
+        if let Some(writer) = self.output.lock().unwrap_or_else(|e| e.into_inner()).as_mut()
+            && let Err(e) = writer.flush()
+        {
+            return Err(VmError::io(format!("failed to write exit trace: {}", e), e));
+        }
+        match self.error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            Some(e) => Err(VmError::io(format!("failed to write exit trace: {}", e), e)),
+            None => Ok(())
+        }
+    }
+}
+
+/// Reads the trace at `path`.
+///
+/// # Returns
+/// * `Ok(events)` in the order they happened
+/// * `Err(VmError)` if the file couldn't be read or holds an invalid line
+pub fn read_exit_trace(path: &Path) -> Result<Vec<TraceEvent>, VmError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open exit trace {}: {}", path.display(), e), e))
+    };
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(VmError::io(format!("failed to read exit trace {}: {}", path.display(), e), e))
+        };
+        if !line.trim().is_empty() {
+            events.push(TraceEvent::parse(&line)?);
+        }
+    }
+    Ok(events)
+}
+
+/// A read whose result differs from the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the event in the trace
+    pub index: usize,
+    /// The recorded event
+    pub expected: TraceEvent,
+    /// Data the devices returned, `None` if no device handled the access
+    pub actual: Option<Vec<u8>>,
+}
+
+/// Outcome of `replay_exit_trace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayOutcome {
+    /// Events replayed, up to and including a divergence
+    pub replayed: usize,
+    /// First read whose result differs from the trace, where the replay stopped
+    pub divergence: Option<Divergence>,
+}
+
+/// Replays `events` against devices set up like those of the recorded VM, in the
+/// order they were recorded, stopping at the first read returning other data than
+/// recorded.
+///
+/// # Arguments
+/// * `events` - Trace read with `read_exit_trace`
+/// * `mmio_bus` - MMIO devices of the VM
+/// * `pio_bus` - Port I/O devices of the VM; unhandled port reads return `0xff`
+///   bytes, as on the real backend
+/// * `serial` - UART receiving the recorded serial input, if the VM had one
+pub fn replay_exit_trace(events: &[TraceEvent], mmio_bus: &MmioBus, pio_bus: &PortIoBus, serial: Option<&Mutex<Serial>>) -> ReplayOutcome {
+    for (index, event) in events.iter().enumerate() {
+        let actual = match event {
+            TraceEvent::IoIn { port, data: expected, .. } => {
+                let mut data = vec![0u8; expected.len()];
+                if !pio_bus.read(*port, &mut data) {
+                    data.fill(0xff);
+                }
+                Some((expected, Some(data)))
+            },
+            TraceEvent::MmioRead { address, data: expected, .. } => {
+                let mut data = vec![0u8; expected.len()];
+                Some((expected, mmio_bus.read(*address, &mut data).then_some(data)))
+            },
+            TraceEvent::IoOut { port, data, .. } => {
+                pio_bus.write(*port, data);
+                None
+            },
+            TraceEvent::MmioWrite { address, data, .. } => {
+                mmio_bus.write(*address, data);
+                None
+            },
+            TraceEvent::SerialInput { data } => {
+                if let Some(serial) = serial {
+                    serial.lock().unwrap_or_else(|e| e.into_inner()).queue_input(data);
+                }
+                None
+            },
+            TraceEvent::Exit { .. } => None,
+        };
+        if let Some((expected, actual)) = actual
+            && actual.as_ref() != Some(expected)
+        {
+            return ReplayOutcome { replayed: index + 1, divergence: Some(Divergence { index, expected: event.clone(), actual }) };
+        }
+    }
+    ReplayOutcome { replayed: events.len(), divergence: None }
+}
+
+/// Returns the name of `reason` in traces.
+fn get_reason_name(reason: VcpuExitReason) -> &'static str {
+    match reason {
+        VcpuExitReason::Stopped => "stopped",
+        VcpuExitReason::Halted => "halted",
+        VcpuExitReason::PoweredOff => "powered-off",
+        VcpuExitReason::Shutdown => "shutdown",
+        VcpuExitReason::Reset => "reset",
+        VcpuExitReason::Panicked => "panicked",
+    }
+}
+
+/// Parses a name written by `get_reason_name`.
+fn parse_reason_name(name: &str) -> Option<VcpuExitReason> {
+    [
+        VcpuExitReason::Stopped,
+        VcpuExitReason::Halted,
+        VcpuExitReason::PoweredOff,
+        VcpuExitReason::Shutdown,
+        VcpuExitReason::Reset,
+        VcpuExitReason::Panicked,
+    ].into_iter().find(|reason| get_reason_name(*reason) == name)
+}
+
+/// Encodes `data` as hexadecimal, or `-` if it is empty so every event keeps its
+/// number of fields.
+fn encode_data(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+    to_hex(data)
+}
+
+/// Decodes data written by `encode_data`.
+fn decode_data(text: &str) -> Option<Vec<u8>> {
+    if text == "-" {
+        return Some(Vec::new());
+    }
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::device_emulation::mmio::MmioDevice;
+
+    // Device returning the number of writes it saw for every read
+    struct CountingDevice {
+        writes: u8,
+    }
+
+    impl MmioDevice for CountingDevice {
+        fn read_mmio(&mut self, _offset: u64, data: &mut [u8]) {
+            data.fill(self.writes);
+        }
+
+        fn write_mmio(&mut self, _offset: u64, _data: &[u8]) {
+            self.writes += 1;
+        }
+    }
+
+    #[test]
+    fn test_trace_lines() {
+        let events = [
+            TraceEvent::IoIn { vcpu: 0, port: 0x3fd, data: vec![0x60] },
+            TraceEvent::IoOut { vcpu: 1, port: 0x3f8, data: vec![b'A'] },
+            TraceEvent::MmioRead { vcpu: 0, address: 0xd000_0070, data: vec![0x0f, 0, 0, 0] },
+            TraceEvent::MmioWrite { vcpu: 1, address: 0xd000_0050, data: vec![1, 0, 0, 0] },
+            TraceEvent::SerialInput { data: b"root\n".to_vec() },
+            TraceEvent::MmioWrite { vcpu: 0, address: 0, data: Vec::new() },
+            TraceEvent::Exit { vcpu: 0, reason: VcpuExitReason::PoweredOff },
+        ];
+        for event in &events {
+            assert_eq!(TraceEvent::parse(&event.to_line()).unwrap(), *event);
+        }
+        assert_eq!(events[2].to_line(), "0 mmio-read d0000070 0f000000");
+        assert!(TraceEvent::parse("0 io-in 10000 00").is_err());
+        assert!(TraceEvent::parse("0 exit melted").is_err());
+        assert!(TraceEvent::parse("x mmio-read 0 0").is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("exits.trace");
+        let recorder = ExitRecorder::create(&path).unwrap();
+        let events = [
+            TraceEvent::MmioRead { vcpu: 0, address: 0x1000, data: vec![0, 0] },
+            TraceEvent::MmioWrite { vcpu: 1, address: 0x1004, data: vec![1] },
+            TraceEvent::IoIn { vcpu: 0, port: 0x80, data: vec![0xff] },
+            TraceEvent::MmioRead { vcpu: 0, address: 0x1000, data: vec![1, 1] },
+            TraceEvent::Exit { vcpu: 0, reason: VcpuExitReason::Halted },
+        ];
+        for event in &events {
+            recorder.record(event);
+        }
+        recorder.finish().unwrap();
+        let trace = read_exit_trace(&path).unwrap();
+        assert_eq!(trace, events);
+
+        let mut mmio_bus = MmioBus::new();
+        mmio_bus.register(0x1000, 0x100, Arc::new(Mutex::new(CountingDevice { writes: 0 }))).unwrap();
+        let outcome = replay_exit_trace(&trace, &mmio_bus, &PortIoBus::new(), None);
+        assert_eq!(outcome, ReplayOutcome { replayed: 5, divergence: None });
+
+        // The write went missing: the second read sees a device that wasn't written to
+        let mut diverging = trace.clone();
+        diverging.remove(1);
+        let mut mmio_bus = MmioBus::new();
+        mmio_bus.register(0x1000, 0x100, Arc::new(Mutex::new(CountingDevice { writes: 0 }))).unwrap();
+        let outcome = replay_exit_trace(&diverging, &mmio_bus, &PortIoBus::new(), None);
+        assert_eq!(outcome.replayed, 3);
+        assert_eq!(outcome.divergence, Some(Divergence { index: 2, expected: events[3].clone(), actual: Some(vec![0, 0]) }));
+    }
+}
@@ -0,0 +1,211 @@
+//! Commands run once when a guest boots for the first time.
+//!
+//! For images without cloud-init, the commands are written into a shell script in the
+//! image together with something that starts it at boot: a one-shot systemd unit, or
+//! `/etc/rc.local` for init systems without systemd. The script leaves a marker behind
+//! so later boots skip it.
+
+use std::path::Path;
+use crate::error::VmError;
+use crate::vm_setup::image_inject::{inject_entries, read_guest_file, remove_guest_files, InjectedEntry};
+
+/// Script holding the first boot commands.
+pub const FIRST_BOOT_SCRIPT: &str = "/usr/local/sbin/asgard-first-boot";
+/// Systemd unit running the script.
+pub const FIRST_BOOT_UNIT: &str = "/etc/systemd/system/asgard-first-boot.service";
+/// Link enabling the unit, as `systemctl enable` would create it.
+const FIRST_BOOT_UNIT_LINK: &str = "/etc/systemd/system/multi-user.target.wants/asgard-first-boot.service";
+/// Script run at the end of boot by rc-style init systems.
+const RC_LOCAL: &str = "/etc/rc.local";
+/// Created by the script once it ran; its presence skips the script on later boots.
+pub const FIRST_BOOT_DONE_MARKER: &str = "/var/lib/asgard/first-boot.done";
+
+/// How the guest starts the first boot script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FirstBootMethod {
+    /// One-shot systemd unit ordered after the network is up
+    #[default]
+    SystemdUnit,
+    /// `/etc/rc.local`, replacing any existing one
+    RcLocal,
+}
+
+/// Installs `commands` into an offline image to run once on its first boot.
+///
+/// The commands run in order as root through `/bin/sh`, stopping at the first one
+/// that fails. Either way they are not run again on later boots; a failure is
+/// reported in the journal (systemd) or on the console (rc.local). Installing again
+/// replaces the previous commands but doesn't clear the marker of an image that
+/// already booted.
+///
+/// # Arguments
+/// * `image` - Raw disk image to modify; see `inject_files` for the supported images
+/// * `commands` - Shell commands to run
+/// * `method` - How the guest's init system starts the commands
+///
+/// # Returns
+/// * `Ok(())` once the commands are installed
+/// * `Err(VmError)` if there are no commands or the image couldn't be modified
+pub fn install_first_boot_commands(image: &Path, commands: &[&str], method: FirstBootMethod) -> Result<(), VmError> {
+    if commands.is_empty() {
+        return Err(VmError::config("no first boot commands given"));
+    }
+
+    let staging = match tempfile::tempdir() {
+        Ok(staging) => staging,
+        Err(e) => return Err(VmError::io(format!("failed to create staging directory: {}", e), e))
+    };
+    let script = staging.path().join("asgard-first-boot");
+    write_staged_file(&script, &render_script(commands), 0o755)?;
+
+    match method {
+        FirstBootMethod::SystemdUnit => {
+            let unit = staging.path().join("asgard-first-boot.service");
+            write_staged_file(&unit, &render_unit(), 0o644)?;
+            inject_entries(image, &[
+                InjectedEntry::File { host_path: &script, guest_path: FIRST_BOOT_SCRIPT },
+                InjectedEntry::File { host_path: &unit, guest_path: FIRST_BOOT_UNIT },
+                InjectedEntry::Symlink { target: FIRST_BOOT_UNIT, guest_path: FIRST_BOOT_UNIT_LINK },
+            ])
+        },
+        FirstBootMethod::RcLocal => {
+            let rc_local = staging.path().join("rc.local");
+            write_staged_file(&rc_local, &render_rc_local(), 0o755)?;
+            inject_entries(image, &[
+                InjectedEntry::File { host_path: &script, guest_path: FIRST_BOOT_SCRIPT },
+                InjectedEntry::File { host_path: &rc_local, guest_path: RC_LOCAL },
+            ])
+        },
+    }
+}
+
+/// Removes installed first boot commands and their marker from an offline image.
+///
+/// Used on images meant to be cloned, so that every clone runs its own first boot
+/// commands. `/etc/rc.local` is only removed if it is the one installed by
+/// `install_first_boot_commands`.
+///
+/// # Returns
+/// * `Ok(())` once nothing of the first boot commands is left
+/// * `Err(VmError)` if the image couldn't be read or modified
+pub fn remove_first_boot_commands(image: &Path) -> Result<(), VmError> {
+    let mut paths = vec![FIRST_BOOT_SCRIPT, FIRST_BOOT_UNIT, FIRST_BOOT_UNIT_LINK, FIRST_BOOT_DONE_MARKER];
+    if let Some(rc_local) = read_guest_file(image, RC_LOCAL)?
+        && rc_local == render_rc_local().as_bytes() {
+        paths.push(RC_LOCAL);
+    }
+    remove_guest_files(image, &paths)
+}
+
+/// Renders the script running `commands` and leaving the marker behind.
+fn render_script(commands: &[&str]) -> String {
+    let mut script = String::from("#!/bin/sh\n# Installed by AsgardManager: first boot commands\n(\nset -e\n");
+    for command in commands {
+        script.push_str(command);
+        script.push('\n');
+    }
+    script.push_str(&format!(
+        ")\nstatus=$?\nmkdir -p \"$(dirname {marker})\"\ntouch {marker}\nexit $status\n",
+        marker = FIRST_BOOT_DONE_MARKER
+    ));
+    script
+}
+
+/// Renders the systemd unit starting the script on the first boot only.
+fn render_unit() -> String {
+    format!(
+        "[Unit]\n\
+         Description=AsgardManager first boot commands\n\
+         ConditionPathExists=!{marker}\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={script}\n\
+         RemainAfterExit=yes\n\
+         StandardOutput=journal+console\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        marker = FIRST_BOOT_DONE_MARKER,
+        script = FIRST_BOOT_SCRIPT
+    )
+}
+
+/// Renders an rc.local starting the script on the first boot only.
+fn render_rc_local() -> String {
+    format!(
+        "#!/bin/sh\n# Installed by AsgardManager: first boot commands\n[ -e {marker} ] || {script} >/dev/console 2>&1\nexit 0\n",
+        marker = FIRST_BOOT_DONE_MARKER,
+        script = FIRST_BOOT_SCRIPT
+    )
+}
+
+/// Writes a file to be injected with the permission bits it gets in the guest.
+pub(crate) fn write_staged_file(path: &Path, content: &str, mode: u32) -> Result<(), VmError> {
+    if let Err(e) = std::fs::write(path, content) {
+        return Err(VmError::io(format!("failed to write {}: {}", path.display(), e), e));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)) {
+            return Err(VmError::io(format!("failed to set the mode of {}: {}", path.display(), e), e));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_script_runs_until_first_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let first = format!("echo one >> {}", log.display());
+        let second = format!("echo two >> {}", log.display());
+        let script = render_script(&[&first, "false", &second]);
+        // Run the command part only; the marker lives outside of the test directory
+        let commands = &script[..script.find("status=$?").unwrap()];
+
+        let status = std::process::Command::new("sh").arg("-c").arg(commands).status().unwrap();
+        assert!(!status.success());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn test_install_into_ext4_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("root.img");
+        std::fs::File::create(&image).unwrap().set_len(8 << 20).unwrap();
+        // e2fsprogs isn't installed everywhere the tests run
+        let formatted = std::process::Command::new("mkfs.ext4").arg("-q").arg("-F").arg(&image).output();
+        if !formatted.is_ok_and(|output| output.status.success()) {
+            return;
+        }
+
+        install_first_boot_commands(&image, &["systemctl enable --now asgard-agent"], FirstBootMethod::SystemdUnit)
+            .expect("Installation should succeed");
+        let cat = |path: &str| {
+            let output = std::process::Command::new("debugfs").arg("-R").arg(format!("cat {}", path)).arg(&image).output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        assert!(cat(FIRST_BOOT_SCRIPT).contains("systemctl enable --now asgard-agent\n"));
+        assert!(cat(FIRST_BOOT_UNIT).contains(&format!("ExecStart={}", FIRST_BOOT_SCRIPT)));
+        let link = std::process::Command::new("debugfs").arg("-R").arg(format!("stat {}", FIRST_BOOT_UNIT_LINK)).arg(&image).output().unwrap();
+        assert!(String::from_utf8_lossy(&link.stdout).contains(&format!("Fast link dest: \"{}\"", FIRST_BOOT_UNIT)));
+
+        assert!(install_first_boot_commands(&image, &[], FirstBootMethod::RcLocal).is_err());
+
+        remove_first_boot_commands(&image).expect("Removal should succeed");
+        assert!(cat(FIRST_BOOT_SCRIPT).is_empty());
+        let link = std::process::Command::new("debugfs").arg("-R").arg(format!("stat {}", FIRST_BOOT_UNIT_LINK)).arg(&image).output().unwrap();
+        assert!(!String::from_utf8_lossy(&link.stdout).contains("Fast link dest"));
+    }
+}
@@ -0,0 +1,224 @@
+//! Host-side sampling profiler for guests.
+//!
+//! While profiling, `VmHandle` kicks every vCPU out of the guest at a fixed interval
+//! and each vCPU loop records where the guest was running before it re-enters it: the
+//! program counter and whether the guest was in kernel or user mode. The guest doesn't
+//! cooperate or even notice beyond the extra exits, so this works on any guest, but
+//! only tells where the time goes, not through which call stacks.
+//!
+//! `GuestProfile::to_folded` aggregates the samples into the folded stack format of
+//! `flamegraph.pl` and `inferno`, naming kernel functions with the guest's `KernelSymbols`.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::error::VmError;
+use crate::vm_setup::kernel_introspection::KernelSymbols;
+
+/// Shortest interval between two samples of a vCPU.
+pub const MIN_SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Privilege level the guest was running at when sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GuestMode {
+    /// Guest kernel (ring 0, EL1)
+    Kernel,
+    /// Guest user space (ring 3, EL0)
+    User,
+}
+
+/// Number of times a vCPU was found at one program counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileEntry {
+    /// Index of the vCPU
+    pub vcpu: u32,
+    /// Privilege level of the guest
+    pub mode: GuestMode,
+    /// Guest virtual address of the instruction about to run
+    pub pc: u64,
+    /// Number of samples taken there
+    pub count: u64,
+}
+
+/// Samples collected by a profiling session.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GuestProfile {
+    /// Samples by vCPU, mode and program counter, in that order
+    pub entries: Vec<ProfileEntry>,
+    /// Number of times the vCPUs were asked for a sample
+    pub rounds: u64,
+}
+
+impl GuestProfile {
+    /// Returns the number of samples taken.
+    pub fn get_sample_count(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.count).sum()
+    }
+
+    /// Renders the profile in the folded stack format, one `vcpu0;kernel;frame count`
+    /// line per frame, ready for `flamegraph.pl` or `inferno-flamegraph`.
+    ///
+    /// Kernel program counters are named after the function holding them when
+    /// `symbols` resolves them, so samples in the same function add up. Other
+    /// program counters are kept as addresses.
+    pub fn to_folded(&self, symbols: Option<&KernelSymbols>) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for entry in &self.entries {
+            let (mode, name) = match entry.mode {
+                GuestMode::Kernel => ("kernel", symbols.and_then(|symbols| symbols.resolve(entry.pc)).map(|(name, _)| name.to_string())),
+                GuestMode::User => ("user", None),
+            };
+            let frame = name.unwrap_or_else(|| format!("{:#x}", entry.pc));
+            *stacks.entry(format!("vcpu{};{};{}", entry.vcpu, mode, frame)).or_default() += entry.count;
+        }
+        let mut folded = String::new();
+        for (stack, count) in stacks {
+            folded.push_str(&format!("{} {}\n", stack, count));
+        }
+        folded
+    }
+}
+
+/// Sampling state behind the `GuestProfiler` mutex.
+#[derive(Default)]
+struct ProfilerState {
+    /// Session currently collecting samples, counting up from 1
+    session: u64,
+    /// Sampling round the vCPUs are asked to answer
+    round: u64,
+    /// Last round each vCPU answered
+    answered: BTreeMap<u32, u64>,
+    /// Samples by vCPU, mode and program counter
+    samples: BTreeMap<(u32, GuestMode, u64), u64>,
+}
+
+/// Profiling state of a VM, shared between the `VmHandle` and the vCPU loops.
+#[derive(Default)]
+pub struct GuestProfiler {
+    active: AtomicBool,
+    state: Mutex<ProfilerState>,
+}
+
+impl GuestProfiler {
+    /// Creates a profiler that isn't sampling.
+    pub fn new() -> Self {
+        GuestProfiler::default()
+    }
+
+    /// Starts a profiling session without any sample.
+    ///
+    /// # Returns
+    /// * `Ok(u64)` with the id of the session, see `is_session_active`
+    /// * `Err(VmError)` if a session is already running
+    pub fn start(&self) -> Result<u64, VmError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if self.active.load(Ordering::Acquire) {
+            return Err(VmError::config("the VM is already being profiled"));
+        }
+        let session = state.session + 1;
+        *state = ProfilerState { session, ..Default::default() };
+        self.active.store(true, Ordering::Release);
+        Ok(session)
+    }
+
+    /// Ends the profiling session and returns its samples.
+    ///
+    /// # Returns
+    /// * `Ok(GuestProfile)` with the samples of the session
+    /// * `Err(VmError)` if no session is running
+    pub fn stop(&self) -> Result<GuestProfile, VmError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !self.active.swap(false, Ordering::AcqRel) {
+            return Err(VmError::config("the VM isn't being profiled"));
+        }
+        let entries = std::mem::take(&mut state.samples).into_iter()
+            .map(|((vcpu, mode, pc), count)| ProfileEntry { vcpu, mode, pc, count })
+            .collect();
+        Ok(GuestProfile { entries, rounds: state.round })
+    }
+
+    /// Returns `true` while the session `session` is collecting samples.
+    pub fn is_session_active(&self, session: u64) -> bool {
+        self.active.load(Ordering::Acquire) && self.state.lock().unwrap_or_else(|e| e.into_inner()).session == session
+    }
+
+    /// Asks every vCPU for one more sample.
+    pub fn request_samples(&self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).round += 1;
+    }
+
+    /// Returns `true` if vCPU `vcpu` owes a sample, which the caller must then `record`.
+    ///
+    /// Called by the vCPU loops before every entry into the guest, so it is cheap while
+    /// no session is running.
+    pub fn take_request(&self, vcpu: u32) -> bool {
+        if !self.active.load(Ordering::Acquire) {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let round = state.round;
+        let answered = state.answered.entry(vcpu).or_default();
+        if *answered >= round {
+            return false;
+        }
+        *answered = round;
+        true
+    }
+
+    /// Records that vCPU `vcpu` was about to run the instruction at `pc` in `mode`.
+    pub fn record(&self, vcpu: u32, mode: GuestMode, pc: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state.samples.entry((vcpu, mode, pc)).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_vcpu_answers_a_round_once() {
+        let profiler = GuestProfiler::new();
+        assert!(!profiler.take_request(0));
+        let session = profiler.start().unwrap();
+        assert!(profiler.start().is_err());
+        assert!(!profiler.take_request(0));
+
+        profiler.request_samples();
+        assert!(profiler.take_request(0));
+        assert!(!profiler.take_request(0));
+        assert!(profiler.take_request(1));
+        profiler.record(0, GuestMode::Kernel, 0x1000);
+        profiler.record(0, GuestMode::Kernel, 0x1000);
+        profiler.record(1, GuestMode::User, 0x40_0000);
+
+        let profile = profiler.stop().unwrap();
+        assert!(!profiler.is_session_active(session));
+        assert!(profiler.stop().is_err());
+        assert_eq!(profile.rounds, 1);
+        assert_eq!(profile.get_sample_count(), 3);
+        assert_eq!(profile.entries[0], ProfileEntry { vcpu: 0, mode: GuestMode::Kernel, pc: 0x1000, count: 2 });
+
+        // A new session starts from scratch
+        let next = profiler.start().unwrap();
+        assert_ne!(next, session);
+        assert_eq!(profiler.stop().unwrap(), GuestProfile::default());
+    }
+
+    #[test]
+    fn test_folded_profile_merges_functions() {
+        let symbols = KernelSymbols::parse("ffffffff81000000 T do_idle\nffffffff81000100 T schedule\n").unwrap();
+        let profile = GuestProfile {
+            entries: vec![
+                ProfileEntry { vcpu: 0, mode: GuestMode::Kernel, pc: 0xffff_ffff_8100_0010, count: 2 },
+                ProfileEntry { vcpu: 0, mode: GuestMode::Kernel, pc: 0xffff_ffff_8100_0020, count: 3 },
+                ProfileEntry { vcpu: 0, mode: GuestMode::User, pc: 0x40_1000, count: 1 },
+                ProfileEntry { vcpu: 1, mode: GuestMode::Kernel, pc: 0xffff_ffff_8100_0104, count: 4 },
+            ],
+            rounds: 5,
+        };
+        assert_eq!(profile.to_folded(Some(&symbols)), "vcpu0;kernel;do_idle 5\nvcpu0;user;0x401000 1\nvcpu1;kernel;schedule 4\n");
+        assert!(profile.to_folded(None).contains("vcpu1;kernel;0xffffffff81000104 4\n"));
+    }
+}
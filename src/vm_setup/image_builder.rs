@@ -0,0 +1,283 @@
+//! Golden images built by provisioning a base image in a temporary VM.
+//!
+//! `ImageBuilder` copies a base image, injects files and a provisioning script into
+//! the copy and installs the script as first boot commands. It then boots the copy
+//! until the guest reboots itself once the script finished, which ends the VM, reads
+//! back how the script went and saves the copy as the golden image. The first boot
+//! commands are removed again, so VMs cloned from the golden image can run their own.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::error::VmError;
+use crate::kernel_setup::setup_utils::KernelComponents;
+use crate::vm_setup::first_boot::{install_first_boot_commands, remove_first_boot_commands, write_staged_file, FirstBootMethod};
+use crate::vm_setup::image_inject::{inject_entries, read_guest_file, remove_guest_files, InjectedEntry};
+use crate::vm_setup::setup_utils::{SerialConsole, VmSetupBuilder};
+use crate::vm_setup::vm_handle::VmState;
+
+#[cfg(target_os = "linux")]
+use crate::vm_setup::linux_setup::spawn_vm;
+#[cfg(target_os = "macos")]
+use crate::vm_setup::macos_setup::spawn_vm;
+#[cfg(target_os = "windows")]
+use crate::vm_setup::windows_setup::spawn_vm;
+
+/// Script holding the provisioning commands.
+pub const PROVISION_SCRIPT: &str = "/usr/local/sbin/asgard-provision";
+/// Output of the provisioning script, read back into the `BuildReport`.
+pub const PROVISION_LOG: &str = "/var/log/asgard-provision.log";
+/// Exit status of the provisioning script.
+const PROVISION_STATUS: &str = "/var/lib/asgard/provision.status";
+/// Time a build may take when no timeout is set.
+pub const DEFAULT_BUILD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Interval at which `build` checks whether the VM stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Lines of the provisioning log quoted in the error of a failed build.
+const LOG_TAIL_LINES: usize = 20;
+
+/// Outcome of a successful build.
+#[derive(Debug, Clone, Default)]
+pub struct BuildReport {
+    /// Time from the start of the build until the golden image was saved
+    pub elapsed: Duration,
+    /// Output of the provisioning script
+    pub log: String,
+}
+
+/// Builds a golden image from a base image by running provisioning steps in a VM.
+///
+/// The base image must boot on its own with the given kernel and run first boot
+/// commands (see `install_first_boot_commands`), and `reboot` must end the VM, as it
+/// does with the `reboot=k` of `DEFAULT_KERNEL_CMDLINE`.
+pub struct ImageBuilder {
+    /// Image the build starts from; it is never modified
+    base_image: PathBuf,
+    /// Memory of the build VM in megabytes
+    memory_mb: u32,
+    /// vCPUs of the build VM
+    cpus: u32,
+    /// Kernel booted by the build VM and its command line
+    kernel: Option<(KernelComponents, String)>,
+    /// Host files copied into the image, with their guest paths
+    files: Vec<(PathBuf, String)>,
+    /// Shell commands of the provisioning script
+    commands: Vec<String>,
+    /// How the guest starts the provisioning script
+    first_boot_method: FirstBootMethod,
+    /// File receiving the serial console output of the build VM
+    serial_log: Option<PathBuf>,
+    /// Time after which the build VM is stopped and the build fails
+    timeout: Duration,
+}
+
+impl ImageBuilder {
+    /// Starts a build from `base_image` with a 1 GiB, 1 vCPU build VM.
+    pub fn new(base_image: impl Into<PathBuf>) -> ImageBuilder {
+        ImageBuilder {
+            base_image: base_image.into(),
+            memory_mb: 1024,
+            cpus: 1,
+            kernel: None,
+            files: Vec::new(),
+            commands: Vec::new(),
+            first_boot_method: FirstBootMethod::default(),
+            serial_log: None,
+            timeout: DEFAULT_BUILD_TIMEOUT,
+        }
+    }
+    /// Set the memory size in megabytes and the CPU cores count of the build VM.
+    pub fn resources(mut self, mega_bytes: u32, cpu_cores_count: u32) -> ImageBuilder {
+        self.memory_mb = mega_bytes;
+        self.cpus = cpu_cores_count;
+        self
+    }
+    /// Boot the build VM with the given kernel and command line.
+    pub fn kernel(mut self, kernel: KernelComponents, cmdline: &str) -> ImageBuilder {
+        self.kernel = Some((kernel, cmdline.to_string()));
+        self
+    }
+    /// Copy a host file into the image before it boots, e.g. a package or a config file.
+    pub fn file(mut self, host_path: impl Into<PathBuf>, guest_path: &str) -> ImageBuilder {
+        self.files.push((host_path.into(), guest_path.to_string()));
+        self
+    }
+    /// Append a shell command to the provisioning script; the script stops at the
+    /// first command that fails.
+    pub fn command(mut self, command: &str) -> ImageBuilder {
+        self.commands.push(command.to_string());
+        self
+    }
+    /// Set how the guest's init system starts the provisioning script.
+    pub fn first_boot_method(mut self, first_boot_method: FirstBootMethod) -> ImageBuilder {
+        self.first_boot_method = first_boot_method;
+        self
+    }
+    /// Append the serial console output of the build VM to the given file.
+    pub fn serial_log(mut self, path: impl Into<PathBuf>) -> ImageBuilder {
+        self.serial_log = Some(path.into());
+        self
+    }
+    /// Set how long the build VM may run before the build fails.
+    pub fn timeout(mut self, timeout: Duration) -> ImageBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs the build and saves the golden image as `output`.
+    ///
+    /// The build works on a copy next to `output`, so `output` is only replaced by a
+    /// fully provisioned image. Must be called from within a Tokio runtime.
+    ///
+    /// # Returns
+    /// * `Ok(BuildReport)` once the golden image is saved
+    /// * `Err(VmError)` if there is nothing to provision, the image couldn't be prepared,
+    ///   the VM failed or timed out, or the provisioning script failed; the latter
+    ///   quotes the end of the provisioning log
+    pub async fn build(self, output: &Path) -> Result<BuildReport, VmError> {
+        if self.files.is_empty() && self.commands.is_empty() {
+            return Err(VmError::config("no provisioning steps given"));
+        }
+        let started = Instant::now();
+
+        let directory = match output.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let image = match tempfile::Builder::new().prefix(".build").tempfile_in(directory) {
+            Ok(image) => image,
+            Err(e) => return Err(VmError::io(format!("failed to create build image in {}: {}", directory.display(), e), e))
+        };
+        if let Err(e) = std::fs::copy(&self.base_image, image.path()) {
+            return Err(VmError::io(format!("failed to copy {}: {}", self.base_image.display(), e), e));
+        }
+        self.prepare_image(image.path())?;
+
+        let mut setup = VmSetupBuilder::new(self.memory_mb, self.cpus).disk_image(image.path());
+        if let Some((kernel, cmdline)) = self.kernel {
+            setup = setup.kernel(kernel, &cmdline);
+        }
+        if let Some(serial_log) = self.serial_log {
+            setup = setup.serial_console(SerialConsole::File(serial_log));
+        }
+        let handle = spawn_vm(setup.build()?);
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        while handle.get_state() != VmState::Stopped {
+            if tokio::time::Instant::now() >= deadline {
+                handle.stop().await?;
+                let _ = handle.wait().await;
+                return Err(VmError::image(format!("provisioning didn't finish within {:?}", self.timeout)));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        handle.wait().await?;
+
+        let log = match read_guest_file(image.path(), PROVISION_LOG)? {
+            Some(log) => String::from_utf8_lossy(&log).into_owned(),
+            None => String::new(),
+        };
+        let status = match read_guest_file(image.path(), PROVISION_STATUS)? {
+            Some(status) => String::from_utf8_lossy(&status).trim().to_string(),
+            None => return Err(VmError::image("the build VM stopped before provisioning finished")),
+        };
+        if status != "0" {
+            return Err(VmError::image(format!(
+                "provisioning failed with status {}:\n{}",
+                status,
+                get_log_tail(&log, LOG_TAIL_LINES)
+            )));
+        }
+
+        remove_first_boot_commands(image.path())?;
+        remove_guest_files(image.path(), &[PROVISION_SCRIPT, PROVISION_LOG, PROVISION_STATUS])?;
+        if let Err(e) = image.persist(output) {
+            return Err(VmError::io(format!("failed to create {}: {}", output.display(), e.error), e.error));
+        }
+        Ok(BuildReport { elapsed: started.elapsed(), log })
+    }
+
+    /// Injects the files and the provisioning script into the build image and
+    /// installs the first boot commands running it.
+    fn prepare_image(&self, image: &Path) -> Result<(), VmError> {
+        let staging = match tempfile::tempdir() {
+            Ok(staging) => staging,
+            Err(e) => return Err(VmError::io(format!("failed to create staging directory: {}", e), e))
+        };
+        let script = staging.path().join("asgard-provision");
+        write_staged_file(&script, &render_provision_script(&self.commands), 0o755)?;
+
+        let mut entries: Vec<InjectedEntry> = self.files.iter()
+            .map(|(host_path, guest_path)| InjectedEntry::File { host_path, guest_path })
+            .collect();
+        entries.push(InjectedEntry::File { host_path: &script, guest_path: PROVISION_SCRIPT });
+        inject_entries(image, &entries)?;
+
+        install_first_boot_commands(image, &[&render_provision_command()], self.first_boot_method)
+    }
+}
+
+/// Renders the provisioning script running `commands` until the first failure.
+fn render_provision_script(commands: &[String]) -> String {
+    let mut script = String::from("#!/bin/sh\n# Installed by AsgardManager: provisioning commands\nset -ex\n");
+    for command in commands {
+        script.push_str(command);
+        script.push('\n');
+    }
+    script
+}
+
+/// Renders the first boot command running the provisioning script, recording its
+/// status and rebooting, which ends the build VM.
+fn render_provision_command() -> String {
+    format!(
+        "status=0; {script} >{log} 2>&1 || status=$?; mkdir -p \"$(dirname {status})\"; echo $status >{status}; sync; reboot -f",
+        script = PROVISION_SCRIPT,
+        log = PROVISION_LOG,
+        status = PROVISION_STATUS
+    )
+}
+
+/// Returns the last `lines` lines of `log`.
+fn get_log_tail(log: &str, lines: usize) -> String {
+    let all: Vec<&str> = log.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_provision_script_stops_at_first_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let commands = vec![
+            format!("echo one >> {}", log.display()),
+            "false".to_string(),
+            format!("echo two >> {}", log.display()),
+        ];
+        let script = dir.path().join("provision");
+        write_staged_file(&script, &render_provision_script(&commands), 0o755).unwrap();
+
+        let output = std::process::Command::new(&script).output().unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "one\n");
+        // The trace tells which command failed
+        assert!(String::from_utf8_lossy(&output.stderr).contains("+ false"));
+    }
+
+    #[test]
+    fn test_log_tail() {
+        assert_eq!(get_log_tail("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(get_log_tail("a\n", 5), "a");
+        assert_eq!(get_log_tail("", 5), "");
+    }
+
+    #[tokio::test]
+    async fn test_build_without_steps_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = ImageBuilder::new(dir.path().join("base.img")).build(&dir.path().join("golden.img")).await;
+        assert!(result.is_err());
+        assert!(!dir.path().join("golden.img").exists());
+    }
+}
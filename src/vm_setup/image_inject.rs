@@ -0,0 +1,417 @@
+//! Writing files into disk images that aren't running.
+//!
+//! Agents and configuration files are dropped into an image before its first boot.
+//! An image holding a bare ext2/3/4 filesystem is edited with `debugfs` from e2fsprogs;
+//! anything else (partitioned disks, other filesystems) goes through `guestfish`, which
+//! finds and mounts the guest's root filesystem itself.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use crate::error::VmError;
+
+/// Offset of the ext2/3/4 superblock from the start of the filesystem.
+const EXT_SUPERBLOCK_OFFSET: u64 = 1024;
+/// Offset of the magic number inside the superblock.
+const EXT_MAGIC_OFFSET: u64 = 56;
+/// ext2/3/4 superblock magic number.
+const EXT_MAGIC: u16 = 0xef53;
+
+/// Tool used to write into an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectionMethod {
+    /// `debugfs -w`, for images that are a bare ext2/3/4 filesystem
+    Debugfs,
+    /// `guestfish --rw -i`, for partitioned disks and other filesystems
+    Guestfish,
+}
+
+/// Picks the tool able to write into `image`.
+///
+/// # Returns
+/// * `Ok(InjectionMethod::Debugfs)` if the image starts with an ext2/3/4 filesystem
+/// * `Ok(InjectionMethod::Guestfish)` otherwise
+/// * `Err(VmError)` if the image can't be read
+pub fn detect_injection_method(image: &Path) -> Result<InjectionMethod, VmError> {
+    let mut file = match File::open(image) {
+        Ok(file) => file,
+        Err(e) => return Err(VmError::io(format!("failed to open disk image {}: {}", image.display(), e), e))
+    };
+    let mut magic = [0u8; 2];
+    let read = file.seek(SeekFrom::Start(EXT_SUPERBLOCK_OFFSET + EXT_MAGIC_OFFSET)).and_then(|_| file.read_exact(&mut magic));
+    match read {
+        Ok(()) if u16::from_le_bytes(magic) == EXT_MAGIC => Ok(InjectionMethod::Debugfs),
+        Ok(()) => Ok(InjectionMethod::Guestfish),
+        // Too small to hold a superblock, so certainly not an ext filesystem
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(InjectionMethod::Guestfish),
+        Err(e) => Err(VmError::io(format!("failed to read disk image {}: {}", image.display(), e), e))
+    }
+}
+
+/// Something to create in an offline disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectedEntry<'a> {
+    /// Regular file copied from the host, keeping its host permission bits
+    File { host_path: &'a Path, guest_path: &'a str },
+    /// Symbolic link pointing at `target`
+    Symlink { target: &'a str, guest_path: &'a str },
+}
+
+impl InjectedEntry<'_> {
+    /// Returns the absolute path of the entry in the guest.
+    pub fn get_guest_path(&self) -> &str {
+        match self {
+            InjectedEntry::File { guest_path, .. } | InjectedEntry::Symlink { guest_path, .. } => guest_path,
+        }
+    }
+}
+
+/// Copies host files into an offline disk image.
+///
+/// Missing parent directories are created and existing files replaced. The files
+/// keep their host permission bits and are owned by root in the guest. The image
+/// must not be in use by a running VM.
+///
+/// # Arguments
+/// * `image` - Raw disk image to modify
+/// * `files` - Pairs of host file and absolute destination path in the guest
+///
+/// # Returns
+/// * `Ok(())` once every file was written
+/// * `Err(VmError)` if a path is invalid, the needed tool is missing or it failed
+pub fn inject_files(image: &Path, files: &[(&Path, &str)]) -> Result<(), VmError> {
+    let entries: Vec<InjectedEntry> = files.iter()
+        .map(|&(host_path, guest_path)| InjectedEntry::File { host_path, guest_path })
+        .collect();
+    inject_entries(image, &entries)
+}
+
+/// Creates files and symbolic links in an offline disk image, in one pass of the image tool.
+///
+/// Behaves like `inject_files`; existing entries at the guest paths are replaced.
+///
+/// # Returns
+/// * `Ok(())` once every entry was created
+/// * `Err(VmError)` if a path is invalid, the needed tool is missing or it failed
+pub fn inject_entries(image: &Path, entries: &[InjectedEntry]) -> Result<(), VmError> {
+    for entry in entries {
+        check_guest_path(entry.get_guest_path())?;
+        match entry {
+            InjectedEntry::File { host_path, .. } => {
+                if !host_path.is_file() {
+                    return Err(VmError::config(format!("{} is not a file", host_path.display())));
+                }
+                if host_path.to_str().is_none_or(|path| path.contains(['"', '\n', '\r'])) {
+                    return Err(VmError::config(format!("host path {} can't be passed to the image tools", host_path.display())));
+                }
+            },
+            InjectedEntry::Symlink { target, .. } => {
+                if target.is_empty() || target.contains(['"', '\\']) || target.chars().any(char::is_control) {
+                    return Err(VmError::config(format!("invalid symbolic link target {:?}", target)));
+                }
+            },
+        }
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    match detect_injection_method(image)? {
+        InjectionMethod::Debugfs => inject_with_debugfs(image, entries),
+        InjectionMethod::Guestfish => inject_with_guestfish(image, entries),
+    }
+}
+
+/// Reads a regular file out of an offline disk image.
+///
+/// # Returns
+/// * `Ok(Some(content))` if the file exists
+/// * `Ok(None)` if there is no regular file at `guest_path`
+/// * `Err(VmError)` if the path is invalid, the needed tool is missing or it failed
+pub fn read_guest_file(image: &Path, guest_path: &str) -> Result<Option<Vec<u8>>, VmError> {
+    check_guest_path(guest_path)?;
+    match detect_injection_method(image)? {
+        InjectionMethod::Debugfs => {
+            // debugfs reports missing files on stderr and still succeeds
+            let output = run_tool(Command::new("debugfs").arg("-R").arg(format!("stat \"{}\"", guest_path)).arg(image), "", "debugfs")?;
+            if !String::from_utf8_lossy(&output.stdout).contains("Type: regular") {
+                return Ok(None);
+            }
+            let output = run_tool(Command::new("debugfs").arg("-R").arg(format!("cat \"{}\"", guest_path)).arg(image), "", "debugfs")?;
+            Ok(Some(output.stdout))
+        },
+        InjectionMethod::Guestfish => {
+            let guestfish = || {
+                let mut command = Command::new("guestfish");
+                command.arg("--ro").arg("-i").arg("-a").arg(image);
+                command
+            };
+            let output = run_tool(&mut guestfish(), &format!("is-file \"{}\"\n", guest_path), "guestfish")?;
+            if String::from_utf8_lossy(&output.stdout).trim() != "true" {
+                return Ok(None);
+            }
+            let output = run_tool(&mut guestfish(), &format!("download \"{}\" -\n", guest_path), "guestfish")?;
+            Ok(Some(output.stdout))
+        },
+    }
+}
+
+/// Removes files and symbolic links from an offline disk image; paths that don't
+/// exist are skipped.
+///
+/// # Returns
+/// * `Ok(())` once none of the paths exist anymore
+/// * `Err(VmError)` if a path is invalid, the needed tool is missing or it failed
+pub fn remove_guest_files(image: &Path, guest_paths: &[&str]) -> Result<(), VmError> {
+    for guest_path in guest_paths {
+        check_guest_path(guest_path)?;
+    }
+    if guest_paths.is_empty() {
+        return Ok(());
+    }
+
+    match detect_injection_method(image)? {
+        InjectionMethod::Debugfs => {
+            let script: String = guest_paths.iter().map(|guest_path| format!("rm \"{}\"\n", guest_path)).collect();
+            run_tool(Command::new("debugfs").arg("-w").arg("-f").arg("-").arg(image), &script, "debugfs")?;
+            for guest_path in guest_paths {
+                let output = run_tool(Command::new("debugfs").arg("-R").arg(format!("stat \"{}\"", guest_path)).arg(image), "", "debugfs")?;
+                if String::from_utf8_lossy(&output.stdout).contains("Type: ") {
+                    return Err(VmError::image(format!("debugfs failed to remove {} from {}", guest_path, image.display())));
+                }
+            }
+            Ok(())
+        },
+        InjectionMethod::Guestfish => {
+            let script: String = guest_paths.iter().map(|guest_path| format!("rm-f \"{}\"\n", guest_path)).collect();
+            run_tool(Command::new("guestfish").arg("--rw").arg("-i").arg("-a").arg(image), &script, "guestfish")?;
+            Ok(())
+        },
+    }
+}
+
+/// Requires an absolute, normalized guest path that is safe to quote in tool scripts.
+fn check_guest_path(guest_path: &str) -> Result<(), VmError> {
+    let valid = guest_path.starts_with('/')
+        && !guest_path.ends_with('/')
+        && !guest_path.contains(['"', '\\'])
+        && !guest_path.chars().any(char::is_control)
+        && guest_path[1..].split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+    if !valid {
+        return Err(VmError::config(format!("invalid guest path {:?}: must be absolute and normalized", guest_path)));
+    }
+    Ok(())
+}
+
+/// Splits `/a/b/c` into its parent directories (`/a`, `/a/b`) and file name (`c`).
+fn split_guest_path(guest_path: &str) -> (Vec<&str>, &str) {
+    let (parent, name) = guest_path.rsplit_once('/').unwrap_or(("", guest_path));
+    let parents = parent.match_indices('/').skip(1).map(|(index, _)| &parent[..index])
+        .chain((!parent.is_empty()).then_some(parent))
+        .collect();
+    (parents, name)
+}
+
+fn inject_with_debugfs(image: &Path, entries: &[InjectedEntry]) -> Result<(), VmError> {
+    // debugfs keeps going after a failing command, so existing directories and
+    // files that don't exist yet are harmless; the result is verified afterwards
+    let mut script = String::new();
+    for entry in entries {
+        let guest_path = entry.get_guest_path();
+        let (parents, name) = split_guest_path(guest_path);
+        // debugfs creates top-level directories like "/etc" in the current directory
+        script.push_str("cd /\n");
+        for parent in parents {
+            script.push_str(&format!("mkdir \"{}\"\n", parent));
+        }
+        let parent = guest_path.rsplit_once('/').map(|(parent, _)| parent).filter(|parent| !parent.is_empty()).unwrap_or("/");
+        script.push_str(&format!("cd \"{}\"\nrm \"{}\"\n", parent, name));
+        match entry {
+            InjectedEntry::File { host_path, .. } => script.push_str(&format!("write \"{}\" \"{}\"\n", host_path.display(), name)),
+            InjectedEntry::Symlink { target, .. } => script.push_str(&format!("symlink \"{}\" \"{}\"\n", name, target)),
+        }
+        script.push_str(&format!("sif \"{}\" uid 0\nsif \"{}\" gid 0\n", guest_path, guest_path));
+    }
+    run_tool(Command::new("debugfs").arg("-w").arg("-f").arg("-").arg(image), &script, "debugfs")?;
+
+    for entry in entries {
+        let guest_path = entry.get_guest_path();
+        let output = run_tool(Command::new("debugfs").arg("-R").arg(format!("stat \"{}\"", guest_path)).arg(image), "", "debugfs")?;
+        let (kind, size) = match entry {
+            InjectedEntry::File { host_path, .. } => match std::fs::metadata(host_path) {
+                Ok(metadata) => ("regular", metadata.len()),
+                Err(e) => return Err(VmError::io(format!("failed to stat {}: {}", host_path.display(), e), e))
+            },
+            InjectedEntry::Symlink { target, .. } => ("symlink", target.len() as u64),
+        };
+        let stat = String::from_utf8_lossy(&output.stdout);
+        let written = get_stat_field(&stat, "Type:") == Some(kind)
+            && get_stat_field(&stat, "Size:").and_then(|written| written.parse::<u64>().ok()) == Some(size);
+        if !written {
+            return Err(VmError::image(format!("debugfs failed to write {} into {}", guest_path, image.display())));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the value following `field` (e.g. `Size:`) in the output of `debugfs stat`.
+fn get_stat_field<'a>(stat: &'a str, field: &str) -> Option<&'a str> {
+    stat.split_whitespace().skip_while(|token| *token != field).nth(1)
+}
+
+fn inject_with_guestfish(image: &Path, entries: &[InjectedEntry]) -> Result<(), VmError> {
+    let mut script = String::new();
+    for entry in entries {
+        let guest_path = entry.get_guest_path();
+        let (parents, _) = split_guest_path(guest_path);
+        if let Some(parent) = parents.last() {
+            script.push_str(&format!("mkdir-p \"{}\"\n", parent));
+        }
+        match entry {
+            InjectedEntry::File { host_path, .. } => {
+                let mode = host_file_mode(host_path)?;
+                script.push_str(&format!("upload \"{}\" \"{}\"\n", host_path.display(), guest_path));
+                script.push_str(&format!("chown 0 0 \"{}\"\nchmod {:#o} \"{}\"\n", guest_path, mode, guest_path));
+            },
+            InjectedEntry::Symlink { target, .. } => {
+                script.push_str(&format!("ln-sf \"{}\" \"{}\"\nlchown 0 0 \"{}\"\n", target, guest_path, guest_path));
+            },
+        }
+    }
+    // guestfish stops at the first failing command and exits with an error
+    run_tool(Command::new("guestfish").arg("--rw").arg("-i").arg("-a").arg(image), &script, "guestfish")?;
+    Ok(())
+}
+
+/// Returns the permission bits of a host file.
+fn host_file_mode(path: &Path) -> Result<u32, VmError> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return Err(VmError::io(format!("failed to stat {}: {}", path.display(), e), e))
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    Ok(if metadata.permissions().readonly() { 0o444 } else { 0o644 })
+}
+
+/// Runs an image tool with `script` on its standard input.
+fn run_tool(command: &mut Command, script: &str, tool: &str) -> Result<Output, VmError> {
+    let mut child = match command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(VmError::io(format!("{} is needed to write into this image but isn't installed", tool), e));
+        },
+        Err(e) => return Err(VmError::io(format!("failed to run {}: {}", tool, e), e))
+    };
+    if let Some(mut stdin) = child.stdin.take()
+        && let Err(e) = stdin.write_all(script.as_bytes()) {
+        return Err(VmError::io(format!("failed to send commands to {}: {}", tool, e), e));
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => return Err(VmError::io(format!("failed to wait for {}: {}", tool, e), e))
+    };
+    if !output.status.success() {
+        return Err(VmError::image(format!("{} failed: {}", tool, String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_paths() {
+        assert!(check_guest_path("/etc/asgard/agent.conf").is_ok());
+        for invalid in ["etc/passwd", "/etc/", "/etc/../root", "/a//b", "/a\"b", "/a\nb", "/"] {
+            assert!(check_guest_path(invalid).is_err(), "{:?} should be rejected", invalid);
+        }
+        assert_eq!(split_guest_path("/usr/local/bin/agent"), (vec!["/usr", "/usr/local", "/usr/local/bin"], "agent"));
+        assert_eq!(split_guest_path("/motd"), (vec![], "motd"));
+
+        let stat = "Inode: 12   Type: regular    Mode:  0644   Flags: 0x80000\n\
+                    User:     0   Group:     0   Project:     0   Size: 118\n\
+                    Size of extra inode fields: 32\n";
+        assert_eq!(get_stat_field(stat, "Type:"), Some("regular"));
+        assert_eq!(get_stat_field(stat, "Size:"), Some("118"));
+        assert_eq!(get_stat_field(stat, "Links:"), None);
+    }
+
+    #[test]
+    fn test_detect_injection_method() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img");
+        let mut data = vec![0u8; 4096];
+        std::fs::write(&image, &data).unwrap();
+        assert_eq!(detect_injection_method(&image).unwrap(), InjectionMethod::Guestfish);
+
+        data[1024 + 56..1024 + 58].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        std::fs::write(&image, &data).unwrap();
+        assert_eq!(detect_injection_method(&image).unwrap(), InjectionMethod::Debugfs);
+
+        std::fs::write(&image, [0u8; 16]).unwrap();
+        assert_eq!(detect_injection_method(&image).unwrap(), InjectionMethod::Guestfish);
+    }
+
+    #[test]
+    fn test_inject_into_ext4_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("root.img");
+        File::create(&image).unwrap().set_len(8 << 20).unwrap();
+        // e2fsprogs isn't installed everywhere the tests run
+        let formatted = Command::new("mkfs.ext4").arg("-q").arg("-F").arg(&image).output();
+        if !formatted.is_ok_and(|output| output.status.success()) {
+            return;
+        }
+
+        let agent = dir.path().join("agent.conf");
+        std::fs::write(&agent, b"port=1024\n").unwrap();
+        inject_files(&image, &[(&agent, "/etc/asgard/agent.conf")]).expect("Injection should succeed");
+        // Replacing an existing file works too
+        std::fs::write(&agent, b"port=2048\n").unwrap();
+        inject_files(&image, &[(&agent, "/etc/asgard/agent.conf")]).expect("Injection should succeed");
+
+        let output = Command::new("debugfs").arg("-R").arg("cat /etc/asgard/agent.conf").arg(&image).output().unwrap();
+        assert_eq!(output.stdout, b"port=2048\n");
+
+        let link = InjectedEntry::Symlink { target: "/etc/asgard/agent.conf", guest_path: "/etc/agent.conf" };
+        inject_entries(&image, &[link]).expect("Injection should succeed");
+        let output = Command::new("debugfs").arg("-R").arg("stat /etc/agent.conf").arg(&image).output().unwrap();
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Fast link dest: \"/etc/asgard/agent.conf\""));
+    }
+
+    #[test]
+    fn test_read_and_remove_in_ext4_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("root.img");
+        File::create(&image).unwrap().set_len(8 << 20).unwrap();
+        // e2fsprogs isn't installed everywhere the tests run
+        let formatted = Command::new("mkfs.ext4").arg("-q").arg("-F").arg(&image).output();
+        if !formatted.is_ok_and(|output| output.status.success()) {
+            return;
+        }
+
+        let status = dir.path().join("status");
+        std::fs::write(&status, b"0\n").unwrap();
+        inject_entries(&image, &[
+            InjectedEntry::File { host_path: &status, guest_path: "/var/lib/asgard/status" },
+            InjectedEntry::Symlink { target: "/var/lib/asgard/status", guest_path: "/status" },
+        ]).expect("Injection should succeed");
+        assert_eq!(read_guest_file(&image, "/var/lib/asgard/status").unwrap(), Some(b"0\n".to_vec()));
+        assert_eq!(read_guest_file(&image, "/var/lib/asgard/missing").unwrap(), None);
+        // Directories aren't regular files
+        assert_eq!(read_guest_file(&image, "/var/lib/asgard").unwrap(), None);
+
+        remove_guest_files(&image, &["/var/lib/asgard/status", "/status", "/missing"]).expect("Removal should succeed");
+        assert_eq!(read_guest_file(&image, "/var/lib/asgard/status").unwrap(), None);
+        let output = Command::new("debugfs").arg("-R").arg("stat /status").arg(&image).output().unwrap();
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("Type: "));
+        assert!(remove_guest_files(&image, &["relative"]).is_err());
+    }
+}
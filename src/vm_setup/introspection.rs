@@ -0,0 +1,193 @@
+//! Introspection of the memory of a running guest, for memory forensics and debuggers.
+//!
+//! `VmHandle::read_guest_memory` copies guest RAM while the vCPUs keep running, so a
+//! range the guest is writing to at the same time may be read half old, half new.
+//! `VmHandle::get_page_permissions` tells how the hypervisor maps a guest page.
+//!
+//! Write tracking protects selected guest physical ranges against writes in the
+//! hypervisor. Every write the guest makes there exits to the VMM, which completes it
+//! and records it as a `GuestWrite`; `VmHandle::take_guest_writes` collects them. The
+//! guest keeps running throughout, it only slows down on the tracked pages.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use crate::error::VmError;
+
+/// Granularity of tracked ranges: the hypervisor protects whole pages.
+pub const INTROSPECTION_PAGE_SIZE: u64 = 0x1000;
+/// Number of writes kept until they are collected; older ones are dropped first.
+pub const MAX_RECORDED_WRITES: usize = 65536;
+/// Largest range `VmHandle::read_guest_memory` copies at once.
+pub const MAX_GUEST_READ_LEN: usize = 16 * 1024 * 1024;
+
+/// Accesses the hypervisor lets the guest make to a page of its physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagePermissions {
+    /// The guest can read the page
+    pub read: bool,
+    /// The guest can write the page
+    pub write: bool,
+    /// The guest can run code from the page
+    pub execute: bool,
+}
+
+/// A write the guest made to a tracked range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestWrite {
+    /// Index of the vCPU that wrote
+    pub vcpu: u32,
+    /// Guest physical address written to
+    pub gpa: u64,
+    /// Bytes written
+    pub data: Vec<u8>,
+}
+
+/// Writes collected from the tracked ranges since the last collection.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GuestWriteLog {
+    /// Writes in the order they happened
+    pub writes: Vec<GuestWrite>,
+    /// Writes dropped because more than `MAX_RECORDED_WRITES` were waiting
+    pub dropped: u64,
+}
+
+/// Tracked ranges and the writes recorded in them.
+#[derive(Default)]
+struct TrackerState {
+    /// Tracked ranges as `(start, length)`, page aligned and disjoint
+    ranges: Vec<(u64, u64)>,
+    /// Writes not collected yet
+    writes: VecDeque<GuestWrite>,
+    /// Writes dropped since the last collection
+    dropped: u64,
+}
+
+/// Write tracking state of a VM, shared between the run control and the vCPU loops.
+#[derive(Default)]
+pub struct WriteTracker {
+    state: Mutex<TrackerState>,
+}
+
+impl WriteTracker {
+    /// Creates a tracker without tracked ranges.
+    pub fn new() -> Self {
+        WriteTracker::default()
+    }
+
+    /// Starts tracking the `len` bytes at `gpa`.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the range is empty, not page aligned or overlaps a tracked one
+    pub fn add_range(&self, gpa: u64, len: u64) -> Result<(), VmError> {
+        if len == 0 || !gpa.is_multiple_of(INTROSPECTION_PAGE_SIZE) || !len.is_multiple_of(INTROSPECTION_PAGE_SIZE) {
+            return Err(VmError::config(format!("tracked range {:#x}+{:#x} must be a non-empty multiple of {:#x} bytes at an aligned address",
+                                               gpa, len, INTROSPECTION_PAGE_SIZE)));
+        }
+        let end = match gpa.checked_add(len) {
+            Some(end) => end,
+            None => return Err(VmError::config(format!("tracked range {:#x}+{:#x} overflows", gpa, len)))
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.ranges.iter().any(|(start, length)| gpa < start + length && *start < end) {
+            return Err(VmError::config(format!("tracked range {:#x}+{:#x} overlaps a tracked range", gpa, len)));
+        }
+        state.ranges.push((gpa, len));
+        Ok(())
+    }
+
+    /// Stops tracking the range starting at `gpa`.
+    ///
+    /// # Returns
+    /// * `Some(len)` - the length of the range that was tracked
+    /// * `None` - if no tracked range starts at `gpa`
+    pub fn remove_range(&self, gpa: u64) -> Option<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let index = state.ranges.iter().position(|(start, _)| *start == gpa)?;
+        Some(state.ranges.remove(index).1)
+    }
+
+    /// Returns `true` if `gpa` lies in a tracked range.
+    pub fn is_tracked(&self, gpa: u64) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.ranges.iter().any(|(start, len)| gpa >= *start && gpa - start < *len)
+    }
+
+    /// Records a write, dropping the oldest one if `MAX_RECORDED_WRITES` are waiting.
+    pub fn record(&self, write: GuestWrite) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.writes.len() == MAX_RECORDED_WRITES {
+            state.writes.pop_front();
+            state.dropped += 1;
+        }
+        state.writes.push_back(write);
+    }
+
+    /// Returns the writes recorded since the last call and forgets them.
+    pub fn take_writes(&self) -> GuestWriteLog {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let dropped = std::mem::take(&mut state.dropped);
+        GuestWriteLog { writes: state.writes.drain(..).collect(), dropped }
+    }
+}
+
+/// Copies the `len` bytes of guest RAM at `gpa`.
+///
+/// # Returns
+/// * `Ok(Vec<u8>)` with the bytes on success
+/// * `Err(VmError)` if `len` exceeds `MAX_GUEST_READ_LEN` or the range isn't in guest RAM
+pub fn read_guest_range(guest_memory: &GuestMemoryMmap, gpa: u64, len: usize) -> Result<Vec<u8>, VmError> {
+    if len > MAX_GUEST_READ_LEN {
+        return Err(VmError::config(format!("can't read more than {} bytes of guest memory at once", MAX_GUEST_READ_LEN)));
+    }
+    let mut data = vec![0u8; len];
+    match guest_memory.read_slice(&mut data, GuestAddress(gpa)) {
+        Ok(()) => Ok(data),
+        Err(e) => Err(VmError::memory_source(format!("failed to read guest memory at {:#x}+{:#x}: {}", gpa, len, e), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracked_ranges() {
+        let tracker = WriteTracker::new();
+        tracker.add_range(0x1000, 0x2000).unwrap();
+        assert!(tracker.add_range(0x2000, 0x1000).is_err());
+        assert!(tracker.add_range(0x3800, 0x1000).is_err());
+        assert!(tracker.add_range(0x3000, 0).is_err());
+        assert!(!tracker.is_tracked(0xfff));
+        assert!(tracker.is_tracked(0x1000));
+        assert!(tracker.is_tracked(0x2fff));
+        assert!(!tracker.is_tracked(0x3000));
+
+        assert_eq!(tracker.remove_range(0x2000), None);
+        assert_eq!(tracker.remove_range(0x1000), Some(0x2000));
+        assert!(!tracker.is_tracked(0x1000));
+    }
+
+    #[test]
+    fn test_read_guest_range() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2000)]).unwrap();
+        guest_memory.write_slice(b"asgard", GuestAddress(0xffd)).unwrap();
+        assert_eq!(read_guest_range(&guest_memory, 0xffd, 6).unwrap(), b"asgard");
+        assert!(read_guest_range(&guest_memory, 0x1ffe, 4).is_err());
+        assert!(read_guest_range(&guest_memory, 0, MAX_GUEST_READ_LEN + 1).is_err());
+    }
+
+    #[test]
+    fn test_writes_are_collected_in_order() {
+        let tracker = WriteTracker::new();
+        for index in 0..MAX_RECORDED_WRITES as u64 + 2 {
+            tracker.record(GuestWrite { vcpu: 0, gpa: index, data: vec![1] });
+        }
+        let log = tracker.take_writes();
+        assert_eq!(log.dropped, 2);
+        assert_eq!(log.writes.len(), MAX_RECORDED_WRITES);
+        assert_eq!(log.writes[0].gpa, 2);
+        assert_eq!(tracker.take_writes(), GuestWriteLog::default());
+    }
+}
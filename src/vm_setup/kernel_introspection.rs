@@ -0,0 +1,350 @@
+//! Symbol-aware introspection of a running Linux guest kernel.
+//!
+//! `KernelSymbols` is built from the `System.map` of the guest kernel, e.g. extracted
+//! with `kernel_setup::linux_setup::extract_system_map_from_image`, and resolves symbols
+//! both ways. Reading kernel data then takes two more pieces the map lacks: where the
+//! kernel maps guest physical memory (`KernelMemoryLayout`) and the offsets of the
+//! structure fields involved (`TaskStructLayout`), which depend on the kernel build and
+//! come from its BTF or debug information.
+//!
+//! The helpers read through a `GuestPhysicalReader`, such as a running `VmHandle`. The
+//! guest keeps running while its structures are walked, so a list it changes at the
+//! same time may be read inconsistently; walks are bounded so they always end.
+
+use std::collections::HashMap;
+use std::path::Path;
+use vm_memory::GuestMemoryMmap;
+use crate::error::VmError;
+use crate::vm_setup::introspection::read_guest_range;
+use crate::vm_setup::vm_handle::VmHandle;
+
+/// Base of the kernel image mapping of x86_64 Linux (`__START_KERNEL_map`).
+pub const X86_64_START_KERNEL_MAP: u64 = 0xffff_ffff_8000_0000;
+/// Base of the direct mapping of physical memory of x86_64 Linux without KASLR.
+pub const X86_64_PAGE_OFFSET: u64 = 0xffff_8880_0000_0000;
+/// Most tasks `read_task_list` walks before giving up on a list that doesn't loop back.
+pub const MAX_GUEST_TASKS: usize = 65536;
+/// Length of `task_struct.comm`, the task name (`TASK_COMM_LEN`).
+const TASK_COMM_LEN: usize = 16;
+
+/// Source of guest physical memory for the introspection helpers.
+pub trait GuestPhysicalReader {
+    /// Copies the `len` bytes of guest RAM at `gpa`.
+    fn read_physical(&self, gpa: u64, len: usize) -> Result<Vec<u8>, VmError>;
+}
+
+impl GuestPhysicalReader for VmHandle {
+    fn read_physical(&self, gpa: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        self.read_guest_memory(gpa, len)
+    }
+}
+
+impl GuestPhysicalReader for GuestMemoryMmap {
+    fn read_physical(&self, gpa: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        read_guest_range(self, gpa, len)
+    }
+}
+
+/// Symbols of a guest kernel, from its `System.map`.
+#[derive(Debug, Clone, Default)]
+pub struct KernelSymbols {
+    by_name: HashMap<String, u64>,
+    /// `(address, name)` sorted by address
+    by_address: Vec<(u64, String)>,
+}
+
+impl KernelSymbols {
+    /// Parses the `address type name` lines of a `System.map`.
+    ///
+    /// # Returns
+    /// * `Ok(KernelSymbols)` on success
+    /// * `Err(VmError)` if a line is malformed or the map holds no symbol
+    pub fn parse(system_map: &str) -> Result<KernelSymbols, VmError> {
+        let mut symbols = KernelSymbols::default();
+        for (index, line) in system_map.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let (address, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(address), Some(_), Some(name)) => (address, name),
+                _ => return Err(VmError::image(format!("System.map line {} is malformed: {}", index + 1, line)))
+            };
+            let address = match u64::from_str_radix(address, 16) {
+                Ok(address) => address,
+                Err(e) => return Err(VmError::image_source(format!("System.map line {} has an invalid address: {}", index + 1, e), e))
+            };
+            // The first definition wins, as for duplicate local symbols in kallsyms
+            symbols.by_name.entry(name.to_string()).or_insert(address);
+            symbols.by_address.push((address, name.to_string()));
+        }
+        if symbols.by_address.is_empty() {
+            return Err(VmError::image("System.map holds no symbol"));
+        }
+        symbols.by_address.sort();
+        Ok(symbols)
+    }
+
+    /// Reads and parses the `System.map` at `path`, see `parse`.
+    pub fn from_file(path: &Path) -> Result<KernelSymbols, VmError> {
+        match std::fs::read_to_string(path) {
+            Ok(system_map) => KernelSymbols::parse(&system_map),
+            Err(e) => Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e))
+        }
+    }
+
+    /// Moves every symbol by `slide` bytes, for a kernel KASLR placed away from its
+    /// link address: the slide is the running address of `_text` minus the mapped one.
+    pub fn relocate(mut self, slide: i64) -> Self {
+        for address in self.by_name.values_mut() {
+            *address = address.wrapping_add_signed(slide);
+        }
+        for (address, _) in self.by_address.iter_mut() {
+            *address = address.wrapping_add_signed(slide);
+        }
+        self
+    }
+
+    /// Returns the number of symbols.
+    pub fn len(&self) -> usize {
+        self.by_address.len()
+    }
+
+    /// Returns `true` if there are no symbols; a parsed map always has some.
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+
+    /// Returns the address of the symbol `name`.
+    pub fn get_address(&self, name: &str) -> Option<u64> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Resolves `address` to the symbol it lies in.
+    ///
+    /// # Returns
+    /// * `Some((name, offset))` - the closest symbol at or below `address` and how far past it `address` is
+    /// * `None` - if `address` is below every symbol
+    pub fn resolve(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.by_address.partition_point(|(symbol, _)| *symbol <= address).checked_sub(1)?;
+        let (symbol, name) = &self.by_address[index];
+        Some((name.as_str(), address - symbol))
+    }
+}
+
+/// Where the guest kernel maps physical memory, to turn its virtual addresses into
+/// guest physical ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelMemoryLayout {
+    /// Virtual base of the kernel image mapping
+    pub kernel_map: u64,
+    /// Physical address the kernel image mapping starts at (`phys_base`)
+    pub phys_base: u64,
+    /// Virtual base of the direct mapping of physical memory (`page_offset_base`)
+    pub page_offset: u64,
+}
+
+impl Default for KernelMemoryLayout {
+    /// The layout of an x86_64 kernel booted with `nokaslr`.
+    fn default() -> Self {
+        KernelMemoryLayout { kernel_map: X86_64_START_KERNEL_MAP, phys_base: 0, page_offset: X86_64_PAGE_OFFSET }
+    }
+}
+
+impl KernelMemoryLayout {
+    /// Translates a kernel virtual address of the image or the direct mapping.
+    ///
+    /// # Returns
+    /// * `Some(gpa)` - the guest physical address
+    /// * `None` - if `address` is in neither mapping, e.g. vmalloc or module space, or
+    ///   lies past the end of the physical address space
+    pub fn translate(&self, address: u64) -> Option<u64> {
+        if address >= self.kernel_map {
+            (address - self.kernel_map).checked_add(self.phys_base)
+        } else if address >= self.page_offset {
+            // The direct mapping spans at most 64 TiB
+            let offset = address - self.page_offset;
+            (offset < 1 << 46).then_some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Offsets of the `task_struct` fields the task list walk reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStructLayout {
+    /// Offset of `tasks`, the `list_head` linking every process
+    pub tasks: u64,
+    /// Offset of `pid`
+    pub pid: u64,
+    /// Offset of `comm`, the task name
+    pub comm: u64,
+}
+
+/// A task of the guest kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestTask {
+    /// Kernel virtual address of the `task_struct`
+    pub address: u64,
+    /// Process ID
+    pub pid: i32,
+    /// Task name, at most 15 bytes
+    pub comm: String,
+}
+
+/// A running guest kernel, as far as introspection knows it.
+pub struct GuestKernel {
+    symbols: KernelSymbols,
+    layout: KernelMemoryLayout,
+}
+
+impl GuestKernel {
+    /// Creates the view of a guest kernel with the given symbols and memory layout.
+    pub fn new(symbols: KernelSymbols, layout: KernelMemoryLayout) -> Self {
+        GuestKernel { symbols, layout }
+    }
+
+    /// Returns the symbols of the kernel.
+    pub fn get_symbols(&self) -> &KernelSymbols {
+        &self.symbols
+    }
+
+    /// Copies the `len` bytes at kernel virtual address `address`. The range must not
+    /// cross out of the mapping it starts in.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` with the bytes on success
+    /// * `Err(VmError)` if the address isn't translatable or the memory can't be read
+    pub fn read_virtual(&self, memory: &dyn GuestPhysicalReader, address: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        match self.layout.translate(address) {
+            Some(gpa) => memory.read_physical(gpa, len),
+            None => Err(VmError::config(format!("kernel address {:#x} is outside the image and the direct mapping", address)))
+        }
+    }
+
+    /// Copies the `len` bytes of the kernel variable `name`.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` with the bytes on success
+    /// * `Err(VmError)` if the symbol is unknown or its memory can't be read
+    pub fn read_symbol(&self, memory: &dyn GuestPhysicalReader, name: &str, len: usize) -> Result<Vec<u8>, VmError> {
+        match self.symbols.get_address(name) {
+            Some(address) => self.read_virtual(memory, address, len),
+            None => Err(VmError::config(format!("the guest kernel has no symbol {}", name)))
+        }
+    }
+
+    /// Reads a little-endian 64-bit value at kernel virtual address `address`.
+    fn read_u64(&self, memory: &dyn GuestPhysicalReader, address: u64) -> Result<u64, VmError> {
+        let bytes = self.read_virtual(memory, address, 8)?;
+        Ok(u64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]))
+    }
+
+    /// Lists the processes of the guest by walking the task list from `init_task`,
+    /// which comes first.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<GuestTask>)` in list order on success
+    /// * `Err(VmError)` if `init_task` is unknown, a task pointer is invalid, a task
+    ///   can't be read or the list doesn't loop back within `MAX_GUEST_TASKS` tasks
+    pub fn read_task_list(&self, memory: &dyn GuestPhysicalReader, task_layout: &TaskStructLayout) -> Result<Vec<GuestTask>, VmError> {
+        let init_task = match self.symbols.get_address("init_task") {
+            Some(address) => address,
+            None => return Err(VmError::config("the guest kernel has no symbol init_task"))
+        };
+        // Task pointers come from guest memory, so the field addresses may overflow
+        let field = |task: u64, offset: u64| match task.checked_add(offset) {
+            Some(address) => Ok(address),
+            None => Err(VmError::config(format!("invalid guest task pointer {:#x}", task)))
+        };
+        let head = field(init_task, task_layout.tasks)?;
+        let mut tasks = Vec::new();
+        let mut task = init_task;
+        loop {
+            if tasks.len() == MAX_GUEST_TASKS {
+                return Err(VmError::config(format!("the guest task list doesn't end within {} tasks", MAX_GUEST_TASKS)));
+            }
+            let pid = self.read_virtual(memory, field(task, task_layout.pid)?, 4)?;
+            let comm = self.read_virtual(memory, field(task, task_layout.comm)?, TASK_COMM_LEN)?;
+            let comm_len = comm.iter().position(|byte| *byte == 0).unwrap_or(TASK_COMM_LEN);
+            tasks.push(GuestTask {
+                address: task,
+                pid: i32::from_le_bytes([pid[0], pid[1], pid[2], pid[3]]),
+                comm: String::from_utf8_lossy(&comm[..comm_len]).into_owned(),
+            });
+
+            // tasks.next points at the tasks field of the next task_struct
+            let next = self.read_u64(memory, field(task, task_layout.tasks)?)?;
+            if next == head {
+                return Ok(tasks);
+            }
+            task = match next.checked_sub(task_layout.tasks) {
+                Some(task) => task,
+                None => return Err(VmError::config(format!("invalid guest task list entry {:#x}", next)))
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::{Bytes, GuestAddress};
+
+    const SYSTEM_MAP: &str = "\
+ffffffff80000000 T _text
+ffffffff80000400 T start_kernel
+ffffffff80001000 D init_task
+";
+
+    #[test]
+    fn test_symbols_resolve_both_ways() {
+        let symbols = KernelSymbols::parse(SYSTEM_MAP).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.get_address("start_kernel"), Some(0xffff_ffff_8000_0400));
+        assert_eq!(symbols.resolve(0xffff_ffff_8000_0410), Some(("start_kernel", 0x10)));
+        assert_eq!(symbols.resolve(0xffff_ffff_7fff_ffff), None);
+
+        let symbols = symbols.relocate(0x20_0000);
+        assert_eq!(symbols.get_address("_text"), Some(0xffff_ffff_8020_0000));
+        assert_eq!(symbols.resolve(0xffff_ffff_8020_1000), Some(("init_task", 0)));
+
+        assert!(KernelSymbols::parse("").is_err());
+        assert!(KernelSymbols::parse("zzzz T _text").is_err());
+        assert!(KernelSymbols::parse("ffffffff80000000 _text").is_err());
+    }
+
+    #[test]
+    fn test_read_task_list() {
+        let task_layout = TaskStructLayout { tasks: 0x10, pid: 0x20, comm: 0x30 };
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x4000)]).unwrap();
+        // init_task in the kernel image, one task in the direct mapping at 0x2000
+        let init_task = X86_64_START_KERNEL_MAP + 0x1000;
+        let task = X86_64_PAGE_OFFSET + 0x2000;
+        memory.write_slice(&(task + 0x10).to_le_bytes(), GuestAddress(0x1010)).unwrap();
+        memory.write_slice(&0i32.to_le_bytes(), GuestAddress(0x1020)).unwrap();
+        memory.write_slice(b"swapper/0\0", GuestAddress(0x1030)).unwrap();
+        memory.write_slice(&(init_task + 0x10).to_le_bytes(), GuestAddress(0x2010)).unwrap();
+        memory.write_slice(&1i32.to_le_bytes(), GuestAddress(0x2020)).unwrap();
+        memory.write_slice(b"init\0", GuestAddress(0x2030)).unwrap();
+
+        let kernel = GuestKernel::new(KernelSymbols::parse(SYSTEM_MAP).unwrap(), KernelMemoryLayout::default());
+        let tasks = kernel.read_task_list(&memory, &task_layout).unwrap();
+        assert_eq!(tasks, vec![
+            GuestTask { address: init_task, pid: 0, comm: "swapper/0".to_string() },
+            GuestTask { address: task, pid: 1, comm: "init".to_string() },
+        ]);
+        assert_eq!(kernel.read_symbol(&memory, "init_task", 4).unwrap(), vec![0; 4]);
+        assert!(kernel.read_symbol(&memory, "no_such_symbol", 4).is_err());
+
+        // A list that never loops back to init_task ends in an error, not a hang
+        memory.write_slice(&(task + 0x10).to_le_bytes(), GuestAddress(0x2010)).unwrap();
+        assert!(kernel.read_task_list(&memory, &task_layout).is_err());
+
+        // Task pointers whose fields lie past the end of the address space are refused
+        for next in [u64::MAX, 0x8] {
+            memory.write_slice(&next.to_le_bytes(), GuestAddress(0x2010)).unwrap();
+            assert!(kernel.read_task_list(&memory, &task_layout).is_err());
+        }
+        let layout = KernelMemoryLayout { phys_base: u64::MAX, ..KernelMemoryLayout::default() };
+        assert_eq!(layout.translate(X86_64_START_KERNEL_MAP + 0x1000), None);
+    }
+}
@@ -1,12 +1,61 @@
 //! Linux VM setup and execution utilities using KVM and Tokio.
 //!
 //! This module provides the `run_vm` async function to launch and manage a KVM-based VM instance
-//! with the configuration provided by `VmSetup`.
+//! with the configuration provided by `VmSetup`. VMs wanting more memory than the host has
+//! available are refused before any of it is allocated. `KvmBackend` runs the vCPUs
+//! through the exit loop shared with the other hypervisors, see `backend`.
 
-use kvm_ioctls::{Kvm, VcpuExit};
-use crate::vm_setup::setup_utils::VmSetup;
+use kvm_ioctls::{Kvm, VcpuExit as KvmExit, VcpuFd};
+use crate::vm_setup::setup_utils::{SerialConsole, VirtioTransport, VmSetup, PMEM_ALIGNMENT};
+use crate::vm_setup::vm_handle::{VmHandle, VmControl, RunControlHooks};
+use crate::vm_setup::backend::{run_vcpus, DiskMetrics, HypervisorBackend, VcpuDevices, VcpuExit};
+use crate::vm_setup::exit_summary::VmExitSummary;
+use crate::vm_setup::exit_trace::ExitRecorder;
+use crate::vm_setup::mitigations::{read_host_mitigations, MitigationPolicy};
+use crate::vm_setup::security_features::SecurityFeaturePolicy;
+use crate::vm_setup::pmu::GuestPmu;
+use crate::vm_setup::guest_profiler::GuestMode;
+use crate::vm_setup::introspection::{read_guest_range, PagePermissions};
+use crate::vm_setup::cpu_model::{CpuModel, CpuidEntry};
+use crate::kernel_setup::loader::load_kernel;
+use crate::kernel_setup::x86_64_boot::{setup_boot_environment, configure_boot_vcpu, write_screen_info, LinearFramebuffer};
 use vm_memory::{GuestAddress, GuestMemoryMmap, GuestMemory};
 use kvm_bindings;
+use crate::vm_setup::attachments::{check_attachments, forward_stdin_to_serial, open_serial_output, Attachment};
+use crate::vm_setup::disk_setup::{open_cdrom_image, open_disk};
+use crate::vm_setup::boot_order::select_boot_device;
+use crate::vm_setup::memory_slots::MemorySlotRegistry;
+use crate::vm_setup::boot_progress::BootLogWatcher;
+use crate::vm_setup::console_log::ConsoleRecorder;
+use crate::device_emulation::block_device::virtio_block::{CacheMode, VirtioBlockDevice};
+use crate::device_emulation::mmio::MmioBus;
+use crate::device_emulation::pci::{PciBarAllocator, PciBus, PciEcam, PciMmioWindow, PCI_ECAM_SIZE};
+use crate::device_emulation::rng::VirtioRng;
+use crate::device_emulation::virtio_9p::Virtio9p;
+use crate::device_emulation::balloon::VirtioBalloon;
+use crate::device_emulation::pmem::{map_pmem_file, VirtioPmem};
+use crate::device_emulation::framebuffer::{get_framebuffer_size, Framebuffer};
+use crate::device_emulation::shared_memory::{SharedMemoryDevice, SharedMemoryMapping, SHARED_MEMORY_REGISTERS_SIZE};
+use crate::device_emulation::virtio_mmio::{MmioTransport, VIRTIO_MMIO_SIZE};
+use crate::device_emulation::virtio_pci::{VirtioPciTransport, VIRTIO_PCI_BAR_SIZE};
+use crate::device_emulation::pio::PortIoBus;
+use crate::device_emulation::legacy::{
+    I8042Device, PciConfigPorts, PvPanicDevice, I8042_PORT_BASE, I8042_PORT_COUNT, PCI_CONFIG_PORT_BASE,
+    PCI_CONFIG_PORT_COUNT, PVPANIC_PORT,
+};
+use crate::device_emulation::serial::{Serial, COM1_IRQ, COM1_PORT_BASE, COM_PORT_COUNT};
+use crate::utils::signals::TriggerMode;
+use crate::utils::signals::gsi_allocator::GsiConflictPolicy;
+use crate::utils::signals::irq_router::IrqRouter;
+use crate::vm_setup::memory_monitor::MemoryStatus;
+use crate::linux_bindings::{check_host_resources, get_physical_memory_info};
+use crate::error::VmError;
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
+
+pub use crate::linux_bindings::{get_cpu_topology, CpuTopology};
 
 /// Asynchronously runs a virtual machine using KVM with the provided setup.
 ///
@@ -14,122 +63,617 @@ use kvm_bindings;
 /// * `setup` - The VM configuration to use (memory size, CPU count, etc).
 ///
 /// # Returns
-/// * `Ok(())` if the VM runs successfully.
-/// * `Err(String)` if any error occurs during setup or execution.
-pub async fn run_vm(setup: VmSetup) -> Result<(), String> {
-    // Create a new KVM instance
-    let kvm = match Kvm::new() {
-        Ok(kvm) => kvm,
-        Err(e) => return Err(format!("Failed to create KVM instance: {}", e)),
-    };
-    // Create a new VM from the KVM instance
-    let vm = match kvm.create_vm() {
-        Ok(vm) => vm,
-        Err(e) => return Err(format!("Failed to create VM: {}", e))
-    };
+/// * `Ok(VmExitSummary)` with how each vCPU exited and the disk statistics if the VM runs successfully.
+/// * `Err(VmError)` if any error occurs during setup or execution.
+pub async fn run_vm(setup: VmSetup) -> Result<VmExitSummary, VmError> {
+    run_vm_with_control(setup, Arc::new(VmControl::new())).await
+}
 
-    // Set up guest memory at a specific address
-    let guest_phys_addr = 0x100000;
-    let load_addr = GuestAddress(guest_phys_addr);
-    let guest_memory: GuestMemoryMmap = match GuestMemoryMmap::from_ranges(&[(load_addr, setup.get_memory_size())]) {
-        Ok(mem) => mem,
-        Err(e) => return Err(format!("Failed to create guest memory: {}", e)),
-    };
+/// Starts the VM configured by `setup` in the background.
+///
+/// Must be called from within a Tokio runtime.
+///
+/// # Returns
+/// * A `VmHandle` to pause, resume, stop and wait for the VM
+pub fn spawn_vm(setup: VmSetup) -> VmHandle {
+    VmHandle::spawn(move |control| run_vm_with_control(setup, control))
+}
 
-    let host_addr = match guest_memory.get_host_address(load_addr) {
-        Ok(addr) => addr,
-        Err(e) => return Err(format!("Failed to get host address for guest memory: {}", e)),
-    };
+/// Signal handler for the vCPU kick signal. It does nothing: receiving the signal
+/// is enough to make a pending `KVM_RUN` return with `EINTR`.
+extern "C" fn handle_kick_signal(_num: libc::c_int, _info: *mut libc::siginfo_t, _unused: *mut libc::c_void) {}
+
+/// KVM VM. It is its own run control: vCPU threads are kicked out of `KVM_RUN` with `SIGRTMIN`.
+struct KvmBackend {
+    kvm: Kvm,
+    vm: kvm_ioctls::VmFd,
+    /// CPUID table every vCPU gets
+    cpuid: kvm_bindings::CpuId,
+    /// Entry point of the directly booted kernel, which the boot vCPU starts at
+    kernel_entry: Option<u64>,
+    /// Start of guest RAM, where vCPUs start without a kernel
+    ram_base: u64,
+    /// Threads currently running a vCPU loop
+    threads: Mutex<Vec<libc::pthread_t>>,
+    /// Keyboard controller, polled for the guest's reset request
+    i8042: Arc<Mutex<I8042Device>>,
+    /// Balloon device the VmHandle sets the target of
+    balloon: Option<Arc<Mutex<MmioTransport<VirtioBalloon>>>>,
+    /// Guest RAM, read by memory introspection
+    guest_memory: Option<GuestMemoryMmap>,
+    /// Memory slots of the VM, telling how each guest page is mapped
+    memory_slots: Mutex<MemorySlotRegistry>,
+    /// Host memory shared with the guest and pmem files, mapped for as long as the VM
+    host_mappings: Vec<Arc<SharedMemoryMapping>>,
+    /// Screen of the guest, captured by screenshots
+    framebuffer: Option<Framebuffer>,
+}
+
+impl RunControlHooks for KvmBackend {
+    fn kick(&self) {
+        for thread in self.threads.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            // SAFETY: the thread is alive while registered and the signal has a handler
+            unsafe { libc::pthread_kill(*thread, SIGRTMIN()) };
+        }
+    }
+
+    fn set_balloon_target(&self, bytes: u64) -> Result<(), VmError> {
+        match &self.balloon {
+            Some(balloon) => {
+                let mut transport = balloon.lock().unwrap_or_else(|e| e.into_inner());
+                transport.get_device_mut().set_target(bytes);
+                transport.signal_config_change()
+            },
+            None => Err(VmError::device("the VM has no balloon device"))
+        }
+    }
+
+    fn get_balloon_size(&self) -> Option<u64> {
+        let balloon = self.balloon.as_ref()?;
+        Some(balloon.lock().unwrap_or_else(|e| e.into_inner()).get_device().get_actual())
+    }
+
+    fn read_guest_memory(&self, gpa: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        match &self.guest_memory {
+            Some(guest_memory) => read_guest_range(guest_memory, gpa, len),
+            None => Err(VmError::memory("the VM has no guest RAM"))
+        }
+    }
+
+    fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+        match &self.framebuffer {
+            Some(framebuffer) => framebuffer.screenshot(),
+            None => Err(VmError::device("the VM has no display"))
+        }
+    }
+
+    fn get_page_permissions(&self, gpa: u64) -> Result<Option<PagePermissions>, VmError> {
+        let memory_slots = self.memory_slots.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(memory_slots.find_region(gpa).map(|slot| PagePermissions {
+            read: true,
+            write: slot.flags & kvm_bindings::KVM_MEM_READONLY == 0,
+            execute: true,
+        }))
+    }
+}
+
+impl HypervisorBackend for KvmBackend {
+    type Vcpu = VcpuFd;
+    type Memory = GuestMemoryMmap;
+
+    fn create_vm(setup: &VmSetup) -> Result<Self, VmError> {
+        // Create a new KVM instance
+        let kvm = match Kvm::new() {
+            Ok(kvm) => kvm,
+            Err(e) => return Err(VmError::hypervisor_source(format!("Failed to create KVM instance: {}", e), e)),
+        };
+        // Create a new VM from the KVM instance
+        let vm = match kvm.create_vm() {
+            Ok(vm) => vm,
+            Err(e) => return Err(VmError::hypervisor_source(format!("Failed to create VM: {}", e), e))
+        };
+
+        // Build the CPUID table exposed to every vCPU from the configured CPU model and
+        // mitigation policy
+        let cpuid = get_guest_cpuid(&kvm, setup.get_cpu_model(), setup.get_mitigation_policy(), setup.get_security_features(), setup.get_guest_pmu())?;
+        // Hidden CPUID leaves don't stop the guest from probing the PMU MSRs, so turn the
+        // PMU off for the whole VM before its vCPUs exist where KVM allows it
+        if setup.get_guest_pmu() == GuestPmu::Disabled {
+            disable_guest_pmu(&kvm, &vm)?;
+        }
+
+        // Let the VmHandle kick vCPU threads out of KVM_RUN
+        if let Err(e) = register_signal_handler(SIGRTMIN(), handle_kick_signal) {
+            return Err(VmError::hypervisor_source(format!("Failed to register vCPU kick signal handler: {}", e), e));
+        }
 
-    // Register the memory region with the VM
-    if let Err(e) = unsafe {
-        vm.set_user_memory_region(kvm_bindings::kvm_userspace_memory_region {
-        slot: 0,
-        guest_phys_addr: guest_phys_addr,
-        memory_size: setup.get_memory_size() as u64,
-        userspace_addr: host_addr as u64,
-        flags: 0,
+        // The registry keeps track of the memory slots so regions can be added or removed later on
+        let memory_slots = Mutex::new(MemorySlotRegistry::new(kvm.get_nr_memslots() as u32));
+        Ok(KvmBackend {
+            kvm,
+            vm,
+            cpuid,
+            kernel_entry: None,
+            ram_base: 0,
+            threads: Mutex::new(Vec::new()),
+            i8042: Arc::new(Mutex::new(I8042Device::new())),
+            balloon: None,
+            guest_memory: None,
+            memory_slots,
+            host_mappings: Vec::new(),
+            framebuffer: None,
         })
-    } {
-        return Err(format!("Failed to set memory region: {}", e));
-    };
+    }
+
+    fn map_memory(&mut self, gpa: u64, size: usize) -> Result<GuestMemoryMmap, VmError> {
+        let load_addr = GuestAddress(gpa);
+        let guest_memory: GuestMemoryMmap = match GuestMemoryMmap::from_ranges(&[(load_addr, size)]) {
+            Ok(mem) => mem,
+            Err(e) => return Err(VmError::memory_source(format!("Failed to create guest memory: {}", e), e)),
+        };
 
-    // Spawn a blocking task for each virtual CPU core
-    let mut handlers: Vec<tokio::task::JoinHandle<Result<String, String>>> =
-        Vec::with_capacity(setup.get_cpu_cores_count() as usize);
-    for cpu_id in 0..setup.get_cpu_cores_count() {
-        // Create a VCPU for this core
-        let mut vcpu = match vm.create_vcpu(cpu_id as u64) {
+        let host_addr = match guest_memory.get_host_address(load_addr) {
+            Ok(addr) => addr,
+            Err(e) => return Err(VmError::memory_source(format!("Failed to get host address for guest memory: {}", e), e)),
+        };
+
+        // Register the memory region with the VM
+        let memory_slots = self.memory_slots.get_mut().unwrap_or_else(|e| e.into_inner());
+        // SAFETY: the backend keeps a handle on guest_memory, so the mapping lives as long as the VM
+        unsafe { memory_slots.add_region(&self.vm, gpa, size as u64, host_addr as u64, 0)? };
+        self.ram_base = gpa;
+        self.guest_memory = Some(guest_memory.clone());
+        Ok(guest_memory)
+    }
+
+    fn create_vcpu(&self, index: u32) -> Result<VcpuFd, VmError> {
+        let vcpu = match self.vm.create_vcpu(index as u64) {
             Ok(vcpu) => vcpu,
-            Err(e) => return Err(format!("Failed to create VCPU {}: {}", cpu_id, e)),
+            Err(e) => return Err(VmError::hypervisor_source(format!("Failed to create VCPU {}: {}", index, e), e)),
         };
 
-        // Set initial register state for the VCPU
-        let mut regs = match vcpu.get_regs() {
+        if let Err(e) = vcpu.set_cpuid2(&self.cpuid) {
+            return Err(VmError::hypervisor_source(format!("Failed to set VCPU {} CPUID: {}", index, e), e));
+        }
+
+        // The boot vCPU enters the kernel in long mode. With the in-kernel irqchip the
+        // other vCPUs wait for the INIT/SIPI sequence sent by the kernel.
+        match self.kernel_entry {
+            Some(entry_point) if index == 0 => configure_boot_vcpu(&vcpu, entry_point)?,
+            Some(_) => {},
+            None => set_flat_start(&vcpu, index, self.ram_base)?,
+        }
+        Ok(vcpu)
+    }
+
+    fn register_vcpu(&self, _vcpu: &VcpuFd, _index: u32) {
+        let thread = unsafe { libc::pthread_self() };
+        self.threads.lock().unwrap_or_else(|e| e.into_inner()).push(thread);
+    }
+
+    fn unregister_vcpu(&self, _vcpu: &VcpuFd, _index: u32) {
+        let thread = unsafe { libc::pthread_self() };
+        self.threads.lock().unwrap_or_else(|e| e.into_inner()).retain(|t| *t != thread);
+    }
+
+    fn run_vcpu<'a>(&self, vcpu: &'a mut VcpuFd, index: u32, _devices: &VcpuDevices) -> Result<VcpuExit<'a>, VmError> {
+        // A reset pulsed through the i8042 by the last port write stops the whole VM
+        if self.i8042.lock().unwrap_or_else(|e| e.into_inner()).is_reset_requested() {
+            return Ok(VcpuExit::Reset);
+        }
+        match vcpu.run() {
+            Ok(KvmExit::Hlt) => Ok(VcpuExit::Hlt),
+            Ok(KvmExit::IoIn(port, data)) => Ok(VcpuExit::PioIn(port, data)),
+            Ok(KvmExit::IoOut(port, data)) => Ok(VcpuExit::PioOut(port, data)),
+            Ok(KvmExit::MmioRead(address, data)) => Ok(VcpuExit::MmioRead(address, data)),
+            Ok(KvmExit::MmioWrite(address, data)) => Ok(VcpuExit::MmioWrite(address, data)),
+            Ok(KvmExit::Shutdown) => Ok(VcpuExit::Shutdown),
+            Ok(KvmExit::InternalError) => {
+                Ok(VcpuExit::Exception { reason: "encountered an internal error".to_string() })
+            },
+            Ok(KvmExit::SystemEvent(..)) => {
+                Ok(VcpuExit::Exception { reason: "encountered a system event".to_string() })
+            },
+            Ok(exit_reason) => {
+                Ok(VcpuExit::Exception { reason: format!("exited with unhandled reason {:?}", exit_reason) })
+            },
+            // Kicked by the run control; re-check the run state
+            Err(e) if e.errno() == libc::EINTR => Ok(VcpuExit::Canceled),
+            Err(e) => {
+                Err(VmError::hypervisor_source(format!("VCPU {} encountered an error: {}", index, e), e))
+            }
+        }
+    }
+
+    fn get_guest_position(&self, vcpu: &VcpuFd, index: u32) -> Result<Option<(GuestMode, u64)>, VmError> {
+        let regs = match vcpu.get_regs() {
             Ok(regs) => regs,
-            Err(e) => return Err(format!("Failed to get VCPU {} registers: {}", cpu_id, e)),
+            Err(e) => return Err(VmError::hypervisor_source(format!("Failed to get VCPU {} registers: {}", index, e), e)),
         };
+        let sregs = match vcpu.get_sregs() {
+            Ok(sregs) => sregs,
+            Err(e) => return Err(VmError::hypervisor_source(format!("Failed to get VCPU {} special registers: {}", index, e), e)),
+        };
+        let mode = if sregs.cs.dpl == 0 { GuestMode::Kernel } else { GuestMode::User };
+        Ok(Some((mode, regs.rip)))
+    }
 
-        regs.rip = guest_phys_addr; // Set instruction pointer to the start address
-        regs.rflags = 0x2;
+    fn get_host_memory() -> Result<MemoryStatus, VmError> {
+        let (total, available) = get_physical_memory_info()?;
+        Ok(MemoryStatus { total, available })
+    }
+}
 
-        if let Err(e) = vcpu.set_regs(&regs) {
-            return Err(format!("Failed to set VCPU {} registers: {}", cpu_id, e));
-        };
+/// Runs the VM configured by `setup`, with its vCPUs obeying `control`.
+async fn run_vm_with_control(setup: VmSetup, control: Arc<VmControl>) -> Result<VmExitSummary, VmError> {
+    check_attachments(&setup, "KVM", &[Attachment::DiskImage, Attachment::CdromImage, Attachment::Kernel, Attachment::SerialConsole, Attachment::SharedDirectory, Attachment::SharedMemory, Attachment::Pmem, Attachment::Display, Attachment::MitigationPolicy, Attachment::SecurityFeatures, Attachment::ExitTrace, Attachment::PciTransport, Attachment::GuestPmu])?;
+    check_host_resources(setup.get_memory_size() as u64, setup.get_cpu_cores_count())?;
+    let mitigation_policy = setup.get_mitigation_policy();
+    if !mitigation_policy.get_required().is_empty() {
+        mitigation_policy.check(&read_host_mitigations()?)?;
+    }
 
-        // Spawn a blocking task to run the VCPU event loop
-        let handler = tokio::task::spawn_blocking(move || {
-            loop {
-                match vcpu.run() {
-                    Ok(exit_reason) => {
-                        // Handle different VCPU exit reasons
-                        match exit_reason {
-                            VcpuExit::Hlt => { 
-                                return Ok(format!("VCPU {} exited with HLT instruction", cpu_id));
-                             },
-                            VcpuExit::IoIn( port, data ) => { 
-                                return Err(format!("VCPU {} encountered IO in at port {:x} with data {:?}", cpu_id, port, data));
-                             },
-                            VcpuExit::IoOut( port, data) => { 
-                                return Err(format!("VCPU {} encountered IO out at port {:x} with data {:?}", cpu_id, port, data));
-                             },
-                            VcpuExit::MmioRead ( address, _data ) => { 
-                                return Err(format!("VCPU {} encountered MMIO read at address {:x}", cpu_id, address));
-                             },
-                            VcpuExit::MmioWrite ( address, _data ) => { 
-                                return Err(format!("VCPU {} encountered MMIO write at address {:x}", cpu_id, address));
-                             },
-                            VcpuExit::Shutdown => { 
-                                return Ok(format!("VCPU {} exited gracefully", cpu_id));
-                             },
-                            VcpuExit::InternalError => { 
-                                return Err(format!("VCPU {} encountered an internal error", cpu_id));
-                             },
-                            VcpuExit::SystemEvent (..) => { 
-                                return Err(format!("VCPU {} encountered a system event", cpu_id));
-                             },
-                            _ => { 
-                                return Err(format!("Unhandled VCPU exit reason: {:?}", exit_reason));
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        return Err(format!("VCPU {} encountered an error: {}", cpu_id, e));
-                    }
-                }
+    // Pick the kernel off the first bootable device of the boot order
+    let boot = select_boot_device(&setup);
+
+    let mut backend = KvmBackend::create_vm(&setup)?;
+
+    // Set up guest memory at a specific address. A directly booted kernel needs the
+    // low megabyte for its command line, so RAM starts at 0 in that case.
+    let guest_phys_addr = if boot.is_some() { 0 } else { 0x100000 };
+    let mut guest_memory = backend.map_memory(guest_phys_addr, setup.get_memory_size())?;
+
+    // A Linux guest and the virtio devices need the in-kernel interrupt controllers and timer
+    let has_irqchip = boot.is_some() || !setup.get_disks().is_empty() || setup.get_cdrom_image().is_some()
+        || !setup.get_shared_dirs().is_empty() || !setup.get_pmem_devices().is_empty();
+    // Devices get their GSIs from the router, which programs KVM's routing table
+    let irq_router = IrqRouter::new(clone_vm_fd(&backend.kvm, &backend.vm)?, GsiConflictPolicy::Fail);
+    if has_irqchip {
+        setup_platform_devices(&backend.vm)?;
+        irq_router.apply()?;
+    }
+
+    // Attach the disks in order, then the CD-ROM, as virtio block devices. virtio-mmio ones
+    // are announced on the kernel command line, as there is no firmware to describe them;
+    // virtio-pci ones take the next slots of the PCI bus the guest enumerates. The guest
+    // names them in the order of their slots, so disk i is always /dev/vd<a + i>
+    let mut kernel_cmdline = match &boot {
+        Some(boot) => boot.cmdline.clone(),
+        None => setup.get_kernel_cmdline().to_string()
+    };
+    let mut mmio_bus = MmioBus::new();
+    let mut block_images = Vec::new();
+    for disk in setup.get_disks() {
+        block_images.push((open_disk(disk)?, disk.get_cache_mode(), disk.is_read_only(), disk.get_poll_window()));
+    }
+    if let Some(path) = setup.get_cdrom_image() {
+        block_images.push((open_cdrom_image(&path.to_string_lossy())?, CacheMode::default(), true, Duration::ZERO));
+    }
+    let mut pci_bus = match setup.get_virtio_transport() {
+        VirtioTransport::Pci => Some(PciBus::new()),
+        VirtioTransport::Mmio => None
+    };
+    let mut bar_allocator = PciBarAllocator::new(PCI_MMIO_WINDOW_BASE, PCI_MMIO_WINDOW_SIZE);
+    // Read the disk statistics once the vCPUs exited
+    let mut disk_metrics: DiskMetrics = Vec::new();
+    for (index, (disk_image, cache_mode, read_only, poll_window)) in block_images.into_iter().enumerate() {
+        let name = format!("virtio-blk{}", index);
+        let interrupt = irq_router.create_interrupt(&name, None, TriggerMode::Edge)?;
+        let block_device = VirtioBlockDevice::with_storage(guest_memory.clone(), disk_image, setup.get_virtqueue_config())?;
+        block_device.set_cache_mode(cache_mode);
+        block_device.set_read_only(read_only);
+        block_device.set_poll_window(poll_window);
+        // Disk I/O runs on its own thread so the vCPU isn't held up by it
+        let io_worker_name = format!("{}-io", name);
+        match &mut pci_bus {
+            Some(pci_bus) => {
+                let irq_line = interrupt.get_gsi() as u8;
+                let bar_address = bar_allocator.allocate(VIRTIO_PCI_BAR_SIZE)?;
+                let mut transport = VirtioPciTransport::new(block_device, Box::new(interrupt), irq_line, bar_address)?;
+                let notifier = transport.get_vring_notifier();
+                transport.get_device_mut().start_io_worker(&io_worker_name, notifier)?;
+                let transport = Arc::new(Mutex::new(transport));
+                pci_bus.add_device(transport.clone())?;
+                disk_metrics.push((name, Box::new(move || transport.lock().unwrap_or_else(|e| e.into_inner()).get_device().metrics())));
+            },
+            None => {
+                let base = VIRTIO_BLK_MMIO_BASE + index as u64 * VIRTIO_MMIO_SIZE;
+                kernel_cmdline.push_str(&format!(" virtio_mmio.device=4K@{:#x}:{}", base, interrupt.get_gsi()));
+                let mut transport = MmioTransport::new(block_device, Box::new(interrupt));
+                let notifier = transport.get_vring_notifier();
+                transport.get_device_mut().start_io_worker(&io_worker_name, notifier)?;
+                let transport = Arc::new(Mutex::new(transport));
+                mmio_bus.register(base, VIRTIO_MMIO_SIZE, transport.clone())?;
+                mmio_bus.set_name(base, &name);
+                disk_metrics.push((name, Box::new(move || transport.lock().unwrap_or_else(|e| e.into_inner()).get_device().metrics())));
             }
-        });
-        handlers.push(handler);
+        }
+    }
+    // The configuration space of the PCI bus is reachable through ECAM and, further down,
+    // the legacy configuration ports, which x86 guests without ACPI tables use
+    let pci_bus = pci_bus.map(Arc::new);
+    if let Some(pci_bus) = &pci_bus {
+        mmio_bus.register(PCI_ECAM_BASE, PCI_ECAM_SIZE, Arc::new(Mutex::new(PciEcam::new(pci_bus.clone()))))?;
+        mmio_bus.set_name(PCI_ECAM_BASE, "pci-ecam");
+        let window = PciMmioWindow::new(pci_bus.clone(), PCI_MMIO_WINDOW_BASE);
+        mmio_bus.register(PCI_MMIO_WINDOW_BASE, PCI_MMIO_WINDOW_SIZE, Arc::new(Mutex::new(window)))?;
+        mmio_bus.set_name(PCI_MMIO_WINDOW_BASE, "pci-bars");
+    }
+    // Entropy for the guest kernel, which otherwise stalls early boot services such as
+    // SSH host key generation until its own random pool is seeded
+    if has_irqchip {
+        let interrupt = irq_router.create_interrupt("virtio-rng0", None, TriggerMode::Edge)?;
+        kernel_cmdline.push_str(&format!(" virtio_mmio.device=4K@{:#x}:{}", VIRTIO_RNG_MMIO_BASE, interrupt.get_gsi()));
+        let transport = MmioTransport::new(VirtioRng::new(guest_memory.clone())?, Box::new(interrupt));
+        mmio_bus.register(VIRTIO_RNG_MMIO_BASE, VIRTIO_MMIO_SIZE, Arc::new(Mutex::new(transport)))?;
+        mmio_bus.set_name(VIRTIO_RNG_MMIO_BASE, "virtio-rng0");
+    }
+    // Host directories the guest mounts by their tag
+    for (index, shared_dir) in setup.get_shared_dirs().iter().enumerate() {
+        let name = format!("virtio-9p{}", index);
+        let base = VIRTIO_9P_MMIO_BASE + index as u64 * VIRTIO_MMIO_SIZE;
+        let interrupt = irq_router.create_interrupt(&name, None, TriggerMode::Edge)?;
+        kernel_cmdline.push_str(&format!(" virtio_mmio.device=4K@{:#x}:{}", base, interrupt.get_gsi()));
+        let device = Virtio9p::new(guest_memory.clone(), shared_dir.get_path(), shared_dir.get_tag())?;
+        mmio_bus.register(base, VIRTIO_MMIO_SIZE, Arc::new(Mutex::new(MmioTransport::new(device, Box::new(interrupt)))))?;
+        mmio_bus.set_name(base, &name);
+    }
+    // Balloon the VmHandle asks the guest to return memory through
+    let balloon = if has_irqchip {
+        let interrupt = irq_router.create_interrupt("virtio-balloon0", None, TriggerMode::Edge)?;
+        kernel_cmdline.push_str(&format!(" virtio_mmio.device=4K@{:#x}:{}", VIRTIO_BALLOON_MMIO_BASE, interrupt.get_gsi()));
+        let transport = Arc::new(Mutex::new(MmioTransport::new(VirtioBalloon::new(guest_memory.clone())?, Box::new(interrupt))));
+        mmio_bus.register(VIRTIO_BALLOON_MMIO_BASE, VIRTIO_MMIO_SIZE, transport.clone())?;
+        mmio_bus.set_name(VIRTIO_BALLOON_MMIO_BASE, "virtio-balloon0");
+        Some(transport)
+    } else {
+        None
+    };
+
+    // Host memory shared with the guest, mapped above RAM. Each region gets a register
+    // window at a fixed address telling the guest where it was mapped
+    let mut host_mapping_address = SHARED_MEMORY_BASE.max((guest_phys_addr + setup.get_memory_size() as u64).next_multiple_of(SHARED_MEMORY_BASE_ALIGNMENT));
+    for (index, region) in setup.get_shared_memory().iter().enumerate() {
+        let name = format!("shmem{}", index);
+        let mapping = Arc::new(SharedMemoryMapping::open(region)?);
+        map_host_memory(&mut backend, host_mapping_address, &mapping)?;
+        let base = SHARED_MEMORY_REGISTERS_BASE + index as u64 * SHARED_MEMORY_REGISTERS_SIZE;
+        let device = SharedMemoryDevice::new(host_mapping_address, mapping.get_size());
+        mmio_bus.register(base, SHARED_MEMORY_REGISTERS_SIZE, Arc::new(Mutex::new(device)))?;
+        mmio_bus.set_name(base, &name);
+        host_mapping_address += mapping.get_size();
+    }
+    // Files the guest maps directly as persistent memory, after the shared-memory regions
+    for (index, pmem) in setup.get_pmem_devices().iter().enumerate() {
+        let name = format!("virtio-pmem{}", index);
+        let mapping = Arc::new(map_pmem_file(pmem)?);
+        let guest_address = host_mapping_address.next_multiple_of(PMEM_ALIGNMENT);
+        map_host_memory(&mut backend, guest_address, &mapping)?;
+        let base = VIRTIO_PMEM_MMIO_BASE + index as u64 * VIRTIO_MMIO_SIZE;
+        let interrupt = irq_router.create_interrupt(&name, None, TriggerMode::Edge)?;
+        kernel_cmdline.push_str(&format!(" virtio_mmio.device=4K@{:#x}:{}", base, interrupt.get_gsi()));
+        let device = VirtioPmem::new(guest_memory.clone(), Arc::clone(&mapping), guest_address)?;
+        mmio_bus.register(base, VIRTIO_MMIO_SIZE, Arc::new(Mutex::new(MmioTransport::new(device, Box::new(interrupt)))))?;
+        mmio_bus.set_name(base, &name);
+        host_mapping_address = guest_address + mapping.get_size();
     }
+    // Framebuffer of the display, after the pmem files
+    let framebuffer = match setup.get_display() {
+        Some(display) => {
+            let size = get_framebuffer_size(display.get_width(), display.get_height()).next_multiple_of(FRAMEBUFFER_ALIGNMENT);
+            let mapping = Arc::new(SharedMemoryMapping::anonymous(size)?);
+            let guest_address = host_mapping_address.next_multiple_of(FRAMEBUFFER_ALIGNMENT);
+            map_host_memory(&mut backend, guest_address, &mapping)?;
+            let framebuffer = Framebuffer::new(mapping, display.get_width(), display.get_height())?;
+            backend.framebuffer = Some(framebuffer.clone());
+            Some(LinearFramebuffer { base: guest_address, width: framebuffer.get_width(), height: framebuffer.get_height(), stride: framebuffer.get_stride() })
+        },
+        None => None
+    };
 
-    // Await all VCPU tasks and handle their results
-    for handler in handlers {
-        match handler.await {
-            Ok(Ok(msg)) => println!("VCPU completed: {}", msg),
-            Ok(Err(err)) => return Err(err),
-            Err(e) => return Err(format!("Task join error: {}", e)),
+    // Copy the kernel, initrd and command line into guest RAM and build the zero page,
+    // page tables and GDT the 64-bit boot protocol expects
+    if let Some(boot) = &boot {
+        let loaded = load_kernel(&mut guest_memory, &boot.kernel, &kernel_cmdline)?;
+        setup_boot_environment(&guest_memory, &boot.kernel.kernel, &loaded)?;
+        // The kernel finds its screen in the zero page, as if left there by EFI firmware
+        if let Some(framebuffer) = &framebuffer {
+            write_screen_info(&guest_memory, framebuffer)?;
         }
+        backend.kernel_entry = Some(loaded.entry_point);
     }
 
+    // Legacy port I/O devices
+    let mut pio_bus = PortIoBus::new();
+    pio_bus.register(I8042_PORT_BASE, I8042_PORT_COUNT, backend.i8042.clone())?;
+    pio_bus.set_name(I8042_PORT_BASE, "i8042");
+    let pci_config_ports = match pci_bus {
+        Some(pci_bus) => PciConfigPorts::with_bus(pci_bus),
+        None => PciConfigPorts::new()
+    };
+    pio_bus.register(PCI_CONFIG_PORT_BASE, PCI_CONFIG_PORT_COUNT, Arc::new(Mutex::new(pci_config_ports)))?;
+    pio_bus.set_name(PCI_CONFIG_PORT_BASE, "pci-config");
+    let boot_progress = control.get_boot_progress();
+    pio_bus.register(PVPANIC_PORT, 1, Arc::new(Mutex::new(PvPanicDevice::new(Arc::clone(boot_progress)))))?;
+    pio_bus.set_name(PVPANIC_PORT, "pvpanic");
+
+    // Record the device accesses of the vCPUs if asked to, for `replay_exit_trace`
+    let recorder = match setup.get_exit_trace() {
+        Some(path) => Some(Arc::new(ExitRecorder::create(path)?)),
+        None => None
+    };
+
+    // COM1 writes guest output to the configured serial console, recording it in the
+    // console log and watching it for boot milestones. Without the in-kernel interrupt controllers there is no IRQ 4 and the
+    // guest has to poll the UART
+    if let Some(output) = open_serial_output(setup.get_serial_console())? {
+        let output = ConsoleRecorder::new(output, Arc::clone(control.get_console_log()));
+        let output = Box::new(BootLogWatcher::new(output, Arc::clone(boot_progress)));
+        let serial = if has_irqchip {
+            let interrupt = irq_router.create_interrupt("serial0", Some(COM1_IRQ), TriggerMode::Edge)?;
+            Serial::with_interrupt(output, Box::new(interrupt))
+        } else {
+            Serial::new(output)
+        };
+        let serial = Arc::new(Mutex::new(serial));
+        pio_bus.register(COM1_PORT_BASE, COM_PORT_COUNT, serial.clone())?;
+        pio_bus.set_name(COM1_PORT_BASE, "serial0");
+        if *setup.get_serial_console() == SerialConsole::Stdout {
+            forward_stdin_to_serial(Arc::downgrade(&serial), recorder.clone())?;
+        }
+    }
+    let devices = Arc::new(VcpuDevices { mmio_bus, pio_bus, recorder, latencies: Arc::clone(control.get_exit_latencies()) });
+    backend.balloon = balloon;
+
+    run_vcpus(Arc::new(backend), control, setup.get_cpu_cores_count(), devices, disk_metrics).await
+}
+
+/// Guest physical address of the registers of the first virtio-mmio block device; the
+/// next ones follow it.
+const VIRTIO_BLK_MMIO_BASE: u64 = 0xd000_0000;
+/// Guest physical address of the registers of the virtio-rng device, past the slots of
+/// every disk and the CD-ROM.
+const VIRTIO_RNG_MMIO_BASE: u64 = 0xd001_0000;
+/// Guest physical address of the registers of the virtio-balloon device, after the
+/// virtio-rng device.
+const VIRTIO_BALLOON_MMIO_BASE: u64 = 0xd001_1000;
+/// Guest physical address of the registers of the first virtio-9p device, after the
+/// virtio-balloon device; the next ones follow it.
+const VIRTIO_9P_MMIO_BASE: u64 = 0xd001_2000;
+/// Guest physical address of the register window of the first shared-memory region,
+/// past the slots of every virtio-9p device; the next ones follow it.
+const SHARED_MEMORY_REGISTERS_BASE: u64 = 0xd002_0000;
+/// Guest physical address of the registers of the first virtio-pmem device, past the
+/// register windows of every shared-memory region; the next ones follow it.
+const VIRTIO_PMEM_MMIO_BASE: u64 = 0xd002_8000;
+/// Guest physical address of the ECAM window of the PCI bus, past the virtio-mmio devices.
+const PCI_ECAM_BASE: u64 = 0xe000_0000;
+/// Guest physical address of the MMIO window the BARs of the PCI devices are allocated
+/// from, after the ECAM window and below the I/O APIC.
+const PCI_MMIO_WINDOW_BASE: u64 = 0xe010_0000;
+/// Size of the MMIO window of the PCI devices.
+const PCI_MMIO_WINDOW_SIZE: u64 = 0x0ff0_0000;
+/// Alignment of the framebuffer in guest physical memory and of its size, a page.
+const FRAMEBUFFER_ALIGNMENT: u64 = 4096;
+/// Lowest guest physical address of the shared-memory regions and pmem files, which
+/// every x86-64 CPU can address and a guest with less RAM never uses.
+const SHARED_MEMORY_BASE: u64 = 0x10_0000_0000;
+/// Alignment of the first shared-memory region when guest RAM reaches past `SHARED_MEMORY_BASE`.
+const SHARED_MEMORY_BASE_ALIGNMENT: u64 = 1 << 30;
+
+/// Maps host memory into the guest at `gpa` under a new memory slot, keeping the
+/// mapping alive with the backend.
+fn map_host_memory(backend: &mut KvmBackend, gpa: u64, mapping: &Arc<SharedMemoryMapping>) -> Result<(), VmError> {
+    let memory_slots = backend.memory_slots.get_mut().unwrap_or_else(|e| e.into_inner());
+    // SAFETY: the backend keeps the mapping, so it lives as long as the VM
+    unsafe { memory_slots.add_region(&backend.vm, gpa, mapping.get_size(), mapping.get_host_address(), 0)? };
+    backend.host_mappings.push(Arc::clone(mapping));
+    Ok(())
+}
+
+/// Opens a second handle to `vm` for devices that need their own `VmFd`.
+fn clone_vm_fd(kvm: &Kvm, vm: &kvm_ioctls::VmFd) -> Result<kvm_ioctls::VmFd, VmError> {
+    let fd = unsafe { libc::dup(vm.as_raw_fd()) };
+    if fd < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(VmError::io(format!("failed to duplicate VM file descriptor: {}", e), e));
+    }
+    // SAFETY: `fd` is a freshly duplicated KVM VM descriptor owned by nothing else
+    match unsafe { kvm.create_vmfd_from_rawfd(fd) } {
+        Ok(vm_fd) => Ok(vm_fd),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to wrap duplicated VM file descriptor: {}", e), e))
+    }
+}
+
+/// Address of the three page TSS region KVM needs on Intel hosts, just below 4 GiB.
+const KVM_TSS_ADDRESS: usize = 0xfffb_d000;
+
+/// Creates the in-kernel PIC/IOAPIC/LAPIC and PIT a Linux guest expects.
+fn setup_platform_devices(vm: &kvm_ioctls::VmFd) -> Result<(), VmError> {
+    if let Err(e) = vm.set_tss_address(KVM_TSS_ADDRESS) {
+        return Err(VmError::hypervisor_source(format!("Failed to set TSS address: {}", e), e));
+    }
+    if let Err(e) = vm.create_irq_chip() {
+        return Err(VmError::hypervisor_source(format!("Failed to create in-kernel irqchip: {}", e), e));
+    }
+    let pit_config = kvm_bindings::kvm_pit_config { flags: kvm_bindings::KVM_PIT_SPEAKER_DUMMY, ..Default::default() };
+    if let Err(e) = vm.create_pit2(pit_config) {
+        return Err(VmError::hypervisor_source(format!("Failed to create PIT: {}", e), e));
+    }
+    Ok(())
+}
+
+/// Points a vCPU at `start_addr` without any further setup, for guests without a kernel.
+fn set_flat_start(vcpu: &VcpuFd, cpu_id: u32, start_addr: u64) -> Result<(), VmError> {
+    let mut regs = match vcpu.get_regs() {
+        Ok(regs) => regs,
+        Err(e) => return Err(VmError::hypervisor_source(format!("Failed to get VCPU {} registers: {}", cpu_id, e), e)),
+    };
+
+    regs.rip = start_addr;
+    regs.rflags = 0x2;
+
+    if let Err(e) = vcpu.set_regs(&regs) {
+        return Err(VmError::hypervisor_source(format!("Failed to set VCPU {} registers: {}", cpu_id, e), e));
+    };
+    Ok(())
+}
+
+/// Returns the host supported CPUID table filtered through `cpu_model`, `mitigation_policy`,
+/// `security_features` and `guest_pmu`. KVM then refuses the CR4 bits of the hidden features.
+///
+/// # Returns
+/// * `Ok(CpuId)` ready to be passed to `set_cpuid2`
+/// * `Err(VmError)` if KVM can't report its CPUID, the host lacks features of the model
+///   or can't virtualize the PMU
+fn get_guest_cpuid(kvm: &Kvm, cpu_model: &CpuModel, mitigation_policy: &MitigationPolicy,
+                   security_features: &SecurityFeaturePolicy, guest_pmu: GuestPmu) -> Result<kvm_bindings::CpuId, VmError> {
+    let mut cpuid = match kvm.get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES) {
+        Ok(cpuid) => cpuid,
+        Err(e) => return Err(VmError::hypervisor_source(format!("Failed to get supported CPUID: {}", e), e)),
+    };
+
+    let host_entries: Vec<CpuidEntry> = cpuid.as_slice().iter().map(|e| CpuidEntry {
+        function: e.function, index: e.index, eax: e.eax, ebx: e.ebx, ecx: e.ecx, edx: e.edx,
+    }).collect();
+    let missing = cpu_model.get_missing_features(&host_entries);
+    if !missing.is_empty() {
+        let names: Vec<&str> = missing.iter().map(|f| f.name()).collect();
+        return Err(VmError::config(format!("CPU model {:?} needs features missing on this host: {}", cpu_model, names.join(", "))));
+    }
+    guest_pmu.check(&host_entries)?;
+
+    for entry in cpuid.as_mut_slice() {
+        let mut filtered = CpuidEntry {
+            function: entry.function, index: entry.index, eax: entry.eax, ebx: entry.ebx, ecx: entry.ecx, edx: entry.edx,
+        };
+        cpu_model.apply(&mut filtered);
+        mitigation_policy.apply(&mut filtered);
+        security_features.apply(&mut filtered);
+        guest_pmu.apply(&mut filtered);
+        entry.eax = filtered.eax;
+        entry.ebx = filtered.ebx;
+        entry.ecx = filtered.ecx;
+        entry.edx = filtered.edx;
+    }
+
+    Ok(cpuid)
+}
+
+/// Disables the PMU of `vm` so the guest can't read the host counters through its MSRs.
+///
+/// # Returns
+/// * `Ok(())` on success, or if KVM can't disable the PMU and only the CPUID hides it
+/// * `Err(VmError)` if KVM refuses to disable the PMU it advertised as disableable
+fn disable_guest_pmu(kvm: &Kvm, vm: &kvm_ioctls::VmFd) -> Result<(), VmError> {
+    let capabilities = kvm.check_extension_raw(kvm_bindings::KVM_CAP_PMU_CAPABILITY as _);
+    if capabilities <= 0 || capabilities as u32 & kvm_bindings::KVM_PMU_CAP_DISABLE == 0 {
+        return Ok(());
+    }
+    let mut enable_cap = kvm_bindings::kvm_enable_cap { cap: kvm_bindings::KVM_CAP_PMU_CAPABILITY, ..Default::default() };
+    enable_cap.args[0] = kvm_bindings::KVM_PMU_CAP_DISABLE as u64;
+    if let Err(e) = vm.enable_cap(&enable_cap) {
+        return Err(VmError::hypervisor_source(format!("Failed to disable the guest PMU: {}", e), e));
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,13 +1,46 @@
 //! Virtual Machine setup and execution utilities for macOS using applevisor and Tokio.
 //!
 //! This module provides the `VmSetup` struct for configuring VM memory and CPU cores,
-//! and the `run_vm` async function to launch and manage a VM instance.
+//! and the `run_vm` async function to launch and manage a VM instance. VMs that
+//! don't fit the host are refused, and the host's memory and core counts are
+//! available to size VMs with `get_recommended_resources`.
+//!
+//! A directly booted kernel gets virtio-mmio devices below RAM: Data Abort exits at
+//! their registers are completed through the MMIO bus, and their interrupts reach the
+//! guest through an emulated GICv2 or GICv3 the vCPU loop forwards to the vCPU's IRQ
+//! line. The system registers of the GICv3 CPU interface trap and are served the same way.
+//!
+//! `HvfBackend` runs the vCPUs through the exit loop shared with the other hypervisors,
+//! see `backend`. A Data Abort reaches it as an MMIO exit, completed into the target
+//! register the next time the vCPU runs.
 use applevisor::*;
 use std::{result::Result};
-use tokio;
-use tokio::sync::Mutex;
 use std::sync::Arc;
-use crate::vm_setup::setup_utils::VmSetup;
+use std::time::Duration;
+use crate::vm_setup::setup_utils::{BootDevice, VmSetup};
+use crate::kernel_setup::setup_utils::KernelComponents;
+use crate::vm_setup::attachments::{check_attachments, Attachment};
+use crate::vm_setup::vm_handle::{VmHandle, VmControl, RunControlHooks};
+use crate::vm_setup::backend::{run_vcpus, HypervisorBackend, VcpuDevices, VcpuExit};
+use crate::vm_setup::exit_summary::VmExitSummary;
+use crate::vm_setup::memory_monitor::MemoryStatus;
+use crate::vm_setup::guest_profiler::GuestMode;
+use crate::macos_bindings::{check_host_resources, get_physical_memory_info, wrap_guest_memory};
+use crate::kernel_setup::arm64_boot::{
+    build_fdt_with_devices, compute_boot_layout, decompress_kernel, Arm64Devices, Arm64Gic, Arm64ImageHeader, PsciCall,
+    ARM64_BOOT_CPSR, ARM64_RAM_BASE, PSCI_NOT_SUPPORTED,
+};
+use crate::device_emulation::gic::{Gic, GicCpuInterface, GicDistributor, GicRedistributor, GicSpi, GicVersion, GICC_SIZE, GICR_SIZE};
+use crate::device_emulation::mmio::{read_le, MmioBus};
+use crate::device_emulation::pio::PortIoBus;
+use crate::device_emulation::rng::VirtioRng;
+use crate::device_emulation::virtio_9p::Virtio9p;
+use crate::device_emulation::virtio_mmio::{MmioTransport, VIRTIO_MMIO_SIZE};
+use vm_memory::GuestMemoryMmap;
+use crate::utils::signals::TriggerMode;
+use crate::error::VmError;
+
+pub use crate::macos_bindings::{get_core_counts, get_recommended_resources, CoreCounts};
 
 /// Asynchronously run a Virtual Machine with the given setup on macOS.
 ///
@@ -15,108 +48,538 @@ use crate::vm_setup::setup_utils::VmSetup;
 /// * `setup` - The VM configuration to use.
 ///
 /// # Returns
-/// * `Ok(())` if the VM runs successfully.
-/// * `Err(String)` if any error occurs during setup or execution.
+/// * `Ok(VmExitSummary)` with how each vCPU exited if the VM runs successfully.
+/// * `Err(VmError)` if any error occurs during setup or execution.
 //Running VM on macos
-pub async fn run_vm(setup: VmSetup) -> Result<(), String> {
+pub async fn run_vm(setup: VmSetup) -> Result<VmExitSummary, VmError> {
+    run_vm_with_control(setup, Arc::new(VmControl::new())).await
+}
 
-    // Create a new VirtualMachine instance, wrapped in Arc<Mutex<...>> for thread safety.
-    let mut _vm = match VirtualMachine::new() {
-        Ok(vm) => Arc::new(Mutex::new(vm)),
-        Err(e) => return Err(format!("Failed to create VM: {}", e))
-    };
-    // Allocate guest memory for the VM.
-    let mut mem = match Mapping::new(setup.get_memory_size()) {
-        Ok(mem) => mem,
-        Err(_) => return Err("Failed to create memory".to_string())
-    };
-    // Map the memory region at address 0x4000 with RWX permissions.
-    if let Err(_) = mem.map(0x4000, MemPerms::RWX) {
-        return Err("Failed to map memory".to_string());
-    };
+/// Start a Virtual Machine with the given setup in the background.
+///
+/// Must be called from within a Tokio runtime.
+///
+/// # Returns
+/// * A `VmHandle` to pause, resume, stop and wait for the VM.
+pub fn spawn_vm(setup: VmSetup) -> VmHandle {
+    VmHandle::spawn(move |control| run_vm_with_control(setup, control))
+}
 
-    // Spawn a blocking task for each virtual CPU core.
-    let mut handlers: Vec<tokio::task::JoinHandle<Result<String, String>>> = Vec::new();
-    for i in 0..setup.get_cpu_cores_count() {
-        
-        let handle = tokio::task::spawn_blocking(move || {
-            // Create a new VCPU instance.
-            let vcpu = match Vcpu::new() {
-                Ok(vcpu) => vcpu,
-                Err(_) => {
-                    return Err("Failed to create VCPU".to_string());
-                }
-            };
-            // Set up debug exception and register traps for the VCPU.
-            if let Err(_) = vcpu.set_trap_debug_exceptions(true) {
-                return Err("Failed to set trap debug exceptions for CPU".to_string());
-            }
-            if let Err(_) = vcpu.set_trap_debug_reg_accesses(true) {
-                return Err("Failed to set trap debug register accesses for CPU".to_string());
+/// vCPUs currently running their event loop, by VCPU index.
+#[derive(Default)]
+struct RunningVcpus {
+    ids: Vec<u32>,
+    instances: Vec<VcpuInstance>,
+}
+
+/// Hypervisor.framework VM. It is its own run control: vCPUs are kicked with `hv_vcpus_exit`.
+struct HvfBackend {
+    _vm: VirtualMachine,
+    /// Interrupt controller, whose pending IRQ is forwarded to the vCPU before every run
+    gic: Arc<Gic>,
+    /// Kernel entry point and device tree address of a directly booted kernel
+    boot: Option<(u64, u64)>,
+    /// Start of guest RAM, where vCPUs start without a kernel
+    ram_base: u64,
+    vcpus: std::sync::Mutex<RunningVcpus>,
+}
+
+/// vCPU of a Hypervisor.framework VM.
+struct HvfVcpu {
+    vcpu: Vcpu,
+    /// Hypervisor.framework masks the virtual timer when it fires, until the guest handled it
+    vtimer_masked: bool,
+    /// Syndrome of the Data Abort whose MMIO access the last run stopped at
+    pending_abort: Option<u64>,
+    /// Data of that access
+    data: [u8; 8],
+}
+
+impl RunControlHooks for HvfBackend {
+    fn kick(&self) {
+        let vcpus = self.vcpus.lock().unwrap_or_else(|e| e.into_inner());
+        if !vcpus.instances.is_empty() {
+            // Only fails for invalid vCPUs, which are unregistered before being destroyed
+            let _ = Vcpu::stop(&vcpus.instances);
+        }
+    }
+}
+
+impl HypervisorBackend for HvfBackend {
+    type Vcpu = HvfVcpu;
+    type Memory = Mapping;
+
+    fn create_vm(setup: &VmSetup) -> Result<Self, VmError> {
+        let vm = match VirtualMachine::new() {
+            Ok(vm) => vm,
+            Err(e) => return Err(VmError::hypervisor(format!("Failed to create VM: {}", e)))
+        };
+        Ok(HvfBackend {
+            _vm: vm,
+            gic: Gic::new(setup.get_gic_version()),
+            boot: None,
+            ram_base: 0,
+            vcpus: std::sync::Mutex::new(RunningVcpus::default()),
+        })
+    }
+
+    fn map_memory(&mut self, gpa: u64, size: usize) -> Result<Mapping, VmError> {
+        // Allocate guest memory for the VM.
+        let mut mem = match Mapping::new(size) {
+            Ok(mem) => mem,
+            Err(_) => return Err(VmError::memory("Failed to create memory"))
+        };
+        // Map the memory region with RWX permissions.
+        if let Err(_) = mem.map(gpa, MemPerms::RWX) {
+            return Err(VmError::memory("Failed to map memory"));
+        };
+        self.ram_base = gpa;
+        Ok(mem)
+    }
+
+    fn create_vcpu(&self, _index: u32) -> Result<HvfVcpu, VmError> {
+        // Create a new VCPU instance.
+        let vcpu = match Vcpu::new() {
+            Ok(vcpu) => vcpu,
+            Err(_) => {
+                return Err(VmError::hypervisor("Failed to create VCPU"));
             }
-            // Set the program counter (PC) register to the start address.
-            if let Err(_) = vcpu.set_reg(Reg::PC, 0x4000)  {
-                return Err("Failed to set trap debug instruction executions for CPU".to_string());
+        };
+        // Set up debug exception and register traps for the VCPU.
+        if let Err(_) = vcpu.set_trap_debug_exceptions(true) {
+            return Err(VmError::hypervisor("Failed to set trap debug exceptions for CPU"));
+        }
+        if let Err(_) = vcpu.set_trap_debug_reg_accesses(true) {
+            return Err(VmError::hypervisor("Failed to set trap debug register accesses for CPU"));
+        }
+        // Set the program counter (PC) register to the kernel entry or start address,
+        // and pass the device tree in X0 as the arm64 boot protocol requires.
+        let registers = match self.boot {
+            Some((entry, fdt_addr)) => vec![
+                (Reg::X0, fdt_addr), (Reg::X1, 0), (Reg::X2, 0), (Reg::X3, 0), (Reg::CPSR, ARM64_BOOT_CPSR), (Reg::PC, entry),
+            ],
+            None => vec![(Reg::PC, self.ram_base)],
+        };
+        for (reg, value) in registers {
+            if let Err(_) = vcpu.set_reg(reg, value) {
+                return Err(VmError::hypervisor("Failed to set boot registers for CPU"));
             }
-            // Start running the VCPU.
-            if let Err(_) = vcpu.run() {
-                return Err(format!("Failed to run VCPU {}", i));
+        }
+        Ok(HvfVcpu { vcpu, vtimer_masked: false, pending_abort: None, data: [0; 8] })
+    }
+
+    fn register_vcpu(&self, vcpu: &HvfVcpu, index: u32) {
+        let mut vcpus = self.vcpus.lock().unwrap_or_else(|e| e.into_inner());
+        vcpus.ids.push(index);
+        vcpus.instances.push(vcpu.vcpu.get_instance());
+    }
+
+    fn unregister_vcpu(&self, _vcpu: &HvfVcpu, index: u32) {
+        let mut vcpus = self.vcpus.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pos) = vcpus.ids.iter().position(|v| *v == index) {
+            vcpus.ids.remove(pos);
+            vcpus.instances.remove(pos);
+        }
+    }
+
+    fn run_vcpu<'a>(&self, vcpu: &'a mut HvfVcpu, i: u32, _devices: &VcpuDevices) -> Result<VcpuExit<'a>, VmError> {
+        // Finish the device access the last run stopped at
+        if let Some(syndrome) = vcpu.pending_abort.take() {
+            complete_data_abort(&vcpu.vcpu, i, syndrome, &vcpu.data)?;
+        }
+        if vcpu.vtimer_masked && !is_vtimer_firing(&vcpu.vcpu, i)? {
+            self.gic.set_ppi_level(VTIMER_PPI, false);
+            if let Err(_) = vcpu.vcpu.set_vtimer_mask(false) {
+                return Err(VmError::hypervisor(format!("Failed to unmask the virtual timer of VCPU {}", i)));
             }
+            vcpu.vtimer_masked = false;
+        }
+        // The IRQ line only applies to the next run, so it is set before every one.
+        if let Err(_) = vcpu.vcpu.set_pending_interrupt(InterruptType::IRQ, self.gic.has_pending_irq()) {
+            return Err(VmError::hypervisor(format!("Failed to set the IRQ line of VCPU {}", i)));
+        }
+        // Start running the VCPU.
+        if let Err(_) = vcpu.vcpu.run() {
+            return Err(VmError::hypervisor(format!("Failed to run VCPU {}", i)));
+        }
+
+        let exit = vcpu.vcpu.get_exit_info();
+        match exit.reason {
+            // Kicked by the run control
+            ExitReason::CANCELED => Ok(VcpuExit::Canceled),
+            ExitReason::EXCEPTION => {
+                let exception = exit.exception;
+                let syndrome = exception.syndrome;
+                let ec = (syndrome >> 26) & 0x3F;
+                let iss = syndrome & 0xFFFFFF;
 
-            // Main VCPU event loop: handle VM exits and exceptions.
-            loop {
-                let exit = vcpu.get_exit_info();
-                match exit.reason {
-                    ExitReason::CANCELED => {
-                        return Ok(format!("VCPU {} stopped", i))
-                    },
-                    ExitReason::EXCEPTION => {
-                        let exception = exit.exception;
-                        let syndrome = exception.syndrome;
-                        let ec = (syndrome >> 26) & 0x3F;
-                        let iss = syndrome & 0xFFFFFF;
-
-                        match ec {
-                            0x0D => {
-                                // General Protection Fault
-                                return Err(format!("VCPU {} encountered General Protection Fault", i));
-                            }
-                            0x15 => { // Data Abort
-                                let va = exception.virtual_address;
-                                let pa = exception.physical_address;
-                                return Err(format!(
-                                    "VCPU {} Data Abort at VA: 0x{:x}, PA: 0x{:x}, ISS: 0x{:x}",
-                                    i, va, pa, iss
-                                ));
-                            }
-                            _ => {
-                                // Other exception
-                                return Err(format!(
-                                    "VCPU {} exited with exception EC=0x{:x}, ISS=0x{:x}",
-                                    i, ec, iss
-                                ));
-                            }
+                match ec {
+                    0x16 => {
+                        // HVC, used by the guest for PSCI calls
+                        handle_psci_call(&vcpu.vcpu, i)
+                    }
+                    0x0D => {
+                        // General Protection Fault
+                        Ok(VcpuExit::Exception { reason: "encountered General Protection Fault".to_string() })
+                    }
+                    0x01 => {
+                        // WFI or WFE: sleep until an interrupt is signalled, then resume after it
+                        advance_pc(&vcpu.vcpu, i)?;
+                        if !self.gic.has_pending_irq() {
+                            self.gic.wait_for_irq(WFI_POLL_INTERVAL);
+                        }
+                        Ok(VcpuExit::Handled)
+                    }
+                    0x18 => {
+                        // MSR/MRS: only the GICv3 CPU interface registers are emulated
+                        if !handle_sysreg_access(&vcpu.vcpu, i, syndrome, &self.gic)? {
+                            return Ok(VcpuExit::Exception {
+                                reason: format!("accessed an unhandled system register, ISS: 0x{:x}", iss),
+                            });
                         }
+                        Ok(VcpuExit::Handled)
                     }
-                    ExitReason::VTIMER_ACTIVATED => {
-                        return Err(format!("VCPU {} exited due to virtual timer activation", i));
+                    0x15 => { // Data Abort
+                        let va = exception.virtual_address;
+                        let pa = exception.physical_address;
+                        // Without a valid syndrome, e.g. for a load pair, the access can't be emulated
+                        if syndrome & DABT_ISV == 0 {
+                            return Ok(VcpuExit::Exception {
+                                reason: format!("Data Abort at VA: 0x{:x}, PA: 0x{:x}, ISS: 0x{:x}", va, pa, iss),
+                            });
+                        }
+                        start_data_abort(vcpu, i, syndrome, pa)
                     }
-                    ExitReason::UNKNOWN => {
-                        return Err(format!("VCPU {} exited due to unknown reason", i));
+                    _ => {
+                        // Other exception
+                        Ok(VcpuExit::Exception { reason: format!("exited with exception EC=0x{:x}, ISS=0x{:x}", ec, iss) })
                     }
-                };
+                }
             }
-        });
-
-        handlers.push(handle);
+            ExitReason::VTIMER_ACTIVATED => {
+                // The timer stays masked until its level drops, see above
+                self.gic.set_ppi_level(VTIMER_PPI, true);
+                vcpu.vtimer_masked = true;
+                Ok(VcpuExit::Handled)
+            }
+            ExitReason::UNKNOWN => {
+                Ok(VcpuExit::Exception { reason: "exited due to unknown reason".to_string() })
+            }
+        }
     }
 
-    // Await all VCPU tasks and check for errors.
-    for handle in handlers {
-        if let Err(_) = handle.await {
-            return Err("Failed to join VCPU task".to_string());
+    fn get_guest_position(&self, vcpu: &HvfVcpu, i: u32) -> Result<Option<(GuestMode, u64)>, VmError> {
+        let pc = match vcpu.vcpu.get_reg(Reg::PC) {
+            Ok(pc) => pc,
+            Err(_) => return Err(VmError::hypervisor(format!("Failed to read PC of VCPU {}", i)))
+        };
+        let cpsr = match vcpu.vcpu.get_reg(Reg::CPSR) {
+            Ok(cpsr) => cpsr,
+            Err(_) => return Err(VmError::hypervisor(format!("Failed to read CPSR of VCPU {}", i)))
         };
+        // CPSR.M[3:2] holds the exception level the guest runs at
+        let mode = if (cpsr >> 2) & 0x3 == 0 { GuestMode::User } else { GuestMode::Kernel };
+        Ok(Some((mode, pc)))
+    }
+
+    fn get_host_memory() -> Result<MemoryStatus, VmError> {
+        let (total, available) = get_physical_memory_info()?;
+        Ok(MemoryStatus { total, available })
+    }
+}
+
+/// Run a Virtual Machine with the given setup, with its vCPUs obeying `control`.
+async fn run_vm_with_control(setup: VmSetup, control: Arc<VmControl>) -> Result<VmExitSummary, VmError> {
+    check_attachments(&setup, "Hypervisor.framework", &[Attachment::Kernel, Attachment::SharedDirectory, Attachment::GicV3])?;
+    check_host_resources(setup.get_memory_size() as u64, setup.get_cpu_cores_count())?;
+
+    // The direct kernel is the only boot device here; it boots unless left out of the boot order
+    let kernel = setup.get_kernel().filter(|_| setup.get_boot_order().contains(&BootDevice::Kernel));
+
+    let mut backend = HvfBackend::create_vm(&setup)?;
+    // A directly booted kernel gets RAM at ARM64_RAM_BASE, anything else keeps starting at 0x4000.
+    let ram_base = if kernel.is_some() { ARM64_RAM_BASE } else { 0x4000 };
+    let mut mem = backend.map_memory(ram_base, setup.get_memory_size())?;
+
+    // Devices are found through the device tree, so only a directly booted kernel gets them
+    let mut mmio_bus = MmioBus::new();
+    let mut arm64_devices = Arm64Devices::default();
+    if kernel.is_some() {
+        let guest_memory = wrap_guest_memory(&mem)?;
+        arm64_devices = attach_devices(&setup, &guest_memory, &backend.gic, &mut mmio_bus)?;
+    } else if !setup.get_shared_dirs().is_empty() {
+        return Err(VmError::config("shared directories need a directly booted kernel on Hypervisor.framework"));
+    }
+    let devices = Arc::new(VcpuDevices {
+        mmio_bus,
+        pio_bus: PortIoBus::new(),
+        recorder: None,
+        latencies: Arc::clone(control.get_exit_latencies()),
+    });
+
+    // Load the kernel, initrd and device tree and get the boot vCPU entry state
+    if let Some(kernel) = kernel {
+        backend.boot = Some(load_kernel(&mut mem, kernel, setup.get_kernel_cmdline(), ram_base, setup.get_memory_size() as u64, &arm64_devices)?);
+    }
+    // Secondary vCPUs are brought up through PSCI CPU_ON, which isn't supported yet,
+    // so a directly booted kernel runs on the boot vCPU only.
+    let vcpu_count = if backend.boot.is_some() { 1 } else { setup.get_cpu_cores_count() };
+
+    // Interrupts raised by devices make the vCPU exit so it enters again with its IRQ pending
+    let backend = Arc::new(backend);
+    let kicked = Arc::downgrade(&backend);
+    backend.gic.set_kick(Box::new(move || {
+        if let Some(backend) = kicked.upgrade() {
+            backend.kick();
+        }
+    }));
+
+    run_vcpus(backend, control, vcpu_count, devices, Vec::new()).await
+}
+
+/// Guest physical address of the GIC distributor, below RAM.
+const GICD_BASE: u64 = 0x0800_0000;
+/// Guest physical address of the GICv2 CPU interface, after the distributor.
+const GICC_BASE: u64 = 0x0801_0000;
+/// Guest physical address of the GICv3 redistributors, after the distributor.
+const GICR_BASE: u64 = 0x080a_0000;
+/// Guest physical address of the registers of the virtio-rng device.
+const VIRTIO_RNG_MMIO_BASE: u64 = 0x0a00_0000;
+/// Guest physical address of the registers of the first virtio-9p device, after the
+/// virtio-rng device; the next ones follow it.
+const VIRTIO_9P_MMIO_BASE: u64 = 0x0a00_1000;
+/// SPI of the virtio-rng device; the virtio-9p devices take the next ones.
+const VIRTIO_RNG_SPI: u32 = 16;
+/// PPI of the virtual timer, as described by the device tree.
+const VTIMER_PPI: u32 = 11;
+/// CNTV_CTL_EL0 bits: timer enabled, interrupt masked and timer condition met.
+const CNTV_CTL_ENABLE: u64 = 1 << 0;
+const CNTV_CTL_IMASK: u64 = 1 << 1;
+const CNTV_CTL_ISTATUS: u64 = 1 << 2;
+/// How long a vCPU waiting for an interrupt sleeps before it runs again; the virtual
+/// timer only fires while the vCPU runs.
+const WFI_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Data Abort syndrome bits describing the access: valid syndrome, access size, sign
+/// extension, target register, 64-bit register and write.
+const DABT_ISV: u64 = 1 << 24;
+const DABT_SAS_SHIFT: u64 = 22;
+const DABT_SSE: u64 = 1 << 21;
+const DABT_SRT_SHIFT: u64 = 16;
+const DABT_SF: u64 = 1 << 15;
+const DABT_WNR: u64 = 1 << 6;
+/// System register trap syndrome: read, and the shifts of the register encoding fields
+/// and of the transferred register.
+const SYSREG_READ: u64 = 1 << 0;
+const SYSREG_OP0_SHIFT: u64 = 20;
+const SYSREG_OP2_SHIFT: u64 = 17;
+const SYSREG_OP1_SHIFT: u64 = 14;
+const SYSREG_CRN_SHIFT: u64 = 10;
+const SYSREG_RT_SHIFT: u64 = 5;
+const SYSREG_CRM_SHIFT: u64 = 1;
+/// General-purpose registers by number; number 31 is the zero register in loads and stores.
+const X_REGISTERS: [Reg; 31] = [
+    Reg::X0, Reg::X1, Reg::X2, Reg::X3, Reg::X4, Reg::X5, Reg::X6, Reg::X7, Reg::X8, Reg::X9, Reg::X10, Reg::X11, Reg::X12,
+    Reg::X13, Reg::X14, Reg::X15, Reg::X16, Reg::X17, Reg::X18, Reg::X19, Reg::X20, Reg::X21, Reg::X22, Reg::X23, Reg::X24,
+    Reg::X25, Reg::X26, Reg::X27, Reg::X28, Reg::X29, Reg::X30,
+];
+
+/// Puts the register windows of the GIC, the virtio-rng device and a virtio-9p device
+/// per shared directory on the MMIO bus.
+///
+/// # Returns
+/// * `Ok(Arm64Devices)` describing the devices for the device tree
+/// * `Err(VmError)` if a device can't be created or doesn't fit the bus
+fn attach_devices(setup: &VmSetup, guest_memory: &GuestMemoryMmap, gic: &Arc<Gic>, mmio_bus: &mut MmioBus) -> Result<Arm64Devices, VmError> {
+    let version = gic.get_version();
+    let distributor = GicDistributor::new(Arc::clone(gic));
+    mmio_bus.register(GICD_BASE, version.get_distributor_size(), Arc::new(std::sync::Mutex::new(distributor)))?;
+    mmio_bus.set_name(GICD_BASE, "gic-distributor");
+    let layout = match version {
+        GicVersion::V2 => {
+            mmio_bus.register(GICC_BASE, GICC_SIZE, Arc::new(std::sync::Mutex::new(GicCpuInterface::new(Arc::clone(gic)))))?;
+            mmio_bus.set_name(GICC_BASE, "gic-cpu-interface");
+            Arm64Gic::V2 { distributor: GICD_BASE, cpu_interface: GICC_BASE }
+        },
+        GicVersion::V3 => {
+            // The only vCPU gets the only redistributor
+            mmio_bus.register(GICR_BASE, GICR_SIZE, Arc::new(std::sync::Mutex::new(GicRedistributor::new(Arc::clone(gic)))))?;
+            mmio_bus.set_name(GICR_BASE, "gic-redistributor");
+            Arm64Gic::V3 { distributor: GICD_BASE, redistributors: GICR_BASE }
+        },
+    };
+    let mut devices = Arm64Devices { gic: Some(layout), virtio_mmio: Vec::new() };
+
+    // Entropy for the guest kernel, which otherwise stalls early boot services
+    let interrupt = GicSpi::new(Arc::clone(gic), VIRTIO_RNG_SPI, TriggerMode::Level)?;
+    let transport = MmioTransport::new(VirtioRng::new(guest_memory.clone())?, Box::new(interrupt));
+    mmio_bus.register(VIRTIO_RNG_MMIO_BASE, VIRTIO_MMIO_SIZE, Arc::new(std::sync::Mutex::new(transport)))?;
+    mmio_bus.set_name(VIRTIO_RNG_MMIO_BASE, "virtio-rng0");
+    devices.virtio_mmio.push((VIRTIO_RNG_MMIO_BASE, VIRTIO_RNG_SPI));
+
+    // Host directories the guest mounts by their tag
+    for (index, shared_dir) in setup.get_shared_dirs().iter().enumerate() {
+        let base = VIRTIO_9P_MMIO_BASE + index as u64 * VIRTIO_MMIO_SIZE;
+        let spi = VIRTIO_RNG_SPI + 1 + index as u32;
+        let interrupt = GicSpi::new(Arc::clone(gic), spi, TriggerMode::Level)?;
+        let device = Virtio9p::new(guest_memory.clone(), shared_dir.get_path(), shared_dir.get_tag())?;
+        mmio_bus.register(base, VIRTIO_MMIO_SIZE, Arc::new(std::sync::Mutex::new(MmioTransport::new(device, Box::new(interrupt)))))?;
+        mmio_bus.set_name(base, &format!("virtio-9p{}", index));
+        devices.virtio_mmio.push((base, spi));
+    }
+    Ok(devices)
+}
+
+/// Returns `true` if the virtual timer of the vCPU asserts its interrupt.
+fn is_vtimer_firing(vcpu: &Vcpu, i: u32) -> Result<bool, VmError> {
+    let ctl = match vcpu.get_sys_reg(SysReg::CNTV_CTL_EL0) {
+        Ok(ctl) => ctl,
+        Err(_) => return Err(VmError::hypervisor(format!("Failed to read the virtual timer control of VCPU {}", i)))
+    };
+    Ok(ctl & (CNTV_CTL_ENABLE | CNTV_CTL_IMASK | CNTV_CTL_ISTATUS) == CNTV_CTL_ENABLE | CNTV_CTL_ISTATUS)
+}
+
+/// Moves the PC of the vCPU past the trapped instruction.
+fn advance_pc(vcpu: &Vcpu, i: u32) -> Result<(), VmError> {
+    let pc = match vcpu.get_reg(Reg::PC) {
+        Ok(pc) => pc,
+        Err(_) => return Err(VmError::hypervisor(format!("Failed to read PC of VCPU {}", i)))
+    };
+    if let Err(_) = vcpu.set_reg(Reg::PC, pc + 4) {
+        return Err(VmError::hypervisor(format!("Failed to write PC of VCPU {}", i)));
+    }
+    Ok(())
+}
+
+/// Starts a guest load or store to a device register, which traps as a Data Abort at
+/// the unmapped address `pa`, as an MMIO exit. `complete_data_abort` finishes it.
+fn start_data_abort(vcpu: &mut HvfVcpu, i: u32, syndrome: u64, pa: u64) -> Result<VcpuExit<'_>, VmError> {
+    let size = 1usize << ((syndrome >> DABT_SAS_SHIFT) & 0x3);
+    vcpu.pending_abort = Some(syndrome);
+    if syndrome & DABT_WNR != 0 {
+        let register = ((syndrome >> DABT_SRT_SHIFT) & 0x1f) as usize;
+        vcpu.data = read_x_register(&vcpu.vcpu, i, register)?.to_le_bytes();
+        Ok(VcpuExit::MmioWrite(pa, &vcpu.data[..size]))
+    } else {
+        vcpu.data = [0; 8];
+        Ok(VcpuExit::MmioRead(pa, &mut vcpu.data[..size]))
+    }
+}
+
+/// Completes the device access started by `start_data_abort`: a load gets `data` into
+/// its target register, and the PC moves past the instruction.
+fn complete_data_abort(vcpu: &Vcpu, i: u32, syndrome: u64, data: &[u8; 8]) -> Result<(), VmError> {
+    if syndrome & DABT_WNR == 0 {
+        let size = 1usize << ((syndrome >> DABT_SAS_SHIFT) & 0x3);
+        let register = ((syndrome >> DABT_SRT_SHIFT) & 0x1f) as usize;
+        let mut value = read_le(&data[..size]);
+        if syndrome & DABT_SSE != 0 && size < 8 {
+            let shift = 64 - size as u32 * 8;
+            value = ((value << shift) as i64 >> shift) as u64;
+            // Sign extension into a W register leaves the upper half clear
+            if syndrome & DABT_SF == 0 {
+                value &= 0xffff_ffff;
+            }
+        }
+        write_x_register(vcpu, i, register, value)?;
+    }
+    advance_pc(vcpu, i)
+}
+
+/// Reads general-purpose register `register` of the vCPU; number 31 reads as zero.
+fn read_x_register(vcpu: &Vcpu, i: u32, register: usize) -> Result<u64, VmError> {
+    match X_REGISTERS.get(register) {
+        Some(reg) => match vcpu.get_reg(*reg) {
+            Ok(value) => Ok(value),
+            Err(_) => Err(VmError::hypervisor(format!("Failed to read X{} of VCPU {}", register, i)))
+        },
+        None => Ok(0)
+    }
+}
+
+/// Writes general-purpose register `register` of the vCPU; writes to number 31 are discarded.
+fn write_x_register(vcpu: &Vcpu, i: u32, register: usize, value: u64) -> Result<(), VmError> {
+    if let Some(reg) = X_REGISTERS.get(register) {
+        if let Err(_) = vcpu.set_reg(*reg, value) {
+            return Err(VmError::hypervisor(format!("Failed to write X{} of VCPU {}", register, i)));
+        }
     }
-    
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Completes a trapped MSR or MRS to a system register of the GICv3 CPU interface.
+///
+/// # Returns
+/// * `Ok(true)` once the access was served and the PC moved past the instruction
+/// * `Ok(false)` if the register isn't one the GIC emulates
+/// * `Err(VmError)` if the vCPU registers can't be accessed
+fn handle_sysreg_access(vcpu: &Vcpu, i: u32, syndrome: u64, gic: &Gic) -> Result<bool, VmError> {
+    let field = |shift: u64, mask: u64| ((syndrome >> shift) & mask) as u8;
+    let register = (
+        field(SYSREG_OP0_SHIFT, 0x3), field(SYSREG_OP1_SHIFT, 0x7), field(SYSREG_CRN_SHIFT, 0xf),
+        field(SYSREG_CRM_SHIFT, 0xf), field(SYSREG_OP2_SHIFT, 0x7),
+    );
+    let rt = field(SYSREG_RT_SHIFT, 0x1f) as usize;
+
+    if syndrome & SYSREG_READ != 0 {
+        match gic.read_icc_register(register) {
+            Some(value) => write_x_register(vcpu, i, rt, value)?,
+            None => return Ok(false)
+        }
+    } else if !gic.write_icc_register(register, read_x_register(vcpu, i, rt)?) {
+        return Ok(false);
+    }
+    advance_pc(vcpu, i)?;
+    Ok(true)
+}
+
+/// Serves a PSCI call made by the guest with `hvc #0`.
+///
+/// # Returns
+/// * `Ok(VcpuExit)` powering the vCPU or the system off, or resetting it
+/// * `Ok(VcpuExit::Handled)` if the guest can keep running, with the result in X0
+/// * `Err(VmError)` if the vCPU registers can't be accessed
+fn handle_psci_call(vcpu: &Vcpu, i: u32) -> Result<VcpuExit<'static>, VmError> {
+    let function_id = match vcpu.get_reg(Reg::X0) {
+        Ok(x0) => x0 as u32,
+        Err(_) => return Err(VmError::hypervisor(format!("Failed to read X0 of VCPU {}", i)))
+    };
+
+    let result = match PsciCall::from_function_id(function_id) {
+        // PSCI 0.2
+        PsciCall::Version => 0x2,
+        PsciCall::CpuOff => return Ok(VcpuExit::PoweredOff),
+        PsciCall::SystemOff => return Ok(VcpuExit::Shutdown),
+        PsciCall::SystemReset => return Ok(VcpuExit::Reset),
+        PsciCall::CpuOn | PsciCall::Unsupported(_) => PSCI_NOT_SUPPORTED,
+    };
+
+    if let Err(_) = vcpu.set_reg(Reg::X0, result) {
+        return Err(VmError::hypervisor(format!("Failed to write X0 of VCPU {}", i)));
+    }
+    Ok(VcpuExit::Handled)
+}
+
+/// Copies the kernel, initrd and a device tree describing `devices` into guest RAM.
+///
+/// # Returns
+/// * `Ok((entry, fdt_addr))` with the kernel entry point and device tree address
+/// * `Err(VmError)` if the kernel isn't an ARM64 Image or doesn't fit in RAM
+fn load_kernel(mem: &mut Mapping, kernel: &KernelComponents, cmdline: &str, ram_base: u64, ram_size: u64,
+               devices: &Arm64Devices) -> Result<(u64, u64), VmError> {
+    let image = decompress_kernel(&kernel.kernel)?;
+    let header = Arm64ImageHeader::parse(&image)?;
+    let layout = compute_boot_layout(&header, kernel.initrd.as_ref().map(|initrd| initrd.len()), ram_base, ram_size)?;
+    let fdt = build_fdt_with_devices(cmdline, ram_base, ram_size, layout.initrd, 1, devices)?;
+
+    let mut write = |addr: u64, data: &[u8], what: &str| match mem.write(addr, data) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(VmError::memory(format!("Failed to write {} at {:#x}: {}", what, addr, e)))
+    };
+    write(layout.kernel_addr, &image, "kernel")?;
+    if let (Some(initrd), Some((addr, _))) = (&kernel.initrd, layout.initrd) {
+        write(addr, initrd, "initrd")?;
+    }
+    write(layout.fdt_addr, &fdt, "device tree")?;
+
+    Ok((layout.kernel_addr, layout.fdt_addr))
+}
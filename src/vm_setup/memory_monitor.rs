@@ -0,0 +1,297 @@
+//! Host memory pressure tracking while VMs run.
+//!
+//! Backends read the host's memory status (through `GlobalMemoryStatusEx` on
+//! Windows, `sysctl` on macOS and `/proc/meminfo` on Linux) and feed it into the
+//! `MemoryMonitor` of each running VM at a regular interval. The monitor turns the
+//! available memory into a `MemoryPressure` level and tells its handlers whenever the
+//! level changes, so they can log, alert or make the guest give memory back, e.g. by
+//! inflating a balloon.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::error::VmError;
+
+/// Interval at which the host memory status is read unless configured otherwise.
+pub const DEFAULT_MEMORY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Physical memory of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryStatus {
+    /// Total physical memory in bytes
+    pub total: u64,
+    /// Physical memory available to new allocations, in bytes
+    pub available: u64,
+}
+
+/// How short the host is on memory, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum MemoryPressure {
+    /// Enough memory is available
+    #[default]
+    Normal,
+    /// Available memory fell below the low threshold
+    Low,
+    /// Available memory fell below the critical threshold; the host may start paging
+    /// guest memory out or fail allocations
+    Critical,
+}
+
+/// Thresholds of the pressure levels, in percent of the host's total memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryThresholds {
+    /// Available memory below which the pressure is `Low`
+    pub low_percent: u8,
+    /// Available memory below which the pressure is `Critical`
+    pub critical_percent: u8,
+    /// Margin above a threshold that available memory must reach before the
+    /// pressure drops back, so a level doesn't flap around its threshold
+    pub hysteresis_percent: u8,
+}
+
+impl Default for MemoryThresholds {
+    fn default() -> MemoryThresholds {
+        MemoryThresholds { low_percent: 10, critical_percent: 5, hysteresis_percent: 2 }
+    }
+}
+
+impl MemoryThresholds {
+    /// Returns the pressure level of `status` for a monitor currently at `current`.
+    pub fn get_level(&self, status: MemoryStatus, current: MemoryPressure) -> MemoryPressure {
+        let below = |percent: u8| (status.available as u128) * 100 < (status.total as u128) * percent as u128;
+        let level = if below(self.critical_percent) {
+            MemoryPressure::Critical
+        } else if below(self.low_percent) {
+            MemoryPressure::Low
+        } else {
+            MemoryPressure::Normal
+        };
+
+        // Getting worse takes effect right away, getting better only past the margin
+        let threshold = match current {
+            MemoryPressure::Critical => self.critical_percent,
+            MemoryPressure::Low => self.low_percent,
+            MemoryPressure::Normal => return level,
+        };
+        if level < current && below(threshold.saturating_add(self.hysteresis_percent)) {
+            return current;
+        }
+        level
+    }
+}
+
+/// Change of the memory pressure level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryPressureEvent {
+    /// Level before the change
+    pub previous: MemoryPressure,
+    /// Level after the change
+    pub level: MemoryPressure,
+    /// Memory status that caused the change
+    pub status: MemoryStatus,
+}
+
+/// Reacts to memory pressure changes, e.g. by asking a balloon device to reclaim
+/// guest memory. Closures taking a `&MemoryPressureEvent` are handlers too.
+pub trait MemoryPressureHandler: Send + Sync {
+    /// Called on every level change, from the thread reading the memory status.
+    fn on_pressure_change(&self, event: &MemoryPressureEvent);
+}
+
+impl<F: Fn(&MemoryPressureEvent) + Send + Sync> MemoryPressureHandler for F {
+    fn on_pressure_change(&self, event: &MemoryPressureEvent) {
+        self(event)
+    }
+}
+
+struct MonitorState {
+    thresholds: MemoryThresholds,
+    interval: Duration,
+    level: MemoryPressure,
+    status: Option<MemoryStatus>,
+}
+
+/// Memory pressure of the host as seen by one VM, see the module documentation.
+pub struct MemoryMonitor {
+    state: Mutex<MonitorState>,
+    handlers: Mutex<Vec<Box<dyn MemoryPressureHandler>>>,
+}
+
+impl Default for MemoryMonitor {
+    fn default() -> MemoryMonitor {
+        MemoryMonitor::new()
+    }
+}
+
+impl MemoryMonitor {
+    /// Creates a monitor with the default thresholds and poll interval.
+    pub fn new() -> MemoryMonitor {
+        MemoryMonitor {
+            state: Mutex::new(MonitorState {
+                thresholds: MemoryThresholds::default(),
+                interval: DEFAULT_MEMORY_POLL_INTERVAL,
+                level: MemoryPressure::Normal,
+                status: None,
+            }),
+            handlers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sets the thresholds of the pressure levels; they apply from the next update.
+    pub fn set_thresholds(&self, thresholds: MemoryThresholds) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).thresholds = thresholds;
+    }
+
+    /// Returns the thresholds of the pressure levels.
+    pub fn get_thresholds(&self) -> MemoryThresholds {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).thresholds
+    }
+
+    /// Sets how often the memory status is read.
+    pub fn set_interval(&self, interval: Duration) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).interval = interval;
+    }
+
+    /// Returns how often the memory status is read.
+    pub fn get_interval(&self) -> Duration {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).interval
+    }
+
+    /// Returns the current pressure level; `Normal` until the first update.
+    pub fn get_pressure(&self) -> MemoryPressure {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).level
+    }
+
+    /// Returns the latest memory status, if the host memory is watched at all.
+    pub fn get_status(&self) -> Option<MemoryStatus> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).status
+    }
+
+    /// Adds a handler called on every pressure level change.
+    pub fn add_handler(&self, handler: impl MemoryPressureHandler + 'static) {
+        self.handlers.lock().unwrap_or_else(|e| e.into_inner()).push(Box::new(handler));
+    }
+
+    /// Records a new memory status and calls the handlers if the level changed.
+    ///
+    /// # Returns
+    /// * `Some(MemoryPressureEvent)` if the level changed
+    /// * `None` otherwise
+    pub fn update(&self, status: MemoryStatus) -> Option<MemoryPressureEvent> {
+        let event = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.status = Some(status);
+            let level = state.thresholds.get_level(status, state.level);
+            if level == state.level {
+                return None;
+            }
+            let event = MemoryPressureEvent { previous: state.level, level, status };
+            state.level = level;
+            event
+        };
+
+        for handler in self.handlers.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            handler.on_pressure_change(&event);
+        }
+        Some(event)
+    }
+}
+
+/// Task feeding a `MemoryMonitor`; the task ends when this is dropped.
+pub struct MemoryWatch {
+    task: JoinHandle<()>,
+}
+
+impl Drop for MemoryWatch {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Starts feeding `monitor` with the status returned by `probe` at the monitor's
+/// interval, warning on stderr when the pressure rises.
+///
+/// Backends call this with the host's memory status; on hosts they don't watch yet,
+/// `probe` can read the status some other way. Must be called from within a Tokio
+/// runtime.
+pub fn watch_host_memory<P>(monitor: Arc<MemoryMonitor>, probe: P) -> MemoryWatch
+where
+    P: Fn() -> Result<MemoryStatus, VmError> + Send + 'static,
+{
+    let task = tokio::spawn(async move {
+        loop {
+            match probe() {
+                Ok(status) => {
+                    if let Some(event) = monitor.update(status) && event.level > event.previous {
+                        eprintln!(
+                            "Host memory pressure {:?}: {} of {} MiB available",
+                            event.level,
+                            status.available >> 20,
+                            status.total >> 20
+                        );
+                    }
+                },
+                Err(e) => eprintln!("Failed to read host memory status: {}", e)
+            }
+            tokio::time::sleep(monitor.get_interval()).await;
+        }
+    });
+    MemoryWatch { task }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    fn status(available_percent: u64) -> MemoryStatus {
+        MemoryStatus { total: 100 * GIB, available: available_percent * GIB }
+    }
+
+    #[test]
+    fn test_pressure_levels_with_hysteresis() {
+        let monitor = MemoryMonitor::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        monitor.add_handler(move |event: &MemoryPressureEvent| recorded.lock().unwrap().push((event.previous, event.level)));
+
+        assert_eq!(monitor.update(status(50)), None);
+        assert_eq!(monitor.update(status(9)).map(|event| event.level), Some(MemoryPressure::Low));
+        assert_eq!(monitor.update(status(4)).map(|event| event.level), Some(MemoryPressure::Critical));
+        // Within the margin above the critical threshold
+        assert_eq!(monitor.update(status(6)), None);
+        assert_eq!(monitor.get_pressure(), MemoryPressure::Critical);
+        assert_eq!(monitor.update(status(8)).map(|event| event.level), Some(MemoryPressure::Low));
+        assert_eq!(monitor.update(status(11)), None);
+        assert_eq!(monitor.update(status(30)).map(|event| event.level), Some(MemoryPressure::Normal));
+        assert_eq!(monitor.get_status(), Some(status(30)));
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            (MemoryPressure::Normal, MemoryPressure::Low),
+            (MemoryPressure::Low, MemoryPressure::Critical),
+            (MemoryPressure::Critical, MemoryPressure::Low),
+            (MemoryPressure::Low, MemoryPressure::Normal),
+        ]);
+    }
+
+    #[test]
+    fn test_custom_thresholds() {
+        let monitor = MemoryMonitor::new();
+        monitor.set_thresholds(MemoryThresholds { low_percent: 40, critical_percent: 20, hysteresis_percent: 0 });
+        assert_eq!(monitor.update(status(30)).map(|event| event.level), Some(MemoryPressure::Low));
+        assert_eq!(monitor.update(status(40)).map(|event| event.level), Some(MemoryPressure::Normal));
+        // An empty status never counts as pressure
+        assert_eq!(MemoryThresholds::default().get_level(MemoryStatus::default(), MemoryPressure::Normal), MemoryPressure::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_watch_host_memory() {
+        let monitor = Arc::new(MemoryMonitor::new());
+        monitor.set_interval(Duration::from_millis(5));
+        let watch = watch_host_memory(Arc::clone(&monitor), || Ok(status(3)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(monitor.get_pressure(), MemoryPressure::Critical);
+        drop(watch);
+    }
+}
@@ -0,0 +1,252 @@
+//! KVM memory slot bookkeeping.
+//!
+//! Every guest RAM region is registered with KVM under a slot id. The registry hands
+//! out slot ids, refuses misaligned or overlapping regions and remembers what each
+//! slot maps, so regions can be added and removed while the VM runs (memory hotplug,
+//! virtio-mem) without the caller tracking ids itself. Slots registered with
+//! `KVM_MEM_LOG_DIRTY_PAGES` report the pages the guest wrote to, which incremental
+//! memory checkpoints are built from, see `checkpoint`.
+
+use std::collections::BTreeMap;
+use kvm_bindings::kvm_userspace_memory_region;
+use kvm_ioctls::VmFd;
+use crate::error::VmError;
+
+/// Alignment required for the guest address, size and host address of a region.
+pub const MEMORY_SLOT_ALIGNMENT: u64 = 4096;
+
+/// A guest physical memory region registered with KVM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySlot {
+    /// KVM slot id
+    pub slot: u32,
+    /// First guest physical address of the region
+    pub guest_phys_addr: u64,
+    /// Size of the region in bytes
+    pub memory_size: u64,
+    /// Host virtual address backing the region
+    pub userspace_addr: u64,
+    /// KVM memory region flags (`KVM_MEM_LOG_DIRTY_PAGES`, `KVM_MEM_READONLY`)
+    pub flags: u32,
+}
+
+impl MemorySlot {
+    /// Returns the guest physical address just past the region.
+    pub fn get_end(&self) -> u64 {
+        self.guest_phys_addr + self.memory_size
+    }
+
+    /// Returns `true` if `addr` lies in the region.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.guest_phys_addr && addr < self.get_end()
+    }
+
+    fn as_kvm(&self) -> kvm_userspace_memory_region {
+        kvm_userspace_memory_region {
+            slot: self.slot,
+            flags: self.flags,
+            guest_phys_addr: self.guest_phys_addr,
+            memory_size: self.memory_size,
+            userspace_addr: self.userspace_addr,
+        }
+    }
+}
+
+/// The memory slots of a KVM VM.
+#[derive(Debug, Clone)]
+pub struct MemorySlotRegistry {
+    slots: BTreeMap<u32, MemorySlot>,
+    max_slots: u32,
+}
+
+impl MemorySlotRegistry {
+    /// Creates an empty registry.
+    ///
+    /// # Arguments
+    /// * `max_slots` - Number of slots KVM supports, from `Kvm::get_nr_memslots`
+    pub fn new(max_slots: u32) -> Self {
+        MemorySlotRegistry { slots: BTreeMap::new(), max_slots }
+    }
+
+    /// Validates a region and records it under the lowest free slot id.
+    ///
+    /// Only updates the registry; `add_region` also registers the region with KVM.
+    ///
+    /// # Returns
+    /// * `Ok(MemorySlot)` describing the recorded region
+    /// * `Err(VmError)` if the region is empty, misaligned, wraps around, overlaps another
+    ///   region or every slot is taken
+    pub fn allocate_slot(&mut self, guest_phys_addr: u64, memory_size: u64, userspace_addr: u64, flags: u32) -> Result<MemorySlot, VmError> {
+        if memory_size == 0 {
+            return Err(VmError::memory(format!("memory region at {:#x} is empty", guest_phys_addr)));
+        }
+        let aligned = [guest_phys_addr, memory_size, userspace_addr].iter().all(|value| value.is_multiple_of(MEMORY_SLOT_ALIGNMENT));
+        if !aligned {
+            return Err(VmError::memory(format!(
+                "memory region {:#x}+{:#x} (host {:#x}) is not aligned to {:#x} bytes", guest_phys_addr, memory_size, userspace_addr, MEMORY_SLOT_ALIGNMENT
+            )));
+        }
+        let end = match guest_phys_addr.checked_add(memory_size) {
+            Some(end) => end,
+            None => return Err(VmError::memory(format!("memory region {:#x}+{:#x} wraps around", guest_phys_addr, memory_size)))
+        };
+        if let Some(other) = self.slots.values().find(|other| guest_phys_addr < other.get_end() && other.guest_phys_addr < end) {
+            return Err(VmError::memory(format!(
+                "memory region {:#x}-{:#x} overlaps slot {} at {:#x}-{:#x}", guest_phys_addr, end - 1, other.slot, other.guest_phys_addr, other.get_end() - 1
+            )));
+        }
+        let slot = match (0..self.max_slots).find(|slot| !self.slots.contains_key(slot)) {
+            Some(slot) => slot,
+            None => return Err(VmError::memory(format!("all {} memory slots are in use", self.max_slots)))
+        };
+
+        let region = MemorySlot { slot, guest_phys_addr, memory_size, userspace_addr, flags };
+        self.slots.insert(slot, region);
+        Ok(region)
+    }
+
+    /// Forgets a slot without telling KVM.
+    ///
+    /// # Returns
+    /// * The region the slot held, if any
+    pub fn release_slot(&mut self, slot: u32) -> Option<MemorySlot> {
+        self.slots.remove(&slot)
+    }
+
+    /// Registers a region with KVM under a new slot.
+    ///
+    /// # Safety
+    /// `userspace_addr` must point to `memory_size` bytes of host memory that stay
+    /// mapped until the region is removed or the VM is destroyed.
+    ///
+    /// # Returns
+    /// * `Ok(MemorySlot)` describing the registered region
+    /// * `Err(VmError)` if the region is invalid or KVM refused it; the registry is left unchanged
+    pub unsafe fn add_region(&mut self, vm: &VmFd, guest_phys_addr: u64, memory_size: u64, userspace_addr: u64, flags: u32) -> Result<MemorySlot, VmError> {
+        let region = self.allocate_slot(guest_phys_addr, memory_size, userspace_addr, flags)?;
+        // SAFETY: the caller guarantees the host mapping outlives the slot
+        if let Err(e) = unsafe { vm.set_user_memory_region(region.as_kvm()) } {
+            self.slots.remove(&region.slot);
+            return Err(VmError::memory_source(format!("Failed to set memory region for slot {}: {}", region.slot, e), e));
+        }
+        Ok(region)
+    }
+
+    /// Removes a region from KVM and frees its slot.
+    ///
+    /// # Returns
+    /// * `Ok(MemorySlot)` describing the removed region
+    /// * `Err(VmError)` if the slot isn't in use or KVM refused to delete it
+    pub fn remove_region(&mut self, vm: &VmFd, slot: u32) -> Result<MemorySlot, VmError> {
+        let region = match self.slots.get(&slot) {
+            Some(region) => *region,
+            None => return Err(VmError::memory(format!("memory slot {} is not in use", slot)))
+        };
+        // A zero sized region deletes the slot
+        let deleted = kvm_userspace_memory_region { memory_size: 0, ..region.as_kvm() };
+        // SAFETY: deleting a slot doesn't give KVM access to any host memory
+        if let Err(e) = unsafe { vm.set_user_memory_region(deleted) } {
+            return Err(VmError::memory_source(format!("Failed to remove memory slot {}: {}", slot, e), e));
+        }
+        self.slots.remove(&slot);
+        Ok(region)
+    }
+
+    /// Returns the guest physical addresses of the pages of `slot` written since the
+    /// last call, for a slot registered with `KVM_MEM_LOG_DIRTY_PAGES`.
+    ///
+    /// # Returns
+    /// * `Ok(addresses)` on success
+    /// * `Err(VmError)` if the slot isn't in use or KVM has no dirty log for it
+    pub fn get_dirty_pages(&self, vm: &VmFd, slot: u32) -> Result<Vec<u64>, VmError> {
+        let region = match self.slots.get(&slot) {
+            Some(region) => *region,
+            None => return Err(VmError::memory(format!("memory slot {} is not in use", slot)))
+        };
+        match vm.get_dirty_log(slot, region.memory_size as usize) {
+            Ok(bitmap) => Ok(get_dirty_addresses(region.guest_phys_addr, &bitmap)),
+            Err(e) => Err(VmError::memory_source(format!("Failed to get the dirty log of memory slot {}: {}", slot, e), e))
+        }
+    }
+
+    /// Returns the region containing guest physical address `addr`, if any.
+    pub fn find_region(&self, addr: u64) -> Option<&MemorySlot> {
+        self.slots.values().find(|region| region.contains(addr))
+    }
+
+    /// Returns the region registered under `slot`, if any.
+    pub fn get_slot(&self, slot: u32) -> Option<&MemorySlot> {
+        self.slots.get(&slot)
+    }
+
+    /// Returns the registered regions ordered by slot id.
+    pub fn get_slots(&self) -> impl Iterator<Item = &MemorySlot> {
+        self.slots.values()
+    }
+
+    /// Returns the number of slots in use.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns `true` if no slot is in use.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+/// Decodes a KVM dirty log, one bit per page of the region at `guest_phys_addr`.
+fn get_dirty_addresses(guest_phys_addr: u64, bitmap: &[u64]) -> Vec<u64> {
+    let mut addresses = Vec::new();
+    for (word_index, word) in bitmap.iter().enumerate() {
+        let mut bits = *word;
+        while bits != 0 {
+            let page = word_index as u64 * 64 + bits.trailing_zeros() as u64;
+            addresses.push(guest_phys_addr + page * MEMORY_SLOT_ALIGNMENT);
+            bits &= bits - 1;
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: u64 = 0x7f00_0000_0000;
+
+    #[test]
+    fn test_slot_ids_are_reused() {
+        let mut registry = MemorySlotRegistry::new(8);
+        assert_eq!(registry.allocate_slot(0, 0x10_0000, HOST, 0).unwrap().slot, 0);
+        assert_eq!(registry.allocate_slot(0x10_0000, 0x10_0000, HOST + 0x10_0000, 0).unwrap().slot, 1);
+        assert_eq!(registry.allocate_slot(0x100_0000, 0x1000, HOST + 0x20_0000, 0).unwrap().slot, 2);
+
+        assert_eq!(registry.release_slot(1).map(|region| region.guest_phys_addr), Some(0x10_0000));
+        assert_eq!(registry.allocate_slot(0x20_0000, 0x1000, HOST + 0x30_0000, 0).unwrap().slot, 1);
+        assert_eq!(registry.find_region(0x20_0fff).map(|region| region.slot), Some(1));
+        assert!(registry.find_region(0x20_1000).is_none());
+    }
+
+    #[test]
+    fn test_invalid_regions_are_refused() {
+        let mut registry = MemorySlotRegistry::new(2);
+        registry.allocate_slot(0x10_0000, 0x10_0000, HOST, 0).unwrap();
+
+        assert!(registry.allocate_slot(0x1_f000, 0x2000, HOST, 0).is_ok());
+        assert!(registry.allocate_slot(0x1ff000, 0x2000, HOST, 0).is_err()); // Overlap
+        assert!(registry.allocate_slot(0x30_0800, 0x1000, HOST, 0).is_err()); // Misaligned address
+        assert!(registry.allocate_slot(0x30_0000, 0x1800, HOST, 0).is_err()); // Misaligned size
+        assert!(registry.allocate_slot(0x30_0000, 0, HOST, 0).is_err());
+        assert!(registry.allocate_slot(u64::MAX - 0xfff, 0x2000, HOST, 0).is_err());
+        // Both slots are taken
+        assert!(registry.allocate_slot(0x40_0000, 0x1000, HOST, 0).is_err());
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn test_dirty_log_decoding() {
+        assert_eq!(get_dirty_addresses(0x10_0000, &[0b101, 0, 1 << 63]), vec![0x10_0000, 0x10_2000, 0x10_0000 + 191 * 0x1000]);
+        assert!(get_dirty_addresses(0, &[0, 0]).is_empty());
+    }
+}
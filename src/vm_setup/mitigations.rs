@@ -0,0 +1,346 @@
+//! Host CPU vulnerability mitigations and their effect on guests.
+//!
+//! The host kernel reports, for every speculative execution issue it knows about,
+//! whether the CPU is affected and how it is mitigated under
+//! `/sys/devices/system/cpu/vulnerabilities`. KVM adds its own knobs, such as the L1
+//! data cache flush on VM entry guarding against L1TF. `read_host_mitigations`
+//! collects both so operators of multi-tenant hosts can see what protects their
+//! guests from one another.
+//!
+//! A `MitigationPolicy` attached to a VM refuses to start it on a host left vulnerable
+//! to the listed issues, and can hide the speculation control CPUID bits from the
+//! guest, which then skips its own mitigations.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use crate::error::VmError;
+use crate::vm_setup::cpu_model::{CpuidEntry, CpuidRegister};
+
+/// Directory where the host kernel reports CPU vulnerabilities.
+pub const VULNERABILITIES_DIR: &str = "/sys/devices/system/cpu/vulnerabilities";
+/// Directory holding the kernel module parameters.
+pub const MODULE_PARAMETERS_DIR: &str = "/sys/module";
+
+/// KVM module parameters controlling mitigations, as `(module, parameter)`.
+const KVM_MITIGATION_PARAMETERS: [(&str, &str); 3] = [
+    ("kvm", "nx_huge_pages"),
+    ("kvm", "mitigate_smt_rsb"),
+    ("kvm_intel", "vmentry_l1d_flush"),
+];
+
+/// Speculation control feature bits, as `(leaf, subleaf, register, bit)`: SPEC_CTRL
+/// (IBRS and IBPB), STIBP, L1D_FLUSH, ARCH_CAPABILITIES, SSBD and MD_CLEAR on Intel,
+/// then IBPB, IBRS, STIBP, SSBD and VIRT_SSBD on AMD.
+const SPECULATION_CONTROL_BITS: [(u32, u32, CpuidRegister, u32); 11] = [
+    (0x7, 0, CpuidRegister::Edx, 26),
+    (0x7, 0, CpuidRegister::Edx, 27),
+    (0x7, 0, CpuidRegister::Edx, 28),
+    (0x7, 0, CpuidRegister::Edx, 29),
+    (0x7, 0, CpuidRegister::Edx, 31),
+    (0x7, 0, CpuidRegister::Edx, 10),
+    (0x8000_0008, 0, CpuidRegister::Ebx, 12),
+    (0x8000_0008, 0, CpuidRegister::Ebx, 14),
+    (0x8000_0008, 0, CpuidRegister::Ebx, 15),
+    (0x8000_0008, 0, CpuidRegister::Ebx, 24),
+    (0x8000_0008, 0, CpuidRegister::Ebx, 25),
+];
+
+/// Whether the host is exposed to a vulnerability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VulnerabilityState {
+    /// The CPU doesn't have the issue
+    NotAffected,
+    /// The CPU has the issue and the kernel mitigates it
+    Mitigated,
+    /// The CPU has the issue and nothing mitigates it
+    Vulnerable,
+    /// The kernel can't tell
+    Unknown,
+}
+
+impl VulnerabilityState {
+    /// Parses the status the kernel reports, e.g. `Mitigation: PTI`. KVM specific
+    /// statuses start with `KVM: `.
+    pub fn parse(status: &str) -> VulnerabilityState {
+        let status = status.strip_prefix("KVM: ").unwrap_or(status);
+        if status.starts_with("Not affected") {
+            VulnerabilityState::NotAffected
+        } else if status.starts_with("Mitigation") {
+            VulnerabilityState::Mitigated
+        } else if status.starts_with("Vulnerable") || status.starts_with("Processor vulnerable") {
+            VulnerabilityState::Vulnerable
+        } else {
+            VulnerabilityState::Unknown
+        }
+    }
+}
+
+/// A vulnerability reported by the host kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vulnerability {
+    /// Name of the issue, e.g. `spectre_v2`, `mds` or `retbleed`
+    pub name: String,
+    pub state: VulnerabilityState,
+    /// Status as reported, naming the mitigation in place
+    pub status: String,
+}
+
+/// Mitigation state of the host.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HostMitigationReport {
+    /// Vulnerabilities sorted by name
+    pub vulnerabilities: Vec<Vulnerability>,
+    /// KVM mitigation parameters of the loaded modules, as `(parameter, value)`
+    pub kvm_parameters: Vec<(String, String)>,
+}
+
+impl HostMitigationReport {
+    /// Returns the vulnerability named `name`, if the host reports it.
+    pub fn get_vulnerability(&self, name: &str) -> Option<&Vulnerability> {
+        self.vulnerabilities.iter().find(|vulnerability| vulnerability.name == name)
+    }
+
+    /// Returns the vulnerabilities nothing mitigates on the host.
+    pub fn get_unmitigated(&self) -> Vec<&Vulnerability> {
+        self.vulnerabilities.iter().filter(|vulnerability| vulnerability.state == VulnerabilityState::Vulnerable).collect()
+    }
+
+    /// Returns the value of the KVM parameter `name`, if its module is loaded.
+    pub fn get_kvm_parameter(&self, name: &str) -> Option<&str> {
+        self.kvm_parameters.iter().find(|(parameter, _)| parameter == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the vulnerabilities and KVM mitigation parameters of the host.
+///
+/// # Returns
+/// * `Ok(HostMitigationReport)` on success
+/// * `Err(VmError)` if the host kernel doesn't report vulnerabilities
+pub fn read_host_mitigations() -> Result<HostMitigationReport, VmError> {
+    Ok(HostMitigationReport {
+        vulnerabilities: read_vulnerabilities(Path::new(VULNERABILITIES_DIR))?,
+        kvm_parameters: read_kvm_parameters(Path::new(MODULE_PARAMETERS_DIR))?,
+    })
+}
+
+/// Reads the vulnerabilities reported in `dir`, one file per vulnerability.
+///
+/// # Returns
+/// * `Ok(vulnerabilities)` sorted by name
+/// * `Err(VmError)` if the directory or one of its files can't be read
+pub fn read_vulnerabilities(dir: &Path) -> Result<Vec<Vulnerability>, VmError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(VmError::io(format!("failed to list CPU vulnerabilities in {}: {}", dir.display(), e), e))
+    };
+    let mut vulnerabilities = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(VmError::io(format!("failed to list CPU vulnerabilities in {}: {}", dir.display(), e), e))
+        };
+        let status = match fs::read_to_string(entry.path()) {
+            Ok(status) => status.trim().to_string(),
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", entry.path().display(), e), e))
+        };
+        vulnerabilities.push(Vulnerability {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            state: VulnerabilityState::parse(&status),
+            status,
+        });
+    }
+    vulnerabilities.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(vulnerabilities)
+}
+
+/// Reads the KVM mitigation parameters under the module directory `modules_dir`,
+/// skipping those of modules that aren't loaded.
+fn read_kvm_parameters(modules_dir: &Path) -> Result<Vec<(String, String)>, VmError> {
+    let mut parameters = Vec::new();
+    for (module, parameter) in KVM_MITIGATION_PARAMETERS {
+        let path = get_parameter_path(modules_dir, module, parameter);
+        match fs::read_to_string(&path) {
+            Ok(value) => parameters.push((parameter.to_string(), value.trim().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(VmError::io(format!("failed to read {}: {}", path.display(), e), e))
+        }
+    }
+    Ok(parameters)
+}
+
+fn get_parameter_path(modules_dir: &Path, module: &str, parameter: &str) -> PathBuf {
+    modules_dir.join(module).join("parameters").join(parameter)
+}
+
+/// When KVM flushes the L1 data cache on VM entry, against L1TF on Intel hosts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L1dFlush {
+    /// On every VM entry
+    Always,
+    /// On VM entries after the host ran code that may have loaded secrets; the default
+    Conditional,
+    /// Never, leaving guests exposed to one another on hosts with L1TF
+    Never,
+}
+
+impl L1dFlush {
+    /// Returns the value of the `vmentry_l1d_flush` parameter.
+    pub fn get_parameter_value(&self) -> &'static str {
+        match self {
+            L1dFlush::Always => "always",
+            L1dFlush::Conditional => "cond",
+            L1dFlush::Never => "never",
+        }
+    }
+}
+
+/// Sets when KVM flushes the L1 data cache on VM entry. The setting applies to every
+/// VM of the host, takes root privileges and lasts until the `kvm_intel` module is
+/// reloaded.
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if `kvm_intel` isn't loaded, the CPU doesn't need the flush or the
+///   parameter can't be written
+pub fn set_kvm_l1d_flush(flush: L1dFlush) -> Result<(), VmError> {
+    let path = get_parameter_path(Path::new(MODULE_PARAMETERS_DIR), "kvm_intel", "vmentry_l1d_flush");
+    match fs::write(&path, flush.get_parameter_value()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::io(format!("failed to set {}: {}", path.display(), e), e))
+    }
+}
+
+/// Mitigation requirements of a VM.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MitigationPolicy {
+    /// Vulnerabilities the host must not be left vulnerable to
+    required: Vec<String>,
+    /// Whether the speculation control CPUID bits are hidden from the guest
+    hide_speculation_controls: bool,
+}
+
+impl MitigationPolicy {
+    /// Creates a policy requiring nothing and hiding nothing.
+    pub fn new() -> Self {
+        MitigationPolicy::default()
+    }
+
+    /// Refuses to start the VM unless the host reports the vulnerability `name`, e.g.
+    /// `mds` or `retbleed`, as not affecting it or mitigated.
+    pub fn require_mitigated(mut self, name: &str) -> Self {
+        if !self.required.iter().any(|required| required == name) {
+            self.required.push(name.to_string());
+        }
+        self
+    }
+
+    /// Hides the speculation control features (SPEC_CTRL, STIBP, SSBD, MD_CLEAR, ...)
+    /// from the guest. The guest then doesn't mitigate the issues itself, trading
+    /// protection of its processes from one another for speed.
+    pub fn hide_speculation_controls(mut self, hide: bool) -> Self {
+        self.hide_speculation_controls = hide;
+        self
+    }
+
+    /// Returns the vulnerabilities the host must be protected from.
+    pub fn get_required(&self) -> &[String] {
+        &self.required
+    }
+
+    /// Returns whether the speculation control features are hidden from the guest.
+    pub fn get_hide_speculation_controls(&self) -> bool {
+        self.hide_speculation_controls
+    }
+
+    /// Fails if the host described by `report` doesn't meet the policy.
+    ///
+    /// # Returns
+    /// * `Ok(())` if every required vulnerability is reported as not affecting the host
+    ///   or mitigated
+    /// * `Err(VmError)` naming the first one that isn't
+    pub fn check(&self, report: &HostMitigationReport) -> Result<(), VmError> {
+        for name in &self.required {
+            match report.get_vulnerability(name) {
+                Some(vulnerability) if matches!(vulnerability.state, VulnerabilityState::NotAffected | VulnerabilityState::Mitigated) => {},
+                Some(vulnerability) => {
+                    return Err(VmError::config(format!("host is not protected from {}: {}", name, vulnerability.status)));
+                },
+                None => return Err(VmError::config(format!("host kernel doesn't report the CPU vulnerability {}", name))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears the speculation control bits of `entry` if the policy hides them.
+    pub fn apply(&self, entry: &mut CpuidEntry) {
+        if !self.hide_speculation_controls {
+            return;
+        }
+        for (leaf, subleaf, register, bit) in SPECULATION_CONTROL_BITS {
+            if leaf != entry.function || subleaf != entry.index {
+                continue;
+            }
+            let value = match register {
+                CpuidRegister::Eax => &mut entry.eax,
+                CpuidRegister::Ebx => &mut entry.ebx,
+                CpuidRegister::Ecx => &mut entry.ecx,
+                CpuidRegister::Edx => &mut entry.edx,
+            };
+            *value &= !(1u32 << bit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_host_report() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let vulnerabilities = dir.path().join("vulnerabilities");
+        fs::create_dir(&vulnerabilities).unwrap();
+        fs::write(vulnerabilities.join("retbleed"), "Mitigation: untrained return thunk; SMT enabled with STIBP protection\n").unwrap();
+        fs::write(vulnerabilities.join("mds"), "Vulnerable: Clear CPU buffers attempted, no microcode; SMT vulnerable\n").unwrap();
+        fs::write(vulnerabilities.join("itlb_multihit"), "KVM: Mitigation: Split huge pages\n").unwrap();
+        fs::write(vulnerabilities.join("meltdown"), "Not affected\n").unwrap();
+        let modules = dir.path().join("module");
+        fs::create_dir_all(modules.join("kvm_intel/parameters")).unwrap();
+        fs::write(modules.join("kvm_intel/parameters/vmentry_l1d_flush"), "cond\n").unwrap();
+
+        let report = HostMitigationReport {
+            vulnerabilities: read_vulnerabilities(&vulnerabilities).unwrap(),
+            kvm_parameters: read_kvm_parameters(&modules).unwrap(),
+        };
+        let names: Vec<&str> = report.vulnerabilities.iter().map(|vulnerability| vulnerability.name.as_str()).collect();
+        assert_eq!(names, ["itlb_multihit", "mds", "meltdown", "retbleed"]);
+        assert_eq!(report.get_vulnerability("itlb_multihit").unwrap().state, VulnerabilityState::Mitigated);
+        assert_eq!(report.get_vulnerability("meltdown").unwrap().state, VulnerabilityState::NotAffected);
+        assert_eq!(report.get_unmitigated().len(), 1);
+        assert_eq!(report.get_unmitigated()[0].name, "mds");
+        assert_eq!(report.get_kvm_parameter("vmentry_l1d_flush"), Some("cond"));
+        assert_eq!(report.get_kvm_parameter("nx_huge_pages"), None);
+
+        assert!(MitigationPolicy::new().require_mitigated("retbleed").require_mitigated("meltdown").check(&report).is_ok());
+        let error = MitigationPolicy::new().require_mitigated("mds").check(&report).unwrap_err();
+        assert!(error.to_string().contains("not protected from mds"));
+        assert!(MitigationPolicy::new().require_mitigated("gds").check(&report).is_err());
+    }
+
+    #[test]
+    fn test_hide_speculation_controls() {
+        let mut entry = CpuidEntry { function: 0x7, index: 0, eax: u32::MAX, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX };
+        MitigationPolicy::new().apply(&mut entry);
+        assert_eq!(entry.edx, u32::MAX);
+
+        let policy = MitigationPolicy::new().hide_speculation_controls(true);
+        policy.apply(&mut entry);
+        assert_eq!(entry.edx, !((1 << 10) | (1 << 26) | (1 << 27) | (1 << 28) | (1 << 29) | (1 << 31)));
+        assert_eq!(entry.ebx, u32::MAX);
+
+        let mut amd = CpuidEntry { function: 0x8000_0008, index: 0, ebx: u32::MAX, ..CpuidEntry::default() };
+        policy.apply(&mut amd);
+        assert_eq!(amd.ebx, !((1 << 12) | (1 << 14) | (1 << 15) | (1 << 24) | (1 << 25)));
+    }
+}
@@ -0,0 +1,318 @@
+//! Deterministic hypervisor backend for tests without hardware virtualization.
+//!
+//! `MockHypervisor` implements `HypervisorBackend` with vCPUs that don't run any guest
+//! code: each one takes the exits of a script, in order, and the shared vCPU loop
+//! dispatches them to the device buses exactly as it does for KVM, WHP or
+//! Hypervisor.framework. Guest RAM is an ordinary `GuestMemoryMmap`, so virtio devices
+//! work on it, and `MockExit::Execute` stands in for guest code writing to it, e.g. to
+//! fill a virtqueue before notifying the device.
+//!
+//! Nothing depends on timing: a vCPU only ever waits in `MockExit::Spin`, which lasts
+//! until the `VmHandle` kicks it, so tests of the run control, the buses and the
+//! devices behave the same on every CI machine.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use crate::device_emulation::mmio::MmioBus;
+use crate::device_emulation::pio::PortIoBus;
+use crate::error::VmError;
+use crate::vm_setup::backend::{run_vcpus, HypervisorBackend, VcpuDevices, VcpuExit};
+use crate::vm_setup::exit_summary::VmExitSummary;
+use crate::vm_setup::exit_trace::ExitRecorder;
+use crate::vm_setup::guest_profiler::GuestMode;
+use crate::vm_setup::memory_monitor::MemoryStatus;
+use crate::vm_setup::setup_utils::VmSetup;
+use crate::vm_setup::vm_handle::{RunControlHooks, VmControl, VmHandle};
+
+/// Host memory the mock reports, plenty for any test VM.
+const MOCK_HOST_MEMORY: u64 = 64 << 30;
+
+/// Guest code run by `MockExit::Execute`.
+pub type GuestCode = Arc<dyn Fn(&GuestMemoryMmap) + Send + Sync>;
+
+/// Exit a scripted vCPU takes.
+#[derive(Clone)]
+pub enum MockExit {
+    /// Read `len` bytes from a port
+    PioIn { port: u16, len: usize },
+    /// Write `data` to a port
+    PioOut { port: u16, data: Vec<u8> },
+    /// Read `len` bytes from a device register
+    MmioRead { address: u64, len: usize },
+    /// Write `data` to a device register
+    MmioWrite { address: u64, data: Vec<u8> },
+    /// Run guest code on guest RAM without leaving the guest
+    Execute(GuestCode),
+    /// Keep running until kicked by the run control, then take the same exit again
+    Spin,
+    /// Halt; also taken once the script ran out
+    Hlt,
+    /// Power the vCPU off
+    PoweredOff,
+    /// Shut the system down
+    Shutdown,
+    /// Reset the system
+    Reset,
+    /// Fault with the given reason
+    Exception(String),
+}
+
+impl MockExit {
+    /// Wraps `code` into an `Execute` exit.
+    pub fn execute(code: impl Fn(&GuestMemoryMmap) + Send + Sync + 'static) -> MockExit {
+        MockExit::Execute(Arc::new(code))
+    }
+}
+
+/// Device a scripted read went to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockAccess {
+    /// I/O port
+    Port(u16),
+    /// Guest physical address of a device register
+    Mmio(u64),
+}
+
+/// Read completed by a scripted vCPU, with the data the device returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockRead {
+    /// Index of the vCPU
+    pub vcpu: u32,
+    /// Where it read from
+    pub access: MockAccess,
+    /// Data it got
+    pub data: Vec<u8>,
+}
+
+/// Reads completed by the vCPUs of a `MockHypervisor`, readable after it was run.
+#[derive(Clone, Default)]
+pub struct MockReadLog {
+    reads: Arc<Mutex<Vec<MockRead>>>,
+}
+
+impl MockReadLog {
+    /// Returns the reads completed so far, in the order the vCPUs completed them.
+    pub fn to_vec(&self) -> Vec<MockRead> {
+        self.reads.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Returns the reads vCPU `vcpu` completed so far, in order.
+    pub fn get_vcpu_reads(&self, vcpu: u32) -> Vec<MockRead> {
+        self.to_vec().into_iter().filter(|read| read.vcpu == vcpu).collect()
+    }
+
+    fn push(&self, read: MockRead) {
+        self.reads.lock().unwrap_or_else(|e| e.into_inner()).push(read);
+    }
+}
+
+/// Scripted vCPU of a `MockHypervisor`.
+pub(crate) struct MockVcpu {
+    /// Exits still to take
+    script: VecDeque<MockExit>,
+    /// Exits taken, reported as the program counter
+    exits_taken: u64,
+    /// Data of the last device access
+    data: Vec<u8>,
+    /// Read to log once the loop filled `data`
+    pending_read: Option<MockAccess>,
+    /// Kicks seen by the last `Spin`
+    seen_kicks: u64,
+}
+
+/// Hypervisor running scripted vCPUs, see the module documentation.
+///
+/// Create it from a `VmSetup`, which sizes guest RAM and the number of vCPUs, register
+/// devices on its buses, give vCPUs their scripts and `run` or `spawn` it. vCPUs
+/// without a script halt right away.
+pub struct MockHypervisor {
+    vcpu_count: u32,
+    guest_memory: Option<GuestMemoryMmap>,
+    scripts: Mutex<BTreeMap<u32, Vec<MockExit>>>,
+    reads: MockReadLog,
+    kicks: Mutex<u64>,
+    kicked: Condvar,
+    mmio_bus: MmioBus,
+    pio_bus: PortIoBus,
+    recorder: Option<Arc<ExitRecorder>>,
+}
+
+impl MockHypervisor {
+    /// Creates the VM configured by `setup`, with its RAM mapped at guest physical
+    /// address 0 and no device.
+    ///
+    /// # Returns
+    /// * `Ok(MockHypervisor)` with the VM
+    /// * `Err(VmError)` if guest RAM can't be allocated or the exit trace can't be created
+    pub fn new(setup: &VmSetup) -> Result<MockHypervisor, VmError> {
+        let mut hypervisor = MockHypervisor::create_vm(setup)?;
+        hypervisor.map_memory(0, setup.get_memory_size())?;
+        Ok(hypervisor)
+    }
+
+    /// Returns guest RAM, for devices and for checking what the vCPUs left in it.
+    pub fn get_guest_memory(&self) -> &GuestMemoryMmap {
+        // Mapped by `new`
+        self.guest_memory.as_ref().expect("guest RAM is mapped on creation")
+    }
+
+    /// Returns the MMIO bus to register devices on.
+    pub fn get_mmio_bus_mut(&mut self) -> &mut MmioBus {
+        &mut self.mmio_bus
+    }
+
+    /// Returns the port I/O bus to register devices on.
+    pub fn get_pio_bus_mut(&mut self) -> &mut PortIoBus {
+        &mut self.pio_bus
+    }
+
+    /// Sets the exits vCPU `vcpu` takes, replacing any earlier script.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if the VM has no such vCPU
+    pub fn set_script(&mut self, vcpu: u32, script: Vec<MockExit>) -> Result<(), VmError> {
+        if vcpu >= self.vcpu_count {
+            return Err(VmError::config(format!("VCPU {} doesn't exist, the VM has {}", vcpu, self.vcpu_count)));
+        }
+        self.scripts.get_mut().unwrap_or_else(|e| e.into_inner()).insert(vcpu, script);
+        Ok(())
+    }
+
+    /// Returns the log of the reads the vCPUs complete, which stays readable once the
+    /// hypervisor was consumed by `run` or `spawn`.
+    pub fn get_read_log(&self) -> MockReadLog {
+        self.reads.clone()
+    }
+
+    /// Runs the scripted vCPUs until they all exited.
+    ///
+    /// # Returns
+    /// * `Ok(VmExitSummary)` with how each vCPU exited
+    /// * `Err(VmError)` with the first vCPU error, e.g. an exception or unclaimed MMIO access
+    pub async fn run(self) -> Result<VmExitSummary, VmError> {
+        self.run_with_control(Arc::new(VmControl::new())).await
+    }
+
+    /// Starts the scripted vCPUs in the background. Must be called from within a Tokio runtime.
+    ///
+    /// # Returns
+    /// * A `VmHandle` to pause, resume, stop and wait for the VM
+    pub fn spawn(self) -> VmHandle {
+        VmHandle::spawn(move |control| self.run_with_control(control))
+    }
+
+    async fn run_with_control(mut self, control: Arc<VmControl>) -> Result<VmExitSummary, VmError> {
+        let devices = Arc::new(VcpuDevices {
+            mmio_bus: std::mem::take(&mut self.mmio_bus),
+            pio_bus: std::mem::take(&mut self.pio_bus),
+            recorder: self.recorder.take(),
+            latencies: Arc::clone(control.get_exit_latencies()),
+        });
+        let vcpu_count = self.vcpu_count;
+        run_vcpus(Arc::new(self), control, vcpu_count, devices, Vec::new()).await
+    }
+}
+
+impl RunControlHooks for MockHypervisor {
+    fn kick(&self) {
+        *self.kicks.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.kicked.notify_all();
+    }
+}
+
+impl HypervisorBackend for MockHypervisor {
+    type Vcpu = MockVcpu;
+    type Memory = GuestMemoryMmap;
+
+    fn create_vm(setup: &VmSetup) -> Result<Self, VmError> {
+        let recorder = match setup.get_exit_trace() {
+            Some(path) => Some(Arc::new(ExitRecorder::create(path)?)),
+            None => None
+        };
+        Ok(MockHypervisor {
+            vcpu_count: setup.get_cpu_cores_count(),
+            guest_memory: None,
+            scripts: Mutex::new(BTreeMap::new()),
+            reads: MockReadLog::default(),
+            kicks: Mutex::new(0),
+            kicked: Condvar::new(),
+            mmio_bus: MmioBus::new(),
+            pio_bus: PortIoBus::new(),
+            recorder,
+        })
+    }
+
+    fn map_memory(&mut self, gpa: u64, size: usize) -> Result<GuestMemoryMmap, VmError> {
+        let guest_memory: GuestMemoryMmap = match GuestMemoryMmap::from_ranges(&[(GuestAddress(gpa), size)]) {
+            Ok(mem) => mem,
+            Err(e) => return Err(VmError::memory_source(format!("Failed to create guest memory: {}", e), e)),
+        };
+        self.guest_memory = Some(guest_memory.clone());
+        Ok(guest_memory)
+    }
+
+    fn create_vcpu(&self, index: u32) -> Result<MockVcpu, VmError> {
+        let script = self.scripts.lock().unwrap_or_else(|e| e.into_inner()).remove(&index).unwrap_or_default();
+        Ok(MockVcpu { script: script.into(), exits_taken: 0, data: Vec::new(), pending_read: None, seen_kicks: 0 })
+    }
+
+    fn run_vcpu<'a>(&self, vcpu: &'a mut MockVcpu, index: u32, _devices: &VcpuDevices) -> Result<VcpuExit<'a>, VmError> {
+        // Complete the last read, like a hypervisor does when it resumes the guest
+        if let Some(access) = vcpu.pending_read.take() {
+            self.reads.push(MockRead { vcpu: index, access, data: vcpu.data.clone() });
+        }
+        loop {
+            let Some(exit) = vcpu.script.pop_front() else { return Ok(VcpuExit::Hlt) };
+            vcpu.exits_taken += 1;
+            match exit {
+                MockExit::PioIn { port, len } => {
+                    vcpu.data = vec![0; len];
+                    vcpu.pending_read = Some(MockAccess::Port(port));
+                    return Ok(VcpuExit::PioIn(port, &mut vcpu.data));
+                },
+                MockExit::PioOut { port, data } => {
+                    vcpu.data = data;
+                    return Ok(VcpuExit::PioOut(port, &vcpu.data));
+                },
+                MockExit::MmioRead { address, len } => {
+                    vcpu.data = vec![0; len];
+                    vcpu.pending_read = Some(MockAccess::Mmio(address));
+                    return Ok(VcpuExit::MmioRead(address, &mut vcpu.data));
+                },
+                MockExit::MmioWrite { address, data } => {
+                    vcpu.data = data;
+                    return Ok(VcpuExit::MmioWrite(address, &vcpu.data));
+                },
+                // Guest code doesn't leave the guest; carry on with the script
+                MockExit::Execute(code) => code(self.get_guest_memory()),
+                MockExit::Spin => {
+                    // Stay in the guest until the run control kicks the vCPU out, and
+                    // keep spinning when it comes back
+                    vcpu.script.push_front(MockExit::Spin);
+                    let mut kicks = self.kicks.lock().unwrap_or_else(|e| e.into_inner());
+                    while *kicks == vcpu.seen_kicks {
+                        kicks = self.kicked.wait(kicks).unwrap_or_else(|e| e.into_inner());
+                    }
+                    vcpu.seen_kicks = *kicks;
+                    return Ok(VcpuExit::Canceled);
+                },
+                MockExit::Hlt => return Ok(VcpuExit::Hlt),
+                MockExit::PoweredOff => return Ok(VcpuExit::PoweredOff),
+                MockExit::Shutdown => return Ok(VcpuExit::Shutdown),
+                MockExit::Reset => return Ok(VcpuExit::Reset),
+                MockExit::Exception(reason) => return Ok(VcpuExit::Exception { reason }),
+            }
+        }
+    }
+
+    /// Reports kernel mode, with the number of exits taken as the program counter.
+    fn get_guest_position(&self, vcpu: &MockVcpu, _index: u32) -> Result<Option<(GuestMode, u64)>, VmError> {
+        Ok(Some((GuestMode::Kernel, vcpu.exits_taken)))
+    }
+
+    fn get_host_memory() -> Result<MemoryStatus, VmError> {
+        Ok(MemoryStatus { total: MOCK_HOST_MEMORY, available: MOCK_HOST_MEMORY })
+    }
+}
@@ -4,8 +4,39 @@ pub mod macos_setup;
 #[cfg(target_os = "linux")]
 pub mod linux_setup;
 
+#[cfg(target_os = "linux")]
+pub mod memory_slots;
+
+#[cfg(target_os = "linux")]
+pub mod boot_order;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod checkpoint;
+
 #[cfg(target_os = "windows")]
 pub mod windows_setup;
 
 pub mod setup_utils;
-mod disk_setup;
\ No newline at end of file
+pub mod cpu_model;
+pub mod mitigations;
+pub mod security_features;
+pub mod pmu;
+pub mod vm_handle;
+pub mod introspection;
+pub mod kernel_introspection;
+pub mod guest_profiler;
+pub mod exit_summary;
+pub mod exit_trace;
+pub mod exit_latency;
+pub mod mock_hypervisor;
+pub mod boot_progress;
+pub mod console_log;
+pub mod memory_monitor;
+pub mod screen_recorder;
+pub mod image_inject;
+pub mod first_boot;
+pub mod cloudinit;
+pub mod image_builder;
+pub mod disk_setup;
+pub(crate) mod attachments;
+pub(crate) mod backend;
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! Guest access to the performance monitoring unit.
+//!
+//! KVM can virtualize the PMU counters so `perf` and other profilers work inside the
+//! guest. The counters count on the host CPU the guest shares with everything else,
+//! which makes them a side channel into other workloads, so guests only get a PMU when
+//! the VM opts in with `GuestPmu::Virtualized`. Otherwise the PMU leaves are hidden
+//! from the guest CPUID and, where KVM allows it, the PMU is disabled for the whole VM
+//! so its MSRs fault as well.
+
+use crate::error::VmError;
+use crate::vm_setup::cpu_model::{CpuidEntry, CpuidRegister};
+
+/// CPUID leaf describing the architectural PMU of Intel CPUs.
+const CPUID_ARCH_PERFMON: u32 = 0xa;
+/// CPUID leaf describing the PerfMonV2 PMU of AMD CPUs.
+const CPUID_AMD_PERFMON_V2: u32 = 0x8000_0022;
+/// Single PMU feature bits, as `(leaf, subleaf, register, bit)`: the perf capabilities
+/// MSR (PDCM), architectural LBRs and AMD's core performance counter extensions.
+const PMU_FEATURE_BITS: [(u32, u32, CpuidRegister, u32); 3] = [
+    (0x1, 0, CpuidRegister::Ecx, 15),
+    (0x7, 0, CpuidRegister::Edx, 19),
+    (0x8000_0001, 0, CpuidRegister::Ecx, 23),
+];
+
+/// Whether the guest gets a virtual PMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GuestPmu {
+    /// The guest sees no PMU
+    #[default]
+    Disabled,
+    /// KVM virtualizes the host PMU counters for the guest
+    Virtualized,
+}
+
+impl GuestPmu {
+    /// Checks that the host can give the guest the PMU the policy asks for, from the
+    /// CPUID entries KVM supports.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the policy can be met
+    /// * `Err(VmError)` if a virtualized PMU is requested but KVM offers none, e.g.
+    ///   because the kvm module was loaded with `enable_pmu=0`
+    pub fn check(&self, supported: &[CpuidEntry]) -> Result<(), VmError> {
+        if *self == GuestPmu::Disabled {
+            return Ok(());
+        }
+        let has_arch_perfmon = supported.iter().any(|e| e.function == CPUID_ARCH_PERFMON && e.eax & 0xff != 0);
+        let has_perfctr_core = supported.iter().any(|e| e.function == 0x8000_0001 && e.ecx & (1 << 23) != 0);
+        if !has_arch_perfmon && !has_perfctr_core {
+            return Err(VmError::config("a virtualized PMU was requested but KVM doesn't offer one on this host"));
+        }
+        Ok(())
+    }
+
+    /// Hides the PMU from `entry` unless the policy virtualizes it.
+    pub fn apply(&self, entry: &mut CpuidEntry) {
+        if *self == GuestPmu::Virtualized {
+            return;
+        }
+        if entry.function == CPUID_ARCH_PERFMON || entry.function == CPUID_AMD_PERFMON_V2 {
+            entry.eax = 0;
+            entry.ebx = 0;
+            entry.ecx = 0;
+            entry.edx = 0;
+            return;
+        }
+        for (leaf, subleaf, register, bit) in PMU_FEATURE_BITS {
+            if leaf != entry.function || subleaf != entry.index {
+                continue;
+            }
+            let value = match register {
+                CpuidRegister::Eax => &mut entry.eax,
+                CpuidRegister::Ebx => &mut entry.ebx,
+                CpuidRegister::Ecx => &mut entry.ecx,
+                CpuidRegister::Edx => &mut entry.edx,
+            };
+            *value &= !(1u32 << bit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_entry(function: u32, index: u32) -> CpuidEntry {
+        CpuidEntry { function, index, eax: u32::MAX, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX }
+    }
+
+    #[test]
+    fn test_disabled_pmu_is_hidden() {
+        let mut perfmon = full_entry(CPUID_ARCH_PERFMON, 0);
+        GuestPmu::Disabled.apply(&mut perfmon);
+        assert_eq!((perfmon.eax, perfmon.ebx, perfmon.ecx, perfmon.edx), (0, 0, 0, 0));
+        let mut leaf1 = full_entry(0x1, 0);
+        GuestPmu::Disabled.apply(&mut leaf1);
+        assert_eq!(leaf1.ecx, !(1 << 15));
+        assert_eq!(leaf1.edx, u32::MAX);
+
+        let mut perfmon = full_entry(CPUID_ARCH_PERFMON, 0);
+        GuestPmu::Virtualized.apply(&mut perfmon);
+        assert_eq!(perfmon, full_entry(CPUID_ARCH_PERFMON, 0));
+    }
+
+    #[test]
+    fn test_virtualized_pmu_needs_host_support() {
+        let without_pmu = [CpuidEntry { function: CPUID_ARCH_PERFMON, index: 0, eax: 0, ebx: 0, ecx: 0, edx: 0 }];
+        assert!(GuestPmu::Virtualized.check(&without_pmu).is_err());
+        assert!(GuestPmu::Disabled.check(&without_pmu).is_ok());
+        assert!(GuestPmu::Virtualized.check(&[full_entry(CPUID_ARCH_PERFMON, 0)]).is_ok());
+        assert!(GuestPmu::Virtualized.check(&[full_entry(0x8000_0001, 0)]).is_ok());
+    }
+}
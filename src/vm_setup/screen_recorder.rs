@@ -0,0 +1,91 @@
+//! Periodic screenshots of a guest's display.
+//!
+//! `VmHandle::start_screen_recording` returns a `ScreenRecorder` that writes a PNG
+//! screenshot to a directory at a fixed interval, named `frame-00000.png`,
+//! `frame-00001.png` and so on. A frame identical to the one before it isn't written,
+//! so an idle screen costs nothing. Test frameworks keep the frames as evidence of what
+//! the guest showed before a failure.
+//!
+//! Recording ends when the recorder is stopped or dropped, or when the VM stops.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::error::VmError;
+use crate::vm_setup::vm_handle::{VmControl, VmState};
+
+/// Shortest interval between two screenshots.
+pub const MIN_RECORDING_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Screen recording of a running VM.
+pub struct ScreenRecorder {
+    /// Ends the recording task when sent to or dropped
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Result<Vec<PathBuf>, VmError>>,
+}
+
+impl ScreenRecorder {
+    /// Creates `directory` and starts capturing the display of the VM every `interval`.
+    pub(crate) fn start(control: Arc<VmControl>, directory: PathBuf, interval: Duration) -> Result<ScreenRecorder, VmError> {
+        if let Err(e) = std::fs::create_dir_all(&directory) {
+            return Err(VmError::io(format!("Failed to create recording directory {}: {}", directory.display(), e), e));
+        }
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut frames = Vec::new();
+            let mut last = None;
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                let stopping = tokio::select! {
+                    _ = ticks.tick() => false,
+                    _ = &mut stopped => true,
+                };
+                if control.get_state() == VmState::Stopped {
+                    break;
+                }
+                // The hooks are released when the VM stops, so a failed screenshot of a
+                // VM that just stopped ends the recording instead of failing it
+                let image = match control.screenshot() {
+                    Ok(image) => image,
+                    Err(_) if control.get_state() == VmState::Stopped => break,
+                    Err(e) => return Err(e)
+                };
+                if last.as_ref() != Some(&image) {
+                    frames.push(write_frame(&directory, frames.len(), &image)?);
+                    last = Some(image);
+                }
+                if stopping {
+                    break;
+                }
+            }
+            Ok(frames)
+        });
+        Ok(ScreenRecorder { stop, task })
+    }
+
+    /// Takes a last screenshot and stops recording.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<PathBuf>)` with the frames written, oldest first
+    /// * `Err(VmError)` if a screenshot or frame couldn't be written
+    pub async fn stop(self) -> Result<Vec<PathBuf>, VmError> {
+        // The task may have ended already because the VM stopped
+        let _ = self.stop.send(());
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(VmError::device_source(format!("Screen recording task join error: {}", e), e))
+        }
+    }
+}
+
+/// Writes the `index`th frame of a recording to `directory`.
+fn write_frame(directory: &Path, index: usize, image: &[u8]) -> Result<PathBuf, VmError> {
+    let path = directory.join(format!("frame-{:05}.png", index));
+    match std::fs::write(&path, image) {
+        Ok(()) => Ok(path),
+        Err(e) => Err(VmError::io(format!("Failed to write frame {}: {}", path.display(), e), e))
+    }
+}
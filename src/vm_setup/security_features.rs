@@ -0,0 +1,205 @@
+//! Guest control of the CPU security features SMEP, SMAP and CET.
+//!
+//! The guest kernel turns these features on in CR4 when CPUID reports them, and the
+//! hypervisor refuses CR4 bits whose feature is hidden from the guest CPUID. Hiding a
+//! feature through a `SecurityFeaturePolicy` therefore keeps the guest from using it,
+//! so the same kernel can be tested with and without it under the same VMM. A feature
+//! the host lacks can't be shown: the policy only hides.
+
+use crate::error::VmError;
+use crate::vm_setup::cpu_model::{CpuidEntry, CpuidRegister};
+
+/// CR4 bit enabling SMEP.
+const X86_CR4_SMEP: u64 = 1 << 20;
+/// CR4 bit enabling SMAP.
+const X86_CR4_SMAP: u64 = 1 << 21;
+/// CR4 bit enabling CET, both shadow stacks and indirect branch tracking.
+const X86_CR4_CET: u64 = 1 << 23;
+/// XSAVE supervisor state components of CET, as `(leaf, subleaf, register, bit)`: the
+/// user mode and the supervisor mode state.
+const CET_XSAVE_BITS: [(u32, u32, CpuidRegister, u32); 2] = [
+    (0xd, 1, CpuidRegister::Ecx, 11),
+    (0xd, 1, CpuidRegister::Ecx, 12),
+];
+
+/// CPU security feature the guest can be denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecurityFeature {
+    /// Supervisor Mode Execution Prevention: the kernel can't run user pages
+    Smep,
+    /// Supervisor Mode Access Prevention: the kernel can't touch user pages unless it asks to
+    Smap,
+    /// CET shadow stacks guarding return addresses
+    CetShadowStack,
+    /// CET indirect branch tracking
+    CetIbt,
+}
+
+/// Every security feature, in CPUID order.
+const ALL_SECURITY_FEATURES: [SecurityFeature; 4] = [
+    SecurityFeature::Smep, SecurityFeature::Smap, SecurityFeature::CetShadowStack, SecurityFeature::CetIbt,
+];
+
+impl SecurityFeature {
+    /// Returns the lower-case flag name as used by `/proc/cpuinfo`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SecurityFeature::Smep => "smep",
+            SecurityFeature::Smap => "smap",
+            SecurityFeature::CetShadowStack => "shstk",
+            SecurityFeature::CetIbt => "ibt",
+        }
+    }
+
+    /// Looks a feature up by its flag name.
+    pub fn from_name(name: &str) -> Option<SecurityFeature> {
+        ALL_SECURITY_FEATURES.iter().copied().find(|f| f.name() == name)
+    }
+
+    /// Returns where the feature bit lives: `(leaf, subleaf, register, bit)`.
+    pub fn location(&self) -> (u32, u32, CpuidRegister, u32) {
+        match self {
+            SecurityFeature::Smep => (0x7, 0, CpuidRegister::Ebx, 7),
+            SecurityFeature::Smap => (0x7, 0, CpuidRegister::Ebx, 20),
+            SecurityFeature::CetShadowStack => (0x7, 0, CpuidRegister::Ecx, 7),
+            SecurityFeature::CetIbt => (0x7, 0, CpuidRegister::Edx, 20),
+        }
+    }
+
+    /// Returns the CR4 bit the guest sets to turn the feature on.
+    pub fn get_cr4_bit(&self) -> u64 {
+        match self {
+            SecurityFeature::Smep => X86_CR4_SMEP,
+            SecurityFeature::Smap => X86_CR4_SMAP,
+            SecurityFeature::CetShadowStack | SecurityFeature::CetIbt => X86_CR4_CET,
+        }
+    }
+}
+
+/// Security features hidden from a VM's guest.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SecurityFeaturePolicy {
+    hidden: Vec<SecurityFeature>,
+}
+
+impl SecurityFeaturePolicy {
+    /// Creates a policy showing the guest every security feature the host has.
+    pub fn new() -> Self {
+        SecurityFeaturePolicy::default()
+    }
+
+    /// Parses comma separated `-flag`/`+flag` toggles, e.g. `-smep,-smap`, applied in
+    /// order to a policy showing every feature.
+    ///
+    /// # Returns
+    /// * `Ok(SecurityFeaturePolicy)` on success
+    /// * `Err(VmError)` if a toggle is malformed or names an unknown feature
+    pub fn from_toggles(toggles: &str) -> Result<SecurityFeaturePolicy, VmError> {
+        let mut policy = SecurityFeaturePolicy::new();
+        for toggle in toggles.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (hide, flag) = match toggle.split_at_checked(1) {
+                Some(("-", flag)) => (true, flag),
+                Some(("+", flag)) => (false, flag),
+                _ => return Err(VmError::config(format!("invalid security feature toggle {}", toggle)))
+            };
+            let feature = match SecurityFeature::from_name(flag) {
+                Some(feature) => feature,
+                None => return Err(VmError::config(format!("unknown security feature {}", flag)))
+            };
+            policy = policy.hide(feature, hide);
+        }
+        Ok(policy)
+    }
+
+    /// Hides `feature` from the guest, or shows it again if `hide` is `false`.
+    pub fn hide(mut self, feature: SecurityFeature, hide: bool) -> Self {
+        self.hidden.retain(|f| *f != feature);
+        if hide {
+            self.hidden.push(feature);
+        }
+        self
+    }
+
+    /// Returns the features hidden from the guest.
+    pub fn get_hidden(&self) -> &[SecurityFeature] {
+        &self.hidden
+    }
+
+    /// Returns `true` if `feature` is hidden from the guest.
+    pub fn is_hidden(&self, feature: SecurityFeature) -> bool {
+        self.hidden.contains(&feature)
+    }
+
+    /// Returns the CR4 bits the guest can't set under the policy. CET stays available
+    /// as long as either of its features is shown.
+    pub fn get_hidden_cr4_bits(&self) -> u64 {
+        ALL_SECURITY_FEATURES.iter()
+            .map(|feature| feature.get_cr4_bit())
+            .filter(|bit| {
+                ALL_SECURITY_FEATURES.iter().filter(|feature| feature.get_cr4_bit() == *bit).all(|feature| self.is_hidden(*feature))
+            })
+            .fold(0, |bits, bit| bits | bit)
+    }
+
+    /// Clears the bits of the hidden features from `entry`. Hiding both CET features
+    /// also hides the CET state from XSAVE.
+    pub fn apply(&self, entry: &mut CpuidEntry) {
+        let mut bits: Vec<(u32, u32, CpuidRegister, u32)> = self.hidden.iter().map(|feature| feature.location()).collect();
+        if self.get_hidden_cr4_bits() & X86_CR4_CET != 0 {
+            bits.extend_from_slice(&CET_XSAVE_BITS);
+        }
+        for (leaf, subleaf, register, bit) in bits {
+            if leaf != entry.function || subleaf != entry.index {
+                continue;
+            }
+            let value = match register {
+                CpuidRegister::Eax => &mut entry.eax,
+                CpuidRegister::Ebx => &mut entry.ebx,
+                CpuidRegister::Ecx => &mut entry.ecx,
+                CpuidRegister::Edx => &mut entry.edx,
+            };
+            *value &= !(1u32 << bit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_entry(function: u32, index: u32) -> CpuidEntry {
+        CpuidEntry { function, index, eax: u32::MAX, ebx: u32::MAX, ecx: u32::MAX, edx: u32::MAX }
+    }
+
+    #[test]
+    fn test_hidden_features_are_cleared() {
+        let mut leaf7 = full_entry(0x7, 0);
+        SecurityFeaturePolicy::new().apply(&mut leaf7);
+        assert_eq!(leaf7, full_entry(0x7, 0));
+
+        let policy = SecurityFeaturePolicy::new().hide(SecurityFeature::Smep, true).hide(SecurityFeature::CetIbt, true);
+        policy.apply(&mut leaf7);
+        assert_eq!(leaf7.ebx, !(1 << 7));
+        assert_eq!(leaf7.edx, !(1 << 20));
+        assert_eq!(leaf7.ecx, u32::MAX);
+        assert_eq!(policy.get_hidden_cr4_bits(), X86_CR4_SMEP);
+
+        // CET state stays in XSAVE while shadow stacks are shown
+        let mut xsave = full_entry(0xd, 1);
+        policy.apply(&mut xsave);
+        assert_eq!(xsave, full_entry(0xd, 1));
+        let policy = policy.hide(SecurityFeature::CetShadowStack, true);
+        policy.apply(&mut xsave);
+        assert_eq!(xsave.ecx, !((1 << 11) | (1 << 12)));
+        assert_eq!(policy.get_hidden_cr4_bits(), X86_CR4_SMEP | X86_CR4_CET);
+    }
+
+    #[test]
+    fn test_from_toggles() {
+        let policy = SecurityFeaturePolicy::from_toggles("-smep, -smap,+smep").unwrap();
+        assert_eq!(policy.get_hidden(), [SecurityFeature::Smap]);
+        assert_eq!(SecurityFeaturePolicy::from_toggles("").unwrap(), SecurityFeaturePolicy::new());
+        assert!(SecurityFeaturePolicy::from_toggles("smep").is_err());
+        assert!(SecurityFeaturePolicy::from_toggles("-nx").is_err());
+    }
+}
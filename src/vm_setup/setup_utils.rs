@@ -1,12 +1,377 @@
+use crate::vm_setup::cpu_model::CpuModel;
+use crate::vm_setup::mitigations::MitigationPolicy;
+use crate::vm_setup::security_features::SecurityFeaturePolicy;
+use crate::vm_setup::pmu::GuestPmu;
+use crate::device_emulation::virtqueue_config::VirtqueueConfig;
+use crate::device_emulation::block_device::disk_backend::CacheMode;
+use crate::device_emulation::gic::GicVersion;
+use crate::kernel_setup::setup_utils::KernelComponents;
+use crate::error::VmError;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Kernel command line used when none is given.
+pub const DEFAULT_KERNEL_CMDLINE: &str = "console=ttyS0 reboot=k panic=1";
+/// Most disks a VM can have; each takes a virtio slot and an interrupt line.
+pub const MAX_DISKS: usize = 8;
+/// Most directories a VM can share with its host; each takes a virtio slot and an
+/// interrupt line.
+pub const MAX_SHARED_DIRS: usize = 8;
+/// Longest mount tag of a shared directory.
+pub const MAX_MOUNT_TAG_LEN: usize = 32;
+/// Most shared-memory regions a VM can have; each takes a memory slot and a register window.
+pub const MAX_SHARED_MEMORY_REGIONS: usize = 4;
+/// Granularity of the size of a shared-memory region.
+pub const SHARED_MEMORY_ALIGNMENT: u64 = 4096;
+/// Most pmem devices a VM can have; each takes a virtio slot and an interrupt line.
+pub const MAX_PMEM_DEVICES: usize = 4;
+/// Granularity of the size of a pmem device, the huge page size guest DAX mappings use.
+pub const PMEM_ALIGNMENT: u64 = 2 * 1024 * 1024;
+/// Largest width and height of a display, in pixels.
+pub const MAX_DISPLAY_SIZE: u32 = 4096;
+
+/// Where the output of the guest's serial console goes.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SerialConsole {
+    /// No serial port is exposed to the guest.
+    #[default]
+    Disabled,
+    /// Guest output is written to the host's standard output and the host's standard
+    /// input is typed into the guest.
+    Stdout,
+    /// Guest output is appended to the given file.
+    File(PathBuf),
+}
+
+/// Transport the virtio block devices are exposed to the guest through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VirtioTransport {
+    /// virtio-mmio devices announced on the kernel command line.
+    #[default]
+    Mmio,
+    /// virtio-pci functions behind a PCI host bridge the guest enumerates; x86_64 only.
+    Pci,
+}
+
+/// Storage backend serving a disk to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiskBackendType {
+    /// Picked from the image's content: raw images and ISOs are mapped, qcow2 images
+    /// are read and written natively.
+    #[default]
+    Auto,
+    /// Raw images are read and written through the file, without mapping them.
+    File,
+    /// Raw images are read and written through io_uring; needs the `linux_io_uring` feature.
+    IoUring,
+}
+
+/// A disk attached to the VM as a virtio block device.
+///
+/// Disks get virtio slots in the order they were added, so the guest sees the first
+/// one as `/dev/vda`, the second as `/dev/vdb`, and so on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskDevice {
+    /// Disk image exposed to the guest.
+    path: PathBuf,
+    /// Backend serving the image.
+    backend: DiskBackendType,
+    /// When guest writes reach the image.
+    cache_mode: CacheMode,
+    /// Whether guest writes are refused.
+    read_only: bool,
+    /// How long the device polls its queues after draining them.
+    poll_window: Duration,
+}
+
+impl DiskDevice {
+    /// Create a writable disk on the image at `path`, served by the `Auto` backend
+    /// with writeback caching.
+    pub fn new(path: impl Into<PathBuf>) -> DiskDevice {
+        DiskDevice {
+            path: path.into(),
+            backend: DiskBackendType::Auto,
+            cache_mode: CacheMode::Writeback,
+            read_only: false,
+            poll_window: Duration::ZERO,
+        }
+    }
+    /// Set the backend serving the image.
+    pub fn backend(mut self, backend: DiskBackendType) -> DiskDevice {
+        self.backend = backend;
+        self
+    }
+    /// Set when guest writes reach the image; the guest can switch it later on.
+    pub fn cache_mode(mut self, cache_mode: CacheMode) -> DiskDevice {
+        self.cache_mode = cache_mode;
+        self
+    }
+    /// Refuse guest writes; the image is opened read-only.
+    pub fn read_only(mut self, read_only: bool) -> DiskDevice {
+        self.read_only = read_only;
+        self
+    }
+    /// Keep polling the queues for `window` after draining them instead of waiting for
+    /// the guest to notify the device, trading a host CPU for latency, see
+    /// `VirtioBlockDevice::set_poll_window`. Zero, the default, turns polling off.
+    pub fn poll_window(mut self, window: Duration) -> DiskDevice {
+        self.poll_window = window;
+        self
+    }
+    /// Get the path of the disk image.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+    /// Get the backend serving the image.
+    pub fn get_backend(&self) -> DiskBackendType {
+        self.backend
+    }
+    /// Get when guest writes reach the image.
+    pub fn get_cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+    /// Get whether guest writes are refused.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    /// Get how long the device polls its queues after draining them.
+    pub fn get_poll_window(&self) -> Duration {
+        self.poll_window
+    }
+}
+
+/// Device the VM can boot from, see `VmSetupBuilder::boot_order`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BootDevice {
+    /// The kernel given with `VmSetupBuilder::kernel`.
+    Kernel,
+    /// The kernel installed on the disk image.
+    Disk,
+    /// The kernel of the installation or live media attached as a CD-ROM.
+    Cdrom,
+    /// Network boot; no backend supports it yet, so it is always skipped.
+    Network,
+}
+
+/// Boot order used when none is given: a direct kernel wins, then the disk, then the CD-ROM.
+pub const DEFAULT_BOOT_ORDER: [BootDevice; 4] = [BootDevice::Kernel, BootDevice::Disk, BootDevice::Cdrom, BootDevice::Network];
+
+/// A virtual network interface attached to the VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkDevice {
+    /// Host interface (e.g. TAP device) the guest traffic goes through.
+    host_interface: String,
+    /// MAC address of the guest interface.
+    mac_address: [u8; 6],
+}
+
+impl NetworkDevice {
+    /// Create a network device bridged to `host_interface`.
+    ///
+    /// # Arguments
+    /// * `host_interface` - Name of the host interface carrying the traffic
+    /// * `mac_address` - Unicast MAC address of the guest interface
+    pub fn new(host_interface: &str, mac_address: [u8; 6]) -> NetworkDevice {
+        NetworkDevice { host_interface: host_interface.to_string(), mac_address }
+    }
+    /// Get the host interface name.
+    pub fn get_host_interface(&self) -> &str {
+        &self.host_interface
+    }
+    /// Get the guest MAC address.
+    pub fn get_mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+}
+
+/// A host directory shared with the guest.
+///
+/// The guest mounts it by its tag, e.g.
+/// `mount -t 9p -o trans=virtio,version=9p2000.L <tag> /mnt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedDirectory {
+    /// Host directory exposed to the guest.
+    path: PathBuf,
+    /// Tag the guest mounts the directory by.
+    tag: String,
+}
+
+impl SharedDirectory {
+    /// Share the host directory at `path` under `tag`.
+    pub fn new(path: impl Into<PathBuf>, tag: &str) -> SharedDirectory {
+        SharedDirectory { path: path.into(), tag: tag.to_string() }
+    }
+    /// Get the shared host directory.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+    /// Get the tag the guest mounts the directory by.
+    pub fn get_tag(&self) -> &str {
+        &self.tag
+    }
+}
+
+/// Host memory a shared-memory region is backed by.
+#[derive(Debug, Clone)]
+pub enum SharedMemoryBacking {
+    /// File at the given path, e.g. under `/dev/shm`; created or grown to the size of
+    /// the region if needed.
+    Path(PathBuf),
+    /// An open file, such as a memfd from `shared_memory::create_memfd`, the host keeps
+    /// its own handle on. It must be opened for reading and writing.
+    File(Arc<File>),
+}
+
+/// Host memory mapped into the guest physical address space, through which host
+/// processes and the guest exchange data without copies.
+///
+/// See `device_emulation::shared_memory` for where the guest finds the region.
+#[derive(Debug, Clone)]
+pub struct SharedMemoryRegion {
+    /// Host memory backing the region.
+    backing: SharedMemoryBacking,
+    /// Size of the region in bytes.
+    size: u64,
+}
+
+impl SharedMemoryRegion {
+    /// Share `size` bytes of `backing` with the guest.
+    ///
+    /// # Arguments
+    /// * `backing` - Host memory backing the region
+    /// * `size` - Size of the region, a multiple of `SHARED_MEMORY_ALIGNMENT`
+    pub fn new(backing: SharedMemoryBacking, size: u64) -> SharedMemoryRegion {
+        SharedMemoryRegion { backing, size }
+    }
+    /// Get the host memory backing the region.
+    pub fn get_backing(&self) -> &SharedMemoryBacking {
+        &self.backing
+    }
+    /// Get the size of the region in bytes.
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// A host file exposed to the guest as persistent memory through virtio-pmem.
+///
+/// The file is mapped into the guest physical address space, so the guest reaches it
+/// without going through a block device: it shows up as `/dev/pmem<n>`, in the order
+/// the devices were added, and a filesystem on it mounted with `-o dax` lets guest
+/// programs mmap the file's pages directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PmemDevice {
+    /// File backing the device.
+    path: PathBuf,
+    /// Size of the device; the size of the file when `None`.
+    size: Option<u64>,
+}
+
+impl PmemDevice {
+    /// Expose the whole file at `path`, whose size must be a multiple of `PMEM_ALIGNMENT`.
+    pub fn new(path: impl Into<PathBuf>) -> PmemDevice {
+        PmemDevice { path: path.into(), size: None }
+    }
+    /// Expose `size` bytes of the file, a multiple of `PMEM_ALIGNMENT`, creating or
+    /// growing the file as needed.
+    pub fn size(mut self, size: u64) -> PmemDevice {
+        self.size = Some(size);
+        self
+    }
+    /// Get the path of the file backing the device.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+    /// Get the size of the device, if it isn't the size of the file.
+    pub fn get_size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+/// Screen of the guest: a linear framebuffer the guest kernel draws its console and
+/// graphics to, which the host captures with `VmHandle::screenshot`.
+///
+/// See `device_emulation::framebuffer` for the pixel format and how the guest finds it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Display {
+    /// Width of the screen in pixels.
+    width: u32,
+    /// Height of the screen in pixels.
+    height: u32,
+}
+
+impl Display {
+    /// Create a screen of `width` by `height` pixels, at most `MAX_DISPLAY_SIZE` each.
+    pub fn new(width: u32, height: u32) -> Display {
+        Display { width, height }
+    }
+    /// Get the width of the screen in pixels.
+    pub fn get_width(&self) -> u32 {
+        self.width
+    }
+    /// Get the height of the screen in pixels.
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+}
+
 /// Configuration for a Virtual Machine instance.
 pub struct VmSetup {
     /// Size of VM memory in bytes.
     memory: usize,
     /// Number of CPU cores to allocate to the VM.
-    cpu_cores_count: u32
+    cpu_cores_count: u32,
+    /// Whether guest RAM should be backed by large pages where the host supports it.
+    use_large_pages: bool,
+    /// CPU model presented to the guest.
+    cpu_model: CpuModel,
+    /// Mitigations the host must have in place and speculation controls hidden from the guest.
+    mitigation_policy: MitigationPolicy,
+    /// CPU security features hidden from the guest.
+    security_features: SecurityFeaturePolicy,
+    /// Whether the guest gets a virtual PMU.
+    guest_pmu: GuestPmu,
+    /// Interrupt controller emulated for ARM64 guests.
+    gic_version: GicVersion,
+    /// Explicit virtqueue sizing; derived from the vCPU count when `None`.
+    virtqueue_config: Option<VirtqueueConfig>,
+    /// Kernel booted directly by the VM, if any.
+    kernel: Option<KernelComponents>,
+    /// Command line passed to the kernel.
+    kernel_cmdline: String,
+    /// Disks exposed to the guest as virtio block devices, in slot order.
+    disks: Vec<DiskDevice>,
+    /// Transport of the disks and the CD-ROM.
+    virtio_transport: VirtioTransport,
+    /// ISO image exposed to the guest as a read-only virtio block device.
+    cdrom_image: Option<PathBuf>,
+    /// Devices tried in turn when the VM boots.
+    boot_order: Vec<BootDevice>,
+    /// Destination of the guest serial console.
+    serial_console: SerialConsole,
+    /// Network interfaces of the guest.
+    network_devices: Vec<NetworkDevice>,
+    /// Host directories shared with the guest.
+    shared_dirs: Vec<SharedDirectory>,
+    /// Host memory regions shared with the guest, in register window order.
+    shared_memory: Vec<SharedMemoryRegion>,
+    /// Files exposed to the guest as persistent memory, in device order.
+    pmem_devices: Vec<PmemDevice>,
+    /// Screen of the guest, if any.
+    display: Option<Display>,
+    /// File the vCPU exits are recorded to, see `exit_trace`.
+    exit_trace: Option<PathBuf>
 }
 
 impl VmSetup {
+    /// Start building a `VmSetup` with attached devices.
+    ///
+    /// See `VmSetupBuilder` for the available attachments.
+    pub fn builder(mega_bytes: u32, cpu_cores_count: u32) -> VmSetupBuilder {
+        VmSetupBuilder::new(mega_bytes, cpu_cores_count)
+    }
     /// Create a new `VmSetup`.
     ///
     /// # Arguments
@@ -18,7 +383,11 @@ impl VmSetup {
         } else {
             cpu_cores_count
         };
-        VmSetup {memory: 1024 * 1024 * mega_bytes as usize, cpu_cores_count: cpu_cores_to_set}
+        VmSetup {memory: 1024 * 1024 * mega_bytes as usize, cpu_cores_count: cpu_cores_to_set, use_large_pages: false, cpu_model: CpuModel::HostPassthrough, mitigation_policy: MitigationPolicy::default(),
+            security_features: SecurityFeaturePolicy::default(), guest_pmu: GuestPmu::Disabled, gic_version: GicVersion::V2, virtqueue_config: None,
+            kernel: None, kernel_cmdline: DEFAULT_KERNEL_CMDLINE.to_string(), disks: Vec::new(), virtio_transport: VirtioTransport::Mmio, cdrom_image: None,
+            boot_order: DEFAULT_BOOT_ORDER.to_vec(), serial_console: SerialConsole::Disabled, network_devices: Vec::new(), shared_dirs: Vec::new(),
+            shared_memory: Vec::new(), pmem_devices: Vec::new(), display: None, exit_trace: None}
     }
     /// Get the configured memory size in bytes.
     pub fn get_memory_size(&self) -> usize {
@@ -28,4 +397,381 @@ impl VmSetup {
     pub fn get_cpu_cores_count(&self) -> u32 {
         self.cpu_cores_count
     }
-}
\ No newline at end of file
+    /// Request large page backing for guest RAM.
+    ///
+    /// Currently honored on Windows hosts; if the host lacks large page support or
+    /// the required privilege, standard pages are used instead.
+    pub fn set_use_large_pages(&mut self, use_large_pages: bool) {
+        self.use_large_pages = use_large_pages;
+    }
+    /// Get whether large page backing was requested.
+    pub fn get_use_large_pages(&self) -> bool {
+        self.use_large_pages
+    }
+    /// Set the CPU model presented to the guest.
+    ///
+    /// Defaults to `CpuModel::HostPassthrough`. Pick a named model to keep the guest
+    /// visible CPU identical across hosts with different processors.
+    pub fn set_cpu_model(&mut self, cpu_model: CpuModel) {
+        self.cpu_model = cpu_model;
+    }
+    /// Get the configured CPU model.
+    pub fn get_cpu_model(&self) -> &CpuModel {
+        &self.cpu_model
+    }
+    /// Set the mitigations the host must have in place for the VM to start and whether
+    /// the guest sees the speculation control features.
+    pub fn set_mitigation_policy(&mut self, mitigation_policy: MitigationPolicy) {
+        self.mitigation_policy = mitigation_policy;
+    }
+    /// Get the mitigation policy of the VM.
+    pub fn get_mitigation_policy(&self) -> &MitigationPolicy {
+        &self.mitigation_policy
+    }
+    /// Set the CPU security features (SMEP, SMAP, CET) hidden from the guest.
+    pub fn set_security_features(&mut self, security_features: SecurityFeaturePolicy) {
+        self.security_features = security_features;
+    }
+    /// Get the security feature policy of the VM.
+    pub fn get_security_features(&self) -> &SecurityFeaturePolicy {
+        &self.security_features
+    }
+    /// Set whether the guest gets a virtual PMU.
+    ///
+    /// Defaults to `GuestPmu::Disabled`. A virtualized PMU lets profilers run in the
+    /// guest but also lets it observe the host CPU it shares, see `pmu`.
+    pub fn set_guest_pmu(&mut self, guest_pmu: GuestPmu) {
+        self.guest_pmu = guest_pmu;
+    }
+    /// Get whether the guest gets a virtual PMU.
+    pub fn get_guest_pmu(&self) -> GuestPmu {
+        self.guest_pmu
+    }
+    /// Set the interrupt controller emulated for ARM64 guests.
+    ///
+    /// Defaults to `GicVersion::V2`. Guests with more than 8 vCPUs, or kernels built
+    /// without GICv2 support, need `GicVersion::V3`.
+    pub fn set_gic_version(&mut self, gic_version: GicVersion) {
+        self.gic_version = gic_version;
+    }
+    /// Get the interrupt controller emulated for ARM64 guests.
+    pub fn get_gic_version(&self) -> GicVersion {
+        self.gic_version
+    }
+    /// Override the virtqueue sizing of the VM's virtio devices.
+    ///
+    /// Pass `None` to go back to sizing derived from the vCPU count.
+    pub fn set_virtqueue_config(&mut self, virtqueue_config: Option<VirtqueueConfig>) {
+        self.virtqueue_config = virtqueue_config;
+    }
+    /// Get the virtqueue sizing used for the VM's virtio devices.
+    pub fn get_virtqueue_config(&self) -> VirtqueueConfig {
+        match self.virtqueue_config {
+            Some(config) => config,
+            None => VirtqueueConfig::for_vcpus(self.cpu_cores_count)
+        }
+    }
+    /// Boot the given kernel directly instead of starting from empty memory.
+    ///
+    /// # Arguments
+    /// * `kernel` - Kernel and optional initrd, e.g. from `extract_kernel_components_from_qcow2`
+    /// * `cmdline` - Kernel command line
+    pub fn set_kernel(&mut self, kernel: KernelComponents, cmdline: &str) {
+        self.kernel = Some(kernel);
+        self.kernel_cmdline = cmdline.to_string();
+    }
+    /// Get the kernel booted directly by the VM, if any.
+    pub fn get_kernel(&self) -> Option<&KernelComponents> {
+        self.kernel.as_ref()
+    }
+    /// Get the kernel command line.
+    pub fn get_kernel_cmdline(&self) -> &str {
+        &self.kernel_cmdline
+    }
+    /// Get the image of the first disk, the one the VM boots from, if any.
+    pub fn get_disk_image(&self) -> Option<&Path> {
+        self.disks.first().map(|disk| disk.get_path())
+    }
+    /// Get the disks exposed to the guest, in slot order.
+    pub fn get_disks(&self) -> &[DiskDevice] {
+        &self.disks
+    }
+    /// Set the transport the disks and the CD-ROM are exposed through.
+    ///
+    /// Defaults to `VirtioTransport::Mmio`. Guests without virtio-mmio support, or
+    /// that should see their disks as PCI devices, get `VirtioTransport::Pci`.
+    pub fn set_virtio_transport(&mut self, virtio_transport: VirtioTransport) {
+        self.virtio_transport = virtio_transport;
+    }
+    /// Get the transport the disks and the CD-ROM are exposed through.
+    pub fn get_virtio_transport(&self) -> VirtioTransport {
+        self.virtio_transport
+    }
+    /// Get the ISO image exposed to the guest as a CD-ROM, if any.
+    pub fn get_cdrom_image(&self) -> Option<&Path> {
+        self.cdrom_image.as_deref()
+    }
+    /// Set the order in which the devices are tried when the VM boots.
+    ///
+    /// Devices that aren't attached, or hold no kernel, are skipped. Changing the order
+    /// between runs lets the same setup boot an installer from the CD-ROM first and
+    /// the installed system from the disk afterwards.
+    pub fn set_boot_order(&mut self, boot_order: &[BootDevice]) {
+        self.boot_order = boot_order.to_vec();
+    }
+    /// Get the order in which the devices are tried when the VM boots.
+    pub fn get_boot_order(&self) -> &[BootDevice] {
+        &self.boot_order
+    }
+    /// Get the destination of the guest serial console.
+    pub fn get_serial_console(&self) -> &SerialConsole {
+        &self.serial_console
+    }
+    /// Get the network interfaces of the guest.
+    pub fn get_network_devices(&self) -> &[NetworkDevice] {
+        &self.network_devices
+    }
+    /// Get the host directories shared with the guest.
+    pub fn get_shared_dirs(&self) -> &[SharedDirectory] {
+        &self.shared_dirs
+    }
+    /// Get the host memory regions shared with the guest.
+    pub fn get_shared_memory(&self) -> &[SharedMemoryRegion] {
+        &self.shared_memory
+    }
+    /// Get the files exposed to the guest as persistent memory.
+    pub fn get_pmem_devices(&self) -> &[PmemDevice] {
+        &self.pmem_devices
+    }
+    /// Get the screen of the guest, if any.
+    pub fn get_display(&self) -> Option<&Display> {
+        self.display.as_ref()
+    }
+    /// Get the file the vCPU exits are recorded to, if any.
+    pub fn get_exit_trace(&self) -> Option<&Path> {
+        self.exit_trace.as_deref()
+    }
+}
+
+/// Builder for a `VmSetup` with a disk, a kernel, a serial console and network devices.
+///
+/// Attachments a platform backend cannot provide make its `run_vm` fail with a
+/// configuration error rather than being silently dropped.
+pub struct VmSetupBuilder {
+    setup: VmSetup,
+}
+
+impl VmSetupBuilder {
+    /// Start from a VM with the given memory size in megabytes and CPU cores count.
+    ///
+    /// The values are interpreted as by `VmSetup::new`.
+    pub fn new(mega_bytes: u32, cpu_cores_count: u32) -> VmSetupBuilder {
+        VmSetupBuilder { setup: VmSetup::new(mega_bytes, cpu_cores_count) }
+    }
+    /// Expose a raw, ISO or qcow2 disk image to the guest as a writable virtio block
+    /// device; the format is detected from the image's content.
+    pub fn disk_image(self, path: impl Into<PathBuf>) -> VmSetupBuilder {
+        self.disk(DiskDevice::new(path))
+    }
+    /// Add a disk after the ones added before, see `DiskDevice` for its device name.
+    pub fn disk(mut self, disk: DiskDevice) -> VmSetupBuilder {
+        self.setup.disks.push(disk);
+        self
+    }
+    /// Expose an ISO image, such as installation media, to the guest as a read-only
+    /// virtio block device. The VM can boot the kernel it holds, see `boot_order`.
+    pub fn cdrom_image(mut self, path: impl Into<PathBuf>) -> VmSetupBuilder {
+        self.setup.cdrom_image = Some(path.into());
+        self
+    }
+    /// Attach an ISO image, such as a cloud-init seed or a driver disk, as the next
+    /// disk, read-only. Unlike the CD-ROM it comes before, so it keeps its device name
+    /// whether or not a CD-ROM is attached; the VM doesn't boot from it.
+    pub fn iso_image(self, path: impl Into<PathBuf>) -> VmSetupBuilder {
+        self.disk(DiskDevice::new(path).read_only(true))
+    }
+    /// Set the transport the disks and the CD-ROM are exposed through, see `VirtioTransport`.
+    pub fn virtio_transport(mut self, virtio_transport: VirtioTransport) -> VmSetupBuilder {
+        self.setup.set_virtio_transport(virtio_transport);
+        self
+    }
+    /// Set the order in which the devices are tried when the VM boots; defaults to
+    /// `DEFAULT_BOOT_ORDER`.
+    pub fn boot_order(mut self, boot_order: &[BootDevice]) -> VmSetupBuilder {
+        self.setup.set_boot_order(boot_order);
+        self
+    }
+    /// Boot the given kernel directly with the given command line.
+    pub fn kernel(mut self, kernel: KernelComponents, cmdline: &str) -> VmSetupBuilder {
+        self.setup.set_kernel(kernel, cmdline);
+        self
+    }
+    /// Set where the guest serial console output goes.
+    pub fn serial_console(mut self, serial_console: SerialConsole) -> VmSetupBuilder {
+        self.setup.serial_console = serial_console;
+        self
+    }
+    /// Add a network interface to the guest.
+    pub fn network_device(mut self, network_device: NetworkDevice) -> VmSetupBuilder {
+        self.setup.network_devices.push(network_device);
+        self
+    }
+    /// Share the host directory at `host_path` with the guest, which mounts it by
+    /// `tag`, see `SharedDirectory`.
+    pub fn share_dir(mut self, host_path: impl Into<PathBuf>, tag: &str) -> VmSetupBuilder {
+        self.setup.shared_dirs.push(SharedDirectory::new(host_path, tag));
+        self
+    }
+    /// Map a host memory region into the guest after the ones added before, see
+    /// `SharedMemoryRegion`.
+    pub fn shared_memory(mut self, region: SharedMemoryRegion) -> VmSetupBuilder {
+        self.setup.shared_memory.push(region);
+        self
+    }
+    /// Add a persistent memory device after the ones added before, see `PmemDevice`.
+    pub fn pmem(mut self, pmem: PmemDevice) -> VmSetupBuilder {
+        self.setup.pmem_devices.push(pmem);
+        self
+    }
+    /// Give the guest a screen, see `Display`.
+    pub fn display(mut self, display: Display) -> VmSetupBuilder {
+        self.setup.display = Some(display);
+        self
+    }
+    /// Set the CPU model presented to the guest.
+    pub fn cpu_model(mut self, cpu_model: CpuModel) -> VmSetupBuilder {
+        self.setup.set_cpu_model(cpu_model);
+        self
+    }
+    /// Set the mitigation policy of the VM, see `MitigationPolicy`.
+    pub fn mitigation_policy(mut self, mitigation_policy: MitigationPolicy) -> VmSetupBuilder {
+        self.setup.set_mitigation_policy(mitigation_policy);
+        self
+    }
+    /// Set the CPU security features hidden from the guest, see `SecurityFeaturePolicy`.
+    pub fn security_features(mut self, security_features: SecurityFeaturePolicy) -> VmSetupBuilder {
+        self.setup.set_security_features(security_features);
+        self
+    }
+    /// Set whether the guest gets a virtual PMU, see `GuestPmu`.
+    pub fn guest_pmu(mut self, guest_pmu: GuestPmu) -> VmSetupBuilder {
+        self.setup.set_guest_pmu(guest_pmu);
+        self
+    }
+    /// Set the interrupt controller emulated for ARM64 guests, see `GicVersion`.
+    pub fn gic_version(mut self, gic_version: GicVersion) -> VmSetupBuilder {
+        self.setup.set_gic_version(gic_version);
+        self
+    }
+    /// Request large page backing for guest RAM.
+    pub fn use_large_pages(mut self, use_large_pages: bool) -> VmSetupBuilder {
+        self.setup.set_use_large_pages(use_large_pages);
+        self
+    }
+    /// Override the virtqueue sizing of the VM's virtio devices.
+    pub fn virtqueue_config(mut self, virtqueue_config: VirtqueueConfig) -> VmSetupBuilder {
+        self.setup.set_virtqueue_config(Some(virtqueue_config));
+        self
+    }
+    /// Record the device accesses of the vCPUs and the serial input to the trace file
+    /// at `path`, which `exit_trace::replay_exit_trace` can replay.
+    pub fn record_exits(mut self, path: impl Into<PathBuf>) -> VmSetupBuilder {
+        self.setup.exit_trace = Some(path.into());
+        self
+    }
+    /// Validate the attachments and create the `VmSetup`.
+    ///
+    /// # Returns
+    /// * `Ok(VmSetup)` on success
+    /// * `Err(VmError)` if a network device has a multicast or duplicate MAC address
+    ///   or an empty host interface name, the boot order is empty or names a device
+    ///   twice, or there are more than `MAX_DISKS` disks or an image is attached twice,
+    ///   or there are more than `MAX_SHARED_DIRS` shared directories or a mount tag is
+    ///   empty, longer than `MAX_MOUNT_TAG_LEN` bytes or used twice, or there are more
+    ///   than `MAX_SHARED_MEMORY_REGIONS` shared-memory regions, one is empty, its size
+    ///   isn't a multiple of `SHARED_MEMORY_ALIGNMENT` or its file backs another region,
+    ///   or there are more than `MAX_PMEM_DEVICES` pmem devices, one has a size that isn't
+    ///   a non-zero multiple of `PMEM_ALIGNMENT` or its file backs another device, or the
+    ///   display is empty or wider or higher than `MAX_DISPLAY_SIZE`
+    pub fn build(self) -> Result<VmSetup, VmError> {
+        let disks = &self.setup.disks;
+        if disks.len() > MAX_DISKS {
+            return Err(VmError::config(format!("{} disks are attached, at most {} are supported", disks.len(), MAX_DISKS)));
+        }
+        for (i, disk) in disks.iter().enumerate() {
+            if disks[..i].iter().any(|other| other.path == disk.path) {
+                return Err(VmError::config(format!("disk {} reuses the image {} of another disk", i, disk.path.display())));
+            }
+        }
+        let boot_order = &self.setup.boot_order;
+        if boot_order.is_empty() {
+            return Err(VmError::config("boot order is empty"));
+        }
+        for (i, device) in boot_order.iter().enumerate() {
+            if boot_order[..i].contains(device) {
+                return Err(VmError::config(format!("boot order names {:?} twice", device)));
+            }
+        }
+        let devices = &self.setup.network_devices;
+        for (i, device) in devices.iter().enumerate() {
+            if device.host_interface.is_empty() {
+                return Err(VmError::config(format!("network device {} has no host interface", i)));
+            }
+            // The least significant bit of the first octet marks multicast addresses
+            if device.mac_address[0] & 1 != 0 {
+                return Err(VmError::config(format!("network device {} has a multicast MAC address", i)));
+            }
+            if devices[..i].iter().any(|other| other.mac_address == device.mac_address) {
+                return Err(VmError::config(format!("network device {} reuses the MAC address of another device", i)));
+            }
+        }
+        let shared_dirs = &self.setup.shared_dirs;
+        if shared_dirs.len() > MAX_SHARED_DIRS {
+            return Err(VmError::config(format!("{} directories are shared, at most {} are supported", shared_dirs.len(), MAX_SHARED_DIRS)));
+        }
+        for (i, shared_dir) in shared_dirs.iter().enumerate() {
+            if shared_dir.tag.is_empty() || shared_dir.tag.len() > MAX_MOUNT_TAG_LEN {
+                return Err(VmError::config(format!("shared directory {} needs a mount tag of 1 to {} bytes", i, MAX_MOUNT_TAG_LEN)));
+            }
+            if shared_dirs[..i].iter().any(|other| other.tag == shared_dir.tag) {
+                return Err(VmError::config(format!("shared directory {} reuses the mount tag {}", i, shared_dir.tag)));
+            }
+        }
+        let shared_memory = &self.setup.shared_memory;
+        if shared_memory.len() > MAX_SHARED_MEMORY_REGIONS {
+            return Err(VmError::config(format!("{} shared-memory regions are attached, at most {} are supported", shared_memory.len(), MAX_SHARED_MEMORY_REGIONS)));
+        }
+        for (i, region) in shared_memory.iter().enumerate() {
+            if region.size == 0 || region.size % SHARED_MEMORY_ALIGNMENT != 0 {
+                return Err(VmError::config(format!("shared-memory region {} needs a non-zero size in multiples of {} bytes", i, SHARED_MEMORY_ALIGNMENT)));
+            }
+            let reuses_backing = shared_memory[..i].iter().any(|other| match (&other.backing, &region.backing) {
+                (SharedMemoryBacking::Path(a), SharedMemoryBacking::Path(b)) => a == b,
+                (SharedMemoryBacking::File(a), SharedMemoryBacking::File(b)) => Arc::ptr_eq(a, b),
+                _ => false
+            });
+            if reuses_backing {
+                return Err(VmError::config(format!("shared-memory region {} reuses the file of another region", i)));
+            }
+        }
+        let pmem_devices = &self.setup.pmem_devices;
+        if pmem_devices.len() > MAX_PMEM_DEVICES {
+            return Err(VmError::config(format!("{} pmem devices are attached, at most {} are supported", pmem_devices.len(), MAX_PMEM_DEVICES)));
+        }
+        for (i, pmem) in pmem_devices.iter().enumerate() {
+            if pmem.size.is_some_and(|size| size == 0 || size % PMEM_ALIGNMENT != 0) {
+                return Err(VmError::config(format!("pmem device {} needs a non-zero size in multiples of {} bytes", i, PMEM_ALIGNMENT)));
+            }
+            if pmem_devices[..i].iter().any(|other| other.path == pmem.path) {
+                return Err(VmError::config(format!("pmem device {} reuses the file {} of another device", i, pmem.path.display())));
+            }
+        }
+        if let Some(display) = &self.setup.display {
+            let valid = 1..=MAX_DISPLAY_SIZE;
+            if !valid.contains(&display.width) || !valid.contains(&display.height) {
+                return Err(VmError::config(format!("display of {}x{} pixels isn't 1 to {} pixels wide and high", display.width, display.height, MAX_DISPLAY_SIZE)));
+            }
+        }
+        Ok(self.setup)
+    }
+}
@@ -0,0 +1,903 @@
+//! Lifecycle control of a running virtual machine.
+//!
+//! `spawn_vm` in the platform setup modules starts a VM in the background and returns
+//! a `VmHandle`. The handle and the vCPU loops of the VM share a `VmControl`: the
+//! handle changes the requested state and kicks the vCPUs out of the guest, and every
+//! vCPU loop checks the state before it re-enters the guest.
+
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::error::VmError;
+use crate::vm_setup::boot_progress::{BootOutcome, BootProgress};
+use crate::vm_setup::console_log::{ConsoleLines, ConsoleLog};
+use crate::vm_setup::exit_latency::{ExitLatencies, ExitLatency};
+use crate::vm_setup::exit_summary::{VcpuExitReason, VcpuFailure, VmExitSummary};
+use crate::vm_setup::guest_profiler::{GuestProfile, GuestProfiler, MIN_SAMPLE_INTERVAL};
+use crate::vm_setup::introspection::{GuestWriteLog, PagePermissions};
+use crate::vm_setup::memory_monitor::MemoryMonitor;
+use crate::vm_setup::screen_recorder::{ScreenRecorder, MIN_RECORDING_INTERVAL};
+
+/// Interval at which `pause` and `stop` re-kick vCPUs that haven't reacted yet.
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+/// Interval at which `wait_for_boot` checks the boot progress.
+const BOOT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Lifecycle state of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    /// vCPUs are executing guest code.
+    Running,
+    /// vCPUs are parked and guest time is frozen where the hypervisor supports it.
+    Paused,
+    /// The VM was asked to stop or has finished running.
+    Stopped,
+}
+
+/// Hypervisor specific part of the run control, installed by the platform backend.
+pub(crate) trait RunControlHooks: Send + Sync {
+    /// Forces every vCPU currently executing guest code to return to its loop.
+    fn kick(&self);
+
+    /// Called once the VM has been asked to pause, before the vCPUs are kicked.
+    fn on_pause(&self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    /// Called when the VM resumes, before the vCPUs are released.
+    fn on_resume(&self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    /// Sets the size the balloon device of the VM asks the guest to inflate to.
+    fn set_balloon_target(&self, _bytes: u64) -> Result<(), VmError> {
+        Err(VmError::device("the VM has no balloon device"))
+    }
+
+    /// Returns the size of the balloon reported by the guest, if the VM has one.
+    fn get_balloon_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Starts recording the guest's writes to the `len` bytes at guest physical address `gpa`.
+    fn track_guest_writes(&self, _gpa: u64, _len: u64) -> Result<(), VmError> {
+        Err(VmError::hypervisor("write tracking isn't supported by this backend"))
+    }
+
+    /// Stops recording the guest's writes to the range starting at `gpa`.
+    fn untrack_guest_writes(&self, _gpa: u64) -> Result<(), VmError> {
+        Err(VmError::hypervisor("write tracking isn't supported by this backend"))
+    }
+
+    /// Returns the writes recorded since the last call.
+    fn take_guest_writes(&self) -> Result<GuestWriteLog, VmError> {
+        Err(VmError::hypervisor("write tracking isn't supported by this backend"))
+    }
+
+    /// Copies the `len` bytes of guest RAM at guest physical address `gpa`.
+    fn read_guest_memory(&self, _gpa: u64, _len: usize) -> Result<Vec<u8>, VmError> {
+        Err(VmError::hypervisor("memory introspection isn't supported by this backend"))
+    }
+
+    /// Returns how the page holding `gpa` is mapped, or `None` if it isn't guest RAM.
+    fn get_page_permissions(&self, _gpa: u64) -> Result<Option<PagePermissions>, VmError> {
+        Err(VmError::hypervisor("memory introspection isn't supported by this backend"))
+    }
+
+    /// Captures the guest's display as a PNG image.
+    fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+        Err(VmError::device("the VM has no display"))
+    }
+}
+
+impl<T: RunControlHooks> RunControlHooks for Arc<T> {
+    fn kick(&self) {
+        (**self).kick()
+    }
+
+    fn on_pause(&self) -> Result<(), VmError> {
+        (**self).on_pause()
+    }
+
+    fn on_resume(&self) -> Result<(), VmError> {
+        (**self).on_resume()
+    }
+
+    fn set_balloon_target(&self, bytes: u64) -> Result<(), VmError> {
+        (**self).set_balloon_target(bytes)
+    }
+
+    fn get_balloon_size(&self) -> Option<u64> {
+        (**self).get_balloon_size()
+    }
+
+    fn track_guest_writes(&self, gpa: u64, len: u64) -> Result<(), VmError> {
+        (**self).track_guest_writes(gpa, len)
+    }
+
+    fn untrack_guest_writes(&self, gpa: u64) -> Result<(), VmError> {
+        (**self).untrack_guest_writes(gpa)
+    }
+
+    fn take_guest_writes(&self) -> Result<GuestWriteLog, VmError> {
+        (**self).take_guest_writes()
+    }
+
+    fn read_guest_memory(&self, gpa: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        (**self).read_guest_memory(gpa, len)
+    }
+
+    fn get_page_permissions(&self, gpa: u64) -> Result<Option<PagePermissions>, VmError> {
+        (**self).get_page_permissions(gpa)
+    }
+
+    fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+        (**self).screenshot()
+    }
+}
+
+/// Counters protected by the `VmControl` mutex.
+struct ControlState {
+    /// Requested lifecycle state
+    state: VmState,
+    /// Number of vCPU loops currently alive
+    active_vcpus: usize,
+    /// Number of vCPU loops parked because the VM is paused
+    parked_vcpus: usize,
+}
+
+/// State shared between a `VmHandle` and the vCPU loops of its VM.
+pub(crate) struct VmControl {
+    inner: Mutex<ControlState>,
+    changed: Condvar,
+    hooks: Mutex<Option<Box<dyn RunControlHooks>>>,
+    boot_progress: Arc<BootProgress>,
+    memory_monitor: Arc<MemoryMonitor>,
+    console_log: Arc<ConsoleLog>,
+    profiler: Arc<GuestProfiler>,
+    exit_latencies: Arc<ExitLatencies>,
+    vcpu_failures: Mutex<Vec<VcpuFailure>>,
+}
+
+/// Marks a vCPU loop as alive for as long as it is held.
+pub(crate) struct VcpuGuard {
+    control: Arc<VmControl>,
+}
+
+impl Drop for VcpuGuard {
+    fn drop(&mut self) {
+        let mut inner = self.control.lock();
+        inner.active_vcpus -= 1;
+        self.control.changed.notify_all();
+    }
+}
+
+impl VmControl {
+    /// Creates the control of a running VM without any vCPU.
+    pub(crate) fn new() -> VmControl {
+        VmControl {
+            inner: Mutex::new(ControlState { state: VmState::Running, active_vcpus: 0, parked_vcpus: 0 }),
+            changed: Condvar::new(),
+            hooks: Mutex::new(None),
+            boot_progress: Arc::new(BootProgress::new()),
+            memory_monitor: Arc::new(MemoryMonitor::new()),
+            console_log: Arc::new(ConsoleLog::new()),
+            profiler: Arc::new(GuestProfiler::new()),
+            exit_latencies: Arc::new(ExitLatencies::new()),
+            vcpu_failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Installs the hypervisor hooks. If the VM was paused before the backend got
+    /// that far, the pause hook is applied right away.
+    pub(crate) fn set_hooks(&self, hooks: Box<dyn RunControlHooks>) -> Result<(), VmError> {
+        if self.get_state() == VmState::Paused {
+            hooks.on_pause()?;
+        }
+        *self.hooks.lock().unwrap_or_else(|e| e.into_inner()) = Some(hooks);
+        Ok(())
+    }
+
+    /// Registers the calling vCPU loop; it is unregistered when the guard drops.
+    pub(crate) fn enter_vcpu(self: &Arc<Self>) -> VcpuGuard {
+        self.lock().active_vcpus += 1;
+        VcpuGuard { control: Arc::clone(self) }
+    }
+
+    /// Called by a vCPU loop before entering the guest.
+    ///
+    /// Blocks while the VM is paused.
+    ///
+    /// # Returns
+    /// * `true` if the vCPU may run the guest
+    /// * `false` if the VM is stopping and the loop must exit
+    pub(crate) fn wait_for_run(&self) -> bool {
+        let mut inner = self.lock();
+        if inner.state == VmState::Paused {
+            inner.parked_vcpus += 1;
+            self.changed.notify_all();
+            while inner.state == VmState::Paused {
+                inner = self.changed.wait(inner).unwrap_or_else(|e| e.into_inner());
+            }
+            inner.parked_vcpus -= 1;
+        }
+        inner.state == VmState::Running
+    }
+
+    /// Runs `body`, the loop of vCPU `vcpu`, catching a panic in it so it doesn't take
+    /// the other vCPUs and the `VmHandle` down with it.
+    ///
+    /// # Returns
+    /// * The result of `body` if it returned
+    /// * `Ok(VcpuExitReason::Panicked)` if it panicked; the panic is recorded as a `VcpuFailure`
+    pub(crate) fn catch_vcpu_panic(
+        &self,
+        vcpu: u32,
+        body: impl FnOnce() -> Result<VcpuExitReason, VmError>,
+    ) -> Result<VcpuExitReason, VmError> {
+        let payload = match catch_unwind(AssertUnwindSafe(body)) {
+            Ok(result) => return result,
+            Err(payload) => payload,
+        };
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "unknown panic".to_string(),
+            },
+        };
+        eprintln!("VCPU {} panicked: {}", vcpu, message);
+        self.vcpu_failures.lock().unwrap_or_else(|e| e.into_inner()).push(VcpuFailure { vcpu, message });
+        Ok(VcpuExitReason::Panicked)
+    }
+
+    /// Returns the panics caught in the vCPU loops so far.
+    pub(crate) fn get_vcpu_failures(&self) -> Vec<VcpuFailure> {
+        self.vcpu_failures.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Returns the boot progress the backend reports guest milestones to.
+    pub(crate) fn get_boot_progress(&self) -> &Arc<BootProgress> {
+        &self.boot_progress
+    }
+
+    /// Returns the log the backend records the guest's serial console output in.
+    pub(crate) fn get_console_log(&self) -> &Arc<ConsoleLog> {
+        &self.console_log
+    }
+
+    /// Returns the profiler the vCPU loops record their samples in.
+    pub(crate) fn get_profiler(&self) -> &Arc<GuestProfiler> {
+        &self.profiler
+    }
+
+    /// Returns the histograms the vCPU loops time device exits in.
+    pub(crate) fn get_exit_latencies(&self) -> &Arc<ExitLatencies> {
+        &self.exit_latencies
+    }
+
+    /// Returns the monitor the backend reports host memory pressure to.
+    pub(crate) fn get_memory_monitor(&self) -> &Arc<MemoryMonitor> {
+        &self.memory_monitor
+    }
+
+    /// Returns the requested lifecycle state.
+    pub(crate) fn get_state(&self) -> VmState {
+        self.lock().state
+    }
+
+    /// Stops the VM from inside, e.g. when the guest asks for a reset or power off.
+    ///
+    /// Every vCPU is kicked out of the guest and leaves its loop at the next
+    /// `wait_for_run`.
+    pub(crate) fn request_stop(&self) {
+        self.set_state(VmState::Stopped);
+        self.kick();
+    }
+
+    fn set_state(&self, state: VmState) {
+        self.lock().state = state;
+        self.changed.notify_all();
+    }
+
+    fn with_hooks<T>(&self, default: T, f: impl FnOnce(&dyn RunControlHooks) -> T) -> T {
+        match self.hooks.lock().unwrap_or_else(|e| e.into_inner()).as_deref() {
+            Some(hooks) => f(hooks),
+            None => default
+        }
+    }
+
+    fn kick(&self) {
+        self.with_hooks((), |hooks| hooks.kick());
+    }
+
+    /// Captures the guest's display as a PNG image, see `VmHandle::screenshot`.
+    pub(crate) fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+        self.with_hooks(Err(VmError::device("the VM has no display")), |hooks| hooks.screenshot())
+    }
+
+    /// Returns `true` once every alive vCPU is parked.
+    fn all_parked(&self) -> bool {
+        let inner = self.lock();
+        inner.parked_vcpus == inner.active_vcpus
+    }
+
+    /// Returns `true` once every vCPU loop has exited.
+    fn all_exited(&self) -> bool {
+        self.lock().active_vcpus == 0
+    }
+}
+
+/// Handle to a VM started with `spawn_vm`.
+pub struct VmHandle {
+    control: Arc<VmControl>,
+    task: JoinHandle<Result<VmExitSummary, VmError>>,
+}
+
+impl VmHandle {
+    /// Runs the VM future produced by `run` on a dedicated blocking thread and
+    /// returns a handle controlling it. Must be called from within a Tokio runtime.
+    pub(crate) fn spawn<F, Fut>(run: F) -> VmHandle
+    where
+        F: FnOnce(Arc<VmControl>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<VmExitSummary, VmError>>,
+    {
+        let control = Arc::new(VmControl::new());
+        let vm_control = Arc::clone(&control);
+        let runtime = tokio::runtime::Handle::current();
+
+        // The backends hold hypervisor objects that aren't `Send`, so the VM future is
+        // driven by `block_on` on its own thread instead of being spawned as a task
+        let task = tokio::task::spawn_blocking(move || {
+            let result = runtime.block_on(run(Arc::clone(&vm_control)));
+            vm_control.set_state(VmState::Stopped);
+            vm_control.console_log.close();
+            // Release the hypervisor objects held by the hooks
+            *vm_control.hooks.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            result
+        });
+
+        VmHandle { control, task }
+    }
+
+    /// Returns the current lifecycle state of the VM.
+    pub fn get_state(&self) -> VmState {
+        if self.task.is_finished() {
+            return VmState::Stopped;
+        }
+        self.control.get_state()
+    }
+
+    /// Pauses the VM and waits until every vCPU has left the guest.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the VM is paused, or if it was already paused
+    /// * `Err(VmError)` if the VM is stopped or the hypervisor refused to pause it
+    pub async fn pause(&self) -> Result<(), VmError> {
+        match self.get_state() {
+            VmState::Paused => return Ok(()),
+            VmState::Stopped => return Err(VmError::config("cannot pause a stopped VM")),
+            VmState::Running => {}
+        }
+
+        self.control.set_state(VmState::Paused);
+        if let Err(e) = self.control.with_hooks(Ok(()), |hooks| hooks.on_pause()) {
+            self.control.set_state(VmState::Running);
+            return Err(e);
+        }
+
+        while !self.control.all_parked() && !self.task.is_finished() {
+            self.control.kick();
+            tokio::time::sleep(KICK_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
+    /// Resumes a paused VM.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the vCPUs were released, or if the VM was already running
+    /// * `Err(VmError)` if the VM is stopped or the hypervisor refused to resume it
+    pub async fn resume(&self) -> Result<(), VmError> {
+        match self.get_state() {
+            VmState::Running => return Ok(()),
+            VmState::Stopped => return Err(VmError::config("cannot resume a stopped VM")),
+            VmState::Paused => {}
+        }
+
+        self.control.with_hooks(Ok(()), |hooks| hooks.on_resume())?;
+        self.control.set_state(VmState::Running);
+        Ok(())
+    }
+
+    /// Stops the VM and waits until every vCPU loop has exited.
+    ///
+    /// A paused VM is resumed at the hypervisor level first so its vCPUs can unwind.
+    ///
+    /// # Returns
+    /// * `Ok(())` once all vCPUs exited, or if the VM had already stopped
+    /// * `Err(VmError)` if a paused VM couldn't be resumed for shutdown
+    pub async fn stop(&self) -> Result<(), VmError> {
+        match self.get_state() {
+            VmState::Stopped => return Ok(()),
+            VmState::Paused => self.control.with_hooks(Ok(()), |hooks| hooks.on_resume())?,
+            VmState::Running => {}
+        }
+
+        self.control.set_state(VmState::Stopped);
+        while !self.control.all_exited() && !self.task.is_finished() {
+            self.control.kick();
+            tokio::time::sleep(KICK_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the host memory pressure monitor of the VM, to read the pressure level
+    /// or add handlers reacting to its changes.
+    ///
+    /// Every backend watches host memory while the VM runs; before that the pressure
+    /// is `Normal`.
+    pub fn get_memory_monitor(&self) -> &Arc<MemoryMonitor> {
+        self.control.get_memory_monitor()
+    }
+
+    /// Asks the guest to hand `bytes` of its memory to its balloon, which the host
+    /// reclaims, or to take memory back with a lower target. The guest driver inflates
+    /// or deflates the balloon in the background, see `get_balloon_size`.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the guest was told about the new target
+    /// * `Err(VmError)` if the VM has no balloon device or isn't running yet
+    pub fn set_balloon_target(&self, bytes: u64) -> Result<(), VmError> {
+        self.control.with_hooks(Err(VmError::device("the VM has no balloon device")), |hooks| hooks.set_balloon_target(bytes))
+    }
+
+    /// Returns the size of the balloon as last reported by the guest, or `None` if
+    /// the VM has no balloon device.
+    pub fn get_balloon_size(&self) -> Option<u64> {
+        self.control.with_hooks(None, |hooks| hooks.get_balloon_size())
+    }
+
+    /// Starts recording every write the guest makes to the `len` bytes at guest
+    /// physical address `gpa`, see `introspection`. The range must be page aligned and
+    /// lie in guest RAM. Only the Windows Hypervisor Platform backend tracks writes.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the range is protected
+    /// * `Err(VmError)` if the range is invalid or overlaps a tracked one, the backend
+    ///   can't track writes or the VM isn't running yet
+    pub fn track_guest_writes(&self, gpa: u64, len: u64) -> Result<(), VmError> {
+        self.control.with_hooks(Err(VmError::hypervisor("the VM isn't running yet")), |hooks| hooks.track_guest_writes(gpa, len))
+    }
+
+    /// Stops recording the writes to the tracked range starting at `gpa`. Writes
+    /// recorded so far stay available to `take_guest_writes`.
+    pub fn untrack_guest_writes(&self, gpa: u64) -> Result<(), VmError> {
+        self.control.with_hooks(Err(VmError::hypervisor("the VM isn't running yet")), |hooks| hooks.untrack_guest_writes(gpa))
+    }
+
+    /// Returns the writes made to the tracked ranges since the last call.
+    pub fn take_guest_writes(&self) -> Result<GuestWriteLog, VmError> {
+        self.control.with_hooks(Err(VmError::hypervisor("the VM isn't running yet")), |hooks| hooks.take_guest_writes())
+    }
+
+    /// Copies the `len` bytes of guest RAM at guest physical address `gpa` without
+    /// pausing the VM, see `introspection`. Only the KVM backend reads guest memory.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` with the bytes on success
+    /// * `Err(VmError)` if the range isn't in guest RAM or is larger than
+    ///   `MAX_GUEST_READ_LEN`, the backend can't read guest memory or the VM isn't running yet
+    pub fn read_guest_memory(&self, gpa: u64, len: usize) -> Result<Vec<u8>, VmError> {
+        self.control.with_hooks(Err(VmError::hypervisor("the VM isn't running yet")), |hooks| hooks.read_guest_memory(gpa, len))
+    }
+
+    /// Returns the accesses the hypervisor lets the guest make to the page holding
+    /// `gpa`, or `None` if the page isn't guest RAM (a device or a hole).
+    pub fn get_page_permissions(&self, gpa: u64) -> Result<Option<PagePermissions>, VmError> {
+        self.control.with_hooks(Err(VmError::hypervisor("the VM isn't running yet")), |hooks| hooks.get_page_permissions(gpa))
+    }
+
+    /// Starts sampling where each vCPU runs in the guest every `interval`, see
+    /// `guest_profiler`. Must be called from within a Tokio runtime.
+    ///
+    /// Every sample kicks the vCPUs out of the guest, so short intervals slow it down.
+    /// No samples are taken while the VM is paused.
+    ///
+    /// # Returns
+    /// * `Ok(())` once sampling started
+    /// * `Err(VmError)` if `interval` is below `MIN_SAMPLE_INTERVAL`, the VM is stopped
+    ///   or already being profiled
+    pub fn start_profiling(&self, interval: Duration) -> Result<(), VmError> {
+        if interval < MIN_SAMPLE_INTERVAL {
+            return Err(VmError::config(format!("the sampling interval must be at least {:?}", MIN_SAMPLE_INTERVAL)));
+        }
+        if self.get_state() == VmState::Stopped {
+            return Err(VmError::config("cannot profile a stopped VM"));
+        }
+        let session = self.control.get_profiler().start()?;
+        let control = Arc::clone(&self.control);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                ticks.tick().await;
+                if !control.get_profiler().is_session_active(session) {
+                    break;
+                }
+                match control.get_state() {
+                    VmState::Stopped => break,
+                    VmState::Paused => continue,
+                    VmState::Running => {}
+                }
+                control.get_profiler().request_samples();
+                control.kick();
+            }
+        });
+        Ok(())
+    }
+
+    /// Stops sampling and returns the samples taken since `start_profiling`.
+    ///
+    /// # Returns
+    /// * `Ok(GuestProfile)` with the samples
+    /// * `Err(VmError)` if the VM isn't being profiled
+    pub fn stop_profiling(&self) -> Result<GuestProfile, VmError> {
+        self.control.get_profiler().stop()
+    }
+
+    /// Captures what the guest shows on its display, see `framebuffer`. Tests keep it
+    /// as evidence when the guest misbehaves.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` with the PNG image on success
+    /// * `Err(VmError)` if the VM has no display or isn't running yet
+    pub fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+        self.control.screenshot()
+    }
+
+    /// Starts writing a screenshot to `directory` every `interval`, see `screen_recorder`.
+    /// Must be called from within a Tokio runtime.
+    ///
+    /// # Returns
+    /// * `Ok(ScreenRecorder)` stopping the recording when stopped or dropped
+    /// * `Err(VmError)` if `interval` is below `MIN_RECORDING_INTERVAL`, the VM has no
+    ///   display or is stopped, or `directory` can't be created
+    pub fn start_screen_recording(&self, directory: impl Into<PathBuf>, interval: Duration) -> Result<ScreenRecorder, VmError> {
+        if interval < MIN_RECORDING_INTERVAL {
+            return Err(VmError::config(format!("the recording interval must be at least {:?}", MIN_RECORDING_INTERVAL)));
+        }
+        if self.get_state() == VmState::Stopped {
+            return Err(VmError::config("cannot record a stopped VM"));
+        }
+        // Fail now rather than in the recording task if there is nothing to capture
+        self.control.screenshot()?;
+        ScreenRecorder::start(Arc::clone(&self.control), directory.into(), interval)
+    }
+
+    /// Returns the host time spent serving the device exits of the vCPUs so far, by
+    /// device and kind of exit, the most expensive first. See `exit_latency`.
+    pub fn get_exit_latencies(&self) -> Vec<ExitLatency> {
+        self.control.get_exit_latencies().snapshot()
+    }
+
+    /// Returns the lines the guest printed on its serial console, from the first one
+    /// on and then as they come in, until the VM stopped. Tests use
+    /// `ConsoleLines::wait_for` to wait for a prompt.
+    ///
+    /// Only backends with a serial console record anything; on the others the lines
+    /// just end once the VM stopped.
+    pub fn console_lines(&self) -> ConsoleLines {
+        ConsoleLines::new(Arc::clone(self.control.get_console_log()))
+    }
+
+    /// Returns the panics caught in the vCPU loops so far. A vCPU that panicked is gone,
+    /// but the rest of the VM keeps running until stopped.
+    pub fn get_vcpu_failures(&self) -> Vec<VcpuFailure> {
+        self.control.get_vcpu_failures()
+    }
+
+    /// Waits until the guest finished booting, panicked or `timeout` expired.
+    ///
+    /// Milestones come from the guest's serial console output and the pvpanic port,
+    /// so a VM without a serial console only ever reports a panic. Time spent paused
+    /// counts towards the timeout.
+    ///
+    /// # Returns
+    /// * `BootOutcome::Booted` or `BootOutcome::Panicked` as soon as the guest gets there
+    /// * `BootOutcome::Stopped` if the VM stopped first
+    /// * `BootOutcome::StillBooting` or `BootOutcome::Hung` if the timeout expired, depending
+    ///   on whether the guest showed signs of life within `BOOT_HANG_THRESHOLD`
+    pub async fn wait_for_boot(&self, timeout: Duration) -> BootOutcome {
+        let progress = self.control.get_boot_progress();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(outcome) = progress.get_outcome(self.get_state() == VmState::Stopped) {
+                return outcome;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return progress.get_timeout_outcome();
+            }
+            tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + BOOT_POLL_INTERVAL)).await;
+        }
+    }
+
+    /// Waits for the VM to finish and returns its result.
+    ///
+    /// # Returns
+    /// * `Ok(VmExitSummary)` with how each vCPU exited if the VM ran (or was stopped) successfully
+    /// * `Err(VmError)` if the VM failed or its task panicked
+    pub async fn wait(self) -> Result<VmExitSummary, VmError> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(VmError::hypervisor_source(format!("VM task join error: {}", e), e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::vm_setup::boot_progress::BootStage;
+    use crate::vm_setup::exit_summary::join_vcpus;
+    use crate::vm_setup::guest_profiler::GuestMode;
+
+    struct CountingHooks {
+        kicks: Arc<AtomicUsize>,
+    }
+
+    impl RunControlHooks for CountingHooks {
+        fn kick(&self) {
+            self.kicks.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Helper: spawn a fake VM whose vCPUs loop on `wait_for_run` like a real backend
+    fn spawn_fake_vm(vcpus: usize, kicks: Arc<AtomicUsize>) -> VmHandle {
+        VmHandle::spawn(move |control| async move {
+            control.set_hooks(Box::new(CountingHooks { kicks }))?;
+            let mut handlers = Vec::new();
+            for vcpu in 0..vcpus as u32 {
+                let control = Arc::clone(&control);
+                handlers.push(tokio::task::spawn_blocking(move || {
+                    let _guard = control.enter_vcpu();
+                    while control.wait_for_run() {
+                        if control.get_profiler().take_request(vcpu) {
+                            control.get_profiler().record(vcpu, GuestMode::Kernel, 0x1000 * (vcpu as u64 + 1));
+                        }
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    Ok(VcpuExitReason::Stopped)
+                }));
+            }
+            Ok(VmExitSummary { vcpus: join_vcpus(handlers).await?, ..Default::default() })
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pause_resume_stop_lifecycle() {
+        let kicks = Arc::new(AtomicUsize::new(0));
+        let handle = spawn_fake_vm(2, Arc::clone(&kicks));
+        assert_eq!(handle.get_state(), VmState::Running);
+
+        handle.pause().await.unwrap();
+        assert_eq!(handle.get_state(), VmState::Paused);
+        assert!(handle.control.all_parked());
+
+        handle.resume().await.unwrap();
+        assert_eq!(handle.get_state(), VmState::Running);
+
+        handle.stop().await.unwrap();
+        assert_eq!(handle.get_state(), VmState::Stopped);
+        let summary = handle.wait().await.unwrap();
+        assert_eq!(summary.vcpus.len(), 2);
+        assert_eq!(summary.get_reason(), VcpuExitReason::Stopped);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stop_paused_vm() {
+        let handle = spawn_fake_vm(1, Arc::new(AtomicUsize::new(0)));
+        handle.pause().await.unwrap();
+        handle.stop().await.unwrap();
+        assert!(handle.wait().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_balloon_without_device() {
+        let handle = spawn_fake_vm(1, Arc::new(AtomicUsize::new(0)));
+        assert!(handle.set_balloon_target(64 << 20).unwrap_err().to_string().contains("no balloon device"));
+        assert_eq!(handle.get_balloon_size(), None);
+        handle.stop().await.unwrap();
+    }
+
+    // Hooks of a display showing a new image every `changes` screenshots
+    struct DisplayHooks {
+        screenshots: AtomicUsize,
+        changes: usize,
+    }
+
+    impl RunControlHooks for DisplayHooks {
+        fn kick(&self) {}
+
+        fn screenshot(&self) -> Result<Vec<u8>, VmError> {
+            let count = self.screenshots.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![(count / self.changes) as u8; 16])
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_screenshot_without_display() {
+        let handle = spawn_fake_vm(1, Arc::new(AtomicUsize::new(0)));
+        assert!(handle.screenshot().unwrap_err().to_string().contains("no display"));
+        let directory = tempfile::tempdir().unwrap();
+        assert!(handle.start_screen_recording(directory.path(), Duration::from_millis(10)).is_err());
+        handle.stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_screen_recording_skips_identical_frames() {
+        let handle = VmHandle::spawn(|control| async move {
+            control.set_hooks(Box::new(DisplayHooks { screenshots: AtomicUsize::new(0), changes: 3 }))?;
+            while control.get_state() != VmState::Stopped {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            Ok(VmExitSummary::default())
+        });
+        while handle.screenshot().is_err() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let directory = tempfile::tempdir().unwrap();
+        assert!(handle.start_screen_recording(directory.path(), Duration::ZERO).is_err());
+
+        let recorder = handle.start_screen_recording(directory.path().join("frames"), Duration::from_millis(10)).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let frames = recorder.stop().await.unwrap();
+        assert!(frames.len() >= 2);
+        for (index, frame) in frames.iter().enumerate() {
+            assert_eq!(frame, &directory.path().join("frames").join(format!("frame-{:05}.png", index)));
+            // Each frame differs from the one before it
+            let image = std::fs::read(frame).unwrap();
+            assert_eq!(image.len(), 16);
+            if index > 0 {
+                assert_ne!(image, std::fs::read(&frames[index - 1]).unwrap());
+            }
+        }
+
+        // Recording ends by itself once the VM stopped
+        let recorder = handle.start_screen_recording(directory.path().join("stopped"), Duration::from_millis(10)).unwrap();
+        handle.stop().await.unwrap();
+        assert!(recorder.stop().await.is_ok());
+        assert!(handle.start_screen_recording(directory.path(), Duration::from_millis(10)).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_profiling_samples_every_vcpu() {
+        let kicks = Arc::new(AtomicUsize::new(0));
+        let handle = spawn_fake_vm(2, Arc::clone(&kicks));
+        assert!(handle.start_profiling(Duration::ZERO).is_err());
+        assert!(handle.stop_profiling().is_err());
+
+        handle.start_profiling(Duration::from_millis(2)).unwrap();
+        assert!(handle.start_profiling(Duration::from_millis(2)).is_err());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let profile = handle.stop_profiling().unwrap();
+        assert!(kicks.load(Ordering::SeqCst) > 0);
+        assert!(profile.rounds > 0);
+        assert!(profile.entries.iter().any(|entry| entry.vcpu == 0 && entry.pc == 0x1000));
+        assert!(profile.entries.iter().any(|entry| entry.vcpu == 1 && entry.pc == 0x2000));
+
+        handle.stop().await.unwrap();
+        assert!(handle.start_profiling(Duration::from_millis(2)).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pause_finished_vm_fails() {
+        let handle = VmHandle::spawn(|_control| async { Ok(VmExitSummary::default()) });
+        while !handle.task.is_finished() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(handle.pause().await.is_err());
+        assert!(handle.resume().await.is_err());
+        assert!(handle.stop().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_for_boot() {
+        let handle = VmHandle::spawn(|control| async move {
+            let progress = Arc::clone(control.get_boot_progress());
+            progress.advance(BootStage::KernelStarted);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            progress.advance(BootStage::Booted);
+            while control.wait_for_run() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            Ok(VmExitSummary::default())
+        });
+        assert_eq!(handle.wait_for_boot(Duration::from_secs(5)).await, BootOutcome::Booted);
+        handle.stop().await.unwrap();
+        assert!(handle.wait().await.is_ok());
+
+        let handle = VmHandle::spawn(|control| async move {
+            control.get_boot_progress().advance(BootStage::InitStarted);
+            Ok(VmExitSummary::default())
+        });
+        assert_eq!(handle.wait_for_boot(Duration::from_secs(5)).await, BootOutcome::Stopped(BootStage::InitStarted));
+
+        let handle = spawn_fake_vm(1, Arc::new(AtomicUsize::new(0)));
+        assert_eq!(handle.wait_for_boot(Duration::from_millis(20)).await, BootOutcome::StillBooting(BootStage::NotStarted));
+        handle.stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_console_lines_end_when_vm_stops() {
+        let handle = VmHandle::spawn(|control| async move {
+            control.get_console_log().write(b"Welcome to Debian\ndebian login: ");
+            while control.wait_for_run() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            Ok(VmExitSummary::default())
+        });
+        let mut lines = handle.console_lines();
+        assert_eq!(lines.wait_for("login:").await.unwrap().text, "debian login: ");
+
+        handle.stop().await.unwrap();
+        handle.wait().await.unwrap();
+        assert_eq!(lines.next().await.unwrap().text, "debian login: ");
+        assert_eq!(lines.next().await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_vcpu_panic_is_isolated() {
+        let handle = VmHandle::spawn(|control| async move {
+            let mut handlers = Vec::new();
+            for vcpu in 0..2 {
+                let control = Arc::clone(&control);
+                handlers.push(tokio::task::spawn_blocking(move || {
+                    let _guard = control.enter_vcpu();
+                    control.catch_vcpu_panic(vcpu, || {
+                        if vcpu == 1 {
+                            panic!("range end index 4096 out of range");
+                        }
+                        while control.wait_for_run() {
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                        Ok(VcpuExitReason::Stopped)
+                    })
+                }));
+            }
+            let vcpus = join_vcpus(handlers).await?;
+            Ok(VmExitSummary { vcpus, failures: control.get_vcpu_failures(), ..Default::default() })
+        });
+        while handle.get_vcpu_failures().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // The remaining vCPU still obeys the handle
+        assert_eq!(handle.get_state(), VmState::Running);
+        handle.pause().await.unwrap();
+        handle.resume().await.unwrap();
+        handle.stop().await.unwrap();
+        let summary = handle.wait().await.unwrap();
+        assert_eq!(summary.vcpus[0].reason, VcpuExitReason::Stopped);
+        assert_eq!(summary.vcpus[1].reason, VcpuExitReason::Panicked);
+        assert_eq!(summary.get_reason(), VcpuExitReason::Panicked);
+        assert_eq!(summary.failures, vec![VcpuFailure { vcpu: 1, message: "range end index 4096 out of range".to_string() }]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_returns_vm_error() {
+        let handle = VmHandle::spawn(|_control| async { Err(VmError::hypervisor("boom")) });
+        let result = handle.wait().await;
+        assert_eq!(result.unwrap_err().to_string(), "boom");
+    }
+}
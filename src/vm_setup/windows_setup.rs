@@ -4,11 +4,32 @@ use windows::Win32::System::Hypervisor::{
     WHvMapGpaRangeFlagRead, WHvMapGpaRangeFlagWrite, WHvMapGpaRangeFlagExecute,
     WHvSetupPartition, WHvCreateVirtualProcessor, WHvRunVirtualProcessor,
     WHV_RUN_VP_EXIT_CONTEXT, WHV_PARTITION_HANDLE,
+    WHvRunVpExitReasonX64Halt, WHvRunVpExitReasonNone, WHvRunVpExitReasonMemoryAccess,
+    WHvRunVpExitReasonX64IoPortAccess, WHvRunVpExitReasonX64MsrAccess, WHvRunVpExitReasonX64Cpuid,
+    WHvRunVpExitReasonException, WHvRunVpExitReasonUnsupportedFeature, WHvRunVpExitReasonCanceled,
 };
 use crate::vm_setup::setup_utils::VmSetup;
+use crate::vm_setup::cpu_model::CpuModel;
+use crate::vm_setup::vm_handle::{VmHandle, VmControl, RunControlHooks};
+use crate::vm_setup::backend::{run_vcpus, DiskMetrics, HypervisorBackend, VcpuDevices, VcpuExit};
+use crate::vm_setup::exit_latency::ExitKind;
+use crate::vm_setup::exit_summary::VmExitSummary;
+use crate::vm_setup::introspection::{GuestWriteLog, WriteTracker};
+use crate::vm_setup::attachments::{check_attachments, Attachment};
+use crate::vm_setup::disk_setup::{open_cdrom_image, open_disk};
+use crate::vm_setup::memory_monitor::MemoryStatus;
+use crate::vm_setup::guest_profiler::GuestMode;
+use crate::device_emulation::block_device::virtio_block::{CacheMode, VirtioBlockDevice};
+use crate::device_emulation::mmio::MmioBus;
+use crate::device_emulation::pio::PortIoBus;
+use crate::device_emulation::virtio_mmio::{MmioTransport, VIRTIO_MMIO_SIZE};
+use crate::utils::signals::TriggerMode;
+use crate::utils::signals::windows::WhpInterrupt;
+use crate::error::VmError;
 use super::super::windows_bindings::*;
-use std::sync::Arc;
-use tokio::task;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vm_memory::{GuestMemory, GuestMemoryMmap};
 
 /// Asynchronously runs a virtual machine configured by `setup`.
 ///
@@ -22,104 +43,325 @@ use tokio::task;
 ///
 /// # Returns
 ///
-/// * `Ok(())` if the VM ran successfully (all vCPUs halted properly).
-/// * `Err(String)` if any step fails during partition creation, setup, memory allocation,
+/// * `Ok(VmExitSummary)` with how each vCPU exited if the VM ran successfully (all vCPUs halted properly).
+/// * `Err(VmError)` if any step fails during partition creation, setup, memory allocation,
 ///    vCPU creation, or execution.
 ///
 /// # Notes
 ///
 /// - Uses Windows Hypervisor Platform APIs to create and manage partitions and vCPUs.
-/// - Runs each virtual CPU on a separate blocking task using `tokio::task::spawn_blocking`.
+/// - Runs each virtual CPU on a separate blocking task through the exit loop shared
+///   with the other hypervisors, see `backend`.
 ///
-pub async fn run_vm(setup: VmSetup) -> Result<(), String> {
-    // 1. Create a new partition (virtual machine container)
-    let partition = match create_partition() {
-        Ok(p) => Arc::new(p),
-        Err(e) => return Err(format!("Partition creation failed: {:?}", e)),
-    };
-
-    // 2. Set the number of virtual processors for the partition
-    let processor_count = setup.get_cpu_cores_count() as u32;
-    if let Err(e) = set_processor_count_property(&partition, setup.get_cpu_cores_count()) {
-        return Err(format!("Failed to set processor count: {:?}", e));
+pub async fn run_vm(setup: VmSetup) -> Result<VmExitSummary, VmError> {
+    run_vm_with_control(setup, Arc::new(VmControl::new())).await
+}
+
+/// Starts the VM configured by `setup` in the background.
+///
+/// Must be called from within a Tokio runtime.
+///
+/// # Returns
+/// * A `VmHandle` to pause, resume, stop and wait for the VM
+pub fn spawn_vm(setup: VmSetup) -> VmHandle {
+    VmHandle::spawn(move |control| run_vm_with_control(setup, control))
+}
+
+/// WHP partition. It is its own run control: guest time is suspended while paused and
+/// vCPUs are kicked with `WHvCancelRunVirtualProcessor`. Guest writes are tracked by
+/// mapping the tracked ranges read-only.
+struct WhpBackend {
+    partition: Arc<Partition>,
+    /// Whether guest RAM should be backed by large pages, if no device shares it
+    use_large_pages: bool,
+    /// Whether virtio devices share guest RAM
+    has_block_devices: bool,
+    /// Guest RAM, unless it is backed by large pages
+    guest_memory: Option<GuestMemoryMmap>,
+    write_tracker: WriteTracker,
+    /// CPU model the CPUID exits are answered with
+    cpu_model: CpuModel,
+}
+
+/// vCPU of a WHP partition.
+struct WhpVcpu {
+    /// Completes the MMIO accesses and tracked writes of the vCPU
+    emulator: MmioEmulator,
+    /// Privilege level and instruction pointer of the last exit
+    last_position: Option<(GuestMode, u64)>,
+    /// Port access the exit loop is serving, completed before the vCPU runs again
+    pending_io: Option<PortIoAccess>,
+    /// Data of the pending port access
+    io_data: [u8; 4],
+}
+
+impl RunControlHooks for WhpBackend {
+    fn kick(&self) {
+        self.partition.cancel_vcpus();
     }
 
-    // 3. Setup the partition (apply all configured properties)
-    if let Err(e) = setup_partition(&partition) {
-        return Err(format!("Failed to setup partition: {:?}", e));
+    fn on_pause(&self) -> Result<(), VmError> {
+        self.partition.suspend()
     }
 
-    // 4. Allocate and map guest physical memory for the partition
-    if let Err(e) = allocate_partition_memory(&partition, setup.get_memory_size() as u64) {
-        return Err(format!("Failed to allocate and map guest memory: {:?}", e));
+    fn on_resume(&self) -> Result<(), VmError> {
+        self.partition.resume()
     }
 
-    // 5. Create and run virtual CPUs (vCPUs) concurrently, one per CPU core
-    let mut handlers: Vec<tokio::task::JoinHandle<Result<String, String>>> = Vec::new();
-    for cpu_id in 0..setup.get_cpu_cores_count() {
-        // Clone the partition handle for each task (handle is Copy)
-        let ph = Arc::clone(&partition);
-
-        // Spawn a blocking task for each vCPU to avoid blocking async runtime
-        handlers.push(task::spawn_blocking(move || -> Result<String, String> {
-            // Create the vCPU within the partition with the given CPU id
-            if let Err(e) = create_vcpu(&ph, cpu_id as u32) {
-                return Err(format!("Failed to create VCPU {}: {:?}", cpu_id, e));
-            };
-
-            // Enter an execution loop for this vCPU
-            loop {
-                // Run the vCPU until it exits for some reason
-                let exit_ctx = match run_vcpu(&ph, cpu_id) {
-                    Ok(exit_ctx) => exit_ctx,
-                    Err(e) => return Err(format!("VCPU {} failed to run: {:?}", cpu_id, e))
-                };
-
-                // Check the reason the vCPU stopped execution
-                match exit_ctx.ExitReason {
-                    WHvRunVpExitReasonX64Halt => {
-                        // VCPU executed HLT instruction; clean halt
-                        return Ok(format!("VCPU {} halted (HLT)", cpu_id))
-                    }
-                    WHvRunVpExitReasonNone => {
-                        // Invalid or unexpected exit state
-                        return Err(format!("VCPU {} exited with NONE (invalid state)", cpu_id))
-                    }
-                    WHvRunVpExitReasonMemoryAccess => {
-                        return Err(format!("VCPU {} memory access exit", cpu_id))
-                    }
-                    WHvRunVpExitReasonX64IoPortAccess => {
-                        return Err(format!("VCPU {} IO port access exit", cpu_id))
-                    }
-                    WHvRunVpExitReasonX64MsrAccess => {
-                        return Err(format!("VCPU {} MSR access exit", cpu_id))
-                    }
-                    WHvRunVpExitReasonX64Cpuid => {
-                        return Err(format!("VCPU {} CPUID exit (unhandled CPUID)", cpu_id))
-                    }
-                    WHvRunVpExitReasonException => {
-                        return Err(format!("VCPU {} caused exception", cpu_id))
-                    }
-                    WHvRunVpExitReasonUnsupportedFeature => {
-                        return Err(format!("VCPU {} unsupported feature exit", cpu_id))
-                    }
-                    other => {
-                        // Catch any other unknown exit reasons
-                        return Err(format!("VCPU {} unknown exit reason {:?}", cpu_id, other))
-                    }
+    fn track_guest_writes(&self, gpa: u64, len: u64) -> Result<(), VmError> {
+        let guest_memory = match &self.guest_memory {
+            Some(guest_memory) => guest_memory,
+            None => return Err(VmError::config("write tracking isn't available with large page backed guest RAM"))
+        };
+        let in_ram = len > 0 && gpa.checked_add(len - 1).is_some_and(|last| last <= guest_memory.last_addr().0);
+        if !in_ram {
+            return Err(VmError::config(format!("tracked range {:#x}+{:#x} isn't in guest RAM", gpa, len)));
+        }
+        self.write_tracker.add_range(gpa, len)?;
+        if let Err(e) = remap_guest_range(&self.partition, guest_memory, gpa, len, false) {
+            self.write_tracker.remove_range(gpa);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn untrack_guest_writes(&self, gpa: u64) -> Result<(), VmError> {
+        let len = match self.write_tracker.remove_range(gpa) {
+            Some(len) => len,
+            None => return Err(VmError::config(format!("no tracked range starts at {:#x}", gpa)))
+        };
+        match &self.guest_memory {
+            Some(guest_memory) => remap_guest_range(&self.partition, guest_memory, gpa, len, true),
+            None => Ok(())
+        }
+    }
+
+    fn take_guest_writes(&self) -> Result<GuestWriteLog, VmError> {
+        Ok(self.write_tracker.take_writes())
+    }
+}
+
+impl HypervisorBackend for WhpBackend {
+    type Vcpu = WhpVcpu;
+    type Memory = Option<GuestMemoryMmap>;
+
+    fn create_vm(setup: &VmSetup) -> Result<Self, VmError> {
+        let has_block_devices = !setup.get_disks().is_empty() || setup.get_cdrom_image().is_some();
+
+        // 1. Create a new partition (virtual machine container)
+        let partition = match create_partition() {
+            Ok(p) => Arc::new(p),
+            Err(e) => return Err(VmError::hypervisor_source(format!("Partition creation failed: {}", e), e)),
+        };
+
+        // 2. Set the number of virtual processors for the partition
+        if let Err(e) = set_processor_count_property(&partition, setup.get_cpu_cores_count()) {
+            return Err(e);
+        }
+
+        // Restrict the CPUID seen by the guest to the configured CPU model
+        set_cpuid_result_list(&partition, setup.get_cpu_model())?;
+        // and answer the leaves telling the guest it runs under this VMM ourselves
+        set_cpuid_exit_list(&partition, &CPUID_EXIT_LEAVES)?;
+
+        // Virtio devices interrupt the guest through the emulated local APIC
+        if has_block_devices {
+            set_local_apic_emulation(&partition)?;
+        }
+
+        // 3. Setup the partition (apply all configured properties)
+        if let Err(e) = setup_partition(&partition) {
+            return Err(VmError::hypervisor_source(format!("Failed to setup partition: {}", e), e));
+        }
+
+        Ok(WhpBackend {
+            partition,
+            use_large_pages: setup.get_use_large_pages(),
+            has_block_devices,
+            guest_memory: None,
+            write_tracker: WriteTracker::new(),
+            cpu_model: setup.get_cpu_model().clone(),
+        })
+    }
+
+    fn map_memory(&mut self, gpa: u64, size: usize) -> Result<Option<GuestMemoryMmap>, VmError> {
+        // Devices and write tracking reach guest RAM through a GuestMemoryMmap, which
+        // large page allocations can't be wrapped in
+        if self.use_large_pages && !self.has_block_devices {
+            if gpa != 0 {
+                return Err(VmError::memory(format!("guest RAM backed by large pages must start at 0 on WHP, not {:#x}", gpa)));
+            }
+            if let Err(e) = allocate_partition_memory_with_backing(&self.partition, size as u64, true) {
+                return Err(VmError::memory_source(format!("Failed to allocate and map guest memory: {}", e), e));
+            }
+            return Ok(None);
+        }
+        if self.use_large_pages {
+            eprintln!("Large pages can't back memory shared with emulated devices, falling back to standard pages");
+        }
+        let guest_memory = allocate_guest_memory(&self.partition, gpa, size as u64)?;
+        self.guest_memory = Some(guest_memory.clone());
+        Ok(Some(guest_memory))
+    }
+
+    fn create_vcpu(&self, index: u32) -> Result<WhpVcpu, VmError> {
+        // Create the vCPU within the partition with the given CPU id
+        if let Err(e) = create_vcpu(&self.partition, index) {
+            return Err(VmError::hypervisor_source(format!("Failed to create VCPU {}: {}", index, e), e));
+        };
+        // Start in real mode at the bottom of guest RAM rather than at the reset vector,
+        // which no memory backs
+        set_vcpu_registers(&self.partition, index, &X64Registers::real_mode_entry(0, 0))?;
+        Ok(WhpVcpu { emulator: MmioEmulator::new()?, last_position: None, pending_io: None, io_data: [0; 4] })
+    }
+
+    fn run_vcpu<'a>(&self, vcpu: &'a mut WhpVcpu, cpu_id: u32, devices: &VcpuDevices) -> Result<VcpuExit<'a>, VmError> {
+        self.partition.wait_while_suspended();
+
+        // Hand the guest the result of the port access the loop served since the last exit
+        if let Some(access) = vcpu.pending_io.take() {
+            complete_port_io(&self.partition, cpu_id, &access, access.get_completed_rax(&vcpu.io_data[..access.size]))?;
+        }
+
+        // Run the vCPU until it exits for some reason
+        let exit_ctx = match run_vcpu(&self.partition, cpu_id) {
+            Ok(exit_ctx) => exit_ctx,
+            Err(e) => return Err(VmError::hypervisor_source(format!("VCPU {} failed to run: {}", cpu_id, e), e))
+        };
+        // The exit context tells where the guest was, which is all the profiler needs
+        let mode = if exit_ctx.VpContext.Cs.Selector & 0x3 == 0 { GuestMode::Kernel } else { GuestMode::User };
+        vcpu.last_position = Some((mode, exit_ctx.VpContext.Rip));
+
+        // Check the reason the vCPU stopped execution
+        match exit_ctx.ExitReason {
+            WHvRunVpExitReasonCanceled => {
+                // Run was cancelled by a pause or stop request
+                Ok(VcpuExit::Canceled)
+            }
+            WHvRunVpExitReasonX64Halt => {
+                // VCPU executed HLT instruction; clean halt
+                Ok(VcpuExit::Hlt)
+            }
+            WHvRunVpExitReasonNone => {
+                // Invalid or unexpected exit state
+                Ok(VcpuExit::Exception { reason: "exited with NONE (invalid state)".to_string() })
+            }
+            WHvRunVpExitReasonMemoryAccess => {
+                // Access to a device register or a write to a tracked range; WHP's emulator
+                // completes it through the bus before the guest resumes
+                let tracked = self.guest_memory.as_ref().map(|memory| (memory, &self.write_tracker));
+                let started = Instant::now();
+                if let Err(e) = vcpu.emulator.emulate(&self.partition, cpu_id, &exit_ctx, &devices.mmio_bus, tracked) {
+                    return Err(VmError::hypervisor_source(format!("VCPU {} memory access exit: {}", cpu_id, e), e))
                 }
+                // SAFETY: the exit reason is WHvRunVpExitReasonMemoryAccess, so MemoryAccess is the active field
+                let gpa = unsafe { exit_ctx.Anonymous.MemoryAccess.Gpa };
+                devices.record_latency(cpu_id, devices.mmio_bus.get_name(gpa, 1), ExitKind::EmulatedMmio, started);
+                Ok(VcpuExit::Handled)
+            }
+            WHvRunVpExitReasonX64IoPortAccess => {
+                // IN or OUT, which the loop serves on the port bus
+                let access = PortIoAccess::from_exit(&exit_ctx);
+                if access.is_string {
+                    return Ok(VcpuExit::Exception { reason: format!("used string port I/O on port {:#x}, which isn't supported", access.port) });
+                }
+                if !matches!(access.size, 1 | 2 | 4) {
+                    return Ok(VcpuExit::Exception { reason: format!("accessed port {:#x} with an invalid size of {} bytes", access.port, access.size) });
+                }
+                vcpu.pending_io = Some(access);
+                if access.is_write {
+                    vcpu.io_data = access.get_write_data();
+                    Ok(VcpuExit::PioOut(access.port, &vcpu.io_data[..access.size]))
+                } else {
+                    Ok(VcpuExit::PioIn(access.port, &mut vcpu.io_data[..access.size]))
+                }
+            }
+            WHvRunVpExitReasonX64MsrAccess => {
+                Ok(VcpuExit::Exception { reason: "MSR access exit".to_string() })
             }
-        }));
+            WHvRunVpExitReasonX64Cpuid => {
+                // One of CPUID_EXIT_LEAVES, answered from the CPU model
+                complete_cpuid(&self.partition, cpu_id, &exit_ctx, &self.cpu_model)?;
+                Ok(VcpuExit::Handled)
+            }
+            WHvRunVpExitReasonException => {
+                Ok(VcpuExit::Exception { reason: "caused exception".to_string() })
+            }
+            WHvRunVpExitReasonUnsupportedFeature => {
+                Ok(VcpuExit::Exception { reason: "unsupported feature exit".to_string() })
+            }
+            other => {
+                // Catch any other unknown exit reasons
+                Ok(VcpuExit::Exception { reason: format!("unknown exit reason {:?}", other) })
+            }
+        }
+    }
+
+    fn get_guest_position(&self, vcpu: &WhpVcpu, _index: u32) -> Result<Option<(GuestMode, u64)>, VmError> {
+        Ok(vcpu.last_position)
+    }
+
+    fn get_host_memory() -> Result<MemoryStatus, VmError> {
+        let (total, available) = get_physical_memory_info()?;
+        Ok(MemoryStatus { total, available })
     }
+}
+
+/// Runs the VM configured by `setup`, with its vCPUs obeying `control`.
+async fn run_vm_with_control(setup: VmSetup, control: Arc<VmControl>) -> Result<VmExitSummary, VmError> {
+    // 0. Refuse attachments this backend can't provide yet
+    check_attachments(&setup, "Windows Hypervisor Platform", &[Attachment::DiskImage, Attachment::CdromImage])?;
+
+    // 1-3. Create, size and set up the partition
+    let mut backend = WhpBackend::create_vm(&setup)?;
+
+    // 4. Allocate and map guest physical memory for the partition
+    let guest_memory = backend.map_memory(0, setup.get_memory_size())?;
 
-    // Await all vCPU tasks and collect their results
-    for h in handlers {
-        match h.await {
-            Ok(Ok(msg)) => println!("Success: {}", msg), // Task succeeded, vCPU halted properly
-            Ok(Err(err)) => return Err(err),             // Task returned an error from vCPU execution
-            Err(e) => return Err(format!("Task join error: {}", e)), // Tokio task join error
+    let mut mmio_bus = MmioBus::new();
+    let mut disk_metrics: DiskMetrics = Vec::new();
+    if let Some(guest_memory) = &guest_memory {
+        // Attach the disks in order, then the CD-ROM, as virtio-mmio block devices
+        let mut block_images = Vec::new();
+        for disk in setup.get_disks() {
+            block_images.push((open_disk(disk)?, disk.get_cache_mode(), disk.is_read_only(), disk.get_poll_window()));
+        }
+        if let Some(path) = setup.get_cdrom_image() {
+            block_images.push((open_cdrom_image(&path.to_string_lossy())?, CacheMode::default(), true, Duration::ZERO));
+        }
+        for (index, (disk_image, cache_mode, read_only, poll_window)) in block_images.into_iter().enumerate() {
+            let name = format!("virtio-blk{}", index);
+            let vector = VIRTIO_BLK_VECTOR_BASE + index as u32;
+            let interrupt = WhpInterrupt::new(Arc::clone(&backend.partition), vector, BOOT_APIC_ID, TriggerMode::Level)?;
+            let block_device = VirtioBlockDevice::with_storage(guest_memory.clone(), disk_image, setup.get_virtqueue_config())?;
+            block_device.set_cache_mode(cache_mode);
+            block_device.set_read_only(read_only);
+            block_device.set_poll_window(poll_window);
+            let mut transport = MmioTransport::new(block_device, Box::new(interrupt));
+            let notifier = transport.get_vring_notifier();
+            transport.get_device_mut().start_io_worker(&format!("{}-io", name), notifier)?;
+            let transport = Arc::new(Mutex::new(transport));
+            let base = VIRTIO_BLK_MMIO_BASE + index as u64 * VIRTIO_MMIO_SIZE;
+            mmio_bus.register(base, VIRTIO_MMIO_SIZE, transport.clone())?;
+            mmio_bus.set_name(base, &name);
+            disk_metrics.push((name, Box::new(move || transport.lock().unwrap_or_else(|e| e.into_inner()).get_device().metrics())));
         }
     }
+    let devices = Arc::new(VcpuDevices {
+        mmio_bus,
+        pio_bus: PortIoBus::new(),
+        recorder: None,
+        latencies: Arc::clone(control.get_exit_latencies()),
+    });
 
-    Ok(())
+    // 5. Create and run virtual CPUs (vCPUs) concurrently, one per CPU core
+    run_vcpus(Arc::new(backend), control, setup.get_cpu_cores_count(), devices, disk_metrics).await
 }
+
+/// Guest physical address of the registers of the first virtio-mmio block device; the
+/// next ones follow it. Same layout as the KVM backend.
+const VIRTIO_BLK_MMIO_BASE: u64 = 0xd000_0000;
+/// Interrupt vector of the first virtio-mmio block device; the next ones follow it.
+const VIRTIO_BLK_VECTOR_BASE: u32 = 0x30;
+/// APIC id of the boot vCPU, which device interrupts are delivered to.
+const BOOT_APIC_ID: u32 = 0;
@@ -1,14 +1,99 @@
 use windows::Win32::System::Hypervisor::{
     WHvCreatePartition, WHvDeletePartition, WHvSetPartitionProperty,
     WHV_PARTITION_HANDLE, WHvPartitionPropertyCodeProcessorCount,
-    WHvMapGpaRange, WHV_MAP_GPA_RANGE_FLAGS,
+    WHvMapGpaRange, WHvUnmapGpaRange, WHV_MAP_GPA_RANGE_FLAGS,
     WHvMapGpaRangeFlagRead, WHvMapGpaRangeFlagWrite, WHvMapGpaRangeFlagExecute,
     WHvSetupPartition, WHvCreateVirtualProcessor, WHvRunVirtualProcessor,
-    WHV_RUN_VP_EXIT_CONTEXT
+    WHV_RUN_VP_EXIT_CONTEXT, WHvSuspendPartitionTime, WHvResumePartitionTime,
+    WHvCancelRunVirtualProcessor, WHV_X64_CPUID_RESULT, WHvPartitionPropertyCodeCpuidResultList,
+    WHvPartitionPropertyCodeCpuidExitList,
+    WHvRequestInterrupt, WHV_INTERRUPT_CONTROL, WHvX64InterruptTypeFixed,
+    WHvX64InterruptDestinationModePhysical, WHvX64InterruptTriggerModeEdge, WHvX64InterruptTriggerModeLevel,
+    WHvPartitionPropertyCodeLocalApicEmulationMode, WHvX64LocalApicEmulationModeXApic, WHV_X64_LOCAL_APIC_EMULATION_MODE,
+    WHvEmulatorCreateEmulator, WHvEmulatorDestroyEmulator, WHvEmulatorTryMmioEmulation, WHV_EMULATOR_CALLBACKS,
+    WHV_EMULATOR_MEMORY_ACCESS_INFO, WHV_EMULATOR_IO_ACCESS_INFO, WHvGetVirtualProcessorRegisters,
+    WHvSetVirtualProcessorRegisters, WHvTranslateGva, WHV_REGISTER_NAME, WHV_REGISTER_VALUE,
+    WHV_TRANSLATE_GVA_FLAGS, WHV_TRANSLATE_GVA_RESULT, WHV_TRANSLATE_GVA_RESULT_CODE,
+    WHV_X64_SEGMENT_REGISTER, WHV_X64_SEGMENT_REGISTER_0, WHV_X64_TABLE_REGISTER,
+    WHvX64RegisterRax, WHvX64RegisterRcx, WHvX64RegisterRdx, WHvX64RegisterRbx,
+    WHvX64RegisterRsp, WHvX64RegisterRbp, WHvX64RegisterRsi, WHvX64RegisterRdi,
+    WHvX64RegisterR8, WHvX64RegisterR9, WHvX64RegisterR10, WHvX64RegisterR11,
+    WHvX64RegisterR12, WHvX64RegisterR13, WHvX64RegisterR14, WHvX64RegisterR15,
+    WHvX64RegisterRip, WHvX64RegisterRflags, WHvX64RegisterCs, WHvX64RegisterDs,
+    WHvX64RegisterEs, WHvX64RegisterFs, WHvX64RegisterGs, WHvX64RegisterSs,
+    WHvX64RegisterTr, WHvX64RegisterLdtr, WHvX64RegisterGdtr, WHvX64RegisterIdtr,
+    WHvX64RegisterCr0, WHvX64RegisterCr2, WHvX64RegisterCr3, WHvX64RegisterCr4, WHvX64RegisterEfer,
 };
 use windows::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
-use windows::core::HRESULT;
-use windows::Win32::System::Memory::{VirtualAlloc, MEM_COMMIT, MEM_RESERVE, PAGE_READWRITE};
+use windows::core::{HRESULT, PCWSTR};
+use windows::Win32::System::Memory::{VirtualAlloc, VirtualFree, GetLargePageMinimum, MEM_COMMIT, MEM_RESERVE, MEM_RELEASE, MEM_LARGE_PAGES, PAGE_READWRITE};
+use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+use windows::Win32::Security::{
+    AdjustTokenPrivileges, LookupPrivilegeValueW, TOKEN_PRIVILEGES, LUID_AND_ATTRIBUTES,
+    SE_LOCK_MEMORY_NAME, SE_PRIVILEGE_ENABLED, TOKEN_ADJUST_PRIVILEGES, TOKEN_QUERY,
+};
+use windows::Win32::Foundation::{CloseHandle, GetLastError, HANDLE, LUID, ERROR_NOT_ALL_ASSIGNED, E_FAIL, E_NOTIMPL, S_OK};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use crate::device_emulation::mmio::MmioBus;
+use crate::vm_setup::introspection::{GuestWrite, WriteTracker};
+use crate::error::VmError;
+use crate::vm_setup::cpu_model::{CpuModel, CpuidEntry, HYPERVISOR_CPUID_LEAF};
+use std::sync::{Condvar, Mutex};
+
+/// Kind of host memory backing the guest RAM of a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBacking {
+    /// Regular pageable 4 KiB pages.
+    StandardPages,
+    /// Locked large pages allocated with `MEM_LARGE_PAGES`.
+    LargePages,
+}
+
+/// Start of the hole below 4 GiB left to device registers, where no guest RAM is mapped.
+pub const PCI_HOLE_START: u64 = 0xc000_0000;
+/// End of the hole below 4 GiB, where guest RAM reaching the hole continues.
+pub const PCI_HOLE_END: u64 = 0x1_0000_0000;
+/// Granularity of the guest physical ranges WHP maps.
+const GPA_PAGE_SIZE: u64 = 0x1000;
+
+/// Host memory mapped into the guest physical address space of a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// Guest physical address of the region
+    pub gpa: u64,
+    /// Size of the region in bytes
+    pub size: u64,
+    /// Host virtual address of the memory backing the region
+    pub host_address: u64,
+}
+
+impl MemoryRegion {
+    /// Returns `true` if the region shares a byte with `size` bytes at `gpa`.
+    fn overlaps(&self, gpa: u64, size: u64) -> bool {
+        gpa < self.gpa + self.size && self.gpa < gpa + size
+    }
+}
+
+/// Returns the guest physical ranges, as `(gpa, size)`, of `size` bytes of guest RAM
+/// starting at `gpa`, laid out like on x86 hardware: RAM reaching `PCI_HOLE_START`
+/// continues at `PCI_HOLE_END`, leaving the hole to device registers.
+///
+/// # Returns
+/// * `Ok(Vec<(u64, u64)>)` with the low range, then the high one if any
+/// * `Err(VmError)` if `gpa` or `size` isn't page-aligned or `gpa` lies in the hole
+pub fn get_guest_ram_ranges(gpa: u64, size: u64) -> Result<Vec<(u64, u64)>, VmError> {
+    if gpa % GPA_PAGE_SIZE != 0 || size % GPA_PAGE_SIZE != 0 || size == 0 {
+        return Err(VmError::memory(format!("guest RAM of {:#x} bytes at {:#x} isn't a non-empty range of whole pages", size, gpa)));
+    }
+    if (PCI_HOLE_START..PCI_HOLE_END).contains(&gpa) {
+        return Err(VmError::memory(format!("guest RAM can't start at {:#x}, in the PCI hole {:#x}-{:#x}", gpa, PCI_HOLE_START, PCI_HOLE_END)));
+    }
+    if gpa >= PCI_HOLE_END || gpa + size <= PCI_HOLE_START {
+        return Ok(vec![(gpa, size)]);
+    }
+    let low_size = PCI_HOLE_START - gpa;
+    Ok(vec![(gpa, low_size), (PCI_HOLE_END, size - low_size)])
+}
 
 /// A safe wrapper around a WHV_PARTITION_HANDLE.
 ///
@@ -17,6 +102,16 @@ use windows::Win32::System::Memory::{VirtualAlloc, MEM_COMMIT, MEM_RESERVE, PAGE
 pub struct Partition {
     // The raw hypervisor partition handle.
     partition: WHV_PARTITION_HANDLE,
+    // Ids of the virtual processors created in the partition.
+    vcpus: Mutex<Vec<u32>>,
+    // Whether the partition is currently suspended.
+    suspended: Mutex<bool>,
+    // Wakes vCPU threads parked in `wait_while_suspended` on resume.
+    resumed: Condvar,
+    // Host memory mapped into the guest, by ascending guest physical address.
+    regions: Mutex<Vec<MemoryRegion>>,
+    // Host addresses of the guest RAM allocated by `allocate_partition_memory`, freed on drop.
+    allocations: Mutex<Vec<u64>>,
 }
 
 impl Partition {
@@ -26,7 +121,14 @@ impl Partition {
     /// The caller must ensure that the handle is valid and not used elsewhere.
     /// This struct assumes ownership and will delete the partition on drop.
     pub fn new(partition: WHV_PARTITION_HANDLE) -> Self {
-        Partition { partition }
+        Partition {
+            partition,
+            vcpus: Mutex::new(Vec::new()),
+            suspended: Mutex::new(false),
+            resumed: Condvar::new(),
+            regions: Mutex::new(Vec::new()),
+            allocations: Mutex::new(Vec::new()),
+        }
     }
 
     /// Returns the raw `WHV_PARTITION_HANDLE` for use with FFI functions.
@@ -36,6 +138,134 @@ impl Partition {
     pub fn get_whv_partition_handle(&self) -> WHV_PARTITION_HANDLE {
         self.partition
     }
+
+    /// Suspends the partition.
+    ///
+    /// Guest time is frozen with `WHvSuspendPartitionTime` and every vCPU currently
+    /// inside `WHvRunVirtualProcessor` is kicked out with `WHvCancelRunVirtualProcessor`.
+    /// vCPU loops are expected to call `wait_while_suspended` before re-entering the
+    /// guest so they stay parked until `resume` is called.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success or if the partition is already suspended
+    /// * `Err(VmError)` if the hypervisor rejected the request
+    pub fn suspend(&self) -> Result<(), VmError> {
+        let mut suspended = self.suspended.lock().unwrap_or_else(|e| e.into_inner());
+        if *suspended {
+            return Ok(());
+        }
+
+        if let Err(e) = unsafe { WHvSuspendPartitionTime(self.partition) } {
+            return Err(VmError::hypervisor_source(format!("Failed to suspend partition time: {:?}", e), e));
+        }
+        *suspended = true;
+        drop(suspended);
+
+        self.cancel_vcpus();
+        Ok(())
+    }
+
+    /// Makes every vCPU currently inside `WHvRunVirtualProcessor` return with a
+    /// `WHvRunVpExitReasonCanceled` exit.
+    pub fn cancel_vcpus(&self) {
+        let vcpus = self.vcpus.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for cpu_id in vcpus {
+            // A vCPU that is not running at the moment has nothing to cancel, so the
+            // call may fail harmlessly; it will check its run state before the next run.
+            let _ = unsafe { WHvCancelRunVirtualProcessor(self.partition, cpu_id, 0) };
+        }
+    }
+
+    /// Resumes a partition previously suspended with `suspend`.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success or if the partition is not suspended
+    /// * `Err(VmError)` if the hypervisor rejected the request
+    pub fn resume(&self) -> Result<(), VmError> {
+        let mut suspended = self.suspended.lock().unwrap_or_else(|e| e.into_inner());
+        if !*suspended {
+            return Ok(());
+        }
+
+        if let Err(e) = unsafe { WHvResumePartitionTime(self.partition) } {
+            return Err(VmError::hypervisor_source(format!("Failed to resume partition time: {:?}", e), e));
+        }
+        *suspended = false;
+        self.resumed.notify_all();
+
+        Ok(())
+    }
+
+    /// Returns `true` while the partition is suspended.
+    pub fn is_suspended(&self) -> bool {
+        *self.suspended.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the host memory mapped into the guest, by ascending guest physical address.
+    pub fn get_memory_regions(&self) -> Vec<MemoryRegion> {
+        self.regions.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Copies `data` into guest physical memory at `gpa`, e.g. to load boot code, a
+    /// kernel or firmware before the vCPUs run. The range may span several regions but
+    /// every byte of it must be mapped.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if part of the range isn't backed by a memory region
+    pub fn write_guest_memory(&self, gpa: u64, data: &[u8]) -> Result<(), VmError> {
+        self.access_guest_memory(gpa, data.len(), |host, offset, len| {
+            // SAFETY: the region is mapped, so its host memory is valid, and `offset + len`
+            // is within `data`
+            unsafe { std::ptr::copy_nonoverlapping(data[offset..].as_ptr(), host, len) };
+        })
+    }
+
+    /// Copies guest physical memory at `gpa` into `data`. The range may span several
+    /// regions but every byte of it must be mapped.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success
+    /// * `Err(VmError)` if part of the range isn't backed by a memory region
+    pub fn read_guest_memory(&self, gpa: u64, data: &mut [u8]) -> Result<(), VmError> {
+        self.access_guest_memory(gpa, data.len(), |host, offset, len| {
+            // SAFETY: as in `write_guest_memory`
+            unsafe { std::ptr::copy_nonoverlapping(host, data[offset..].as_mut_ptr(), len) };
+        })
+    }
+
+    /// Splits the `len` bytes at `gpa` along the memory regions and calls `copy` with the
+    /// host address, offset in the range and length of each piece. Holds the region list
+    /// so no region is unmapped meanwhile; nothing is copied unless the whole range is mapped.
+    fn access_guest_memory(&self, gpa: u64, len: usize, mut copy: impl FnMut(*mut u8, usize, usize)) -> Result<(), VmError> {
+        let Some(end) = gpa.checked_add(len as u64) else {
+            return Err(VmError::memory(format!("guest memory range of {:#x} bytes at {:#x} overflows", len, gpa)));
+        };
+        let regions = self.regions.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pieces = Vec::new();
+        let mut addr = gpa;
+        while addr < end {
+            let region = match regions.iter().find(|region| region.overlaps(addr, 1)) {
+                Some(region) => region,
+                None => return Err(VmError::memory(format!("guest physical address {:#x} isn't backed by memory", addr)))
+            };
+            let piece_end = end.min(region.gpa + region.size);
+            pieces.push((region.host_address + (addr - region.gpa), (addr - gpa) as usize, (piece_end - addr) as usize));
+            addr = piece_end;
+        }
+        for (host_address, offset, piece_len) in pieces {
+            copy(host_address as *mut u8, offset, piece_len);
+        }
+        Ok(())
+    }
+
+    /// Blocks the calling vCPU thread until the partition is no longer suspended.
+    pub fn wait_while_suspended(&self) {
+        let mut suspended = self.suspended.lock().unwrap_or_else(|e| e.into_inner());
+        while *suspended {
+            suspended = self.resumed.wait(suspended).unwrap_or_else(|e| e.into_inner());
+        }
+    }
 }
 
 impl Drop for Partition {
@@ -45,12 +275,18 @@ impl Drop for Partition {
     fn drop(&mut self) {
         // SAFETY: This is safe because we own the handle and Drop is only called once.
         delete_partition(self.partition);
+        // The guest is gone, so nothing maps the memory allocated for it anymore
+        for host_address in self.allocations.get_mut().unwrap_or_else(|e| e.into_inner()).drain(..) {
+            if let Err(e) = unsafe { VirtualFree(host_address as *mut _, 0, MEM_RELEASE) } {
+                eprintln!("Failed to free guest memory at {:#x}: {:?}", host_address, e);
+            }
+        }
     }
 }
 
 /// Retrieves total and available physical memory on the host system.
 /// Returns a tuple: (total_physical_memory_bytes, available_physical_memory_bytes)
-fn get_physical_memory_info() -> Result<(u64, u64), String> {
+pub fn get_physical_memory_info() -> Result<(u64, u64), VmError> {
     unsafe {
         // Initialize MEMORYSTATUSEX struct with its size
         let mut mem_status = MEMORYSTATUSEX::default();
@@ -59,27 +295,27 @@ fn get_physical_memory_info() -> Result<(u64, u64), String> {
         // Call GlobalMemoryStatusEx to fill mem_status with memory info
         match GlobalMemoryStatusEx(&mut mem_status) {
             Ok(_) => Ok((mem_status.ullTotalPhys, mem_status.ullAvailPhys)),
-            Err(e) => Err(format!("{:?}", e))
+            Err(e) => Err(VmError::memory_source(format!("Failed to query host memory status: {:?}", e), e))
         }
     }
 }
 
 /// Creates a new Hyper-V partition and returns Partition instance with its handle.
-/// On failure, returns an error string.
-pub fn create_partition() -> Result<Partition, String> {
+/// On failure, returns a VmError.
+pub fn create_partition() -> Result<Partition, VmError> {
     match unsafe { WHvCreatePartition() } {
         Ok(handle) => Ok(Partition::new(handle)),
-        Err(e) => Err(format!("{:?}", e))
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to create partition: {:?}", e), e))
     }
 }
 
 /// Sets the processor count property for a given partition.
 /// Valid processor_count range is 1 to 64 (inclusive).
-/// Returns Ok on success or an error string on failure.
-pub fn set_processor_count_property(partition: &Partition, processor_count: u32) -> Result<(), String> {
+/// Returns Ok on success or a VmError on failure.
+pub fn set_processor_count_property(partition: &Partition, processor_count: u32) -> Result<(), VmError> {
     // Validate processor count
     if processor_count == 0 || processor_count > 64 {
-        return Err(format!("Failed to set processor count: processor_count equal to {}", processor_count));
+        return Err(VmError::config(format!("Failed to set processor count: processor_count equal to {}", processor_count)));
     }
     // Attempt to set the property on the partition
     if let Err(e) = unsafe {
@@ -90,17 +326,109 @@ pub fn set_processor_count_property(partition: &Partition, processor_count: u32)
             std::mem::size_of::<u32>() as u32,
         )
     } {
-        return Err(format!("{:?}", e));
+        return Err(VmError::hypervisor_source(format!("Failed to set processor count: {:?}", e), e));
+    }
+
+    Ok(())
+}
+
+/// Installs the CPUID results of `cpu_model` on the partition.
+///
+/// The managed CPUID leaves are read from the host, filtered through the model and
+/// registered as the partition's CPUID result list. Must be called before
+/// `setup_partition`. `CpuModel::HostPassthrough` leaves the partition untouched.
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the host lacks features of the model or the property can't be set
+pub fn set_cpuid_result_list(partition: &Partition, cpu_model: &CpuModel) -> Result<(), VmError> {
+    if *cpu_model == CpuModel::HostPassthrough {
+        return Ok(());
+    }
+
+    let host_entries: Vec<CpuidEntry> = CpuModel::get_filtered_leaves().into_iter().map(|leaf| {
+        let result = core::arch::x86_64::__cpuid_count(leaf, 0);
+        CpuidEntry { function: leaf, index: 0, eax: result.eax, ebx: result.ebx, ecx: result.ecx, edx: result.edx }
+    }).collect();
+
+    let missing = cpu_model.get_missing_features(&host_entries);
+    if !missing.is_empty() {
+        let names: Vec<&str> = missing.iter().map(|f| f.name()).collect();
+        return Err(VmError::config(format!("CPU model {:?} needs features missing on this host: {}", cpu_model, names.join(", "))));
+    }
+
+    let results: Vec<WHV_X64_CPUID_RESULT> = host_entries.into_iter().map(|mut entry| {
+        cpu_model.apply(&mut entry);
+        WHV_X64_CPUID_RESULT {
+            Function: entry.function,
+            Reserved: [0; 3],
+            Eax: entry.eax,
+            Ebx: entry.ebx,
+            Ecx: entry.ecx,
+            Edx: entry.edx,
+        }
+    }).collect();
+
+    if let Err(e) = unsafe {
+        WHvSetPartitionProperty(
+            partition.get_whv_partition_handle(),
+            WHvPartitionPropertyCodeCpuidResultList,
+            results.as_ptr() as *const _,
+            std::mem::size_of_val(results.as_slice()) as u32,
+        )
+    } {
+        return Err(VmError::hypervisor_source(format!("Failed to set CPUID result list: {:?}", e), e));
+    }
+
+    Ok(())
+}
+
+/// CPUID leaves answered through `complete_cpuid` instead of the result list: leaf 1
+/// for the hypervisor bit and the hypervisor range for the VMM signature.
+pub const CPUID_EXIT_LEAVES: [u32; 3] = [0x1, HYPERVISOR_CPUID_LEAF, HYPERVISOR_CPUID_LEAF + 1];
+
+/// Makes the guest exit on the CPUID `leaves`, which the backend then answers with
+/// `complete_cpuid`. Must be called before `setup_partition`.
+/// Returns Ok on success or a VmError on failure.
+pub fn set_cpuid_exit_list(partition: &Partition, leaves: &[u32]) -> Result<(), VmError> {
+    if let Err(e) = unsafe {
+        WHvSetPartitionProperty(
+            partition.get_whv_partition_handle(),
+            WHvPartitionPropertyCodeCpuidExitList,
+            leaves.as_ptr() as *const _,
+            std::mem::size_of_val(leaves) as u32,
+        )
+    } {
+        return Err(VmError::hypervisor_source(format!("Failed to set CPUID exit list: {:?}", e), e));
+    }
+    Ok(())
+}
+
+/// Enables the local APIC emulated by the hypervisor in xAPIC mode, which
+/// `request_interrupt` delivers device interrupts to. Must be called before
+/// `setup_partition`.
+/// Returns Ok on success or a VmError on failure.
+pub fn set_local_apic_emulation(partition: &Partition) -> Result<(), VmError> {
+    let mode: WHV_X64_LOCAL_APIC_EMULATION_MODE = WHvX64LocalApicEmulationModeXApic;
+    if let Err(e) = unsafe {
+        WHvSetPartitionProperty(
+            partition.get_whv_partition_handle(),
+            WHvPartitionPropertyCodeLocalApicEmulationMode,
+            &mode as *const _ as *const _,
+            std::mem::size_of::<WHV_X64_LOCAL_APIC_EMULATION_MODE>() as u32,
+        )
+    } {
+        return Err(VmError::hypervisor_source(format!("Failed to enable local APIC emulation: {:?}", e), e));
     }
 
     Ok(())
 }
 
 /// Deletes the given partition handle, cleaning up resources.
-/// Returns Ok on success or an error string on failure.
-fn delete_partition(partition: WHV_PARTITION_HANDLE) -> Result<(), String> {
+/// Returns Ok on success or a VmError on failure.
+fn delete_partition(partition: WHV_PARTITION_HANDLE) -> Result<(), VmError> {
     if let Err(e) = unsafe { WHvDeletePartition(partition) } {
-        return Err(format!("{:?}", e))
+        return Err(VmError::hypervisor_source(format!("Failed to delete partition: {:?}", e), e))
     }
 
     Ok(())
@@ -108,86 +436,855 @@ fn delete_partition(partition: WHV_PARTITION_HANDLE) -> Result<(), String> {
 
 /// Finalizes the partition setup after properties are configured.
 /// Must be called before running virtual processors.
-/// Returns Ok on success or an error string on failure.
-pub fn setup_partition(partition: &Partition) -> Result<(), String> {
+/// Returns Ok on success or a VmError on failure.
+pub fn setup_partition(partition: &Partition) -> Result<(), VmError> {
     match unsafe { WHvSetupPartition(partition.get_whv_partition_handle()) } {
         Ok(()) => Ok(()),
-        Err(e) => Err(format!("{:?}", e))
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to setup partition: {:?}", e), e))
+    }
+}
+
+/// Enables `SeLockMemoryPrivilege` on the current process token.
+/// The privilege is required for large page allocations and must be granted to the
+/// user account ("Lock pages in memory" policy); this only activates it.
+/// Returns Ok on success or a VmError if the privilege can't be enabled.
+fn enable_lock_memory_privilege() -> Result<(), VmError> {
+    let mut token = HANDLE::default();
+    if let Err(e) = unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY, &mut token) } {
+        return Err(VmError::memory_source(format!("Failed to open process token: {:?}", e), e));
+    }
+
+    let mut luid = LUID::default();
+    if let Err(e) = unsafe { LookupPrivilegeValueW(PCWSTR::null(), SE_LOCK_MEMORY_NAME, &mut luid) } {
+        let _ = unsafe { CloseHandle(token) };
+        return Err(VmError::memory_source(format!("Failed to look up SeLockMemoryPrivilege: {:?}", e), e));
+    }
+
+    let privileges = TOKEN_PRIVILEGES {
+        PrivilegeCount: 1,
+        Privileges: [LUID_AND_ATTRIBUTES { Luid: luid, Attributes: SE_PRIVILEGE_ENABLED }],
+    };
+    let adjust_result = unsafe { AdjustTokenPrivileges(token, false, Some(&privileges), 0, None, None) };
+    // AdjustTokenPrivileges reports success even if the account doesn't hold the privilege
+    let last_error = unsafe { GetLastError() };
+    let _ = unsafe { CloseHandle(token) };
+
+    if let Err(e) = adjust_result {
+        return Err(VmError::memory_source(format!("Failed to adjust token privileges: {:?}", e), e));
+    }
+    if last_error == ERROR_NOT_ALL_ASSIGNED {
+        return Err(VmError::memory("SeLockMemoryPrivilege is not held by the current user"));
+    }
+
+    Ok(())
+}
+
+/// Tries to allocate `mem_size` bytes of host memory backed by large pages.
+/// The size is rounded up to the large page granularity.
+/// Returns the allocation pointer on success or a VmError explaining why
+/// large pages are unavailable.
+fn allocate_large_page_memory(mem_size: u64) -> Result<*mut core::ffi::c_void, VmError> {
+    enable_lock_memory_privilege()?;
+
+    let large_page_size = unsafe { GetLargePageMinimum() } as u64;
+    if large_page_size == 0 {
+        return Err(VmError::memory("Large pages are not supported by the host"));
     }
+    let rounded_size = mem_size.div_ceil(large_page_size) * large_page_size;
+
+    let ptr = unsafe {
+        VirtualAlloc(
+            None,
+            rounded_size as usize,
+            MEM_COMMIT | MEM_RESERVE | MEM_LARGE_PAGES,
+            PAGE_READWRITE,
+        )
+    };
+    if ptr.is_null() {
+        return Err(VmError::memory("VirtualAlloc with MEM_LARGE_PAGES failed"));
+    }
+
+    Ok(ptr)
 }
 
 /// Allocates host memory and maps it into the guest physical address space.
 /// - `partition`: Partition handle to map memory into.
 /// - `mem_size`: Size of memory to allocate and map (in bytes).
-/// Returns Ok on success or error string on failure.
-pub fn allocate_partition_memory(partition: &Partition, mem_size: u64) -> Result<(), String> {
+/// Returns Ok on success or a VmError on failure.
+pub fn allocate_partition_memory(partition: &Partition, mem_size: u64) -> Result<(), VmError> {
+    match allocate_partition_memory_with_backing(partition, mem_size, false) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Allocates host memory, optionally backed by large pages, and maps it into the
+/// guest physical address space.
+/// - `partition`: Partition handle to map memory into.
+/// - `mem_size`: Size of memory to allocate and map (in bytes).
+/// - `use_large_pages`: Try to back guest RAM with large pages. If the privilege or
+///   enough contiguous memory is missing, falls back to standard pages with a warning.
+/// Returns the backing actually used on success or a VmError on failure.
+pub fn allocate_partition_memory_with_backing(partition: &Partition, mem_size: u64, use_large_pages: bool) -> Result<MemoryBacking, VmError> {
     // Get host memory info
     let (total_mem, avail_mem) = match get_physical_memory_info() {
         Ok((total_mem, avail_mem)) => (total_mem, avail_mem),
-        Err(e) => return Err(format!("Failed to get memory info: {}", e)),
+        Err(e) => return Err(VmError::memory_source(format!("Failed to get memory info: {}", e), e)),
     };
 
     // Check if enough available memory on host
     if avail_mem < mem_size {
-        return Err("Failed to allocate the memory: not enough available memory".to_string());
+        return Err(VmError::memory("Failed to allocate the memory: not enough available memory"));
+    }
+
+    // Try large pages first if requested, falling back to standard pages
+    let mut backing = MemoryBacking::StandardPages;
+    let mut ptr = std::ptr::null_mut();
+    if use_large_pages {
+        match allocate_large_page_memory(mem_size) {
+            Ok(p) => {
+                ptr = p;
+                backing = MemoryBacking::LargePages;
+            },
+            Err(e) => eprintln!("Large pages unavailable, falling back to standard pages: {}", e)
+        }
     }
 
     // Allocate virtual memory on host with read/write permissions
-    let ptr = unsafe {
-        VirtualAlloc(
-            None,
-            mem_size as usize,
-            MEM_COMMIT | MEM_RESERVE,
-            PAGE_READWRITE,
-        )
-    };
     if ptr.is_null() {
-        return Err("VirtualAlloc failed".to_string());
+        ptr = unsafe {
+            VirtualAlloc(
+                None,
+                mem_size as usize,
+                MEM_COMMIT | MEM_RESERVE,
+                PAGE_READWRITE,
+            )
+        };
+    }
+    if ptr.is_null() {
+        return Err(VmError::memory("VirtualAlloc failed"));
+    }
+    // The partition frees the allocation once it is deleted, even if mapping it fails
+    partition.allocations.lock().unwrap_or_else(|e| e.into_inner()).push(ptr as u64);
+
+    // Map the allocated host memory from GPA 0, continuing above 4 GiB past the PCI hole
+    let mut host_address = ptr as u64;
+    for (gpa, size) in get_guest_ram_ranges(0, mem_size)? {
+        // SAFETY: the partition owns the allocation, which it frees after deleting itself
+        unsafe { map_memory_region(partition, gpa, size, host_address, guest_ram_flags())? };
+        host_address += size;
     }
+    Ok(backing)
+}
 
-    // Prepare flags for memory mapping: readable, writable, executable
-    let flags = WHV_MAP_GPA_RANGE_FLAGS(
-        WHvMapGpaRangeFlagRead.0 |
-        WHvMapGpaRangeFlagWrite.0 |
-        WHvMapGpaRangeFlagExecute.0,
-    );
+/// Returns the flags guest RAM is mapped with: readable, writable and executable.
+fn guest_ram_flags() -> WHV_MAP_GPA_RANGE_FLAGS {
+    WHV_MAP_GPA_RANGE_FLAGS(WHvMapGpaRangeFlagRead.0 | WHvMapGpaRangeFlagWrite.0 | WHvMapGpaRangeFlagExecute.0)
+}
 
-    // Map the allocated host memory into the guest physical address space starting at GPA 0
-    let result = unsafe {
-        WHvMapGpaRange(partition.get_whv_partition_handle(), ptr as *mut _, 0x0000, mem_size, flags)
+/// Maps `size` bytes of host memory at `host_address` into the guest physical address
+/// space of `partition` at `gpa`, and tracks them as a region of the partition.
+///
+/// # Safety
+/// The host memory must stay valid for as long as the region is mapped.
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(VmError)` if the range isn't made of whole pages, overlaps a mapped region or
+///   the hypervisor rejected it
+pub unsafe fn map_memory_region(partition: &Partition, gpa: u64, size: u64, host_address: u64, flags: WHV_MAP_GPA_RANGE_FLAGS) -> Result<(), VmError> {
+    if gpa % GPA_PAGE_SIZE != 0 || size % GPA_PAGE_SIZE != 0 || size == 0 || host_address % GPA_PAGE_SIZE != 0 {
+        return Err(VmError::memory(format!("memory region of {:#x} bytes at {:#x} isn't a non-empty range of whole pages", size, gpa)));
+    }
+    let mut regions = partition.regions.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(region) = regions.iter().find(|region| region.overlaps(gpa, size)) {
+        return Err(VmError::memory(format!("memory region {:#x}-{:#x} overlaps the region {:#x}-{:#x}", gpa, gpa + size, region.gpa, region.gpa + region.size)));
+    }
+    if let Err(e) = unsafe { WHvMapGpaRange(partition.get_whv_partition_handle(), host_address as *const _, gpa, size, flags) } {
+        return Err(VmError::memory_source(format!("Failed to map memory at {:#x}: {:?}", gpa, e), e));
+    }
+    let index = regions.partition_point(|region| region.gpa < gpa);
+    regions.insert(index, MemoryRegion { gpa, size, host_address });
+    Ok(())
+}
+
+/// Unmaps the region of `partition` mapped at `gpa` by `map_memory_region`.
+///
+/// # Returns
+/// * `Ok(MemoryRegion)` with the region, whose host memory the guest no longer reaches
+/// * `Err(VmError)` if no region starts at `gpa` or the hypervisor rejected the request
+pub fn unmap_memory_region(partition: &Partition, gpa: u64) -> Result<MemoryRegion, VmError> {
+    let mut regions = partition.regions.lock().unwrap_or_else(|e| e.into_inner());
+    let index = match regions.iter().position(|region| region.gpa == gpa) {
+        Some(index) => index,
+        None => return Err(VmError::memory(format!("no memory region is mapped at {:#x}", gpa)))
     };
+    if let Err(e) = unsafe { WHvUnmapGpaRange(partition.get_whv_partition_handle(), gpa, regions[index].size) } {
+        return Err(VmError::memory_source(format!("Failed to unmap memory at {:#x}: {:?}", gpa, e), e));
+    }
+    Ok(regions.remove(index))
+}
 
-    match result {
+/// Allocates guest RAM as a `GuestMemoryMmap` and maps it into the guest physical
+/// address space starting at `gpa`, so emulated devices reach the same memory as the
+/// guest. RAM reaching the PCI hole continues above 4 GiB, see `get_guest_ram_ranges`.
+/// - `partition`: Partition handle to map memory into.
+/// - `gpa`: Guest physical address of the first byte of RAM.
+/// - `mem_size`: Size of memory to allocate and map (in bytes).
+/// Returns the guest memory on success or a VmError on failure. The memory must outlive
+/// the partition's use of it.
+pub fn allocate_guest_memory(partition: &Partition, gpa: u64, mem_size: u64) -> Result<GuestMemoryMmap, VmError> {
+    let (_, avail_mem) = get_physical_memory_info()?;
+    if avail_mem < mem_size {
+        return Err(VmError::memory("Failed to allocate the memory: not enough available memory"));
+    }
+
+    let ranges: Vec<(GuestAddress, usize)> = get_guest_ram_ranges(gpa, mem_size)?.into_iter()
+        .map(|(gpa, size)| (GuestAddress(gpa), size as usize))
+        .collect();
+    let guest_memory: GuestMemoryMmap = match GuestMemoryMmap::from_ranges(&ranges) {
+        Ok(mem) => mem,
+        Err(e) => return Err(VmError::memory_source(format!("Failed to create guest memory: {}", e), e)),
+    };
+
+    for region in guest_memory.iter() {
+        let host_addr = match guest_memory.get_host_address(region.start_addr()) {
+            Ok(addr) => addr,
+            Err(e) => return Err(VmError::memory_source(format!("Failed to get host address for guest memory: {}", e), e)),
+        };
+        // SAFETY: guest_memory owns the mapping; the caller keeps it alive while the partition uses it
+        unsafe { map_memory_region(partition, region.start_addr().0, region.len(), host_addr as u64, guest_ram_flags())? };
+    }
+
+    Ok(guest_memory)
+}
+
+/// Maps the `len` bytes of `guest_memory` at `gpa` again, writable or read-only. Guest
+/// writes to a read-only range exit with `WHvRunVpExitReasonMemoryAccess`.
+/// Returns Ok on success or a VmError on failure, in which case the range may be unmapped.
+pub fn remap_guest_range(partition: &Partition, guest_memory: &GuestMemoryMmap, gpa: u64, len: u64, writable: bool) -> Result<(), VmError> {
+    let host_addr = match guest_memory.get_host_address(GuestAddress(gpa)) {
+        Ok(addr) => addr,
+        Err(e) => return Err(VmError::memory_source(format!("Failed to get host address for guest memory: {}", e), e)),
+    };
+    if let Err(e) = unsafe { WHvUnmapGpaRange(partition.get_whv_partition_handle(), gpa, len) } {
+        return Err(VmError::memory_source(format!("Failed to unmap memory at {:#x}: {:?}", gpa, e), e));
+    }
+
+    let mut flags = WHvMapGpaRangeFlagRead.0 | WHvMapGpaRangeFlagExecute.0;
+    if writable {
+        flags |= WHvMapGpaRangeFlagWrite.0;
+    }
+    // SAFETY: guest_memory owns the mapping; the caller keeps it alive while the partition uses it
+    match unsafe { WHvMapGpaRange(partition.get_whv_partition_handle(), host_addr as *const _, gpa, len, WHV_MAP_GPA_RANGE_FLAGS(flags)) } {
         Ok(()) => Ok(()),
-        Err(e) => Err(format!("Failed to map memory: {:?}", e)),
+        Err(e) => Err(VmError::memory_source(format!("Failed to map memory at {:#x}: {:?}", gpa, e), e)),
     }
 }
 
 /// Creates a virtual CPU (vCPU) in the given partition with the specified CPU ID.
-/// Returns Ok on success or error string on failure.
-pub fn create_vcpu(partition: &Partition, cpu_id: u32) -> Result<(), String> {
+/// Returns Ok on success or a VmError on failure.
+pub fn create_vcpu(partition: &Partition, cpu_id: u32) -> Result<(), VmError> {
     let hresult = unsafe { WHvCreateVirtualProcessor(partition.get_whv_partition_handle(), cpu_id, 0) };
     if let Err(e) = hresult {
-        return Err(format!("Failed to create virtual processor: {:?}", e));
+        return Err(VmError::hypervisor_source(format!("Failed to create virtual processor: {:?}", e), e));
     }
 
+    // Remember the vCPU so `Partition::suspend` can cancel its run
+    partition.vcpus.lock().unwrap_or_else(|e| e.into_inner()).push(cpu_id);
+
     Ok(())
 }
 
 /// Runs the virtual CPU with the given CPU ID on the specified partition.
-/// Returns the exit context on success or error string on failure.
-pub fn run_vcpu(partition: &Partition, cpu_id: u32) -> Result<WHV_RUN_VP_EXIT_CONTEXT, String> {
+/// Returns the exit context on success or a VmError on failure.
+pub fn run_vcpu(partition: &Partition, cpu_id: u32) -> Result<WHV_RUN_VP_EXIT_CONTEXT, VmError> {
     let mut vcpu_ctx: WHV_RUN_VP_EXIT_CONTEXT = WHV_RUN_VP_EXIT_CONTEXT::default();
     let val_size = std::mem::size_of_val(&vcpu_ctx) as u32;
 
     // Run the vCPU and fill vcpu_ctx with exit information
     if let Err(e) = unsafe { WHvRunVirtualProcessor(partition.get_whv_partition_handle(), cpu_id, &mut vcpu_ctx as *mut _ as *mut _, val_size) } {
-        return Err(format!("{:?}", e));
+        return Err(VmError::hypervisor_source(format!("Failed to run virtual processor: {:?}", e), e));
     }
 
     Ok(vcpu_ctx)
 }
 
+/// Segment register of an x86-64 vCPU.
+///
+/// `attributes` holds bits 8-15 and 20-23 of the high dword of the segment descriptor
+/// (type, S, DPL, P, AVL, L, D/B and G), the way WHP packs them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct X64Segment {
+    /// Linear address the segment starts at
+    pub base: u64,
+    /// Last valid offset in the segment, in bytes
+    pub limit: u32,
+    /// Selector loaded in the register
+    pub selector: u16,
+    /// Descriptor attributes
+    pub attributes: u16,
+}
+
+impl X64Segment {
+    fn from_whv(segment: &WHV_X64_SEGMENT_REGISTER) -> Self {
+        X64Segment { base: segment.Base, limit: segment.Limit, selector: segment.Selector, attributes: unsafe { segment.Anonymous.Attributes } }
+    }
+
+    fn to_whv(self) -> WHV_X64_SEGMENT_REGISTER {
+        WHV_X64_SEGMENT_REGISTER {
+            Base: self.base,
+            Limit: self.limit,
+            Selector: self.selector,
+            Anonymous: WHV_X64_SEGMENT_REGISTER_0 { Attributes: self.attributes },
+        }
+    }
+}
+
+/// Descriptor table register (GDTR or IDTR) of an x86-64 vCPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct X64Table {
+    /// Linear address of the table
+    pub base: u64,
+    /// Size of the table in bytes, minus one
+    pub limit: u16,
+}
+
+/// General purpose, segment and control registers of an x86-64 vCPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct X64Registers {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: X64Segment,
+    pub ds: X64Segment,
+    pub es: X64Segment,
+    pub fs: X64Segment,
+    pub gs: X64Segment,
+    pub ss: X64Segment,
+    pub tr: X64Segment,
+    pub ldtr: X64Segment,
+    pub gdtr: X64Table,
+    pub idtr: X64Table,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+/// Registers `X64Registers` is read and written as, in the order of its fields.
+const X64_REGISTER_NAMES: [WHV_REGISTER_NAME; 33] = [
+    WHvX64RegisterRax, WHvX64RegisterRcx, WHvX64RegisterRdx, WHvX64RegisterRbx,
+    WHvX64RegisterRsp, WHvX64RegisterRbp, WHvX64RegisterRsi, WHvX64RegisterRdi,
+    WHvX64RegisterR8, WHvX64RegisterR9, WHvX64RegisterR10, WHvX64RegisterR11,
+    WHvX64RegisterR12, WHvX64RegisterR13, WHvX64RegisterR14, WHvX64RegisterR15,
+    WHvX64RegisterRip, WHvX64RegisterRflags,
+    WHvX64RegisterCs, WHvX64RegisterDs, WHvX64RegisterEs, WHvX64RegisterFs,
+    WHvX64RegisterGs, WHvX64RegisterSs, WHvX64RegisterTr, WHvX64RegisterLdtr,
+    WHvX64RegisterGdtr, WHvX64RegisterIdtr,
+    WHvX64RegisterCr0, WHvX64RegisterCr2, WHvX64RegisterCr3, WHvX64RegisterCr4, WHvX64RegisterEfer,
+];
+
+// Control register bits
+const X86_CR0_PE: u64 = 1 << 0;
+const X86_CR0_ET: u64 = 1 << 4;
+const X86_CR0_PG: u64 = 1 << 31;
+const X86_CR4_PAE: u64 = 1 << 5;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+/// Bit 1 of RFLAGS, which always reads as one.
+const RFLAGS_RESERVED: u64 = 0x2;
+
+/// Segment attributes of a present, accessed read/write data segment.
+const SEGMENT_DATA: u16 = 0x0093;
+/// Segment attributes of a present, accessed execute/read code segment.
+const SEGMENT_CODE: u16 = 0x009b;
+/// Segment attributes of a 64-bit code segment with 4 KiB granularity.
+const SEGMENT_CODE_64: u16 = 0xa09b;
+/// Segment attributes of a flat 32-bit data segment with 4 KiB granularity.
+const SEGMENT_DATA_FLAT: u16 = 0xc093;
+/// Segment attributes of a present, busy 64-bit TSS.
+const SEGMENT_TSS: u16 = 0x008b;
+/// Segment attributes of a present LDT.
+const SEGMENT_LDT: u16 = 0x0082;
+
+impl X64Registers {
+    /// Returns the registers of a vCPU entering 16-bit real-mode code at
+    /// `segment:offset`, with paging and protection off and every data segment at
+    /// `segment` too.
+    pub fn real_mode_entry(segment: u16, offset: u16) -> Self {
+        let code = X64Segment { base: (segment as u64) << 4, limit: 0xffff, selector: segment, attributes: SEGMENT_CODE };
+        let data = X64Segment { attributes: SEGMENT_DATA, ..code };
+        X64Registers {
+            rip: offset as u64,
+            rflags: RFLAGS_RESERVED,
+            cs: code,
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: X64Segment { limit: 0xffff, attributes: SEGMENT_TSS, ..Default::default() },
+            ldtr: X64Segment { limit: 0xffff, attributes: SEGMENT_LDT, ..Default::default() },
+            gdtr: X64Table { base: 0, limit: 0xffff },
+            idtr: X64Table { base: 0, limit: 0xffff },
+            cr0: X86_CR0_ET,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the registers of a vCPU entering 64-bit code at `entry`, with flat
+    /// code and data segments and paging on.
+    ///
+    /// The guest must hold a GDT at `gdt_base` with a 64-bit code segment at selector
+    /// 0x08, a data segment at 0x10 and a TSS at 0x18, and the page tables at
+    /// `page_tables` must map `entry` and `stack_pointer`.
+    ///
+    /// # Arguments
+    /// * `entry` - Guest address of the first instruction
+    /// * `stack_pointer` - Initial RSP
+    /// * `page_tables` - Guest physical address of the PML4, loaded into CR3
+    /// * `gdt_base` - Guest physical address of the GDT, which has 4 entries
+    pub fn long_mode_entry(entry: u64, stack_pointer: u64, page_tables: u64, gdt_base: u64) -> Self {
+        let code = X64Segment { base: 0, limit: 0xffff_ffff, selector: 0x08, attributes: SEGMENT_CODE_64 };
+        let data = X64Segment { selector: 0x10, attributes: SEGMENT_DATA_FLAT, ..code };
+        X64Registers {
+            rsp: stack_pointer,
+            rbp: stack_pointer,
+            rip: entry,
+            rflags: RFLAGS_RESERVED,
+            cs: code,
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: X64Segment { base: 0, limit: 0xffff_ffff, selector: 0x18, attributes: SEGMENT_TSS | 0x8000 },
+            ldtr: X64Segment { limit: 0xffff, attributes: SEGMENT_LDT, ..Default::default() },
+            gdtr: X64Table { base: gdt_base, limit: 4 * 8 - 1 },
+            idtr: X64Table { base: 0, limit: 0 },
+            cr0: X86_CR0_PE | X86_CR0_ET | X86_CR0_PG,
+            cr3: page_tables,
+            cr4: X86_CR4_PAE,
+            efer: EFER_LME | EFER_LMA,
+            ..Default::default()
+        }
+    }
+
+    fn from_values(values: &[WHV_REGISTER_VALUE; X64_REGISTER_NAMES.len()]) -> Self {
+        // SAFETY: every value was read for the register at the same index of
+        // X64_REGISTER_NAMES, whose kind picks the active field
+        unsafe {
+            X64Registers {
+                rax: values[0].Reg64,
+                rcx: values[1].Reg64,
+                rdx: values[2].Reg64,
+                rbx: values[3].Reg64,
+                rsp: values[4].Reg64,
+                rbp: values[5].Reg64,
+                rsi: values[6].Reg64,
+                rdi: values[7].Reg64,
+                r8: values[8].Reg64,
+                r9: values[9].Reg64,
+                r10: values[10].Reg64,
+                r11: values[11].Reg64,
+                r12: values[12].Reg64,
+                r13: values[13].Reg64,
+                r14: values[14].Reg64,
+                r15: values[15].Reg64,
+                rip: values[16].Reg64,
+                rflags: values[17].Reg64,
+                cs: X64Segment::from_whv(&values[18].Segment),
+                ds: X64Segment::from_whv(&values[19].Segment),
+                es: X64Segment::from_whv(&values[20].Segment),
+                fs: X64Segment::from_whv(&values[21].Segment),
+                gs: X64Segment::from_whv(&values[22].Segment),
+                ss: X64Segment::from_whv(&values[23].Segment),
+                tr: X64Segment::from_whv(&values[24].Segment),
+                ldtr: X64Segment::from_whv(&values[25].Segment),
+                gdtr: X64Table { base: values[26].Table.Base, limit: values[26].Table.Limit },
+                idtr: X64Table { base: values[27].Table.Base, limit: values[27].Table.Limit },
+                cr0: values[28].Reg64,
+                cr2: values[29].Reg64,
+                cr3: values[30].Reg64,
+                cr4: values[31].Reg64,
+                efer: values[32].Reg64,
+            }
+        }
+    }
+
+    fn to_values(&self) -> [WHV_REGISTER_VALUE; X64_REGISTER_NAMES.len()] {
+        let reg64 = |value: u64| WHV_REGISTER_VALUE { Reg64: value };
+        let segment = |segment: X64Segment| WHV_REGISTER_VALUE { Segment: segment.to_whv() };
+        let table = |table: X64Table| WHV_REGISTER_VALUE { Table: WHV_X64_TABLE_REGISTER { Pad: [0; 3], Limit: table.limit, Base: table.base } };
+        [
+            reg64(self.rax), reg64(self.rcx), reg64(self.rdx), reg64(self.rbx),
+            reg64(self.rsp), reg64(self.rbp), reg64(self.rsi), reg64(self.rdi),
+            reg64(self.r8), reg64(self.r9), reg64(self.r10), reg64(self.r11),
+            reg64(self.r12), reg64(self.r13), reg64(self.r14), reg64(self.r15),
+            reg64(self.rip), reg64(self.rflags),
+            segment(self.cs), segment(self.ds), segment(self.es), segment(self.fs),
+            segment(self.gs), segment(self.ss), segment(self.tr), segment(self.ldtr),
+            table(self.gdtr), table(self.idtr),
+            reg64(self.cr0), reg64(self.cr2), reg64(self.cr3), reg64(self.cr4), reg64(self.efer),
+        ]
+    }
+}
+
+/// Reads the registers of the vCPU with the given CPU ID, which must not be running.
+/// Returns the registers on success or a VmError on failure.
+pub fn get_vcpu_registers(partition: &Partition, cpu_id: u32) -> Result<X64Registers, VmError> {
+    let mut values = [WHV_REGISTER_VALUE::default(); X64_REGISTER_NAMES.len()];
+    let result = unsafe {
+        WHvGetVirtualProcessorRegisters(partition.get_whv_partition_handle(), cpu_id, X64_REGISTER_NAMES.as_ptr(), X64_REGISTER_NAMES.len() as u32, values.as_mut_ptr())
+    };
+    match result {
+        Ok(()) => Ok(X64Registers::from_values(&values)),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to get VCPU {} registers: {:?}", cpu_id, e), e)),
+    }
+}
+
+/// Writes every register of `registers` to the vCPU with the given CPU ID, which must
+/// not be running. Returns Ok on success or a VmError on failure.
+pub fn set_vcpu_registers(partition: &Partition, cpu_id: u32, registers: &X64Registers) -> Result<(), VmError> {
+    let values = registers.to_values();
+    let result = unsafe {
+        WHvSetVirtualProcessorRegisters(partition.get_whv_partition_handle(), cpu_id, X64_REGISTER_NAMES.as_ptr(), X64_REGISTER_NAMES.len() as u32, values.as_ptr())
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to set VCPU {} registers: {:?}", cpu_id, e), e)),
+    }
+}
+
+/// Moves the vCPU with the given CPU ID to `rip`, leaving its other registers alone.
+/// Returns Ok on success or a VmError on failure.
+pub fn set_instruction_pointer(partition: &Partition, cpu_id: u32, rip: u64) -> Result<(), VmError> {
+    let value = WHV_REGISTER_VALUE { Reg64: rip };
+    match unsafe { WHvSetVirtualProcessorRegisters(partition.get_whv_partition_handle(), cpu_id, &WHvX64RegisterRip, 1, &value) } {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to set VCPU {} instruction pointer: {:?}", cpu_id, e), e)),
+    }
+}
+
+/// Returns the address of the instruction following the one the vCPU exited on.
+fn get_next_rip(exit_ctx: &WHV_RUN_VP_EXIT_CONTEXT) -> u64 {
+    // The low four bits hold the length of the instruction
+    exit_ctx.VpContext.Rip + (exit_ctx.VpContext._bitfield & 0xf) as u64
+}
+
+/// Answers the CPUID exit `exit_ctx` with the result `cpu_model` gives the guest and
+/// moves the vCPU with the given CPU ID past the instruction.
+/// Returns Ok on success or a VmError on failure.
+pub fn complete_cpuid(partition: &Partition, cpu_id: u32, exit_ctx: &WHV_RUN_VP_EXIT_CONTEXT, cpu_model: &CpuModel) -> Result<(), VmError> {
+    // SAFETY: the caller checked the exit reason is WHvRunVpExitReasonX64Cpuid, so CpuidAccess is the active field
+    let access = unsafe { &exit_ctx.Anonymous.CpuidAccess };
+    let mut entry = CpuidEntry {
+        function: access.Rax as u32,
+        index: access.Rcx as u32,
+        eax: access.DefaultResultRax as u32,
+        ebx: access.DefaultResultRbx as u32,
+        ecx: access.DefaultResultRcx as u32,
+        edx: access.DefaultResultRdx as u32,
+    };
+    cpu_model.complete_guest_entry(&mut entry);
+
+    let names = [WHvX64RegisterRax, WHvX64RegisterRbx, WHvX64RegisterRcx, WHvX64RegisterRdx, WHvX64RegisterRip];
+    let values = [entry.eax as u64, entry.ebx as u64, entry.ecx as u64, entry.edx as u64, get_next_rip(exit_ctx)]
+        .map(|value| WHV_REGISTER_VALUE { Reg64: value });
+    match unsafe { WHvSetVirtualProcessorRegisters(partition.get_whv_partition_handle(), cpu_id, names.as_ptr(), names.len() as u32, values.as_ptr()) } {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to complete CPUID leaf {:#x} on VCPU {}: {:?}", entry.function, cpu_id, e), e)),
+    }
+}
+
+/// Port access reported by a port I/O exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortIoAccess {
+    /// Port the guest accessed
+    pub port: u16,
+    /// Size of the access in bytes: 1, 2 or 4
+    pub size: usize,
+    /// Whether the guest wrote to the port
+    pub is_write: bool,
+    /// Whether the access is a string instruction (INS or OUTS) going through guest memory
+    pub is_string: bool,
+    /// RAX when the guest executed the instruction
+    pub rax: u64,
+    /// Address of the instruction following the access
+    pub next_rip: u64,
+}
+
+impl PortIoAccess {
+    /// Decodes the port access `exit_ctx` reports, which must be a port I/O exit.
+    pub fn from_exit(exit_ctx: &WHV_RUN_VP_EXIT_CONTEXT) -> Self {
+        // SAFETY: the caller checked the exit reason is WHvRunVpExitReasonX64IoPortAccess, so IoPortAccess is the active field
+        let access = unsafe { &exit_ctx.Anonymous.IoPortAccess };
+        Self::decode(unsafe { access.AccessInfo.AsUINT32 }, access.PortNumber, access.Rax, get_next_rip(exit_ctx))
+    }
+
+    /// Decodes the bits of `WHV_X64_IO_PORT_ACCESS_INFO`: IsWrite (bit 0), AccessSize
+    /// (bits 1-3) and StringOp (bit 4).
+    fn decode(access_info: u32, port: u16, rax: u64, next_rip: u64) -> Self {
+        PortIoAccess {
+            port,
+            size: ((access_info >> 1) & 0x7) as usize,
+            is_write: access_info & 0x1 != 0,
+            is_string: access_info & 0x10 != 0,
+            rax,
+            next_rip,
+        }
+    }
+
+    /// Returns the bytes an OUT writes, the low `size` bytes of RAX.
+    pub fn get_write_data(&self) -> [u8; 4] {
+        (self.rax as u32).to_le_bytes()
+    }
+
+    /// Returns RAX once the access completed, for an IN with the `data` read from the
+    /// port. A 32-bit read clears the upper half of RAX like any write to EAX; narrower
+    /// reads leave the other bytes alone.
+    pub fn get_completed_rax(&self, data: &[u8]) -> u64 {
+        if self.is_write {
+            return self.rax;
+        }
+        let mut bytes = [0u8; 4];
+        let size = self.size.min(data.len()).min(bytes.len());
+        bytes[..size].copy_from_slice(&data[..size]);
+        let value = u32::from_le_bytes(bytes) as u64;
+        match self.size {
+            1 => (self.rax & !0xff) | value,
+            2 => (self.rax & !0xffff) | value,
+            _ => value,
+        }
+    }
+}
+
+/// Completes the port access `access` of the vCPU with the given CPU ID: RAX is set
+/// to `rax` and the vCPU moves past the instruction.
+/// Returns Ok on success or a VmError on failure.
+pub fn complete_port_io(partition: &Partition, cpu_id: u32, access: &PortIoAccess, rax: u64) -> Result<(), VmError> {
+    let names = [WHvX64RegisterRax, WHvX64RegisterRip];
+    let values = [WHV_REGISTER_VALUE { Reg64: rax }, WHV_REGISTER_VALUE { Reg64: access.next_rip }];
+    match unsafe { WHvSetVirtualProcessorRegisters(partition.get_whv_partition_handle(), cpu_id, names.as_ptr(), names.len() as u32, values.as_ptr()) } {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to complete access to port {:#x} on VCPU {}: {:?}", access.port, cpu_id, e), e)),
+    }
+}
+
+/// Requests a fixed interrupt with the given vector on the local APIC `destination`.
+///
+/// `level` selects level-triggered delivery, which keeps the interrupt pending in the
+/// APIC until the guest signals end-of-interrupt.
+pub fn request_interrupt(partition: &Partition, vector: u32, destination: u32, level: bool) -> Result<(), VmError> {
+    let trigger_mode = if level { WHvX64InterruptTriggerModeLevel } else { WHvX64InterruptTriggerModeEdge };
+    // Bitfield layout: Type (8 bits), DestinationMode (4 bits), TriggerMode (4 bits)
+    let interrupt = WHV_INTERRUPT_CONTROL {
+        _bitfield: (WHvX64InterruptTypeFixed.0 as u64 & 0xff)
+            | (WHvX64InterruptDestinationModePhysical.0 as u64 & 0xf) << 8
+            | (trigger_mode.0 as u64 & 0xf) << 12,
+        Destination: destination,
+        Vector: vector,
+    };
+
+    let size = std::mem::size_of::<WHV_INTERRUPT_CONTROL>() as u32;
+    match unsafe { WHvRequestInterrupt(partition.get_whv_partition_handle(), &interrupt, size) } {
+        Ok(()) => Ok(()),
+        Err(e) => Err(VmError::hypervisor_source(format!("Failed to request interrupt vector {}: {:?}", vector, e), e)),
+    }
+}
+
+/// Kind of guest access a memory access exit reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccessType {
+    Read,
+    Write,
+    Execute,
+}
+
+impl MemoryAccessType {
+    /// Decodes the access type held in the low two bits of `WHV_MEMORY_ACCESS_INFO`.
+    pub fn from_access_info(access_info: u32) -> Self {
+        match access_info & 0x3 {
+            0 => MemoryAccessType::Read,
+            1 => MemoryAccessType::Write,
+            _ => MemoryAccessType::Execute,
+        }
+    }
+}
+
+/// Describes a memory access exit for error messages, e.g. "MMIO write at 0xd0000000
+/// by instruction 89 07".
+fn describe_memory_access(access_type: MemoryAccessType, gpa: u64, instruction: &[u8]) -> String {
+    let kind = match access_type {
+        MemoryAccessType::Read => "read",
+        MemoryAccessType::Write => "write",
+        MemoryAccessType::Execute => "instruction fetch",
+    };
+    if instruction.is_empty() {
+        return format!("MMIO {} at {:#x}", kind, gpa);
+    }
+    let bytes: Vec<String> = instruction.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("MMIO {} at {:#x} by instruction {}", kind, gpa, bytes.join(" "))
+}
+
+/// Instruction emulator completing the MMIO accesses of a vCPU.
+///
+/// WHP reports a memory access exit with the faulting instruction but doesn't carry it
+/// out. The emulator of WinHvEmulation decodes it, performs the access on the MMIO bus,
+/// or in guest RAM for writes to ranges protected by write tracking, and updates the
+/// registers of the vCPU, including RIP.
+pub struct MmioEmulator {
+    // Handle of the WinHvEmulation emulator.
+    emulator: *mut core::ffi::c_void,
+}
+
+/// State the emulator callbacks work on for one emulated access.
+struct EmulationContext<'a> {
+    partition: &'a Partition,
+    cpu_id: u32,
+    mmio_bus: &'a MmioBus,
+    tracked: Option<(&'a GuestMemoryMmap, &'a WriteTracker)>,
+}
+
+impl MmioEmulator {
+    /// Creates an emulator for the vCPU thread calling it.
+    /// Returns the emulator on success or a VmError on failure.
+    pub fn new() -> Result<Self, VmError> {
+        let callbacks = WHV_EMULATOR_CALLBACKS {
+            Size: std::mem::size_of::<WHV_EMULATOR_CALLBACKS>() as u32,
+            Reserved: 0,
+            WHvEmulatorIoPortCallback: Some(emulate_io_port),
+            WHvEmulatorMemoryCallback: Some(emulate_memory_access),
+            WHvEmulatorGetVirtualProcessorRegisters: Some(get_emulated_registers),
+            WHvEmulatorSetVirtualProcessorRegisters: Some(set_emulated_registers),
+            WHvEmulatorTranslateGvaPage: Some(translate_emulated_gva),
+        };
+        let mut emulator = std::ptr::null_mut();
+        match unsafe { WHvEmulatorCreateEmulator(&callbacks, &mut emulator) } {
+            Ok(()) => Ok(MmioEmulator { emulator }),
+            Err(e) => Err(VmError::hypervisor_source(format!("Failed to create instruction emulator: {:?}", e), e))
+        }
+    }
+
+    /// Completes the memory access `exit_ctx` reports by routing it to `mmio_bus`, or to
+    /// guest RAM if it falls in a range of the write tracker in `tracked`, where it is recorded.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the access was performed and the vCPU moved past the instruction
+    /// * `Err(VmError)` if the instruction couldn't be emulated or no device claims the address
+    pub(crate) fn emulate(&self, partition: &Partition, cpu_id: u32, exit_ctx: &WHV_RUN_VP_EXIT_CONTEXT, mmio_bus: &MmioBus,
+                          tracked: Option<(&GuestMemoryMmap, &WriteTracker)>) -> Result<(), VmError> {
+        let context = EmulationContext { partition, cpu_id, mmio_bus, tracked };
+        // SAFETY: the exit reason is WHvRunVpExitReasonMemoryAccess, so MemoryAccess is the active field
+        let access = unsafe { &exit_ctx.Anonymous.MemoryAccess };
+        let access_type = MemoryAccessType::from_access_info(unsafe { access.AccessInfo.AsUINT32 });
+        let instruction = &access.InstructionBytes[..(access.InstructionByteCount as usize).min(access.InstructionBytes.len())];
+        // Code can't run from device registers, so there is nothing to emulate
+        if access_type == MemoryAccessType::Execute {
+            return Err(VmError::hypervisor(format!("{} isn't backed by guest RAM", describe_memory_access(access_type, access.Gpa, instruction))));
+        }
+        let status = match unsafe {
+            WHvEmulatorTryMmioEmulation(self.emulator, &context as *const _ as *const _, &exit_ctx.VpContext, access)
+        } {
+            Ok(status) => status,
+            Err(e) => return Err(VmError::hypervisor_source(format!("Failed to emulate {}: {:?}", describe_memory_access(access_type, access.Gpa, instruction), e), e))
+        };
+        // Bit 0 of the status tells the emulation succeeded
+        if unsafe { status.AsUINT32 } & 1 == 0 {
+            return Err(VmError::hypervisor(format!("{} couldn't be emulated (status {:#x})", describe_memory_access(access_type, access.Gpa, instruction), unsafe { status.AsUINT32 })));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MmioEmulator {
+    fn drop(&mut self) {
+        let _ = unsafe { WHvEmulatorDestroyEmulator(self.emulator) };
+    }
+}
+
+/// Turns the result of a WHP call into the HRESULT an emulator callback returns.
+fn to_hresult(result: windows::core::Result<()>) -> HRESULT {
+    match result {
+        Ok(()) => S_OK,
+        Err(e) => e.code()
+    }
+}
+
+/// Emulator callback performing a memory access on the MMIO bus.
+unsafe extern "system" fn emulate_memory_access(context: *const core::ffi::c_void, access: *mut WHV_EMULATOR_MEMORY_ACCESS_INFO) -> HRESULT {
+    let context = unsafe { &*(context as *const EmulationContext) };
+    let access = unsafe { &mut *access };
+    let size = (access.AccessSize as usize).min(access.Data.len());
+    // Direction 0 is a read, 1 a write
+    if let Some((guest_memory, tracker)) = context.tracked {
+        if tracker.is_tracked(access.GpaAddress) {
+            let result = if access.Direction == 0 {
+                guest_memory.read_slice(&mut access.Data[..size], GuestAddress(access.GpaAddress))
+            } else {
+                guest_memory.write_slice(&access.Data[..size], GuestAddress(access.GpaAddress))
+            };
+            if result.is_err() {
+                return E_FAIL;
+            }
+            if access.Direction != 0 {
+                tracker.record(GuestWrite { vcpu: context.cpu_id, gpa: access.GpaAddress, data: access.Data[..size].to_vec() });
+            }
+            return S_OK;
+        }
+    }
+    let handled = if access.Direction == 0 {
+        context.mmio_bus.read(access.GpaAddress, &mut access.Data[..size])
+    } else {
+        context.mmio_bus.write(access.GpaAddress, &access.Data[..size])
+    };
+    if handled { S_OK } else { E_FAIL }
+}
+
+/// Emulator callback for port I/O, which only memory access exits are emulated for.
+unsafe extern "system" fn emulate_io_port(_context: *const core::ffi::c_void, _access: *mut WHV_EMULATOR_IO_ACCESS_INFO) -> HRESULT {
+    E_NOTIMPL
+}
+
+/// Emulator callback reading registers of the vCPU.
+unsafe extern "system" fn get_emulated_registers(context: *const core::ffi::c_void, names: *const WHV_REGISTER_NAME, count: u32, values: *mut WHV_REGISTER_VALUE) -> HRESULT {
+    let context = unsafe { &*(context as *const EmulationContext) };
+    to_hresult(unsafe { WHvGetVirtualProcessorRegisters(context.partition.get_whv_partition_handle(), context.cpu_id, names, count, values) })
+}
+
+/// Emulator callback writing registers of the vCPU.
+unsafe extern "system" fn set_emulated_registers(context: *const core::ffi::c_void, names: *const WHV_REGISTER_NAME, count: u32, values: *const WHV_REGISTER_VALUE) -> HRESULT {
+    let context = unsafe { &*(context as *const EmulationContext) };
+    to_hresult(unsafe { WHvSetVirtualProcessorRegisters(context.partition.get_whv_partition_handle(), context.cpu_id, names, count, values) })
+}
+
+/// Emulator callback translating a guest virtual address of the instruction's operands.
+unsafe extern "system" fn translate_emulated_gva(context: *const core::ffi::c_void, gva: u64, flags: WHV_TRANSLATE_GVA_FLAGS,
+                                                 result_code: *mut WHV_TRANSLATE_GVA_RESULT_CODE, gpa: *mut u64) -> HRESULT {
+    let context = unsafe { &*(context as *const EmulationContext) };
+    let mut result = WHV_TRANSLATE_GVA_RESULT::default();
+    let hresult = to_hresult(unsafe { WHvTranslateGva(context.partition.get_whv_partition_handle(), context.cpu_id, gva, flags, &mut result, gpa) });
+    unsafe { *result_code = result.ResultCode };
+    hresult
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -289,7 +1386,7 @@ mod tests {
         let result = set_processor_count_property(&partition, 0);
         assert!(result.is_err(), "Should fail for processor_count == 0");
         assert_eq!(
-            result.unwrap_err(),
+            result.unwrap_err().to_string(),
             "Failed to set processor count: processor_count equal to 0"
         );
     }
@@ -344,7 +1441,7 @@ mod tests {
         let result = setup_partition(&partition);
         assert!(result.is_err(), "Expected setup to fail without required properties");
 
-        let err_msg = result.unwrap_err();
+        let err_msg = result.unwrap_err().to_string();
         assert!(
             err_msg.contains("HRESULT"),
             "Expected HRESULT error, got: {}",
@@ -360,7 +1457,7 @@ mod tests {
         let result = setup_partition(&partition);
         assert!(result.is_err(), "Expected setup to fail with invalid handle");
 
-        let err_msg = result.unwrap_err();
+        let err_msg = result.unwrap_err().to_string();
         assert!(
             err_msg.contains("HRESULT"),
             "Expected HRESULT error, got: {}",
@@ -391,6 +1488,113 @@ mod tests {
         assert!(result.is_ok(), "Expected success, got error: {:?}", result.err());
     }
 
+    /// Test guest RAM is split around the PCI hole
+    #[test]
+    fn test_get_guest_ram_ranges() {
+        assert_eq!(get_guest_ram_ranges(0, 0x4000_0000).unwrap(), vec![(0, 0x4000_0000)]);
+        // 4 GiB of RAM: 3 GiB below the hole, 1 GiB above it
+        assert_eq!(get_guest_ram_ranges(0, 0x1_0000_0000).unwrap(), vec![(0, PCI_HOLE_START), (PCI_HOLE_END, 0x4000_0000)]);
+        assert_eq!(get_guest_ram_ranges(0x2_0000_0000, 0x1000).unwrap(), vec![(0x2_0000_0000, 0x1000)]);
+
+        assert!(get_guest_ram_ranges(0xd000_0000, 0x1000).is_err());
+        assert!(get_guest_ram_ranges(0x800, 0x1000).is_err());
+        assert!(get_guest_ram_ranges(0, 0).is_err());
+    }
+
+    /// Test memory regions are mapped at arbitrary addresses, tracked and unmapped
+    #[test]
+    fn test_map_and_unmap_memory_regions() {
+        let partition = create_partition().expect("Failed to create partition");
+        set_processor_count_property(&partition, 1).expect("Failed to set processor count");
+        setup_partition(&partition).expect("Failed to setup partition");
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x3000)]).unwrap();
+        let host_address = memory.get_host_address(GuestAddress(0)).unwrap() as u64;
+
+        unsafe {
+            map_memory_region(&partition, PCI_HOLE_END, 0x2000, host_address, guest_ram_flags()).expect("Mapping above 4 GiB should succeed");
+            map_memory_region(&partition, 0x10_0000, 0x1000, host_address + 0x2000, guest_ram_flags()).expect("Mapping below should succeed");
+            assert!(map_memory_region(&partition, PCI_HOLE_END + 0x1000, 0x1000, host_address, guest_ram_flags()).is_err(), "Overlaps should be refused");
+        }
+        let regions = partition.get_memory_regions();
+        assert_eq!(regions, vec![
+            MemoryRegion { gpa: 0x10_0000, size: 0x1000, host_address: host_address + 0x2000 },
+            MemoryRegion { gpa: PCI_HOLE_END, size: 0x2000, host_address },
+        ]);
+
+        assert_eq!(unmap_memory_region(&partition, PCI_HOLE_END).unwrap().size, 0x2000);
+        assert!(unmap_memory_region(&partition, PCI_HOLE_END).is_err());
+        assert_eq!(partition.get_memory_regions().len(), 1);
+    }
+
+    /// Test guest memory is written and read back through the regions of the partition
+    #[test]
+    fn test_write_and_read_guest_memory() {
+        let partition = create_partition().expect("Failed to create partition");
+        set_processor_count_property(&partition, 1).expect("Failed to set processor count");
+        setup_partition(&partition).expect("Failed to setup partition");
+        allocate_partition_memory(&partition, 0x4000).expect("Failed to allocate guest memory");
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let host_address = memory.get_host_address(GuestAddress(0)).unwrap() as u64;
+        unsafe { map_memory_region(&partition, 0x4000, 0x1000, host_address, guest_ram_flags()).expect("Mapping should succeed") };
+
+        // Across the end of the allocated RAM into the next region
+        let data: Vec<u8> = (0..0x20).collect();
+        partition.write_guest_memory(0x3ff0, &data).expect("Write should succeed");
+        let mut read_back = [0u8; 0x20];
+        partition.read_guest_memory(0x3ff0, &mut read_back).expect("Read should succeed");
+        assert_eq!(read_back[..], data[..]);
+        assert_eq!(memory.read_obj::<u8>(GuestAddress(0xf)).unwrap(), 0x1f);
+
+        // Nothing is written unless the whole range is mapped
+        assert!(partition.write_guest_memory(0x4ff0, &[0xAA; 0x20]).is_err());
+        assert_eq!(memory.read_obj::<u8>(GuestAddress(0xff0)).unwrap(), 0);
+        assert!(partition.read_guest_memory(u64::MAX, &mut [0u8; 2]).is_err());
+        unmap_memory_region(&partition, 0x4000).expect("Unmapping should succeed");
+    }
+
+    /// Test large page allocation either succeeds or gracefully falls back to standard pages
+    #[test]
+    fn test_allocate_partition_memory_large_pages_or_fallback() {
+        let partition = create_partition().expect("Failed to create partition");
+
+        let cpu_count: u32 = 1;
+        unsafe {
+            WHvSetPartitionProperty(
+                partition.get_whv_partition_handle(),
+                WHvPartitionPropertyCodeProcessorCount,
+                &cpu_count as *const _ as *const _,
+                size_of::<u32>() as u32,
+            ).expect("Failed to set processor count");
+
+            WHvSetupPartition(partition.get_whv_partition_handle()).expect("Failed to setup partition");
+        }
+
+        // 2 MiB is a multiple of the large page size on x64 hosts
+        let result = allocate_partition_memory_with_backing(&partition, 2 * 1024 * 1024, true);
+        assert!(result.is_ok(), "Expected success or fallback, got error: {:?}", result.err());
+    }
+
+    /// Test standard page allocation reports standard backing
+    #[test]
+    fn test_allocate_partition_memory_standard_backing() {
+        let partition = create_partition().expect("Failed to create partition");
+
+        let cpu_count: u32 = 1;
+        unsafe {
+            WHvSetPartitionProperty(
+                partition.get_whv_partition_handle(),
+                WHvPartitionPropertyCodeProcessorCount,
+                &cpu_count as *const _ as *const _,
+                size_of::<u32>() as u32,
+            ).expect("Failed to set processor count");
+
+            WHvSetupPartition(partition.get_whv_partition_handle()).expect("Failed to setup partition");
+        }
+
+        let result = allocate_partition_memory_with_backing(&partition, 4096, false);
+        assert_eq!(result.ok(), Some(MemoryBacking::StandardPages));
+    }
+
     /// Test memory allocation failure due to insufficient available memory
     #[test]
     fn test_allocate_partition_memory_insufficient_memory() {
@@ -412,7 +1616,7 @@ mod tests {
         let result = allocate_partition_memory(&partition, u64::MAX);
         assert!(result.is_err());
         assert!(
-            result.as_ref().unwrap_err().contains("not enough available memory"),
+            result.as_ref().unwrap_err().to_string().contains("not enough available memory"),
             "Unexpected error: {:?}", result
         );
     }
@@ -500,6 +1704,144 @@ mod tests {
         );
     }
 
+    /// Test the registers survive the conversion to and from WHP register values
+    #[test]
+    fn test_x64_registers_round_trip_through_values() {
+        let mut registers = X64Registers::long_mode_entry(0x100_0000, 0x8ff0, 0x9000, 0x500);
+        registers.rax = 1;
+        registers.r15 = 15;
+        registers.cr2 = 0xdead_b000;
+        assert_eq!(X64Registers::from_values(&registers.to_values()), registers);
+    }
+
+    /// Test the entry helpers produce the expected segments and control registers
+    #[test]
+    fn test_x64_registers_entry_states() {
+        let real = X64Registers::real_mode_entry(0x1000, 0x0010);
+        assert_eq!(real.cs.base, 0x1_0000);
+        assert_eq!(real.cs.selector, 0x1000);
+        assert_eq!(real.rip, 0x10);
+        assert_eq!(real.cr0 & X86_CR0_PE, 0);
+        assert_eq!(real.ss, real.ds);
+
+        let long = X64Registers::long_mode_entry(0x100_0000, 0x8ff0, 0x9000, 0x500);
+        assert_eq!(long.rip, 0x100_0000);
+        assert_eq!(long.cs.selector, 0x08);
+        // L bit set, D bit clear
+        assert_eq!(long.cs.attributes & 0x6000, 0x2000);
+        assert_eq!(long.cr0 & (X86_CR0_PE | X86_CR0_PG), X86_CR0_PE | X86_CR0_PG);
+        assert_eq!(long.cr3, 0x9000);
+        assert_eq!(long.efer & (EFER_LME | EFER_LMA), EFER_LME | EFER_LMA);
+        assert_eq!(long.gdtr, X64Table { base: 0x500, limit: 31 });
+    }
+
+    /// Test registers written to a vCPU read back unchanged
+    #[test]
+    fn test_set_and_get_vcpu_registers() {
+        let partition = create_partition().expect("Failed to create partition");
+        set_processor_count_property(&partition, 1).expect("Failed to set processor count");
+        setup_partition(&partition).expect("Failed to set up partition");
+        create_vcpu(&partition, 0).expect("Failed to create vCPU");
+
+        let registers = X64Registers { rbx: 0x1234, ..X64Registers::long_mode_entry(0x100_0000, 0x8ff0, 0x9000, 0x500) };
+        set_vcpu_registers(&partition, 0, &registers).expect("Failed to set registers");
+        let read = get_vcpu_registers(&partition, 0).expect("Failed to get registers");
+        assert_eq!(read.rip, 0x100_0000);
+        assert_eq!(read.rbx, 0x1234);
+        assert_eq!(read.cs.selector, 0x08);
+        assert_eq!(read.cr3, 0x9000);
+        assert_eq!(read.efer & EFER_LMA, EFER_LMA);
+
+        set_instruction_pointer(&partition, 0, 0x200_0000).expect("Failed to set RIP");
+        let read = get_vcpu_registers(&partition, 0).unwrap();
+        assert_eq!(read.rip, 0x200_0000);
+        assert_eq!(read.rbx, 0x1234);
+    }
+
+    /// Test reading registers of a vCPU that doesn't exist fails
+    #[test]
+    fn test_get_vcpu_registers_invalid_vcpu() {
+        let partition = create_partition().expect("Failed to create partition");
+        set_processor_count_property(&partition, 1).expect("Failed to set processor count");
+        setup_partition(&partition).expect("Failed to set up partition");
+        assert!(get_vcpu_registers(&partition, 3).is_err());
+    }
+
+    /// Test installing a CPU model before partition setup succeeds
+    #[test]
+    fn test_set_cpuid_result_list_baseline_model() {
+        let partition = create_partition().expect("Failed to create partition");
+        set_processor_count_property(&partition, 1).expect("Failed to set processor count");
+
+        let result = set_cpuid_result_list(&partition, &CpuModel::X86_64);
+        assert!(result.is_ok(), "set_cpuid_result_list failed: {:?}", result);
+        assert!(setup_partition(&partition).is_ok());
+    }
+
+    /// Test suspending and resuming a set up partition toggles its state
+    #[test]
+    fn test_suspend_and_resume_partition() {
+        let partition = create_partition().expect("Failed to create partition");
+        set_processor_count_property(&partition, 1).expect("Failed to set processor count");
+        setup_partition(&partition).expect("Failed to setup partition");
+        create_vcpu(&partition, 0).expect("Failed to create vCPU");
+
+        assert!(!partition.is_suspended());
+        let result = partition.suspend();
+        assert!(result.is_ok(), "suspend failed: {:?}", result);
+        assert!(partition.is_suspended());
+
+        // Suspending twice is a no-op
+        assert!(partition.suspend().is_ok());
+
+        let result = partition.resume();
+        assert!(result.is_ok(), "resume failed: {:?}", result);
+        assert!(!partition.is_suspended());
+
+        // Must return immediately once resumed
+        partition.wait_while_suspended();
+    }
+
+    /// Test suspending a partition with an invalid handle fails and leaves it running
+    #[test]
+    fn test_suspend_invalid_partition() {
+        let partition = Partition::new(WHV_PARTITION_HANDLE::default());
+        assert!(partition.suspend().is_err());
+        assert!(!partition.is_suspended());
+    }
+
+    /// Test memory access exits are decoded and described with the faulting instruction
+    #[test]
+    fn test_describe_memory_access() {
+        assert_eq!(MemoryAccessType::from_access_info(0), MemoryAccessType::Read);
+        // Bits above the access type, e.g. GpaUnmapped, don't change it
+        assert_eq!(MemoryAccessType::from_access_info(0x5), MemoryAccessType::Write);
+        assert_eq!(MemoryAccessType::from_access_info(2), MemoryAccessType::Execute);
+
+        assert_eq!(describe_memory_access(MemoryAccessType::Write, 0xd000_0000, &[0x89, 0x07]), "MMIO write at 0xd0000000 by instruction 89 07");
+        assert_eq!(describe_memory_access(MemoryAccessType::Execute, 0xd000_0000, &[]), "MMIO instruction fetch at 0xd0000000");
+    }
+
+    /// Test port I/O exits are decoded and IN results merged into RAX
+    #[test]
+    fn test_port_io_access_decode() {
+        // OUT of one byte to COM1
+        let out = PortIoAccess::decode(0x3, 0x3f8, 0x1234_5641, 0x1002);
+        assert_eq!(out, PortIoAccess { port: 0x3f8, size: 1, is_write: true, is_string: false, rax: 0x1234_5641, next_rip: 0x1002 });
+        assert_eq!(out.get_write_data()[..out.size], [0x41]);
+        assert_eq!(out.get_completed_rax(&[]), 0x1234_5641);
+
+        // IN of two bytes keeps the upper bytes, IN of four clears the upper half
+        let in_word = PortIoAccess::decode(0x4, 0x70, 0xffff_ffff_ffff_ffff, 0x1002);
+        assert!(!in_word.is_write);
+        assert_eq!(in_word.get_completed_rax(&[0x34, 0x12]), 0xffff_ffff_ffff_1234);
+        let in_dword = PortIoAccess::decode(0x8, 0xcfc, 0xffff_ffff_ffff_ffff, 0x1002);
+        assert_eq!(in_dword.get_completed_rax(&[0x78, 0x56, 0x34, 0x12]), 0x1234_5678);
+
+        // INSB
+        assert!(PortIoAccess::decode(0x12, 0x1f0, 0, 0).is_string);
+    }
+
     /// Test running a vCPU with an invalid partition handle should fail
     #[test]
     fn test_run_vcpu_invalid_partition() {
@@ -0,0 +1,72 @@
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use AsgardManager::device_emulation::balloon::{VirtioBalloon, BALLOON_DEFLATE_QUEUE, BALLOON_INFLATE_QUEUE, BALLOON_PAGE_SIZE};
+use AsgardManager::device_emulation::virtio_mmio::VirtioDevice;
+use super::{create_guest_memory, setup_queue};
+
+// Helper: hand the device a list of page frame numbers at `addr`, the way the driver does
+fn add_page_list(mem: &GuestMemoryMmap, base: u64, index: u16, addr: u64, pfns: &[u32]) {
+    for (position, pfn) in pfns.iter().enumerate() {
+        mem.write_obj(*pfn, GuestAddress(addr + 4 * position as u64)).unwrap();
+    }
+    let desc = GuestAddress(base + 16 * index as u64);
+    mem.write_obj(addr, desc).unwrap();
+    mem.write_obj(4 * pfns.len() as u32, GuestAddress(desc.0 + 8)).unwrap();
+    mem.write_obj(0u16, GuestAddress(desc.0 + 12)).unwrap();
+    mem.write_obj(0u16, GuestAddress(desc.0 + 14)).unwrap();
+
+    let avail = base + 0x1000;
+    mem.write_obj(index, GuestAddress(avail + 4 + 2 * index as u64)).unwrap();
+    mem.write_obj(index + 1, GuestAddress(avail + 2)).unwrap();
+}
+
+// Helper: return the number of used buffers
+fn read_used_index(mem: &GuestMemoryMmap, base: u64) -> u16 {
+    mem.read_obj(GuestAddress(base + 0x2000 + 2)).unwrap()
+}
+
+#[test]
+fn test_virtio_balloon_config_space() {
+    let mut balloon = VirtioBalloon::new(create_guest_memory()).expect("Failed to create balloon");
+    assert_eq!(balloon.get_device_type(), 5);
+    assert_eq!(balloon.get_num_queues(), 2);
+
+    balloon.set_target(64 * BALLOON_PAGE_SIZE + 100);
+    assert_eq!(balloon.get_target(), 64 * BALLOON_PAGE_SIZE);
+    let mut config = [0u8; 8];
+    balloon.read_config(0, &mut config);
+    assert_eq!(config, [64, 0, 0, 0, 0, 0, 0, 0]);
+
+    // The driver reports the balloon size; the target is read-only
+    balloon.write_config(4, &32u32.to_le_bytes());
+    balloon.write_config(0, &1u32.to_le_bytes());
+    assert_eq!(balloon.get_actual(), 32 * BALLOON_PAGE_SIZE);
+    assert_eq!(balloon.get_target(), 64 * BALLOON_PAGE_SIZE);
+
+    balloon.reset();
+    assert_eq!(balloon.get_actual(), 0);
+    assert_eq!(balloon.get_target(), 64 * BALLOON_PAGE_SIZE);
+}
+
+#[test]
+fn test_virtio_balloon_releases_inflated_pages() {
+    let mem = create_guest_memory();
+    let mut balloon = VirtioBalloon::new(mem.clone()).expect("Failed to create balloon");
+    setup_queue(balloon.get_queue_mut(BALLOON_INFLATE_QUEUE).unwrap(), 0x1000);
+    setup_queue(balloon.get_queue_mut(BALLOON_DEFLATE_QUEUE).unwrap(), 0x4000);
+    mem.write_slice(&[0xaa; 16], GuestAddress(0x20000)).unwrap();
+
+    add_page_list(&mem, 0x1000, 0, 0x8000, &[0x20, 0x21, 0x30]);
+    balloon.process_queue(BALLOON_INFLATE_QUEUE);
+    assert_eq!(read_used_index(&mem, 0x1000), 1);
+    assert_eq!(balloon.get_released_bytes(), 3 * BALLOON_PAGE_SIZE);
+    let mut released = [0xffu8; 16];
+    mem.read_slice(&mut released, GuestAddress(0x20000)).unwrap();
+    assert_eq!(released, [0u8; 16]);
+
+    // Deflated pages are just handed back
+    add_page_list(&mem, 0x4000, 0, 0x9000, &[0x20]);
+    balloon.process_queue(BALLOON_DEFLATE_QUEUE);
+    assert_eq!(read_used_index(&mem, 0x4000), 1);
+    assert_eq!(balloon.get_released_bytes(), 3 * BALLOON_PAGE_SIZE);
+    assert!(!balloon.process_queue(2));
+}
@@ -1,11 +1,18 @@
-use std::fs::OpenOptions;
 use std::io::Write;
 use memmap2::MmapMut;
-use vm_memory::{GuestMemoryMmap, GuestAddress};
+use vm_memory::{Bytes, GuestMemoryMmap, GuestAddress};
 use virtio_queue::QueueT;
 use kvm_ioctls::{Kvm, VmFd};
-use AsgardManager::device_emulation::block_device::linux::VirtioBlockDevice; // Adjust crate path as needed
-use AsgardManager::utils::signals::linux::Interrupt;
+use AsgardManager::device_emulation::block_device::virtio_block::{CacheMode, VirtioBlockDevice, DEFAULT_MAX_QUEUE_DEPTH}; // Adjust crate path as needed
+use AsgardManager::device_emulation::block_device::disk_backend::{DiskBackend, FileBackend};
+use AsgardManager::utils::signals::{Interrupt, TriggerMode};
+use AsgardManager::utils::signals::gsi_allocator::GsiConflictPolicy;
+use AsgardManager::utils::signals::irq_router::IrqRouter;
+use AsgardManager::device_emulation::virtqueue_config::VirtqueueConfig;
+use AsgardManager::device_emulation::mmio::{MmioBus, MmioDevice};
+use AsgardManager::device_emulation::virtio_mmio::{MmioTransport, VirtioDevice};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // Helper: create guest memory of 64 KiB at address 0
 fn create_guest_memory() -> GuestMemoryMmap {
@@ -13,23 +20,22 @@ fn create_guest_memory() -> GuestMemoryMmap {
 }
 
 // Helper: create a temporary disk image mmap of specified size filled with zeros
+// (a separate file per call, as tests run in parallel and write to their images)
 fn create_disk_image(size: usize) -> MmapMut {
-    let mut path = std::env::temp_dir();
-    path.push("virtio_block_device_test.img");
-
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&path)
-        .expect("Failed to create disk image file");
+    let mut file = tempfile::tempfile().expect("Failed to create disk image file");
     file.set_len(size as u64).expect("Failed to set disk image size");
     file.write_all(&vec![0u8; size]).expect("Failed to write disk image");
 
     unsafe { MmapMut::map_mut(&file).expect("Failed to mmap disk image") }
 }
 
+// Helper: copy `len` bytes at `sector` out of the device's disk
+fn read_disk(device: &VirtioBlockDevice, sector: u64, len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    device.disk_image.lock().unwrap().read_sectors(sector, &mut data).expect("Failed to read the disk");
+    data
+}
+
 // Helper: create a VmFd with IRQ chip initialized (required for Interrupt)
 fn create_vm_fd() -> VmFd {
     let kvm = Kvm::new().expect("Failed to open /dev/kvm");
@@ -39,20 +45,82 @@ fn create_vm_fd() -> VmFd {
     vm
 }
 
-// Helper: create a real Interrupt instance using VmFd and a GSI number
-fn create_real_interrupt() -> Interrupt {
-    let vm_fd = create_vm_fd();
-    let gsi = 5; // example IRQ number
-    Interrupt::new(vm_fd, gsi).expect("Failed to create Interrupt")
+// Helper: create a real Interrupt instance on a GSI picked by the VM's IrqRouter
+fn create_real_interrupt() -> Box<dyn Interrupt> {
+    let router = IrqRouter::new(create_vm_fd(), GsiConflictPolicy::Fail);
+    Box::new(router.create_interrupt("virtio-blk0", None, TriggerMode::Edge).expect("Failed to create Interrupt"))
+}
+
+// Helper: perform a 32-bit MMIO read
+fn read_mmio_u32(device: &mut MmioTransport<VirtioBlockDevice>, offset: u64) -> u32 {
+    let mut data = [0u8; 4];
+    device.read_mmio(offset, &mut data);
+    u32::from_le_bytes(data)
+}
+
+// Helper: set up queue 0 through the virtio-mmio queue registers like a guest driver
+fn setup_queue(device: &mut MmioTransport<VirtioBlockDevice>, size: u32) {
+    device.write_mmio(0x030, &0u32.to_le_bytes());      // VIRTIO_MMIO_QUEUE_SEL
+    device.write_mmio(0x038, &size.to_le_bytes());      // VIRTIO_MMIO_QUEUE_NUM
+    device.write_mmio(0x080, &0x4000u32.to_le_bytes()); // VIRTIO_MMIO_QUEUE_DESC_LOW
+    device.write_mmio(0x090, &0x5000u32.to_le_bytes()); // VIRTIO_MMIO_QUEUE_AVAIL_LOW
+    device.write_mmio(0x0a0, &0x6000u32.to_le_bytes()); // VIRTIO_MMIO_QUEUE_USED_LOW
+    device.write_mmio(0x044, &1u32.to_le_bytes());      // VIRTIO_MMIO_QUEUE_READY
+}
+
+// Helper: point queue 0 at a split virtqueue of 16 descriptors at address 0 and mark it ready
+fn setup_request_queue(device: &VirtioBlockDevice) {
+    let mut queue = device.queues[0].borrow_mut();
+    queue.set_size(16);
+    queue.set_desc_table_address(Some(0), Some(0));
+    queue.set_avail_ring_address(Some(0x1000), Some(0));
+    queue.set_used_ring_address(Some(0x2000), Some(0));
+    queue.set_ready(true);
+}
+
+/// Descriptor flag chaining the next descriptor.
+const VRING_DESC_F_NEXT: u16 = 1;
+/// Descriptor flag marking a buffer the device writes to.
+const VRING_DESC_F_WRITE: u16 = 2;
+
+// Helper: write `descriptors` (address, length, flags) as a chain starting at descriptor
+// `first` and make it available in avail ring slot `index`
+fn add_chain(mem: &GuestMemoryMmap, index: u16, first: u16, descriptors: &[(u64, u32, u16)]) {
+    for (position, &(addr, len, flags)) in descriptors.iter().enumerate() {
+        let number = first + position as u16;
+        let desc = GuestAddress(16 * number as u64);
+        let next = if position + 1 < descriptors.len() { VRING_DESC_F_NEXT } else { 0 };
+        mem.write_obj(addr, desc).unwrap();
+        mem.write_obj(len, GuestAddress(desc.0 + 8)).unwrap();
+        mem.write_obj(flags | next, GuestAddress(desc.0 + 12)).unwrap();
+        mem.write_obj(number + 1, GuestAddress(desc.0 + 14)).unwrap();
+    }
+    mem.write_obj(first, GuestAddress(0x1004 + 2 * index as u64)).unwrap();
+    mem.write_obj(index + 1, GuestAddress(0x1002)).unwrap();
+}
+
+// Helper: write a request header at 0x3000
+fn write_request_header(mem: &GuestMemoryMmap, request_type: u32, sector: u64) {
+    mem.write_obj(request_type, GuestAddress(0x3000)).unwrap();
+    mem.write_obj(sector, GuestAddress(0x3008)).unwrap();
+}
+
+// Helper: make a request header (0x3000), data buffer (0x4000) and status byte (0x5000) available
+// as descriptor chain number `index`, and return the address of the status byte
+fn add_request(mem: &GuestMemoryMmap, index: u16, request_type: u32, sector: u64, len: u32) -> GuestAddress {
+    write_request_header(mem, request_type, sector);
+    let data_flags = if request_type == 0 { VRING_DESC_F_WRITE } else { 0 }; // VIRTIO_BLK_T_IN writes to guest memory
+    add_chain(mem, index, 3 * index, &[(0x3000, 16, 0), (0x4000, len, data_flags), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    mem.write_obj(0xffu8, GuestAddress(0x5000)).unwrap();
+    GuestAddress(0x5000)
 }
 
 #[test]
 fn test_virtio_block_device_new() {
     let mem = create_guest_memory();
     let disk_image = create_disk_image(512 * 1024); // 512 KiB
-    let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt);
+    let device = VirtioBlockDevice::new(mem, disk_image);
     assert!(device.is_ok(), "VirtioBlockDevice::new should succeed");
 }
 
@@ -62,14 +130,41 @@ fn test_virtio_block_device_read_mmio() {
     let disk_image = create_disk_image(512 * 1024);
     let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
 
-    assert_eq!(device.read_mmio(0x000), 0x74726976); // VIRTIO_MMIO_MAGIC_VALUE
-    assert_eq!(device.read_mmio(0x004), 2);           // VIRTIO_MMIO_VERSION
-    assert_eq!(device.read_mmio(0x008), 2);           // VIRTIO_ID_BLOCK
-    assert_eq!(device.read_mmio(0x00c), 0x554d4551);  // VIRTIO_MMIO_VENDOR_ID
-    assert_eq!(device.read_mmio(0x010), 0);           // Host features (none)
-    assert_eq!(device.read_mmio(0x100), 0);           // Unknown offset returns 0
+    assert_eq!(read_mmio_u32(&mut device, 0x000), 0x74726976); // VIRTIO_MMIO_MAGIC_VALUE
+    assert_eq!(read_mmio_u32(&mut device, 0x004), 2);           // VIRTIO_MMIO_VERSION
+    assert_eq!(read_mmio_u32(&mut device, 0x008), 2);           // VIRTIO_ID_BLOCK
+    assert_eq!(read_mmio_u32(&mut device, 0x00c), 0x554d4551);  // VIRTIO_MMIO_VENDOR_ID
+    assert_eq!(read_mmio_u32(&mut device, 0x010), 0x1a56);      // SIZE_MAX, SEG_MAX, GEOMETRY, BLK_SIZE, FLUSH, CONFIG_WCE, MQ
+    assert_eq!(read_mmio_u32(&mut device, 0x100), 1024);        // Capacity in sectors
+    assert_eq!(read_mmio_u32(&mut device, 0x200), 0);           // Past the config space returns 0
+}
+
+#[test]
+fn test_virtio_block_device_config_space() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(8 * 1024 * 1024 + 100); // Partial last sector
+    let interrupt = create_real_interrupt();
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+
+    let mut capacity = [0u8; 8];
+    device.read_mmio(0x100, &mut capacity);
+    assert_eq!(u64::from_le_bytes(capacity), 16384);
+    assert_eq!(read_mmio_u32(&mut device, 0x10c), 126);         // seg_max
+    assert_eq!(read_mmio_u32(&mut device, 0x110), 0x3f10_0010); // 16 cylinders, 16 heads, 63 sectors
+    assert_eq!(read_mmio_u32(&mut device, 0x114), 512);         // blk_size
+    let mut heads = [0u8; 1];
+    device.read_mmio(0x112, &mut heads);
+    assert_eq!(heads, [16]);
+
+    // Growing the image changes the capacity and bumps the config generation
+    assert!(device.get_device_mut().set_disk_image(create_disk_image(16 * 1024 * 1024)));
+    device.signal_config_change().expect("Failed to signal config change");
+    assert_eq!(read_mmio_u32(&mut device, 0x100), 32768);
+    assert_eq!(read_mmio_u32(&mut device, 0x0fc), 1);           // VIRTIO_MMIO_CONFIG_GENERATION
+    assert_eq!(read_mmio_u32(&mut device, 0x060), 0x2);         // VIRTIO_MMIO_INT_CONFIG
+    assert!(!device.get_device_mut().set_disk_image(create_disk_image(16 * 1024 * 1024)));
 }
 
 #[test]
@@ -78,19 +173,18 @@ fn test_virtio_block_device_write_mmio_queue_notify_no_panic() {
     let disk_image = create_disk_image(512 * 1024);
     let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
 
     // Writing to QUEUE_NOTIFY offset triggers process_descriptor_chain; should not panic
-    device.write_mmio(0x50); // VIRTIO_MMIO_QUEUE_NOTIFY is 0x50
+    device.write_mmio(0x50, &0u32.to_le_bytes()); // VIRTIO_MMIO_QUEUE_NOTIFY is 0x50
 }
 
 #[test]
 fn test_virtio_block_device_process_descriptor_chain_empty_queue() {
     let mem = create_guest_memory();
     let disk_image = create_disk_image(512 * 1024);
-    let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
 
     // The queue is empty, so processing descriptor chain should return immediately without error
     device.process_descriptor_chain();
@@ -100,13 +194,12 @@ fn test_virtio_block_device_process_descriptor_chain_empty_queue() {
 fn test_virtio_block_device_invalid_queue() {
     let mem = create_guest_memory();
     let disk_image = create_disk_image(512 * 1024);
-    let interrupt = create_real_interrupt();
 
     // Create a device but manually set queue ready to false to simulate invalid queue
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
 
     {
-        let mut queue = device.queue.borrow_mut();
+        let mut queue = device.queues[0].borrow_mut();
         queue.set_ready(false);
     }
 
@@ -120,10 +213,10 @@ fn test_virtio_block_device_trigger_interrupt() {
     let disk_image = create_disk_image(512 * 1024);
     let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
 
     // Directly trigger interrupt, expect Ok result
-    let result = device.interrupt_controller.trigger();
+    let result = device.get_interrupt().trigger();
     assert!(result.is_ok(), "Interrupt trigger should succeed");
 }
 
@@ -131,9 +224,8 @@ fn test_virtio_block_device_trigger_interrupt() {
 fn test_virtio_block_device_process_descriptor_chain_invalid_request_type() {
     let mem = create_guest_memory();
     let disk_image = create_disk_image(512 * 1024);
-    let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
 
     // Manually mark queue ready to true and push invalid descriptor chain if possible
     // This is complex without real guest interaction, so here we just ensure no panic occurs
@@ -144,18 +236,549 @@ fn test_virtio_block_device_process_descriptor_chain_invalid_request_type() {
 fn test_virtio_block_device_read_write_disk_image_bounds() {
     let mem = create_guest_memory();
     let disk_image = create_disk_image(512 * 1024);
-    let interrupt = create_real_interrupt();
 
-    let device = VirtioBlockDevice::new(mem, disk_image, 0x1000, interrupt).expect("Failed to create device");
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
 
     // Write to disk image directly and verify content
-    {
-        let mut disk_img = device.disk_image.borrow_mut();
-        disk_img[0..4].copy_from_slice(&[1, 2, 3, 4]);
+    device.disk_image.lock().unwrap().write_sectors(0, &[1; 512]).expect("Write should succeed");
+    assert_eq!(read_disk(&device, 0, 512), [1; 512], "Disk image content should match written bytes");
+    // Accesses past the end of the image and partial sectors are refused
+    assert!(device.disk_image.lock().unwrap().write_sectors(1023, &[1; 1024]).is_err());
+    assert!(device.disk_image.lock().unwrap().write_sectors(0, &[1, 2, 3, 4]).is_err());
+}
+#[test]
+fn test_virtio_block_device_default_metrics() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
+
+    let metrics = device.metrics();
+    assert_eq!(metrics.max_queue_depth, DEFAULT_MAX_QUEUE_DEPTH);
+    assert_eq!(metrics.inflight, 0);
+    assert_eq!(metrics.completed_requests, 0);
+    assert_eq!(metrics.backpressure_events, 0);
+}
+
+#[test]
+fn test_virtio_block_device_set_max_queue_depth() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
+
+    assert!(device.set_max_queue_depth(16).is_ok());
+    assert_eq!(device.get_max_queue_depth(), 16);
+    assert_eq!(device.metrics().max_queue_depth, 16);
+
+    // Zero depth would stall the queue forever and must be rejected
+    assert!(device.set_max_queue_depth(0).is_err());
+    assert_eq!(device.get_max_queue_depth(), 16);
+}
+
+#[test]
+fn test_virtio_block_device_backpressure_when_saturated() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    device.set_max_queue_depth(2).expect("Failed to set max queue depth");
+    setup_request_queue(&device);
+
+    // More requests outstanding than the queue depth
+    for index in 0..4 {
+        add_request(&mem, index, 0, index as u64, 512); // VIRTIO_BLK_T_IN
+    }
+    device.process_descriptor_chain();
+
+    let metrics = device.metrics();
+    assert_eq!(metrics.completed_requests, 4);
+    assert_eq!(metrics.peak_inflight, 4);
+    assert_eq!(metrics.inflight, 0);
+    assert_eq!(metrics.backpressure_events, 1);
+    assert_eq!(mem.read_obj::<u16>(GuestAddress(0x2002)).unwrap(), 4); // Used ring index
+    // Notifications are back on once the backlog was worked off
+    assert_eq!(mem.read_obj::<u16>(GuestAddress(0x2000)).unwrap(), 0); // VRING_USED_F_NO_NOTIFY cleared
+
+    // A backlog within the queue depth doesn't back-pressure the guest
+    add_request(&mem, 4, 0, 4, 512);
+    device.process_descriptor_chain();
+    assert_eq!(device.metrics().completed_requests, 5);
+    assert_eq!(device.metrics().backpressure_events, 1);
+}
+
+#[test]
+fn test_virtio_block_device_queue_config() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+
+    let config = VirtqueueConfig::for_vcpus(4);
+    let device = VirtioBlockDevice::with_queue_config(mem, disk_image, config).expect("Failed to create device");
+
+    assert_eq!(device.get_queue_config(), config);
+    assert_eq!(device.queues.len(), 4);
+    assert_eq!(device.queues[0].borrow().max_size(), 256);
+    // Every queue waits for the guest driver to configure it
+    assert!(device.queues.iter().all(|queue| !queue.borrow().ready()));
+    device.process_descriptor_chain();
+}
+
+#[test]
+fn test_virtio_block_device_multiqueue_features_and_config() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let interrupt = create_real_interrupt();
+    let device = VirtioBlockDevice::with_queue_config(mem, disk_image, VirtqueueConfig::for_vcpus(4)).expect("Failed to create device");
+    let mut device = MmioTransport::new(device, interrupt);
+
+    assert_ne!(device.get_device().get_device_features() & (1 << 12), 0); // VIRTIO_BLK_F_MQ
+    assert_eq!(read_mmio_u32(&mut device, 0x010) & (1 << 12), 1 << 12);
+    let mut num_queues = [0u8; 2];
+    device.read_mmio(0x100 + 34, &mut num_queues);
+    assert_eq!(u16::from_le_bytes(num_queues), 4);
+    assert_eq!(read_mmio_u32(&mut device, 0x034), 256); // VIRTIO_MMIO_QUEUE_NUM_MAX
+
+    // A single queue device reports one queue
+    let single = VirtioBlockDevice::new(create_guest_memory(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    let mut config = [0u8; 2];
+    single.read_config(34, &mut config);
+    assert_eq!(u16::from_le_bytes(config), 1);
+}
+
+#[test]
+fn test_virtio_block_device_default_queue_config() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+
+    let device = VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device");
+    assert_eq!(device.queues.len(), 1);
+    assert_eq!(device.queues[0].borrow().max_size(), 1024);
+}
+
+#[test]
+fn test_virtio_block_device_mmio_access_widths() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let interrupt = create_real_interrupt();
+
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+
+    // Byte and half-word reads return the addressed bytes of the magic value
+    let mut byte = [0u8; 1];
+    device.read_mmio(0x000, &mut byte);
+    assert_eq!(byte, [0x76]);
+    let mut word = [0u8; 2];
+    device.read_mmio(0x002, &mut word);
+    assert_eq!(u16::from_le_bytes(word), 0x7472);
+
+    // A 64-bit read spans the magic value and the version
+    let mut qword = [0u8; 8];
+    device.read_mmio(0x000, &mut qword);
+    assert_eq!(u64::from_le_bytes(qword), 0x0000_0002_7472_6976);
+
+    // Invalid widths read as zero and writes of invalid widths are ignored
+    let mut odd = [0xFFu8; 3];
+    device.read_mmio(0x000, &mut odd);
+    assert_eq!(odd, [0, 0, 0]);
+    device.write_mmio(0x50, &[0u8; 3]);
+}
+
+#[test]
+fn test_virtio_block_device_interrupt_ack_byte_write() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let interrupt = create_real_interrupt();
+
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+
+    assert_eq!(read_mmio_u32(&mut device, 0x060), 0); // VIRTIO_MMIO_INTERRUPT_STATUS
+    // A byte-wide ACK of nothing pending must leave the status clear and not panic
+    device.write_mmio(0x064, &[0x1]); // VIRTIO_MMIO_INTERRUPT_ACK
+    assert_eq!(read_mmio_u32(&mut device, 0x060), 0);
+}
+
+#[test]
+fn test_virtio_block_device_level_interrupt_resample() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let router = IrqRouter::new(create_vm_fd(), GsiConflictPolicy::Fail);
+    let interrupt = Box::new(router.create_interrupt("virtio-blk0", None, TriggerMode::Level).expect("Failed to create level interrupt"));
+    let device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+
+    // Nothing pending: the resample neither fails nor re-asserts the line
+    assert!(device.get_interrupt().get_resample_event().is_some());
+    assert!(device.handle_resample().is_ok());
+}
+
+#[test]
+fn test_virtio_block_device_on_mmio_bus() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let interrupt = create_real_interrupt();
+    let device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+
+    let mut bus = MmioBus::new();
+    bus.register(0xd000_0000, 0x1000, Arc::new(Mutex::new(device))).expect("Failed to register device");
+
+    let mut data = [0u8; 4];
+    assert!(bus.read(0xd000_0008, &mut data));
+    assert_eq!(u32::from_le_bytes(data), 2); // VIRTIO_ID_BLOCK
+    assert!(bus.write(0xd000_0050, &0u32.to_le_bytes())); // QueueNotify
+    assert!(!bus.read(0xd000_1000, &mut data));
+}
+
+#[test]
+fn test_virtio_block_device_status_negotiation() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let interrupt = create_real_interrupt();
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+
+    // VIRTIO_F_VERSION_1 is bit 0 of the second features word
+    device.write_mmio(0x014, &1u32.to_le_bytes()); // VIRTIO_MMIO_DEVICE_FEATURES_SEL
+    assert_eq!(read_mmio_u32(&mut device, 0x010), 1);
+
+    // Skipping DRIVER is refused and leaves the status unchanged
+    device.write_mmio(0x070, &0x1u32.to_le_bytes()); // ACKNOWLEDGE
+    device.write_mmio(0x070, &0x9u32.to_le_bytes()); // ACKNOWLEDGE | FEATURES_OK
+    assert_eq!(read_mmio_u32(&mut device, 0x070), 0x1);
+
+    device.write_mmio(0x070, &0x3u32.to_le_bytes()); // ACKNOWLEDGE | DRIVER
+    device.write_mmio(0x024, &1u32.to_le_bytes());   // VIRTIO_MMIO_DRIVER_FEATURES_SEL
+    device.write_mmio(0x020, &1u32.to_le_bytes());   // VIRTIO_F_VERSION_1
+    device.write_mmio(0x070, &0xbu32.to_le_bytes()); // ... | FEATURES_OK
+    setup_queue(&mut device, 256);
+    device.write_mmio(0x070, &0xfu32.to_le_bytes()); // ... | DRIVER_OK
+    assert_eq!(read_mmio_u32(&mut device, 0x070), 0xf);
+    assert!(device.get_core().is_driver_ok());
+    device.write_mmio(0x50, &0u32.to_le_bytes());
+
+    // Writing 0 resets the device and the queue the driver set up
+    device.write_mmio(0x070, &0u32.to_le_bytes());
+    assert_eq!(read_mmio_u32(&mut device, 0x070), 0);
+    assert_eq!(device.get_core().get_driver_features(), 0);
+    assert!(!device.get_device().queues[0].borrow().ready());
+    assert_eq!(read_mmio_u32(&mut device, 0x044), 0); // VIRTIO_MMIO_QUEUE_READY
+}
+
+#[test]
+fn test_virtio_block_device_queue_setup_through_mmio() {
+    let mem = create_guest_memory();
+    let disk_image = create_disk_image(512 * 1024);
+    let interrupt = create_real_interrupt();
+    let mut device = MmioTransport::new(VirtioBlockDevice::new(mem, disk_image).expect("Failed to create device"), interrupt);
+    for status in [0x1u32, 0x3, 0xb] {
+        device.write_mmio(0x070, &status.to_le_bytes());
     }
 
+    assert_eq!(read_mmio_u32(&mut device, 0x034), 1024); // VIRTIO_MMIO_QUEUE_NUM_MAX
+    // A size above QueueNumMax is refused
+    setup_queue(&mut device, 2048);
+    assert_eq!(read_mmio_u32(&mut device, 0x044), 0);
+
+    setup_queue(&mut device, 256);
+    assert_eq!(read_mmio_u32(&mut device, 0x044), 1);
     {
-        let disk_img = device.disk_image.borrow();
-        assert_eq!(&disk_img[0..4], &[1, 2, 3, 4], "Disk image content should match written bytes");
+        let queue = device.get_device().queues[0].borrow();
+        assert!(queue.ready());
+        assert_eq!(queue.size(), 256);
+        assert_eq!(queue.desc_table(), 0x4000);
+        assert_eq!(queue.avail_ring(), 0x5000);
+        assert_eq!(queue.used_ring(), 0x6000);
+    }
+
+    // Rings outside of guest memory leave the queue inactive
+    device.write_mmio(0x044, &0u32.to_le_bytes());
+    device.write_mmio(0x080, &0x10_0000u32.to_le_bytes()); // VIRTIO_MMIO_QUEUE_DESC_LOW
+    device.write_mmio(0x044, &1u32.to_le_bytes());
+    assert_eq!(read_mmio_u32(&mut device, 0x044), 0);
+    assert!(!device.get_device().queues[0].borrow().ready());
+}
+
+#[test]
+fn test_virtio_block_device_request_within_bounds() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    setup_request_queue(&device);
+
+    // Write the last sector of the disk, then read it back
+    mem.write_slice(&[0xab; 512], GuestAddress(0x4000)).unwrap();
+    let status = add_request(&mem, 0, 1, 1023, 512); // VIRTIO_BLK_T_OUT
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0); // VIRTIO_BLK_S_OK
+    assert_eq!(read_disk(&device, 1023, 512), [0xab; 512]);
+
+    mem.write_slice(&[0; 512], GuestAddress(0x4000)).unwrap();
+    let status = add_request(&mem, 1, 0, 1023, 512); // VIRTIO_BLK_T_IN
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0);
+    let mut data = [0u8; 512];
+    mem.read_slice(&mut data, GuestAddress(0x4000)).unwrap();
+    assert_eq!(data, [0xab; 512]);
+}
+
+#[test]
+fn test_virtio_block_device_read_only() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    assert_eq!(device.get_device_features() & (1 << 5), 0); // VIRTIO_BLK_F_RO
+    device.set_read_only(true);
+    assert!(device.is_read_only());
+    assert_ne!(device.get_device_features() & (1 << 5), 0);
+    setup_request_queue(&device);
+
+    // Writes fail and leave the disk alone, reads still work
+    mem.write_slice(&[0xab; 512], GuestAddress(0x4000)).unwrap();
+    let status = add_request(&mem, 0, 1, 7, 512); // VIRTIO_BLK_T_OUT
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 1); // VIRTIO_BLK_S_IOERR
+    assert_eq!(read_disk(&device, 7, 512), [0; 512]);
+
+    let status = add_request(&mem, 1, 0, 7, 512); // VIRTIO_BLK_T_IN
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0); // VIRTIO_BLK_S_OK
+}
+
+#[test]
+fn test_virtio_block_device_out_of_range_requests() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    setup_request_queue(&device);
+
+    // Past the end, straddling the end, and a sector whose byte offset overflows
+    let requests = [(0u32, 1024u64, 512u32), (1, 1023, 1024), (0, u64::MAX / 256, 512)];
+    for (index, (request_type, sector, len)) in requests.into_iter().enumerate() {
+        let status = add_request(&mem, index as u16, request_type, sector, len);
+        device.process_descriptor_chain();
+        assert_eq!(mem.read_obj::<u8>(status).unwrap(), 1, "request {} should fail", index); // VIRTIO_BLK_S_IOERR
+    }
+    assert_eq!(device.metrics().completed_requests, 3);
+    assert!(read_disk(&device, 0, 512 * 1024).iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn test_virtio_block_device_multi_segment_requests() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    setup_request_queue(&device);
+    let used_len = |index: u64| mem.read_obj::<u32>(GuestAddress(0x2008 + 8 * index)).unwrap();
+
+    // Write three sectors from three scattered buffers
+    for (segment, addr) in [0x4000u64, 0x4800, 0x4400].into_iter().enumerate() {
+        mem.write_slice(&[segment as u8 + 1; 512], GuestAddress(addr)).unwrap();
+    }
+    write_request_header(&mem, 1, 8); // VIRTIO_BLK_T_OUT
+    add_chain(&mem, 0, 0, &[(0x3000, 16, 0), (0x4000, 512, 0), (0x4800, 512, 0), (0x4400, 512, 0), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 0); // VIRTIO_BLK_S_OK
+    assert_eq!(used_len(0), 1536);
+    let disk = read_disk(&device, 8, 3 * 512);
+    assert_eq!(disk, [[1u8; 512], [2; 512], [3; 512]].concat());
+
+    // Read them back into segments of different sizes
+    write_request_header(&mem, 0, 8); // VIRTIO_BLK_T_IN
+    add_chain(&mem, 1, 5, &[(0x3000, 16, 0), (0x6000, 1024, VRING_DESC_F_WRITE), (0x7000, 512, VRING_DESC_F_WRITE), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 0);
+    assert_eq!(used_len(1), 1536);
+    let mut data = vec![0u8; 1536];
+    mem.read_slice(&mut data[..1024], GuestAddress(0x6000)).unwrap();
+    mem.read_slice(&mut data[1024..], GuestAddress(0x7000)).unwrap();
+    assert_eq!(data, disk);
+
+    // A read whose second segment isn't device-writable fails without touching the first
+    mem.write_slice(&[0; 1024], GuestAddress(0x6000)).unwrap();
+    add_chain(&mem, 2, 9, &[(0x3000, 16, 0), (0x6000, 512, VRING_DESC_F_WRITE), (0x7000, 512, 0), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 1); // VIRTIO_BLK_S_IOERR
+    assert_eq!(used_len(2), 0);
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x6000)).unwrap(), 0);
+}
+
+#[test]
+fn test_virtio_block_device_flush_and_cache_mode() {
+    let mem = create_guest_memory();
+    let interrupt = create_real_interrupt();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    setup_request_queue(&device);
+    let mut transport = MmioTransport::new(device, interrupt);
+
+    // Writeback by default; the guest switches to writethrough through the writeback field
+    let mut writeback = [0u8; 1];
+    transport.read_mmio(0x120, &mut writeback);
+    assert_eq!(writeback, [1]);
+    transport.write_mmio(0x120, &[0]);
+    assert_eq!(transport.get_device().get_cache_mode(), CacheMode::Writethrough);
+    transport.read_mmio(0x120, &mut writeback);
+    assert_eq!(writeback, [0]);
+    // The rest of the configuration space is read-only
+    transport.write_mmio(0x100, &[0xff]);
+    assert_eq!(transport.get_device().get_capacity(), 1024);
+
+    let device = transport.get_device();
+    mem.write_slice(&[0x5a; 512], GuestAddress(0x4000)).unwrap();
+    let status = add_request(&mem, 0, 1, 3, 512); // VIRTIO_BLK_T_OUT
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0); // VIRTIO_BLK_S_OK
+    assert_eq!(read_disk(device, 3, 512), [0x5a; 512]);
+
+    device.set_cache_mode(CacheMode::Writeback);
+    write_request_header(&mem, 4, 0); // VIRTIO_BLK_T_FLUSH
+    add_chain(&mem, 1, 3, &[(0x3000, 16, 0), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    mem.write_obj(0xffu8, GuestAddress(0x5000)).unwrap();
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 0);
+    assert_eq!(mem.read_obj::<u32>(GuestAddress(0x2010)).unwrap(), 0); // Nothing written to guest buffers
+}
+
+#[test]
+fn test_virtio_block_device_io_worker() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    setup_request_queue(&device);
+    let mut transport = MmioTransport::new(device, create_real_interrupt());
+    let notifier = transport.get_vring_notifier();
+    transport.get_device_mut().start_io_worker("virtio-blk-test-io", notifier).expect("Failed to start the I/O worker");
+
+    mem.write_slice(&[0x42; 512], GuestAddress(0x4000)).unwrap();
+    let status = add_request(&mem, 0, 1, 7, 512); // VIRTIO_BLK_T_OUT
+    // The notification returns right away; the worker raises the interrupt once done
+    assert!(!transport.get_device_mut().process_queue(0));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while transport.get_interrupt_status() == 0 {
+        assert!(Instant::now() < deadline, "the I/O worker should complete the request");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(transport.get_interrupt_status(), 0x1); // VIRTIO_MMIO_INT_VRING
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0); // VIRTIO_BLK_S_OK
+    assert_eq!(read_disk(transport.get_device(), 7, 512), [0x42; 512]);
+    assert_eq!(transport.get_device().metrics().completed_requests, 1);
+}
+
+#[test]
+fn test_virtio_block_device_poll_window() {
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    assert_eq!(device.get_poll_window(), Duration::ZERO);
+    device.set_poll_window(Duration::from_millis(500));
+    assert_eq!(device.get_poll_window(), Duration::from_millis(500));
+    setup_request_queue(&device);
+    let mut transport = MmioTransport::new(device, create_real_interrupt());
+    let notifier = transport.get_vring_notifier();
+    transport.get_device_mut().start_io_worker("virtio-blk-poll-io", notifier).expect("Failed to start the I/O worker");
+
+    let status = add_request(&mem, 0, 1, 3, 512); // VIRTIO_BLK_T_OUT
+    assert!(!transport.get_device_mut().process_queue(0));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while transport.get_device().metrics().completed_requests < 1 {
+        assert!(Instant::now() < deadline, "the I/O worker should complete the request");
+        std::thread::sleep(Duration::from_millis(1));
     }
-}
\ No newline at end of file
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0); // VIRTIO_BLK_S_OK
+    // Once polling, the worker asks the guest not to notify the device
+    while mem.read_obj::<u16>(GuestAddress(0x2000)).unwrap() & 1 == 0 { // VRING_USED_F_NO_NOTIFY
+        assert!(Instant::now() < deadline, "the I/O worker should suppress notifications while polling");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    // A request made available without a notification is picked up all the same
+    mem.write_slice(&[0x42; 512], GuestAddress(0x4000)).unwrap();
+    add_request(&mem, 1, 1, 5, 512);
+    while transport.get_device().metrics().completed_requests < 2 {
+        assert!(Instant::now() < deadline, "the polling I/O worker should find the request");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(read_disk(transport.get_device(), 5, 512), [0x42; 512]);
+}
+
+#[test]
+fn test_virtio_block_device_file_backend() {
+    let image = tempfile::NamedTempFile::new().expect("Failed to create disk image file");
+    image.as_file().set_len(512 * 1024).expect("Failed to set disk image size");
+    let backend = FileBackend::open(image.path()).expect("Failed to open the disk image");
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::with_storage(mem.clone(), Box::new(backend), VirtqueueConfig::default()).expect("Failed to create device");
+    assert_eq!(device.get_capacity(), 1024);
+    setup_request_queue(&device);
+    device.set_cache_mode(CacheMode::Writethrough);
+
+    // Segments needn't be whole sectors as long as the request is
+    mem.write_slice(&[0x33; 256], GuestAddress(0x4000)).unwrap();
+    mem.write_slice(&[0x44; 768], GuestAddress(0x4800)).unwrap();
+    write_request_header(&mem, 1, 10); // VIRTIO_BLK_T_OUT
+    add_chain(&mem, 0, 0, &[(0x3000, 16, 0), (0x4000, 256, 0), (0x4800, 768, 0), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 0); // VIRTIO_BLK_S_OK
+    let on_disk = std::fs::read(image.path()).unwrap();
+    assert_eq!(&on_disk[10 * 512..12 * 512], &[vec![0x33u8; 256], vec![0x44; 768]].concat()[..]);
+    assert_eq!(read_disk(&device, 11, 512), [0x44; 512]);
+
+    // A request ending mid-sector is refused
+    let status = add_request(&mem, 1, 1, 20, 100); // VIRTIO_BLK_T_OUT
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 1); // VIRTIO_BLK_S_IOERR
+    assert!(read_disk(&device, 20, 512).iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn test_virtio_block_device_snapshot_and_revert() {
+    use AsgardManager::device_emulation::block_device::snapshot::SnapshotDisk;
+
+    let dir = tempfile::TempDir::new().expect("Failed to create snapshot directory");
+    let mem = create_guest_memory();
+    let mut device = VirtioBlockDevice::new(mem.clone(), create_disk_image(512 * 1024)).expect("Failed to create device");
+    setup_request_queue(&device);
+    assert!(device.snapshot("clean").is_err(), "A mapped image alone can't take snapshots");
+
+    let image = tempfile::NamedTempFile::new().expect("Failed to create disk image file");
+    image.as_file().set_len(512 * 1024).expect("Failed to set disk image size");
+    let backend = FileBackend::open(image.path()).expect("Failed to open the disk image");
+    assert!(!device.set_storage(Box::new(SnapshotDisk::new(Box::new(backend), dir.path()))));
+    device.snapshot("clean").expect("Snapshot should succeed");
+
+    mem.write_slice(&[0x55; 512], GuestAddress(0x4000)).unwrap();
+    let status = add_request(&mem, 0, 1, 3, 512); // VIRTIO_BLK_T_OUT
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 0); // VIRTIO_BLK_S_OK
+    assert_eq!(read_disk(&device, 3, 512), [0x55; 512]);
+
+    device.revert("clean").expect("Revert should succeed");
+    assert!(read_disk(&device, 3, 512).iter().all(|&byte| byte == 0));
+    assert!(device.revert("missing").is_err());
+}
+
+#[cfg(feature = "linux_io_uring")]
+#[test]
+fn test_virtio_block_device_io_uring_storage() {
+    use AsgardManager::device_emulation::block_device::uring::{IoUringBackend, IoUringOptions};
+
+    let image = tempfile::NamedTempFile::new().expect("Failed to create disk image file");
+    image.as_file().set_len(512 * 1024).expect("Failed to set disk image size");
+    let backend = IoUringBackend::new(image.path().to_str().unwrap(), IoUringOptions::default()).expect("Failed to create backend");
+    let mem = create_guest_memory();
+    let device = VirtioBlockDevice::with_storage(mem.clone(), Box::new(backend), VirtqueueConfig::default()).expect("Failed to create device");
+    assert_eq!(device.get_capacity(), 1024);
+    setup_request_queue(&device);
+
+    // Write two sectors from scattered buffers, then read them back in one segment
+    mem.write_slice(&[0x11; 512], GuestAddress(0x4000)).unwrap();
+    mem.write_slice(&[0x22; 512], GuestAddress(0x4800)).unwrap();
+    write_request_header(&mem, 1, 1022); // VIRTIO_BLK_T_OUT
+    add_chain(&mem, 0, 0, &[(0x3000, 16, 0), (0x4000, 512, 0), (0x4800, 512, 0), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 0); // VIRTIO_BLK_S_OK
+    let on_disk = std::fs::read(image.path()).unwrap();
+    assert_eq!(&on_disk[1022 * 512..], &[[0x11u8; 512], [0x22; 512]].concat()[..]);
+
+    write_request_header(&mem, 0, 1022); // VIRTIO_BLK_T_IN
+    add_chain(&mem, 1, 4, &[(0x3000, 16, 0), (0x6000, 1024, VRING_DESC_F_WRITE), (0x5000, 1, VRING_DESC_F_WRITE)]);
+    mem.write_obj(0xffu8, GuestAddress(0x5000)).unwrap();
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x5000)).unwrap(), 0);
+    let mut data = [0u8; 1024];
+    mem.read_slice(&mut data, GuestAddress(0x6000)).unwrap();
+    assert_eq!(&data[..], &[[0x11u8; 512], [0x22; 512]].concat()[..]);
+
+    // Past the end fails without touching the image
+    let status = add_request(&mem, 2, 1, 1024, 512); // VIRTIO_BLK_T_OUT
+    device.process_descriptor_chain();
+    assert_eq!(mem.read_obj::<u8>(status).unwrap(), 1); // VIRTIO_BLK_S_IOERR
+}
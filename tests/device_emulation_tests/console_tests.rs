@@ -0,0 +1,52 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use vm_memory::{Bytes, GuestAddress};
+use AsgardManager::device_emulation::console::{VirtioConsole, CONSOLE_RECEIVE_QUEUE, CONSOLE_TRANSMIT_QUEUE};
+use AsgardManager::device_emulation::virtio_mmio::VirtioDevice;
+use super::{add_buffer, create_guest_memory, read_used, setup_queue, VRING_DESC_F_WRITE};
+
+#[tokio::test]
+async fn test_virtio_console_guest_output() {
+    let mem = create_guest_memory();
+    let (mut console, mut stream) = VirtioConsole::new(mem.clone()).expect("Failed to create console");
+    assert_eq!(console.get_device_type(), 3);
+    assert_eq!(console.get_num_queues(), 2);
+
+    setup_queue(console.get_queue_mut(CONSOLE_TRANSMIT_QUEUE).unwrap(), 0x4000);
+    mem.write_slice(b"hello", GuestAddress(0x8000)).unwrap();
+    add_buffer(&mem, 0x4000, 0, 0x8000, 5, 0);
+
+    console.process_queue(CONSOLE_TRANSMIT_QUEUE);
+    assert_eq!(read_used(&mem, 0x4000), (1, 0));
+
+    let mut output = [0u8; 5];
+    stream.read_exact(&mut output).await.unwrap();
+    assert_eq!(&output, b"hello");
+}
+
+#[tokio::test]
+async fn test_virtio_console_host_input() {
+    let mem = create_guest_memory();
+    let (mut console, mut stream) = VirtioConsole::new(mem.clone()).expect("Failed to create console");
+    setup_queue(console.get_queue_mut(CONSOLE_RECEIVE_QUEUE).unwrap(), 0x1000);
+
+    // Input written before the driver provides buffers waits in the device
+    stream.write_all(b"ls\n").await.unwrap();
+    console.process_queue(CONSOLE_RECEIVE_QUEUE);
+    add_buffer(&mem, 0x1000, 0, 0x9000, 64, VRING_DESC_F_WRITE);
+    console.process_queue(CONSOLE_RECEIVE_QUEUE);
+
+    assert_eq!(read_used(&mem, 0x1000), (1, 3));
+    let mut input = [0u8; 3];
+    mem.read_slice(&mut input, GuestAddress(0x9000)).unwrap();
+    assert_eq!(&input, b"ls\n");
+}
+
+#[tokio::test]
+async fn test_virtio_console_stream_after_device_drop() {
+    let (console, mut stream) = VirtioConsole::new(create_guest_memory()).expect("Failed to create console");
+    drop(console);
+
+    let mut output = Vec::new();
+    assert_eq!(stream.read_to_end(&mut output).await.unwrap(), 0);
+    assert!(stream.write_all(b"x").await.is_err());
+}
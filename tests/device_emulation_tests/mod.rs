@@ -1 +1,71 @@
-pub mod block_device_tests;
\ No newline at end of file
+pub mod block_device_tests;
+#[cfg(target_os = "linux")]
+pub mod console_tests;
+#[cfg(target_os = "linux")]
+pub mod rng_tests;
+#[cfg(target_os = "linux")]
+pub mod balloon_tests;
+#[cfg(target_os = "linux")]
+pub mod pmem_tests;
+#[cfg(target_os = "linux")]
+pub mod virtio_9p_tests;
+
+#[cfg(target_os = "linux")]
+use virtio_queue::{QueueSync, QueueT};
+#[cfg(target_os = "linux")]
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+/// Descriptor flag chaining the next descriptor.
+#[cfg(target_os = "linux")]
+const VRING_DESC_F_NEXT: u16 = 1;
+/// Descriptor flag marking a buffer the device writes to.
+#[cfg(target_os = "linux")]
+const VRING_DESC_F_WRITE: u16 = 2;
+/// Size of the virtqueues set up by `setup_queue`.
+#[cfg(target_os = "linux")]
+const QUEUE_SIZE: u16 = 16;
+
+// Helper: create guest memory of 256 KiB at address 0
+#[cfg(target_os = "linux")]
+fn create_guest_memory() -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x40000)]).expect("Failed to create guest memory")
+}
+
+// Helper: lay out a split virtqueue at `base` (descriptors, then avail ring, then used ring) and mark it ready
+#[cfg(target_os = "linux")]
+fn setup_queue(queue: &mut QueueSync, base: u64) {
+    queue.set_size(QUEUE_SIZE);
+    queue.set_desc_table_address(Some(base as u32), Some(0));
+    queue.set_avail_ring_address(Some((base + 0x1000) as u32), Some(0));
+    queue.set_used_ring_address(Some((base + 0x2000) as u32), Some(0));
+    queue.set_ready(true);
+}
+
+// Helper: write descriptor `index` of the table at `base`
+#[cfg(target_os = "linux")]
+fn write_descriptor(mem: &GuestMemoryMmap, base: u64, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+    let desc = GuestAddress(base + 16 * index as u64);
+    mem.write_obj(addr, desc).unwrap();
+    mem.write_obj(len, GuestAddress(desc.0 + 8)).unwrap();
+    mem.write_obj(flags, GuestAddress(desc.0 + 12)).unwrap();
+    mem.write_obj(next, GuestAddress(desc.0 + 14)).unwrap();
+}
+
+// Helper: make a single descriptor buffer available the way a driver would
+#[cfg(target_os = "linux")]
+fn add_buffer(mem: &GuestMemoryMmap, base: u64, index: u16, addr: u64, len: u32, flags: u16) {
+    write_descriptor(mem, base, index, addr, len, flags, 0);
+
+    let avail = base + 0x1000;
+    mem.write_obj(index, GuestAddress(avail + 4 + 2 * index as u64)).unwrap();
+    mem.write_obj(index + 1, GuestAddress(avail + 2)).unwrap();
+}
+
+// Helper: return the number of used buffers and the length reported for the last one
+#[cfg(target_os = "linux")]
+fn read_used(mem: &GuestMemoryMmap, base: u64) -> (u16, u32) {
+    let used = base + 0x2000;
+    let idx: u16 = mem.read_obj(GuestAddress(used + 2)).unwrap();
+    let len: u32 = mem.read_obj(GuestAddress(used + 4 + 8 * (idx as u64 - 1) + 4)).unwrap();
+    (idx, len)
+}
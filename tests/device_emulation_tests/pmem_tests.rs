@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use AsgardManager::device_emulation::pmem::{map_pmem_file, VirtioPmem, VIRTIO_PMEM_REQ_TYPE_FLUSH, VIRTIO_PMEM_RESP_EIO, VIRTIO_PMEM_RESP_OK};
+use AsgardManager::device_emulation::virtio_mmio::VirtioDevice;
+use AsgardManager::vm_setup::setup_utils::{PmemDevice, PMEM_ALIGNMENT};
+use super::{create_guest_memory, setup_queue, write_descriptor, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+
+const QUEUE_BASE: u64 = 0x1000;
+const PMEM_GUEST_ADDRESS: u64 = 0x10_0000_0000;
+
+// Helper: queue a request of `request_type` with room for the response, as the guest driver does
+fn add_request(mem: &GuestMemoryMmap, slot: u16, request_type: u32, response_addr: u64) {
+    let request_addr = 0x8000 + 0x100 * slot as u64;
+    mem.write_obj(request_type, GuestAddress(request_addr)).unwrap();
+    mem.write_obj(0xffff_ffffu32, GuestAddress(response_addr)).unwrap();
+    write_descriptor(mem, QUEUE_BASE, 2 * slot, request_addr, 4, VRING_DESC_F_NEXT, 2 * slot + 1);
+    write_descriptor(mem, QUEUE_BASE, 2 * slot + 1, response_addr, 4, VRING_DESC_F_WRITE, 0);
+
+    let avail = QUEUE_BASE + 0x1000;
+    mem.write_obj(2 * slot, GuestAddress(avail + 4 + 2 * slot as u64)).unwrap();
+    mem.write_obj(slot + 1, GuestAddress(avail + 2)).unwrap();
+}
+
+// Helper: create a pmem device on a fresh file of `size` bytes
+fn create_pmem(mem: &GuestMemoryMmap, dir: &tempfile::TempDir, size: u64) -> VirtioPmem {
+    let pmem = PmemDevice::new(dir.path().join("pmem.img")).size(size);
+    let mapping = Arc::new(map_pmem_file(&pmem).expect("Failed to map the pmem file"));
+    VirtioPmem::new(mem.clone(), mapping, PMEM_GUEST_ADDRESS).expect("Failed to create pmem device")
+}
+
+#[test]
+fn test_virtio_pmem_config_describes_the_region() {
+    let mem = create_guest_memory();
+    let dir = tempfile::tempdir().unwrap();
+    let pmem = create_pmem(&mem, &dir, PMEM_ALIGNMENT);
+    assert_eq!(pmem.get_device_type(), 27);
+    assert_eq!(pmem.get_num_queues(), 1);
+    assert_eq!(std::fs::metadata(dir.path().join("pmem.img")).unwrap().len(), PMEM_ALIGNMENT);
+
+    let mut config = [0u8; 16];
+    pmem.read_config(0, &mut config);
+    assert_eq!(u64::from_le_bytes(config[..8].try_into().unwrap()), PMEM_GUEST_ADDRESS);
+    assert_eq!(u64::from_le_bytes(config[8..].try_into().unwrap()), PMEM_ALIGNMENT);
+    let mut past_end = [0xffu8; 4];
+    pmem.read_config(16, &mut past_end);
+    assert_eq!(past_end, [0; 4]);
+}
+
+#[test]
+fn test_virtio_pmem_answers_flush_requests() {
+    let mem = create_guest_memory();
+    let dir = tempfile::tempdir().unwrap();
+    let mut pmem = create_pmem(&mem, &dir, PMEM_ALIGNMENT);
+    setup_queue(pmem.get_queue_mut(), QUEUE_BASE);
+
+    add_request(&mem, 0, VIRTIO_PMEM_REQ_TYPE_FLUSH, 0x9000);
+    pmem.process_queue(0);
+    assert_eq!(mem.read_obj::<u32>(GuestAddress(0x9000)).unwrap(), VIRTIO_PMEM_RESP_OK);
+    assert_eq!(pmem.get_flushes(), 1);
+
+    // Unknown requests fail
+    add_request(&mem, 1, 7, 0x9100);
+    pmem.process_queue(0);
+    assert_eq!(mem.read_obj::<u32>(GuestAddress(0x9100)).unwrap(), VIRTIO_PMEM_RESP_EIO);
+    assert_eq!(pmem.get_flushes(), 1);
+    let used_idx: u16 = mem.read_obj(GuestAddress(QUEUE_BASE + 0x2000 + 2)).unwrap();
+    assert_eq!(used_idx, 2);
+}
+
+#[test]
+fn test_map_pmem_file_checks_the_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("pmem.img");
+    // Without a size the file must exist and be aligned
+    assert!(map_pmem_file(&PmemDevice::new(&path)).is_err());
+    std::fs::write(&path, vec![0u8; 4096]).unwrap();
+    assert!(map_pmem_file(&PmemDevice::new(&path)).is_err());
+
+    std::fs::File::options().write(true).open(&path).unwrap().set_len(2 * PMEM_ALIGNMENT).unwrap();
+    let mapping = map_pmem_file(&PmemDevice::new(&path)).expect("An aligned file should map");
+    assert_eq!(mapping.get_size(), 2 * PMEM_ALIGNMENT);
+}
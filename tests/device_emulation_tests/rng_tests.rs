@@ -0,0 +1,48 @@
+use vm_memory::{Bytes, GuestAddress};
+use AsgardManager::device_emulation::rng::VirtioRng;
+use AsgardManager::device_emulation::virtio_mmio::VirtioDevice;
+use super::{add_buffer, create_guest_memory, read_used, setup_queue, VRING_DESC_F_WRITE};
+
+#[test]
+fn test_virtio_rng_fills_buffers() {
+    let mem = create_guest_memory();
+    let mut rng = VirtioRng::new(mem.clone()).expect("Failed to create entropy device");
+    assert_eq!(rng.get_device_type(), 4);
+    assert_eq!(rng.get_num_queues(), 1);
+    assert_eq!(rng.get_device_features(), 0);
+
+    setup_queue(rng.get_queue_mut(), 0x1000);
+    add_buffer(&mem, 0x1000, 0, 0x8000, 64, VRING_DESC_F_WRITE);
+    rng.process_queue(0);
+    assert_eq!(read_used(&mem, 0x1000), (1, 64));
+
+    add_buffer(&mem, 0x1000, 1, 0x9000, 64, VRING_DESC_F_WRITE);
+    rng.process_queue(0);
+    assert_eq!(read_used(&mem, 0x1000), (2, 64));
+    assert_eq!(rng.get_bytes_provided(), 128);
+
+    let mut first = [0u8; 64];
+    let mut second = [0u8; 64];
+    mem.read_slice(&mut first, GuestAddress(0x8000)).unwrap();
+    mem.read_slice(&mut second, GuestAddress(0x9000)).unwrap();
+    assert_ne!(first, [0u8; 64]);
+    assert_ne!(first, second);
+}
+
+#[test]
+fn test_virtio_rng_skips_device_readable_buffers() {
+    let mem = create_guest_memory();
+    let mut rng = VirtioRng::new(mem.clone()).expect("Failed to create entropy device");
+    setup_queue(rng.get_queue_mut(), 0x1000);
+
+    // The device only writes, a buffer it may only read is returned empty
+    add_buffer(&mem, 0x1000, 0, 0x8000, 64, 0);
+    rng.process_queue(0);
+    assert_eq!(read_used(&mem, 0x1000), (1, 0));
+    let mut untouched = [0u8; 64];
+    mem.read_slice(&mut untouched, GuestAddress(0x8000)).unwrap();
+    assert_eq!(untouched, [0u8; 64]);
+
+    // Queues other than the request queue don't exist
+    assert!(!rng.process_queue(1));
+}
@@ -0,0 +1,68 @@
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use AsgardManager::device_emulation::virtio_9p::Virtio9p;
+use AsgardManager::device_emulation::virtio_9p::protocol::{WireWriter, P9_RLERROR, P9_TCLUNK, P9_TVERSION, P9_VERSION};
+use AsgardManager::device_emulation::virtio_mmio::VirtioDevice;
+use super::{create_guest_memory, read_used, setup_queue, write_descriptor, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+
+// Helper: make `request` available as the `slot`th chain, followed by a 256 byte reply
+// buffer at `reply_addr`, the way the guest driver does
+fn add_request(mem: &GuestMemoryMmap, base: u64, slot: u16, request: &[u8], reply_addr: u64) {
+    let request_addr = reply_addr + 0x100;
+    mem.write_slice(request, GuestAddress(request_addr)).unwrap();
+    write_descriptor(mem, base, 2 * slot, request_addr, request.len() as u32, VRING_DESC_F_NEXT, 2 * slot + 1);
+    write_descriptor(mem, base, 2 * slot + 1, reply_addr, 256, VRING_DESC_F_WRITE, 0);
+
+    let avail = base + 0x1000;
+    mem.write_obj(2 * slot, GuestAddress(avail + 4 + 2 * slot as u64)).unwrap();
+    mem.write_obj(slot + 1, GuestAddress(avail + 2)).unwrap();
+}
+
+#[test]
+fn test_virtio_9p_config_space_holds_the_tag() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let device = Virtio9p::new(create_guest_memory(), dir.path(), "hostshare").expect("Failed to create 9p device");
+    assert_eq!(device.get_device_type(), 9);
+    assert_eq!(device.get_device_features(), 1);
+    assert_eq!(device.get_tag(), "hostshare");
+
+    let mut config = [0u8; 11];
+    device.read_config(0, &mut config);
+    assert_eq!(&config[..2], &9u16.to_le_bytes());
+    assert_eq!(&config[2..], b"hostshare");
+
+    assert!(Virtio9p::new(create_guest_memory(), dir.path(), "").is_err());
+    assert!(Virtio9p::new(create_guest_memory(), &dir.path().join("missing"), "data").is_err());
+}
+
+#[test]
+fn test_virtio_9p_answers_requests() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mem = create_guest_memory();
+    let mut device = Virtio9p::new(mem.clone(), dir.path(), "data").expect("Failed to create 9p device");
+    setup_queue(device.get_queue_mut(), 0x1000);
+
+    let mut version = WireWriter::new(P9_TVERSION, 0xffff);
+    version.write_u32(8192);
+    version.write_string(P9_VERSION);
+    add_request(&mem, 0x1000, 0, &version.finish(), 0x8000);
+    device.process_queue(0);
+    // Size, type and tag, then the agreed message size and the version
+    let (used, len) = read_used(&mem, 0x1000);
+    assert_eq!((used, len), (1, 7 + 4 + 2 + 8));
+    let mut reply = vec![0u8; len as usize];
+    mem.read_slice(&mut reply, GuestAddress(0x8000)).unwrap();
+    assert_eq!(reply[4], P9_TVERSION + 1);
+    assert_eq!(&reply[7..11], &8192u32.to_le_bytes());
+    assert_eq!(&reply[13..], P9_VERSION.as_bytes());
+
+    // Requests on fids that were never attached fail with EBADF
+    let mut clunk = WireWriter::new(P9_TCLUNK, 1);
+    clunk.write_u32(7);
+    add_request(&mem, 0x1000, 1, &clunk.finish(), 0x9000);
+    device.process_queue(0);
+    assert_eq!(read_used(&mem, 0x1000), (2, 11));
+    let mut reply = [0u8; 11];
+    mem.read_slice(&mut reply, GuestAddress(0x9000)).unwrap();
+    assert_eq!(reply[4], P9_RLERROR);
+    assert_eq!(&reply[7..], &9u32.to_le_bytes());
+}
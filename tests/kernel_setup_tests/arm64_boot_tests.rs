@@ -0,0 +1,107 @@
+use std::io::Write;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use AsgardManager::kernel_setup::arm64_boot::{
+    build_fdt, build_fdt_with_devices, compute_boot_layout, decompress_kernel, Arm64Devices, Arm64Gic, Arm64ImageHeader, PsciCall,
+    ARM64_RAM_BASE, FDT_MAX_SIZE, PSCI_SYSTEM_OFF, PSCI_VERSION,
+};
+
+const RAM_SIZE: u64 = 256 << 20; // 256 MiB
+
+// Helper: an ARM64 Image with the given text_offset and image_size
+fn create_image(text_offset: u64, image_size: u64) -> Vec<u8> {
+    let mut image = vec![0u8; 4096];
+    image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+    image[16..24].copy_from_slice(&image_size.to_le_bytes());
+    image[24..32].copy_from_slice(&0x2u64.to_le_bytes()); // 4K pages, little-endian
+    image[56..60].copy_from_slice(b"ARM\x64");
+    image
+}
+
+#[test]
+fn test_parse_image_header() {
+    let header = Arm64ImageHeader::parse(&create_image(0, 0x20_0000)).expect("Header should parse");
+    assert_eq!(header.text_offset, 0);
+    assert_eq!(header.image_size, 0x20_0000);
+
+    // Kernels before 3.17 leave image_size at 0 and expect a 0x80000 text_offset
+    let legacy = Arm64ImageHeader::parse(&create_image(0x1234, 0)).unwrap();
+    assert_eq!(legacy.text_offset, 0x8_0000);
+
+    assert!(Arm64ImageHeader::parse(&[0u8; 4096]).is_err());
+    let mut big_endian = create_image(0, 0x20_0000);
+    big_endian[24] |= 1;
+    assert!(Arm64ImageHeader::parse(&big_endian).is_err());
+}
+
+#[test]
+fn test_decompress_gzip_kernel() {
+    let image = create_image(0, 0x20_0000);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&image).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    assert_eq!(decompress_kernel(&compressed).unwrap(), image);
+    assert_eq!(decompress_kernel(&image).unwrap(), image);
+}
+
+#[test]
+fn test_boot_layout() {
+    let header = Arm64ImageHeader::parse(&create_image(0, 0x20_0000)).unwrap();
+    let layout = compute_boot_layout(&header, Some(8192), ARM64_RAM_BASE, RAM_SIZE).expect("Layout should fit");
+
+    assert_eq!(layout.kernel_addr, ARM64_RAM_BASE);
+    assert_eq!(layout.initrd, Some((ARM64_RAM_BASE + 0x20_0000, 8192)));
+    assert_eq!(layout.fdt_addr, ARM64_RAM_BASE + RAM_SIZE - FDT_MAX_SIZE as u64);
+
+    assert!(compute_boot_layout(&header, Some(RAM_SIZE as usize), ARM64_RAM_BASE, RAM_SIZE).is_err());
+}
+
+#[test]
+fn test_build_fdt() {
+    let fdt = build_fdt("console=ttyAMA0", ARM64_RAM_BASE, RAM_SIZE, Some((0x4100_0000, 4096)), 2).expect("FDT should build");
+    let read_be = |off: usize| u32::from_be_bytes(fdt[off..off + 4].try_into().unwrap());
+
+    assert_eq!(read_be(0), 0xd00d_feed);
+    assert_eq!(read_be(4) as usize, fdt.len());
+    assert_eq!(read_be(20), 17);
+
+    let contains = |needle: &[u8]| fdt.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"console=ttyAMA0\0"));
+    assert!(contains(b"memory@40000000\0"));
+    assert!(contains(b"cpu@1\0"));
+    assert!(contains(b"linux,initrd-start\0"));
+}
+
+#[test]
+fn test_build_fdt_with_devices() {
+    let gic = Arm64Gic::V2 { distributor: 0x0800_0000, cpu_interface: 0x0801_0000 };
+    let devices = Arm64Devices { gic: Some(gic), virtio_mmio: vec![(0x0a00_0000, 16)] };
+    let fdt = build_fdt_with_devices("", ARM64_RAM_BASE, RAM_SIZE, None, 1, &devices).expect("FDT should build");
+    let contains = |needle: &[u8]| fdt.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"intc@8000000\0"));
+    assert!(contains(b"interrupt-parent\0"));
+    assert!(contains(b"virtio_mmio@a000000\0"));
+    // SPI 16, level-triggered
+    assert!(contains(&[0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 4]));
+
+    // A GICv3 covers the redistributors of every CPU
+    let gic = Arm64Gic::V3 { distributor: 0x0800_0000, redistributors: 0x080a_0000 };
+    let devices = Arm64Devices { gic: Some(gic), virtio_mmio: Vec::new() };
+    let fdt = build_fdt_with_devices("", ARM64_RAM_BASE, RAM_SIZE, None, 2, &devices).expect("FDT should build");
+    let contains = |needle: &[u8]| fdt.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"arm,gic-v3\0"));
+    assert!(contains(&[0, 0, 0, 0, 0x08, 0x0a, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0]));
+
+    // Devices without an interrupt controller can't signal the guest
+    let devices = Arm64Devices { gic: None, virtio_mmio: vec![(0x0a00_0000, 16)] };
+    assert!(build_fdt_with_devices("", ARM64_RAM_BASE, RAM_SIZE, None, 1, &devices).is_err());
+}
+
+#[test]
+fn test_psci_call_decoding() {
+    assert_eq!(PsciCall::from_function_id(PSCI_VERSION), PsciCall::Version);
+    assert_eq!(PsciCall::from_function_id(PSCI_SYSTEM_OFF), PsciCall::SystemOff);
+    assert_eq!(PsciCall::from_function_id(0xC400_0003), PsciCall::CpuOn);
+    assert_eq!(PsciCall::from_function_id(0x1234), PsciCall::Unsupported(0x1234));
+}
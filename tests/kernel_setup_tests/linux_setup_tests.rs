@@ -1,4 +1,7 @@
 use std::path::Path;
+use std::process::Command;
+use AsgardManager::device_emulation::block_device::disk_backend::DiskBackend;
+use AsgardManager::device_emulation::block_device::qcow2::Qcow2Backend;
 use AsgardManager::kernel_setup::linux_setup::extract_kernel_components_from_qcow2;
 
 #[test]
@@ -8,9 +11,9 @@ fn test_extract_kernel_invalid_path() {
     assert!(result.is_err());
     
     // Confirm error message contains relevant hint
-    let err = result.unwrap_err();
+    let err = result.unwrap_err().to_string();
     assert!(
-        err.contains("guestmount failed") || err.contains("No such file"),
+        err.contains("No such file"),
         "Unexpected error message: {}",
         err
     );
@@ -54,4 +57,31 @@ fn test_extract_kernel_without_initrd() {
     let components = result.unwrap();
     assert!(!components.kernel.is_empty());
     assert!(components.initrd.is_none());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_extract_kernel_from_generated_image() {
+    // Build an ext4 filesystem holding two kernels and an initrd, and copy it into a qcow2 image
+    let dir = tempfile::tempdir().unwrap();
+    let boot = dir.path().join("root").join("boot");
+    std::fs::create_dir_all(&boot).unwrap();
+    std::fs::write(boot.join("vmlinuz-6.1.0-9-amd64"), b"old kernel").unwrap();
+    std::fs::write(boot.join("vmlinuz-6.1.0-10-amd64"), vec![0x4b; 100_000]).unwrap();
+    std::fs::write(boot.join("initrd.img-6.1.0-10-amd64"), vec![0x49; 50_000]).unwrap();
+    std::fs::write(boot.join("initrd.img-6.1.0-9-amd64"), b"old initrd").unwrap();
+    let raw = dir.path().join("root.img");
+    std::fs::File::create(&raw).unwrap().set_len(8 << 20).unwrap();
+    let formatted = Command::new("mkfs.ext4").args(["-q", "-F", "-d"]).arg(dir.path().join("root")).arg(&raw).output();
+    if !formatted.is_ok_and(|output| output.status.success()) {
+        eprintln!("Skipping test_extract_kernel_from_generated_image: mkfs.ext4 missing");
+        return;
+    }
+    let qcow2_path = dir.path().join("root.qcow2");
+    let mut qcow2 = Qcow2Backend::create(&qcow2_path, 8 << 20).expect("Failed to create qcow2 image");
+    qcow2.write_sectors(0, &std::fs::read(&raw).unwrap()).expect("Failed to fill qcow2 image");
+    drop(qcow2);
+
+    let components = extract_kernel_components_from_qcow2(qcow2_path.to_str().unwrap()).expect("Extraction should succeed");
+    assert_eq!(components.kernel, vec![0x4b; 100_000]);
+    assert_eq!(components.initrd, Some(vec![0x49; 50_000]));
+}
@@ -0,0 +1,120 @@
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use AsgardManager::kernel_setup::loader::{load_kernel, KERNEL_LOAD_ADDR, CMDLINE_ADDR, CMDLINE_MAX_SIZE};
+use AsgardManager::kernel_setup::setup_utils::KernelComponents;
+
+const GUEST_MEM_SIZE: usize = 64 << 20; // 64 MiB
+
+// Helper: guest RAM starting at 0
+fn create_guest_memory() -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), GUEST_MEM_SIZE)]).expect("Failed to create guest memory")
+}
+
+// Helper: a fake bzImage with `setup_sects` setup sectors followed by `payload`
+fn create_bzimage(setup_sects: u8, payload: &[u8]) -> Vec<u8> {
+    let mut image = vec![0u8; (setup_sects as usize + 1) * 512];
+    image[0x1f1] = setup_sects;
+    image[0x1fe..0x200].copy_from_slice(&0xAA55u16.to_le_bytes());
+    image[0x201] = 0x66; // setup header ends at 0x268
+    image[0x202..0x206].copy_from_slice(b"HdrS");
+    image[0x206..0x208].copy_from_slice(&0x020fu16.to_le_bytes());
+    image[0x236..0x238].copy_from_slice(&1u16.to_le_bytes()); // XLF_KERNEL_64
+    image.extend_from_slice(payload);
+    image
+}
+
+// Helper: a minimal ELF64 kernel with one PT_LOAD segment of `payload` at `paddr`
+fn create_elf(paddr: u64, entry: u64, payload: &[u8]) -> Vec<u8> {
+    let mut elf = vec![0u8; 0x40 + 0x38];
+    elf[0..4].copy_from_slice(b"\x7fELF");
+    elf[4] = 2; // 64-bit
+    elf[5] = 1; // little-endian
+    elf[0x18..0x20].copy_from_slice(&entry.to_le_bytes());
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes()); // e_phoff
+    elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes()); // e_phentsize
+    elf[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+    let ph = 0x40;
+    elf[ph..ph + 4].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    let offset = elf.len() as u64;
+    elf[ph + 0x08..ph + 0x10].copy_from_slice(&offset.to_le_bytes());
+    elf[ph + 0x18..ph + 0x20].copy_from_slice(&paddr.to_le_bytes());
+    elf[ph + 0x20..ph + 0x28].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+    elf[ph + 0x28..ph + 0x30].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+    elf.extend_from_slice(payload);
+    elf
+}
+
+#[test]
+fn test_load_bzimage_with_initrd() {
+    let mut mem = create_guest_memory();
+    let components = KernelComponents {
+        kernel: create_bzimage(4, &[0xAA; 4096]),
+        initrd: Some(vec![0xBB; 8192]),
+    };
+
+    let loaded = load_kernel(&mut mem, &components, "console=ttyS0").expect("Loading should succeed");
+
+    assert_eq!(loaded.entry_point, GuestAddress(KERNEL_LOAD_ADDR + 0x200));
+    assert_eq!(loaded.kernel_end, GuestAddress(KERNEL_LOAD_ADDR + 4096));
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(KERNEL_LOAD_ADDR)).unwrap(), 0xAA);
+
+    let (initrd_addr, initrd_size) = loaded.initrd.expect("initrd should be loaded");
+    assert_eq!(initrd_size, 8192);
+    assert_eq!(initrd_addr.0 % 0x1000, 0);
+    assert!(initrd_addr.0 + 8192 <= GUEST_MEM_SIZE as u64);
+    assert_eq!(mem.read_obj::<u8>(initrd_addr).unwrap(), 0xBB);
+
+    let mut cmdline = vec![0u8; loaded.cmdline_size];
+    mem.read_slice(&mut cmdline, GuestAddress(CMDLINE_ADDR)).unwrap();
+    assert_eq!(cmdline, b"console=ttyS0\0");
+}
+
+#[test]
+fn test_load_elf_kernel() {
+    let mut mem = create_guest_memory();
+    let components = KernelComponents { kernel: create_elf(0x100_0000, 0x100_0000, &[0xCC; 512]), initrd: None };
+
+    let loaded = load_kernel(&mut mem, &components, "").expect("Loading should succeed");
+
+    assert_eq!(loaded.entry_point, GuestAddress(0x100_0000));
+    assert_eq!(loaded.kernel_end, GuestAddress(0x100_0000 + 512));
+    assert!(loaded.initrd.is_none());
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(0x100_0000)).unwrap(), 0xCC);
+}
+
+#[test]
+fn test_load_kernel_rejects_unknown_format() {
+    let mut mem = create_guest_memory();
+    let components = KernelComponents { kernel: vec![0u8; 4096], initrd: None };
+    let result = load_kernel(&mut mem, &components, "");
+    assert!(result.unwrap_err().to_string().contains("unsupported kernel format"));
+}
+
+#[test]
+fn test_load_kernel_rejects_oversized_initrd_and_cmdline() {
+    let mut mem = create_guest_memory();
+    let components = KernelComponents {
+        kernel: create_bzimage(4, &[0xAA; 4096]),
+        initrd: Some(vec![0u8; GUEST_MEM_SIZE]),
+    };
+    assert!(load_kernel(&mut mem, &components, "").is_err());
+
+    let components = KernelComponents { kernel: create_bzimage(4, &[0xAA; 4096]), initrd: None };
+    let cmdline = "a".repeat(CMDLINE_MAX_SIZE);
+    assert!(load_kernel(&mut mem, &components, &cmdline).is_err());
+}
+
+#[test]
+fn test_load_elf_kernel_rejects_overflowing_headers() {
+    let mut mem = create_guest_memory();
+    let cases: [(usize, u64); 3] = [
+        (0x20, u64::MAX), // e_phoff
+        (0x40 + 0x08, u64::MAX), // p_offset
+        (0x40 + 0x18, u64::MAX - 1), // p_paddr
+    ];
+    for (field, value) in cases {
+        let mut kernel = create_elf(0x100_0000, 0x100_0000, &[0xCC; 512]);
+        kernel[field..field + 8].copy_from_slice(&value.to_le_bytes());
+        let components = KernelComponents { kernel, initrd: None };
+        assert!(load_kernel(&mut mem, &components, "").is_err());
+    }
+}
@@ -1,2 +1,7 @@
+pub mod arm64_boot_tests;
 #[cfg(target_os = "linux")]
-pub mod linux_setup_tests;
\ No newline at end of file
+pub mod linux_setup_tests;
+#[cfg(target_os = "linux")]
+pub mod loader_tests;
+#[cfg(target_os = "linux")]
+pub mod x86_64_boot_tests;
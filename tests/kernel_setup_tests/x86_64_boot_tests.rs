@@ -0,0 +1,150 @@
+use kvm_bindings::kvm_sregs;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use AsgardManager::kernel_setup::loader::{load_kernel, CMDLINE_ADDR};
+use AsgardManager::kernel_setup::setup_utils::KernelComponents;
+use AsgardManager::kernel_setup::x86_64_boot::{
+    build_boot_params, build_e820_map, build_screen_info, get_boot_sregs, setup_boot_environment, write_screen_info,
+    BzImageHeader, E820Entry, LinearFramebuffer, E820_RAM, E820_RESERVED, EBDA_START, HIGH_MEMORY_START, PDE_ADDR,
+    PML4_ADDR, ZERO_PAGE_ADDR,
+};
+
+const GUEST_MEM_SIZE: usize = 64 << 20; // 64 MiB
+
+// Helper: a bzImage header with protocol 2.15 and a 64-bit entry point
+fn create_bzimage(setup_sects: u8, payload: &[u8]) -> Vec<u8> {
+    // A setup_sects value of 0 means 4
+    let mut image = vec![0u8; (setup_sects.max(4) as usize + 1) * 512];
+    image[0x1f1] = setup_sects;
+    image[0x1fe..0x200].copy_from_slice(&0xAA55u16.to_le_bytes());
+    image[0x201] = 0x66; // setup header ends at 0x268
+    image[0x202..0x206].copy_from_slice(b"HdrS");
+    image[0x206..0x208].copy_from_slice(&0x020fu16.to_le_bytes());
+    image[0x211] = 0x01; // LOADED_HIGH
+    image[0x22c..0x230].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+    image[0x236..0x238].copy_from_slice(&1u16.to_le_bytes()); // XLF_KERNEL_64
+    image[0x238..0x23c].copy_from_slice(&2047u32.to_le_bytes());
+    image.extend_from_slice(payload);
+    image
+}
+
+#[test]
+fn test_parse_bzimage_header() {
+    let header = BzImageHeader::parse(&create_bzimage(0, &[0; 512])).expect("Header should parse");
+    assert_eq!(header.version, 0x020f);
+    assert_eq!(header.setup_sects, 0);
+    assert_eq!(header.get_setup_size(), 5 * 512);
+    assert_eq!(header.initrd_addr_max, 0x7fff_ffff);
+    assert_eq!(header.cmdline_size, 2047);
+}
+
+#[test]
+fn test_parse_rejects_invalid_headers() {
+    assert!(BzImageHeader::parse(&[0u8; 4096]).is_err());
+
+    let mut old_protocol = create_bzimage(4, &[]);
+    old_protocol[0x206..0x208].copy_from_slice(&0x0204u16.to_le_bytes());
+    assert!(BzImageHeader::parse(&old_protocol).is_err());
+
+    let mut no_64bit_entry = create_bzimage(4, &[]);
+    no_64bit_entry[0x236] = 0;
+    assert!(BzImageHeader::parse(&no_64bit_entry).is_err());
+
+    // Images cut off inside the header, including one claiming a header of 2 bytes
+    let mut short_header = create_bzimage(4, &[]);
+    short_header.truncate(0x208);
+    short_header[0x201] = 0;
+    assert!(BzImageHeader::parse(&short_header).is_err());
+    assert!(BzImageHeader::parse(&create_bzimage(4, &[])[..0x267]).is_err());
+}
+
+#[test]
+fn test_e820_map_reserves_legacy_hole() {
+    let map = build_e820_map(GUEST_MEM_SIZE as u64);
+    assert_eq!(map, vec![
+        E820Entry { addr: 0, size: EBDA_START, entry_type: E820_RAM },
+        E820Entry { addr: EBDA_START, size: HIGH_MEMORY_START - EBDA_START, entry_type: E820_RESERVED },
+        E820Entry { addr: HIGH_MEMORY_START, size: GUEST_MEM_SIZE as u64 - HIGH_MEMORY_START, entry_type: E820_RAM },
+    ]);
+}
+
+#[test]
+fn test_boot_params_fields() {
+    let kernel = create_bzimage(4, &[0xAA; 4096]);
+    let header = BzImageHeader::parse(&kernel).unwrap();
+    let mut mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), GUEST_MEM_SIZE)]).unwrap();
+    let components = KernelComponents { kernel, initrd: Some(vec![0xBB; 8192]) };
+    let loaded = load_kernel(&mut mem, &components, "console=ttyS0").unwrap();
+    let e820 = build_e820_map(GUEST_MEM_SIZE as u64);
+
+    let params = build_boot_params(&header, &loaded, &e820).expect("Boot params should build");
+    let read_u32 = |off: usize| u32::from_le_bytes(params[off..off + 4].try_into().unwrap());
+
+    assert_eq!(params.len(), 4096);
+    assert_eq!(&params[0x202..0x206], b"HdrS");
+    assert_eq!(params[0x210], 0xff); // type_of_loader
+    assert_eq!(params[0x211] & 0x80, 0x80); // CAN_USE_HEAP
+    assert_eq!(read_u32(0x228), CMDLINE_ADDR as u32);
+    let (initrd_addr, initrd_size) = loaded.initrd.unwrap();
+    assert_eq!(read_u32(0x218), initrd_addr.0 as u32);
+    assert_eq!(read_u32(0x21c), initrd_size as u32);
+    assert_eq!(params[0x1e8], 3);
+    assert_eq!(read_u32(0x2d0 + 2 * 20 + 16), E820_RAM);
+}
+
+#[test]
+fn test_setup_boot_environment_writes_structures() {
+    let kernel = create_bzimage(4, &[0xAA; 4096]);
+    let mut mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), GUEST_MEM_SIZE)]).unwrap();
+    let components = KernelComponents { kernel: kernel.clone(), initrd: None };
+    let loaded = load_kernel(&mut mem, &components, "").unwrap();
+
+    setup_boot_environment(&mem, &kernel, &loaded).expect("Boot environment should be written");
+
+    let mut signature = [0u8; 4];
+    mem.read_slice(&mut signature, GuestAddress(ZERO_PAGE_ADDR + 0x202)).unwrap();
+    assert_eq!(&signature, b"HdrS");
+    assert_eq!(mem.read_obj::<u64>(GuestAddress(PML4_ADDR)).unwrap() & !0xfff, 0xa000);
+    // The second 2 MiB page maps physical address 2 MiB as a huge page
+    assert_eq!(mem.read_obj::<u64>(GuestAddress(PDE_ADDR + 8)).unwrap(), 0x20_0083);
+}
+
+#[test]
+fn test_screen_info_describes_framebuffer() {
+    let framebuffer = LinearFramebuffer { base: 0x10_4000_0000, width: 1024, height: 768, stride: 4096 };
+    let info = build_screen_info(&framebuffer).expect("Screen info should build");
+    assert_eq!(info[0x0f], 0x70); // VIDEO_TYPE_EFI
+    assert_eq!(u16::from_le_bytes([info[0x12], info[0x13]]), 1024);
+    assert_eq!(u16::from_le_bytes([info[0x14], info[0x15]]), 768);
+    assert_eq!(u16::from_le_bytes([info[0x16], info[0x17]]), 32);
+    assert_eq!(u32::from_le_bytes(info[0x18..0x1c].try_into().unwrap()), 0x4000_0000);
+    assert_eq!(u32::from_le_bytes(info[0x1c..0x20].try_into().unwrap()), 4096 * 768);
+    assert_eq!(u16::from_le_bytes([info[0x24], info[0x25]]), 4096);
+    assert_eq!(info[0x26..0x2e], [8, 16, 8, 8, 8, 0, 8, 24]);
+    // The upper half of the address is used
+    assert_eq!(u32::from_le_bytes(info[0x36..0x3a].try_into().unwrap()) & 2, 2);
+    assert_eq!(u32::from_le_bytes(info[0x3a..0x3e].try_into().unwrap()), 0x10);
+
+    assert!(build_screen_info(&LinearFramebuffer { base: 0, width: 1 << 16, height: 1, stride: 4 }).is_err());
+
+    // Written over the start of the zero page, leaving the setup header alone
+    let kernel = create_bzimage(4, &[0xAA; 4096]);
+    let mut mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), GUEST_MEM_SIZE)]).unwrap();
+    let loaded = load_kernel(&mut mem, &KernelComponents { kernel: kernel.clone(), initrd: None }, "").unwrap();
+    setup_boot_environment(&mem, &kernel, &loaded).unwrap();
+    write_screen_info(&mem, &framebuffer).expect("Screen info should be written");
+    assert_eq!(mem.read_obj::<u8>(GuestAddress(ZERO_PAGE_ADDR + 0x0f)).unwrap(), 0x70);
+    let mut signature = [0u8; 4];
+    mem.read_slice(&mut signature, GuestAddress(ZERO_PAGE_ADDR + 0x202)).unwrap();
+    assert_eq!(&signature, b"HdrS");
+}
+
+#[test]
+fn test_boot_sregs_enable_long_mode() {
+    let sregs = get_boot_sregs(kvm_sregs::default());
+    assert_eq!(sregs.cs.selector, 0x08);
+    assert_eq!(sregs.cs.l, 1);
+    assert_eq!(sregs.ds.selector, 0x10);
+    assert_eq!(sregs.cr3, PML4_ADDR);
+    assert_ne!(sregs.cr0 & (1 << 31), 0); // paging
+    assert_ne!(sregs.efer & (1 << 10), 0); // long mode active
+}
@@ -1,4 +1,6 @@
 #[cfg(test)]
 mod vm_setup_tests;
 #[cfg(test)]
-mod device_emulation_tests;
\ No newline at end of file
+mod device_emulation_tests;
+#[cfg(test)]
+mod kernel_setup_tests;
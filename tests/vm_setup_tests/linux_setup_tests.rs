@@ -1,5 +1,6 @@
 use AsgardManager::vm_setup::setup_utils::VmSetup;
-use AsgardManager::vm_setup::linux_setup::run_vm;
+use AsgardManager::vm_setup::linux_setup::{run_vm, spawn_vm};
+use AsgardManager::vm_setup::vm_handle::VmState;
 use std::sync::Mutex;
 
 // Constants for test setup
@@ -29,6 +30,9 @@ fn assert_guest_memory_error(e: &str) -> bool {
 fn assert_memory_region_error(e: &str) -> bool {
     e.contains("Failed to set memory region")
 }
+fn assert_host_memory_error(e: &str) -> bool {
+    e.contains("of available host memory")
+}
 fn assert_vcpu_creation_error(e: &str) -> bool {
     e.contains("Failed to create VCPU")
 }
@@ -62,13 +66,15 @@ fn assert_error_for_4gb(e: &str) {
     assert!(
         assert_guest_memory_error(e)
             || assert_memory_region_error(e)
+            || assert_host_memory_error(e)
             || assert_vcpu_exit_or_runtime_error(e),
         "Unexpected error for 4GB memory config: {}", e
     );
 }
 fn assert_error_for_1tb(e: &str) {
     assert!(
-        e.contains("Failed to create guest memory")
+        assert_host_memory_error(e)
+            || e.contains("Failed to create guest memory")
             || e.contains("Failed to set memory region")
             || e.contains("address space")
             || e.contains("ENOMEM")
@@ -84,13 +90,13 @@ fn assert_error_for_min_memory(e: &str) {
 }
 fn assert_error_for_32cpus(e: &str) {
     assert!(
-        assert_vcpu_creation_error(e),
+        assert_vcpu_creation_error(e) || assert_host_memory_error(e),
         "Unexpected error for 32 CPUs: {}", e
     );
 }
 fn assert_error_for_massive_config(e: &str) {
     assert!(
-        e.contains("Failed to set memory region"),
+        assert_host_memory_error(e) || e.contains("Failed to set memory region"),
         "Unexpected error for 1TB/32CPU config: {}", e
     );
 }
@@ -156,8 +162,8 @@ async fn test_run_vm_success_or_expected_error() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_1gb_1cpu(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_1gb_1cpu(&e.to_string()),
     }
 }
 
@@ -168,8 +174,8 @@ async fn test_run_vm_multiple_cpus() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_2cpu(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_2cpu(&e.to_string()),
     }
 }
 
@@ -180,8 +186,8 @@ async fn test_run_vm_large_memory() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_4gb(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_4gb(&e.to_string()),
     }
 }
 
@@ -192,8 +198,8 @@ async fn test_run_vm_tremendous_memory() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_1tb(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_1tb(&e.to_string()),
     }
 }
 
@@ -204,8 +210,8 @@ async fn test_run_vm_minimal_memory() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_min_memory(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_min_memory(&e.to_string()),
     }
 }
 
@@ -216,8 +222,8 @@ async fn test_run_vm_many_cpus() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_32cpus(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_32cpus(&e.to_string()),
     }
 }
 
@@ -228,8 +234,8 @@ async fn test_run_vm_massive_config() {
     let result = run_vm(setup).await;
 
     match result {
-        Ok(()) => assert!(true),
-        Err(e) => assert_error_for_massive_config(&e),
+        Ok(summary) => assert!(!summary.vcpus.is_empty()),
+        Err(e) => assert_error_for_massive_config(&e.to_string()),
     }
 }
 
@@ -241,6 +247,19 @@ async fn test_run_vm_zero_cpus_should_fail() {
 
     assert!(result.is_err(), "VM should not run with 0 CPUs");
     if let Err(e) = result {
-        assert_error_for_zero_cpu(&e);
+        assert_error_for_zero_cpu(&e.to_string());
     }
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spawn_vm_stop_and_wait() {
+    let _guard = VM_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let setup = VmSetup::new(TEST_MEM_2GB_MB, TEST_CPU_1);
+    let handle = spawn_vm(setup);
+
+    let result = handle.stop().await;
+    assert!(result.is_ok(), "Expected stop to succeed, got error: {:?}", result);
+    assert_eq!(handle.get_state(), VmState::Stopped);
+    let result = handle.wait().await;
+    assert!(result.is_ok(), "Expected stopped VM to finish cleanly, got error: {:?}", result);
+}
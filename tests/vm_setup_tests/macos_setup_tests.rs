@@ -1,4 +1,5 @@
-use AsgardManager::vm_setup::macos_setup::run_vm;
+use AsgardManager::vm_setup::macos_setup::{run_vm, spawn_vm};
+use AsgardManager::vm_setup::vm_handle::VmState;
 use AsgardManager::vm_setup::setup_utils::VmSetup;
 use std::sync::Mutex;
 
@@ -17,7 +18,7 @@ async fn test_run_vm_zero_memory() {
     let setup = VmSetup::new(0, 2);
     let result = run_vm(setup).await;
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Failed to map memory");
+    assert_eq!(result.unwrap_err().to_string(), "Failed to map memory");
 }
 
 // Test that run_vm returns error if CPU count is zero (should default to 2)
@@ -46,5 +47,18 @@ async fn test_run_vm_mem_map_fail() {
 
     let result = run_vm(setup).await;
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Failed to map memory");
+    assert_eq!(result.unwrap_err().to_string(), "Failed to map memory");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spawn_vm_stop_and_wait() {
+    let _guard = VM_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let setup = VmSetup::new(TEST_MB, TEST_CPU_CORES);
+    let handle = spawn_vm(setup);
+
+    let result = handle.stop().await;
+    assert!(result.is_ok(), "Expected stop to succeed, got error: {:?}", result);
+    assert_eq!(handle.get_state(), VmState::Stopped);
+    let result = handle.wait().await;
+    assert!(result.is_ok(), "Expected stopped VM to finish cleanly, got error: {:?}", result);
 }
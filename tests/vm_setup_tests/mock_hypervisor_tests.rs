@@ -0,0 +1,144 @@
+use AsgardManager::device_emulation::mmio::{read_le, write_le, MmioDevice};
+use AsgardManager::device_emulation::serial::Serial;
+use AsgardManager::vm_setup::exit_latency::ExitKind;
+use AsgardManager::vm_setup::exit_summary::VcpuExitReason;
+use AsgardManager::vm_setup::mock_hypervisor::{MockAccess, MockExit, MockHypervisor, MockRead};
+use AsgardManager::vm_setup::setup_utils::VmSetup;
+use AsgardManager::vm_setup::vm_handle::VmState;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use vm_memory::{Bytes, GuestAddress};
+
+const TEST_MEM_MB: u32 = 4;
+const TEST_CPUS: u32 = 2;
+const COM1: u16 = 0x3f8;
+const REGISTER_BASE: u64 = 0xd000_0000;
+
+// Helper: serial console output kept in memory
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Helper: MMIO device holding a single register
+#[derive(Default)]
+struct Register {
+    value: u64,
+}
+
+impl MmioDevice for Register {
+    fn read_mmio(&mut self, _offset: u64, data: &mut [u8]) {
+        write_le(data, self.value);
+    }
+
+    fn write_mmio(&mut self, _offset: u64, data: &[u8]) {
+        self.value = read_le(data);
+    }
+}
+
+// Helper: a mock VM with two vCPUs
+fn create_mock_vm() -> MockHypervisor {
+    let setup = VmSetup::builder(TEST_MEM_MB, TEST_CPUS).build().expect("Builder should succeed");
+    MockHypervisor::new(&setup).expect("Failed to create the mock VM")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mock_vm_dispatches_exits_to_devices() {
+    let mut vm = create_mock_vm();
+    let output = SharedOutput::default();
+    vm.get_pio_bus_mut().register(COM1, 8, Arc::new(Mutex::new(Serial::new(Box::new(output.clone()))))).unwrap();
+    vm.get_pio_bus_mut().set_name(COM1, "serial0");
+    vm.get_mmio_bus_mut().register(REGISTER_BASE, 8, Arc::new(Mutex::new(Register::default()))).unwrap();
+    vm.get_mmio_bus_mut().set_name(REGISTER_BASE, "register");
+    vm.set_script(0, vec![
+        MockExit::PioOut { port: COM1, data: b"h".to_vec() },
+        MockExit::PioOut { port: COM1, data: b"i".to_vec() },
+        MockExit::MmioWrite { address: REGISTER_BASE, data: vec![0x34, 0x12, 0, 0] },
+        MockExit::MmioRead { address: REGISTER_BASE, len: 4 },
+        MockExit::PioIn { port: 0x80, len: 1 },
+    ]).unwrap();
+    assert!(vm.set_script(TEST_CPUS, Vec::new()).is_err());
+    let reads = vm.get_read_log();
+
+    let summary = vm.run().await.expect("The scripted vCPUs should run");
+    // vCPU 0 halts once its script ran out, vCPU 1 right away
+    assert_eq!(summary.get_reason(), VcpuExitReason::Halted);
+    assert_eq!(summary.vcpus.len(), 2);
+    assert_eq!(*output.0.lock().unwrap(), b"hi");
+    // The register read back what was written, and the unclaimed port read as all ones
+    assert_eq!(reads.get_vcpu_reads(0), vec![
+        MockRead { vcpu: 0, access: MockAccess::Mmio(REGISTER_BASE), data: vec![0x34, 0x12, 0, 0] },
+        MockRead { vcpu: 0, access: MockAccess::Port(0x80), data: vec![0xff] },
+    ]);
+    assert!(reads.get_vcpu_reads(1).is_empty());
+
+    let serial_writes = summary.exit_latencies.iter()
+        .find(|latency| latency.device == "serial0" && latency.kind == ExitKind::PortWrite)
+        .expect("The serial writes should be timed");
+    assert_eq!(serial_writes.histogram.get_count(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mock_vm_guest_code_writes_guest_ram() {
+    let mut vm = create_mock_vm();
+    let guest_memory = vm.get_guest_memory().clone();
+    vm.set_script(1, vec![
+        MockExit::execute(|memory| memory.write_slice(b"ready", GuestAddress(0x1000)).unwrap()),
+        MockExit::Shutdown,
+    ]).unwrap();
+
+    let summary = vm.run().await.unwrap();
+    assert_eq!(summary.get_reason(), VcpuExitReason::Shutdown);
+    let mut data = [0u8; 5];
+    guest_memory.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+    assert_eq!(&data, b"ready");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mock_vm_faults_fail_the_run() {
+    let mut vm = create_mock_vm();
+    vm.set_script(0, vec![MockExit::PioIn { port: 0x80, len: 1 }, MockExit::Exception("triple faulted".to_string())]).unwrap();
+    let err = vm.run().await.unwrap_err();
+    assert_eq!(err.to_string(), "VCPU 0 triple faulted (Kernel mode, pc 0x2)");
+
+    let mut vm = create_mock_vm();
+    vm.set_script(0, vec![MockExit::MmioWrite { address: REGISTER_BASE, data: vec![1] }]).unwrap();
+    let err = vm.run().await.unwrap_err();
+    assert_eq!(err.to_string(), "VCPU 0 encountered MMIO write at unmapped address d0000000");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mock_vm_handle_controls_spinning_vcpus() {
+    let mut vm = create_mock_vm();
+    vm.set_script(0, vec![MockExit::Spin]).unwrap();
+    vm.set_script(1, vec![MockExit::PioOut { port: 0x80, data: vec![1] }, MockExit::Spin]).unwrap();
+
+    let handle = vm.spawn();
+    handle.pause().await.expect("The spinning vCPUs should park");
+    assert_eq!(handle.get_state(), VmState::Paused);
+    handle.resume().await.unwrap();
+    assert_eq!(handle.get_state(), VmState::Running);
+    handle.stop().await.unwrap();
+    let summary = handle.wait().await.unwrap();
+    assert_eq!(summary.get_reason(), VcpuExitReason::Stopped);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mock_vm_reset_stops_every_vcpu() {
+    let mut vm = create_mock_vm();
+    vm.set_script(0, vec![MockExit::Spin]).unwrap();
+    vm.set_script(1, vec![MockExit::Reset]).unwrap();
+
+    let summary = vm.run().await.unwrap();
+    assert_eq!(summary.get_reason(), VcpuExitReason::Reset);
+    assert_eq!(summary.vcpus[0].reason, VcpuExitReason::Stopped);
+}
@@ -1,4 +1,5 @@
 pub mod setup_utils_tests;
+pub mod mock_hypervisor_tests;
 
 #[cfg(target_os = "macos")]
 pub mod macos_setup_tests;
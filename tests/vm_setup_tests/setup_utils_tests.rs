@@ -1,5 +1,19 @@
-use AsgardManager::vm_setup::setup_utils::VmSetup;
+use AsgardManager::vm_setup::setup_utils::{
+    BootDevice, DiskBackendType, DiskDevice, Display, VmSetup, NetworkDevice, PmemDevice, SerialConsole, SharedMemoryBacking, SharedMemoryRegion, VirtioTransport,
+    DEFAULT_BOOT_ORDER, DEFAULT_KERNEL_CMDLINE, MAX_DISKS, MAX_DISPLAY_SIZE, MAX_MOUNT_TAG_LEN, MAX_SHARED_MEMORY_REGIONS, PMEM_ALIGNMENT,
+};
+use AsgardManager::device_emulation::block_device::disk_backend::CacheMode;
+use AsgardManager::device_emulation::gic::GicVersion;
+use std::path::Path;
+use std::sync::Arc;
+use AsgardManager::kernel_setup::setup_utils::KernelComponents;
+use AsgardManager::vm_setup::cpu_model::CpuModel;
+use AsgardManager::vm_setup::mitigations::MitigationPolicy;
+use AsgardManager::vm_setup::security_features::{SecurityFeature, SecurityFeaturePolicy};
+use AsgardManager::vm_setup::pmu::GuestPmu;
+use AsgardManager::device_emulation::virtqueue_config::VirtqueueConfig;
 use std::sync::Mutex;
+use std::time::Duration;
 
 const TEST_MB: u32 = 4;
 const TEST_CPU_CORES: u32 = 2;
@@ -33,4 +47,274 @@ fn test_vmsetup_new_with_zero_memory() {
     let setup = VmSetup::new(ZERO_MB, TEST_CPU_CORES);
     assert_eq!(setup.get_memory_size(), 0);
     assert_eq!(setup.get_cpu_cores_count(), TEST_CPU_CORES);
-}
\ No newline at end of file
+}
+#[test]
+fn test_vmsetup_large_pages_default_and_toggle() {
+    let mut setup = VmSetup::new(TEST_MB, TEST_CPU_CORES);
+    assert!(!setup.get_use_large_pages());
+    setup.set_use_large_pages(true);
+    assert!(setup.get_use_large_pages());
+}
+
+#[test]
+fn test_vmsetup_cpu_model_default_and_set() {
+    let mut setup = VmSetup::new(TEST_MB, TEST_CPU_CORES);
+    assert_eq!(setup.get_cpu_model(), &CpuModel::HostPassthrough);
+    setup.set_cpu_model(CpuModel::X86_64V2);
+    assert_eq!(setup.get_cpu_model(), &CpuModel::X86_64V2);
+}
+
+#[test]
+fn test_vmsetup_virtqueue_config_auto_and_override() {
+    let mut setup = VmSetup::new(TEST_MB, 4);
+    assert_eq!(setup.get_virtqueue_config(), VirtqueueConfig::for_vcpus(4));
+    assert_eq!(setup.get_virtqueue_config().get_num_queues(), 4);
+
+    let manual = VirtqueueConfig::new(1, 4096).unwrap();
+    setup.set_virtqueue_config(Some(manual));
+    assert_eq!(setup.get_virtqueue_config(), manual);
+
+    setup.set_virtqueue_config(None);
+    assert_eq!(setup.get_virtqueue_config().get_num_queues(), 4);
+}
+
+#[test]
+fn test_vmsetup_kernel_default_and_set() {
+    let mut setup = VmSetup::new(TEST_MB, TEST_CPU_CORES);
+    assert!(setup.get_kernel().is_none());
+    assert_eq!(setup.get_kernel_cmdline(), DEFAULT_KERNEL_CMDLINE);
+
+    setup.set_kernel(KernelComponents { kernel: vec![1, 2, 3], initrd: None }, "console=hvc0");
+    assert_eq!(setup.get_kernel().unwrap().kernel, vec![1, 2, 3]);
+    assert_eq!(setup.get_kernel_cmdline(), "console=hvc0");
+}
+
+#[test]
+fn test_vmsetup_builder_attachments() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .disk_image("disk.img")
+        .cdrom_image("installer.iso")
+        .boot_order(&[BootDevice::Cdrom, BootDevice::Disk])
+        .kernel(KernelComponents { kernel: vec![1, 2, 3], initrd: None }, "console=ttyS0")
+        .serial_console(SerialConsole::Stdout)
+        .network_device(NetworkDevice::new("tap0", [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]))
+        .cpu_model(CpuModel::X86_64V2)
+        .build()
+        .expect("Builder should succeed");
+
+    assert_eq!(setup.get_memory_size(), (1024 * 1024 * TEST_MB) as usize);
+    assert_eq!(setup.get_disk_image(), Some(Path::new("disk.img")));
+    assert_eq!(setup.get_cdrom_image(), Some(Path::new("installer.iso")));
+    assert_eq!(setup.get_boot_order(), &[BootDevice::Cdrom, BootDevice::Disk]);
+    assert_eq!(setup.get_kernel_cmdline(), "console=ttyS0");
+    assert_eq!(setup.get_serial_console(), &SerialConsole::Stdout);
+    assert_eq!(setup.get_network_devices()[0].get_host_interface(), "tap0");
+    assert_eq!(setup.get_cpu_model(), &CpuModel::X86_64V2);
+}
+
+#[test]
+fn test_vmsetup_builder_disks_keep_their_order() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .disk_image("system.img")
+        .disk(DiskDevice::new("data.img").backend(DiskBackendType::File).cache_mode(CacheMode::Writethrough).poll_window(Duration::from_micros(50)))
+        .disk(DiskDevice::new("seed.iso").read_only(true))
+        .build()
+        .expect("Builder should succeed");
+
+    let disks = setup.get_disks();
+    assert_eq!(disks.iter().map(|disk| disk.get_path()).collect::<Vec<_>>(), vec![
+        Path::new("system.img"),
+        Path::new("data.img"),
+        Path::new("seed.iso"),
+    ]);
+    assert_eq!(disks[0].get_backend(), DiskBackendType::Auto);
+    assert_eq!(disks[0].get_cache_mode(), CacheMode::Writeback);
+    assert!(!disks[0].is_read_only());
+    assert_eq!(disks[0].get_poll_window(), Duration::ZERO);
+    assert_eq!(disks[1].get_backend(), DiskBackendType::File);
+    assert_eq!(disks[1].get_cache_mode(), CacheMode::Writethrough);
+    assert_eq!(disks[1].get_poll_window(), Duration::from_micros(50));
+    assert!(disks[2].is_read_only());
+    // The VM boots from the first disk
+    assert_eq!(setup.get_disk_image(), Some(Path::new("system.img")));
+}
+
+#[test]
+fn test_vmsetup_builder_iso_images_are_read_only_disks() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .disk_image("cloud.qcow2")
+        .iso_image("seed.iso")
+        .iso_image("drivers.iso")
+        .cdrom_image("installer.iso")
+        .build()
+        .expect("Builder should succeed");
+
+    let disks = setup.get_disks();
+    assert_eq!(disks.len(), 3);
+    assert!(!disks[0].is_read_only() && disks[1].is_read_only() && disks[2].is_read_only());
+    assert_eq!(disks[2].get_path(), Path::new("drivers.iso"));
+    assert_eq!(setup.get_cdrom_image(), Some(Path::new("installer.iso")));
+}
+
+#[test]
+fn test_vmsetup_builder_rejects_invalid_disks() {
+    let result = VmSetup::builder(TEST_MB, TEST_CPU_CORES).disk_image("disk.img").disk_image("disk.img").build();
+    assert!(result.err().expect("Builder should fail").to_string().contains("reuses the image disk.img"));
+
+    let mut builder = VmSetup::builder(TEST_MB, TEST_CPU_CORES);
+    for i in 0..=MAX_DISKS {
+        builder = builder.disk_image(format!("disk{}.img", i));
+    }
+    assert!(builder.build().is_err());
+}
+
+#[test]
+fn test_vmsetup_builder_shared_dirs() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .share_dir("/srv/src", "src")
+        .share_dir("/srv/data", "data")
+        .build()
+        .expect("Builder should succeed");
+    let tags: Vec<&str> = setup.get_shared_dirs().iter().map(|shared_dir| shared_dir.get_tag()).collect();
+    assert_eq!(tags, ["src", "data"]);
+    assert_eq!(setup.get_shared_dirs()[0].get_path(), Path::new("/srv/src"));
+
+    let result = VmSetup::builder(TEST_MB, TEST_CPU_CORES).share_dir("/srv/src", "src").share_dir("/srv/data", "src").build();
+    assert!(result.err().expect("Builder should fail").to_string().contains("reuses the mount tag src"));
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).share_dir("/srv/src", "").build().is_err());
+    let long_tag = "t".repeat(MAX_MOUNT_TAG_LEN + 1);
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).share_dir("/srv/src", &long_tag).build().is_err());
+}
+
+#[test]
+fn test_vmsetup_builder_shared_memory() {
+    let file = Arc::new(tempfile::tempfile().unwrap());
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .shared_memory(SharedMemoryRegion::new(SharedMemoryBacking::Path("/dev/shm/vm0".into()), 0x10_0000))
+        .shared_memory(SharedMemoryRegion::new(SharedMemoryBacking::File(file.clone()), 0x1000))
+        .build()
+        .expect("Builder should succeed");
+    let sizes: Vec<u64> = setup.get_shared_memory().iter().map(|region| region.get_size()).collect();
+    assert_eq!(sizes, [0x10_0000, 0x1000]);
+
+    let region = |size| SharedMemoryRegion::new(SharedMemoryBacking::Path("/dev/shm/vm0".into()), size);
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).shared_memory(region(0)).build().is_err());
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).shared_memory(region(0x1800)).build().is_err());
+    let result = VmSetup::builder(TEST_MB, TEST_CPU_CORES).shared_memory(region(0x1000)).shared_memory(region(0x2000)).build();
+    assert!(result.err().expect("Builder should fail").to_string().contains("reuses the file of another region"));
+    let shared = SharedMemoryRegion::new(SharedMemoryBacking::File(file), 0x1000);
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).shared_memory(shared.clone()).shared_memory(shared).build().is_err());
+
+    let too_many = (0..=MAX_SHARED_MEMORY_REGIONS).fold(VmSetup::builder(TEST_MB, TEST_CPU_CORES), |builder, i| {
+        builder.shared_memory(SharedMemoryRegion::new(SharedMemoryBacking::Path(format!("/dev/shm/vm{}", i).into()), 0x1000))
+    });
+    assert!(too_many.build().is_err());
+}
+
+#[test]
+fn test_vmsetup_builder_pmem_devices() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .pmem(PmemDevice::new("/srv/db.img"))
+        .pmem(PmemDevice::new("/srv/log.img").size(4 * PMEM_ALIGNMENT))
+        .build()
+        .expect("Builder should succeed");
+    let pmem_devices = setup.get_pmem_devices();
+    assert_eq!(pmem_devices.len(), 2);
+    assert_eq!(pmem_devices[0].get_path(), Path::new("/srv/db.img"));
+    assert_eq!(pmem_devices[0].get_size(), None);
+    assert_eq!(pmem_devices[1].get_size(), Some(4 * PMEM_ALIGNMENT));
+
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).pmem(PmemDevice::new("/srv/db.img").size(4096)).build().is_err());
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).pmem(PmemDevice::new("/srv/db.img").size(0)).build().is_err());
+    let result = VmSetup::builder(TEST_MB, TEST_CPU_CORES).pmem(PmemDevice::new("/srv/db.img")).pmem(PmemDevice::new("/srv/db.img")).build();
+    assert!(result.err().expect("Builder should fail").to_string().contains("reuses the file /srv/db.img"));
+}
+
+#[test]
+fn test_vmsetup_builder_display() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).display(Display::new(1024, 768)).build().expect("Builder should succeed");
+    let display = setup.get_display().expect("Display should be set");
+    assert_eq!((display.get_width(), display.get_height()), (1024, 768));
+
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).display(Display::new(MAX_DISPLAY_SIZE, MAX_DISPLAY_SIZE)).build().is_ok());
+    assert!(VmSetup::builder(TEST_MB, TEST_CPU_CORES).display(Display::new(0, 768)).build().is_err());
+    let result = VmSetup::builder(TEST_MB, TEST_CPU_CORES).display(Display::new(MAX_DISPLAY_SIZE + 1, 768)).build();
+    assert!(result.err().expect("Builder should fail").to_string().contains("display of 4097x768 pixels"));
+}
+
+#[test]
+fn test_vmsetup_builder_mitigation_policy() {
+    let policy = MitigationPolicy::new().require_mitigated("mds").require_mitigated("mds").hide_speculation_controls(true);
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).mitigation_policy(policy.clone()).build().expect("Builder should succeed");
+    assert_eq!(setup.get_mitigation_policy(), &policy);
+    assert_eq!(setup.get_mitigation_policy().get_required(), ["mds"]);
+    assert!(setup.get_mitigation_policy().get_hide_speculation_controls());
+}
+
+#[test]
+fn test_vmsetup_builder_security_features() {
+    let policy = SecurityFeaturePolicy::new().hide(SecurityFeature::Smap, true).hide(SecurityFeature::CetShadowStack, true);
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).security_features(policy.clone()).build().expect("Builder should succeed");
+    assert_eq!(setup.get_security_features(), &policy);
+    assert!(setup.get_security_features().is_hidden(SecurityFeature::Smap));
+    assert!(!setup.get_security_features().is_hidden(SecurityFeature::Smep));
+}
+
+#[test]
+fn test_vmsetup_builder_guest_pmu() {
+    assert_eq!(VmSetup::new(TEST_MB, TEST_CPU_CORES).get_guest_pmu(), GuestPmu::Disabled);
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).guest_pmu(GuestPmu::Virtualized).build().expect("Builder should succeed");
+    assert_eq!(setup.get_guest_pmu(), GuestPmu::Virtualized);
+}
+
+#[test]
+fn test_vmsetup_builder_gic_version() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).build().expect("Builder should succeed");
+    assert_eq!(setup.get_gic_version(), GicVersion::V2);
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).gic_version(GicVersion::V3).build().expect("Builder should succeed");
+    assert_eq!(setup.get_gic_version(), GicVersion::V3);
+}
+
+#[test]
+fn test_vmsetup_builder_virtio_transport() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).disk_image("/tmp/disk.img").build().expect("Builder should succeed");
+    assert_eq!(setup.get_virtio_transport(), VirtioTransport::Mmio);
+
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .disk_image("/tmp/disk.img")
+        .virtio_transport(VirtioTransport::Pci)
+        .build()
+        .expect("Builder should succeed");
+    assert_eq!(setup.get_virtio_transport(), VirtioTransport::Pci);
+}
+
+#[test]
+fn test_vmsetup_builder_defaults_have_no_attachments() {
+    let setup = VmSetup::builder(TEST_MB, TEST_CPU_CORES).build().expect("Builder should succeed");
+    assert!(setup.get_disks().is_empty());
+    assert!(setup.get_cdrom_image().is_none());
+    assert!(setup.get_kernel().is_none());
+    assert_eq!(setup.get_boot_order(), &DEFAULT_BOOT_ORDER);
+    assert_eq!(setup.get_serial_console(), &SerialConsole::Disabled);
+    assert!(setup.get_network_devices().is_empty());
+    assert!(setup.get_shared_dirs().is_empty());
+    assert!(setup.get_shared_memory().is_empty());
+    assert!(setup.get_pmem_devices().is_empty());
+    assert_eq!(setup.get_display(), None);
+    assert_eq!(setup.get_mitigation_policy(), &MitigationPolicy::default());
+}
+
+#[test]
+fn test_vmsetup_builder_rejects_invalid_network_devices() {
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let duplicate = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .network_device(NetworkDevice::new("tap0", mac))
+        .network_device(NetworkDevice::new("tap1", mac))
+        .build();
+    assert!(duplicate.is_err());
+
+    let multicast = VmSetup::builder(TEST_MB, TEST_CPU_CORES)
+        .network_device(NetworkDevice::new("tap0", [0x01, 0, 0, 0, 0, 1]))
+        .build();
+    assert!(multicast.is_err());
+}
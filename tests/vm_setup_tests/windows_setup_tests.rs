@@ -1,5 +1,6 @@
 use AsgardManager::vm_setup::setup_utils::VmSetup;
-use AsgardManager::vm_setup::windows_setup::run_vm;
+use AsgardManager::vm_setup::windows_setup::{run_vm, spawn_vm};
+use AsgardManager::vm_setup::vm_handle::VmState;
 use std::sync::Mutex;
 
 // Constants for test setup
@@ -50,7 +51,7 @@ async fn test_run_vm_fail_large_memory() {
     let setup = VmSetup::new(TEST_MEM_1TB, TEST_CPU_1);
     let result = run_vm(setup).await;
     assert!(result.is_err(), "Expected failure due to large memory allocation");
-    let err_msg = result.unwrap_err();
+    let err_msg = result.unwrap_err().to_string();
     assert!(
         err_msg.contains("Failed to allocate the memory: not enough available memory"),
         "Expected large memory related error, got: {}", err_msg
@@ -68,7 +69,7 @@ async fn test_run_vm_fail_minimal_memory() {
         // If it succeeds, just pass the test (optional)
         assert!(true);
     } else {
-        let err_msg = result.unwrap_err();
+        let err_msg = result.unwrap_err().to_string();
         assert!(
             err_msg.contains("Map memory error"),
             "Expected memory mapping error due to small memory, got: {}", err_msg
@@ -83,7 +84,7 @@ async fn test_run_vm_fail_many_cpus() {
     let setup = VmSetup::new(TEST_MEM_16MB, TEST_CPU_32);
     let result = run_vm(setup).await;
     assert!(result.is_err(), "Expected failure when creating 100 CPUs");
-    let err_msg = result.unwrap_err();
+    let err_msg = result.unwrap_err().to_string();
     assert!(
         err_msg.contains("Failed to set processor count: processor_count equal to"),
         "Expected VCPU creation error for many CPUs, got: {}", err_msg
@@ -99,3 +100,16 @@ async fn test_run_vm_zero_cpu_normalizes_to_two() {
     let result = run_vm(setup).await;
     assert!(result.is_ok(), "VM should run with normalized 2 CPUs");
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spawn_vm_stop_and_wait() {
+    let _guard = VM_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let setup = VmSetup::new(TEST_MEM_16MB, TEST_CPU_1);
+    let handle = spawn_vm(setup);
+
+    let result = handle.stop().await;
+    assert!(result.is_ok(), "Expected stop to succeed, got error: {:?}", result);
+    assert_eq!(handle.get_state(), VmState::Stopped);
+    let result = handle.wait().await;
+    assert!(result.is_ok(), "Expected stopped VM to finish cleanly, got error: {:?}", result);
+}